@@ -21,8 +21,13 @@ Commands:
   roppongi
   dut
   tast
+  __complete
 EOF
   fi
+  if [ "$*" = "__complete -- flash --board br" ]; then
+    echo "brya"
+    echo "brask"
+  fi
   if [ "$*" = "dut list --ids" ]; then
     echo "dut1 dut2 dut3"
   fi
@@ -77,6 +82,7 @@ test_complete
 echo "${COMPREPLY[@]}" | grep -wq "shibuya"
 echo "${COMPREPLY[@]}" | grep -wq "roppongi"
 echo "${COMPREPLY[@]}" | grep -wq "Commands" && exit 1
+echo "${COMPREPLY[@]}" | grep -wq "__complete" && exit 1
 echo "${COMPREPLY[@]}" | grep -wq -e "-d"
 echo "${COMPREPLY[@]}" | grep -wq -e "--dummy"
 
@@ -92,6 +98,12 @@ echo "${COMPREPLY[@]}" | grep -wq "dut1"
 echo "${COMPREPLY[@]}" | grep -wq "dut2"
 echo "${COMPREPLY[@]}" | grep -wq "dut5" && exit 1
 
+COMP_CWORD=3
+COMP_WORDS=("cro3" "flash" "--board" "br")
+test_complete
+echo "${COMPREPLY[@]}" | grep -wq "brya"
+echo "${COMPREPLY[@]}" | grep -wq "brask"
+
 COMP_CWORD=3
 COMP_WORDS=("cro3" "tast" "run" "")
 test_complete
@@ -4,14 +4,22 @@
 // license that can be found in the LICENSE file or at
 // https://developers.google.com/open-source/licenses/bsd
 
+use std::path::Path;
+use std::path::PathBuf;
 use std::process::Command;
+use std::time::Duration;
+use std::time::Instant;
 
+use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
+use tempdir::TempDir;
 use tracing::info;
 
 use crate::config::Config;
+use crate::image_cache::cached_image_dir;
+use crate::image_cache::ImageKind;
 
 const MASTER_ARC_DEV: &str = "master";
 const RVC: &str = "rvc";
@@ -63,3 +71,170 @@ pub fn setup_arc_repo(repo: &str, version: &str) -> Result<()> {
 
     Ok(())
 }
+
+/// Returns the serials of the devices in the output of `fastboot devices` or
+/// `adb devices` that are in `state` (e.g. "fastboot", "device")
+pub fn parse_device_serials(output: &str, state: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|l| {
+            let mut fields = l.split_whitespace();
+            let serial = fields.next()?;
+            (fields.next()? == state).then(|| serial.to_string())
+        })
+        .collect()
+}
+
+fn run_android_tool(tool: &str, serial: &str, args: &[&str]) -> Result<String> {
+    let output = Command::new(tool)
+        .arg("-s")
+        .arg(serial)
+        .args(args)
+        .output()
+        .context(anyhow!(
+            "Failed to run {tool}. Is the Android SDK platform-tools in PATH?"
+        ))?;
+    if !output.status.success() {
+        bail!(
+            "{tool} {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Returns the build target (e.g. cheets_x86_64-userdebug) of the Android
+/// running on the device
+pub fn android_target_of_device(serial: &str) -> Result<String> {
+    let product = run_android_tool("adb", serial, &["shell", "getprop", "ro.build.product"])?;
+    let build_type = run_android_tool("adb", serial, &["shell", "getprop", "ro.build.type"])?;
+    Ok(format!("{product}-{build_type}"))
+}
+
+/// Downloads the image zip of an Android build with fetch_artifact, or reuses
+/// the one downloaded before
+pub fn download_android_images(build: &str, target: &str) -> Result<PathBuf> {
+    let dir = cached_image_dir(target, build, ImageKind::Android)?;
+    let product = target.rsplit_once('-').map(|(p, _)| p).unwrap_or(target);
+    let zip = dir.join(format!("{product}-img-{build}.zip"));
+    if zip.exists() {
+        info!("Using the cached {zip:?}");
+        return Ok(zip);
+    }
+    info!("Downloading the images of {target} {build}...");
+    Command::new("fetch_artifact")
+        .args(["--bid", build, "--target", target])
+        .arg(zip.file_name().context("No file name")?)
+        .arg(&dir)
+        .status()
+        .context("Failed to run fetch_artifact (Android build tools are needed)")?
+        .exit_ok()
+        .context(anyhow!("Failed to download the images of {target} {build}"))?;
+    if !zip.exists() {
+        bail!("{zip:?} is not downloaded");
+    }
+    Ok(zip)
+}
+
+fn wait_for_device(tool: &str, serial: &str, state: &str, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if let Ok(output) = Command::new(tool).arg("devices").output() {
+            let devices = String::from_utf8_lossy(&output.stdout);
+            if parse_device_serials(&devices, state)
+                .iter()
+                .any(|s| s == serial)
+            {
+                return Ok(());
+            }
+        }
+        std::thread::sleep(Duration::from_secs(2));
+    }
+    bail!("{serial} did not appear in `{tool} devices` in {timeout:?}")
+}
+
+/// Reboots the device into fastboot and flashes the images in `zip`. Only
+/// `partitions` are flashed if given, and userdata is wiped if `wipe`. Returns
+/// the build ID that the device runs after rebooting.
+pub fn flash_android(
+    serial: &str,
+    zip: &Path,
+    partitions: &[String],
+    wipe: bool,
+) -> Result<String> {
+    let in_fastboot = Command::new("fastboot")
+        .arg("devices")
+        .output()
+        .map(|o| {
+            parse_device_serials(&String::from_utf8_lossy(&o.stdout), "fastboot")
+                .iter()
+                .any(|s| s == serial)
+        })
+        .unwrap_or(false);
+    if !in_fastboot {
+        info!("Rebooting {serial} into fastboot...");
+        run_android_tool("adb", serial, &["reboot", "bootloader"])?;
+        wait_for_device("fastboot", serial, "fastboot", Duration::from_secs(120))?;
+    }
+    let zip_path = zip.to_string_lossy();
+    if partitions.is_empty() {
+        info!("Flashing {zip:?} to {serial}...");
+        let mut args = vec!["update", "--skip-reboot", &zip_path];
+        if wipe {
+            args.insert(0, "-w");
+        }
+        run_android_tool("fastboot", serial, &args)?;
+    } else {
+        let dir = TempDir::new("cro3_android")?;
+        for partition in partitions {
+            let image = format!("{partition}.img");
+            Command::new("unzip")
+                .args(["-o", "-q", &zip_path, &image, "-d"])
+                .arg(dir.path())
+                .status()?
+                .exit_ok()
+                .context(anyhow!("{image} is not found in {zip:?}"))?;
+            info!("Flashing {partition} to {serial}...");
+            let image = dir.path().join(&image);
+            run_android_tool(
+                "fastboot",
+                serial,
+                &["flash", partition, &image.to_string_lossy()],
+            )?;
+        }
+        if wipe {
+            run_android_tool("fastboot", serial, &["-w"])?;
+        }
+    }
+    info!("Rebooting {serial}...");
+    run_android_tool("fastboot", serial, &["reboot"])?;
+    wait_for_device("adb", serial, "device", Duration::from_secs(300))?;
+    run_android_tool(
+        "adb",
+        serial,
+        &["shell", "getprop", "ro.build.version.incremental"],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn android_devices() {
+        let output = "List of devices attached
+emulator-5554\tdevice
+0123456789ABCDEF\tfastboot
+FA7AB1A00000\tunauthorized
+";
+        assert_eq!(
+            parse_device_serials(output, "device"),
+            vec!["emulator-5554".to_string()]
+        );
+        assert_eq!(
+            parse_device_serials(output, "fastboot"),
+            vec!["0123456789ABCDEF".to_string()]
+        );
+        assert!(parse_device_serials("", "device").is_empty());
+    }
+}
@@ -0,0 +1,137 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Results of autotest runs with test_that, read from the status.log of each
+//! test in the results directory.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::test_results::TestResult;
+use crate::test_results::TestStatus;
+
+fn timestamp(fields: &[&str]) -> Option<i64> {
+    fields
+        .iter()
+        .find_map(|f| f.strip_prefix("timestamp="))
+        .and_then(|t| t.parse().ok())
+}
+
+/// Parses status.log of a test_that run in `dir`, which has lines of tab
+/// separated fields (STATUS, subdir, test name, key=value..., reason) nested
+/// with tabs. A test has START, the statuses and END STATUS lines.
+pub fn parse_status_log(log: &str, dir: &Path) -> Vec<TestResult> {
+    let mut starts: HashMap<&str, i64> = HashMap::new();
+    let mut reasons: HashMap<&str, &str> = HashMap::new();
+    let mut results = Vec::new();
+    for line in log.lines() {
+        let fields: Vec<&str> = line.trim_start_matches('\t').split('\t').collect();
+        let [status, subdir, name, ..] = fields.as_slice() else {
+            continue;
+        };
+        // Entries of the jobs themselves
+        if *name == "----" || *subdir == "----" {
+            continue;
+        }
+        if *status == "START" {
+            if let Some(t) = timestamp(&fields) {
+                starts.insert(name, t);
+            }
+            continue;
+        }
+        let Some(status) = status.strip_prefix("END ") else {
+            if *status != "GOOD" && *status != "INFO" {
+                if let Some(reason) = fields.last().filter(|f| !f.is_empty() && !f.contains('=')) {
+                    reasons.entry(name).or_insert(reason);
+                }
+            }
+            continue;
+        };
+        let status = match status {
+            "GOOD" | "WARN" => TestStatus::Passed,
+            "TEST_NA" => TestStatus::Skipped,
+            _ => TestStatus::Failed,
+        };
+        let duration = match (starts.get(name), timestamp(&fields)) {
+            (Some(start), Some(end)) => (end - start).max(0) as f64,
+            _ => 0.0,
+        };
+        results.push(TestResult {
+            name: name.to_string(),
+            status,
+            duration,
+            error: reasons
+                .get(name)
+                .filter(|_| status != TestStatus::Passed)
+                .map(|r| r.to_string()),
+            log: Some(dir.join(subdir).join("debug").join(format!("{name}.DEBUG"))),
+            attempts: 1,
+        });
+    }
+    results
+}
+
+/// Reads the results of a test_that run from `results_dir`, which has a
+/// results-N-TEST directory for each test
+pub fn read_autotest_results(results_dir: &Path) -> Result<Vec<TestResult>> {
+    let mut dirs: Vec<_> = fs::read_dir(results_dir)?
+        .flatten()
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with("results-"))
+        })
+        .collect();
+    // Sort results-2-... before results-10-...
+    dirs.sort_by_key(|p| {
+        p.file_name()
+            .and_then(|n| n.to_string_lossy().split('-').nth(1)?.parse::<u32>().ok())
+    });
+    let mut results = Vec::new();
+    for dir in dirs {
+        if let Ok(log) = fs::read_to_string(dir.join("status.log")) {
+            results.extend(parse_status_log(&log, &dir));
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    #[test]
+    fn status_log() {
+        let log = "START\t----\t----\ttimestamp=1697364000\tlocaltime=Oct 15 10:00:00\t
+\tSTART\tdummy_Fail\tdummy_Fail\ttimestamp=1697364001\tlocaltime=Oct 15 10:00:01\t
+\t\tFAIL\tdummy_Fail\tdummy_Fail\ttimestamp=1697364004\tlocaltime=Oct 15 10:00:04\tboom
+\tEND FAIL\tdummy_Fail\tdummy_Fail\ttimestamp=1697364004\tlocaltime=Oct 15 10:00:04\t
+\tSTART\tdummy_Pass\tdummy_Pass\ttimestamp=1697364005\tlocaltime=Oct 15 10:00:05\t
+\t\tGOOD\tdummy_Pass\tdummy_Pass\ttimestamp=1697364007\tlocaltime=Oct 15 10:00:07\tcompleted \
+                   successfully
+\tEND GOOD\tdummy_Pass\tdummy_Pass\ttimestamp=1697364007\tlocaltime=Oct 15 10:00:07\t
+END GOOD\t----\t----\ttimestamp=1697364008\tlocaltime=Oct 15 10:00:08\t
+";
+        let results = parse_status_log(log, Path::new("/r/results-1"));
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, "dummy_Fail");
+        assert_eq!(results[0].status, TestStatus::Failed);
+        assert_eq!(results[0].duration, 3.0);
+        assert_eq!(results[0].error.as_deref(), Some("boom"));
+        assert_eq!(
+            results[0].log,
+            Some(PathBuf::from(
+                "/r/results-1/dummy_Fail/debug/dummy_Fail.DEBUG"
+            ))
+        );
+        assert_eq!(results[1].status, TestStatus::Passed);
+        assert_eq!(results[1].error, None);
+    }
+}
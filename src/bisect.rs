@@ -0,0 +1,154 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Bisection of release versions of ChromiumOS, to find the first version
+//! where a check fails.
+
+use regex_macro::regex;
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Verdict {
+    Good,
+    Bad,
+    /// The version could not be tested (e.g. the image is not available)
+    Skip,
+}
+impl std::fmt::Display for Verdict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Verdict::Good => "good",
+            Verdict::Bad => "bad",
+            Verdict::Skip => "skip",
+        })
+    }
+}
+
+/// Parses "15700.0.0" or "R120-15700.0.0" into the numbers of the version
+pub fn parse_version(version: &str) -> Option<[u32; 3]> {
+    let caps = regex!(r"(?:^|R\d+-)(\d+)\.(\d+)\.(\d+)/?$").captures(version.trim())?;
+    Some([
+        caps[1].parse().ok()?,
+        caps[2].parse().ok()?,
+        caps[3].parse().ok()?,
+    ])
+}
+
+/// Picks the release versions (e.g. "R120-15710.0.0") strictly between
+/// `good` and `bad` from a listing of
+/// gs://chromeos-image-archive/BOARD-release/, in order. The builds of release
+/// branches (e.g. 15700.10.0) are picked only if both `good` and `bad` are on
+/// the same branch.
+pub fn release_versions_between(listing: &str, good: &str, bad: &str) -> Vec<String> {
+    let (Some(lo), Some(hi)) = (parse_version(good), parse_version(bad)) else {
+        return Vec::new();
+    };
+    let same_branch = lo[0] == hi[0];
+    let mut versions: Vec<([u32; 3], String)> = regex!(r"R\d+-\d+\.\d+\.\d+")
+        .find_iter(listing)
+        .filter_map(|m| Some((parse_version(m.as_str())?, m.as_str().to_string())))
+        .filter(|(v, _)| lo < *v && *v < hi)
+        .filter(|(v, _)| {
+            if same_branch {
+                v[0] == lo[0]
+            } else {
+                v[1] == 0 && v[2] == 0
+            }
+        })
+        .collect();
+    versions.sort();
+    versions.dedup_by(|a, b| a.0 == b.0);
+    versions.into_iter().map(|(_, v)| v).collect()
+}
+
+/// State of a bisection over `versions`, where the first one is known to be
+/// good and the last one is known to be bad
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bisection {
+    pub versions: Vec<String>,
+    pub verdicts: Vec<Option<Verdict>>,
+}
+impl Bisection {
+    pub fn new(good: &str, candidates: &[String], bad: &str) -> Self {
+        let mut versions = vec![good.to_string()];
+        versions.extend(candidates.iter().cloned());
+        versions.push(bad.to_string());
+        let mut verdicts = vec![None; versions.len()];
+        verdicts[0] = Some(Verdict::Good);
+        verdicts[versions.len() - 1] = Some(Verdict::Bad);
+        Self { versions, verdicts }
+    }
+    /// Indices of the last good version and the first bad version after it
+    pub fn range(&self) -> (usize, usize) {
+        let bad = self
+            .verdicts
+            .iter()
+            .position(|v| *v == Some(Verdict::Bad))
+            .unwrap_or(self.versions.len() - 1);
+        let good = self.verdicts[..bad]
+            .iter()
+            .rposition(|v| *v == Some(Verdict::Good))
+            .unwrap_or(0);
+        (good, bad)
+    }
+    /// The untested version closest to the middle of the range, or None if
+    /// the bisection is done
+    pub fn next(&self) -> Option<usize> {
+        let (good, bad) = self.range();
+        let mid = (good + bad) / 2;
+        (good + 1..bad)
+            .filter(|i| self.verdicts[*i].is_none())
+            .min_by_key(|i| i.abs_diff(mid))
+    }
+    pub fn mark(&mut self, index: usize, verdict: Verdict) {
+        self.verdicts[index] = Some(verdict);
+    }
+    /// Rough number of the versions left to test
+    pub fn steps_left(&self) -> u32 {
+        let (good, bad) = self.range();
+        let untested = (good + 1..bad)
+            .filter(|i| self.verdicts[*i].is_none())
+            .count();
+        (untested + 1).next_power_of_two().trailing_zeros()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn bisection() {
+        let listing = "gs://chromeos-image-archive/brya-release/LATEST-main
+gs://chromeos-image-archive/brya-release/R120-15662.0.0/
+gs://chromeos-image-archive/brya-release/R121-15710.0.0/
+gs://chromeos-image-archive/brya-release/R121-15700.10.0/
+gs://chromeos-image-archive/brya-release/R121-15730.0.0/
+gs://chromeos-image-archive/brya-release/R121-15720.0.0/
+gs://chromeos-image-archive/brya-release/R122-15770.0.0/
+";
+        let versions = release_versions_between(listing, "15700.0.0", "15770.0.0");
+        assert_eq!(
+            versions,
+            vec!["R121-15710.0.0", "R121-15720.0.0", "R121-15730.0.0"]
+        );
+        assert_eq!(
+            release_versions_between(listing, "15700.0.0", "R121-15700.20.0"),
+            vec!["R121-15700.10.0"]
+        );
+
+        let mut b = Bisection::new("15700.0.0", &versions, "15770.0.0");
+        assert_eq!(b.steps_left(), 2);
+        assert_eq!(b.next(), Some(2));
+        b.mark(2, Verdict::Skip);
+        let next = b.next().unwrap();
+        assert!(next == 1 || next == 3);
+        b.mark(1, Verdict::Good);
+        b.mark(3, Verdict::Bad);
+        assert_eq!(b.range(), (1, 3));
+        assert_eq!(b.next(), None);
+    }
+}
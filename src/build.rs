@@ -0,0 +1,1466 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Building packages and images in the chroot. The output of emerge is parsed
+//! to show the progress and to time the packages, and the log of the failed
+//! ebuild is summarized on errors.
+//! The images built are recorded so that `cro3 flash` can find them.
+//! See `cro3 build`.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
+use regex_macro::regex;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::cache::KvCache;
+use crate::chroot::Chroot;
+use crate::dut::SshInfo;
+use crate::image_cache::format_size;
+use crate::image_cache::parse_size;
+use crate::progress::add_progress_bar;
+use crate::progress::format_duration;
+use crate::util::cro3_paths::gen_path_in_cro3_dir;
+
+/// Images built by `cro3 build image`, keyed by board/type
+static LOCAL_IMAGES: KvCache<LocalImage> = KvCache::new("local_images");
+
+/// Packages worked on (built from the source in the checkout instead of the
+/// prebuilts) per board, as of the last `cro3 workon` or build
+static WORKON_PACKAGES: KvCache<Vec<String>> = KvCache::new("workon_packages");
+
+/// Overrides of USE flags and FEATURES that the packages were built with last,
+/// keyed by board/package
+static PACKAGE_OVERRIDES: KvCache<BuildOverrides> = KvCache::new("package_overrides");
+
+/// Timings of the packages in the last successful build of each board
+static BUILD_PROFILES: KvCache<Vec<PackageTiming>> = KvCache::new("build_profiles");
+
+/// Number of the last lines of the failed ebuild log to show
+const FAILED_LOG_LINES: usize = 40;
+
+/// ccache of the chroot, shared by all the boards
+pub const CCACHE_DIR: &str = "/var/cache/distfiles/ccache";
+
+/// The whole output of emerge of the last build for `board`
+pub fn build_log_path(board: &str) -> Result<PathBuf> {
+    gen_path_in_cro3_dir(&format!("build_{board}.log"))
+}
+
+/// Binary packages built for `board`, which are reused instead of building
+/// them again
+pub fn binpkg_dir(board: &str) -> String {
+    format!("/build/{board}/packages")
+}
+
+/// Endpoint of the remote execution backend used by reclient
+const RBE_ENDPOINT: &str = "remotebuildexecution.googleapis.com:443";
+
+/// Where reproxy writes its logs and metrics in the chroot
+const REMOTEEXEC_LOG_DIR: &str = "/tmp/cro3_remoteexec";
+
+/// Whether to build with remote execution (reclient). It is used by the
+/// packages that support it, e.g. chromeos-chrome.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RemoteExec {
+    On,
+    Off,
+}
+impl FromStr for RemoteExec {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "on" => Ok(RemoteExec::On),
+            "off" => Ok(RemoteExec::Off),
+            _ => bail!("Unknown value: {s}. Use on or off"),
+        }
+    }
+}
+
+/// Options of build_packages()
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildOptions {
+    /// Run cros-workon start for the packages to build them from the source
+    /// in the checkout
+    pub workon: bool,
+    /// None to follow the configuration of the chroot
+    pub remoteexec: Option<RemoteExec>,
+    /// Show where the time of the build went
+    pub profile: bool,
+    pub overrides: BuildOverrides,
+}
+
+/// USE flags and FEATURES of portage added for a build, on top of the ones in
+/// make.conf (e.g. "asan -lto" adds asan and removes lto)
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BuildOverrides {
+    pub use_flags: Option<String>,
+    pub features: Option<String>,
+}
+impl BuildOverrides {
+    /// Checks that the flags are words with an optional "-", so that they can
+    /// be put in a script as is
+    pub fn new(use_flags: Option<&str>, features: Option<&str>) -> Result<Self> {
+        let flags = regex!(r"^-?[A-Za-z0-9][A-Za-z0-9_+@.-]*$");
+        for flag in use_flags
+            .iter()
+            .chain(features.iter())
+            .flat_map(|s| s.split_whitespace())
+        {
+            if !flags.is_match(flag) {
+                bail!("Invalid flag: {flag}");
+            }
+        }
+        let normalize = |s: Option<&str>| {
+            s.map(|s| s.split_whitespace().collect::<Vec<&str>>().join(" "))
+                .filter(|s| !s.is_empty())
+        };
+        Ok(Self {
+            use_flags: normalize(use_flags),
+            features: normalize(features),
+        })
+    }
+    pub fn is_empty(&self) -> bool {
+        self.use_flags.is_none() && self.features.is_none()
+    }
+    /// Lines of a script that apply the overrides to the commands after them.
+    /// Both variables are incremental in portage, so they are combined with
+    /// the ones in make.conf.
+    pub fn env_script(&self) -> String {
+        let mut script = String::new();
+        if let Some(use_flags) = &self.use_flags {
+            script.push_str(&format!("export USE='{use_flags}'\n"));
+        }
+        if let Some(features) = &self.features {
+            script.push_str(&format!("export FEATURES='{features}'\n"));
+        }
+        script
+    }
+}
+impl std::fmt::Display for BuildOverrides {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(use_flags) = &self.use_flags {
+            parts.push(format!("USE=\"{use_flags}\""));
+        }
+        if let Some(features) = &self.features {
+            parts.push(format!("FEATURES=\"{features}\""));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// Records the overrides that `packages` were built with for `board`
+fn record_package_overrides(
+    board: &str,
+    packages: &[String],
+    overrides: &BuildOverrides,
+) -> Result<()> {
+    for package in packages {
+        let key = format!("{board}/{package}");
+        if overrides.is_empty() {
+            PACKAGE_OVERRIDES.remove(&key)?;
+        } else {
+            PACKAGE_OVERRIDES.set(&key, overrides.clone())?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the packages of `board` built with overrides last, with the
+/// overrides
+pub fn recorded_package_overrides(board: &str) -> Result<BTreeMap<String, BuildOverrides>> {
+    let prefix = format!("{board}/");
+    Ok(PACKAGE_OVERRIDES
+        .entries()?
+        .into_iter()
+        .filter_map(|(key, overrides)| Some((key.strip_prefix(&prefix)?.to_string(), overrides)))
+        .collect())
+}
+
+/// Returns the lines of a script that configure remote execution
+pub fn remoteexec_env(remoteexec: Option<RemoteExec>) -> String {
+    match remoteexec {
+        None => String::new(),
+        Some(RemoteExec::Off) => "export USE_REMOTEEXEC=false\n".to_string(),
+        Some(RemoteExec::On) => format!(
+            "export USE_REMOTEEXEC=true\nexport RBE_log_dir={REMOTEEXEC_LOG_DIR}\nexport \
+             RBE_output_dir={REMOTEEXEC_LOG_DIR}\nrm -rf {REMOTEEXEC_LOG_DIR} && mkdir -p \
+             {REMOTEEXEC_LOG_DIR}\n"
+        ),
+    }
+}
+
+/// Returns an error if the remote execution backend is not reachable
+pub fn check_remoteexec_backend() -> Result<()> {
+    let addr = RBE_ENDPOINT
+        .to_socket_addrs()
+        .context(anyhow!("Failed to resolve {RBE_ENDPOINT}"))?
+        .next()
+        .context(anyhow!("No address of {RBE_ENDPOINT}"))?;
+    TcpStream::connect_timeout(&addr, Duration::from_secs(5)).context(anyhow!(
+        "The remote execution backend ({RBE_ENDPOINT}) is not reachable. Use --remoteexec off to \
+         build locally."
+    ))?;
+    Ok(())
+}
+
+/// Numbers of the actions run remotely (including the cache hits) and
+/// locally with remote execution
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RemoteExecStats {
+    pub remote: u64,
+    pub cache_hits: u64,
+    pub local: u64,
+}
+
+/// Parses the CompletionStatus counts in rbe_metrics.txt written by reproxy
+pub fn parse_rbe_metrics(metrics: &str) -> RemoteExecStats {
+    let mut stats = RemoteExecStats::default();
+    let mut in_completion_status = false;
+    let mut status = None;
+    for line in metrics.lines().map(|l| l.trim()) {
+        if line.starts_with("stats") {
+            in_completion_status = false;
+        } else if line == "name: \"CompletionStatus\"" {
+            in_completion_status = true;
+        } else if !in_completion_status {
+            continue;
+        } else if let Some(name) = line.strip_prefix("name:") {
+            status = Some(name.trim().trim_matches('"').to_string());
+        } else if let (Some(count), Some(name)) = (line.strip_prefix("count:"), &status) {
+            let count: u64 = count.trim().parse().unwrap_or(0);
+            match name.as_str() {
+                "CACHE_HIT" => stats.cache_hits += count,
+                "REMOTE_EXECUTION" | "RACING_REMOTE" => stats.remote += count,
+                "LOCAL_EXECUTION" | "RACING_LOCAL" | "LOCAL_FALLBACK" => stats.local += count,
+                _ => {}
+            }
+            status = None;
+        }
+    }
+    stats
+}
+
+/// Shows the numbers of the actions run remotely and locally in the last
+/// build with remote execution
+pub fn report_remoteexec_stats(chroot: &Chroot) -> Result<()> {
+    let Ok(metrics) =
+        chroot.exec_in_chroot(&["cat", &format!("{REMOTEEXEC_LOG_DIR}/rbe_metrics.txt")])
+    else {
+        info!("No actions were run with remote execution");
+        return Ok(());
+    };
+    let stats = parse_rbe_metrics(&metrics);
+    info!(
+        "Remote execution: {} remote actions ({} cache hits), {} local actions",
+        stats.remote + stats.cache_hits,
+        stats.cache_hits,
+        stats.local
+    );
+    Ok(())
+}
+
+/// Returns a script that builds `packages` for `board` in the chroot, with
+/// the dependencies from the prebuilts if possible. The packages are marked
+/// as worked on first if `workon` is true, to build them from the source in
+/// the checkout.
+pub fn emerge_script(board: &str, packages: &[String], workon: bool) -> String {
+    let packages = packages.join(" ");
+    let mut script = String::new();
+    if workon {
+        script.push_str(&format!("cros-workon-{board} start {packages}\n"));
+    }
+    script.push_str(&format!(
+        "emerge-{board} --jobs --usepkg --getbinpkg --with-bdeps=y --quiet-build=y {packages}\n"
+    ));
+    script
+}
+
+/// Notable lines in the output of emerge
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmergeEvent {
+    /// A package (e.g. "chromeos-base/foo-0.0.1-r1") started to be built, as
+    /// the `index`-th of `total`
+    Started {
+        index: usize,
+        total: usize,
+        package: String,
+    },
+    Completed {
+        index: usize,
+        total: usize,
+        package: String,
+    },
+    /// A package failed to be built, with the path of its log in the chroot
+    Failed {
+        package: String,
+        log: Option<String>,
+    },
+}
+
+/// Parses the output of emerge line by line. Some events span multiple
+/// lines, so the state is kept between the lines.
+#[derive(Debug, Default)]
+pub struct EmergeOutputParser {
+    /// The failed package whose log path is expected in the next line
+    failed: Option<String>,
+}
+impl EmergeOutputParser {
+    pub fn feed(&mut self, line: &str) -> Option<EmergeEvent> {
+        let line = line.trim();
+        if let Some(package) = self.failed.take() {
+            let log = line
+                .trim_start_matches(">>>")
+                .trim()
+                .trim_matches('\'')
+                .to_string();
+            return Some(EmergeEvent::Failed {
+                package,
+                log: log.ends_with(".log").then_some(log),
+            });
+        }
+        if let Some(rest) = line.strip_prefix(">>> Failed to emerge ") {
+            let package = rest
+                .split([' ', ','])
+                .next()
+                .unwrap_or_default()
+                .to_string();
+            if rest.ends_with("Log file:") {
+                self.failed = Some(package);
+                return None;
+            }
+            return Some(EmergeEvent::Failed { package, log: None });
+        }
+        let (rest, started) = if let Some(rest) = line
+            .strip_prefix(">>> Emerging binary ")
+            .or_else(|| line.strip_prefix(">>> Emerging "))
+        {
+            (rest, true)
+        } else if let Some(rest) = line.strip_prefix(">>> Completed ") {
+            (rest, false)
+        } else {
+            return None;
+        };
+        // e.g. "(1 of 3) chromeos-base/foo-0.0.1-r1::chromiumos for /build/brya/"
+        let (counts, rest) = rest.strip_prefix('(')?.split_once(") ")?;
+        let (index, total) = counts.split_once(" of ")?;
+        let index = index.trim().parse().ok()?;
+        let total = total.trim().parse().ok()?;
+        let package = rest
+            .split([' ', ':'])
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        Some(if started {
+            EmergeEvent::Started {
+                index,
+                total,
+                package,
+            }
+        } else {
+            EmergeEvent::Completed {
+                index,
+                total,
+                package,
+            }
+        })
+    }
+}
+
+/// When a package was built, in seconds since the start of the build
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackageTiming {
+    /// e.g. "chromeos-base/foo-0.0.1-r1"
+    pub package: String,
+    /// Position in the order of emerge, which follows the dependencies
+    pub index: usize,
+    pub start: f64,
+    pub end: f64,
+}
+impl PackageTiming {
+    pub fn duration(&self) -> f64 {
+        self.end - self.start
+    }
+    /// e.g. "chromeos-base/foo", to compare the builds of different versions
+    pub fn name(&self) -> &str {
+        package_name(&self.package)
+    }
+}
+
+/// Strips the version from a package, e.g. "chromeos-base/foo-0.0.1-r1" to
+/// "chromeos-base/foo"
+pub fn package_name(package: &str) -> &str {
+    regex!(r"^(.+?)-[0-9][^-]*(-r[0-9]+)?$")
+        .captures(package)
+        .and_then(|c| c.get(1))
+        .map_or(package, |m| m.as_str())
+}
+
+/// Records the timings of the packages from the events of emerge
+#[derive(Debug, Default)]
+pub struct BuildProfiler {
+    running: Vec<(usize, String, f64)>,
+    timings: Vec<PackageTiming>,
+}
+impl BuildProfiler {
+    /// Records `event` that happened `at` seconds since the start of the
+    /// build
+    pub fn record(&mut self, event: &EmergeEvent, at: f64) {
+        match event {
+            EmergeEvent::Started { index, package, .. } => {
+                self.running.push((*index, package.clone(), at))
+            }
+            EmergeEvent::Completed { package, .. } => {
+                if let Some(i) = self.running.iter().position(|(_, p, _)| p == package) {
+                    let (index, package, start) = self.running.remove(i);
+                    self.timings.push(PackageTiming {
+                        package,
+                        index,
+                        start,
+                        end: at,
+                    });
+                }
+            }
+            EmergeEvent::Failed { .. } => {}
+        }
+    }
+    pub fn timings(&self) -> &[PackageTiming] {
+        &self.timings
+    }
+}
+
+/// Returns the longest chain of packages built one after another, in the order
+/// of the build. This is not the critical path: emerge does not report the
+/// dependencies, so each package is only assumed to have waited for the one
+/// completed last before it started.
+pub fn longest_chain(timings: &[PackageTiming]) -> Vec<&PackageTiming> {
+    let mut path = Vec::new();
+    let mut last = timings.iter().max_by(|a, b| a.end.total_cmp(&b.end));
+    while let Some(t) = last {
+        path.push(t);
+        last = timings
+            .iter()
+            .filter(|p| p.end <= t.start)
+            .max_by(|a, b| a.end.total_cmp(&b.end));
+    }
+    path.reverse();
+    path
+}
+
+/// Formats the slowest packages and the longest chain of packages of a build,
+/// with the differences from the `previous` build of the same packages. Only
+/// emerge is timed, so `build image` is not covered.
+pub fn format_build_profile(
+    timings: &[PackageTiming],
+    previous: Option<&[PackageTiming]>,
+) -> String {
+    let previous_duration = |t: &PackageTiming| {
+        previous?
+            .iter()
+            .find(|p| p.name() == t.name())
+            .map(PackageTiming::duration)
+    };
+    let format_timing = |t: &PackageTiming| {
+        let diff = match previous_duration(t) {
+            Some(d) => format!(" ({:+.0}s)", t.duration() - d),
+            None => String::new(),
+        };
+        format!(
+            "  {:>7}{diff:<8} {}\n",
+            format_duration(t.duration()),
+            t.package
+        )
+    };
+    let total = |timings: &[PackageTiming]| timings.iter().map(|t| t.end).fold(0.0, f64::max);
+    let mut report = format!(
+        "Build time: {} for {} packages",
+        format_duration(total(timings)),
+        timings.len()
+    );
+    if let Some(previous) = previous {
+        report.push_str(&format!(
+            " ({} in the previous build)",
+            format_duration(total(previous))
+        ));
+    }
+    report.push_str("\nSlowest packages:\n");
+    let mut slowest: Vec<&PackageTiming> = timings.iter().collect();
+    slowest.sort_by(|a, b| b.duration().total_cmp(&a.duration()));
+    for t in slowest.iter().take(10) {
+        report.push_str(&format_timing(t));
+    }
+    report.push_str("Longest chain of packages:\n");
+    for t in longest_chain(timings) {
+        report.push_str(&format_timing(t));
+    }
+    report
+}
+
+/// Records the timings of a build of `board`, and shows them if `show` is
+/// true
+fn save_build_profile(board: &str, timings: &[PackageTiming], show: bool) -> Result<()> {
+    if show {
+        let previous = BUILD_PROFILES.get(board)?;
+        println!("{}", format_build_profile(timings, previous.as_deref()));
+    }
+    BUILD_PROFILES.set(board, timings.to_vec())
+}
+
+/// Builds `packages` for `board` in the chroot, showing the progress instead
+/// of the whole output of emerge, which is saved to ~/.cro3/build_BOARD.log.
+/// Builds for different boards can run in parallel.
+pub fn build_packages(
+    chroot: &Chroot,
+    board: &str,
+    packages: &[String],
+    options: &BuildOptions,
+) -> Result<()> {
+    if packages.is_empty() {
+        bail!("No packages to build");
+    }
+    if options.remoteexec == Some(RemoteExec::On) {
+        check_remoteexec_backend()?;
+    }
+    if !options.overrides.is_empty() {
+        warn!(
+            "Building with {}. The packages stay installed with it for the images built later.",
+            options.overrides
+        );
+    }
+    warn_workon_packages(board, packages)?;
+    let ccache_before = check_ccache(chroot)
+        .map_err(|e| warn!("Failed to check ccache: {e:#}"))
+        .ok();
+    let log_path = build_log_path(board)?;
+    let mut log = File::create(&log_path).context(anyhow!("Failed to create {log_path:?}"))?;
+    let bar = add_progress_bar(ProgressBar::new_spinner());
+    bar.set_style(ProgressStyle::with_template(
+        "{spinner} {prefix}: {msg} [{elapsed}]",
+    )?);
+    bar.set_prefix(board.to_string());
+    bar.set_message("resolving dependencies");
+    bar.enable_steady_tick(Duration::from_millis(200));
+
+    let mut parser = EmergeOutputParser::default();
+    let mut profiler = BuildProfiler::default();
+    let start = Instant::now();
+    let mut failed = Vec::new();
+    let result = chroot.run_bash_script_in_chroot_with_lines(
+        &format!("build_pkg_{board}"),
+        &format!(
+            "{}{}{}",
+            remoteexec_env(options.remoteexec),
+            options.overrides.env_script(),
+            emerge_script(board, packages, options.workon)
+        ),
+        |line| {
+            let _ = writeln!(log, "{line}");
+            let event = parser.feed(line);
+            if let Some(event) = &event {
+                profiler.record(event, start.elapsed().as_secs_f64());
+            }
+            match event {
+                Some(EmergeEvent::Started {
+                    index,
+                    total,
+                    package,
+                }) => bar.set_message(format!("[{index}/{total}] {package}")),
+                Some(EmergeEvent::Completed {
+                    index,
+                    total,
+                    package,
+                }) => bar.println(format!("[{index}/{total}] built {package}")),
+                Some(EmergeEvent::Failed { package, log }) => {
+                    bar.println(format!("failed to build {package}"));
+                    failed.push((package, log));
+                }
+                None => {}
+            }
+        },
+    );
+    if options.workon {
+        // Record the packages started by the script
+        if let Err(e) = list_workon_packages(chroot, board) {
+            warn!("Failed to list the packages worked on: {e:#}");
+        }
+    }
+    if result.is_ok() {
+        bar.finish_and_clear();
+        info!("Built {} for {board}", packages.join(" "));
+        record_package_overrides(board, packages, &options.overrides)?;
+        if let Err(e) = save_build_profile(board, profiler.timings(), options.profile) {
+            warn!("Failed to save the timings of the build: {e:#}");
+        }
+        if let (Some(before), Ok(after)) = (ccache_before, ccache_stats(chroot)) {
+            let stats = after.since(&before);
+            if let Some(rate) = stats.hit_rate() {
+                info!(
+                    "ccache: {} hits, {} misses ({:.1}% hit rate)",
+                    stats.hits,
+                    stats.misses,
+                    rate * 100.0
+                );
+            }
+        }
+        if options.remoteexec == Some(RemoteExec::On) {
+            report_remoteexec_stats(chroot)?;
+        }
+        return Ok(());
+    }
+    bar.abandon_with_message("failed");
+    for (package, log) in &failed {
+        let Some(log) = log else {
+            continue;
+        };
+        // The log is in the chroot, whose location on the host varies
+        match chroot.exec_in_chroot(&["cat", log]) {
+            Ok(content) => print_build_failure(package, log, &content),
+            Err(e) => error!("Failed to read the log of {package} ({log}): {e}"),
+        }
+    }
+    result.context(match failed.first() {
+        Some((package, _)) => {
+            anyhow!("Failed to build {package}. See {log_path:?} for the output of emerge.")
+        }
+        None => anyhow!("Failed to build packages. See {log_path:?} for the output of emerge."),
+    })
+}
+
+/// The first error found in the log of a failed ebuild
+#[derive(Debug, Clone, PartialEq)]
+pub struct BuildError {
+    pub file: String,
+    pub line: u32,
+    pub message: String,
+}
+
+/// What is found in the log of a failed ebuild
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BuildLogSummary {
+    /// e.g. "compile"
+    pub phase: Option<String>,
+    pub error: Option<BuildError>,
+}
+
+/// Finds the failed phase and the first compiler error (of C/C++, Rust or Go)
+/// in the log of a failed ebuild
+pub fn summarize_build_log(log: &str) -> BuildLogSummary {
+    let mut summary = BuildLogSummary::default();
+    // e.g. "../foo/bar.cc:12:5: error: use of undeclared identifier 'x'"
+    let re_cc = regex!(r"^(\S+?):(\d+):(?:\d+:)? (?:fatal )?error: (.*)$");
+    // e.g. "error[E0425]: cannot find value `x` in this scope" followed by
+    // "  --> src/main.rs:2:5"
+    let re_rust_location = regex!(r"^\s*--> (\S+?):(\d+):\d+$");
+    // e.g. "foo/bar.go:12:5: undefined: x"
+    let re_go = regex!(r"^(\S+?\.go):(\d+):\d+: (.*)$");
+    // e.g. " * ERROR: chromeos-base/foo-9999::chromiumos failed (compile phase):"
+    let re_phase = regex!(r"ERROR: \S+ failed \((\w+) phase\)");
+    let mut rust_error = None;
+    for line in log.lines() {
+        if summary.phase.is_none() {
+            if let Some(c) = re_phase.captures(line) {
+                summary.phase = Some(c[1].to_string());
+            }
+        }
+        if summary.error.is_some() {
+            continue;
+        }
+        if let Some(c) = re_cc.captures(line).or_else(|| re_go.captures(line)) {
+            summary.error = Some(BuildError {
+                file: c[1].to_string(),
+                line: c[2].parse().unwrap_or_default(),
+                message: c[3].trim().to_string(),
+            });
+        } else if line.starts_with("error[") || line.starts_with("error: ") {
+            rust_error = Some(line.trim().to_string());
+        } else if let (Some(message), Some(c)) = (&rust_error, re_rust_location.captures(line)) {
+            summary.error = Some(BuildError {
+                file: c[1].to_string(),
+                line: c[2].parse().unwrap_or_default(),
+                message: message.clone(),
+            });
+        }
+    }
+    summary
+}
+
+/// Shows the summary of the log of a failed ebuild, or the last lines of it
+/// if no compiler error is found
+fn print_build_failure(package: &str, log_path: &str, log: &str) {
+    let summary = summarize_build_log(log);
+    let phase = summary
+        .phase
+        .map(|p| format!(" in the {p} phase"))
+        .unwrap_or_default();
+    match summary.error {
+        Some(e) => error!(
+            "Failed to build {package}{phase}\n  {}:{}: {}\n  log: {log_path} (in the chroot)",
+            e.file, e.line, e.message
+        ),
+        None => {
+            let lines: Vec<&str> = log.lines().collect();
+            error!(
+                "Failed to build {package}{phase}. Last lines of {log_path} (in the chroot):\n{}",
+                lines[lines.len().saturating_sub(FAILED_LOG_LINES)..].join("\n")
+            );
+        }
+    }
+}
+
+/// Type of the images built by build_image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BuildImageType {
+    Test,
+    Dev,
+    Base,
+    /// Factory install shims
+    Factory,
+}
+impl FromStr for BuildImageType {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "test" => Ok(BuildImageType::Test),
+            "dev" => Ok(BuildImageType::Dev),
+            "base" => Ok(BuildImageType::Base),
+            "factory" => Ok(BuildImageType::Factory),
+            _ => bail!("Unknown image type: {s}. Use test, dev, base or factory"),
+        }
+    }
+}
+impl BuildImageType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BuildImageType::Test => "test",
+            BuildImageType::Dev => "dev",
+            BuildImageType::Base => "base",
+            BuildImageType::Factory => "factory",
+        }
+    }
+    /// Argument of build_image
+    fn build_image_arg(&self) -> &'static str {
+        match self {
+            BuildImageType::Factory => "factory_install",
+            _ => self.as_str(),
+        }
+    }
+    /// Name of the image file in the output directory of build_image
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            BuildImageType::Test => "chromiumos_test_image.bin",
+            BuildImageType::Dev => "chromiumos_image.bin",
+            BuildImageType::Base => "chromiumos_base_image.bin",
+            BuildImageType::Factory => "factory_install_shim.bin",
+        }
+    }
+}
+
+/// An image built locally
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocalImage {
+    pub path: PathBuf,
+    /// Unix time
+    pub built_at: i64,
+    /// Packages in the image built with overrides of USE flags or FEATURES
+    #[serde(default)]
+    pub package_overrides: BTreeMap<String, BuildOverrides>,
+}
+
+/// Returns the image of `image_type` built last for `board` by `cro3 build
+/// image`, if it still exists
+pub fn recorded_local_image(board: &str, image_type: BuildImageType) -> Result<Option<LocalImage>> {
+    Ok(LOCAL_IMAGES
+        .get(&format!("{board}/{}", image_type.as_str()))?
+        .filter(|image| image.path.exists()))
+}
+
+/// Builds an image of `image_type` for `board` with the packages built
+/// before, and returns the path to it. The path is recorded for
+/// recorded_local_image().
+pub fn build_image(
+    chroot: &Chroot,
+    repo: &str,
+    board: &str,
+    image_type: BuildImageType,
+    enable_rootfs_verification: bool,
+) -> Result<PathBuf> {
+    let verification = if enable_rootfs_verification {
+        ""
+    } else {
+        "--noenable_rootfs_verification"
+    };
+    warn_workon_packages(board, &[])?;
+    let package_overrides = recorded_package_overrides(board)?;
+    for (package, overrides) in &package_overrides {
+        info!("The image includes {package} built with {overrides}");
+    }
+    chroot.run_bash_script_in_chroot(
+        "build_image",
+        &format!(
+            "build_image --board={board} {verification} {}",
+            image_type.build_image_arg()
+        ),
+        None,
+    )?;
+    // build_image points "latest" to the output directory of the last build
+    let latest = Path::new(repo).join(format!("src/build/images/{board}/latest"));
+    let dir = latest
+        .canonicalize()
+        .context(anyhow!("Failed to resolve {latest:?}"))?;
+    let path = dir.join(image_type.file_name());
+    if !path.exists() {
+        bail!("{path:?} is not found after build_image");
+    }
+    LOCAL_IMAGES.set(
+        &format!("{board}/{}", image_type.as_str()),
+        LocalImage {
+            path: path.clone(),
+            built_at: chrono::Local::now().timestamp(),
+            package_overrides,
+        },
+    )?;
+    Ok(path)
+}
+
+/// Returns the kernel package (e.g. "sys-kernel/chromeos-kernel-5_15") in the
+/// output of `equery -q list 'sys-kernel/chromeos-kernel-*'`
+pub fn parse_installed_kernel(output: &str) -> Option<String> {
+    let re = regex!(r"^(sys-kernel/chromeos-kernel-\d+_\d+)-\d");
+    output
+        .lines()
+        .find_map(|l| re.captures(l.trim()).map(|c| c[1].to_string()))
+}
+
+/// Returns the kernel package installed in the sysroot of `board`
+pub fn kernel_package_of_board(chroot: &Chroot, board: &str) -> Result<String> {
+    let output = chroot
+        .exec_in_chroot(&[
+            &format!("equery-{board}"),
+            "-q",
+            "list",
+            "sys-kernel/chromeos-kernel-*",
+        ])
+        .unwrap_or_default();
+    parse_installed_kernel(&output).context(anyhow!(
+        "No kernel package is installed for {board}. Please run build_packages first, or specify \
+         the kernel package."
+    ))
+}
+
+/// Writes the kernel built for `board` and its modules to the DUT with
+/// update_kernel.sh, which updates the kernel partition and reboots the DUT.
+/// `target` must be reachable from the chroot (see SshInfo::into_forwarded).
+pub fn update_kernel(
+    chroot: &Chroot,
+    board: &str,
+    target: &SshInfo,
+    ab_update: bool,
+) -> Result<()> {
+    chroot.run_bash_script_in_chroot(
+        "update_kernel",
+        &format!(
+            r###"
+TOPDIR=~/trunk
+[ -d $TOPDIR ] || TOPDIR=~/chromiumos
+$TOPDIR/src/scripts/update_kernel.sh --board={board} {} --remote={} --ssh_port {} --remote_bootargs
+"###,
+            if ab_update { "--ab_update" } else { "" },
+            target.host(),
+            target.port()
+        ),
+        None,
+    )?;
+    Ok(())
+}
+
+fn workon_target(board: &str) -> String {
+    if board == "host" {
+        "--host".to_string()
+    } else {
+        format!("--build-target={board}")
+    }
+}
+
+/// Returns the packages worked on for `board`, and records them
+pub fn list_workon_packages(chroot: &Chroot, board: &str) -> Result<Vec<String>> {
+    let packages: Vec<String> = chroot
+        .exec_in_chroot(&["cros", "workon", &workon_target(board), "list"])?
+        .lines()
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect();
+    WORKON_PACKAGES.set(board, packages.clone())?;
+    Ok(packages)
+}
+
+/// Starts (or stops if `start` is false) working on `packages` for `board`,
+/// or all of them if `packages` is empty. Returns the packages worked on
+/// after that.
+pub fn set_workon(
+    chroot: &Chroot,
+    board: &str,
+    packages: &[String],
+    start: bool,
+) -> Result<Vec<String>> {
+    let target = workon_target(board);
+    let mut cmd = vec![
+        "cros",
+        "workon",
+        &target,
+        if start { "start" } else { "stop" },
+    ];
+    if packages.is_empty() {
+        cmd.push("--all");
+    }
+    cmd.extend(packages.iter().map(|p| p.as_str()));
+    chroot.exec_in_chroot(&cmd)?;
+    list_workon_packages(chroot, board)
+}
+
+/// Returns the packages worked on for `board` as of the last `cro3 workon` or
+/// build
+pub fn recorded_workon_packages(board: &str) -> Result<Vec<String>> {
+    Ok(WORKON_PACKAGES.get(board)?.unwrap_or_default())
+}
+
+/// Returns the packages in `workon` that are not in `building`, which are
+/// names with or without the category (e.g. "chromeos-base/foo" or "foo")
+pub fn other_workon_packages(workon: &[String], building: &[String]) -> Vec<String> {
+    workon
+        .iter()
+        .filter(|w| {
+            !building
+                .iter()
+                .any(|b| *w == b || w.rsplit('/').next() == Some(b.as_str()))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Warns if packages other than `building` are worked on for `board`, since
+/// they are built from the source in the checkout, which may not match the
+/// prebuilts of the synced version
+pub fn warn_workon_packages(board: &str, building: &[String]) -> Result<()> {
+    let others = other_workon_packages(&recorded_workon_packages(board)?, building);
+    if !others.is_empty() {
+        warn!(
+            "{} packages are worked on for {board}, and they shadow the prebuilts: {}. Run `cro3 \
+             workon stop --board {board}` if they are not needed.",
+            others.len(),
+            others.join(" ")
+        );
+    }
+    Ok(())
+}
+
+/// Boards with packages worked on, as of the last `cro3 workon` or build
+pub fn boards_with_workon_packages() -> Result<Vec<(String, Vec<String>)>> {
+    let mut boards: Vec<(String, Vec<String>)> = WORKON_PACKAGES
+        .entries()?
+        .into_iter()
+        .filter(|(_, packages)| !packages.is_empty())
+        .collect();
+    boards.sort();
+    Ok(boards)
+}
+
+/// Boards set up in the chroot
+pub fn list_chroot_boards(chroot: &Chroot) -> Result<Vec<String>> {
+    Ok(chroot
+        .exec_in_chroot(&["ls", "/build"])?
+        .split_whitespace()
+        .map(|s| s.to_string())
+        .filter(|s| s != "bin")
+        .collect())
+}
+
+/// Returns the size of each of `dirs` in the chroot, or None if it does not
+/// exist
+pub fn chroot_dir_sizes(chroot: &Chroot, dirs: &[String]) -> Result<Vec<Option<u64>>> {
+    let script = dirs
+        .iter()
+        .map(|d| format!("echo $(sudo du -sb {d} 2>/dev/null | cut -f1)"))
+        .collect::<Vec<String>>()
+        .join("; ");
+    let output = chroot.exec_in_chroot(&["bash", "-c", &script])?;
+    Ok(output.lines().map(|l| l.trim().parse().ok()).collect())
+}
+
+/// Hits, misses and the size of ccache
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CcacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+impl CcacheStats {
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        (total > 0).then_some(self.hits as f64 / total as f64)
+    }
+    /// ccache evicts the old entries when it is full, which lowers the hit
+    /// rate
+    pub fn is_nearly_full(&self) -> bool {
+        match (self.size, self.max_size) {
+            (Some(size), Some(max_size)) => max_size > 0 && size * 10 >= max_size * 9,
+            _ => false,
+        }
+    }
+    /// Returns the hits and the misses since `before`
+    pub fn since(&self, before: &CcacheStats) -> CcacheStats {
+        CcacheStats {
+            hits: self.hits.saturating_sub(before.hits),
+            misses: self.misses.saturating_sub(before.misses),
+            size: self.size,
+            max_size: self.max_size,
+        }
+    }
+}
+
+/// Parses the output of `ccache -s` of ccache 3 ("cache hit (direct) 70",
+/// "cache size 1.2 GB") or 4 ("Hits: 80 / 120 (66.67 %)", "Cache size (GB):
+/// 1.2 / 5.0 (24.00 %)")
+pub fn parse_ccache_stats(output: &str) -> CcacheStats {
+    let mut stats = CcacheStats::default();
+    for line in output.lines() {
+        let line = line.trim();
+        let first_number = |s: &str| -> u64 {
+            s.split_whitespace()
+                .next()
+                .and_then(|n| n.parse().ok())
+                .unwrap_or(0)
+        };
+        if let Some(rest) = line.strip_prefix("Hits:") {
+            stats.hits = first_number(rest);
+        } else if let Some(rest) = line.strip_prefix("Misses:") {
+            stats.misses = first_number(rest);
+        } else if let Some(rest) = line
+            .strip_prefix("cache hit (direct)")
+            .or_else(|| line.strip_prefix("cache hit (preprocessed)"))
+        {
+            stats.hits += first_number(rest);
+        } else if let Some(rest) = line.strip_prefix("cache miss") {
+            stats.misses += first_number(rest);
+        } else if let Some(rest) = line.strip_prefix("cache size") {
+            stats.size = parse_size(rest).ok();
+        } else if let Some(rest) = line.strip_prefix("max cache size") {
+            stats.max_size = parse_size(rest).ok();
+        } else if let Some(rest) = line.strip_prefix("Cache size (") {
+            let Some((unit, rest)) = rest.split_once("):") else {
+                continue;
+            };
+            let mut sizes = rest.split('/').map(|s| {
+                let n = s.split_whitespace().next().unwrap_or_default();
+                parse_size(&format!("{n}{unit}")).ok()
+            });
+            stats.size = sizes.next().flatten();
+            stats.max_size = sizes.next().flatten();
+        }
+    }
+    stats
+}
+
+pub fn ccache_stats(chroot: &Chroot) -> Result<CcacheStats> {
+    let output =
+        chroot.exec_in_chroot(&["env", &format!("CCACHE_DIR={CCACHE_DIR}"), "ccache", "-s"])?;
+    Ok(parse_ccache_stats(&output))
+}
+
+/// Marks the lines added to make.conf.user by cro3
+const MAKE_CONF_MARKER: &str = "# Added by cro3";
+const MAKE_CONF_USER: &str = "/etc/make.conf.user";
+
+/// Returns true if ccache is enabled in FEATURES of portage in the chroot
+pub fn ccache_enabled(chroot: &Chroot) -> Result<bool> {
+    let features = chroot.exec_in_chroot(&["portageq", "envvar", "FEATURES"])?;
+    Ok(features.split_whitespace().any(|f| f == "ccache"))
+}
+
+/// Enables or disables ccache for the builds in the chroot by (un)setting
+/// FEATURES=-ccache in make.conf.user, and sets the maximum size of ccache
+pub fn configure_ccache(
+    chroot: &Chroot,
+    enable: Option<bool>,
+    max_size: Option<&str>,
+) -> Result<()> {
+    if let Some(enable) = enable {
+        let mut script = format!(
+            "sudo touch {MAKE_CONF_USER} && sudo sed -i '/{MAKE_CONF_MARKER}$/d' {MAKE_CONF_USER}"
+        );
+        if !enable {
+            script.push_str(&format!(
+                " && echo 'FEATURES=\"${{FEATURES}} -ccache\" {MAKE_CONF_MARKER}' | sudo tee -a \
+                 {MAKE_CONF_USER} >/dev/null"
+            ));
+        }
+        chroot.exec_in_chroot(&["bash", "-c", &script])?;
+    }
+    if let Some(max_size) = max_size {
+        chroot.exec_in_chroot(&[
+            "sudo",
+            "env",
+            &format!("CCACHE_DIR={CCACHE_DIR}"),
+            "ccache",
+            "-M",
+            max_size,
+        ])?;
+    }
+    Ok(())
+}
+
+/// Returns the statistics of ccache, warning if it is disabled or nearly full
+pub fn check_ccache(chroot: &Chroot) -> Result<CcacheStats> {
+    if !ccache_enabled(chroot)? {
+        warn!(
+            "ccache is disabled, so everything is compiled from scratch. Run `cro3 build cache \
+             ccache --enable` to enable it."
+        );
+    }
+    let stats = ccache_stats(chroot)?;
+    if stats.is_nearly_full() {
+        warn!(
+            "ccache is nearly full ({} of {}), so old entries are evicted. Run `cro3 build cache \
+             ccache --max-size` to enlarge it.",
+            format_size(stats.size.unwrap_or_default()),
+            format_size(stats.max_size.unwrap_or_default())
+        );
+    }
+    Ok(stats)
+}
+
+/// Returns how many packages were installed from the binary packages and how
+/// many were built from the source, in the output of emerge
+pub fn count_binpkg_usage(output: &str) -> (usize, usize) {
+    let mut binary = 0;
+    let mut source = 0;
+    for line in output.lines() {
+        if line.starts_with(">>> Emerging binary (") {
+            binary += 1;
+        } else if line.starts_with(">>> Emerging (") {
+            source += 1;
+        }
+    }
+    (binary, source)
+}
+
+/// Removes the binary packages of `board` in the chroot
+pub fn clear_binpkgs(chroot: &Chroot, board: &str) -> Result<()> {
+    chroot
+        .exec_in_chroot(&["sudo", "rm", "-rf", &binpkg_dir(board)])
+        .map(|_| ())
+}
+
+/// Removes all the entries of ccache, which is shared by all the boards
+pub fn clear_ccache(chroot: &Chroot) -> Result<()> {
+    chroot
+        .exec_in_chroot(&["env", &format!("CCACHE_DIR={CCACHE_DIR}"), "ccache", "-C"])
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn emerge_output_parser() {
+        let mut parser = EmergeOutputParser::default();
+        assert_eq!(
+            parser.feed(
+                ">>> Emerging (1 of 3) chromeos-base/foo-0.0.1-r12::chromiumos for /build/brya/"
+            ),
+            Some(EmergeEvent::Started {
+                index: 1,
+                total: 3,
+                package: "chromeos-base/foo-0.0.1-r12".to_string()
+            })
+        );
+        assert_eq!(
+            parser.feed(
+                ">>> Emerging binary (2 of 3) dev-libs/bar-1.2::portage-stable for /build/brya/"
+            ),
+            Some(EmergeEvent::Started {
+                index: 2,
+                total: 3,
+                package: "dev-libs/bar-1.2".to_string()
+            })
+        );
+        assert_eq!(
+            parser.feed(
+                ">>> Completed (1 of 3) chromeos-base/foo-0.0.1-r12::chromiumos for /build/brya/"
+            ),
+            Some(EmergeEvent::Completed {
+                index: 1,
+                total: 3,
+                package: "chromeos-base/foo-0.0.1-r12".to_string()
+            })
+        );
+        assert_eq!(parser.feed(">>> Jobs: 1 of 3 complete, 1 running"), None);
+        assert_eq!(
+            parser.feed(">>> Failed to emerge chromeos-base/baz-9999 for /build/brya/, Log file:"),
+            None
+        );
+        assert_eq!(
+            parser.feed(
+                ">>>  '/build/brya/tmp/portage/logs/chromeos-base:baz-9999:20231010-101010.log'"
+            ),
+            Some(EmergeEvent::Failed {
+                package: "chromeos-base/baz-9999".to_string(),
+                log: Some(
+                    "/build/brya/tmp/portage/logs/chromeos-base:baz-9999:20231010-101010.log"
+                        .to_string()
+                )
+            })
+        );
+        assert_eq!(
+            parser.feed("emerge: there are no ebuilds to satisfy \"foo\"."),
+            None
+        );
+    }
+
+    #[test]
+    fn ccache_stats_of_both_versions() {
+        let ccache3 = "cache directory                     /var/cache/distfiles/ccache
+cache hit (direct)                    70
+cache hit (preprocessed)              10
+cache miss                            40
+cache size                           4.6 GB
+max cache size                       5.0 GB";
+        let stats = parse_ccache_stats(ccache3);
+        assert_eq!(
+            stats,
+            CcacheStats {
+                hits: 80,
+                misses: 40,
+                size: Some(parse_size("4.6G").unwrap()),
+                max_size: Some(5 << 30),
+            }
+        );
+        assert!(stats.is_nearly_full());
+        let ccache4 = "Cacheable calls:   120 / 130 (92.31%)
+  Hits:             80 / 120 (66.67%)
+    Direct:         70 /  80 (87.50%)
+    Preprocessed:   10 /  80 (12.50%)
+  Misses:           40 / 120 (33.33%)
+Local storage:
+  Cache size (GB):  4.6 / 5.0 (92.00%)";
+        assert_eq!(parse_ccache_stats(ccache4), stats);
+        let before = CcacheStats {
+            hits: 60,
+            misses: 30,
+            ..Default::default()
+        };
+        assert_eq!(
+            (stats.since(&before).hits, stats.since(&before).misses),
+            (20, 10)
+        );
+        assert_eq!(stats.hit_rate().map(|r| (r * 1000.0).round()), Some(667.0));
+        assert_eq!(parse_ccache_stats("").hit_rate(), None);
+    }
+
+    #[test]
+    fn binpkg_usage() {
+        assert_eq!(
+            count_binpkg_usage(
+                ">>> Emerging binary (1 of 3) dev-libs/bar-1.2::portage-stable
+>>> Emerging (2 of 3) chromeos-base/foo-0.0.1-r12::chromiumos
+>>> Emerging binary (3 of 3) dev-libs/baz-1.0::portage-stable"
+            ),
+            (2, 1)
+        );
+    }
+
+    #[test]
+    fn rbe_metrics() {
+        let metrics = r#"stats: {
+  name: "CompletionStatus"
+  counts_by_value: {
+    name: "CACHE_HIT"
+    count: 120
+  }
+  counts_by_value: {
+    name: "REMOTE_EXECUTION"
+    count: 30
+  }
+  counts_by_value: {
+    name: "LOCAL_FALLBACK"
+    count: 2
+  }
+}
+stats: {
+  name: "LocalMetadata.ExecutedLocally"
+  counts_by_value: {
+    name: "true"
+    count: 7
+  }
+}"#;
+        assert_eq!(
+            parse_rbe_metrics(metrics),
+            RemoteExecStats {
+                remote: 30,
+                cache_hits: 120,
+                local: 2
+            }
+        );
+    }
+
+    #[test]
+    fn installed_kernel() {
+        assert_eq!(
+            parse_installed_kernel("sys-kernel/chromeos-kernel-5_15-5.15.120-r1234\n"),
+            Some("sys-kernel/chromeos-kernel-5_15".to_string())
+        );
+        assert_eq!(
+            parse_installed_kernel("sys-kernel/linux-headers-4.14-r1"),
+            None
+        );
+    }
+
+    #[test]
+    fn other_workon_packages_of_workon() {
+        let workon = vec![
+            "chromeos-base/foo".to_string(),
+            "sys-kernel/chromeos-kernel-5_15".to_string(),
+        ];
+        assert_eq!(
+            other_workon_packages(&workon, &["foo".to_string()]),
+            vec!["sys-kernel/chromeos-kernel-5_15"]
+        );
+        assert_eq!(
+            other_workon_packages(&workon, &workon),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn build_log_summary() {
+        let log = r#" * Package:    chromeos-base/foo-9999:0
+>>> Compiling source in /build/brya/tmp/portage/chromeos-base/foo-9999/work/foo-9999 ...
+../foo/bar.cc:10:3: warning: unused variable 'y' [-Wunused-variable]
+../foo/bar.cc:12:5: error: use of undeclared identifier 'x'
+../foo/bar.cc:20:5: error: use of undeclared identifier 'z'
+ * ERROR: chromeos-base/foo-9999::chromiumos failed (compile phase):
+ *   ninja failed"#;
+        assert_eq!(
+            summarize_build_log(log),
+            BuildLogSummary {
+                phase: Some("compile".to_string()),
+                error: Some(BuildError {
+                    file: "../foo/bar.cc".to_string(),
+                    line: 12,
+                    message: "use of undeclared identifier 'x'".to_string()
+                })
+            }
+        );
+        let log = "error[E0425]: cannot find value `x` in this scope
+ --> src/main.rs:2:5
+  |";
+        assert_eq!(
+            summarize_build_log(log).error,
+            Some(BuildError {
+                file: "src/main.rs".to_string(),
+                line: 2,
+                message: "error[E0425]: cannot find value `x` in this scope".to_string()
+            })
+        );
+        assert_eq!(
+            summarize_build_log("foo/bar.go:7:2: undefined: x")
+                .error
+                .map(|e| e.line),
+            Some(7)
+        );
+        assert_eq!(summarize_build_log("all good"), BuildLogSummary::default());
+    }
+
+    #[test]
+    fn emerge_script_starts_workon() {
+        assert!(emerge_script("brya", &["foo".to_string()], true)
+            .starts_with("cros-workon-brya start foo\n"));
+    }
+
+    #[test]
+    fn build_overrides() {
+        let overrides = BuildOverrides::new(Some(" asan  -lto "), Some("-sandbox")).unwrap();
+        assert_eq!(
+            overrides.env_script(),
+            "export USE='asan -lto'\nexport FEATURES='-sandbox'\n"
+        );
+        assert_eq!(
+            overrides.to_string(),
+            "USE=\"asan -lto\" FEATURES=\"-sandbox\""
+        );
+        assert!(BuildOverrides::new(Some(""), None).unwrap().is_empty());
+        assert!(BuildOverrides::new(Some("asan'; rm -rf /"), None).is_err());
+    }
+
+    #[test]
+    fn package_name_strips_the_version() {
+        assert_eq!(
+            package_name("chromeos-base/foo-0.0.1-r12"),
+            "chromeos-base/foo"
+        );
+        assert_eq!(package_name("dev-libs/bar-1.2"), "dev-libs/bar");
+        assert_eq!(package_name("chromeos-base/baz-9999"), "chromeos-base/baz");
+        assert_eq!(package_name("virtual/pkg"), "virtual/pkg");
+    }
+
+    #[test]
+    fn build_profile() {
+        let mut profiler = BuildProfiler::default();
+        let started = |index: usize, package: &str| EmergeEvent::Started {
+            index,
+            total: 3,
+            package: package.to_string(),
+        };
+        let completed = |index: usize, package: &str| EmergeEvent::Completed {
+            index,
+            total: 3,
+            package: package.to_string(),
+        };
+        profiler.record(&started(1, "a/a-1"), 0.0);
+        profiler.record(&started(2, "b/b-1"), 0.0);
+        profiler.record(&completed(1, "a/a-1"), 10.0);
+        profiler.record(&started(3, "d/d-1"), 12.0);
+        profiler.record(&completed(3, "d/d-1"), 20.0);
+        profiler.record(&completed(2, "b/b-1"), 30.0);
+        profiler.record(&started(4, "c/c-1"), 30.0);
+        profiler.record(&completed(4, "c/c-1"), 50.0);
+        let timings = profiler.timings();
+        assert_eq!(timings.len(), 4);
+        assert_eq!(
+            longest_chain(timings)
+                .iter()
+                .map(|t| t.package.as_str())
+                .collect::<Vec<_>>(),
+            vec!["b/b-1", "c/c-1"]
+        );
+        let mut previous = timings.to_vec();
+        previous[3].package = "c/c-0".to_string();
+        previous[3].end = 40.0;
+        let report = format_build_profile(timings, Some(&previous));
+        assert!(report.starts_with("Build time: 50s for 4 packages (40s in the previous build)"));
+        assert!(report.contains("Slowest packages:\n      30s (+0s)   b/b-1\n"));
+        assert!(report.contains("      20s (+10s)  c/c-1"));
+    }
+}
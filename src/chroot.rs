@@ -5,31 +5,246 @@
 // https://developers.google.com/open-source/licenses/bsd
 
 use std::fs;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::path::Path;
+use std::path::PathBuf;
 use std::process::Command;
 use std::process::Stdio;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
 use signal_hook::consts::SIGINT;
 use tracing::error;
 use tracing::info;
 
+use crate::cache::KvCache;
+use crate::config::Config;
+use crate::config::SdkContainer;
 use crate::util::cro3_paths::cro3_dir;
 use crate::util::cro3_paths::gen_path_in_cro3_dir;
 use crate::util::shell_helpers::get_stderr;
 use crate::util::shell_helpers::get_stdout;
 use crate::util::shell_helpers::run_bash_command;
 
+/// Locations of the chroots created at non-default paths by `cro3 chroot
+/// create`, keyed by the canonical path of the checkout
+static CHROOT_LOCATIONS: KvCache<ChrootLocation> = KvCache::new("chroot_locations");
+
+/// Where the chroot and the out dir of a checkout are. None means the default
+/// (chroot and out in the checkout).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChrootLocation {
+    pub chroot: Option<String>,
+    pub out_dir: Option<String>,
+}
+impl ChrootLocation {
+    fn key(repo: &str) -> String {
+        fs::canonicalize(repo)
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or(repo.to_string())
+    }
+    /// Returns the location recorded for the checkout, or the default one
+    pub fn of_repo(repo: &str) -> Result<Self> {
+        Ok(CHROOT_LOCATIONS.get(&Self::key(repo))?.unwrap_or_default())
+    }
+    /// Records the location for the later commands on the checkout
+    pub fn record(&self, repo: &str) -> Result<()> {
+        if self == &Self::default() {
+            CHROOT_LOCATIONS.remove(&Self::key(repo))?;
+            Ok(())
+        } else {
+            CHROOT_LOCATIONS.set(&Self::key(repo), self.clone())
+        }
+    }
+    /// Arguments of cros_sdk to use this location
+    pub fn cros_sdk_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(chroot) = &self.chroot {
+            args.extend(["--chroot".to_string(), chroot.clone()]);
+        }
+        if let Some(out_dir) = &self.out_dir {
+            args.extend(["--out-dir".to_string(), out_dir.clone()]);
+        }
+        args
+    }
+    pub fn chroot_dir(&self, repo: &str) -> PathBuf {
+        match &self.chroot {
+            Some(chroot) => PathBuf::from(chroot),
+            None => Path::new(repo).join("chroot"),
+        }
+    }
+    pub fn out_dir(&self, repo: &str) -> PathBuf {
+        match &self.out_dir {
+            Some(out_dir) => PathBuf::from(out_dir),
+            None => Path::new(repo).join("out"),
+        }
+    }
+}
+
+/// Returns the mount points under `dir` in the content of /proc/mounts, which
+/// exist while the chroot is entered
+pub fn mounts_under(mounts: &str, dir: &Path) -> Vec<String> {
+    mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .filter(|mount_point| Path::new(mount_point).starts_with(dir))
+        .map(|mount_point| mount_point.to_string())
+        .collect()
+}
+
+/// Parses the output of `du -sb`, which has the size and the path of each
+/// directory
+pub fn parse_du_output(output: &str) -> Vec<(String, u64)> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .filter_map(|(size, path)| Some((path.trim().to_string(), size.trim().parse().ok()?)))
+        .collect()
+}
+
+/// Returns the sizes of the directories of the chroot of `repo` (the chroot,
+/// the out dir, the cache of the SDK and the images built), skipping the ones
+/// that do not exist
+pub fn chroot_disk_usage(repo: &str) -> Result<Vec<(String, u64)>> {
+    let location = ChrootLocation::of_repo(repo)?;
+    let dirs: Vec<String> = [
+        location.chroot_dir(repo),
+        location.out_dir(repo),
+        Path::new(repo).join(".cache"),
+        Path::new(repo).join("src/build/images"),
+    ]
+    .iter()
+    .filter(|d| d.exists())
+    .map(|d| d.to_string_lossy().to_string())
+    .collect();
+    if dirs.is_empty() {
+        return Ok(Vec::new());
+    }
+    // The files in the chroot are owned by root
+    let output = run_bash_command(&format!("sudo du -sb {}", dirs.join(" ")), None)?;
+    output
+        .status
+        .exit_ok()
+        .context(anyhow!("du failed: {}", get_stderr(&output)))?;
+    Ok(parse_du_output(&get_stdout(&output)))
+}
+
+/// Returns the command line of cros_sdk for the chroot of `repo` at
+/// `location`, to which the other arguments are added. If `container` is
+/// given, cros_sdk runs in it with the checkout, ~/.cro3 and the chroot
+/// mounted at the same paths as the host, so that the paths work as they are.
+/// `tty` is needed for interactive shells.
+pub fn cros_sdk_command_line(
+    repo: &str,
+    location: &ChrootLocation,
+    container: Option<&SdkContainer>,
+    cro3_dir: &str,
+    tty: bool,
+) -> Vec<String> {
+    let mut cmd = Vec::new();
+    if let Some(container) = container {
+        cmd.extend(
+            [
+                &container.engine,
+                "run",
+                "--rm",
+                "-i",
+                "--privileged",
+                "--network=host",
+            ]
+            .map(String::from),
+        );
+        if tty {
+            cmd.push("-t".to_string());
+        }
+        let mut volumes = vec![repo.to_string(), cro3_dir.to_string()];
+        volumes.extend(
+            location
+                .chroot
+                .iter()
+                .chain(location.out_dir.iter())
+                .cloned(),
+        );
+        for volume in volumes {
+            cmd.extend(["-v".to_string(), format!("{volume}:{volume}")]);
+        }
+        cmd.extend(["-w".to_string(), repo.to_string(), container.image.clone()]);
+    }
+    cmd.push("cros_sdk".to_string());
+    cmd.extend(location.cros_sdk_args());
+    cmd
+}
+
+/// Returns a command of cros_sdk (in the SDK container if configured) for the
+/// chroot of `repo` at `location`
+fn cros_sdk_command(repo: &str, location: &ChrootLocation, tty: bool) -> Result<Command> {
+    let argv = cros_sdk_command_line(
+        repo,
+        location,
+        Config::read()?.sdk_container().as_ref(),
+        &cro3_dir()?,
+        tty,
+    );
+    let mut cmd = Command::new(&argv[0]);
+    cmd.args(&argv[1..]).current_dir(repo);
+    Ok(cmd)
+}
+
+/// Runs cros_sdk with `args` for the lifecycle of the chroot of `repo`,
+/// showing its output
+fn run_cros_sdk(repo: &str, location: &ChrootLocation, args: &[&str]) -> Result<()> {
+    let status = cros_sdk_command(repo, location, false)?
+        .args(args)
+        .status()
+        .context("Failed to run cros_sdk (maybe you need depot_tools)")?;
+    status
+        .exit_ok()
+        .context(anyhow!("cros_sdk {} failed", args.join(" ")))?;
+    Ok(())
+}
+
+/// Creates the chroot of `repo` at `location` (replacing the existing one if
+/// `replace`), and records the location for the later commands
+pub fn create_chroot(repo: &str, location: &ChrootLocation, replace: bool) -> Result<()> {
+    run_cros_sdk(
+        repo,
+        location,
+        &[if replace { "--replace" } else { "--create" }],
+    )?;
+    location.record(repo)
+}
+
+/// Deletes the chroot of `repo`. Fails if it is entered by someone.
+pub fn delete_chroot(repo: &str) -> Result<()> {
+    let location = ChrootLocation::of_repo(repo)?;
+    let chroot_dir = location.chroot_dir(repo);
+    let mounts = mounts_under(&fs::read_to_string("/proc/mounts")?, &chroot_dir);
+    if !mounts.is_empty() {
+        bail!(
+            "The chroot at {chroot_dir:?} is in use ({} is mounted). Please exit the chroot first.",
+            mounts[0]
+        );
+    }
+    run_cros_sdk(repo, &location, &["--delete"])?;
+    ChrootLocation::default().record(repo)
+}
+
 pub struct Chroot {
     repo_path: String,
+    location: ChrootLocation,
 }
 impl Chroot {
     pub fn new(repo_path: &str) -> Result<Self> {
         let chroot = Chroot {
             repo_path: repo_path.to_string(),
+            location: ChrootLocation::of_repo(repo_path)?,
         };
         let cro3_dir_path = cro3_dir()?;
         info!("Using Chromium OS checkout at {}", repo_path);
@@ -47,8 +262,13 @@ impl Chroot {
         drop(chroot.run_bash_script_in_chroot("remove_bash_logout", "rm -f ~/.bash_logout", None));
         Ok(chroot)
     }
+    /// Returns a command of cros_sdk for the chroot, to which the other
+    /// arguments are added
+    fn cros_sdk(&self) -> Result<Command> {
+        cros_sdk_command(&self.repo_path, &self.location, false)
+    }
     pub fn exec_in_chroot(&self, args: &[&str]) -> Result<String> {
-        let mut cmd = Command::new("cros_sdk");
+        let mut cmd = self.cros_sdk()?;
         cmd.arg("--no-ns-pid")
             .arg("--")
             .args(args)
@@ -70,7 +290,18 @@ impl Chroot {
         let mut cmd = async_process::Command::new("bash");
         let cmd = cmd
             .arg("-c")
-            .arg("cros_sdk --no-ns-pid -- ".to_string() + &args.join(" "))
+            .arg(format!(
+                "{} --no-ns-pid -- {}",
+                cros_sdk_command_line(
+                    &self.repo_path,
+                    &self.location,
+                    Config::read()?.sdk_container().as_ref(),
+                    &cro3_dir()?,
+                    false
+                )
+                .join(" "),
+                args.join(" ")
+            ))
             .current_dir(&self.repo_path)
             .kill_on_drop(true)
             .stdin(Stdio::null())
@@ -79,6 +310,18 @@ impl Chroot {
         info!("Executing: {cmd:?} async");
         cmd.spawn().context("exec_in_chroot_async failed")
     }
+    /// Copies a file on the host into ~/.cro3/tmp, which is mounted as
+    /// /cro3 in the chroot, and returns its path in the chroot
+    pub fn share_file(&self, path: &Path) -> Result<String> {
+        let name = path
+            .file_name()
+            .context(anyhow!("Not a file: {path:?}"))?
+            .to_string_lossy()
+            .to_string();
+        fs::copy(path, gen_path_in_cro3_dir(&format!("tmp/{name}"))?)
+            .context(anyhow!("Failed to copy {path:?}"))?;
+        Ok(format!("/cro3/tmp/{name}"))
+    }
     pub fn write_bash_script_for_chroot(&self, name: &str, script: &str) -> Result<()> {
         let dst = gen_path_in_cro3_dir(&format!("tmp/{name}.sh"))?;
         fs::write(dst, script.as_bytes()).context("Failed to create a script file")?;
@@ -99,7 +342,7 @@ impl Chroot {
         args: Option<&[&str]>,
     ) -> Result<String> {
         self.write_bash_script_for_chroot(name, script)?;
-        let mut cmd = Command::new("cros_sdk");
+        let mut cmd = self.cros_sdk()?;
         cmd.args([
             "--no-ns-pid",
             "--",
@@ -148,8 +391,58 @@ impl Chroot {
         let result = get_stdout(&result);
         Ok(result)
     }
+    /// Runs a script in chroot like run_bash_script_in_chroot(), passing each
+    /// line of the output (both stdout and stderr) to `on_line` as it is
+    /// printed instead of showing it
+    pub fn run_bash_script_in_chroot_with_lines(
+        &self,
+        name: &str,
+        script: &str,
+        mut on_line: impl FnMut(&str),
+    ) -> Result<()> {
+        self.write_bash_script_for_chroot(name, &format!("exec 2>&1\n{script}"))?;
+        let mut cmd = self.cros_sdk()?;
+        cmd.args([
+            "--no-ns-pid",
+            "--",
+            "bash",
+            "-e",
+            &format!("/cro3/tmp/{}.sh", name),
+        ])
+        .current_dir(&self.repo_path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped());
+        info!("Running {name} in chroot...");
+        let mut run = cmd
+            .spawn()
+            .context(anyhow!("spawn failed. cmd = {cmd:?}"))?;
+        // See run_bash_script_in_chroot() for the handling of Ctrl-C
+        let intr = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register_conditional_shutdown(SIGINT, 1, Arc::clone(&intr))?;
+        signal_hook::flag::register(SIGINT, Arc::clone(&intr))?;
+        let stdout = run.stdout.take().context("No stdout")?;
+        for line in BufReader::new(stdout).lines() {
+            on_line(&line?);
+        }
+        let status = run.wait().context(anyhow!("wait failed. cmd = {cmd:?}"))?;
+        if intr.load(Ordering::Relaxed) {
+            return Err(anyhow!("Caught a SIGINT (Ctrl+C)"));
+        }
+        status
+            .exit_ok()
+            .context(anyhow!("run_in_chroot failed. cmd = {cmd:?}"))?;
+        Ok(())
+    }
     pub fn run_in_chroot_async(&self, script: &str) -> Result<async_process::Child> {
-        async_process::Command::new("cros_sdk")
+        let argv = cros_sdk_command_line(
+            &self.repo_path,
+            &self.location,
+            Config::read()?.sdk_container().as_ref(),
+            &cro3_dir()?,
+            false,
+        );
+        async_process::Command::new(&argv[0])
+            .args(&argv[1..])
             .args(["--no-ns-pid", "--", "bash", "-xe", "-c", script])
             .current_dir(&self.repo_path)
             .kill_on_drop(true)
@@ -159,8 +452,31 @@ impl Chroot {
             .spawn()
             .context("Failed to launch servod")
     }
+    /// Starts `args` in the chroot in a new session with the output written to
+    /// `log`, so that it keeps running after cro3 exits
+    pub fn spawn_detached_in_chroot(&self, args: &[&str], log: &Path) -> Result<()> {
+        let argv = cros_sdk_command_line(
+            &self.repo_path,
+            &self.location,
+            Config::read()?.sdk_container().as_ref(),
+            &cro3_dir()?,
+            false,
+        );
+        let log = fs::File::create(log).context(anyhow!("Failed to create {log:?}"))?;
+        Command::new("setsid")
+            .args(&argv)
+            .args(["--no-ns-pid", "--"])
+            .args(args)
+            .current_dir(&self.repo_path)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .spawn()
+            .context(anyhow!("Failed to start {args:?} in the chroot"))?;
+        Ok(())
+    }
     pub fn open_chroot(&self, additional_args: &[String]) -> Result<()> {
-        let cmd = Command::new("cros_sdk")
+        let cmd = cros_sdk_command(&self.repo_path, &self.location, true)?
             .arg("--no-color")
             .args(additional_args)
             .current_dir(&self.repo_path)
@@ -172,3 +488,59 @@ impl Chroot {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn chroot_lifecycle() {
+        let mounts = "proc /proc proc rw,nosuid 0 0
+proc /work/cros/chroot/proc proc rw,nosuid 0 0
+/dev/sda1 /work/cros/chroot/cro3 ext4 rw 0 0
+/dev/sda1 /work/cros/chroot2 ext4 rw 0 0
+";
+        assert_eq!(
+            mounts_under(mounts, Path::new("/work/cros/chroot")),
+            vec!["/work/cros/chroot/proc", "/work/cros/chroot/cro3"]
+        );
+        assert!(mounts_under(mounts, Path::new("/ssd/chroot")).is_empty());
+        assert_eq!(
+            parse_du_output("1234\t/work/cros/chroot\n56\t/work/cros/out\n"),
+            vec![
+                ("/work/cros/chroot".to_string(), 1234),
+                ("/work/cros/out".to_string(), 56)
+            ]
+        );
+        let location = ChrootLocation {
+            chroot: Some("/ssd/chroot".to_string()),
+            out_dir: None,
+        };
+        assert_eq!(location.cros_sdk_args(), vec!["--chroot", "/ssd/chroot"]);
+        assert_eq!(
+            location.out_dir("/work/cros"),
+            PathBuf::from("/work/cros/out")
+        );
+        assert!(ChrootLocation::default().cros_sdk_args().is_empty());
+        assert_eq!(
+            cros_sdk_command_line("/work/cros", &location, None, "/home/u/.cro3", true),
+            vec!["cros_sdk", "--chroot", "/ssd/chroot"]
+        );
+        let container = SdkContainer {
+            engine: "podman".to_string(),
+            image: "cros-sdk:latest".to_string(),
+        };
+        assert_eq!(
+            cros_sdk_command_line(
+                "/work/cros",
+                &location,
+                Some(&container),
+                "/home/u/.cro3",
+                false
+            )
+            .join(" "),
+            "podman run --rm -i --privileged --network=host -v /work/cros:/work/cros -v \
+             /home/u/.cro3:/home/u/.cro3 -v /ssd/chroot:/ssd/chroot -w /work/cros cros-sdk:latest \
+             cros_sdk --chroot /ssd/chroot"
+        );
+    }
+}
@@ -6,23 +6,35 @@
 
 use anyhow::Result;
 use argh::FromArgs;
+use cro3::proxy::apply_proxy_config;
+use cro3::util::picker::set_interactive;
+use tracing::warn;
 
 pub mod arc;
+pub mod bisect;
 pub mod board;
 pub mod build;
+pub mod cache;
 pub mod chroot;
 pub mod cl;
+pub mod complete;
 pub mod config;
 pub mod deploy;
+pub mod dlc;
 pub mod dut;
 pub mod flash;
+pub mod go;
 pub mod packages;
+pub mod selftest;
 pub mod servo;
 pub mod setup;
 pub mod sync;
 pub mod tast;
+pub mod test;
 pub mod version;
 pub mod vm;
+pub mod watch;
+pub mod workon;
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// yet another wrapper for CrOS developers.
@@ -35,6 +47,11 @@ pub struct TopLevel {
     /// <https://docs.rs/tracing-subscriber/latest/tracing_subscriber/filter/struct.EnvFilter.html>
     pub verbosity: Option<String>,
 
+    #[argh(switch)]
+    /// never prompt (e.g. choosing a DUT when --dut is omitted). Useful for
+    /// scripts.
+    pub no_interactive: bool,
+
     #[argh(subcommand)]
     nested: Args,
 }
@@ -44,41 +61,63 @@ pub struct TopLevel {
 /// cro3's ChromiumOS dev commands
 pub enum Args {
     Arc(arc::Args),
+    Bisect(bisect::Args),
     Board(board::Args),
     Build(build::Args),
+    Cache(cache::Args),
     Cl(cl::Args),
+    Complete(complete::Args),
     Chroot(chroot::Args),
     Config(config::Args),
     Deploy(deploy::Args),
+    Dlc(dlc::Args),
     Dut(dut::Args),
-    Flash(flash::Args),
+    Flash(Box<flash::Args>),
+    Go(go::Args),
     Packages(packages::Args),
+    Selftest(selftest::Args),
     Servo(servo::Args),
     Setup(setup::Args),
     Sync(sync::Args),
     Tast(tast::Args),
+    Test(test::Args),
     Version(version::Args),
     Vm(vm::Args),
+    Watch(watch::Args),
+    Workon(workon::Args),
 }
 
 #[tracing::instrument(level = "trace")]
 pub fn run(args: &TopLevel) -> Result<()> {
+    set_interactive(!args.no_interactive);
+    if let Err(e) = apply_proxy_config() {
+        warn!("Failed to apply the proxy config: {e:#}");
+    }
     match &args.nested {
         Args::Arc(args) => arc::run(args),
+        Args::Bisect(args) => bisect::run(args),
         Args::Board(args) => board::run(args),
         Args::Build(args) => build::run(args),
+        Args::Cache(args) => cache::run(args),
         Args::Cl(args) => cl::run(args),
+        Args::Complete(args) => complete::run(args),
         Args::Chroot(args) => chroot::run(args),
         Args::Config(args) => config::run(args),
         Args::Deploy(args) => deploy::run(args),
+        Args::Dlc(args) => dlc::run(args),
         Args::Dut(args) => dut::run(args),
         Args::Flash(args) => flash::run(args),
+        Args::Go(args) => go::run(args),
         Args::Packages(args) => packages::run(args),
+        Args::Selftest(args) => selftest::run(args),
         Args::Servo(args) => servo::run(args),
         Args::Setup(args) => setup::run(args),
         Args::Sync(args) => sync::run(args),
         Args::Tast(args) => tast::run(args),
+        Args::Test(args) => test::run(args),
         Args::Version(args) => version::run(args),
         Args::Vm(args) => vm::run(args),
+        Args::Watch(args) => watch::run(args),
+        Args::Workon(args) => workon::run(args),
     }
 }
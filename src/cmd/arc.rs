@@ -13,6 +13,7 @@ use anyhow::Result;
 use argh::FromArgs;
 use cro3::chroot::Chroot;
 use cro3::cros::ensure_testing_rsa_is_there;
+use cro3::dut::resolve_dut;
 use cro3::dut::SshInfo;
 use cro3::repo::get_cros_dir;
 use tracing::error;
@@ -97,9 +98,9 @@ pub struct ArgsArcFlash {
     #[argh(option)]
     image_type: Option<String>,
 
-    /// target DUT
+    /// target DUT (choose interactively if omitted)
     #[argh(option)]
-    dut: String,
+    dut: Option<String>,
 
     /// force flash
     #[argh(switch)]
@@ -111,7 +112,7 @@ pub struct ArgsArcFlash {
 fn run_arc_flash(args: &ArgsArcFlash) -> Result<()> {
     let repo = &get_cros_dir(&args.cros)?;
     ensure_testing_rsa_is_there()?;
-    let target = &SshInfo::new(&args.dut)?;
+    let target = &SshInfo::new(&resolve_dut(&args.dut, None)?)?;
     let mut different = false;
 
     info!("Checking arch...");
@@ -175,12 +176,12 @@ fn run_arc_flash(args: &ArgsArcFlash) -> Result<()> {
 /// logcat wrapper
 #[argh(subcommand, name = "logcat")]
 pub struct ArgsLogcat {
-    /// target DUT
+    /// target DUT (choose interactively if omitted)
     #[argh(option)]
-    dut: String,
+    dut: Option<String>,
 }
 fn run_logcat(args: &ArgsLogcat) -> Result<()> {
-    let remote = SshInfo::new(&args.dut)?;
+    let remote = SshInfo::new(&resolve_dut(&args.dut, None)?)?;
     let devices = remote.run_cmd_stdio("adb devices")?;
     if !devices.contains("localhost:22") {
         remote.run_cmd_piped(&["adb", "connect", "localhost:22"])?;
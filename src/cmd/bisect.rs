@@ -0,0 +1,265 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! ## Find the version that broke something
+//! `cro3 bisect` flashes the release images between a good and a bad version
+//! to the DUT, runs a check on each and narrows down the range of the versions
+//! where it started to fail. The check is a tast test pattern, run with the
+//! test bundles in each image (like `cro3 tast run --prebuilt`), or a shell
+//! command run on the host with DUT and VERSION set in the environment, which
+//! exits with 0 if the version is good, 125 if it can not be tested, and others
+//! if it is bad. Versions whose images are not available are skipped. The
+//! stateful partition of the DUT is wiped on each flash. If it is interrupted,
+//! `--resume` continues with the same arguments.
+//! ```
+//! cro3 bisect --cros ${CROS} --dut ${DUT} --good 15700.0.0 --bad 15770.0.0 --test 'example.Pass'
+//! cro3 bisect --dut ${DUT} --board ${BOARD} --good 15700.0.0 --bad 15770.0.0 \
+//!     --test 'ssh ${DUT} grep -q foo /var/log/messages'
+//! ```
+
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use argh::FromArgs;
+use cro3::bisect::parse_version;
+use cro3::bisect::release_versions_between;
+use cro3::bisect::Bisection;
+use cro3::bisect::Verdict;
+use cro3::cache::KvCache;
+use cro3::chroot::Chroot;
+use cro3::cros::ensure_testing_rsa_is_there;
+use cro3::dut::ensure_dut_is_not_leased;
+use cro3::dut::infer_board;
+use cro3::dut::invalidate_dut_image_info;
+use cro3::dut::resolve_dut;
+use cro3::dut::SshInfo;
+use cro3::google_storage::list_gs_files;
+use cro3::progress::FlashProgress;
+use cro3::progress::Phase;
+use cro3::provision::download_test_image;
+use cro3::provision::provision_test_image;
+use cro3::provision::ProvisionOptions;
+use cro3::provision::TestImage;
+use cro3::repo::get_cros_dir;
+use cro3::tast::host_tast;
+use cro3::test_results::TestStatus;
+use regex_macro::regex;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::cmd::tast::run_prebuilt_tast_tests;
+
+/// The bisection in progress on each DUT, for --resume
+static BISECT_STATE: KvCache<BisectState> = KvCache::new("bisect_state");
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct BisectState {
+    /// The arguments of the bisection, which must match to resume it
+    key: String,
+    bisection: Bisection,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// find the first release version where a check fails by flashing the
+/// versions between good and bad ones
+#[argh(subcommand, name = "bisect")]
+pub struct Args {
+    /// target DUT (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// board of the images (default: the board of the DUT)
+    #[argh(option)]
+    board: Option<String>,
+
+    /// a version where the check passes (e.g. 15700.0.0)
+    #[argh(option)]
+    good: String,
+
+    /// a later version where the check fails (e.g. 15770.0.0)
+    #[argh(option)]
+    bad: String,
+
+    /// tast test pattern, or a shell command run on the host with $DUT and
+    /// $VERSION set (exits with 0: good, 125: skip, others: bad)
+    #[argh(option)]
+    test: String,
+
+    /// cros repo dir to run tast in (the tast in PATH is used without it)
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// continue the last bisection on the DUT with the same arguments
+    #[argh(switch)]
+    resume: bool,
+}
+
+/// Whether `test` is a tast test pattern (e.g. "example.*") or an attribute
+/// expression (e.g. '("group:mainline" && !informational)'), rather than a
+/// shell command
+fn is_tast_pattern(test: &str) -> bool {
+    let test = test.trim();
+    regex!(r"^[A-Za-z0-9_]+\.[A-Za-z0-9_.*?]+$").is_match(test)
+        || (test.starts_with('(') && test.ends_with(')'))
+}
+
+#[tracing::instrument(level = "trace")]
+pub fn run(args: &Args) -> Result<()> {
+    ensure_testing_rsa_is_there()?;
+    let (Some(good), Some(bad)) = (parse_version(&args.good), parse_version(&args.bad)) else {
+        bail!("Versions should be like 15700.0.0 or R120-15700.0.0");
+    };
+    if good >= bad {
+        bail!("--good should be older than --bad");
+    }
+    let dut = resolve_dut(&args.dut, args.board.as_deref())?;
+    let board = infer_board(args.board.as_deref(), Some(&dut))?;
+    let chroot = if !is_tast_pattern(&args.test) {
+        None
+    } else {
+        match get_cros_dir(&args.cros) {
+            Ok(repodir) => Some(Chroot::new(&repodir)?),
+            Err(_) if host_tast().is_some() => None,
+            Err(e) => return Err(e),
+        }
+    };
+    let key = format!("{board} {} {} {}", args.good, args.bad, args.test);
+    let mut bisection = match (args.resume, BISECT_STATE.get(&dut)?) {
+        (false, _) => {
+            let listing = list_gs_files(&format!("gs://chromeos-image-archive/{board}-release/"))?;
+            let versions = release_versions_between(&listing, &args.good, &args.bad);
+            if versions.is_empty() {
+                bail!(
+                    "No release images of {board} between {} and {}",
+                    args.good,
+                    args.bad
+                );
+            }
+            Bisection::new(&args.good, &versions, &args.bad)
+        }
+        (true, Some(state)) if state.key == key => state.bisection,
+        (true, Some(_)) => bail!("The last bisection on {dut} had different arguments to resume"),
+        (true, None) => bail!("No bisection on {dut} to resume"),
+    };
+
+    while let Some(i) = bisection.next() {
+        let version = bisection.versions[i].clone();
+        info!(
+            "Testing {version} (about {} steps left)...",
+            bisection.steps_left()
+        );
+        let verdict = test_version(args, chroot.as_ref(), &board, &dut, &version)?;
+        info!("{version} is {verdict}");
+        bisection.mark(i, verdict);
+        BISECT_STATE.set(
+            &dut,
+            BisectState {
+                key: key.clone(),
+                bisection: bisection.clone(),
+            },
+        )?;
+    }
+
+    let (good, bad) = bisection.range();
+    println!("{:<20} RESULT", "VERSION");
+    for (version, verdict) in bisection.versions.iter().zip(&bisection.verdicts) {
+        if let Some(verdict) = verdict {
+            println!("{version:<20} {verdict}");
+        }
+    }
+    let skipped: Vec<&str> = bisection.versions[good + 1..bad]
+        .iter()
+        .map(String::as_str)
+        .collect();
+    println!(
+        "The first bad version is {}, after {}",
+        bisection.versions[bad], bisection.versions[good]
+    );
+    if !skipped.is_empty() {
+        println!(
+            "(or one of the versions which were skipped: {})",
+            skipped.join(" ")
+        );
+    }
+    BISECT_STATE.remove(&dut)?;
+    Ok(())
+}
+
+/// Flashes `version` to the DUT and runs the check on it
+fn test_version(
+    args: &Args,
+    chroot: Option<&Chroot>,
+    board: &str,
+    dut: &str,
+    version: &str,
+) -> Result<Verdict> {
+    let image = match download_test_image(board, version) {
+        Ok(image) => image,
+        Err(e) => {
+            warn!("Skipping {version} since the image is not available: {e:#}");
+            return Ok(Verdict::Skip);
+        }
+    };
+    let ssh = SshInfo::new(dut)?;
+    ensure_dut_is_not_leased(&ssh, false)?;
+    let mut progress = FlashProgress::new(
+        dut,
+        &[
+            Phase::WriteKernel,
+            Phase::WriteRootfs,
+            Phase::Stateful,
+            Phase::Reboot,
+        ],
+    )?;
+    provision_test_image(
+        &ssh,
+        &TestImage::Artifacts(image),
+        &ProvisionOptions {
+            // The versions go back and forth, so the stateful partition of
+            // another version is not kept
+            clobber_stateful: true,
+            delta: false,
+            disable_rootfs_verification: true,
+            clear_tpm_owner: true,
+        },
+        &mut progress,
+    )?;
+    progress.finish()?;
+    invalidate_dut_image_info(dut)?;
+    ssh.wait_for_boot(Duration::from_secs(300), true)
+        .context("The DUT did not show the login screen")?;
+
+    if is_tast_pattern(&args.test) {
+        // The tests are run with the bundles in the image, since the ones
+        // built from the checkout may not match the version
+        let ssh = ssh.into_forwarded()?;
+        let run = run_prebuilt_tast_tests(chroot, dut, &ssh, &args.test, None, 0)?;
+        return Ok(if !run.failed().is_empty() {
+            Verdict::Bad
+        } else if run.results.iter().all(|r| r.status == TestStatus::Skipped) {
+            Verdict::Skip
+        } else {
+            Verdict::Good
+        });
+    }
+    let status = Command::new("bash")
+        .arg("-c")
+        .arg(&args.test)
+        .env("DUT", dut)
+        .env("VERSION", version)
+        .status()
+        .context("Failed to run the check")?;
+    Ok(match status.code() {
+        Some(0) => Verdict::Good,
+        Some(125) => Verdict::Skip,
+        _ => Verdict::Bad,
+    })
+}
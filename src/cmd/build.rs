@@ -9,12 +9,129 @@
 //! cro3 build --cros $CROS --board brya --packages sys-kernel/arcvm-kernel-ack-5_10
 //! cro3 build --full --cros $CROS --board brya
 //! ```
+//!
+//! --board can be omitted if --dut is given. The board of the DUT is cached
+//! after the first contact.
+//! ```
+//! cro3 build --cros $CROS --dut $DUT --packages sys-kernel/arcvm-kernel-ack-5_10
+//! ```
+//!
+//! `cro3 build pkg` builds packages with cros-workon and emerge, showing the
+//! progress instead of the whole output. On errors, the failed phase and the
+//! first compiler error (with the file and the line) in the log of the failed
+//! ebuild are shown. The output of emerge is saved in ~/.cro3/build_BOARD.log.
+//! ```
+//! cro3 build pkg --cros $CROS --board $BOARD $PACKAGE_NAME
+//! # Build for multiple boards in parallel (2 at a time by default), showing
+//! # the result of each board at the end
+//! cro3 build pkg --cros $CROS --board brya --board corsola --jobs 2 $PACKAGE_NAME
+//! # Build the version of the package pinned in the manifest, not the source
+//! # in the checkout
+//! cro3 build pkg --cros $CROS --board $BOARD --no-workon $PACKAGE_NAME
+//! # Show where the time went, compared with the previous build
+//! cro3 build pkg --cros $CROS --board $BOARD --profile $PACKAGE_NAME
+//! # Add or remove USE flags and FEATURES for this build only, without
+//! # editing make.conf. They are recorded with the images built later.
+//! cro3 build pkg --cros $CROS --board $BOARD --use "asan -lto" $PACKAGE_NAME
+//! cro3 build kernel --cros $CROS --board $BOARD --use kasan
+//! ```
+//!
+//! Remote execution (reclient) can be turned on or off for a build. The
+//! backend is checked before the build, and the numbers of the actions run
+//! remotely and locally are shown after it.
+//! ```
+//! cro3 build pkg --cros $CROS --board $BOARD --remoteexec on chromeos-base/chromeos-chrome
+//! ```
+//!
+//! `cro3 build image` builds an image with the packages built before. The path
+//! to the image is recorded, and `cro3 flash --local-build` flashes the test
+//! image recorded for the board.
+//! ```
+//! cro3 build image --cros $CROS --board $BOARD --type test
+//! cro3 build image --cros $CROS --board $BOARD --type factory
+//! ```
+//!
+//! `cro3 build kernel` builds only the kernel of the board, and optionally
+//! writes it with the modules to a DUT and reboots it.
+//! ```
+//! cro3 build kernel --cros $CROS --board $BOARD --deploy $DUT
+//! # The board of the DUT is used if --board is omitted
+//! cro3 build kernel --cros $CROS --deploy $DUT
+//! ```
+//!
+//! `cro3 build upload` shares the image built last (and binary packages) on
+//! Google Storage with the version, the builder and the git hashes of the
+//! checkout, so that others can flash it with `cro3 flash --from`.
+//! ```
+//! cro3 build upload --cros $CROS --board $BOARD --to gs://bucket/path
+//! cro3 build upload --cros $CROS --board $BOARD --to gs://bucket/path --package chromeos-base/foo
+//! ```
+//!
+//! The binary packages and ccache in the chroot, and the images downloaded by
+//! cro3, can be inspected and cleared per board. ccache is shared by all the
+//! boards.
+//! ```
+//! cro3 build cache stats --cros $CROS
+//! # Clear only the binary packages of a board
+//! cro3 build cache clear --cros $CROS --board $BOARD --packages
+//! # Clear everything of a board, and ccache
+//! cro3 build cache clear --cros $CROS --board $BOARD --yes
+//! ```
+//!
+//! ccache is checked before each build, with a warning if it is disabled or
+//! nearly full, and its hits and misses are shown after the build.
+//! ```
+//! # Show whether ccache is enabled and how full it is
+//! cro3 build cache ccache --cros $CROS
+//! # Enable ccache and enlarge it to 50 GiB
+//! cro3 build cache ccache --cros $CROS --enable --max-size 50G
+//! ```
+
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
 use argh::FromArgs;
+use cro3::build::binpkg_dir;
+use cro3::build::build_image;
+use cro3::build::build_log_path;
+use cro3::build::build_packages;
+use cro3::build::ccache_enabled;
+use cro3::build::ccache_stats;
+use cro3::build::check_remoteexec_backend;
+use cro3::build::chroot_dir_sizes;
+use cro3::build::clear_binpkgs;
+use cro3::build::clear_ccache;
+use cro3::build::configure_ccache;
+use cro3::build::count_binpkg_usage;
+use cro3::build::kernel_package_of_board;
+use cro3::build::list_chroot_boards;
+use cro3::build::list_workon_packages;
+use cro3::build::remoteexec_env;
+use cro3::build::report_remoteexec_stats;
+use cro3::build::BuildImageType;
+use cro3::build::BuildOptions;
+use cro3::build::BuildOverrides;
+use cro3::build::RemoteExec;
+use cro3::build::CCACHE_DIR;
 use cro3::chroot::Chroot;
+use cro3::cros::ensure_testing_rsa_is_there;
+use cro3::deploy::deploy_kernel;
+use cro3::dut::infer_board;
+use cro3::dut::resolve_dut;
+use cro3::dut::SshInfo;
+use cro3::image_cache::format_size;
+use cro3::image_cache::list_cached_images;
+use cro3::image_cache::parse_size;
+use cro3::image_cache::remove_cached_image;
 use cro3::repo::get_cros_dir;
+use cro3::shared_build::upload_build;
+use cro3::util::picker::confirm;
+use rayon::prelude::*;
+use tracing::error;
 use tracing::info;
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -27,7 +144,11 @@ pub struct Args {
 
     /// target board
     #[argh(option)]
-    board: String,
+    board: Option<String>,
+
+    /// DUT to build for, used to determine the board if --board is omitted
+    #[argh(option)]
+    dut: Option<String>,
 
     /// packages to build (or workon, for a full build)
     #[argh(positional)]
@@ -52,14 +173,50 @@ pub struct Args {
     #[argh(switch)]
     full: bool,
 
+    /// build with remote execution (reclient): on or off (default: follow
+    /// the configuration of the chroot)
+    #[argh(option)]
+    remoteexec: Option<RemoteExec>,
+
     #[argh(option, hidden_help)]
     repo: Option<String>,
+
+    #[argh(subcommand)]
+    nested: Option<SubCommand>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum SubCommand {
+    Pkg(ArgsPkg),
+    Image(ArgsImage),
+    Kernel(ArgsKernel),
+    Cache(ArgsCache),
+    Upload(ArgsUpload),
+}
+
+fn resolve_board(board: &Option<String>, dut: &Option<String>) -> Result<String> {
+    infer_board(board.as_deref(), dut.as_deref())
 }
+
 #[tracing::instrument(level = "trace")]
 pub fn run(args: &Args) -> Result<()> {
-    let board = &args.board;
+    match &args.nested {
+        Some(SubCommand::Pkg(args)) => return run_pkg(args),
+        Some(SubCommand::Image(args)) => return run_image(args),
+        Some(SubCommand::Kernel(args)) => return run_kernel(args),
+        Some(SubCommand::Cache(args)) => return run_cache(args),
+        Some(SubCommand::Upload(args)) => return run_upload(args),
+        None => {}
+    }
+    let board = &resolve_board(&args.board, &args.dut)?;
     let use_flags = &args.use_flags;
-    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    let remoteexec_env = remoteexec_env(args.remoteexec);
+    if args.remoteexec == Some(RemoteExec::On) {
+        check_remoteexec_backend()?;
+    }
+    let repo = get_cros_dir(&args.cros)?;
+    let chroot = Chroot::new(&repo)?;
     if !args.skip_setup {
         chroot.run_bash_script_in_chroot(
             "board_setup",
@@ -94,20 +251,22 @@ cros-workon-{board} start {package_list}
             None,
         )?;
     }
+    list_workon_packages(&chroot, board)?;
     if args.full {
         info!("building a full image...");
         chroot.run_bash_script_in_chroot(
             "build_packages",
             &format!(
                 r###"
+{remoteexec_env}
 export USE='{use_flags}'
 build_packages --board={board} --withdev
-build_image --board={board} --noenable_rootfs_verification test
 "###
             ),
             None,
         )?;
-        info!("Succesfully built a test image!");
+        let image = build_image(&chroot, &repo, board, BuildImageType::Test, false)?;
+        info!("Succesfully built a test image! {image:?}");
     } else if !args.packages.is_empty() {
         let package_list = args.packages.join(" ");
         info!("Building {package_list}...");
@@ -115,6 +274,7 @@ build_image --board={board} --noenable_rootfs_verification test
             "emerge_packages",
             &format!(
                 r###"
+{remoteexec_env}
 export USE='{use_flags}'
 emerge-{board} {package_list}
 "###
@@ -127,5 +287,513 @@ emerge-{board} {package_list}
             "Please specify --full or --packages. `cro3 build --help` for more details."
         ));
     }
+    if args.remoteexec == Some(RemoteExec::On) {
+        report_remoteexec_stats(&chroot)?;
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// build packages, showing the progress and the log of the failed ebuild
+#[argh(subcommand, name = "pkg")]
+struct ArgsPkg {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target boards (can be repeated to build for them in parallel)
+    #[argh(option)]
+    board: Vec<String>,
+
+    /// DUT to build for, used to determine the board if --board is omitted
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// number of boards to build for at a time
+    #[argh(option, default = "2")]
+    jobs: usize,
+
+    /// build the versions of the packages pinned in the manifest instead of
+    /// running cros-workon start for them
+    #[argh(switch)]
+    no_workon: bool,
+
+    /// build with remote execution (reclient): on or off (default: follow
+    /// the configuration of the chroot)
+    #[argh(option)]
+    remoteexec: Option<RemoteExec>,
+
+    /// show the slowest packages and the longest chain of packages built one
+    /// after another, compared with the previous build (the time of building
+    /// the image is not included)
+    #[argh(switch)]
+    profile: bool,
+
+    /// USE flags to add (or remove with "-") for this build only, e.g. "asan
+    /// -lto". They are recorded with the images built later.
+    #[argh(option, long = "use")]
+    use_flags: Option<String>,
+
+    /// FEATURES of portage to add (or remove with "-") for this build only
+    #[argh(option)]
+    features: Option<String>,
+
+    /// packages to build
+    #[argh(positional)]
+    packages: Vec<String>,
+}
+fn run_pkg(args: &ArgsPkg) -> Result<()> {
+    if args.packages.is_empty() {
+        bail!("Please specify the packages to build");
+    }
+    let options = BuildOptions {
+        workon: !args.no_workon,
+        remoteexec: args.remoteexec,
+        profile: args.profile,
+        overrides: BuildOverrides::new(args.use_flags.as_deref(), args.features.as_deref())?,
+    };
+    if args.board.len() <= 1 {
+        let board = resolve_board(&args.board.first().cloned(), &args.dut)?;
+        let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+        return build_packages(&chroot, &board, &args.packages, &options);
+    }
+    if args.dut.is_some() {
+        bail!("--dut can not be used with multiple boards");
+    }
+    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    info!(
+        "Building for {} boards, {} at a time",
+        args.board.len(),
+        args.jobs
+    );
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs.max(1))
+        .build()
+        .context("Failed to create a thread pool")?;
+    let results: Vec<(&String, Result<()>, Duration)> = pool.install(|| {
+        args.board
+            .par_iter()
+            .map(|board| {
+                let start = Instant::now();
+                let result = build_packages(&chroot, board, &args.packages, &options);
+                if let Err(e) = &result {
+                    error!("{board}: {e:#}");
+                }
+                (board, result, start.elapsed())
+            })
+            .collect()
+    });
+    let width = args.board.iter().map(|b| b.len()).max().unwrap_or_default();
+    println!("{:width$} {:>6} RESULT", "BOARD", "MIN");
+    let mut failed = 0;
+    for (board, result, elapsed) in &results {
+        let status = match result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => {
+                failed += 1;
+                format!("failed: {e:#}")
+            }
+        };
+        println!(
+            "{board:width$} {:>6.1} {status}",
+            elapsed.as_secs_f64() / 60.0
+        );
+    }
+    if failed > 0 {
+        bail!("Failed to build for {failed} of {} boards", results.len());
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// build an image and record its path for `cro3 flash`
+#[argh(subcommand, name = "image")]
+struct ArgsImage {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target board
+    #[argh(option)]
+    board: Option<String>,
+
+    /// DUT to build for, used to determine the board if --board is omitted
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// type of the image: test (default), dev, base or factory
+    #[argh(option, long = "type", default = "BuildImageType::Test")]
+    image_type: BuildImageType,
+
+    /// keep rootfs verification enabled in the image
+    #[argh(switch)]
+    enable_rootfs_verification: bool,
+}
+fn run_image(args: &ArgsImage) -> Result<()> {
+    let board = resolve_board(&args.board, &args.dut)?;
+    let repo = get_cros_dir(&args.cros)?;
+    let chroot = Chroot::new(&repo)?;
+    let image = build_image(
+        &chroot,
+        &repo,
+        &board,
+        args.image_type,
+        args.enable_rootfs_verification,
+    )?;
+    info!("Built {image:?}");
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// upload an image and packages built locally to Google Storage with their
+/// metadata, for `cro3 flash --from` on other machines
+#[argh(subcommand, name = "upload")]
+struct ArgsUpload {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target board
+    #[argh(option)]
+    board: Option<String>,
+
+    /// DUT to build for, used to determine the board if --board is omitted
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// GS path to upload to (e.g. gs://bucket/path)
+    #[argh(option)]
+    to: String,
+
+    /// type of the image built by `cro3 build image` to upload: test
+    /// (default), dev, base or factory
+    #[argh(option, long = "type", default = "BuildImageType::Test")]
+    image_type: BuildImageType,
+
+    /// upload only the packages, without an image
+    #[argh(switch)]
+    no_image: bool,
+
+    /// binary packages to upload (e.g. chromeos-base/foo). Can be repeated.
+    #[argh(option)]
+    package: Vec<String>,
+}
+fn run_upload(args: &ArgsUpload) -> Result<()> {
+    if args.no_image && args.package.is_empty() {
+        bail!("Nothing to upload. Please specify --package with --no-image");
+    }
+    let board = resolve_board(&args.board, &args.dut)?;
+    let repo = get_cros_dir(&args.cros)?;
+    let chroot = Chroot::new(&repo)?;
+    let image_type = (!args.no_image).then_some(args.image_type);
+    let build = upload_build(&chroot, &repo, &board, &args.to, image_type, &args.package)?;
+    info!(
+        "Uploaded {} {} ({} packages) to {}",
+        build.board,
+        build.version,
+        build.packages.len(),
+        args.to
+    );
+    if build.image.is_some() {
+        info!("Flash it with: cro3 flash --dut $DUT --from {}", args.to);
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// build the kernel, and optionally deploy it to a DUT
+#[argh(subcommand, name = "kernel")]
+struct ArgsKernel {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target board (default: the board of the DUT given with --deploy)
+    #[argh(option)]
+    board: Option<String>,
+
+    /// kernel package to build (default: the one installed for the board,
+    /// e.g. sys-kernel/chromeos-kernel-5_15)
+    #[argh(option)]
+    package: Option<String>,
+
+    /// DUT to write the kernel and the modules to, which is rebooted after
+    #[argh(option)]
+    deploy: Option<String>,
+
+    /// write the kernel to the inactive slot and boot it once (see
+    /// update_kernel.sh --ab_update)
+    #[argh(switch)]
+    ab_update: bool,
+
+    /// USE flags to add (or remove with "-") for this build only, e.g.
+    /// "kasan"
+    #[argh(option, long = "use")]
+    use_flags: Option<String>,
+
+    /// FEATURES of portage to add (or remove with "-") for this build only
+    #[argh(option)]
+    features: Option<String>,
+}
+fn run_kernel(args: &ArgsKernel) -> Result<()> {
+    let dut = args
+        .deploy
+        .as_ref()
+        .map(|dut| resolve_dut(&Some(dut.clone()), args.board.as_deref()))
+        .transpose()?;
+    if args.board.is_none() && dut.is_none() {
+        bail!("Please specify --board or --deploy");
+    }
+    let board = infer_board(args.board.as_deref(), dut.as_deref())?;
+    let target = dut
+        .map(|dut| -> Result<SshInfo> {
+            ensure_testing_rsa_is_there()?;
+            SshInfo::new(&dut)
+        })
+        .transpose()?;
+    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    let package = match &args.package {
+        Some(package) => package.clone(),
+        None => kernel_package_of_board(&chroot, &board)?,
+    };
+    build_packages(
+        &chroot,
+        &board,
+        &[package],
+        &BuildOptions {
+            workon: true,
+            remoteexec: None,
+            profile: false,
+            overrides: BuildOverrides::new(args.use_flags.as_deref(), args.features.as_deref())?,
+        },
+    )?;
+    if let Some(target) = target {
+        deploy_kernel(&chroot, &board, &target, args.ab_update, true)?;
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// inspect and clear the caches used by builds
+#[argh(subcommand, name = "cache")]
+struct ArgsCache {
+    #[argh(subcommand)]
+    nested: CacheSubCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum CacheSubCommand {
+    Stats(ArgsCacheStats),
+    Clear(ArgsCacheClear),
+    Ccache(ArgsCacheCcache),
+}
+fn run_cache(args: &ArgsCache) -> Result<()> {
+    match &args.nested {
+        CacheSubCommand::Stats(args) => run_cache_stats(args),
+        CacheSubCommand::Clear(args) => run_cache_clear(args),
+        CacheSubCommand::Ccache(args) => run_cache_ccache(args),
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// configure ccache in the chroot and show its status
+#[argh(subcommand, name = "ccache")]
+struct ArgsCacheCcache {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// enable ccache for the builds
+    #[argh(switch)]
+    enable: bool,
+
+    /// disable ccache for the builds
+    #[argh(switch)]
+    disable: bool,
+
+    /// maximum size of ccache (e.g. 50G)
+    #[argh(option)]
+    max_size: Option<String>,
+}
+fn run_cache_ccache(args: &ArgsCacheCcache) -> Result<()> {
+    if args.enable && args.disable {
+        bail!("--enable and --disable cannot be used together");
+    }
+    if let Some(max_size) = &args.max_size {
+        parse_size(max_size)?;
+    }
+    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    let enable = (args.enable || args.disable).then_some(args.enable);
+    configure_ccache(&chroot, enable, args.max_size.as_deref())?;
+    let stats = ccache_stats(&chroot)?;
+    println!(
+        "ccache: {} ({CCACHE_DIR})",
+        if ccache_enabled(&chroot)? {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    println!(
+        "  size:     {} of {}{}",
+        size_or_none(stats.size),
+        size_or_none(stats.max_size),
+        if stats.is_nearly_full() {
+            " (nearly full)"
+        } else {
+            ""
+        }
+    );
+    println!(
+        "  hit rate: {} ({} hits, {} misses)",
+        stats
+            .hit_rate()
+            .map(|r| format!("{:.1}%", r * 100.0))
+            .unwrap_or("unknown".to_string()),
+        stats.hits,
+        stats.misses
+    );
+    Ok(())
+}
+
+fn size_or_none(size: Option<u64>) -> String {
+    size.map(format_size).unwrap_or("none".to_string())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show the sizes and hit rates of the caches
+#[argh(subcommand, name = "stats")]
+struct ArgsCacheStats {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// boards to show (default: all the boards set up in the chroot)
+    #[argh(option)]
+    board: Vec<String>,
+}
+fn run_cache_stats(args: &ArgsCacheStats) -> Result<()> {
+    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    let boards = if args.board.is_empty() {
+        list_chroot_boards(&chroot)?
+    } else {
+        args.board.clone()
+    };
+    let mut dirs: Vec<String> = boards.iter().map(|b| binpkg_dir(b)).collect();
+    dirs.push(CCACHE_DIR.to_string());
+    let sizes = chroot_dir_sizes(&chroot, &dirs)?;
+    let images = list_cached_images()?;
+    for (i, board) in boards.iter().enumerate() {
+        let board_images: Vec<_> = images.iter().filter(|i| &i.board == board).collect();
+        println!("{board}:");
+        println!(
+            "  binary packages: {} ({})",
+            size_or_none(sizes.get(i).copied().flatten()),
+            dirs[i]
+        );
+        println!(
+            "  cro3 images:     {} ({} entries)",
+            format_size(board_images.iter().map(|i| i.size).sum()),
+            board_images.len()
+        );
+        if let Ok(log) = std::fs::read_to_string(build_log_path(board)?) {
+            let (binary, source) = count_binpkg_usage(&log);
+            if binary + source > 0 {
+                println!(
+                    "  last build:      {binary} of {} packages from the binary packages ({:.1}%)",
+                    binary + source,
+                    binary as f64 * 100.0 / (binary + source) as f64
+                );
+            }
+        }
+    }
+    let ccache = ccache_stats(&chroot)?;
+    println!(
+        "ccache: {} ({CCACHE_DIR}), hit rate {} ({} hits, {} misses)",
+        size_or_none(sizes.last().copied().flatten()),
+        ccache
+            .hit_rate()
+            .map(|r| format!("{:.1}%", r * 100.0))
+            .unwrap_or("unknown".to_string()),
+        ccache.hits,
+        ccache.misses
+    );
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// clear the caches of a board (all of them unless some are specified)
+#[argh(subcommand, name = "clear")]
+struct ArgsCacheClear {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// board to clear the caches of
+    #[argh(option)]
+    board: Option<String>,
+
+    /// clear the binary packages of the board
+    #[argh(switch)]
+    packages: bool,
+
+    /// clear ccache (shared by all the boards)
+    #[argh(switch)]
+    ccache: bool,
+
+    /// clear the images of the board downloaded by cro3
+    #[argh(switch)]
+    images: bool,
+
+    /// do not ask for confirmation
+    #[argh(switch)]
+    yes: bool,
+}
+fn run_cache_clear(args: &ArgsCacheClear) -> Result<()> {
+    let all = !args.packages && !args.ccache && !args.images;
+    let board = args.board.as_deref();
+    if board.is_none() && (all || args.packages || args.images) {
+        bail!("Please specify --board, or --ccache only");
+    }
+    let mut targets = Vec::new();
+    if all || args.packages {
+        targets.push("binary packages");
+    }
+    if all || args.ccache {
+        targets.push("ccache");
+    }
+    if all || args.images {
+        targets.push("cro3 images");
+    }
+    if !args.yes
+        && !confirm(
+            &format!(
+                "Clear {} of {}?",
+                targets.join(", "),
+                board.unwrap_or("all the boards")
+            ),
+            false,
+        )?
+    {
+        bail!("Aborted");
+    }
+    if let (Some(board), true) = (board, all || args.images) {
+        for image in list_cached_images()?.iter().filter(|i| i.board == board) {
+            remove_cached_image(image)?;
+            info!("Removed {}", image.key());
+        }
+    }
+    if all || args.packages || args.ccache {
+        let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+        if let (Some(board), true) = (board, all || args.packages) {
+            clear_binpkgs(&chroot, board)?;
+            info!("Removed {}", binpkg_dir(board));
+        }
+        if all || args.ccache {
+            clear_ccache(&chroot)?;
+            info!("Cleared ccache");
+        }
+    }
     Ok(())
 }
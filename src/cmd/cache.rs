@@ -0,0 +1,168 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! ## Manage the local image cache
+//! Images downloaded by `cro3 flash` are kept in ~/.cro3/cache and reused.
+//! ```
+//! # List the cached images (the most recently used first)
+//! cro3 cache ls
+//! # Remove the cached images that match a pattern of BOARD/VERSION/KIND
+//! cro3 cache rm 'brya/R119-*'
+//! # Remove the least recently used images until the cache fits in 100GiB
+//! cro3 cache gc --max-size 100G
+//! ```
+
+use anyhow::bail;
+use anyhow::Result;
+use argh::FromArgs;
+use cro3::image_cache::format_size;
+use cro3::image_cache::image_cache_dir;
+use cro3::image_cache::list_cached_images;
+use cro3::image_cache::parse_size;
+use cro3::image_cache::remove_cached_image;
+use cro3::image_cache::select_images_to_evict;
+use cro3::image_cache::CachedImage;
+use glob::Pattern;
+use tracing::info;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// manage the local image cache
+#[argh(subcommand, name = "cache")]
+pub struct Args {
+    #[argh(subcommand)]
+    nested: SubCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum SubCommand {
+    Ls(ArgsLs),
+    Rm(ArgsRm),
+    Gc(ArgsGc),
+}
+#[tracing::instrument(level = "trace")]
+pub fn run(args: &Args) -> Result<()> {
+    match &args.nested {
+        SubCommand::Ls(args) => run_ls(args),
+        SubCommand::Rm(args) => run_rm(args),
+        SubCommand::Gc(args) => run_gc(args),
+    }
+}
+
+fn print_images(images: &[&CachedImage]) {
+    for image in images {
+        let last_used = chrono::DateTime::from_timestamp(image.last_used, 0)
+            .map(|t| {
+                t.with_timezone(&chrono::Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        println!(
+            "{:<48} {:>10}  {last_used}",
+            image.key(),
+            format_size(image.size)
+        );
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// list the cached images
+#[argh(subcommand, name = "ls")]
+struct ArgsLs {
+    /// only list the images of the board
+    #[argh(option)]
+    board: Option<String>,
+}
+fn run_ls(args: &ArgsLs) -> Result<()> {
+    let images = list_cached_images()?;
+    let images: Vec<&CachedImage> = images
+        .iter()
+        .filter(|i| args.board.as_ref().map(|b| &i.board == b).unwrap_or(true))
+        .collect();
+    print_images(&images);
+    println!(
+        "Total: {} in {:?}",
+        format_size(images.iter().map(|i| i.size).sum()),
+        image_cache_dir()?
+    );
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// remove cached images
+#[argh(subcommand, name = "rm")]
+struct ArgsRm {
+    /// remove all the cached images
+    #[argh(switch)]
+    all: bool,
+
+    /// glob patterns of BOARD/VERSION/KIND to remove (e.g. 'brya/*')
+    #[argh(positional)]
+    patterns: Vec<String>,
+}
+fn run_rm(args: &ArgsRm) -> Result<()> {
+    if args.all != args.patterns.is_empty() {
+        bail!("Please specify either patterns or --all");
+    }
+    let patterns = args
+        .patterns
+        .iter()
+        .map(|p| Pattern::new(p))
+        .collect::<Result<Vec<Pattern>, _>>()?;
+    let images = list_cached_images()?;
+    let removed: Vec<&CachedImage> = images
+        .iter()
+        .filter(|i| args.all || patterns.iter().any(|p| p.matches(&i.key())))
+        .collect();
+    if removed.is_empty() && !args.all {
+        bail!("No cached images matched");
+    }
+    for image in &removed {
+        remove_cached_image(image)?;
+        info!("Removed {}", image.key());
+    }
+    info!(
+        "Freed {}",
+        format_size(removed.iter().map(|i| i.size).sum())
+    );
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// evict the least recently used images to limit the size of the cache
+#[argh(subcommand, name = "gc")]
+struct ArgsGc {
+    /// maximum total size of the cache (e.g. 100G, 512M)
+    #[argh(option)]
+    max_size: String,
+
+    /// only show the images to be removed
+    #[argh(switch)]
+    dry_run: bool,
+}
+fn run_gc(args: &ArgsGc) -> Result<()> {
+    let max_size = parse_size(&args.max_size)?;
+    let images = list_cached_images()?;
+    let evicted = select_images_to_evict(&images, max_size);
+    if evicted.is_empty() {
+        info!("The cache is within {}", format_size(max_size));
+        return Ok(());
+    }
+    if args.dry_run {
+        print_images(&evicted);
+        return Ok(());
+    }
+    for image in &evicted {
+        remove_cached_image(image)?;
+        info!("Removed {}", image.key());
+    }
+    info!(
+        "Freed {}",
+        format_size(evicted.iter().map(|i| i.size).sum())
+    );
+    Ok(())
+}
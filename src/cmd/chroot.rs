@@ -4,14 +4,56 @@
 // license that can be found in the LICENSE file or at
 // https://developers.google.com/open-source/licenses/bsd
 
+//! ## Manage the chroot of a checkout
+//! `cro3 chroot` enters the chroot (or runs a command in it) as before.
+//! ```
+//! cro3 chroot --cros ${CROS} --board ${BOARD} --dut ${DUT}
+//! cro3 chroot enter --cros ${CROS} --cmd 'cros_workon list --all'
+//! ```
+//!
+//! The chroot can be created at a non-default location (e.g. on a faster
+//! disk). The location is recorded, and the other cro3 commands on the
+//! checkout use it.
+//! ```
+//! cro3 chroot create --cros ${CROS}
+//! cro3 chroot create --cros ${CROS} --chroot /ssd/chroot --out-dir /ssd/out
+//! cro3 chroot update --cros ${CROS}
+//! # Show the disk usage of the chroot, the out dir, the SDK cache and the
+//! # images
+//! cro3 chroot usage --cros ${CROS}
+//! # Delete the chroot (after a confirmation). This fails while the chroot is
+//! # entered.
+//! cro3 chroot delete --cros ${CROS}
+//! ```
+//!
+//! On machines where cros_sdk can not run (e.g. no sudo or an unsupported
+//! distro), cro3 can run it in a podman or docker container instead. The image
+//! needs depot_tools in PATH. The checkout and ~/.cro3 are mounted at the same
+//! paths, and all the cro3 commands that use the chroot (e.g. `cro3 build`)
+//! go through the container.
+//! ```
+//! cro3 config set sdk_container podman registry.example.com/cros-sdk:latest
+//! cro3 build pkg --cros ${CROS} --board ${BOARD} ${PACKAGE_NAME}
+//! # Back to cros_sdk on the host
+//! cro3 config clear sdk_container
+//! ```
+
+use anyhow::bail;
 use anyhow::Result;
 use argh::FromArgs;
+use cro3::chroot::chroot_disk_usage;
+use cro3::chroot::create_chroot;
+use cro3::chroot::delete_chroot;
 use cro3::chroot::Chroot;
+use cro3::chroot::ChrootLocation;
 use cro3::dut::SshInfo;
+use cro3::image_cache::format_size;
 use cro3::repo::get_cros_dir;
+use cro3::util::picker::confirm;
+use tracing::info;
 
 #[derive(FromArgs, PartialEq, Debug)]
-/// run in chroot
+/// run in chroot, or manage it
 #[argh(subcommand, name = "chroot")]
 pub struct Args {
     /// target cros repo dir
@@ -29,21 +71,51 @@ pub struct Args {
 
     #[argh(option, hidden_help)]
     repo: Option<String>,
+
+    #[argh(subcommand)]
+    nested: Option<SubCommand>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum SubCommand {
+    Create(ArgsCreate),
+    Update(ArgsUpdate),
+    Delete(ArgsDelete),
+    Enter(ArgsEnter),
+    Usage(ArgsUsage),
 }
+
 #[tracing::instrument(level = "trace")]
 pub fn run(args: &Args) -> Result<()> {
-    let repo = get_cros_dir(&args.cros)?;
+    match &args.nested {
+        Some(SubCommand::Create(args)) => run_create(args),
+        Some(SubCommand::Update(args)) => run_update(args),
+        Some(SubCommand::Delete(args)) => run_delete(args),
+        Some(SubCommand::Enter(args)) => enter(&args.cros, &args.dut, &args.board, &args.cmd),
+        Some(SubCommand::Usage(args)) => run_usage(args),
+        None => enter(&args.cros, &args.dut, &args.board, &args.cmd),
+    }
+}
+
+fn enter(
+    cros: &Option<String>,
+    dut: &Option<String>,
+    board: &Option<String>,
+    cmd: &Option<String>,
+) -> Result<()> {
+    let repo = get_cros_dir(cros)?;
     let mut additional_args = Vec::new();
-    if let Some(dut) = &args.dut {
+    if let Some(dut) = dut {
         let dut = SshInfo::new(dut)?.into_forwarded()?;
         let port = dut.port();
         additional_args.push(format!("DUT=localhost:{port}"));
     }
-    if let Some(board) = &args.board {
+    if let Some(board) = board {
         additional_args.push(format!("BOARD={board}"));
     }
     let chroot = Chroot::new(&repo)?;
-    if let Some(cmd) = &args.cmd {
+    if let Some(cmd) = cmd {
         let mut script = String::new();
         for l in additional_args {
             script.push_str(&format!("{l}\n"));
@@ -55,3 +127,129 @@ pub fn run(args: &Args) -> Result<()> {
     }
     Ok(())
 }
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// enter the chroot, or run a command in it
+#[argh(subcommand, name = "enter")]
+struct ArgsEnter {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+    /// DUT env var in chroot
+    #[argh(option)]
+    dut: Option<String>,
+    /// BOARD env var in chroot
+    #[argh(option)]
+    board: Option<String>,
+    /// if specified, run the command in chroot and exit.
+    #[argh(option)]
+    cmd: Option<String>,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// create the chroot of a checkout
+#[argh(subcommand, name = "create")]
+struct ArgsCreate {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+    /// path to create the chroot at (default: chroot in the checkout)
+    #[argh(option)]
+    chroot: Option<String>,
+    /// path of the out dir of the chroot (default: out in the checkout)
+    #[argh(option)]
+    out_dir: Option<String>,
+    /// delete the existing chroot and create a new one
+    #[argh(switch)]
+    replace: bool,
+}
+fn run_create(args: &ArgsCreate) -> Result<()> {
+    let repo = get_cros_dir(&args.cros)?;
+    let location = ChrootLocation {
+        chroot: args.chroot.clone(),
+        out_dir: args.out_dir.clone(),
+    };
+    let recorded = ChrootLocation::of_repo(&repo)?;
+    if recorded != location && recorded.chroot_dir(&repo).exists() && !args.replace {
+        bail!(
+            "A chroot already exists at {:?}. Please delete it first, or use --replace.",
+            recorded.chroot_dir(&repo)
+        );
+    }
+    create_chroot(&repo, &location, args.replace)?;
+    info!("Created the chroot at {:?}", location.chroot_dir(&repo));
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// update the packages of the SDK in the chroot
+#[argh(subcommand, name = "update")]
+struct ArgsUpdate {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+}
+fn run_update(args: &ArgsUpdate) -> Result<()> {
+    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    chroot.run_bash_script_in_chroot("update_chroot", "update_chroot", None)?;
+    info!("Updated the chroot");
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// delete the chroot of a checkout
+#[argh(subcommand, name = "delete")]
+struct ArgsDelete {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+    /// do not ask for confirmation
+    #[argh(switch)]
+    yes: bool,
+}
+fn run_delete(args: &ArgsDelete) -> Result<()> {
+    let repo = get_cros_dir(&args.cros)?;
+    let chroot_dir = ChrootLocation::of_repo(&repo)?.chroot_dir(&repo);
+    if !chroot_dir.exists() {
+        bail!("No chroot is found at {chroot_dir:?}");
+    }
+    print_usage(&repo)?;
+    if !args.yes
+        && !confirm(
+            &format!(
+                "Delete the chroot at {chroot_dir:?}? The packages built in it and the files in \
+                 its home directory will be lost."
+            ),
+            false,
+        )?
+    {
+        bail!("Aborted");
+    }
+    delete_chroot(&repo)?;
+    info!("Deleted the chroot at {chroot_dir:?}");
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show the disk usage of the chroot of a checkout
+#[argh(subcommand, name = "usage")]
+struct ArgsUsage {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+}
+fn run_usage(args: &ArgsUsage) -> Result<()> {
+    print_usage(&get_cros_dir(&args.cros)?)
+}
+
+fn print_usage(repo: &str) -> Result<()> {
+    let usage = chroot_disk_usage(repo)?;
+    for (path, size) in &usage {
+        println!("{:>10}  {path}", format_size(*size));
+    }
+    println!(
+        "{:>10}  total",
+        format_size(usage.iter().map(|(_, size)| size).sum())
+    );
+    Ok(())
+}
@@ -0,0 +1,76 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use argh::FromArgs;
+use cro3::cros::cached_versions;
+use cro3::dut::DUT_BOARD_CACHE;
+use cro3::dut::SSH_CACHE;
+
+use crate::cmd::board::BOARD_CACHE;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// print completion candidates (used by the shell completion scripts)
+#[argh(subcommand, name = "__complete")]
+pub struct Args {
+    /// words on the command line after `cro3`, including the one being
+    /// completed (can be empty) at the end
+    #[argh(positional, greedy)]
+    words: Vec<String>,
+}
+
+#[tracing::instrument(level = "trace")]
+pub fn run(args: &Args) -> Result<()> {
+    let words = &args.words;
+    let (cur, prev) = match words.len() {
+        0 => return Ok(()),
+        1 => (words[0].as_str(), ""),
+        n => (words[n - 1].as_str(), words[n - 2].as_str()),
+    };
+    let candidates = match prev {
+        "--dut" => dut_candidates()?,
+        "--board" => board_candidates()?,
+        "--version" if words.first().map(String::as_str) == Some("flash") => version_candidates()?,
+        _ => BTreeSet::new(),
+    };
+    for c in candidates.iter().filter(|c| c.starts_with(cur)) {
+        println!("{c}");
+    }
+    Ok(())
+}
+
+/// DUT IDs and their addresses
+fn dut_candidates() -> Result<BTreeSet<String>> {
+    let mut candidates = BTreeSet::new();
+    for (id, ssh) in SSH_CACHE.entries()? {
+        candidates.insert(id);
+        if ssh.port() == 22 {
+            candidates.insert(ssh.host().to_string());
+        } else {
+            candidates.insert(ssh.host_and_port());
+        }
+    }
+    Ok(candidates)
+}
+
+/// Boards listed by `cro3 board list` and boards of the known DUTs
+fn board_candidates() -> Result<BTreeSet<String>> {
+    let mut candidates: BTreeSet<String> = BOARD_CACHE.entries()?.into_keys().collect();
+    candidates.extend(DUT_BOARD_CACHE.entries()?.into_values());
+    Ok(candidates)
+}
+
+/// Versions resolved before, in addition to the aliases
+fn version_candidates() -> Result<BTreeSet<String>> {
+    let mut candidates: BTreeSet<String> = ["latest", "latest-dev", "latest-official"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    candidates.extend(cached_versions()?);
+    Ok(candidates)
+}
@@ -52,12 +52,23 @@ _cro3_current_repo() {
   fi
 }
 
+# ask cro3 for candidates that depend on its state (DUTs, boards, versions)
+_cro3_complete_dynamic() {
+  ${COMP_WORDS[0]} __complete -- "${COMP_WORDS[@]:1:COMP_CWORD}" 2>/dev/null
+}
+
 _cro3_get_duts() {
-  ${COMP_WORDS[0]} dut list --ids 2>/dev/null
+  local DUTS
+  DUTS=$(_cro3_complete_dynamic)
+  if [ -n "${DUTS}" ]; then
+    echo "${DUTS}"
+  else
+    ${COMP_WORDS[0]} dut list --ids 2>/dev/null
+  fi
 }
 
 _cro3_get_tests() {
-  ${COMP_WORDS[0]} tast list --cached 2>/dev/null | cut -f 1 -d,
+  ${COMP_WORDS[0]} tast list --cached 2>/dev/null | cut -f 1
 }
 
 _cro3_get_servos() {
@@ -69,7 +80,13 @@ _cro3_get_packages() {
 }
 
 _cro3_get_boards() {
-  ${COMP_WORDS[0]} board list --cached 2>/dev/null | cut -f 1
+  local BOARDS
+  BOARDS=$(_cro3_complete_dynamic)
+  if [ -n "${BOARDS}" ]; then
+    echo "${BOARDS}"
+  else
+    ${COMP_WORDS[0]} board list --cached 2>/dev/null | cut -f 1
+  fi
 }
 
 _cro3_get_configs() {
@@ -156,8 +173,8 @@ _cro3_get_options() { # current
             _cro3_get_configs
           fi
         esac
-      # Subcommands must be shown as it is.
-      elif [ ${otype} = 3 ]; then
+      # Subcommands must be shown as it is, except for internal ones.
+      elif [ ${otype} = 3 ] && [ "${a#__}" = "${a}" ]; then
         echo "${a}"
       fi
       ;;
@@ -170,7 +187,7 @@ _cro3() { # command current prev
   local prev=$3
   local dir_opts="--dir --dest --cros --arc"
   local file_opts="--image"
-  local todo_opts="--version --workon"
+  local todo_opts="--workon"
   local servo_serial_opts="--serial --servo"
 
   COMPREPLY=()
@@ -191,6 +208,10 @@ _cro3() { # command current prev
     local BOARDS
     BOARDS=$(_cro3_get_boards)
     COMPREPLY=($(compgen -W "${BOARDS}" -- "$cur"))
+  elif [ "$prev" = "--version" ]; then
+    local VERSIONS
+    VERSIONS=$(_cro3_complete_dynamic)
+    COMPREPLY=($(compgen -W "${VERSIONS}" -- "$cur"))
   elif [ "$prev" = "--branch" ]; then
     local BRANCHES
     BRANCHES=$(_cro3_get_branches)
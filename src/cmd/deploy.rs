@@ -5,22 +5,86 @@
 // https://developers.google.com/open-source/licenses/bsd
 
 //! ## Deploy packages
+//! Packages built locally (e.g. with `cro3 build pkg`) are pushed to the DUT
+//! with `cros deploy`, one by one after the ones they depend on. Rootfs
+//! verification of the DUT is removed first if needed, and the result of each
+//! package is shown at the end.
 //! ```
-//! cro3 deploy --cros $CROS --dut $DUT --package $PACKAGE_NAME --autologin
+//! cro3 deploy --cros ${CROS} --dut ${DUT} ${PACKAGE_NAME}
+//! cro3 deploy --cros ${CROS} --dut ${DUT} --packages shill,debugd --packages libbrillo
+//! ```
+//!
+//! Redeploying a large package (e.g. chromeos-chrome) that changed slightly is
+//! much faster with `--delta` on a slow link, which sends only the parts of
+//! the files that differ from the ones on the DUT. The packages not installed
+//! on the DUT yet are deployed as usual.
+//! ```
+//! cro3 deploy --cros ${CROS} --dut ${DUT} --delta chromeos-chrome
+//! ```
+//!
+//! After the reboot, the DUT is checked to boot the deployed slot and to keep
+//! ui, shill and dbus running. If it does not, it is rolled back to the other
+//! slot (if bootable) and the reason is reported. The firmware also falls back
+//! to the other slot by itself if the DUT fails to boot. Use `--no-rollback`
+//! to keep the DUT as is for debugging.
+//!
+//! The same packages can be deployed to many DUTs (e.g. of different models)
+//! in parallel, with `--dut` repeated, a DUT group or `--filter`.
+//! ```
+//! cro3 deploy --cros ${CROS} --dut ${DUT} --dut 192.0.2.2 ${PACKAGE_NAME}
+//! cro3 deploy --cros ${CROS} --filter 'board==brya' --jobs 8 ${PACKAGE_NAME}
+//! ```
+//!
+//! `cro3 deploy kernel` writes the kernel built locally and its modules to the
+//! DUT, reboots it, and checks that the new kernel is running. It fails if the
+//! DUT came back with the old kernel (e.g. fell back to the other slot).
+//! ```
+//! cro3 deploy kernel --cros ${CROS} ${DUT}
+//! # Boot the new kernel from the other slot once
+//! cro3 deploy kernel --cros ${CROS} --ab-update ${DUT}
+//! ```
+//!
+//! `cro3 deploy chrome` deploys Chrome built in a Chrome checkout with its
+//! deploy_chrome, outside the chroot. The Chrome version deployed to each DUT
+//! is recorded.
+//! ```
+//! cro3 deploy chrome --build-dir out_${BOARD}/Release --dut ${DUT}
+//! cro3 deploy chrome --list
 //! ```
 
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
 use argh::FromArgs;
 use cro3::chroot::Chroot;
 use cro3::cros::ensure_testing_rsa_is_there;
+use cro3::deploy::deploy_chrome;
+use cro3::deploy::deploy_kernel;
+use cro3::deploy::deploy_order;
+use cro3::deploy::deploy_packages;
+use cro3::deploy::ensure_rootfs_writable;
+use cro3::deploy::DeployResult;
+use cro3::deploy::Rollback;
+use cro3::deploy::DUT_CHROME_BUILDS;
+use cro3::dut::expand_dut_groups;
+use cro3::dut::filter_duts;
+use cro3::dut::infer_board;
+use cro3::dut::resolve_dut;
 use cro3::dut::SshInfo;
+use cro3::progress::format_duration;
 use cro3::repo::get_cros_dir;
 use once_cell::sync::Lazy;
+use rayon::prelude::*;
 use regex::Regex;
+use tracing::error;
 use tracing::info;
+use tracing::warn;
 
 static RE_CROS_KERNEL: Lazy<Regex> = Lazy::new(|| Regex::new("chromeos-kernel-").unwrap());
 
@@ -32,12 +96,31 @@ pub struct Args {
     #[argh(option)]
     cros: Option<String>,
 
-    /// a DUT identifier (e.g. 127.0.0.1, localhost:2222)
+    /// a DUT identifier (e.g. 127.0.0.1, localhost:2222). If omitted, choose
+    /// one of the registered DUTs interactively. Can be repeated (or a DUT
+    /// group) to deploy to multiple DUTs in parallel.
+    #[argh(option)]
+    dut: Vec<String>,
+
+    /// deploy to all the DUTs that match the filter expression (e.g.
+    /// 'board==brya && has_servo') in parallel
+    #[argh(option)]
+    filter: Option<String>,
+
+    /// number of DUTs to deploy to at the same time (default: 4)
+    #[argh(option, default = "4")]
+    jobs: usize,
+
+    /// board of the packages (default: the board of the DUT)
     #[argh(option)]
-    dut: String,
+    board: Option<String>,
 
     /// packages to deploy
-    #[argh(positional)]
+    #[argh(positional, arg_name = "package")]
+    positional_packages: Vec<String>,
+
+    /// packages to deploy, separated by commas or spaces. Can be repeated.
+    #[argh(option)]
     packages: Vec<String>,
 
     /// if specified, it will skip automatic reboot
@@ -48,50 +131,288 @@ pub struct Args {
     #[argh(switch)]
     ab_update: bool,
 
+    /// do not roll back to the other slot if the DUT is unhealthy after the
+    /// reboot
+    #[argh(switch)]
+    no_rollback: bool,
+
+    /// update the packages deployed before by sending only the differences of
+    /// their files (with rsync)
+    #[argh(switch)]
+    delta: bool,
+
     #[argh(option, hidden_help)]
     repo: Option<String>,
+
+    #[argh(subcommand)]
+    nested: Option<SubCommand>,
 }
 
-#[tracing::instrument(level = "trace")]
-pub fn run(args: &Args) -> Result<()> {
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum SubCommand {
+    Kernel(ArgsKernel),
+    Chrome(ArgsChrome),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// deploy Chrome built in a Chrome checkout with deploy_chrome
+#[argh(subcommand, name = "chrome")]
+struct ArgsChrome {
+    /// build dir of Chrome, e.g. out_brya/Release
+    #[argh(option)]
+    build_dir: Option<String>,
+
+    /// a DUT identifier. If omitted, choose one of the registered DUTs
+    /// interactively.
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// board of the DUT (default: the board of the DUT)
+    #[argh(option)]
+    board: Option<String>,
+
+    /// cros repo dir to use deploy_chrome from, if the build dir is not in a
+    /// Chrome checkout
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// do not strip the binaries (faster, but uses more space on the DUT)
+    #[argh(switch)]
+    nostrip: bool,
+
+    /// show the Chrome deployed to each DUT instead
+    #[argh(switch)]
+    list: bool,
+}
+fn run_chrome(args: &ArgsChrome) -> Result<()> {
+    if args.list {
+        let mut builds: Vec<_> = DUT_CHROME_BUILDS.entries()?.into_iter().collect();
+        builds.sort_by(|a, b| a.0.cmp(&b.0));
+        for (dut, build) in builds {
+            let deployed_at = chrono::DateTime::from_timestamp(build.deployed_at, 0)
+                .map(|t| t.with_timezone(&chrono::Local).format("%F %R").to_string())
+                .unwrap_or_default();
+            println!(
+                "{dut:<24} {:<16} {deployed_at}  {} {}",
+                build.version,
+                build.build_dir,
+                build.git_hash.as_deref().unwrap_or("")
+            );
+        }
+        return Ok(());
+    }
+    let build_dir = args
+        .build_dir
+        .as_ref()
+        .context("Please specify --build-dir")?;
     ensure_testing_rsa_is_there()?;
+    let dut = resolve_dut(&args.dut, args.board.as_deref())?;
+    let board = infer_board(args.board.as_deref(), Some(&dut))?;
+    // deploy_chrome in a Chrome checkout does not need a cros checkout
+    let cros = get_cros_dir(&args.cros).ok();
+    let extra_args = if args.nostrip {
+        vec!["--nostrip".to_string()]
+    } else {
+        Vec::new()
+    };
+    let build = deploy_chrome(
+        &dut,
+        &board,
+        Path::new(build_dir),
+        cros.as_deref(),
+        &extra_args,
+    )?;
+    info!("{dut} is running Chrome {}", build.version);
+    Ok(())
+}
 
-    let target = SshInfo::new(&args.dut)?.into_forwarded()?;
-    info!("Target DUT is {:?}", target);
+#[derive(FromArgs, PartialEq, Debug)]
+/// deploy the kernel built locally and check that the DUT boots it
+#[argh(subcommand, name = "kernel")]
+struct ArgsKernel {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
 
-    let board = target.get_board()?;
-    let packages_str = args.packages.join(" ");
+    /// board of the kernel (default: the board of the DUT)
+    #[argh(option)]
+    board: Option<String>,
+
+    /// write the kernel to the inactive slot and boot it once (see
+    /// update_kernel.sh --ab_update)
+    #[argh(switch)]
+    ab_update: bool,
+
+    /// do not roll back to the previous slot if the DUT does not boot the
+    /// new kernel or is unhealthy with it
+    #[argh(switch)]
+    no_rollback: bool,
+
+    /// a DUT identifier. If omitted, choose one of the registered DUTs
+    /// interactively.
+    #[argh(positional)]
+    dut: Option<String>,
+}
+fn run_kernel(args: &ArgsKernel) -> Result<()> {
+    ensure_testing_rsa_is_there()?;
+    let dut = resolve_dut(&args.dut, args.board.as_deref())?;
+    let board = infer_board(args.board.as_deref(), Some(&dut))?;
     let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    deploy_kernel(
+        &chroot,
+        &board,
+        &SshInfo::new(&dut)?,
+        args.ab_update,
+        !args.no_rollback,
+    )?;
+    Ok(())
+}
+
+impl Args {
+    fn packages(&self) -> Vec<String> {
+        let mut packages = self.positional_packages.clone();
+        for p in self.packages.iter().flat_map(|p| p.split([',', ' '])) {
+            if !p.is_empty() && !packages.iter().any(|e| e == p) {
+                packages.push(p.to_string());
+            }
+        }
+        packages
+    }
+}
 
-    let kernel_pkg = extract_kernel_pkg(&args.packages)?;
+#[tracing::instrument(level = "trace")]
+pub fn run(args: &Args) -> Result<()> {
+    match &args.nested {
+        Some(SubCommand::Kernel(args)) => return run_kernel(args),
+        Some(SubCommand::Chrome(args)) => return run_chrome(args),
+        None => {}
+    }
+    ensure_testing_rsa_is_there()?;
+    let packages = args.packages();
+    if packages.is_empty() {
+        bail!("Please specify the packages to deploy");
+    }
+    let kernel_pkg = extract_kernel_pkg(&packages)?;
+    let mut targets = expand_dut_groups(&args.dut)?;
+    if let Some(filter) = &args.filter {
+        for dut in filter_duts(filter)? {
+            if !targets.contains(&dut) {
+                targets.push(dut);
+            }
+        }
+    }
+    if targets.len() > 1 || args.filter.is_some() {
+        if kernel_pkg.is_some() {
+            bail!("Deploying a kernel to multiple DUTs is not supported");
+        }
+        return run_many(args, &targets, &packages);
+    }
 
-    cros_workon_user_packages(&chroot, &board, &args.packages, &packages_str, &target)?;
+    let dut = resolve_dut(&targets.first().cloned(), args.board.as_deref())?;
+    let board = infer_board(args.board.as_deref(), Some(&dut))?;
+    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    let user_pkgs = prepare_packages(&chroot, &board, &packages)?;
+    if !user_pkgs.is_empty() {
+        let results = deploy_to_dut(&chroot, &board, &dut, &user_pkgs, args.delta)?;
+        print_results(&results);
+        let failed = results.iter().filter(|r| !r.status.is_deployed()).count();
+        if failed > 0 {
+            bail!("{failed} of {} packages were not deployed", results.len());
+        }
+    }
 
     if kernel_pkg.is_some() {
-        chroot.run_bash_script_in_chroot(
-            "update_kernel",
-            &format!(
-                r###"
-cros-workon-{board} start {packages_str}
-TOPDIR=~/trunk
-[ -d $TOPDIR ] || TOPDIR=~/chromiumos
-$TOPDIR/src/scripts/update_kernel.sh {} --remote={} --ssh_port {} --remote_bootargs
-"###,
-                if args.ab_update { "--ab_update" } else { "" },
-                target.host(),
-                target.port()
-            ),
-            None,
+        deploy_kernel(
+            &chroot,
+            &board,
+            &SshInfo::new(&dut)?,
+            args.ab_update,
+            !args.no_rollback,
         )?;
-
         return Ok(());
     }
-
     if !args.skip_reboot {
-        info!("Rebooting DUT...");
-        target.run_cmd_piped(&["reboot; exit"])?;
+        reboot_and_verify(&dut, !args.no_rollback)?;
     }
+    Ok(())
+}
 
+/// Deploys `packages` to many DUTs in parallel, after resolving the order of
+/// the packages once for each board
+fn run_many(args: &Args, targets: &[String], packages: &[String]) -> Result<()> {
+    if targets.is_empty() {
+        bail!("No DUTs to deploy to");
+    }
+    let mut boards: BTreeMap<&String, String> = BTreeMap::new();
+    for dut in targets {
+        boards.insert(dut, infer_board(args.board.as_deref(), Some(dut))?);
+    }
+    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    let mut ordered: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for board in boards.values() {
+        if !ordered.contains_key(board) {
+            ordered.insert(board.clone(), prepare_packages(&chroot, board, packages)?);
+        }
+    }
+    info!(
+        "Deploying to {} DUTs, {} at a time",
+        targets.len(),
+        args.jobs
+    );
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs.max(1))
+        .build()
+        .context("Failed to create a thread pool")?;
+    let results: Vec<(&String, Result<usize>, Duration)> = pool.install(|| {
+        targets
+            .par_iter()
+            .map(|dut| {
+                let start = Instant::now();
+                let board = &boards[dut];
+                let result = deploy_to_dut(&chroot, board, dut, &ordered[board], args.delta)
+                    .and_then(|results| {
+                        let failed: Vec<String> = results
+                            .iter()
+                            .filter(|r| !r.status.is_deployed())
+                            .map(|r| format!("{} {}", r.package, r.status))
+                            .collect();
+                        if !failed.is_empty() {
+                            bail!("{}", failed.join(", "));
+                        }
+                        if !args.skip_reboot {
+                            reboot_and_verify(dut, !args.no_rollback)?;
+                        }
+                        Ok(results.len())
+                    });
+                match &result {
+                    Ok(_) => info!("{dut}: Done"),
+                    Err(e) => error!("{dut}: {e:#}"),
+                }
+                (dut, result, start.elapsed())
+            })
+            .collect()
+    });
+    let width = targets.iter().map(|id| id.len()).max().unwrap_or_default();
+    println!("{:width$} {:12} {:>6} RESULT", "DUT", "BOARD", "MIN");
+    let mut failed = 0;
+    for (dut, result, elapsed) in &results {
+        let status = match result {
+            Ok(n) => format!("ok: {n} packages deployed"),
+            Err(e) => {
+                failed += 1;
+                format!("failed: {e:#}")
+            }
+        };
+        println!(
+            "{dut:width$} {:12} {:>6.1} {status}",
+            boards[dut],
+            elapsed.as_secs_f64() / 60.0
+        );
+    }
+    if failed > 0 {
+        bail!("Failed to deploy to {failed} of {} DUTs", results.len());
+    }
     Ok(())
 }
 
@@ -110,31 +431,71 @@ fn extract_kernel_pkg(packages: &[String]) -> Result<Option<String>> {
     }
 }
 
-fn cros_workon_user_packages(
+/// Starts working on `packages` and returns the ones other than the kernel,
+/// in the order to deploy them
+fn prepare_packages(chroot: &Chroot, board: &str, packages: &[String]) -> Result<Vec<String>> {
+    chroot.run_bash_script_in_chroot(
+        "start_workon",
+        &format!("cros-workon-{board} start {}", packages.join(" ")),
+        None,
+    )?;
+    // Filter out all kernel packages
+    let user_pkgs: Vec<String> = packages
+        .iter()
+        .filter(|&s| !RE_CROS_KERNEL.is_match(s))
+        .cloned()
+        .collect();
+    deploy_order(chroot, board, &user_pkgs)
+}
+
+fn deploy_to_dut(
     chroot: &Chroot,
     board: &str,
+    dut: &str,
     packages: &[String],
-    packages_str: &str,
-    target: &SshInfo,
-) -> Result<()> {
-    // Filter out all kernel packages and join them into a space seperated list.
-    let user_pkgs: String = packages
-        .iter()
-        .filter(|&s| !RE_CROS_KERNEL.is_match(s))
-        .map(|s| s.to_string())
-        .collect::<Vec<_>>()
-        .join(" ");
+    delta: bool,
+) -> Result<Vec<DeployResult>> {
+    let target = SshInfo::new(dut)?.into_forwarded()?;
+    info!("{dut}: Target DUT is {:?}", target);
+    if let Some(lease) = target.get_lease()?.filter(|l| !l.is_mine()) {
+        warn!("{dut}: The DUT is {}", lease.description());
+    }
+    ensure_rootfs_writable(&target)?;
+    Ok(deploy_packages(chroot, board, &target, packages, delta))
+}
 
-    if !user_pkgs.is_empty() {
-        chroot.run_bash_script_in_chroot(
-            "deploy",
-            &format!(
-                r"cros-workon-{board} start {packages_str} && cros deploy --force {} {user_pkgs}",
-                target.host_and_port()
-            ),
-            None,
-        )?;
+fn print_results(results: &[DeployResult]) {
+    println!("{:<40} {:>8}  STATUS", "PACKAGE", "TIME");
+    for r in results {
+        println!(
+            "{:<40} {:>8}  {}",
+            r.package,
+            r.duration
+                .map(|d| format_duration(d.as_secs_f64()))
+                .unwrap_or("-".to_string()),
+            r.status
+        );
     }
+}
 
+/// Reboots the DUT, and rolls it back if it is unhealthy after the reboot
+fn reboot_and_verify(dut: &str, rollback: bool) -> Result<()> {
+    let ssh = SshInfo::new(dut)?;
+    let rollback = if rollback {
+        Rollback::to_other_slot(&ssh)?
+    } else {
+        None
+    };
+    if let Some(rollback) = &rollback {
+        rollback.arm(&ssh)?;
+    }
+    info!("{dut}: Rebooting DUT...");
+    ssh.run_cmd_piped(&["reboot; exit"])?;
+    ssh.close_connection()?;
+    if let Some(rollback) = &rollback {
+        std::thread::sleep(Duration::from_secs(10));
+        rollback.verify(&ssh)?;
+        info!("{dut}: The DUT is healthy after the deploy");
+    }
     Ok(())
 }
@@ -0,0 +1,100 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! ## Install DLCs to a DUT
+//! The DLC images built together with the OS on the DUT are downloaded,
+//! pushed and installed with dlcservice.
+//! ```
+//! cro3 dlc install --dut ${DUT} sample-dlc
+//! # DLCs of another build (they must match the OS on the DUT)
+//! cro3 dlc install --dut ${DUT} --version 15753.0.0 sample-dlc
+//! # A DLC image built locally
+//! cro3 dlc install --dut ${DUT} --image dlc.img sample-dlc
+//! ```
+
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Result;
+use argh::FromArgs;
+use cro3::cros::lookup_full_version;
+use cro3::dlc::download_dlc;
+use cro3::dlc::install_dlc;
+use cro3::dlc::DlcBuild;
+use cro3::dut::resolve_dut;
+use cro3::dut::SshInfo;
+use tracing::info;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// install DLCs to DUTs
+#[argh(subcommand, name = "dlc")]
+pub struct Args {
+    #[argh(subcommand)]
+    nested: SubCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum SubCommand {
+    Install(ArgsInstall),
+}
+#[tracing::instrument(level = "trace")]
+pub fn run(args: &Args) -> Result<()> {
+    match &args.nested {
+        SubCommand::Install(args) => run_install(args),
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// download DLCs matching the OS version of a DUT and install them
+#[argh(subcommand, name = "install")]
+struct ArgsInstall {
+    /// target DUT
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// version of the build to get the DLCs from (e.g. 15753.0.0), if it is
+    /// not the one running on the DUT
+    #[argh(option)]
+    version: Option<String>,
+
+    /// path to a DLC image to install instead of downloading one (only with
+    /// a DLC ID)
+    #[argh(option)]
+    image: Option<String>,
+
+    /// IDs of the DLCs to install (e.g. sample-dlc)
+    #[argh(positional)]
+    ids: Vec<String>,
+}
+fn run_install(args: &ArgsInstall) -> Result<()> {
+    if args.ids.is_empty() {
+        bail!("Please specify the IDs of the DLCs to install");
+    }
+    if args.image.is_some() && args.ids.len() > 1 {
+        bail!("--image can be used with only one DLC ID");
+    }
+    let dut = resolve_dut(&args.dut, None)?;
+    let ssh = SshInfo::new(&dut)?;
+    for id in &args.ids {
+        let image = if let Some(image) = &args.image {
+            PathBuf::from(image)
+        } else {
+            let mut build = DlcBuild::of_dut(&ssh)?;
+            if let Some(version) = &args.version {
+                build.builder_path = format!(
+                    "{board}-release/{}",
+                    lookup_full_version(version, &build.board)?,
+                    board = build.board
+                );
+            }
+            download_dlc(&build, id)?
+        };
+        let root = install_dlc(&ssh, id, &image)?;
+        info!("Installed DLC {id} on {dut} (mounted at {root})");
+    }
+    Ok(())
+}
@@ -18,28 +18,169 @@
 //! # Show the list of DUTs registered
 //! cro3 dut list
 //!
+//! # Copy files to / from a DUT (with rsync if available on both sides)
+//! cro3 dut push --dut ${DUT} --dest /usr/local/bin ./out/tool
+//! cro3 dut pull --dut ${DUT} /var/log/messages /var/log/chrome
+//!
+//! # Generate a per-user SSH key and install it to DUTs, so that they are
+//! # accessible even without the testing key. Rotate it from time to time.
+//! cro3 dut key generate
+//! cro3 dut key --filter 'location==lab' install
+//! cro3 dut key rotate
+//!
+//! # Lease a DUT for 2 hours so that teammates know you are using it
+//! cro3 dut lease --dut ${DUT} --minutes 120 --reason 'bisecting a crash'
+//!
+//! # Tag a DUT, then list DUTs by a filter expression on the tags
+//! cro3 dut tag --dut ${DUT} location=desk has_servo
+//! cro3 dut list --filter 'board==brya && has_servo'
+//!
+//! # Export the DUT list (with the cached board, model, release and tags) for a
+//! # spreadsheet, or as Swarming bot dimensions
+//! cro3 dut export --format csv -o /tmp/duts.csv
+//! cro3 dut export --format swarming
+//!
 //! # Check connection and remove DUTs that have reused IP addresses
 //! cro3 dut list --update
 //!
+//! # Follow the kernel and Chrome logs of a DUT, saving them to a local file
+//! cro3 dut logs --dut ${DUT} --follow --source kernel --source chrome --output /tmp/dut.log
+//!
+//! # List crash reports on a DUT, then download Chrome crashes and remove them
+//! cro3 dut crashes --dut ${DUT}
+//! cro3 dut crashes --dut ${DUT} --pull --clear chrome
+//!
+//! # Keep downloading new crash reports as they appear
+//! cro3 dut crashes --dut ${DUT} --watch
+//!
+//! # Reboot / power off / power on / cold reset a DUT, using SSH, servo or the
+//! # power_hook in the config (e.g. for a smart plug), whichever is available
+//! cro3 dut power --dut ${DUT} reboot
+//! cro3 dut power --dut ${DUT} cold-reset
+//! cro3 config set power_hook '/path/to/smart_plug.sh'
+//!
+//! # Show the screen of a DUT with a local VNC viewer, stopping VNC on the DUT
+//! # when the viewer is closed
+//! cro3 dut vnc --dut ${DUT} --viewer 'xtightvncviewer -encodings raw'
+//!
+//! # Wait until a DUT shows the login screen, e.g. between flash and deploy
+//! cro3 dut wait --dut ${DUT} --timeout 300 --state login-screen
+//!
+//! # Save the state of a DUT (users, Chrome profiles, dev packages) and restore
+//! # it later without reflashing. Snapshots are kept under ~/.cro3/snapshots.
+//! cro3 dut snapshot --dut ${DUT} save logged_in
+//! cro3 dut snapshot --dut ${DUT} restore logged_in
+//! cro3 dut snapshot --dut ${DUT} list
+//!
+//! # Capture DNS packets on wlan0 of a DUT for 60 seconds into a local pcap file
+//! cro3 dut pcap --dut ${DUT} --iface wlan0 --duration 60 -o /tmp/dns.pcap 'udp port 53'
+//!
+//! # Check the clock skew of a DUT, and set its clock to the time of this machine
+//! cro3 dut time --dut ${DUT} --fix
+//!
+//! # Open a shell on a DUT with local line editing, per-DUT history (kept in
+//! # ~/.cro3/shell_history) and Tab completion of remote paths, instead of a
+//! # plain SSH login shell
+//! cro3 dut shell --dut ${DUT} --line-edit
+//!
+//! # Show CPU / memory usage of a DUT and its processes, or record it as CSV
+//! cro3 dut top --dut ${DUT}
+//! cro3 dut top --dut ${DUT} --interval 5 --count 60 --csv > /tmp/top.csv
+//!
+//! # Sample the battery, thermal zones and fans of a DUT every 2 seconds
+//! cro3 dut sensors --dut ${DUT} --interval 2
+//! cro3 dut sensors --dut ${DUT} --csv --count 60 > /tmp/sensors.csv
+//!
+//! # Take a screenshot of a DUT and open it
+//! cro3 dut screenshot --dut ${DUT} --output /tmp/bug.png --open
+//!
 //! # Show DUT info
 //! cro3 dut info --dut ${DUT}
 //!
+//! # Show DUT info (versions, firmware, addresses, ...) as a JSON document
+//! cro3 dut info --dut ${DUT} --json
+//!
 //! # Show specific DUT info (e.g. ipv6_addr)
 //! cro3 dut info --dut ${DUT} ipv6_addr
 //!
 //! # Scan DUTs on the same network where `--remote` is connected.
 //! cro3 dut discover --remote ${IP} | tee /tmp/dut_discovered.json
+//!
+//! # Scan DUTs advertised via mDNS, or listening SSH in a subnet
+//! cro3 dut discover --mdns
+//! cro3 dut discover --cidr 192.168.0.0/24
 
+//! # Record the health of DUTs every 5 minutes, and run a script on alerts
+//! # (e.g. reboots) with the message in CRO3_ALERT env var
+//! cro3 dut monitor --health --interval 300 --alert-cmd ./notify.sh ${DUT}
+//!
+//! # Forward a port to a DUT (e.g. for Chrome remote debugging), reconnecting
+//! # automatically when the connection drops
+//! cro3 dut forward --dut ${DUT} --local 9222 --remote 9222
+//!
+//! # Make a port on this machine accessible from a DUT
+//! cro3 dut forward --dut ${DUT} --local 8080 --remote 8080 --reverse
+//!
+//! # Attach to the AP console of a DUT via servo / CCD (logged under
+//! # ~/.cro3/logs). Press Ctrl-] then ? to see the available key sequences.
+//! cro3 dut console --dut ${DUT}
+//!
 //! # Monitor DUTs and keep them accessible via local port forwarding
 //! cro3 dut monitor ${DUT}
+//!
+//! # Connect a DUT to a WiFi network, or give it a static IP address. The
+//! # settings are saved on the DUT and persist across reboots.
+//! cro3 dut net set-wifi --dut ${DUT} --ssid lab-wifi --psk 'passphrase'
+//! cro3 dut net set-static-ip --dut ${DUT} --address 192.168.0.10/24 \
+//!     --gateway 192.168.0.1
+//! cro3 dut net show --dut ${DUT}
+//!
+//! # Access a DUT behind a labstation or a bastion host (all the commands for
+//! # the DUT go through the jump host afterwards)
+//! cro3 dut jump --dut ${IP} --via root@labstation.example \
+//!     --identity ~/.ssh/testing_rsa
+//!
+//! # Or, reach all the DUTs whose host names match a regex via a bastion
+//! cro3 config set proxy_jump '^100\.90\.' user@bastion.example
+//!
+//! # Connecting to DUTs over SSH (for commands and file transfers) is retried
+//! # when it fails (4 attempts with exponential backoff from 1s, for 120s at
+//! # most by default). Commands are not rerun once they are started. Tune it
+//! # with: ATTEMPTS BACKOFF_SEC DEADLINE_SEC (use 1 0 0 to disable retries).
+//! cro3 config set ssh_retry 6 2 300
+//!
+//! # Run a command on all the DUTs that match a filter, 8 DUTs at a time
+//! cro3 dut do --filter 'board==brya' --jobs 8 -- uptime
+//!
+//! # Define a group of DUTs. A group name can be used wherever a DUT is
+//! # accepted, e.g. to run a command on all the DUTs in the group.
+//! cro3 config set dut_groups lab-rack-1 dut1 dut2 dut3
+//! cro3 dut do --dut lab-rack-1 -- uptime
+//!
+//! # Keep the outputs, exit status and timing of each DUT to attach to a bug
+//! cro3 dut do --all --capture-dir /tmp/evidence -- dmesg
+//! cro3 dut do --dut ${DUT} --capture-dir /tmp/evidence -- dmesg
 //! ```
 
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::env::current_exe;
+use std::fs;
 use std::fs::read_to_string;
+use std::fs::File;
 use std::io::stdout;
+use std::io::BufRead;
+use std::io::BufReader;
 use std::io::Read;
 use std::io::Write;
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::thread;
 use std::time;
 
@@ -48,23 +189,79 @@ use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use argh::FromArgs;
+use chrono::Local;
 use cro3::chroot::Chroot;
+use cro3::config::Config;
 use cro3::cros;
 use cro3::dut::discover_local_nodes;
+use cro3::dut::discover_mdns_nodes;
+use cro3::dut::dut_tags;
+use cro3::dut::expand_dut_groups;
 use cro3::dut::fetch_dut_info_in_parallel;
+use cro3::dut::filter_duts;
+use cro3::dut::invalidate_dut_image_info;
 use cro3::dut::register_dut;
+use cro3::dut::register_dut_with_jump;
+use cro3::dut::registered_dut_id;
+use cro3::dut::resolve_dut;
+use cro3::dut::scan_ssh_hosts;
 use cro3::dut::DutInfo;
+use cro3::dut::DutLease;
+use cro3::dut::JumpHost;
 use cro3::dut::MonitoredDut;
 use cro3::dut::PortForwarding;
 use cro3::dut::SshInfo;
+use cro3::dut::DUT_LEASE_CACHE;
+use cro3::dut::DUT_TAGS;
 use cro3::dut::SSH_CACHE;
+use cro3::dut_crash::list_crashes;
+use cro3::dut_crash::remove_crash;
+use cro3::dut_crash::CrashReport;
+use cro3::dut_export::collect_dut_records;
+use cro3::dut_export::to_csv;
+use cro3::dut_export::to_swarming_dimensions;
+use cro3::dut_health::DutHealth;
+use cro3::dut_key::generate_key;
+use cro3::dut_key::install_key;
+use cro3::dut_key::remove_key;
+use cro3::dut_key::replace_user_key;
+use cro3::dut_key::user_key_path;
+use cro3::dut_key::user_public_key;
+use cro3::dut_key::DUT_USER_KEY_CACHE;
+use cro3::dut_net::configure_wifi;
+use cro3::dut_net::connected_service;
+use cro3::dut_net::set_static_ip;
+use cro3::dut_net::static_ip_properties;
+use cro3::dut_net::wait_for_connection;
+use cro3::dut_sensors::SensorSample;
+use cro3::dut_shell;
+use cro3::dut_shell::RemoteShell;
+use cro3::dut_top::ProcSnapshot;
+use cro3::dut_top::TopSample;
 use cro3::repo::get_cros_dir;
+use cro3::servo::console_log_path;
+use cro3::servo::find_servo_for_dut;
+use cro3::servo::forward_keys_to_console;
 use cro3::servo::get_cr50_attached_to_servo;
+use cro3::servo::spawn_console_reader;
+use cro3::servo::ConsoleLog;
 use cro3::servo::LocalServo;
 use cro3::servo::ServoList;
+use cro3::servo::CONSOLE_HELP;
+use cro3::util::cro3_paths::gen_path_in_cro3_dir;
+use cro3::util::line_editor::History;
+use cro3::util::line_editor::LineEditor;
+use cro3::util::picker::confirm;
+use cro3::util::picker::is_interactive;
+use cro3::util::rotating_file::RotatingFile;
+use cro3::util::shell_helpers::shell_quote;
+use futures::executor::block_on;
 use lazy_static::lazy_static;
 use rayon::prelude::*;
 use regex::Regex;
+use serde::Serialize;
+use signal_hook::consts::SIGINT;
+use signal_hook::consts::SIGTERM;
 use termion::screen::IntoAlternateScreen;
 use tracing::error;
 use tracing::info;
@@ -81,33 +278,69 @@ pub struct Args {
 #[argh(subcommand)]
 enum SubCommand {
     ArcInfo(ArgsArcInfo),
+    Console(ArgsDutConsole),
+    Crashes(ArgsDutCrashes),
     Discover(ArgsDiscover),
     Do(ArgsDutDo),
+    Export(ArgsDutExport),
+    Forward(ArgsDutForward),
     Info(ArgsDutInfo),
+    Jump(ArgsDutJump),
     KernelConfig(ArgsDutKernelConfig),
+    Key(ArgsDutKey),
+    Lease(ArgsDutLease),
     List(ArgsDutList),
+    Logs(ArgsDutLogs),
     Shell(ArgsDutShell),
     Monitor(ArgsDutMonitor),
+    Pcap(ArgsDutPcap),
+    Net(ArgsDutNet),
+    Power(ArgsDutPower),
     Pull(ArgsPull),
     Push(ArgsPush),
+    Screenshot(ArgsDutScreenshot),
+    Sensors(ArgsDutSensors),
     Setup(ArgsSetup),
+    Snapshot(ArgsDutSnapshot),
+    Tag(ArgsDutTag),
+    Time(ArgsDutTime),
+    Top(ArgsDutTop),
     Vnc(ArgsVnc),
+    Wait(ArgsDutWait),
 }
 #[tracing::instrument(level = "trace")]
 pub fn run(args: &Args) -> Result<()> {
     match &args.nested {
         SubCommand::ArcInfo(args) => run_arc_info(args),
+        SubCommand::Console(args) => run_dut_console(args),
+        SubCommand::Crashes(args) => run_dut_crashes(args),
         SubCommand::Discover(args) => run_discover(args),
         SubCommand::Do(args) => run_dut_do(args),
+        SubCommand::Export(args) => run_dut_export(args),
+        SubCommand::Forward(args) => run_dut_forward(args),
         SubCommand::Info(args) => run_dut_info(args),
+        SubCommand::Jump(args) => run_dut_jump(args),
         SubCommand::KernelConfig(args) => run_dut_kernel_config(args),
+        SubCommand::Key(args) => run_dut_key(args),
+        SubCommand::Lease(args) => run_dut_lease(args),
         SubCommand::List(args) => run_dut_list(args),
+        SubCommand::Logs(args) => run_dut_logs(args),
         SubCommand::Shell(args) => run_dut_shell(args),
         SubCommand::Monitor(args) => run_dut_monitor(args),
+        SubCommand::Pcap(args) => run_dut_pcap(args),
+        SubCommand::Net(args) => run_dut_net(args),
+        SubCommand::Power(args) => run_dut_power(args),
         SubCommand::Pull(args) => run_dut_pull(args),
         SubCommand::Push(args) => run_dut_push(args),
+        SubCommand::Screenshot(args) => run_dut_screenshot(args),
+        SubCommand::Sensors(args) => run_dut_sensors(args),
         SubCommand::Setup(args) => run_setup(args),
+        SubCommand::Snapshot(args) => run_dut_snapshot(args),
+        SubCommand::Tag(args) => run_dut_tag(args),
+        SubCommand::Time(args) => run_dut_time(args),
+        SubCommand::Top(args) => run_dut_top(args),
         SubCommand::Vnc(args) => run_dut_vnc(args),
+        SubCommand::Wait(args) => run_dut_wait(args),
     }
 }
 
@@ -115,9 +348,9 @@ pub fn run(args: &Args) -> Result<()> {
 /// Pull files from DUT
 #[argh(subcommand, name = "pull")]
 struct ArgsPull {
-    /// DUT which the files are pulled from
+    /// DUT which the files are pulled from (choose interactively if omitted)
     #[argh(option)]
-    dut: String,
+    dut: Option<String>,
 
     /// pulled file names
     #[argh(positional)]
@@ -130,7 +363,7 @@ struct ArgsPull {
 
 fn run_dut_pull(args: &ArgsPull) -> Result<()> {
     cros::ensure_testing_rsa_is_there()?;
-    let target = &SshInfo::new(&args.dut)?;
+    let target = &SshInfo::new(&resolve_dut(&args.dut, None)?)?;
 
     target.get_files(&args.files, args.dest.as_ref())
 }
@@ -139,11 +372,12 @@ fn run_dut_pull(args: &ArgsPull) -> Result<()> {
 /// Push files from DUT
 #[argh(subcommand, name = "push")]
 struct ArgsPush {
-    /// destination DUT
+    /// destination DUT (choose interactively if omitted)
     #[argh(option)]
-    dut: String,
+    dut: Option<String>,
 
-    /// destination directory on a DUT
+    /// destination directory on a DUT, created if missing (default:
+    /// /usr/local/tmp)
     #[argh(option)]
     dest: Option<String>,
 
@@ -154,60 +388,1199 @@ struct ArgsPush {
 
 fn run_dut_push(args: &ArgsPush) -> Result<()> {
     cros::ensure_testing_rsa_is_there()?;
-    let target = &SshInfo::new(&args.dut)?;
+    let target = &SshInfo::new(&resolve_dut(&args.dut, None)?)?;
 
     target.send_files(&args.files, args.dest.as_ref())
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
-/// Open Vnc from DUT
+/// list or download crash reports on a DUT
+#[argh(subcommand, name = "crashes")]
+struct ArgsDutCrashes {
+    /// DUT to get crash reports from (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// download the crash reports to --dest
+    #[argh(switch)]
+    pull: bool,
+
+    /// remove the crash reports from the DUT (after downloading them if
+    /// --pull is given)
+    #[argh(switch)]
+    clear: bool,
+
+    /// keep downloading new crash reports as they appear
+    #[argh(switch)]
+    watch: bool,
+
+    /// interval to check new crash reports with --watch in seconds (default:
+    /// 10)
+    #[argh(option, default = "10")]
+    interval: u64,
+
+    /// directory to download crash reports. Each report is saved under
+    /// <dest>/<DUT>/<report name>/ (default: ./crashes)
+    #[argh(option, default = "String::from(\"crashes\")")]
+    dest: String,
+
+    /// process only the reports whose name contains one of them (e.g. chrome)
+    #[argh(positional)]
+    names: Vec<String>,
+}
+
+fn run_dut_crashes(args: &ArgsDutCrashes) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    let dut = resolve_dut(&args.dut, None)?;
+    let id = registered_dut_id(&dut).unwrap_or(dut.clone());
+    let target = SshInfo::new(&dut)?;
+    let list = || -> Result<Vec<CrashReport>> {
+        Ok(list_crashes(&target)?
+            .into_iter()
+            .filter(|r| args.names.is_empty() || args.names.iter().any(|n| r.name.contains(n)))
+            .collect())
+    };
+    let process = |report: &CrashReport| -> Result<()> {
+        if args.pull || args.watch {
+            let dest = Path::new(&args.dest)
+                .join(id.replace(['/', ':', '[', ']'], "_"))
+                .join(&report.name);
+            fs::create_dir_all(&dest)?;
+            target.get_files(&report.paths(), Some(&dest.to_string_lossy().to_string()))?;
+            info!("Downloaded {} to {dest:?}", report.name);
+        }
+        if args.clear {
+            remove_crash(&target, report)?;
+            info!("Removed {} from {id}", report.name);
+        }
+        Ok(())
+    };
+    if !args.watch {
+        let reports = list()?;
+        for r in &reports {
+            println!(
+                "{}  {:>10}  {}/{} ({})",
+                chrono::DateTime::from_timestamp(r.time, 0)
+                    .map(|t| t.with_timezone(&Local).format("%F %T").to_string())
+                    .unwrap_or_default(),
+                r.size,
+                r.dir,
+                r.name,
+                r.files
+                    .iter()
+                    .flat_map(|f| f.rsplit_once('.').map(|e| e.1))
+                    .collect::<Vec<&str>>()
+                    .join(",")
+            );
+        }
+        if reports.is_empty() {
+            info!("No crash reports found on {id}");
+        }
+        for r in &reports {
+            process(r)?;
+        }
+        return Ok(());
+    }
+    // Reports found at the start are not downloaded unless --pull is given
+    let mut seen: HashSet<(String, String)> = HashSet::new();
+    let mut first = true;
+    info!("Watching crash reports on {id}...");
+    loop {
+        match list() {
+            Ok(reports) => {
+                for r in reports {
+                    if !seen.insert((r.dir.clone(), r.name.clone())) {
+                        continue;
+                    }
+                    if first && !args.pull {
+                        continue;
+                    }
+                    info!("New crash report: {}/{}", r.dir, r.name);
+                    if let Err(e) = process(&r) {
+                        error!("Failed to process {}: {e:#}", r.name);
+                    }
+                }
+                first = false;
+            }
+            Err(e) => warn!("Failed to list crash reports on {id}: {e:#}"),
+        }
+        thread::sleep(time::Duration::from_secs(args.interval));
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show (or follow) logs on a DUT
+#[argh(subcommand, name = "logs")]
+struct ArgsDutLogs {
+    /// DUT to get logs from (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// logs to show: messages (default), kernel, chrome, ui, upstart. Can be
+    /// specified multiple times.
+    #[argh(option)]
+    source: Vec<String>,
+
+    /// keep showing new lines as they are written
+    #[argh(switch)]
+    follow: bool,
+
+    /// number of the last lines to show first (default: 100)
+    #[argh(option, default = "100")]
+    lines: usize,
+
+    /// show only lines that match the regex
+    #[argh(option)]
+    grep: Option<String>,
+
+    /// save the lines shown to a file as well. It is rotated when it gets
+    /// larger than --max-size-mb.
+    #[argh(option)]
+    output: Option<String>,
+
+    /// max size of the file given by --output in MiB (default: 64)
+    #[argh(option, default = "64")]
+    max_size_mb: u64,
+}
+
+/// name, path on the DUT, and a substring that lines should contain (if any)
+const DUT_LOG_SOURCES: [(&str, &str, Option<&str>); 5] = [
+    ("messages", "/var/log/messages", None),
+    ("kernel", "/var/log/messages", Some(" kernel: ")),
+    ("chrome", "/var/log/chrome/chrome", None),
+    ("ui", "/var/log/ui/ui.LATEST", None),
+    ("upstart", "/var/log/upstart.log", None),
+];
+
+lazy_static! {
+    static ref RE_LOG_ERROR: Regex = Regex::new(r"\b(ERROR|ERR|FATAL|CRIT)\b").unwrap();
+    static ref RE_LOG_WARNING: Regex = Regex::new(r"\b(WARNING|WARN)\b").unwrap();
+    static ref RE_TAIL_HEADER: Regex = Regex::new(r"^==> (.*) <==$").unwrap();
+}
+
+fn run_dut_logs(args: &ArgsDutLogs) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    let sources = if args.source.is_empty() {
+        vec!["messages".to_string()]
+    } else {
+        args.source.clone()
+    };
+    let sources = sources
+        .iter()
+        .map(|name| {
+            DUT_LOG_SOURCES
+                .iter()
+                .find(|s| s.0 == name)
+                .context(anyhow!("Unknown log source: {name}"))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut paths: Vec<&str> = sources.iter().map(|s| s.1).collect();
+    paths.sort();
+    paths.dedup();
+    let grep = args
+        .grep
+        .as_ref()
+        .map(|r| Regex::new(r))
+        .transpose()
+        .context("Invalid regex for --grep")?;
+    let mut output = args
+        .output
+        .as_ref()
+        .map(|path| RotatingFile::new(path, args.max_size_mb * 1024 * 1024, 5))
+        .transpose()?;
+    let colored = termion::is_tty(&stdout());
+
+    let target = SshInfo::new(&resolve_dut(&args.dut, None)?)?;
+    let mut child = target
+        .ssh_cmd(None)?
+        .arg(format!(
+            "tail -n {} {} {}",
+            args.lines,
+            if args.follow { "-F" } else { "" },
+            paths.join(" ")
+        ))
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().context("Failed to get stdout")?;
+    let mut current_path = paths[0];
+    for line in BufReader::new(stdout).split(b'\n') {
+        let line = String::from_utf8_lossy(&line?).to_string();
+        if paths.len() > 1 {
+            if let Some(path) = RE_TAIL_HEADER
+                .captures(&line)
+                .and_then(|c| paths.iter().find(|p| **p == &c[1]))
+            {
+                current_path = path;
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+        }
+        // Show the line if any of the sources for the file accepts it
+        let source = if let Some(source) = sources
+            .iter()
+            .find(|s| s.1 == current_path && s.2.map(|m| line.contains(m)).unwrap_or(true))
+        {
+            source
+        } else {
+            continue;
+        };
+        if grep.as_ref().map(|r| !r.is_match(&line)).unwrap_or(false) {
+            continue;
+        }
+        let line = if sources.len() > 1 {
+            format!("[{}] {line}", source.0)
+        } else {
+            line
+        };
+        if let Some(output) = &mut output {
+            output.write_line(&line)?;
+        }
+        if !colored {
+            println!("{line}");
+        } else if RE_LOG_ERROR.is_match(&line) {
+            println!(
+                "{}{line}{}",
+                termion::color::Fg(termion::color::Red),
+                termion::color::Fg(termion::color::Reset)
+            );
+        } else if RE_LOG_WARNING.is_match(&line) {
+            println!(
+                "{}{line}{}",
+                termion::color::Fg(termion::color::Yellow),
+                termion::color::Fg(termion::color::Reset)
+            );
+        } else {
+            println!("{line}");
+        }
+    }
+    child.wait()?.exit_ok().context("Failed to read the logs")?;
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// control the power of a DUT, and wait for it to be up (or down)
+#[argh(subcommand, name = "power")]
+struct ArgsDutPower {
+    /// DUT to control (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// serial of the servo connected to the DUT (default: the "servo" tag of
+    /// the DUT, or the only servo connected)
+    #[argh(option)]
+    servo: Option<String>,
+
+    /// seconds to wait for the DUT to be up (or down) (default: 300)
+    #[argh(option, default = "300")]
+    timeout: u64,
+
+    /// do not wait for the DUT after the operation
+    #[argh(switch)]
+    no_wait: bool,
+
+    /// one of: reboot, off, on, cold-reset
+    #[argh(positional)]
+    action: String,
+}
+
+fn run_power_hook(hook: &str, id: &str, power: &str) -> Result<()> {
+    info!("Turning {power} {id} with the power_hook");
+    Command::new("bash")
+        .args(["-c", hook])
+        .env("CRO3_DUT", id)
+        .env("CRO3_POWER", power)
+        .status()?
+        .exit_ok()
+        .context("power_hook failed")
+}
+
+fn run_dut_power(args: &ArgsDutPower) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    let dut = resolve_dut(&args.dut, None)?;
+    let id = registered_dut_id(&dut).unwrap_or(dut.clone());
+    let ssh = SshInfo::new(&dut)?;
+    let hook = Config::read()?.power_hook();
+    let servo = find_servo_for_dut(&id, &args.servo).ok();
+    let boot_id = || ssh.run_cmd_stdio("cat /proc/sys/kernel/random/boot_id");
+    let reachable = ssh.is_reachable(10);
+    let prev_boot_id = if reachable { boot_id().ok() } else { None };
+    let power_cycle_with_hook = |hook: &str| -> Result<()> {
+        run_power_hook(hook, &id, "off")?;
+        thread::sleep(time::Duration::from_secs(5));
+        run_power_hook(hook, &id, "on")
+    };
+    match (args.action.as_str(), reachable, &servo, &hook) {
+        ("reboot", true, _, _) => {
+            info!("Rebooting {id} via SSH");
+            ssh.run_cmd_piped(&["reboot; exit"])?;
+        }
+        ("reboot" | "cold-reset", _, Some(servo), _) => {
+            info!("Resetting {id} via the EC console of {}", servo.serial());
+            servo.run_cmd("EC", "reboot")?;
+        }
+        ("cold-reset", true, None, _) => {
+            info!("Resetting {id} via ectool");
+            ssh.run_cmd_piped(&["ectool reboot_ec cold; exit"])?;
+        }
+        ("reboot" | "cold-reset", _, None, Some(hook)) => power_cycle_with_hook(hook)?,
+        ("off", true, _, _) => {
+            info!("Shutting down {id} via SSH");
+            ssh.run_cmd_piped(&["poweroff; exit"])?;
+        }
+        ("off", false, Some(servo), _) => {
+            info!(
+                "Shutting down {id} via the EC console of {}",
+                servo.serial()
+            );
+            servo.run_cmd("EC", "apshutdown")?;
+        }
+        ("off", false, None, Some(hook)) => run_power_hook(hook, &id, "off")?,
+        ("on", true, _, _) => {
+            info!("{id} is already on");
+            return Ok(());
+        }
+        ("on", false, Some(servo), _) => {
+            info!("Pressing the power button of {id} via {}", servo.serial());
+            servo.run_cmd("EC", "powerbtn")?;
+        }
+        ("on", false, None, Some(hook)) => run_power_hook(hook, &id, "on")?,
+        ("reboot" | "off" | "on" | "cold-reset", ..) => bail!(
+            "No way to {} {id}: it is not reachable via SSH, no servo is found, and power_hook is \
+             not configured",
+            args.action
+        ),
+        (action, ..) => bail!("Unknown action: {action}. Use one of reboot, off, on, cold-reset"),
+    }
+    ssh.close_connection()?;
+    if args.no_wait {
+        return Ok(());
+    }
+    let deadline = time::Instant::now() + time::Duration::from_secs(args.timeout);
+    if args.action == "off" {
+        info!("Waiting for {id} to be down...");
+        while ssh.is_reachable(5) {
+            if time::Instant::now() > deadline {
+                bail!("{id} is still up after {}s", args.timeout);
+            }
+            thread::sleep(time::Duration::from_secs(5));
+        }
+        info!("{id} is down");
+        return Ok(());
+    }
+    info!("Waiting for {id} to be up...");
+    loop {
+        // Wait until the DUT is up with a new boot_id, to avoid seeing the
+        // DUT before it goes down
+        if ssh.is_reachable(5) {
+            if let Ok(new_boot_id) = boot_id() {
+                if prev_boot_id.as_ref() != Some(&new_boot_id) {
+                    break;
+                }
+            }
+        }
+        if time::Instant::now() > deadline {
+            bail!("{id} did not come back within {}s", args.timeout);
+        }
+        thread::sleep(time::Duration::from_secs(5));
+    }
+    info!("{id} is up");
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// export the DUT list with the cached board, model, release and tags
+#[argh(subcommand, name = "export")]
+struct ArgsDutExport {
+    /// output format: csv, swarming (bot dimensions) or json (default: csv)
+    #[argh(option, default = "String::from(\"csv\")")]
+    format: String,
+
+    /// write to the file instead of stdout
+    #[argh(option, short = 'o')]
+    output: Option<String>,
+}
+
+fn run_dut_export(args: &ArgsDutExport) -> Result<()> {
+    let records = collect_dut_records()?;
+    let exported = match args.format.as_str() {
+        "csv" => to_csv(&records),
+        "swarming" => serde_json::to_string_pretty(&to_swarming_dimensions(&records))? + "\n",
+        "json" => serde_json::to_string_pretty(&records)? + "\n",
+        format => bail!("Unknown format: {format}. Use one of csv, swarming, json"),
+    };
+    if let Some(output) = &args.output {
+        fs::write(output, exported).context(format!("Failed to write {output}"))?;
+        info!("Exported {} DUTs to {output}", records.len());
+    } else {
+        print!("{exported}");
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// wait until a DUT is up (e.g. after flashing or rebooting)
+#[argh(subcommand, name = "wait")]
+struct ArgsDutWait {
+    /// DUT to wait for (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// timeout in seconds (default: 300)
+    #[argh(option, default = "300")]
+    timeout: u64,
+
+    /// state to wait for: ssh (answers SSH) or login-screen (the UI has shown
+    /// the login screen) (default: ssh)
+    #[argh(option, default = "String::from(\"ssh\")")]
+    state: String,
+}
+
+fn run_dut_wait(args: &ArgsDutWait) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    let dut = &resolve_dut(&args.dut, None)?;
+    let login_screen = match args.state.as_str() {
+        "ssh" => false,
+        "login-screen" => true,
+        state => bail!("Unknown state: {state}. Use one of ssh, login-screen"),
+    };
+    let ssh = SshInfo::new(dut)?;
+    // The DUT may have been rebooted since the last connection
+    ssh.close_connection()?;
+    info!("Waiting for {dut} to be up ({})...", args.state);
+    ssh.wait_for_boot(time::Duration::from_secs(args.timeout), login_screen)?;
+    info!("{dut} is up ({})", args.state);
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// check (and fix) the clock skew between this machine and a DUT
+#[argh(subcommand, name = "time")]
+struct ArgsDutTime {
+    /// DUT to check (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// set the clock of the DUT to the time of this machine if skewed
+    #[argh(switch)]
+    fix: bool,
+
+    /// let the DUT sync its clock via network (chronyd, or tlsdated on older
+    /// images) instead of copying the time of this machine. Implies --fix.
+    #[argh(switch)]
+    ntp: bool,
+
+    /// skew in seconds to be tolerated (default: 1.0)
+    #[argh(option, default = "1.0")]
+    threshold: f64,
+}
+
+/// Asks the time daemon of a DUT to step the clock to the network time
+const NTP_SYNC_CMD: &str = "if command -v chronyc >/dev/null; then chronyc -a makestep; else \
+                            restart tlsdated || start tlsdated; fi";
+
+fn run_dut_time(args: &ArgsDutTime) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    let dut = &resolve_dut(&args.dut, None)?;
+    let ssh = SshInfo::new(dut)?;
+    let skew = ssh.clock_skew()?;
+    println!("{dut}: {skew:+.3}s (positive if the DUT is ahead)");
+    if skew.abs() <= args.threshold {
+        return Ok(());
+    }
+    if !args.fix && !args.ntp {
+        warn!(
+            "The clock of {dut} is off by more than {}s. Use --fix or --ntp to correct it.",
+            args.threshold
+        );
+        return Ok(());
+    }
+    if args.ntp {
+        info!("Syncing the clock of {dut} via network...");
+        ssh.run_cmd_stdio(NTP_SYNC_CMD)
+            .context("Failed to sync the clock. Is the DUT connected to the internet?")?;
+        // tlsdated takes a while to set the clock after starting
+        thread::sleep(time::Duration::from_secs(5));
+    } else {
+        info!("Setting the clock of {dut} to the time of this machine...");
+        ssh.set_clock_from_host()?;
+    }
+    let skew = ssh.clock_skew()?;
+    println!("{dut}: {skew:+.3}s after the fix");
+    if skew.abs() > args.threshold {
+        bail!("The clock of {dut} is still off by {skew:+.3}s");
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// save / restore the state of a DUT (users, Chrome profiles, dev packages)
+#[argh(subcommand, name = "snapshot")]
+struct ArgsDutSnapshot {
+    /// DUT to save / restore (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// do not ask for confirmation before restoring
+    #[argh(switch)]
+    yes: bool,
+
+    /// one of: save, restore, list, delete
+    #[argh(positional)]
+    action: String,
+
+    /// name of the snapshot
+    #[argh(positional)]
+    name: Option<String>,
+}
+
+/// Paths on the stateful partition to be saved in a snapshot (relative to /)
+const SNAPSHOT_PATHS: &[&str] = &[
+    // cryptohome vaults of the users, including their Chrome profiles
+    "home/.shadow",
+    "home/chronos",
+    // dev packages installed by `cros deploy` or `emerge`
+    "usr/local",
+    // device policy and owner
+    "var/lib/devicesettings",
+    "var/lib/whitelist",
+];
+
+/// Directory on the DUT to upload a snapshot to before restoring it. It is
+/// outside of SNAPSHOT_PATHS so that it survives the removal of the state.
+const SNAPSHOT_UPLOAD_DIR: &str = "/mnt/stateful_partition/cro3_snapshot";
+
+fn snapshot_path(id: &str, name: &str) -> Result<std::path::PathBuf> {
+    gen_path_in_cro3_dir(&format!("snapshots/{id}/{name}.tar.gz"))
+}
+
+fn run_dut_snapshot(args: &ArgsDutSnapshot) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    let dut = resolve_dut(&args.dut, None)?;
+    let id = registered_dut_id(&dut).unwrap_or(dut.clone());
+    if args.action == "list" {
+        let dir = gen_path_in_cro3_dir(&format!("snapshots/{id}/.keep"))?;
+        let dir = dir.parent().context("Failed to get the snapshot dir")?;
+        let mut snapshots: Vec<(String, u64)> = fs::read_dir(dir)?
+            .flatten()
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().to_string();
+                let name = name.strip_suffix(".tar.gz")?.to_string();
+                Some((name, e.metadata().ok()?.len()))
+            })
+            .collect();
+        snapshots.sort();
+        for (name, size) in snapshots {
+            println!("{name:32} {:>8} MiB", size / 1024 / 1024);
+        }
+        return Ok(());
+    }
+    let name = args
+        .name
+        .as_ref()
+        .context("Please specify the name of the snapshot")?;
+    if !Regex::new(r"^[A-Za-z0-9._-]+$")?.is_match(name) {
+        bail!("Invalid snapshot name: {name}. Use alphanumerics, '.', '_' and '-'");
+    }
+    let path = snapshot_path(&id, name)?;
+    let paths = SNAPSHOT_PATHS.join(" ");
+    let ssh = SshInfo::new(&dut)?;
+    match args.action.as_str() {
+        "save" => {
+            info!("Saving the state of {id} to {}", path.display());
+            // Stop the UI to unmount the user vaults and to get a consistent
+            // state. tar exits with 1 if files are changed while reading.
+            let cmd = format!(
+                "stop ui >/dev/null 2>&1; tar -C / --xattrs -czf - $(cd / && ls -d {paths} \
+                 2>/dev/null); s=$?; start ui >/dev/null 2>&1; [ $s -le 1 ]"
+            );
+            let status = ssh
+                .ssh_cmd(None)?
+                .arg(cmd)
+                .stdout(File::create(&path)?)
+                .status()?;
+            if !status.success() {
+                let _ = fs::remove_file(&path);
+                bail!("Failed to save the snapshot: {status}");
+            }
+            info!(
+                "Saved {name} ({} MiB)",
+                fs::metadata(&path)?.len() / 1024 / 1024
+            );
+        }
+        "restore" => {
+            if !path.exists() {
+                bail!("Snapshot {name} of {id} is not found");
+            }
+            if !args.yes
+                && !confirm(
+                    &format!("The current state of {id} will be lost. Restore {name}?"),
+                    false,
+                )?
+            {
+                bail!("Aborted");
+            }
+            // Upload and check the whole archive before touching the state,
+            // so that a broken snapshot or connection does not leave the DUT
+            // half wiped.
+            info!("Uploading {name} to {id}");
+            ssh.run_cmd_stdio(&format!("mkdir -p {SNAPSHOT_UPLOAD_DIR}"))?;
+            ssh.send_files(
+                &[path.to_string_lossy().to_string()],
+                Some(&SNAPSHOT_UPLOAD_DIR.to_string()),
+            )?;
+            let archive = format!("{SNAPSHOT_UPLOAD_DIR}/{name}.tar.gz");
+            if let Err(e) = ssh.run_cmd_stdio(&format!("tar -tzf {archive} >/dev/null")) {
+                let _ = ssh.run_cmd_stdio(&format!("rm -rf {SNAPSHOT_UPLOAD_DIR}"));
+                return Err(e.context(format!("Snapshot {name} is broken")));
+            }
+            info!("Restoring {name} to {id}");
+            // Mount points (e.g. /home/chronos) can not be removed, so remove
+            // the contents instead.
+            let cmd = format!(
+                "{{ stop ui >/dev/null 2>&1 || true; }} && for p in {paths}; do if [ -d /$p ]; \
+                 then find /$p -mindepth 1 -delete || exit 1; fi; done && tar -C / --xattrs -xzf \
+                 {archive} && rm -rf {SNAPSHOT_UPLOAD_DIR} && sync"
+            );
+            ssh.run_cmd_piped(&[cmd])
+                .context("Failed to restore the snapshot")?;
+            info!("Rebooting {id}");
+            ssh.run_cmd_piped(&["reboot; exit"])?;
+            ssh.close_connection()?;
+            thread::sleep(time::Duration::from_secs(10));
+            ssh.wait_for_boot(time::Duration::from_secs(300), false)?;
+            info!("Restored {name} to {id}");
+        }
+        "delete" => {
+            fs::remove_file(&path).context(format!("Failed to delete {}", path.display()))?;
+            info!("Deleted {name} of {id}");
+        }
+        action => bail!("Unknown action: {action}. Use one of save, restore, list, delete"),
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// sample battery, thermal and fan readings of a DUT periodically
+#[argh(subcommand, name = "sensors")]
+struct ArgsDutSensors {
+    /// DUT to sample (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// interval between samples in seconds (default: 5)
+    #[argh(option, default = "5")]
+    interval: u64,
+
+    /// number of samples to take (default: until interrupted)
+    #[argh(option)]
+    count: Option<u64>,
+
+    /// print samples as CSV instead of a table
+    #[argh(switch)]
+    csv: bool,
+}
+
+fn run_dut_sensors(args: &ArgsDutSensors) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    let dut = &resolve_dut(&args.dut, None)?;
+    let ssh = &SshInfo::new(dut)?;
+    // Columns are fixed by the first sample so that the rows stay aligned
+    let mut header: Vec<String> = Vec::new();
+    let mut widths: Vec<usize> = Vec::new();
+    let mut n = 0;
+    loop {
+        let columns = SensorSample::fetch(ssh)?.columns();
+        if header.is_empty() {
+            header = columns.iter().map(|(k, _)| k.clone()).collect();
+            widths = header.iter().map(|k| k.len().max(8)).collect();
+        }
+        let columns: HashMap<String, String> = columns.into_iter().collect();
+        let values: Vec<String> = header
+            .iter()
+            .map(|k| columns.get(k).cloned().unwrap_or("-".to_string()))
+            .collect();
+        if args.csv {
+            if n == 0 {
+                println!("{}", header.join(","));
+            }
+            println!("{}", values.join(","));
+        } else {
+            if n % 20 == 0 {
+                let line: Vec<String> = header
+                    .iter()
+                    .zip(&widths)
+                    .map(|(k, w)| format!("{k:>w$}"))
+                    .collect();
+                println!("{}", line.join(" "));
+            }
+            let line: Vec<String> = values
+                .iter()
+                .zip(&widths)
+                .map(|(v, w)| format!("{v:>w$}"))
+                .collect();
+            println!("{}", line.join(" "));
+        }
+        n += 1;
+        if args.count.map(|c| n >= c).unwrap_or(false) {
+            return Ok(());
+        }
+        thread::sleep(time::Duration::from_secs(args.interval));
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show CPU / memory usage of a DUT and its processes periodically
+#[argh(subcommand, name = "top")]
+struct ArgsDutTop {
+    /// DUT to sample (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// interval between samples in seconds (default: 2)
+    #[argh(option, default = "2")]
+    interval: u64,
+
+    /// number of samples to take (default: until interrupted)
+    #[argh(option)]
+    count: Option<u64>,
+
+    /// number of processes to show in each sample (default: 15)
+    #[argh(option, default = "15")]
+    procs: usize,
+
+    /// print samples as CSV (one row per process, and pid 0 for the total)
+    #[argh(switch)]
+    csv: bool,
+}
+
+fn run_dut_top(args: &ArgsDutTop) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    let dut = &resolve_dut(&args.dut, None)?;
+    let ssh = &SshInfo::new(dut)?;
+    let live = !args.csv && termion::is_tty(&stdout());
+    if args.csv {
+        println!("time,pid,command,cpu_percent,rss_mb");
+    }
+    let mut prev = ProcSnapshot::fetch(ssh)?;
+    let mut n = 0;
+    loop {
+        thread::sleep(time::Duration::from_secs(args.interval));
+        let cur = ProcSnapshot::fetch(ssh)?;
+        let sample = TopSample::between(&prev, &cur);
+        prev = cur;
+        let now = Local::now().format("%H:%M:%S");
+        let procs = sample.procs.iter().take(args.procs);
+        if args.csv {
+            println!(
+                "{now},0,[total],{:.1},{:.1}",
+                sample.cpu_percent, sample.mem_used_mb
+            );
+            for p in procs {
+                let command = p.command.replace(['"', ','], "_");
+                println!(
+                    "{now},{},{command},{:.1},{:.1}",
+                    p.pid, p.cpu_percent, p.rss_mb
+                );
+            }
+        } else {
+            if live {
+                print!("{}{}", termion::clear::All, termion::cursor::Goto(1, 1));
+            }
+            println!(
+                "{dut} {now}  CPU: {:5.1}%  Mem: {:.0} / {:.0} MiB",
+                sample.cpu_percent, sample.mem_used_mb, sample.mem_total_mb
+            );
+            println!("{:>7} {:>6} {:>9}  COMMAND", "PID", "CPU%", "RSS(MiB)");
+            for p in procs {
+                println!(
+                    "{:>7} {:>6.1} {:>9.1}  {}",
+                    p.pid, p.cpu_percent, p.rss_mb, p.command
+                );
+            }
+            if !live {
+                println!();
+            }
+        }
+        n += 1;
+        if args.count.map(|c| n >= c).unwrap_or(false) {
+            return Ok(());
+        }
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// capture packets on a DUT with tcpdump into a local pcap file
+#[argh(subcommand, name = "pcap")]
+struct ArgsDutPcap {
+    /// DUT to capture on (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// network interface to capture (default: any)
+    #[argh(option, default = "String::from(\"any\")")]
+    iface: String,
+
+    /// seconds to capture. 0 to capture until interrupted (default: 60)
+    #[argh(option, default = "60")]
+    duration: u64,
+
+    /// output file (default: pcap_<dut>_<timestamp>.pcap). The DUT info is
+    /// written next to it as <output>.json.
+    #[argh(option, short = 'o')]
+    output: Option<String>,
+
+    /// capture the SSH traffic to the DUT as well (excluded by default)
+    #[argh(switch)]
+    include_ssh: bool,
+
+    /// pcap filter expression (e.g. 'udp port 53')
+    #[argh(positional)]
+    filter: Vec<String>,
+}
+
+fn run_dut_pcap(args: &ArgsDutPcap) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    let dut = &resolve_dut(&args.dut, None)?;
+    let id = registered_dut_id(dut).unwrap_or(dut.clone());
+    let ssh = SshInfo::new(dut)?;
+    ssh.run_cmd_stdio("command -v tcpdump")
+        .context("tcpdump is not found on the DUT. Is it a test image?")?;
+    let start = Local::now();
+    let output = args
+        .output
+        .clone()
+        .unwrap_or(format!("pcap_{id}_{}.pcap", start.format("%Y%m%d_%H%M%S")));
+    let mut filter = args.filter.join(" ");
+    if !args.include_ssh {
+        filter = if filter.is_empty() {
+            "not port 22".to_string()
+        } else {
+            format!("({filter}) and not port 22")
+        };
+    }
+    // -U flushes each packet so that the file is usable even if interrupted
+    let mut cmd = format!(
+        "tcpdump -i {} -U -w - {}",
+        shell_quote(&args.iface),
+        shell_quote(&filter)
+    );
+    if args.duration > 0 {
+        cmd = format!("timeout -s INT {} {cmd}", args.duration);
+    }
+    info!(
+        "Capturing packets on {}:{} into {output} (filter: {filter})",
+        id, args.iface
+    );
+    let mut child = ssh
+        .ssh_cmd(None)?
+        .arg(cmd)
+        .stdout(File::create(&output).context(format!("Failed to create {output}"))?)
+        .spawn()?;
+    // Stop the capture on Ctrl-C, and still write the metadata
+    let interrupted = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGINT, Arc::clone(&interrupted))?;
+    signal_hook::flag::register(SIGTERM, Arc::clone(&interrupted))?;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if interrupted.load(Ordering::Relaxed) {
+            let _ = child.kill();
+            let _ = child.wait();
+            info!("Stopping tcpdump on {id}");
+            let pattern = format!("^tcpdump -i {} -U -w -", args.iface);
+            if let Err(e) =
+                ssh.run_cmd_stdio(&format!("pkill -INT -f {} || true", shell_quote(&pattern)))
+            {
+                warn!("Failed to stop tcpdump on {id}: {e:#}");
+            }
+            break None;
+        }
+        thread::sleep(time::Duration::from_millis(200));
+    };
+    let end = Local::now();
+    // tcpdump prints the number of captured packets to stderr on exit
+    if let Some(status) = status.filter(|s| !s.success() && s.code() != Some(124)) {
+        warn!("tcpdump exited with {status}");
+    }
+    let info = DutInfo::fetch_keys_optional(&ssh, &["board", "model", "release", "serial"])
+        .unwrap_or_default();
+    let metadata = serde_json::json!({
+        "dut": id,
+        "host": ssh.host_and_port(),
+        "info": info,
+        "iface": args.iface,
+        "filter": filter,
+        "start": start.to_rfc3339(),
+        "end": end.to_rfc3339(),
+    });
+    let metadata_path = format!("{output}.json");
+    fs::write(&metadata_path, serde_json::to_string_pretty(&metadata)?)?;
+    info!(
+        "Saved {output} ({} bytes) and {metadata_path}",
+        fs::metadata(&output)?.len()
+    );
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// take a screenshot of a DUT
+#[argh(subcommand, name = "screenshot")]
+struct ArgsDutScreenshot {
+    /// DUT to take a screenshot of (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// path to save the screenshot (default:
+    /// screenshot_<DUT>_<TIMESTAMP>.png in the current directory)
+    #[argh(option, short = 'o')]
+    output: Option<String>,
+
+    /// open the screenshot with xdg-open after saving it
+    #[argh(switch)]
+    open: bool,
+}
+
+fn run_dut_screenshot(args: &ArgsDutScreenshot) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    let dut = resolve_dut(&args.dut, None)?;
+    let target = SshInfo::new(&dut)?;
+    let timestamp = Local::now().format("%Y%m%d_%H%M%S");
+    let output = args.output.clone().unwrap_or(format!(
+        "screenshot_{}_{timestamp}.png",
+        dut.replace(['/', ':', '[', ']'], "_")
+    ));
+    let remote = format!("/tmp/cro3_screenshot_{timestamp}.png");
+    target
+        .run_cmd_stdio(&format!("screenshot {remote}"))
+        .context("Failed to take a screenshot. Is the DUT running a test image?")?;
+    let result = target.get_files(&[remote.clone()], Some(&output));
+    target.run_cmd_stdio(&format!("rm -f {remote}"))?;
+    result?;
+    info!("Saved a screenshot of {dut} to {output}");
+    if args.open {
+        Command::new("xdg-open")
+            .arg(&output)
+            .status()?
+            .exit_ok()
+            .context("Failed to open the screenshot")?;
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show the screen of a DUT via VNC (kmsvnc), optionally with a local viewer
 #[argh(subcommand, name = "vnc")]
 struct ArgsVnc {
-    /// DUT which the files are pushed to
+    /// DUT to connect (choose interactively if omitted)
     #[argh(option)]
-    dut: String,
+    dut: Option<String>,
 
     /// host port to forward (default: 5900)
     #[argh(option)]
     vnc_port: Option<u16>,
+
+    /// host port to forward for the noVNC web client (default: 6080)
+    #[argh(option)]
+    web_port: Option<u16>,
+
+    /// VNC viewer to launch with localhost:PORT appended (e.g.
+    /// 'xtightvncviewer -encodings raw'). Exits when the viewer is closed.
+    #[argh(option)]
+    viewer: Option<String>,
+}
+
+/// Kills kmsvnc and novnc on the DUT. The brackets prevent the pattern from
+/// matching the shell running this command.
+fn stop_vnc_on_dut(target: &SshInfo) {
+    if let Err(e) = target.run_cmd_piped(&["pkill -9 --full '[k]msvnc|[n]ovnc' || true"]) {
+        error!("Failed to kill vnc instances: {e}")
+    }
+}
+
+/// Waits until a VNC server responds on a local port, by reading the RFB
+/// protocol version sent by the server on connection.
+fn wait_for_vnc_server(port: u16, timeout: time::Duration) -> Result<()> {
+    let start = time::Instant::now();
+    while start.elapsed() < timeout {
+        if let Ok(mut stream) = TcpStream::connect(("127.0.0.1", port)) {
+            stream.set_read_timeout(Some(time::Duration::from_secs(2)))?;
+            let mut version = [0u8; 12];
+            if stream.read_exact(&mut version).is_ok() && version.starts_with(b"RFB ") {
+                return Ok(());
+            }
+        }
+        thread::sleep(time::Duration::from_secs(1));
+    }
+    bail!("VNC server did not respond on localhost:{port} in {timeout:?}")
+}
+
+/// Processes of a VNC session, which are stopped with VNC on the DUT when this
+/// is dropped on any exit path
+struct VncSession<'a> {
+    target: &'a SshInfo,
+    kmsvnc: Option<async_process::Child>,
+    novnc: Option<async_process::Child>,
+    viewer: Option<std::process::Child>,
+}
+impl Drop for VncSession<'_> {
+    fn drop(&mut self) {
+        info!("Stopping VNC on {}", self.target.host());
+        if let Some(viewer) = &mut self.viewer {
+            let _ = viewer.kill();
+        }
+        for child in [&mut self.kmsvnc, &mut self.novnc].into_iter().flatten() {
+            let _ = child.kill();
+        }
+        stop_vnc_on_dut(self.target);
+    }
 }
 
 fn run_dut_vnc(args: &ArgsVnc) -> Result<()> {
     cros::ensure_testing_rsa_is_there()?;
-    let target = &SshInfo::new(&args.dut)?;
+    let dut = &resolve_dut(&args.dut, None)?;
+    let target = &SshInfo::new(dut)?;
     let vnc_port = args.vnc_port.unwrap_or(5900);
-    let web_port = args.vnc_port.unwrap_or(6080);
+    let web_port = args.web_port.unwrap_or(6080);
 
-    if let Err(e) = target.run_cmd_piped(&["kill -9 $(pgrep --full vnc)"]) {
-        error!("Failed to kill previous vnc instance: {e}")
-    }
+    stop_vnc_on_dut(target);
+
+    let mut session = VncSession {
+        target,
+        kmsvnc: None,
+        novnc: None,
+        viewer: None,
+    };
+    session.kmsvnc = Some(target.start_port_forwarding(vnc_port, 5900, "kmsvnc", &[])?);
+    session.novnc = Some(target.start_port_forwarding(web_port, 6080, "novnc", &[])?);
 
-    let mut child_kmsvnc = target.start_port_forwarding(vnc_port, 5900, "kmsvnc", &[])?;
-    let mut child_novnc = target.start_port_forwarding(web_port, 6080, "novnc", &[])?;
+    // Tear down the processes on the DUT as well on Ctrl-C
+    let interrupted = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGINT, Arc::clone(&interrupted))?;
+    signal_hook::flag::register(SIGTERM, Arc::clone(&interrupted))?;
 
-    warn!("To use VNC via web browser, please open:");
-    warn!("  http://localhost:{web_port}/vnc.html");
-    warn!("To connect via VNC client directly, use:");
-    warn!("  xtightvncviewer -encodings raw localhost:{vnc_port}");
+    if let Some(viewer_cmd) = &args.viewer {
+        wait_for_vnc_server(vnc_port, time::Duration::from_secs(30))?;
+        info!("Launching {viewer_cmd} localhost:{vnc_port}");
+        session.viewer = Some(
+            Command::new("sh")
+                .arg("-c")
+                .arg(format!("{viewer_cmd} localhost:{vnc_port}"))
+                .spawn()
+                .context("Failed to launch the VNC viewer")?,
+        );
+    } else {
+        warn!("To use VNC via web browser, please open:");
+        warn!("  http://localhost:{web_port}/vnc.html");
+        warn!("To connect via VNC client directly, use:");
+        warn!("  xtightvncviewer -encodings raw localhost:{vnc_port}");
+    }
 
     loop {
-        if let Some(status) = child_kmsvnc.try_status()? {
-            panic!("kmsvnc terminated {}: {}", &args.dut, status);
+        if interrupted.load(Ordering::Relaxed) {
+            return Ok(());
         }
-        if let Some(status) = child_novnc.try_status()? {
-            panic!("novnc terminated {}: {}", &args.dut, status);
+        if let Some(status) = session
+            .kmsvnc
+            .as_mut()
+            .and_then(|c| c.try_status().transpose())
+        {
+            bail!("kmsvnc terminated {}: {}", dut, status?);
         }
-        thread::sleep(time::Duration::from_secs(5));
+        if let Some(status) = session
+            .novnc
+            .as_mut()
+            .and_then(|c| c.try_status().transpose())
+        {
+            bail!("novnc terminated {}: {}", dut, status?);
+        }
+        if let Some(viewer) = &mut session.viewer {
+            if viewer.try_wait()?.is_some() {
+                return Ok(());
+            }
+        }
+        thread::sleep(time::Duration::from_millis(500));
     }
 }
+#[derive(FromArgs, PartialEq, Debug)]
+/// forward a port between this machine and a DUT, reconnecting on drops
+#[argh(subcommand, name = "forward")]
+struct ArgsDutForward {
+    /// DUT to connect (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// port on this machine (default: same as --remote)
+    #[argh(option)]
+    local: Option<u16>,
+
+    /// port on the DUT
+    #[argh(option)]
+    remote: u16,
+
+    /// forward the port on the DUT to this machine instead
+    #[argh(switch)]
+    reverse: bool,
+}
+
+fn run_dut_forward(args: &ArgsDutForward) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    let dut = &resolve_dut(&args.dut, None)?;
+    let target = &SshInfo::new(dut)?;
+    let local = args.local.unwrap_or(args.remote);
+    let remote = args.remote;
+    let mut connected_once = false;
+    loop {
+        match block_on(target.start_tunnel(local, remote, args.reverse)) {
+            Ok(mut child) => {
+                connected_once = true;
+                if args.reverse {
+                    info!("Ready: 127.0.0.1:{remote} on {dut} is forwarded to localhost:{local}");
+                } else {
+                    info!("Ready: localhost:{local} is forwarded to 127.0.0.1:{remote} on {dut}");
+                }
+                let status = block_on(child.status())?;
+                warn!("Connection to {dut} dropped ({status}). Reconnecting...");
+            }
+            Err(e) if !connected_once => return Err(e),
+            Err(e) => {
+                warn!("Failed to reconnect to {dut}: {e:#}");
+                thread::sleep(time::Duration::from_secs(5));
+            }
+        }
+    }
+}
+
 #[derive(FromArgs, PartialEq, Debug)]
 /// open a SSH monitor
 #[argh(subcommand, name = "monitor")]
 struct ArgsDutMonitor {
-    /// DUT identifiers to monitor. This accepts sub portforwardings after colon
-    /// (e.g. dut,ADDR:PORT,...)
+    /// DUT identifiers (or DUT groups) to monitor. This accepts sub
+    /// portforwardings after colon (e.g. dut,ADDR:PORT,...)
     #[argh(positional)]
     duts: Vec<String>,
+
+    /// monitor DUTs that match the filter expression as well (e.g.
+    /// 'board==brya && has_servo')
+    #[argh(option)]
+    filter: Option<String>,
+
+    /// record the health of DUTs (reachability, uptime, boot id, temperature
+    /// and disk usage) to ~/.cro3/dut_health.jsonl periodically, instead of
+    /// forwarding ports
+    #[argh(switch)]
+    health: bool,
+
+    /// interval of the health check in seconds (default: 60)
+    #[argh(option, default = "60")]
+    interval: u64,
+
+    /// shell command to run on an alert (e.g. a DUT became unreachable or
+    /// rebooted). The message is given via $CRO3_ALERT.
+    #[argh(option)]
+    alert_cmd: Option<String>,
 }
 
 fn parse_fwport(fwport: &str, loport: u16) -> Result<PortForwarding> {
@@ -229,8 +1602,23 @@ fn run_dut_monitor(args: &ArgsDutMonitor) -> Result<()> {
     cros::ensure_testing_rsa_is_there()?;
     let mut targets: Vec<MonitoredDut> = Vec::new();
     let mut port = 4022;
+    let mut duts = expand_dut_groups(&args.duts)?;
+    if let Some(filter) = &args.filter {
+        duts.extend(filter_duts(filter)?);
+    }
+    if duts.is_empty() {
+        bail!("No DUTs to monitor");
+    }
+    if args.health {
+        let duts: Vec<String> = duts
+            .iter()
+            .flat_map(|d| d.split(',').next())
+            .map(|d| d.to_string())
+            .collect();
+        return run_dut_health_monitor(args, &duts);
+    }
 
-    for raw_dut in &args.duts {
+    for raw_dut in &duts {
         let ports: Vec<&str> = raw_dut.split(',').collect();
         let mut fwports: Vec<PortForwarding> = vec![];
         let dut_port = port;
@@ -258,26 +1646,125 @@ fn run_dut_monitor(args: &ArgsDutMonitor) -> Result<()> {
         )?;
         println!("{}", MonitoredDut::get_status_header());
 
-        for target in targets.iter_mut() {
-            println!("{}", target.get_status()?);
-        }
+        for target in targets.iter_mut() {
+            println!("{}", target.get_status()?);
+        }
+
+        thread::sleep(time::Duration::from_secs(5))
+    }
+}
+
+fn run_dut_health_monitor(args: &ArgsDutMonitor, duts: &[String]) -> Result<()> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(duts.len().min(16))
+        .build()
+        .context("Failed to create a thread pool")?;
+    let mut last: HashMap<String, DutHealth> = HashMap::new();
+    loop {
+        let records: Vec<DutHealth> =
+            pool.install(|| duts.par_iter().map(|d| DutHealth::fetch(d)).collect());
+        println!(
+            "{:<31}\t{:<9}\t{:>10}\t{:>6}\t{:>5}",
+            "DUT", "STATUS", "UPTIME", "TEMP", "DISK"
+        );
+        for h in &records {
+            let value = |v: Option<String>| v.unwrap_or("-".to_string());
+            println!(
+                "{:<31}\t{:<9}\t{:>10}\t{:>6}\t{:>5}",
+                h.dut,
+                if h.reachable { "online" } else { "offline" },
+                value(h.uptime_sec.map(|t| format!("{:.0}s", t))),
+                value(h.temp_celsius.map(|t| format!("{t:.1}C"))),
+                value(h.disk_usage_percent.map(|d| format!("{d}%"))),
+            );
+            h.append_to_log()?;
+            for alert in h.alerts(last.get(&h.dut)) {
+                warn!("ALERT: {alert}");
+                if let Some(cmd) = &args.alert_cmd {
+                    let result = Command::new("bash")
+                        .args(["-c", cmd])
+                        .env("CRO3_ALERT", &alert)
+                        .status();
+                    if let Err(e) = result {
+                        error!("Failed to run --alert-cmd: {e}");
+                    }
+                }
+            }
+            let remembered = h.to_remember(last.get(&h.dut));
+            last.insert(h.dut.clone(), remembered);
+        }
+        thread::sleep(time::Duration::from_secs(args.interval));
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// attach to the serial console of a DUT via servo
+#[argh(subcommand, name = "console")]
+struct ArgsDutConsole {
+    /// DUT to attach (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// serial of the servo (or Cr50 / Ti50) connected to the DUT. It is
+    /// remembered as a "servo" tag of the DUT.
+    #[argh(option)]
+    servo: Option<String>,
 
-        thread::sleep(time::Duration::from_secs(5))
+    /// tty type to attach (default: the AP console, e.g. "AP" or "CPU")
+    #[argh(option)]
+    tty: Option<String>,
+
+    /// reboot the DUT via the EC console after attaching, to capture the logs
+    /// from the boot
+    #[argh(switch)]
+    reboot: bool,
+}
+
+fn run_dut_console(args: &ArgsDutConsole) -> Result<()> {
+    let dut = resolve_dut(&args.dut, None)?;
+    let id = registered_dut_id(&dut).unwrap_or(dut);
+    let servo = find_servo_for_dut(&id, &args.servo)?;
+    let tty_type = match &args.tty {
+        Some(tty) => tty.as_str(),
+        None => servo
+            .ap_console_tty_type()
+            .context(anyhow!("{} does not have an AP console", servo.serial()))?,
+    };
+    let mut tty = servo.open_tty(tty_type)?;
+    let log_path = console_log_path(tty_type, &id)?;
+    let log = Arc::new(Mutex::new(ConsoleLog::create(&log_path)?));
+    info!(
+        "Attached to {tty_type} console of {id} ({})",
+        servo.tty_path(tty_type)?
+    );
+    info!("Logging to {log_path:?}");
+    info!("{CONSOLE_HELP}");
+    spawn_console_reader(&tty, log)?;
+    if args.reboot {
+        servo.run_cmd("EC", "reboot")?;
     }
+    forward_keys_to_console(&mut tty, &|| servo.run_cmd("EC", "reboot").map(|_| ()))
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// open a SSH shell
 #[argh(subcommand, name = "shell")]
 struct ArgsDutShell {
-    /// a DUT identifier (e.g. 127.0.0.1, localhost:2222)
+    /// a DUT identifier (e.g. 127.0.0.1, localhost:2222). If omitted, choose
+    /// one of the registered DUTs interactively.
     #[argh(option)]
-    dut: String,
+    dut: Option<String>,
 
     /// if specified, it will invoke autologin before opening a shell
     #[argh(switch)]
     autologin: bool,
 
+    /// open a shell with local line editing, per-DUT history and completion
+    /// of remote paths instead of a SSH login shell. Each line is run as a
+    /// separate command, so full-screen programs do not work in it.
+    #[argh(switch)]
+    line_edit: bool,
+
     /// if specified, run the command on dut and exit. if not, it will open an
     /// interactive shell.
     #[argh(positional)]
@@ -285,15 +1772,46 @@ struct ArgsDutShell {
 }
 fn run_dut_shell(args: &ArgsDutShell) -> Result<()> {
     cros::ensure_testing_rsa_is_there()?;
-    let target = &SshInfo::new(&args.dut)?;
+    let dut = &resolve_dut(&args.dut, None)?;
+    let target = &SshInfo::new(dut)?;
     if args.autologin {
         target.run_autologin()?;
     }
-    if args.args.is_empty() {
-        target.open_ssh()
-    } else {
+    if !args.args.is_empty() {
         target.run_cmd_piped(&args.args)
+    } else if args.line_edit && is_interactive() {
+        run_interactive_shell(dut, target)
+    } else {
+        target.open_ssh()
+    }
+}
+
+fn run_interactive_shell(dut: &str, target: &SshInfo) -> Result<()> {
+    let id = registered_dut_id(dut).unwrap_or(dut.to_string());
+    let mut shell = RemoteShell::new(target)?;
+    let mut editor = LineEditor::new(History::load(dut_shell::history_path(&id)?)?);
+    eprintln!("Connected to {id}. Press Ctrl-D to exit.");
+    let mut last_code = Some(0);
+    loop {
+        let status = match last_code {
+            Some(0) => String::new(),
+            Some(code) => format!(" [{code}]"),
+            None => " [killed]".to_string(),
+        };
+        let prompt = format!("{id}:{}{status}# ", shell.cwd());
+        let line = match editor.read_line(&prompt, &mut |line| shell.complete(line))? {
+            Some(line) => line,
+            None => break,
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if line.trim() == "exit" {
+            break;
+        }
+        last_code = shell.run(&line)?;
     }
+    shell.close()
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
@@ -314,7 +1832,8 @@ fn run_dut_kernel_config(args: &ArgsDutKernelConfig) -> Result<()> {
 
 type DutAction = Box<fn(&SshInfo) -> Result<()>>;
 fn do_reboot(s: &SshInfo) -> Result<()> {
-    s.run_cmd_piped(&["reboot; exit"])
+    s.run_cmd_piped(&["reboot; exit"])?;
+    s.close_connection()
 }
 
 enum PartitionSet {
@@ -573,7 +2092,7 @@ fn check_ssh(servo: &LocalServo) -> Result<DutInfo> {
 
 /// Check if GBB flags are set for development.
 fn check_dev_gbb_flags(dut: &DutInfo) -> Result<()> {
-    let info = DutInfo::fetch_keys(dut.ssh(), &vec!["gbb_flags"])?;
+    let info = DutInfo::fetch_keys(dut.ssh(), &["gbb_flags"])?;
     let gbb_flags = info
         .get("gbb_flags")
         .context("gbb_flags is not set")?
@@ -696,18 +2215,44 @@ fn run_setup(args: &ArgsSetup) -> Result<()> {
 /// send actions
 #[argh(subcommand, name = "do")]
 struct ArgsDutDo {
-    /// a DUT identifier (e.g. 127.0.0.1, localhost:2222)
+    /// a DUT identifier (e.g. 127.0.0.1, localhost:2222). If omitted, choose
+    /// one of the registered DUTs interactively.
     #[argh(option)]
     dut: Option<String>,
-    /// actions to do (--list-actions to see available options)
+    /// actions to do (--list-actions to see available options). With --all,
+    /// --filter or a DUT group as --dut, a shell command to run on each DUT
+    /// instead.
     #[argh(positional)]
     actions: Vec<String>,
     /// list available actions
     #[argh(switch)]
     list_actions: bool,
+    /// run the command on all the registered DUTs
+    #[argh(switch)]
+    all: bool,
+    /// run the command on the DUTs that match the filter expression (e.g.
+    /// 'board==brya && has_servo')
+    #[argh(option)]
+    filter: Option<String>,
+    /// number of DUTs to run the command on at the same time
+    #[argh(option, default = "8")]
+    jobs: usize,
+    /// run the arguments as a shell command (as with --all or --filter), and
+    /// store the command, stdout, stderr, exit status and timing of each DUT
+    /// under <dir>/<timestamp>/<dut>/
+    #[argh(option)]
+    capture_dir: Option<String>,
 }
 fn run_dut_do(args: &ArgsDutDo) -> Result<()> {
     cros::ensure_testing_rsa_is_there()?;
+    let group = if let Some(dut) = &args.dut {
+        Config::read()?.dut_group(dut).cloned()
+    } else {
+        None
+    };
+    if args.all || args.filter.is_some() || group.is_some() || args.capture_dir.is_some() {
+        return run_dut_do_on_many(args, group);
+    }
     if args.list_actions {
         println!(
             "{}",
@@ -730,7 +2275,7 @@ fn run_dut_do(args: &ArgsDutDo) -> Result<()> {
              actions."
         ));
     }
-    let dut = &SshInfo::new(args.dut.as_ref().context(anyhow!("Please specify --dut"))?)?;
+    let dut = &SshInfo::new(&resolve_dut(&args.dut, None)?)?;
     let actions: Vec<&DutAction> = args
         .actions
         .iter()
@@ -743,6 +2288,155 @@ fn run_dut_do(args: &ArgsDutDo) -> Result<()> {
     Ok(())
 }
 
+/// Runs a shell command on all the DUTs, the ones that match --filter, the
+/// ones in the `group` given via --dut, or the DUT given via --dut (with
+/// --capture-dir)
+fn run_dut_do_on_many(args: &ArgsDutDo, group: Option<Vec<String>>) -> Result<()> {
+    let many = args.all || args.filter.is_some();
+    if group.is_some() && many {
+        bail!("A DUT group can not be used with --all or --filter");
+    }
+    if group.is_none() && args.dut.is_some() && many {
+        bail!("--dut can not be used with --all or --filter");
+    }
+    if args.actions.is_empty() {
+        bail!("Please specify a command to run, e.g. `cro3 dut do --all -- uptime`");
+    }
+    let duts = if let Some(group) = group {
+        expand_dut_groups(&group)?
+    } else if let Some(filter) = &args.filter {
+        filter_duts(filter)?
+    } else if !args.all {
+        vec![resolve_dut(&args.dut, None)?]
+    } else {
+        let mut duts: Vec<String> = SSH_CACHE.entries()?.into_keys().collect();
+        duts.sort();
+        duts
+    };
+    if duts.is_empty() {
+        bail!("No DUTs to run the command on");
+    }
+    let cmd = args.actions.join(" ");
+    info!("Running `{cmd}` on {} DUTs", duts.len());
+    // Use a dedicated pool since most of the time is spent on waiting
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs.max(1))
+        .build()
+        .context("Failed to create a thread pool")?;
+    let capture_dir = args
+        .capture_dir
+        .as_ref()
+        .map(|dir| Path::new(dir).join(Local::now().format("%Y%m%d_%H%M%S").to_string()));
+    let results: Vec<(&String, Result<i32>)> = pool.install(|| {
+        duts.par_iter()
+            .map(|id| (id, run_cmd_and_capture(id, &cmd, capture_dir.as_deref())))
+            .collect()
+    });
+    let width = duts.iter().map(|id| id.len()).max().unwrap_or_default();
+    let mut failed = 0;
+    println!("{:width$} RESULT", "DUT");
+    for (id, result) in &results {
+        let status = match result {
+            Ok(0) => "ok".to_string(),
+            Ok(255) => "ssh failed (exit 255)".to_string(),
+            Ok(code) => format!("exit {code}"),
+            Err(e) => format!("error: {e:#}"),
+        };
+        if !matches!(result, Ok(0)) {
+            failed += 1;
+        }
+        println!("{id:width$} {status}");
+    }
+    if let Some(dir) = &capture_dir {
+        info!("The outputs are stored in {}", dir.display());
+    }
+    if failed > 0 {
+        bail!("The command failed on {failed} of {} DUTs", results.len());
+    }
+    Ok(())
+}
+
+/// Written to result.json for each DUT by `dut do --capture-dir`
+#[derive(Serialize)]
+struct CapturedRun<'a> {
+    dut: &'a str,
+    command: &'a str,
+    exit_code: Option<i32>,
+    error: Option<String>,
+    start: String,
+    end: String,
+    duration_sec: f64,
+}
+
+/// Runs a command on a DUT like run_cmd_with_prefix(), storing the outputs
+/// and the result in `capture_dir`/`id` if specified
+fn run_cmd_and_capture(id: &str, cmd: &str, capture_dir: Option<&Path>) -> Result<i32> {
+    let dir = capture_dir.map(|dir| dir.join(id));
+    if let Some(dir) = &dir {
+        fs::create_dir_all(dir).context(format!("Failed to create {}", dir.display()))?;
+        fs::write(dir.join("command.txt"), format!("{cmd}\n"))?;
+    }
+    let start = Local::now();
+    let result = run_cmd_with_prefix(id, cmd, dir.as_deref());
+    if let Some(dir) = &dir {
+        let end = Local::now();
+        let run = CapturedRun {
+            dut: id,
+            command: cmd,
+            exit_code: result.as_ref().ok().copied(),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+            start: start.to_rfc3339(),
+            end: end.to_rfc3339(),
+            duration_sec: (end - start).num_milliseconds() as f64 / 1000.0,
+        };
+        fs::write(dir.join("result.json"), serde_json::to_string_pretty(&run)?)?;
+    }
+    result
+}
+
+/// Runs a command on a DUT, printing each line of its output with the DUT ID
+/// as a prefix, and writing the outputs to stdout.txt and stderr.txt in
+/// `capture_dir` if specified. Returns the exit code.
+fn run_cmd_with_prefix(id: &str, cmd: &str, capture_dir: Option<&Path>) -> Result<i32> {
+    let create = |name: &str| {
+        capture_dir
+            .map(|dir| File::create(dir.join(name)))
+            .transpose()
+    };
+    let mut stdout_file = create("stdout.txt")?;
+    let mut stderr_file = create("stderr.txt")?;
+    let ssh = SshInfo::new(id)?;
+    let mut child = ssh
+        .ssh_cmd(None)?
+        .arg(cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let stdout = child.stdout.take().context("Failed to get stdout")?;
+    let stderr = child.stderr.take().context("Failed to get stderr")?;
+    thread::scope(|s| {
+        s.spawn(|| {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                eprintln!("[{id}] {line}");
+                if let Some(f) = &mut stderr_file {
+                    let _ = writeln!(f, "{line}");
+                }
+            }
+        });
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            println!("[{id}] {line}");
+            if let Some(f) = &mut stdout_file {
+                let _ = writeln!(f, "{line}");
+            }
+        }
+    });
+    child
+        .wait()?
+        .code()
+        .context("The command was terminated by a signal")
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum DutStatus {
     Online,
@@ -778,6 +2472,11 @@ struct ArgsDutList {
     #[argh(switch)]
     update: bool,
 
+    /// show only DUTs that match the filter expression (e.g. 'board==brya &&
+    /// has_servo'). See `cro3 dut tag` for the tags.
+    #[argh(option)]
+    filter: Option<String>,
+
     #[argh(option, hidden_help)]
     repo: Option<String>,
 }
@@ -785,9 +2484,13 @@ fn run_dut_list(args: &ArgsDutList) -> Result<()> {
     if args.clear {
         return SSH_CACHE.clear();
     }
-    let duts = SSH_CACHE
+    let mut duts = SSH_CACHE
         .entries()
         .context(anyhow!("SSH_CACHE is not initialized yet"))?;
+    if let Some(filter) = &args.filter {
+        let matched = filter_duts(filter)?;
+        duts.retain(|id, _| matched.contains(id));
+    }
     if args.ids {
         let mut keys: Vec<String> = duts.keys().map(|s| s.to_string()).collect();
         keys.sort();
@@ -865,21 +2568,506 @@ fn run_dut_list(args: &ArgsDutList) -> Result<()> {
     Ok(())
 }
 
+#[derive(FromArgs, PartialEq, Debug)]
+/// show / configure the network of a DUT via shill
+#[argh(subcommand, name = "net")]
+struct ArgsDutNet {
+    #[argh(subcommand)]
+    nested: DutNetSubCommand,
+}
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum DutNetSubCommand {
+    Show(ArgsDutNetShow),
+    SetWifi(ArgsDutNetSetWifi),
+    SetStaticIp(ArgsDutNetSetStaticIp),
+}
+fn run_dut_net(args: &ArgsDutNet) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    match &args.nested {
+        DutNetSubCommand::Show(args) => run_dut_net_show(args),
+        DutNetSubCommand::SetWifi(args) => run_dut_net_set_wifi(args),
+        DutNetSubCommand::SetStaticIp(args) => run_dut_net_set_static_ip(args),
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show the addresses and routes of a DUT
+#[argh(subcommand, name = "show")]
+struct ArgsDutNetShow {
+    /// DUT to show (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+}
+fn run_dut_net_show(args: &ArgsDutNetShow) -> Result<()> {
+    let ssh = SshInfo::new(&resolve_dut(&args.dut, None)?)?;
+    ssh.run_cmd_piped(&["ip -brief address; echo; ip route"])
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// connect a DUT to a WiFi network and remember it
+#[argh(subcommand, name = "set-wifi")]
+struct ArgsDutNetSetWifi {
+    /// DUT to configure (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// SSID of the network
+    #[argh(option)]
+    ssid: String,
+
+    /// passphrase (WPA-PSK). The network is treated as an open one if omitted.
+    #[argh(option)]
+    psk: Option<String>,
+
+    /// the SSID is not broadcasted
+    #[argh(switch)]
+    hidden: bool,
+
+    /// seconds to wait for the connection (default: 60)
+    #[argh(option, default = "60")]
+    timeout: u64,
+}
+fn run_dut_net_set_wifi(args: &ArgsDutNetSetWifi) -> Result<()> {
+    let dut = &resolve_dut(&args.dut, None)?;
+    let ssh = SshInfo::new(dut)?;
+    info!("Connecting {dut} to {}", args.ssid);
+    let service = configure_wifi(&ssh, &args.ssid, args.psk.as_deref(), args.hidden)?;
+    wait_for_connection(&ssh, &service, time::Duration::from_secs(args.timeout))
+        .context(anyhow!("{dut} could not connect to {}", args.ssid))?;
+    // Check that packets go through the WiFi interface
+    ssh.run_cmd_piped(&[
+        "gw=$(ip -4 route show default dev wlan0 | awk '{print $3; exit}'); ip -brief address \
+         show wlan0 && [ -n \"$gw\" ] && ping -c 3 -W 2 -I wlan0 $gw >/dev/null",
+    ])
+    .context("Connected, but the gateway is not reachable via wlan0")?;
+    info!("{dut} is connected to {}", args.ssid);
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// set (or clear) a static IPv4 address of a DUT
+#[argh(subcommand, name = "set-static-ip")]
+struct ArgsDutNetSetStaticIp {
+    /// DUT to configure (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// address in CIDR notation (e.g. 192.168.0.10/24)
+    #[argh(option)]
+    address: Option<String>,
+
+    /// default gateway
+    #[argh(option)]
+    gateway: Option<String>,
+
+    /// DNS server (can be specified multiple times)
+    #[argh(option)]
+    dns: Vec<String>,
+
+    /// type of the connected service to configure (default: ethernet)
+    #[argh(option, default = "String::from(\"ethernet\")")]
+    service_type: String,
+
+    /// go back to DHCP
+    #[argh(switch)]
+    clear: bool,
+
+    /// seconds to wait for the DUT on the new address (default: 60)
+    #[argh(option, default = "60")]
+    timeout: u64,
+}
+fn run_dut_net_set_static_ip(args: &ArgsDutNetSetStaticIp) -> Result<()> {
+    let dut = &resolve_dut(&args.dut, None)?;
+    let ssh = SshInfo::new(dut)?;
+    let service = connected_service(&ssh, &args.service_type)?;
+    if args.clear {
+        set_static_ip(&ssh, &service, None)?;
+        ssh.close_connection()?;
+        warn!("{dut} uses DHCP from now on. The address may change.");
+        return Ok(());
+    }
+    let (address, gateway) = match (&args.address, &args.gateway) {
+        (Some(address), Some(gateway)) => (address, gateway),
+        _ => bail!("Please specify --address and --gateway (or --clear)"),
+    };
+    let props = static_ip_properties(address, gateway, &args.dns)?;
+    let new_host = address.split('/').next().unwrap_or_default();
+    info!("Setting {address} to {service} of {dut}");
+    // The connection can be dropped if the address of the DUT changes
+    if let Err(e) = set_static_ip(&ssh, &service, Some(&props)) {
+        warn!("{e:#}");
+    }
+    ssh.close_connection()?;
+    let new_ssh = SshInfo::new_host_and_port(new_host, ssh.port())?.with_jump(ssh.jump().cloned());
+    info!("Waiting for {dut} on {new_host}...");
+    new_ssh
+        .wait_for_boot(time::Duration::from_secs(args.timeout), false)
+        .context(anyhow!(
+            "{dut} is not reachable on {new_host}. Please check the settings via the console."
+        ))?;
+    if let Ok(id) = registered_dut_id(dut) {
+        if ssh.host() != new_host {
+            SSH_CACHE.set(&id, new_ssh)?;
+            info!("Updated the address of {id} to {new_host}");
+        }
+    }
+    info!("{dut} is reachable on {new_host}");
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// configure a jump host (e.g. labstation, bastion) to reach a DUT
+#[argh(subcommand, name = "jump")]
+struct ArgsDutJump {
+    /// DUT which is reachable only via the jump host (ID or address). The DUT
+    /// is registered if it is not in the list yet.
+    #[argh(option)]
+    dut: String,
+
+    /// jump host in the form of [user@]host[:port]
+    #[argh(option)]
+    via: Option<String>,
+
+    /// identity file to log in to the jump host (e.g. ~/.ssh/testing_rsa for
+    /// labstations). The ssh config and agent are used if omitted.
+    #[argh(option)]
+    identity: Option<String>,
+
+    /// forward the ssh agent to the DUT
+    #[argh(switch)]
+    forward_agent: bool,
+
+    /// do not share a connection to the jump host among ssh sessions
+    #[argh(switch)]
+    no_connection_sharing: bool,
+
+    /// stop using the jump host for the DUT
+    #[argh(switch)]
+    clear: bool,
+}
+fn run_dut_jump(args: &ArgsDutJump) -> Result<()> {
+    if args.clear {
+        let entries = SSH_CACHE
+            .entries()
+            .context(anyhow!("SSH_CACHE is not initialized yet"))?;
+        let (id, ssh) = entries
+            .iter()
+            .find(|(id, ssh)| *id == &args.dut || ssh.host_and_port() == args.dut)
+            .context(anyhow!("DUT {} is not in the list", args.dut))?;
+        SSH_CACHE.set(id, ssh.with_jump(None))?;
+        info!("{id} is accessed directly from now on");
+        return Ok(());
+    }
+    let via = args
+        .via
+        .as_ref()
+        .context("Please specify a jump host with --via (or --clear)")?;
+    let jump = JumpHost::new(
+        via,
+        args.identity.clone(),
+        args.forward_agent,
+        args.no_connection_sharing,
+    )?;
+    cros::ensure_testing_rsa_is_there()?;
+    let info = register_dut_with_jump(&args.dut, jump)?;
+    info!("{} is accessed via {via} from now on", info.id());
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// manage a per-user SSH key for DUTs
+#[argh(subcommand, name = "key")]
+struct ArgsDutKey {
+    /// DUT (or DUT group) to install the key to / remove it from
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// install / remove on the DUTs that match the filter expression
+    #[argh(option)]
+    filter: Option<String>,
+
+    /// install / remove on all the registered DUTs
+    #[argh(switch)]
+    all: bool,
+
+    /// overwrite the existing key on generate
+    #[argh(switch)]
+    force: bool,
+
+    /// one of: show, generate, install, remove, rotate (install a new key to
+    /// the DUTs that have the current one, then remove the current one)
+    #[argh(positional)]
+    action: String,
+}
+
+fn run_dut_key(args: &ArgsDutKey) -> Result<()> {
+    cros::ensure_testing_rsa_is_there()?;
+    let target_duts = || -> Result<Vec<String>> {
+        let duts = match (&args.dut, &args.filter, args.all) {
+            (Some(dut), None, false) => expand_dut_groups(&[dut.clone()])?,
+            (None, Some(filter), false) => filter_duts(filter)?,
+            (None, None, true) => {
+                let mut duts: Vec<String> = SSH_CACHE.entries()?.into_keys().collect();
+                duts.sort();
+                duts
+            }
+            _ => bail!("Please specify one of --dut, --filter or --all"),
+        };
+        if duts.is_empty() {
+            bail!("No DUTs to update");
+        }
+        Ok(duts)
+    };
+    let current_key = || -> Result<String> {
+        user_public_key()?.context("No key is generated yet. Run `cro3 dut key generate` first.")
+    };
+    match args.action.as_str() {
+        "show" => {
+            println!("{}", current_key()?);
+            let mut duts: Vec<String> = DUT_USER_KEY_CACHE.entries()?.into_keys().collect();
+            duts.sort();
+            println!("Installed on: {}", duts.join(" "));
+        }
+        "generate" => {
+            if user_public_key()?.is_some() && !args.force {
+                bail!("The key is already generated. Use `rotate` to replace it on DUTs too.");
+            }
+            println!("{}", generate_key(&user_key_path()?)?);
+            DUT_USER_KEY_CACHE.clear()?;
+        }
+        "install" => {
+            let key = current_key()?;
+            for dut in target_duts()? {
+                let ssh = SshInfo::new(&dut)?;
+                install_key(&ssh, &key)?;
+                DUT_USER_KEY_CACHE
+                    .set(&registered_dut_id(&dut).unwrap_or(dut.clone()), key.clone())?;
+                info!("Installed the key to {dut}");
+            }
+        }
+        "remove" => {
+            let key = current_key()?;
+            for dut in target_duts()? {
+                let ssh = SshInfo::new(&dut)?;
+                remove_key(&ssh, &key)?;
+                DUT_USER_KEY_CACHE.remove(&registered_dut_id(&dut).unwrap_or(dut.clone()))?;
+                info!("Removed the key from {dut}");
+            }
+        }
+        "rotate" => {
+            let old_key = current_key()?;
+            let new_key_path = gen_path_in_cro3_dir("ssh/id_ed25519.new")?;
+            let new_key = generate_key(&new_key_path)?;
+            let mut duts: Vec<String> = DUT_USER_KEY_CACHE.entries()?.into_keys().collect();
+            duts.sort();
+            // Install the new key first so that the DUTs stay accessible even
+            // if the testing key is removed from them
+            for dut in &duts {
+                install_key(&SshInfo::new(dut)?, &new_key)
+                    .context(anyhow!("Failed to install the new key to {dut}"))?;
+            }
+            replace_user_key(&new_key_path)?;
+            for dut in &duts {
+                // Connections authenticated with the old key are reused otherwise
+                let ssh = SshInfo::new(dut)?;
+                ssh.close_connection()?;
+                remove_key(&ssh, &old_key)
+                    .context(anyhow!("Failed to remove the old key from {dut}"))?;
+                DUT_USER_KEY_CACHE.set(dut, new_key.clone())?;
+                info!("Rotated the key on {dut}");
+            }
+        }
+        action => {
+            bail!("Unknown action: {action}. Use one of show, generate, install, remove, rotate")
+        }
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// lease a DUT so that others can see who is using it
+#[argh(subcommand, name = "lease")]
+struct ArgsDutLease {
+    /// DUT to lease (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// duration of the lease in minutes. Leasing a DUT again extends it.
+    #[argh(option, default = "60")]
+    minutes: u64,
+
+    /// what you are doing with the DUT, shown to others
+    #[argh(option)]
+    reason: Option<String>,
+
+    /// take over the DUT even if someone else holds a lease on it
+    #[argh(switch)]
+    steal: bool,
+
+    /// release the lease
+    #[argh(switch)]
+    release: bool,
+
+    /// show the lease on the DUT (or all the leases you hold if --dut is
+    /// omitted)
+    #[argh(switch)]
+    show: bool,
+}
+fn run_dut_lease(args: &ArgsDutLease) -> Result<()> {
+    if args.show && args.dut.is_none() {
+        for (id, lease) in DUT_LEASE_CACHE.entries()? {
+            if lease.is_expired() {
+                DUT_LEASE_CACHE.remove(&id)?;
+            } else {
+                println!("{id:32} {}", lease.description());
+            }
+        }
+        return Ok(());
+    }
+    cros::ensure_testing_rsa_is_there()?;
+    let dut = resolve_dut(&args.dut, None)?;
+    let ssh = SshInfo::new(&dut)?;
+    let id = registered_dut_id(&dut).unwrap_or(dut.clone());
+    let current = ssh.get_lease()?;
+    if args.show {
+        match current {
+            Some(lease) => println!("{id}: {}", lease.description()),
+            None => println!("{id}: not leased"),
+        }
+        return Ok(());
+    }
+    if let Some(lease) = &current {
+        if !lease.is_mine() && !args.steal {
+            bail!(
+                "{id} is {}. Use --steal to take it over.",
+                lease.description()
+            );
+        }
+    }
+    if args.release {
+        if current.is_some() {
+            ssh.clear_lease()?;
+        }
+        DUT_LEASE_CACHE.remove(&id)?;
+        info!("Released {id}");
+        return Ok(());
+    }
+    let lease = DutLease::new(args.minutes, args.reason.clone());
+    ssh.set_lease(&lease)?;
+    DUT_LEASE_CACHE.set(&id, lease.clone())?;
+    info!("{id} is {}", lease.description());
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// set or show tags of DUTs, which can be used in --filter expressions
+#[argh(subcommand, name = "tag")]
+struct ArgsDutTag {
+    /// DUT to tag. If omitted, the tags of all the DUTs are shown (or a DUT is
+    /// chosen interactively when setting tags).
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// tags to set in the form of key=value, or key (same as key=true)
+    #[argh(positional)]
+    tags: Vec<String>,
+
+    /// key of a tag to remove
+    #[argh(option)]
+    remove: Vec<String>,
+}
+fn run_dut_tag(args: &ArgsDutTag) -> Result<()> {
+    if args.tags.is_empty() && args.remove.is_empty() {
+        let ids = if let Some(dut) = &args.dut {
+            vec![registered_dut_id(dut)?]
+        } else {
+            let mut ids: Vec<String> = SSH_CACHE.entries()?.into_keys().collect();
+            ids.sort();
+            ids
+        };
+        for id in ids {
+            let tags: Vec<String> = dut_tags(&id)?
+                .iter()
+                .filter(|(k, _)| *k != "id")
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect();
+            println!("{:32} {}", id, tags.join(" "));
+        }
+        return Ok(());
+    }
+    let id = registered_dut_id(&resolve_dut(&args.dut, None)?)?;
+    let mut tags = DUT_TAGS.get(&id)?.unwrap_or_default();
+    for tag in &args.tags {
+        let (k, v) = tag.split_once('=').unwrap_or((tag, "true"));
+        if k.is_empty()
+            || !k
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+        {
+            bail!("Invalid tag key {k:?}. Use alphanumeric chars, '_' and '-'.");
+        }
+        tags.insert(k.to_string(), v.to_string());
+    }
+    for k in &args.remove {
+        if tags.remove(k).is_none() {
+            warn!("{id} does not have a tag {k}");
+        }
+    }
+    DUT_TAGS.set(&id, tags)?;
+    info!("Updated the tags of {id}");
+    Ok(())
+}
+
 #[derive(FromArgs, PartialEq, Debug)]
 /// show DUT info
 #[argh(subcommand, name = "info")]
 struct ArgsDutInfo {
     /// DUT identifiers (e.g. 127.0.0.1, localhost:2222,
-    /// droid_NXHKDSJ003138124257611)
+    /// droid_NXHKDSJ003138124257611). If omitted, choose one of the registered
+    /// DUTs interactively.
     #[argh(option)]
-    dut: String,
+    dut: Option<String>,
     /// comma-separated list of attribute names. to show the full list, try
     /// `cro3 dut info --keys ?`
     #[argh(positional)]
     keys: Vec<String>,
+    /// print most of the attributes (or the ones given) as a JSON document,
+    /// with null for the ones not available on the DUT
+    #[argh(switch)]
+    json: bool,
 }
+const DUT_INFO_JSON_KEYS: [&str; 17] = [
+    "timestamp",
+    "dut_id",
+    "board",
+    "model",
+    "hwid",
+    "serial",
+    "release",
+    "os_version",
+    "chrome_version",
+    "arc_version",
+    "kernel",
+    "fwid",
+    "ro_fwid",
+    "ec_version",
+    "ipv4_addr",
+    "ipv6_addr",
+    "mac",
+];
 fn run_dut_info(args: &ArgsDutInfo) -> Result<()> {
-    let dut = &args.dut;
+    let dut = &resolve_dut(&args.dut, None)?;
+    if args.json {
+        let keys: Vec<&str> = if args.keys.is_empty() {
+            DUT_INFO_JSON_KEYS.to_vec()
+        } else {
+            args.keys.iter().map(|s| s.as_str()).collect()
+        };
+        let info = DutInfo::fetch_keys_optional(&SshInfo::new(dut)?, &keys)?;
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
     let keys = if args.keys.is_empty() {
         vec!["timestamp", "dut_id", "release", "model", "serial", "mac"]
     } else {
@@ -907,6 +3095,15 @@ pub struct ArgsDiscover {
     /// path to a list of DUT_IDs to scan.
     #[argh(option)]
     target_list: Option<String>,
+    /// find DUTs advertising SSH via mDNS (requires avahi-browse)
+    #[argh(switch)]
+    mdns: bool,
+    /// scan hosts that accept SSH in an IPv4 CIDR range (e.g. 192.168.0.0/24)
+    #[argh(option)]
+    cidr: Option<String>,
+    /// keep the discovered DUTs in the DUT list without asking
+    #[argh(switch)]
+    yes: bool,
     /// additional attributes to retrieve
     #[argh(positional, greedy)]
     extra_attr: Vec<String>,
@@ -944,12 +3141,53 @@ pub fn run_discover(args: &ArgsDiscover) -> Result<()> {
             .filter(|s| !s.is_empty())
             .map(str::to_string)
             .collect())
+    } else if args.mdns || args.cidr.is_some() {
+        let mut addrs = Vec::new();
+        if args.mdns {
+            addrs.extend(discover_mdns_nodes()?);
+        }
+        if let Some(cidr) = &args.cidr {
+            addrs.extend(scan_ssh_hosts(cidr)?);
+        }
+        addrs.sort();
+        addrs.dedup();
+        Ok(addrs)
     } else {
         discover_local_nodes(args.interface.to_owned())
     }?;
     info!("Found {} candidates. Checking...", addrs.len());
+    let known_duts = SSH_CACHE.entries()?;
     let duts = fetch_dut_info_in_parallel(&addrs, &args.extra_attr)?;
     info!("Discovery completed with {} DUTs", duts.len());
+    // DUTs are added to the list while fetching the info. Ask if the new
+    // ones should be kept.
+    let new_duts: Vec<&DutInfo> = duts
+        .iter()
+        .filter(|d| !known_duts.contains_key(d.id()))
+        .collect();
+    if !new_duts.is_empty() {
+        eprintln!("New DUTs found:");
+        for d in &new_duts {
+            let get = |k: &str| d.info().get(k).cloned().unwrap_or_default();
+            eprintln!(
+                "  {:32} {:24} {:12} {}",
+                d.id(),
+                d.ssh().host_and_port(),
+                get("board"),
+                get("release")
+            );
+        }
+        if !args.yes && !confirm("Add them to the DUT list?", true)? {
+            for d in &new_duts {
+                // The board and so on were cached while fetching the info
+                invalidate_dut_image_info(d.id())?;
+                SSH_CACHE.remove(d.id())?;
+            }
+            info!("Discarded {} new DUTs", new_duts.len());
+        } else {
+            info!("Added {} new DUTs to the list", new_duts.len());
+        }
+    }
     let duts: Vec<HashMap<String, String>> = duts.iter().map(|e| e.info().to_owned()).collect();
     let dut_list = serde_json::to_string_pretty(&duts)?;
     println!("{}", dut_list);
@@ -10,28 +10,135 @@
 //! cro3 flash --cros ${CROS} --dut ${DUT}
 //! # Flash an image into a USB stick
 //! cro3 flash --cros ${CROS} --usb --board ${BOARD}
+//! # Or, download a test image and write it to a USB stick without cros flash,
+//! # verifying the written data
+//! cro3 flash usb --board ${BOARD} --version 15753.0.0 --device /dev/sdb
+//! # Flash a prebuilt test image of a version, or the latest one on a channel,
+//! # without a cros checkout (partitions are written over SSH directly)
+//! cro3 flash --dut ${DUT} --board ${BOARD} --version 15753.0.0 --direct
+//! cro3 flash --dut ${DUT} --channel canary --direct
+//! # Flash the latest test image built locally in a cros checkout natively,
+//! # with no cros flash (or a given image file with --image and --direct)
+//! cro3 flash --dut ${DUT} --local-build ${CROS}
+//! # Flash a build shared by a teammate with `cro3 build upload`
+//! cro3 flash --dut ${DUT} --from gs://bucket/path
+//! # The stateful partition (test accounts, deployed packages) is kept by
+//! # default. Wipe it for a clean device (--yes skips the confirmation)
+//! cro3 flash --cros ${CROS} --dut ${DUT} --clobber-stateful --yes
+//! # Show the image to flash with its size, the DUT and the operations without
+//! # flashing
+//! cro3 flash --dut ${DUT} --version 15753.0.0 --direct --dry-run
+//! # Send only the changed blocks of the rootfs, if the DUT runs a nearby
+//! # version
+//! cro3 flash --dut ${DUT} --board ${BOARD} --version 15753.0.0 --delta
+//! # Flash multiple DUTs in parallel (each image is downloaded only once)
+//! cro3 flash --filter 'board==brya' --version 15753.0.0 --jobs 8
+//! cro3 flash --dut ${DUT} --dut ${IP} --channel dev
+//! # Reimage a bricked DUT from recovery mode via its servo (optionally writing
+//! # the AP firmware first)
+//! cro3 flash --cros ${CROS} --dut ${DUT} --via-servo --version 15753.0.0
+//! cro3 flash --cros ${CROS} --dut ${DUT} --via-servo --firmware image.bin
+//! # Write a recovery image (signed with the release keys if accessible) to a
+//! # USB stick, or recover a DUT with it via servo as consumers do
+//! cro3 flash usb --board ${BOARD} --image-type recovery --device /dev/sdb
+//! cro3 flash --cros ${CROS} --dut ${DUT} --via-servo --image-type recovery
+//! # Flash a release image signed with the release keys over SSH (the DUT has
+//! # no SSH access afterwards), optionally switching it to the normal mode
+//! cro3 flash --dut ${DUT} --version 15753.0.0 --image-type signed --normal-mode
+//! # The image type is chosen from the purpose (tast and ssh need test images,
+//! # develop uses dev images and consumer uses base images), unless
+//! # --image-type is given
+//! cro3 flash --cros ${CROS} --dut ${DUT} --purpose consumer
+//! # Update the AP and/or EC firmware over SSH, verifying the written data
+//! cro3 flash firmware --dut ${DUT} --ap image.bin --ec ec.bin --reboot
+//! # Or, the firmware of a release for the model of the DUT (via servo, if the
+//! # DUT does not boot)
+//! cro3 flash firmware --dut ${DUT} --version 15753.0.0
+//! cro3 flash firmware --dut ${DUT} --ap image.bin --via-servo --cros ${CROS}
+//! # Flash an Android build to an ARC development board with fastboot (only
+//! # the given partitions with --partition)
+//! cro3 flash android --serial 0123456789ABCDEF --build 10817441
 //! ```
 
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
 use std::process::Command;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::anyhow;
 use anyhow::bail;
+use anyhow::Context;
 use anyhow::Result;
 use argh::FromArgs;
+use cro3::arc::android_target_of_device;
+use cro3::arc::download_android_images;
+use cro3::arc::flash_android;
+use cro3::chroot::Chroot;
 use cro3::cros::ensure_testing_rsa_is_there;
 use cro3::cros::lookup_full_version;
+use cro3::dut::board_of_dut;
+use cro3::dut::ensure_dut_is_not_leased;
+use cro3::dut::expand_dut_groups;
+use cro3::dut::filter_duts;
+use cro3::dut::infer_board;
+use cro3::dut::invalidate_dut_image_info;
+use cro3::dut::model_of_dut;
+use cro3::dut::registered_dut_id;
+use cro3::dut::resolve_dut;
 use cro3::dut::DutInfo;
+use cro3::dut::SshInfo;
+use cro3::dut::DUT_BOARD_CACHE;
+use cro3::dut::DUT_LEASE_CACHE;
+use cro3::firmware::cleanup_on_dut;
+use cro3::firmware::download_firmware;
+use cro3::firmware::find_firmware_images;
+use cro3::firmware::firmware_versions;
+use cro3::firmware::flash_ap_over_ssh;
+use cro3::firmware::flash_ec_over_ssh;
+use cro3::firmware::record_firmware_update;
+use cro3::google_storage::stat_gs_file;
+use cro3::image_cache::format_size;
+use cro3::progress::FlashProgress;
+use cro3::progress::Phase;
+use cro3::provision::download_disk_image;
+use cro3::provision::download_recovery_image;
+use cro3::provision::download_signed_image;
+use cro3::provision::download_test_disk_image;
+use cro3::provision::download_test_image;
+use cro3::provision::image_sources;
+use cro3::provision::latest_local_image;
+use cro3::provision::latest_version_on_channel;
+use cro3::provision::provision_signed_image;
+use cro3::provision::provision_test_image;
+use cro3::provision::provision_via_servo;
+use cro3::provision::recover_via_servo;
+use cro3::provision::select_image_type;
+use cro3::provision::verify_provisioned_dut;
+use cro3::provision::ExpectedImage;
+use cro3::provision::ImageType;
+use cro3::provision::ProvisionOptions;
+use cro3::provision::Purpose;
+use cro3::provision::TestImage;
+use cro3::provision::RECOVERY_INSTALL_TIME;
 use cro3::repo::get_cros_dir;
+use cro3::servo::find_servo_for_dut;
+use cro3::servo::ServodConnection;
+use cro3::shared_build::download_shared_image;
+use cro3::shared_build::shared_build_metadata;
+use cro3::usb_image::write_image_with_sudo;
+use cro3::usb_image::BlockDevice;
+use cro3::util::picker::confirm;
+use rayon::prelude::*;
 use regex::Regex;
 use tracing::error;
 use tracing::info;
+use tracing::warn;
 
 fn get_board_from_dut(dut: &str) -> Result<String> {
-    let dut = DutInfo::new(dut)?;
-    dut.info()
-        .get("board")
-        .cloned()
-        .ok_or(anyhow!("Failed to get --board from "))
+    board_of_dut(dut).context("Failed to get --board from the DUT")
 }
 
 /// Determine a BOARD to flash, based on the parameters.
@@ -44,7 +151,7 @@ fn determine_board_to_flash(
 ) -> Result<String> {
     match (arg_dut, arg_board) {
         (None, None) => bail!("Please specify --board or --dut"),
-        (Some(dut), None) => get_board_from_dut(dut),
+        (Some(dut), None) => infer_board(None, Some(dut)),
         (None, Some(board)) => Ok(board.to_string()),
         (Some(dut), Some(board_from_arg)) => {
             // Check if the base board names (without suffix '64' or '-*') are matched
@@ -85,9 +192,20 @@ pub struct Args {
     #[argh(switch)]
     usb: bool,
 
-    /// flash to a dut
+    /// flash to a dut. If neither --dut nor --usb is given, choose one of the
+    /// registered DUTs (compatible with --board) interactively. Can be
+    /// repeated (or a DUT group) to flash multiple DUTs in parallel.
     #[argh(option)]
-    dut: Option<String>,
+    dut: Vec<String>,
+
+    /// flash all the DUTs that match the filter expression (e.g.
+    /// 'board==brya && has_servo') in parallel
+    #[argh(option)]
+    filter: Option<String>,
+
+    /// number of DUTs to flash at the same time (default: 4)
+    #[argh(option, default = "4")]
+    jobs: usize,
 
     /// target cros repo dir
     #[argh(option)]
@@ -97,7 +215,8 @@ pub struct Args {
     #[argh(option)]
     board: Option<String>,
 
-    /// path to image to flash
+    /// path to image to flash. To flash it to many DUTs, they must be of the
+    /// same board (and of --board, if given).
     #[argh(option)]
     image: Option<String>,
 
@@ -105,32 +224,535 @@ pub struct Args {
     #[argh(option, default = "String::from(\"latest-dev\")")]
     version: String,
 
+    /// flash the latest version released to the channel (canary, dev, beta
+    /// or stable) instead of --version
+    #[argh(option)]
+    channel: Option<String>,
+
+    /// flash a test image (prebuilt, or given by --image) by writing the
+    /// partitions over SSH, without `cros flash`. This is used automatically
+    /// for prebuilt images if no cros checkout is found.
+    #[argh(switch)]
+    direct: bool,
+
     /// flash a locally-built image instead of remote prebuilts
     #[argh(switch)]
     use_local_image: bool,
 
-    /// flash recovery image (default: flash test image)
+    /// type of the image to flash: test, dev, base, recovery or signed
+    /// (default: chosen from --purpose, or test). Recovery images can be
+    /// flashed to USB sticks, or to DUTs with --via-servo. Signed release
+    /// images are flashed to DUTs over SSH, after which the DUTs have no SSH
+    /// access.
+    #[argh(option)]
+    image_type: Option<ImageType>,
+
+    /// what the DUT is flashed for, to choose the image type: tast, ssh
+    /// (test images), develop (dev images) or consumer (base images)
+    #[argh(option)]
+    purpose: Option<Purpose>,
+
+    /// switch the DUT to the normal (verified boot) mode after flashing a
+    /// signed image
     #[argh(switch)]
-    recovery: bool,
+    normal_mode: bool,
 
     /// flash image with rootfs verification (disable by default)
     #[argh(switch)]
     enable_rootfs_verification: bool,
 
+    /// flash the DUT even if someone else holds a lease on it
+    #[argh(switch)]
+    steal: bool,
+
+    /// cros checkout to flash the latest test image built for the board of the
+    /// DUT from. The image is written over SSH directly as with --direct.
+    #[argh(option)]
+    local_build: Option<String>,
+
+    /// GS path of a build shared with `cro3 build upload` (e.g.
+    /// gs://bucket/path) to flash. The image is written over SSH directly as
+    /// with --direct.
+    #[argh(option)]
+    from: Option<String>,
+
+    /// reimage a DUT that can not be reached over SSH using its servo: the
+    /// test image is written to the USB stick on the servo, and installed
+    /// from recovery mode. Needs a cros checkout to run servod.
+    #[argh(switch)]
+    via_servo: bool,
+
+    /// serial of the servo to use with --via-servo (default: the one tagged
+    /// to the DUT, or the only one connected)
+    #[argh(option)]
+    servo: Option<String>,
+
+    /// AP firmware image to write via servo before reimaging (with
+    /// --via-servo)
+    #[argh(option)]
+    firmware: Option<String>,
+
+    /// do not check the version, rootfs verification and the login screen of
+    /// the DUT after flashing
+    #[argh(switch)]
+    skip_post_check: bool,
+
+    /// send only the blocks of the rootfs that differ from the running one,
+    /// which is much faster if the DUT runs a nearby version (implies
+    /// --direct)
+    #[argh(switch)]
+    delta: bool,
+
+    /// keep the stateful partition (test accounts, deployed packages and
+    /// files) of the DUT. This is the default.
+    #[argh(switch)]
+    keep_stateful: bool,
+
+    /// wipe the stateful partition of the DUT for a fully clean device, after
+    /// a confirmation
+    #[argh(switch)]
+    clobber_stateful: bool,
+
+    /// do not ask for confirmation before wiping the stateful partition
+    #[argh(switch)]
+    yes: bool,
+
+    /// show the image (the GS objects or the local file) to flash with its
+    /// size, the target DUTs and boards, and the operations, without
+    /// downloading the image or touching the DUTs
+    #[argh(switch)]
+    dry_run: bool,
+
     #[argh(option, hidden_help)]
     repo: Option<String>,
+
+    /// same as --image-type recovery
+    #[argh(switch, hidden_help)]
+    recovery: bool,
+
+    #[argh(subcommand)]
+    nested: Option<SubCommand>,
+}
+
+// Args is boxed in cmd::Args since it is much larger than the others
+// (clippy::large_enum_variant). argh does not support boxed subcommands, so
+// they are parsed as Args.
+impl argh::SubCommand for Box<Args> {
+    const COMMAND: &'static argh::CommandInfo = <Args as argh::SubCommand>::COMMAND;
+}
+impl FromArgs for Box<Args> {
+    fn from_args(command_name: &[&str], args: &[&str]) -> Result<Self, argh::EarlyExit> {
+        Args::from_args(command_name, args).map(Box::new)
+    }
+    fn redact_arg_values(
+        command_name: &[&str],
+        args: &[&str],
+    ) -> Result<Vec<String>, argh::EarlyExit> {
+        Args::redact_arg_values(command_name, args)
+    }
+}
+
+impl Args {
+    /// The image type given with --image-type (or the old --recovery switch)
+    fn requested_image_type(&self) -> Option<ImageType> {
+        self.image_type
+            .or(self.recovery.then_some(ImageType::Recovery))
+    }
+    fn image_type(&self) -> ImageType {
+        select_image_type(self.purpose, self.requested_image_type()).0
+    }
+    /// Whether the image is written over SSH directly by the options
+    fn explicitly_direct(&self) -> bool {
+        self.direct || self.local_build.is_some() || self.from.is_some() || self.delta
+    }
+    fn recovery(&self) -> bool {
+        self.image_type() == ImageType::Recovery
+    }
+    /// Test images are needed to flash over SSH directly, and to check the
+    /// DUT after flashing
+    fn test_image(&self) -> bool {
+        self.image_type() == ImageType::Test
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum SubCommand {
+    Usb(ArgsFlashUsb),
+    Firmware(ArgsFlashFirmware),
+    Android(ArgsFlashAndroid),
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// write a test or recovery image to a USB stick without cros flash, and
+/// verify it
+#[argh(subcommand, name = "usb")]
+struct ArgsFlashUsb {
+    /// device file of the USB stick (e.g. /dev/sdb)
+    #[argh(option)]
+    device: String,
+
+    /// target BOARD (not needed with --image)
+    #[argh(option)]
+    board: Option<String>,
+
+    /// chromiumos version to write (default: latest-dev)
+    #[argh(option, default = "String::from(\"latest-dev\")")]
+    version: String,
+
+    /// write the latest version released to the channel (canary, dev, beta
+    /// or stable) instead of --version
+    #[argh(option)]
+    channel: Option<String>,
+
+    /// path to a disk image to write instead of downloading one
+    #[argh(option)]
+    image: Option<String>,
+
+    /// type of the image to download: test (default), dev, base or recovery
+    #[argh(option, default = "ImageType::Test")]
+    image_type: ImageType,
+
+    /// do not ask for confirmation before writing
+    #[argh(switch)]
+    yes: bool,
+
+    /// write even if the device does not look like a USB stick (e.g. not
+    /// removable, or too large). Mounted devices are always refused.
+    #[argh(switch)]
+    force: bool,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// flash an Android build to an ARC development board with fastboot
+#[argh(subcommand, name = "android")]
+struct ArgsFlashAndroid {
+    /// adb / fastboot serial of the device
+    #[argh(option)]
+    serial: String,
+
+    /// build ID of the Android build to flash
+    #[argh(option)]
+    build: String,
+
+    /// build target (default: the one running on the device, e.g.
+    /// cheets_x86_64-userdebug)
+    #[argh(option)]
+    target: Option<String>,
+
+    /// partitions to flash (e.g. boot, system). Can be repeated. All the
+    /// partitions in the build are flashed if omitted.
+    #[argh(option)]
+    partition: Vec<String>,
+
+    /// wipe userdata
+    #[argh(switch)]
+    wipe: bool,
+}
+fn run_android(args: &ArgsFlashAndroid) -> Result<()> {
+    let target = match &args.target {
+        Some(target) => target.clone(),
+        None => android_target_of_device(&args.serial)
+            .context("Failed to get the build target from the device. Please specify --target")?,
+    };
+    let zip = download_android_images(&args.build, &target)?;
+    let running = flash_android(&args.serial, &zip, &args.partition, args.wipe)?;
+    if running != args.build {
+        warn!(
+            "{} runs build {running}, not {}. Some partitions may not have been flashed.",
+            args.serial, args.build
+        );
+    } else {
+        info!("Flashed {target} {} to {}", args.build, args.serial);
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// update the AP and/or EC firmware of a DUT, and verify it
+#[argh(subcommand, name = "firmware")]
+struct ArgsFlashFirmware {
+    /// target DUT
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// AP firmware image to write
+    #[argh(option)]
+    ap: Option<String>,
+
+    /// EC firmware image to write
+    #[argh(option)]
+    ec: Option<String>,
+
+    /// write the AP and EC firmware of a release (e.g. 15753.0.0 or
+    /// latest-dev) for the model of the DUT, instead of --ap and --ec
+    #[argh(option)]
+    version: Option<String>,
+
+    /// BOARD of the DUT (default: the cached one)
+    #[argh(option)]
+    board: Option<String>,
+
+    /// model of the DUT to pick the firmware of --version for (default: the
+    /// cached one)
+    #[argh(option)]
+    model: Option<String>,
+
+    /// write via the servo of the DUT instead of over SSH. Works even if the
+    /// DUT does not boot. Needs a cros checkout.
+    #[argh(switch)]
+    via_servo: bool,
+
+    /// serial of the servo to use with --via-servo
+    #[argh(option)]
+    servo: Option<String>,
+
+    /// cros checkout to run servod and the flash tools in (with --via-servo)
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// reboot the DUT after the update to run the new firmware
+    #[argh(switch)]
+    reboot: bool,
+
+    /// update the DUT even if someone else holds a lease on it
+    #[argh(switch)]
+    steal: bool,
+}
+
+fn print_firmware_versions(title: &str, versions: &BTreeMap<String, Option<String>>) {
+    println!("{title}:");
+    for (key, value) in versions {
+        println!("  {key:<12} {}", value.as_deref().unwrap_or("-"));
+    }
+}
+
+fn run_firmware(args: &ArgsFlashFirmware) -> Result<()> {
+    ensure_testing_rsa_is_there()?;
+    let dut = resolve_dut(&args.dut, args.board.as_deref())?;
+    let id = registered_dut_id(&dut).unwrap_or(dut.clone());
+    let ssh = SshInfo::new(&dut)?;
+    let reachable = ssh.is_reachable(10);
+    if reachable {
+        ensure_dut_is_not_leased(&ssh, args.steal)?;
+    } else if !args.via_servo {
+        bail!("{id} is not reachable over SSH. Use --via-servo to update it via its servo.");
+    }
+    let board = || -> Result<String> { infer_board(args.board.as_deref(), Some(&dut)) };
+    let (ap, ec) = if let Some(version) = &args.version {
+        if args.ap.is_some() || args.ec.is_some() {
+            bail!("--version can not be used with --ap or --ec");
+        }
+        let board = board()?;
+        let model = match &args.model {
+            Some(model) => model.clone(),
+            None => model_of_dut(&dut).context("Please specify --model")?,
+        };
+        let version = resolve_prebuilt_version(&None, version, &board)?;
+        let dir = download_firmware(&board, &version)?;
+        match find_firmware_images(&dir, &model) {
+            (None, None) => bail!("No firmware for {model} is found in {dir:?}"),
+            images => images,
+        }
+    } else {
+        (
+            args.ap.as_ref().map(PathBuf::from),
+            args.ec.as_ref().map(PathBuf::from),
+        )
+    };
+    if ap.is_none() && ec.is_none() {
+        bail!("Please specify --ap, --ec or --version");
+    }
+
+    let before = if reachable {
+        let versions = firmware_versions(&ssh)?;
+        print_firmware_versions("Before", &versions);
+        Some(versions)
+    } else {
+        None
+    };
+    let mut servod = None;
+    let written = if args.via_servo {
+        let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+        let servo = find_servo_for_dut(&id, &args.servo)?;
+        let conn = match ServodConnection::from_serial(servo.serial()) {
+            Ok(conn) => conn,
+            Err(_) => servo.start_servod(&chroot)?,
+        };
+        // futility and flash_ec verify the written data
+        if let Some(ap) = &ap {
+            conn.flash_ap_firmware(&chroot, &chroot.share_file(ap)?)?;
+        }
+        if let Some(ec) = &ec {
+            conn.flash_ec_firmware(&chroot, &board()?, &chroot.share_file(ec)?)?;
+        }
+        servod = Some((chroot, conn));
+        None
+    } else {
+        let written = ap
+            .as_ref()
+            .map(|ap| flash_ap_over_ssh(&ssh, ap))
+            .transpose()?;
+        if let Some(ec) = &ec {
+            flash_ec_over_ssh(&ssh, ec)?;
+        }
+        cleanup_on_dut(&ssh)?;
+        written
+    };
+
+    let after = if args.reboot {
+        info!("Rebooting {id}...");
+        if let Some((chroot, conn)) = &servod {
+            conn.run_dut_control(chroot, &["power_state:reset"])?;
+        } else if ec.is_some() {
+            // Reboot the EC too so that it runs the new RW firmware
+            ssh.run_cmd_stdio(
+                "ectool reboot_ec cold at-shutdown; (sleep 1; reboot) >/dev/null 2>&1 &",
+            )?;
+        } else {
+            ssh.run_cmd_stdio("(sleep 1; reboot) >/dev/null 2>&1 &")?;
+        }
+        ssh.close_connection()?;
+        std::thread::sleep(Duration::from_secs(10));
+        ssh.wait_for_boot(Duration::from_secs(300), false)?;
+        let versions = firmware_versions(&ssh)?;
+        print_firmware_versions("After", &versions);
+        Some(versions)
+    } else {
+        info!("The new firmware runs after a reboot (or use --reboot)");
+        None
+    };
+    record_firmware_update(&id, ap.as_deref(), ec.as_deref(), &before, &written, &after)
+}
+
+fn run_usb(args: &ArgsFlashUsb) -> Result<()> {
+    let image = if let Some(image) = &args.image {
+        PathBuf::from(image)
+    } else {
+        let board = args
+            .board
+            .as_ref()
+            .context("Please specify --board or --image")?;
+        let version = resolve_prebuilt_version(&args.channel, &args.version, board)?;
+        if args.image_type == ImageType::Signed {
+            bail!(
+                "Signed release images do not boot from USB sticks. Please use --image-type \
+                 recovery."
+            );
+        }
+        download_disk_image(board, &version, args.image_type)?
+    };
+    let image_size = fs::metadata(&image)
+        .context(anyhow!("Failed to read {image:?}"))?
+        .len();
+    let device = BlockDevice::of(&args.device)?;
+    device.check_writable(image_size, args.force)?;
+    if !args.yes
+        && !confirm(
+            &format!(
+                "All the data on {} will be lost. Write {image:?}?",
+                device.description()
+            ),
+            false,
+        )?
+    {
+        bail!("Aborted");
+    }
+    write_image_with_sudo(&image, &args.device, args.force)?;
+    info!("Wrote and verified {image:?} on {}", device.description());
+    Ok(())
 }
 #[tracing::instrument(level = "trace")]
 pub fn run(args: &Args) -> Result<()> {
+    match &args.nested {
+        Some(SubCommand::Usb(args)) => return run_usb(args),
+        Some(SubCommand::Firmware(args)) => return run_firmware(args),
+        Some(SubCommand::Android(args)) => return run_android(args),
+        None => {}
+    }
+    if args.recovery {
+        if args.image_type.is_some_and(|t| t != ImageType::Recovery) {
+            bail!("--recovery can not be used with --image-type");
+        }
+        warn!("--recovery is deprecated. Please use --image-type recovery instead.");
+    }
+    if let (_, Some(warning)) = select_image_type(args.purpose, args.requested_image_type()) {
+        warn!("{warning}");
+    }
     // repo path is needed since cros flash outside chroot only works within the
     // cros checkout
-    let repo = &get_cros_dir(&args.cros)?;
+    let repo = get_cros_dir(&args.cros);
+    let mut targets = expand_dut_groups(&args.dut)?;
+    if let Some(filter) = &args.filter {
+        for dut in filter_duts(filter)? {
+            if !targets.contains(&dut) {
+                targets.push(dut);
+            }
+        }
+    }
+    if args.keep_stateful && args.clobber_stateful {
+        bail!("Please specify either --keep-stateful or --clobber-stateful");
+    }
+    let method = flash_method(args, &targets, &repo);
+    if args.dry_run {
+        return print_flash_plan(args, &repo, &targets, &method);
+    }
+    if args.firmware.is_some() && method != FlashMethod::ViaServo {
+        bail!("--firmware is only supported with --via-servo");
+    }
+    if args.normal_mode && method != FlashMethod::Signed {
+        bail!("--normal-mode is only supported with --image-type signed");
+    }
+    match method {
+        FlashMethod::ViaServo => {
+            let dut = match targets.as_slice() {
+                [dut] => dut,
+                _ => bail!("Please specify a DUT to flash with --via-servo"),
+            };
+            return run_via_servo(args, &repo?, dut);
+        }
+        FlashMethod::Signed => {
+            let dut = match targets.as_slice() {
+                [dut] => dut,
+                _ => bail!("Please specify a DUT to flash a signed image to"),
+            };
+            return run_signed(args, dut);
+        }
+        FlashMethod::Parallel => return run_many(args, &targets),
+        FlashMethod::Direct | FlashMethod::CrosFlash => {}
+    }
+    let dut = if args.usb {
+        targets.first().cloned()
+    } else {
+        Some(resolve_dut(
+            &targets.first().cloned(),
+            args.board.as_deref(),
+        )?)
+    };
+    if let (Some(dut), false) = (&dut, args.usb) {
+        confirm_clobber_stateful(args, &[dut.clone()])?;
+    }
+    if args.explicitly_direct() && (args.usb || !args.test_image() || args.use_local_image) {
+        bail!(
+            "--direct, --delta, --local-build and --from only support flashing test images to a \
+             DUT"
+        );
+    }
+    if let (Some(dut), FlashMethod::Direct) = (&dut, &method) {
+        if !args.explicitly_direct() {
+            info!("No cros checkout is found. Writing the image over SSH directly.");
+        }
+        return run_direct(args, dut);
+    }
+    let repo = &repo?;
 
+    // Full version to check after flashing, if known
+    let mut expected_version = None;
     let image_path = if let Some(image) = &args.image {
         // If --image is specified, use the local file
         image.clone()
     } else {
-        let board_to_flash = determine_board_to_flash(&args.dut, &args.board)?;
+        let board_to_flash = determine_board_to_flash(&dut, &args.board)?;
         info!("{board_to_flash}");
 
         // Determine an image to flash
@@ -141,7 +763,9 @@ pub fn run(args: &Args) -> Result<()> {
         };
         // if version is not specified on the command line, it will be set to "latest"
         // by argh
-        let version = if &args.version == "latest"
+        let version = if let Some(channel) = &args.channel {
+            latest_version_on_channel(&board_to_flash, channel)?
+        } else if &args.version == "latest"
             || &args.version == "latest-dev"
             || &args.version == "latest-official"
         {
@@ -149,35 +773,35 @@ pub fn run(args: &Args) -> Result<()> {
         } else {
             lookup_full_version(&args.version, &board_to_flash)?
         };
+        if !version.starts_with("latest") {
+            expected_version = Some(version.clone());
+        }
         if host == "local" && version != "latest" {
             return Err(anyhow!(
                 "flashing local image other than `--version latest` is not yet supported"
             ));
         }
-        let variant = if args.recovery { "signed" } else { "test" };
+        let variant = args.image_type().xbuddy_variant();
         format!("xBuddy://{host}/{board_to_flash}/{version}/{variant}")
     };
 
     // Determine a destination
-    let destination = match (&args.dut, args.usb, args.recovery) {
+    let destination = match (&dut, args.usb, args.recovery()) {
         (Some(dut), false, false) => {
             ensure_testing_rsa_is_there()?;
             let dut = &DutInfo::new(dut)?;
+            ensure_dut_is_not_leased(dut.ssh(), args.steal)?;
             dut.ssh().into_forwarded()?.host_and_port().to_string()
         }
         (Some(_), false, true) => bail!(
             "Recovery image is not for flashing via SSH. Please specify --usb as a destination \
-             instead of --dut."
+             instead of --dut, or use --via-servo."
         ),
         (None, true, _) => "usb://".to_string(),
         _ => bail!("Please specify either --dut or --usb"),
     };
 
-    let mut cmd_args: Vec<&str> =
-        Vec::from(["flash", "--clobber-stateful", "--clear-tpm-owner", "-vvv"]);
-    if !args.enable_rootfs_verification {
-        cmd_args.push("--disable-rootfs-verification");
-    }
+    let mut cmd_args = cros_flash_args(args);
     cmd_args.push(&destination);
     cmd_args.push(&image_path);
 
@@ -189,5 +813,706 @@ pub fn run(args: &Args) -> Result<()> {
     if !result.status.success() {
         error!("cros sdk failed");
     }
+    if let Some(dut) = &dut {
+        // The board can be changed by the flash. It will be fetched again on
+        // the next contact.
+        invalidate_dut_image_info(dut)?;
+        if result.status.success() && !args.test_image() {
+            // The DUT has no SSH access with the testing key anymore
+            return Ok(());
+        }
+        restore_lease(dut)?;
+        if result.status.success() && !args.usb {
+            post_flash_check(
+                args,
+                &SshInfo::new(dut)?,
+                expected_version,
+                args.enable_rootfs_verification,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the arguments of `cros flash` except the destination and the image
+fn cros_flash_args(args: &Args) -> Vec<&str> {
+    let mut cmd_args: Vec<&str> = Vec::from(["flash", "-vvv"]);
+    // Clearing the TPM owner makes the encrypted stateful unreadable, so it is
+    // done only when the stateful partition is wiped anyway
+    if args.clobber_stateful {
+        cmd_args.extend(["--clobber-stateful", "--clear-tpm-owner"]);
+    }
+    if !args.enable_rootfs_verification {
+        cmd_args.push("--disable-rootfs-verification");
+    }
+    cmd_args
+}
+
+/// How `cro3 flash` writes an image, decided from the arguments
+#[derive(Debug, PartialEq)]
+enum FlashMethod {
+    CrosFlash,
+    Direct,
+    /// Direct, to multiple DUTs in parallel
+    Parallel,
+    ViaServo,
+    Signed,
+}
+
+/// Chooses how run() flashes `targets` (the DUTs given with --dut and the ones
+/// that match --filter)
+fn flash_method(args: &Args, targets: &[String], repo: &Result<String>) -> FlashMethod {
+    let prebuilt_to_dut =
+        !args.usb && args.image.is_none() && !args.use_local_image && args.test_image();
+    if args.via_servo {
+        FlashMethod::ViaServo
+    } else if args.image_type() == ImageType::Signed {
+        FlashMethod::Signed
+    } else if targets.len() > 1 || args.filter.is_some() {
+        FlashMethod::Parallel
+    } else if !args.usb && (args.explicitly_direct() || (prebuilt_to_dut && repo.is_err())) {
+        FlashMethod::Direct
+    } else {
+        FlashMethod::CrosFlash
+    }
+}
+
+/// Prints what `cro3 flash` would do with `args`, without downloading the
+/// images or contacting the DUTs. Only the metadata of the images is fetched.
+fn print_flash_plan(
+    args: &Args,
+    repo: &Result<String>,
+    targets: &[String],
+    method: &FlashMethod,
+) -> Result<()> {
+    let targets = if targets.is_empty() && !args.usb && args.filter.is_none() {
+        vec![resolve_dut(&None, args.board.as_deref())?]
+    } else {
+        targets.to_vec()
+    };
+    println!("Method: {method:?}");
+    if method == &FlashMethod::Parallel {
+        println!("DUTs: {} ({} at a time)", targets.len(), args.jobs.max(1));
+    }
+
+    let mut boards = Vec::new();
+    for dut in &targets {
+        // The DUT is not contacted, so only the cached board is known
+        let board = match &args.board {
+            Some(board) => Some(board.clone()),
+            None => DUT_BOARD_CACHE.get(&registered_dut_id(dut).unwrap_or(dut.clone()))?,
+        };
+        println!(
+            "DUT: {dut} (board: {})",
+            board
+                .as_deref()
+                .unwrap_or("unknown, please specify --board")
+        );
+        if let Some(board) = board {
+            if !boards.contains(&board) {
+                boards.push(board);
+            }
+        }
+    }
+    if args.usb {
+        println!("Destination: a USB stick (chosen by cros flash)");
+        boards.extend(args.board.clone());
+    }
+    for board in &boards {
+        print_image_to_flash(args, method, board)?;
+    }
+
+    println!("Operations:");
+    let mut ops: Vec<String> = Vec::new();
+    if !args.steal && !args.usb {
+        ops.push("Check that the DUT is not leased by someone else".to_string());
+    }
+    match method {
+        FlashMethod::CrosFlash => {
+            ops.push(format!(
+                "Run `cros {} <destination> <image>` in {}",
+                cros_flash_args(args).join(" "),
+                repo.as_deref().unwrap_or("the cros checkout")
+            ));
+        }
+        FlashMethod::Direct | FlashMethod::Parallel => {
+            if method == &FlashMethod::Parallel && args.image.is_some() {
+                ops.push("Check that all the DUTs are of the board of the image".to_string());
+            }
+            if args.delta {
+                ops.push("Write the kernel to the inactive slot over SSH".to_string());
+                ops.push(
+                    "Copy the running rootfs to the inactive slot, and send only the blocks that \
+                     differ"
+                        .to_string(),
+                );
+            } else {
+                ops.push(
+                    "Write the kernel and the rootfs to the inactive slot over SSH".to_string(),
+                );
+            }
+            ops.push(if args.clobber_stateful {
+                "Replace the stateful partition with the one of the image (wiping the data)"
+                    .to_string()
+            } else {
+                "Update dev_image and var_overlay, keeping the stateful partition".to_string()
+            });
+            if !args.enable_rootfs_verification {
+                ops.push("Disable rootfs verification".to_string());
+            }
+            ops.push(if args.clobber_stateful {
+                "Clear the TPM owner, and reboot into the inactive slot".to_string()
+            } else {
+                "Reboot into the inactive slot".to_string()
+            });
+        }
+        FlashMethod::ViaServo => {
+            if let Some(firmware) = &args.firmware {
+                ops.push(format!("Write the AP firmware {firmware} via servo"));
+            }
+            ops.push("Write the image to the USB stick on the servo".to_string());
+            if args.recovery() {
+                ops.push(format!(
+                    "Boot the DUT in recovery mode, and wait {} minutes for the image to install \
+                     itself",
+                    RECOVERY_INSTALL_TIME.as_secs() / 60
+                ));
+            } else {
+                ops.push(
+                    "Boot the DUT in recovery mode from the USB stick, and run chromeos-install"
+                        .to_string(),
+                );
+                ops.push("Boot the DUT from the internal disk".to_string());
+            }
+        }
+        FlashMethod::Signed => {
+            ops.push("Write the kernel and the rootfs to the inactive slot over SSH".to_string());
+            ops.push("Request a powerwash (wiping the stateful partition)".to_string());
+            if args.normal_mode {
+                ops.push("Request the normal mode".to_string());
+            }
+            ops.push("Reboot into the image (the DUT has no SSH access afterwards)".to_string());
+        }
+    }
+    let post_check = match method {
+        FlashMethod::CrosFlash => !args.usb && args.test_image(),
+        FlashMethod::Direct | FlashMethod::Parallel => true,
+        FlashMethod::ViaServo => !args.recovery(),
+        FlashMethod::Signed => false,
+    };
+    if post_check && !args.skip_post_check {
+        ops.push("Check the version, rootfs verification and the login screen".to_string());
+    }
+    for (i, op) in ops.iter().enumerate() {
+        println!("  {}. {op}", i + 1);
+    }
+    Ok(())
+}
+
+/// Prints the image that would be flashed for `board`, and its size
+fn print_image_to_flash(args: &Args, method: &FlashMethod, board: &str) -> Result<()> {
+    let local = if let Some(image) = &args.image {
+        Some(PathBuf::from(image))
+    } else if let (Some(repo), false) = (&args.local_build, method == &FlashMethod::CrosFlash) {
+        Some(latest_local_image(repo, board)?)
+    } else {
+        None
+    };
+    if let Some(image) = local {
+        let size = fs::metadata(&image)
+            .context(anyhow!("Failed to read {image:?}"))?
+            .len();
+        println!("Image for {board}: {image:?} ({})", format_size(size));
+        return Ok(());
+    }
+    if let Some(from) = &args.from {
+        let build = shared_build_metadata(from)?;
+        println!(
+            "Image for {board}: {from} ({} {} for {}, uploaded by {})",
+            build.image.unwrap_or("no image".to_string()),
+            build.version,
+            build.board,
+            build.builder
+        );
+        return Ok(());
+    }
+    if method == &FlashMethod::CrosFlash {
+        let host = if args.use_local_image {
+            "local"
+        } else {
+            "remote"
+        };
+        let variant = args.image_type().xbuddy_variant();
+        let version = match &args.channel {
+            Some(channel) => latest_version_on_channel(board, channel)?,
+            None if args.version.starts_with("latest") || args.use_local_image => {
+                args.version.clone()
+            }
+            None => lookup_full_version(&args.version, board)?,
+        };
+        println!(
+            "Image for {board}: xBuddy://{host}/{board}/{version}/{variant} (resolved by cros \
+             flash)"
+        );
+        return Ok(());
+    }
+    let version = resolve_prebuilt_version(&args.channel, &args.version, board)?;
+    println!("Image for {board}: {version}");
+    let disk = method == &FlashMethod::ViaServo;
+    for source in image_sources(board, &version, args.image_type(), disk)? {
+        match &source.cached {
+            Some(path) => println!(
+                "  {} (cached at {path:?}, {})",
+                source.url,
+                format_size(fs::metadata(path)?.len())
+            ),
+            None => println!(
+                "  {} ({} to download)",
+                source.url,
+                format_size(stat_gs_file(&source.url)?.size)
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Returns the full version (e.g. R120-15662.0.0) of the prebuilt image to
+/// flash with --direct. The "latest-*" aliases of xBuddy are mapped to the
+/// channels.
+fn resolve_prebuilt_version(
+    channel: &Option<String>,
+    version: &str,
+    board: &str,
+) -> Result<String> {
+    let channel = match (channel, version) {
+        (Some(channel), _) => Some(channel.as_str()),
+        (None, "latest" | "latest-official") => Some("canary"),
+        (None, version) => version.strip_prefix("latest-"),
+    };
+    if let Some(channel) = channel {
+        latest_version_on_channel(board, channel)
+    } else {
+        lookup_full_version(version, board)
+    }
+}
+
+/// Checks that the DUT runs the flashed image, unless --skip-post-check
+fn post_flash_check(
+    args: &Args,
+    ssh: &SshInfo,
+    version: Option<String>,
+    rootfs_verification: bool,
+) -> Result<()> {
+    if args.skip_post_check {
+        return Ok(());
+    }
+    verify_provisioned_dut(
+        ssh,
+        &ExpectedImage {
+            version,
+            rootfs_verification,
+        },
+    )
+    .context("Post-flash check failed")
+}
+
+/// An image to flash with --direct
+struct DirectImage {
+    image: TestImage,
+    description: String,
+    /// Full version of a prebuilt image
+    version: Option<String>,
+}
+
+/// Checks that all the DUTs are of the same board (and of the one given with
+/// --board, if any), since the single image given with --image is written to
+/// all of them
+fn check_boards_of_image(args: &Args, targets: &[String]) -> Result<()> {
+    let mut boards: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for dut in targets {
+        let board = board_of_dut(dut).context(anyhow!("Failed to get the board of {dut}"))?;
+        boards.entry(board).or_default().push(dut);
+    }
+    let found: Vec<&String> = boards.keys().collect();
+    match (&args.board, found.as_slice()) {
+        (None, [_]) => Ok(()),
+        (Some(board), [b]) if b == &board => Ok(()),
+        _ => bail!(
+            "The image is written to all the DUTs, but they are of {}{}",
+            boards
+                .iter()
+                .map(|(board, duts)| format!("{board} ({})", duts.join(", ")))
+                .collect::<Vec<_>>()
+                .join(", "),
+            args.board
+                .as_ref()
+                .map(|b| format!(", not {b}"))
+                .unwrap_or_default()
+        ),
+    }
+}
+
+/// Returns the image to flash with --direct for `board`, downloading it if
+/// needed
+fn prepare_direct_image(args: &Args, board: &str) -> Result<DirectImage> {
+    Ok(if let Some(repo) = &args.local_build {
+        let path = latest_local_image(repo, board)?;
+        DirectImage {
+            image: TestImage::DiskImage(path.clone()),
+            description: format!("{path:?}"),
+            version: None,
+        }
+    } else if let Some(path) = &args.image {
+        DirectImage {
+            image: TestImage::DiskImage(PathBuf::from(path)),
+            description: path.clone(),
+            version: None,
+        }
+    } else if let Some(from) = &args.from {
+        let (path, description) = download_shared_build(from, board)?;
+        DirectImage {
+            image: TestImage::DiskImage(path),
+            description,
+            version: None,
+        }
+    } else {
+        let version = resolve_prebuilt_version(&args.channel, &args.version, board)?;
+        DirectImage {
+            image: TestImage::Artifacts(download_test_image(board, &version)?),
+            description: format!("{board} {version}"),
+            version: Some(version),
+        }
+    })
+}
+
+/// Downloads the image of the build shared at `url`, checking that it is for
+/// `board`. Returns the path to the image and its description.
+fn download_shared_build(url: &str, board: &str) -> Result<(PathBuf, String)> {
+    let build = shared_build_metadata(url)?;
+    if build.board != board {
+        bail!(
+            "The build shared at {url} is for {}, not for {board}",
+            build.board
+        );
+    }
+    if build.image_type.as_deref().is_some_and(|t| t != "test") {
+        warn!(
+            "The build shared at {url} is a {} image. Only test images work with cro3.",
+            build.image_type.as_deref().unwrap_or_default()
+        );
+    }
+    for (package, overrides) in &build.package_overrides {
+        info!("The image includes {package} built with {overrides}");
+    }
+    let path = download_shared_image(url, &build)?;
+    let description = format!("{url} ({} built by {})", build.version, build.builder);
+    Ok((path, description))
+}
+
+fn run_direct(args: &Args, dut: &str) -> Result<()> {
+    ensure_testing_rsa_is_there()?;
+    let board = determine_board_to_flash(&Some(dut.to_string()), &args.board)?;
+    let download = args.local_build.is_none() && args.image.is_none();
+    let mut progress = FlashProgress::new(dut, &flash_phases(args, download))?;
+    if download {
+        progress.start(Phase::Download, &board);
+    }
+    let image = prepare_direct_image(args, &board)?;
+    flash_directly(args, dut, &image, progress)
+}
+
+/// Phases of flashing a DUT directly, shown in the progress bar
+fn flash_phases(args: &Args, download: bool) -> Vec<Phase> {
+    let mut phases = vec![];
+    if download {
+        phases.push(Phase::Download);
+    }
+    phases.extend([
+        Phase::WriteKernel,
+        Phase::WriteRootfs,
+        Phase::Stateful,
+        Phase::Reboot,
+    ]);
+    if !args.skip_post_check {
+        phases.push(Phase::Verify);
+    }
+    phases
+}
+
+fn flash_directly(
+    args: &Args,
+    dut: &str,
+    image: &DirectImage,
+    mut progress: FlashProgress,
+) -> Result<()> {
+    let dut_info = DutInfo::new(dut)?;
+    ensure_dut_is_not_leased(dut_info.ssh(), args.steal)?;
+    let description = &image.description;
+    info!("Flashing {description} to {dut}...");
+    provision_test_image(
+        dut_info.ssh(),
+        &image.image,
+        &ProvisionOptions {
+            clobber_stateful: args.clobber_stateful,
+            delta: args.delta,
+            disable_rootfs_verification: !args.enable_rootfs_verification,
+            clear_tpm_owner: args.clobber_stateful,
+        },
+        &mut progress,
+    )?;
+    invalidate_dut_image_info(dut)?;
+    restore_lease(dut)?;
+    if !args.skip_post_check {
+        progress.start(Phase::Verify, "waiting for the login screen");
+    }
+    post_flash_check(
+        args,
+        dut_info.ssh(),
+        image.version.clone(),
+        args.enable_rootfs_verification,
+    )?;
+    progress.finish()?;
+    info!("Flashed {description} to {dut}");
+    Ok(())
+}
+
+fn run_via_servo(args: &Args, repo: &str, dut: &str) -> Result<()> {
+    if args.usb || args.use_local_image {
+        bail!("--via-servo only supports flashing prebuilt or given images");
+    }
+    if args.keep_stateful {
+        bail!("The stateful partition is always wiped when reimaging via servo");
+    }
+    match args.image_type() {
+        ImageType::Test | ImageType::Recovery => {}
+        ImageType::Signed => {
+            bail!("Please use --image-type recovery to install a signed image via servo")
+        }
+        image_type => bail!("{image_type:?} images can not be installed via servo"),
+    }
+    ensure_testing_rsa_is_there()?;
+    let id = registered_dut_id(dut).unwrap_or(dut.to_string());
+    let servo = find_servo_for_dut(&id, &args.servo)?;
+    let ssh = SshInfo::new(dut)?;
+    // The lease can not be read from the DUT if it is bricked, so the cached
+    // one is checked instead
+    if let (Some(lease), false) = (DUT_LEASE_CACHE.get(&id)?, args.steal) {
+        if !lease.is_mine() && !lease.is_expired() {
+            bail!(
+                "The DUT is {}. Use --steal if you really need to use it.",
+                lease.description()
+            );
+        }
+    }
+    // The DUT is likely to be unreachable, so the board is not asked to it
+    let board = infer_board(args.board.as_deref(), Some(dut))?;
+    let (image, version) = if let Some(image) = &args.image {
+        (PathBuf::from(image), None)
+    } else if args.recovery() {
+        let version = resolve_prebuilt_version(&args.channel, &args.version, &board)?;
+        (download_recovery_image(&board, &version)?, Some(version))
+    } else if let Some(repo) = &args.local_build {
+        (latest_local_image(repo, &board)?, None)
+    } else if let Some(from) = &args.from {
+        (download_shared_build(from, &board)?.0, None)
+    } else {
+        let version = resolve_prebuilt_version(&args.channel, &args.version, &board)?;
+        (download_test_disk_image(&board, &version)?, Some(version))
+    };
+
+    let chroot = Chroot::new(repo)?;
+    let servod = match ServodConnection::from_serial(servo.serial()) {
+        Ok(servod) => servod,
+        Err(_) => servo.start_servod(&chroot)?,
+    };
+    if let Some(firmware) = &args.firmware {
+        let firmware = chroot.share_file(Path::new(firmware))?;
+        servod.flash_ap_firmware(&chroot, &firmware)?;
+    }
+    if args.recovery() {
+        info!(
+            "Recovering {id} with {image:?} via servo {}...",
+            servo.serial()
+        );
+        recover_via_servo(&chroot, &servod, &image)?;
+        invalidate_dut_image_info(dut)?;
+        // Recovery images have no SSH access, so the DUT can not be checked
+        info!("Recovered {id} via servo. The DUT is not reachable over SSH with the image.");
+        return Ok(());
+    }
+    info!(
+        "Reimaging {id} with {image:?} via servo {}...",
+        servo.serial()
+    );
+    provision_via_servo(&chroot, &servod, &image, &ssh)?;
+    invalidate_dut_image_info(dut)?;
+    restore_lease(dut)?;
+    // chromeos-install keeps rootfs verification of the image enabled
+    post_flash_check(args, &ssh, version, true)?;
+    info!("Reimaged {id} via servo");
+    Ok(())
+}
+
+/// Returns an error unless the user agrees to wipe the stateful partitions of
+/// `duts` (or --yes is given)
+fn confirm_clobber_stateful(args: &Args, duts: &[String]) -> Result<()> {
+    if !args.clobber_stateful || args.yes {
+        return Ok(());
+    }
+    if !confirm(
+        &format!(
+            "The stateful partition of {} will be wiped, including the test accounts and the \
+             deployed packages. Continue?",
+            duts.join(", ")
+        ),
+        false,
+    )? {
+        bail!(
+            "Aborted. Use --keep-stateful to keep the stateful partition, or --yes to skip the \
+             confirmation."
+        );
+    }
+    Ok(())
+}
+
+/// Flashes multiple DUTs in parallel. The images are written directly (as
+/// with --direct) so that each image is downloaded only once per board.
+fn run_many(args: &Args, targets: &[String]) -> Result<()> {
+    if args.usb || !args.test_image() || args.use_local_image {
+        bail!("Only test images can be flashed to multiple DUTs");
+    }
+    if targets.is_empty() {
+        bail!("No DUTs to flash");
+    }
+    ensure_testing_rsa_is_there()?;
+    if args.image.is_some() {
+        check_boards_of_image(args, targets)?;
+    }
+    confirm_clobber_stateful(args, targets)?;
+    let mut boards: BTreeMap<&String, String> = BTreeMap::new();
+    for dut in targets {
+        boards.insert(
+            dut,
+            determine_board_to_flash(&Some(dut.clone()), &args.board)?,
+        );
+    }
+    let mut images: BTreeMap<String, DirectImage> = BTreeMap::new();
+    for board in boards.values() {
+        if !images.contains_key(board) {
+            images.insert(board.clone(), prepare_direct_image(args, board)?);
+        }
+    }
+    info!("Flashing {} DUTs, {} at a time", targets.len(), args.jobs);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.jobs.max(1))
+        .build()
+        .context("Failed to create a thread pool")?;
+    let results: Vec<(&String, Result<()>, Duration)> = pool.install(|| {
+        targets
+            .par_iter()
+            .map(|dut| {
+                let start = Instant::now();
+                let result =
+                    FlashProgress::new(dut, &flash_phases(args, false)).and_then(|progress| {
+                        flash_directly(args, dut, &images[&boards[dut]], progress)
+                    });
+                if let Err(e) = &result {
+                    error!("{dut}: {e:#}");
+                }
+                (dut, result, start.elapsed())
+            })
+            .collect()
+    });
+    let width = targets.iter().map(|id| id.len()).max().unwrap_or_default();
+    println!("{:width$} {:12} {:>6} RESULT", "DUT", "BOARD", "MIN");
+    let mut failed = 0;
+    for (dut, result, elapsed) in &results {
+        let status = match result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => {
+                failed += 1;
+                format!("failed: {e:#}")
+            }
+        };
+        println!(
+            "{dut:width$} {:12} {:>6.1} {status}",
+            boards[dut],
+            elapsed.as_secs_f64() / 60.0
+        );
+    }
+    if failed > 0 {
+        bail!("Failed to flash {failed} of {} DUTs", results.len());
+    }
+    Ok(())
+}
+
+/// Flashes a signed image to a DUT over SSH, after explaining what changes
+/// with it
+fn run_signed(args: &Args, dut: &str) -> Result<()> {
+    if args.usb || args.use_local_image || args.keep_stateful || args.delta {
+        bail!(
+            "Signed images are flashed to a DUT over SSH, always wiping its stateful partition. \
+             Use --image-type recovery to write one to a USB stick."
+        );
+    }
+    ensure_testing_rsa_is_there()?;
+    let dut_info = DutInfo::new(dut)?;
+    let ssh = dut_info.ssh();
+    ensure_dut_is_not_leased(ssh, args.steal)?;
+    let board = determine_board_to_flash(&Some(dut.to_string()), &args.board)?;
+    let (image, description) = if let Some(image) = &args.image {
+        (PathBuf::from(image), image.clone())
+    } else {
+        let version = resolve_prebuilt_version(&args.channel, &args.version, &board)?;
+        (
+            download_signed_image(&board, &version)?,
+            format!("{board} {version} (signed)"),
+        )
+    };
+    let developer_mode = ssh.run_cmd_stdio("crossystem mainfw_type")? == "developer";
+    warn!("After flashing the signed image:");
+    warn!("- The DUT is not reachable over SSH, so cro3 commands for it do not work");
+    warn!("- The stateful partition is wiped, and the DUT starts from the OOBE screen");
+    if args.normal_mode {
+        warn!(
+            "- The DUT is switched to the normal mode. If its firmware does not have the keys \
+             that the image is signed with, it boots into recovery mode."
+        );
+    } else if developer_mode {
+        warn!(
+            "- The DUT stays in developer mode and shows the developer mode screen on boot. Use \
+             --normal-mode to boot it as the devices of consumers."
+        );
+    }
+    warn!(
+        "- To get a test image back, reimage the DUT via servo (cro3 flash --via-servo) or boot \
+         it from a USB stick (cro3 flash usb) in recovery mode"
+    );
+    if !args.yes && !confirm(&format!("Flash {description} to {dut}?"), false)? {
+        bail!("Aborted");
+    }
+    provision_signed_image(ssh, &image, args.normal_mode)?;
+    invalidate_dut_image_info(dut)?;
+    info!("Flashed {description} to {dut}. Please check the result on the screen of the DUT.");
+    Ok(())
+}
+
+/// The lease marker on the DUT is wiped by --clobber-stateful. Put it back if
+/// the lease is held by this user.
+fn restore_lease(dut: &str) -> Result<()> {
+    let id = if let Ok(id) = registered_dut_id(dut) {
+        id
+    } else {
+        return Ok(());
+    };
+    if let Some(lease) = DUT_LEASE_CACHE.get(&id)? {
+        if lease.is_mine() && !lease.is_expired() {
+            let ssh = SshInfo::new(&id)?;
+            // The DUT has been rebooted by the flash
+            ssh.close_connection()?;
+            ssh.set_lease(&lease)?;
+            info!("Restored the lease on {id}");
+        }
+    }
     Ok(())
 }
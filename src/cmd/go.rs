@@ -0,0 +1,292 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! ## Build, flash and test in one go
+//! `cro3 go` builds the packages and a test image, flashes it to the DUT over
+//! SSH, waits for the login screen and runs Tast tests, and then shows how
+//! each step went. If a step fails, `--resume` continues from it with the
+//! same arguments.
+//! ```
+//! cro3 go --cros ${CROS} --dut ${DUT} --package ${PACKAGE_NAME} --tast 'example.*'
+//! cro3 go --cros ${CROS} --dut ${DUT} --package ${PACKAGE_NAME} --tast 'example.*' --resume
+//! # Only flash the image built last and run the tests
+//! cro3 go --cros ${CROS} --dut ${DUT} --no-build --tast 'example.*'
+//! ```
+
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use argh::FromArgs;
+use cro3::build::build_image;
+use cro3::build::build_packages;
+use cro3::build::BuildImageType;
+use cro3::build::BuildOptions;
+use cro3::cache::KvCache;
+use cro3::chroot::Chroot;
+use cro3::cros::ensure_testing_rsa_is_there;
+use cro3::dut::ensure_dut_is_not_leased;
+use cro3::dut::infer_board;
+use cro3::dut::invalidate_dut_image_info;
+use cro3::dut::resolve_dut;
+use cro3::dut::SshInfo;
+use cro3::progress::format_duration;
+use cro3::progress::FlashProgress;
+use cro3::progress::Phase;
+use cro3::provision::latest_local_image;
+use cro3::provision::provision_test_image;
+use cro3::provision::ProvisionOptions;
+use cro3::provision::TestImage;
+use cro3::repo::get_cros_dir;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+
+use crate::cmd::tast::run_tast_tests;
+
+/// The steps completed in the last run on each DUT, for --resume
+static GO_STATE: KvCache<GoState> = KvCache::new("go_state");
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct GoState {
+    /// The arguments of the run, which must match to resume it
+    key: String,
+    completed: Vec<String>,
+    /// The image built in the run
+    image: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Step {
+    Packages,
+    Image,
+    Flash,
+    Boot,
+    Tast,
+}
+impl Step {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Step::Packages => "build packages",
+            Step::Image => "build image",
+            Step::Flash => "flash",
+            Step::Boot => "wait for boot",
+            Step::Tast => "tast",
+        }
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// build packages and a test image, flash it to a DUT and run Tast tests
+#[argh(subcommand, name = "go")]
+pub struct Args {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target board (default: the board of the DUT)
+    #[argh(option)]
+    board: Option<String>,
+
+    /// target DUT (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// packages to build before the image. Can be repeated.
+    #[argh(option)]
+    package: Vec<String>,
+
+    /// do not build anything, and flash the test image built last
+    #[argh(switch)]
+    no_build: bool,
+
+    /// tast tests to run after flashing (test name or pattern)
+    #[argh(option)]
+    tast: Option<String>,
+
+    /// options for tast run (e.g. "-var ...")
+    #[argh(option)]
+    tast_option: Option<String>,
+
+    /// skip the steps completed in the last run on the DUT with the same
+    /// arguments
+    #[argh(switch)]
+    resume: bool,
+}
+
+impl Args {
+    fn steps(&self) -> Vec<Step> {
+        let mut steps = Vec::new();
+        if !self.no_build {
+            if !self.package.is_empty() {
+                steps.push(Step::Packages);
+            }
+            steps.push(Step::Image);
+        }
+        steps.extend([Step::Flash, Step::Boot]);
+        if self.tast.is_some() {
+            steps.push(Step::Tast);
+        }
+        steps
+    }
+    /// Identifies the arguments, to check that a run can be resumed
+    fn key(&self, board: &str) -> String {
+        format!(
+            "{board} packages={} no_build={} tast={} tast_option={}",
+            self.package.join(","),
+            self.no_build,
+            self.tast.as_deref().unwrap_or_default(),
+            self.tast_option.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+#[tracing::instrument(level = "trace")]
+pub fn run(args: &Args) -> Result<()> {
+    ensure_testing_rsa_is_there()?;
+    let dut = resolve_dut(&args.dut, args.board.as_deref())?;
+    let board = infer_board(args.board.as_deref(), Some(&dut))?;
+    let repo = get_cros_dir(&args.cros)?;
+    let chroot = Chroot::new(&repo)?;
+    let key = args.key(&board);
+    let mut state = match (args.resume, GO_STATE.get(&dut)?) {
+        (false, _) => GoState {
+            key,
+            completed: Vec::new(),
+            image: None,
+        },
+        (true, Some(state)) if state.key == key => state,
+        (true, Some(_)) => bail!("The last run on {dut} had different arguments to resume"),
+        (true, None) => bail!("No run on {dut} to resume"),
+    };
+
+    let mut report: Vec<(Step, String, Option<Duration>)> = Vec::new();
+    let mut failure = None;
+    for step in args.steps() {
+        if state.completed.iter().any(|s| s == step.as_str()) {
+            report.push((step, "done before".to_string(), None));
+            continue;
+        }
+        info!("{}...", step.as_str());
+        let start = Instant::now();
+        match run_step(step, args, &chroot, &repo, &board, &dut, &mut state) {
+            Ok(result) => {
+                report.push((step, result, Some(start.elapsed())));
+                state.completed.push(step.as_str().to_string());
+                GO_STATE.set(&dut, state.clone())?;
+            }
+            Err(e) => {
+                report.push((step, "failed".to_string(), Some(start.elapsed())));
+                failure = Some((step, e));
+                break;
+            }
+        }
+    }
+
+    println!("{:<16} {:>8}  RESULT", "STEP", "TIME");
+    for (step, result, duration) in &report {
+        println!(
+            "{:<16} {:>8}  {result}",
+            step.as_str(),
+            duration
+                .map(|d| format_duration(d.as_secs_f64()))
+                .unwrap_or("-".to_string())
+        );
+    }
+    if let Some((step, e)) = failure {
+        return Err(e.context(anyhow!(
+            "Failed to {}. Run the same command with --resume to continue from it.",
+            step.as_str()
+        )));
+    }
+    GO_STATE.remove(&dut)?;
+    Ok(())
+}
+
+/// Runs `step` and returns its result to show in the report
+fn run_step(
+    step: Step,
+    args: &Args,
+    chroot: &Chroot,
+    repo: &str,
+    board: &str,
+    dut: &str,
+    state: &mut GoState,
+) -> Result<String> {
+    match step {
+        Step::Packages => {
+            build_packages(
+                chroot,
+                board,
+                &args.package,
+                &BuildOptions {
+                    workon: true,
+                    remoteexec: None,
+                    profile: false,
+                    overrides: Default::default(),
+                },
+            )?;
+            Ok(format!("built {}", args.package.join(" ")))
+        }
+        Step::Image => {
+            let image = build_image(chroot, repo, board, BuildImageType::Test, false)?;
+            state.image = Some(image.clone());
+            Ok(format!("built {image:?}"))
+        }
+        Step::Flash => {
+            let image = match &state.image {
+                Some(image) => image.clone(),
+                None => latest_local_image(repo, board)?,
+            };
+            let ssh = SshInfo::new(dut)?;
+            ensure_dut_is_not_leased(&ssh, false)?;
+            let mut progress = FlashProgress::new(
+                dut,
+                &[
+                    Phase::WriteKernel,
+                    Phase::WriteRootfs,
+                    Phase::Stateful,
+                    Phase::Reboot,
+                ],
+            )?;
+            provision_test_image(
+                &ssh,
+                &TestImage::DiskImage(image.clone()),
+                &ProvisionOptions {
+                    clobber_stateful: false,
+                    delta: false,
+                    disable_rootfs_verification: true,
+                    // Keep the encrypted stateful readable, as it is kept
+                    clear_tpm_owner: false,
+                },
+                &mut progress,
+            )?;
+            progress.finish()?;
+            invalidate_dut_image_info(dut)?;
+            Ok(format!("flashed {image:?}"))
+        }
+        Step::Boot => {
+            let ssh = SshInfo::new(dut)?;
+            ssh.wait_for_boot(Duration::from_secs(300), true)
+                .context("The DUT did not show the login screen")?;
+            Ok(ssh.run_cmd_stdio("uname -r")?)
+        }
+        Step::Tast => {
+            let tests = args.tast.as_deref().context("No tests to run")?;
+            let ssh = SshInfo::new(dut)?.into_forwarded()?;
+            let run = run_tast_tests(chroot, dut, &ssh, tests, args.tast_option.as_deref(), 0)?;
+            let failed = run.failed();
+            if !failed.is_empty() {
+                bail!("Failed tests: {}", failed.join(" "));
+            }
+            Ok(run.summary())
+        }
+    }
+}
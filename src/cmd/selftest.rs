@@ -0,0 +1,310 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! ## Verify cro3 itself
+//! ```
+//! # Check config, cache, SSH, flash (dry-run) and test result layers without
+//! # touching your real settings
+//! cro3 selftest
+//!
+//! # Also exercise the SSH layer against a DUT that can be used for tests
+//! cro3 selftest --dut ${DUT} --scratch /tmp/cro3_selftest
+//! ```
+
+use std::env;
+use std::env::current_exe;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use argh::FromArgs;
+use cro3::cache::KvCache;
+use cro3::config::Config;
+use cro3::cros::ensure_testing_rsa_is_there;
+use cro3::dut::SshInfo;
+use cro3::test_results::TestResult as TestCaseResult;
+use cro3::test_results::TestRun;
+use cro3::test_results::TestStatus;
+use cro3::util::shell_helpers::get_stderr;
+use cro3::util::shell_helpers::get_stdout;
+use tempdir::TempDir;
+use tracing::error;
+use tracing::info;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+static SELFTEST_CACHE: KvCache<String> = KvCache::new("selftest_cache");
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// run self-tests to verify that cro3 works on this machine
+#[argh(subcommand, name = "selftest")]
+pub struct Args {
+    /// directory to use for the tests. Config and caches are created under it
+    /// instead of ~/.cro3 (default: a temporary directory)
+    #[argh(option)]
+    scratch: Option<String>,
+
+    /// DUT to run the SSH layer tests against. It should be a DUT that is ok
+    /// to break (files are created under /usr/local/tmp). Skipped if omitted.
+    #[argh(option)]
+    dut: Option<String>,
+}
+
+struct TestResult {
+    name: &'static str,
+    result: Option<Result<()>>,
+    elapsed_ms: u128,
+}
+
+fn run_test(name: &'static str, f: impl FnOnce() -> Result<()>) -> TestResult {
+    info!("selftest: {name}...");
+    let start = Instant::now();
+    let result = f();
+    if let Err(e) = &result {
+        error!("selftest: {name} failed: {e:#}");
+    }
+    TestResult {
+        name,
+        result: Some(result),
+        elapsed_ms: start.elapsed().as_millis(),
+    }
+}
+
+fn skip_test(name: &'static str) -> TestResult {
+    TestResult {
+        name,
+        result: None,
+        elapsed_ms: 0,
+    }
+}
+
+#[tracing::instrument(level = "trace")]
+pub fn run(args: &Args) -> Result<()> {
+    let tmpdir;
+    let scratch = if let Some(scratch) = &args.scratch {
+        let scratch = PathBuf::from(scratch);
+        fs::create_dir_all(&scratch)?;
+        scratch
+    } else {
+        tmpdir = TempDir::new("cro3_selftest")?;
+        tmpdir.path().to_path_buf()
+    };
+    let scratch = scratch.canonicalize()?;
+    info!("cro3 v{VERSION}: running self-tests in {scratch:?}");
+
+    let mut results = vec![run_test("ssh_args", test_ssh_args)];
+    // SSH tests are done before switching the home dir since they use the
+    // DUT list and the keys of the user.
+    if let Some(dut) = &args.dut {
+        results.push(run_test("ssh_exec", || test_ssh_exec(dut)));
+        results.push(run_test("ssh_file_transfer", || {
+            test_ssh_file_transfer(dut, &scratch)
+        }));
+        results.push(run_test("ssh_forwarding", || test_ssh_forwarding(dut)));
+    } else {
+        results.push(skip_test("ssh_exec"));
+        results.push(skip_test("ssh_file_transfer"));
+        results.push(skip_test("ssh_forwarding"));
+    }
+
+    // ~/.cro3 is resolved from $HOME. Point it to the scratch dir so that the
+    // tests below do not touch the real config and caches.
+    let home = scratch.join("home");
+    fs::create_dir_all(&home)?;
+    env::set_var("HOME", &home);
+    results.push(run_test("config", || test_config(&scratch)));
+    results.push(run_test("cache", test_cache));
+    results.push(run_test("flash_dry_run", || test_flash_dry_run(&scratch)));
+    results.push(run_test("result_storage", test_result_storage));
+
+    println!("{:<24} {:<6} {:>8}", "TEST", "RESULT", "TIME");
+    let mut failed = 0;
+    for r in &results {
+        let status = match &r.result {
+            None => "SKIP",
+            Some(Ok(_)) => "PASS",
+            Some(Err(_)) => {
+                failed += 1;
+                "FAIL"
+            }
+        };
+        println!("{:<24} {:<6} {:>6}ms", r.name, status, r.elapsed_ms);
+        if let Some(Err(e)) = &r.result {
+            println!("    {e:#}");
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {} self-tests failed", results.len());
+    }
+    println!("cro3 v{VERSION} looks healthy");
+    Ok(())
+}
+
+fn test_ssh_args() -> Result<()> {
+    let ssh = SshInfo::new("localhost:2222")?;
+    if ssh.host_and_port() != "localhost:2222" {
+        bail!("Unexpected host_and_port: {}", ssh.host_and_port());
+    }
+    let ssh = SshInfo::new_host_and_port("[fe80::1]", 22)?;
+    if ssh.host_and_port() != "[fe80::1]:22" {
+        bail!("Unexpected host_and_port: {}", ssh.host_and_port());
+    }
+    let cmd = format!("{:?}", ssh.ssh_cmd_without_retry(None)?);
+    if !cmd.contains("testing_rsa") || !cmd.contains("root@fe80::1") {
+        bail!("Unexpected ssh command: {cmd}");
+    }
+    Ok(())
+}
+
+fn test_ssh_exec(dut: &str) -> Result<()> {
+    ensure_testing_rsa_is_there()?;
+    let ssh = SshInfo::new(dut)?;
+    let result = ssh.run_cmd_stdio("echo cro3_selftest")?;
+    if result != "cro3_selftest" {
+        bail!("Unexpected output from the DUT: {result:?}");
+    }
+    Ok(())
+}
+
+fn test_ssh_file_transfer(dut: &str, scratch: &Path) -> Result<()> {
+    const REMOTE_DIR: &str = "/usr/local/tmp/cro3_selftest";
+    let ssh = SshInfo::new(dut)?;
+    let src = scratch.join("cro3_selftest_push.txt");
+    let content = format!("cro3 selftest {}", chrono::Local::now());
+    fs::write(&src, &content)?;
+    ssh.run_cmd_stdio(&format!("mkdir -p {REMOTE_DIR}"))?;
+    ssh.send_files(
+        &[src.to_string_lossy().to_string()],
+        Some(&REMOTE_DIR.to_string()),
+    )?;
+    let pulled = scratch.join("pulled");
+    fs::create_dir_all(&pulled)?;
+    ssh.get_files(
+        &[format!("{REMOTE_DIR}/cro3_selftest_push.txt")],
+        Some(&pulled.to_string_lossy().to_string()),
+    )?;
+    ssh.run_cmd_stdio(&format!("rm -rf {REMOTE_DIR}"))?;
+    let pulled = fs::read_to_string(pulled.join("cro3_selftest_push.txt"))?;
+    if pulled != content {
+        bail!("The file pulled from the DUT differs from the pushed one");
+    }
+    Ok(())
+}
+
+fn test_ssh_forwarding(dut: &str) -> Result<()> {
+    let ssh = SshInfo::new(dut)?;
+    let forwarded = ssh.into_forwarded()?;
+    let result = forwarded.run_cmd_stdio("echo cro3_selftest")?;
+    if result != "cro3_selftest" {
+        bail!(
+            "Unexpected output via {}: {result:?}",
+            forwarded.host_and_port()
+        );
+    }
+    Ok(())
+}
+
+fn test_config(scratch: &Path) -> Result<()> {
+    let checkout = scratch.join("chromiumos").to_string_lossy().to_string();
+    let mut config = Config::read()?;
+    config.set("default_cros_checkout", &[&checkout])?;
+    let value = Config::read()?.default_cros_checkout();
+    if value.as_ref() != Some(&checkout) {
+        bail!("Config value was not written correctly: {value:?}");
+    }
+    config.clear("default_cros_checkout")?;
+    let value = Config::read()?.default_cros_checkout();
+    if value.is_some() {
+        bail!("Config value was not cleared: {value:?}");
+    }
+    if config.set("no_such_key", &["value"]).is_ok() {
+        bail!("Config accepted an invalid key");
+    }
+    Ok(())
+}
+
+fn test_cache() -> Result<()> {
+    SELFTEST_CACHE.clear()?;
+    SELFTEST_CACHE.set("key1", "value1".to_string())?;
+    SELFTEST_CACHE.set("key2", "value2".to_string())?;
+    if SELFTEST_CACHE.get("key1")?.as_deref() != Some("value1") {
+        bail!("Failed to read a value from the cache");
+    }
+    if SELFTEST_CACHE.entries()?.len() != 2 {
+        bail!("Unexpected number of cache entries");
+    }
+    SELFTEST_CACHE.remove("key1")?;
+    if SELFTEST_CACHE.get("key1")?.is_some() {
+        bail!("Failed to remove a value from the cache");
+    }
+    let value = SELFTEST_CACHE.get_or_else("key3", &|_| Ok("value3".to_string()))?;
+    if value != "value3" || SELFTEST_CACHE.get("key3")?.as_deref() != Some("value3") {
+        bail!("get_or_else did not store the value");
+    }
+    SELFTEST_CACHE.clear()?;
+    if !SELFTEST_CACHE.entries()?.is_empty() {
+        bail!("Failed to clear the cache");
+    }
+    // The cache should be persisted as a file under ~/.cro3
+    let home = env::var("HOME")?;
+    let path = Path::new(&home).join(".cro3/selftest_cache");
+    fs::metadata(&path).context(anyhow!("Cache file {path:?} was not created"))?;
+    Ok(())
+}
+
+fn test_flash_dry_run(scratch: &Path) -> Result<()> {
+    let image = scratch.join("chromiumos_test_image.bin");
+    fs::write(&image, "cro3 selftest")?;
+    let output = Command::new(current_exe()?)
+        .args(["flash", "--dry-run", "--direct", "--steal"])
+        .args(["--dut", "192.0.2.1", "--board", "selftest"])
+        .arg("--image")
+        .arg(&image)
+        .output()?;
+    if !output.status.success() {
+        bail!("cro3 flash --dry-run failed: {}", get_stderr(&output));
+    }
+    let plan = get_stdout(&output);
+    for expected in [
+        "Method: Direct",
+        "DUT: 192.0.2.1 (board: selftest)",
+        "chromiumos_test_image.bin",
+    ] {
+        if !plan.contains(expected) {
+            bail!("The flash plan does not contain {expected:?}: {plan}");
+        }
+    }
+    Ok(())
+}
+
+fn test_result_storage() -> Result<()> {
+    let mut run = TestRun::start("selftest", "192.0.2.1", "selftest.*")?;
+    run.results.push(TestCaseResult {
+        name: "selftest.Pass".to_string(),
+        status: TestStatus::Passed,
+        duration: 1.0,
+        error: None,
+        log: None,
+        attempts: 1,
+    });
+    run.save()?;
+    let loaded = TestRun::load(&run.id)?;
+    if loaded != run {
+        bail!("The loaded run differs from the saved one");
+    }
+    if TestRun::load_or_latest(None)?.id != run.id {
+        bail!("The saved run is not the latest one");
+    }
+    let junit = TestRun::dir(&run.id)?.join("junit.xml");
+    fs::metadata(&junit).context(anyhow!("{junit:?} was not written"))?;
+    Ok(())
+}
@@ -7,7 +7,8 @@
 //! ## Controlling a Servo (Hardware debugging tool)
 //! Note: the official document is [here](https://chromium.googlesource.com/chromiumos/third_party/hdctools/+/HEAD/docs/servo.md)
 //! ```
-//! # Show list of Servo / Cr50 devices
+//! # Show list of Servo / Cr50 / C2D2 devices, with their firmware versions
+//! # and the DUTs they are attached to (the DUTs tagged with servo=SERIAL)
 //! cro3 servo list
 //!
 //! # Do the same thing in JSON format
@@ -15,26 +16,87 @@
 //!
 //! # Reset Servo USB ports (useful when cro3 servo list does not work)
 //! sudo `which cro3` servo reset
+//!
+//! # Start servod in the background for all the servos (or one with
+//! # --serial) on free ports. Crashed instances are restarted.
+//! cro3 servo start --cros ${CROS}
+//!
+//! # Show the servod instances, and stop the orphaned ones
+//! cro3 servo status --cleanup
+//!
+//! # Stop servod instances
+//! cro3 servo stop --serial SERVOV4P1-S-2103070192
+//!
+//! # Attach to the EC console of a DUT through servod (started if needed).
+//! # The traffic is logged with timestamps in ~/.cro3/logs.
+//! cro3 servo console --cros ${CROS} ${DUT} --type ec
+//!
+//! # Send commands to the console and exit
+//! cro3 servo console ${DUT} --type ec --send powerinfo --send 'battery'
+//!
+//! # Sample the power rails (INAs on the board or a Sweetberry) of a DUT every
+//! # second for 5 minutes while running a workload on it, and show the mean,
+//! # p95 and energy of each rail. The samples are written as CSV and JSON in
+//! # ~/.cro3/power (or --output).
+//! cro3 servo power --cros ${CROS} ${DUT} --workload 'stress-ng --cpu 4 -t 300'
+//!
+//! # Sample specific rails only
+//! cro3 servo power ${DUT} --rail ppvar_sys_mw --rail ppdut5_mw
 //! ```
 
+use std::collections::BTreeSet;
+use std::fs;
 use std::fs::read_to_string;
+use std::io::Write;
+use std::path::PathBuf;
 use std::process;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
 
+use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use argh::FromArgs;
+use chrono::Local;
 use cro3::chroot::Chroot;
+use cro3::dut::registered_dut_id;
+use cro3::dut::resolve_dut;
+use cro3::dut::SshInfo;
 use cro3::repo::get_cros_dir;
+use cro3::servo::console_log_path;
+use cro3::servo::find_servo_for_dut;
+use cro3::servo::forward_keys_to_console;
 use cro3::servo::get_servo_attached_to_cr50;
+use cro3::servo::list_servod_processes;
+use cro3::servo::open_raw_tty;
 use cro3::servo::reset_devices;
+use cro3::servo::servod_states;
+use cro3::servo::spawn_console_reader;
+use cro3::servo::stop_servod;
+use cro3::servo::uart_pty_controls;
+use cro3::servo::ConsoleLog;
 use cro3::servo::LocalServo;
 use cro3::servo::ServoList;
 use cro3::servo::ServodConnection;
+use cro3::servo::ServodState;
+use cro3::servo::CONSOLE_HELP;
+use cro3::servo::SERVOD_INSTANCES;
+use cro3::servo_power::format_power_table;
+use cro3::servo_power::parse_interval;
+use cro3::servo_power::parse_power_log;
+use cro3::servo_power::power_rails;
+use cro3::servo_power::power_stats;
+use cro3::servo_power::sampling_script;
+use cro3::servo_power::to_csv;
+use cro3::servo_power::PowerReport;
 use cro3::util::cro3_paths::cro3_dir;
 use cro3::util::cro3_paths::gen_path_in_cro3_dir;
 use cro3::util::shell_helpers::run_bash_command;
 use tracing::info;
+use tracing::warn;
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// control Servo
@@ -46,24 +108,34 @@ pub struct Args {
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 enum SubCommand {
+    Console(ArgsConsole),
     Control(ArgsControl),
     Get(ArgsGet),
     List(ArgsList),
     Kill(ArgsKill),
+    Power(ArgsPower),
     Reset(ArgsReset),
     Shell(ArgsShell),
     Show(ArgsShow),
+    Start(ArgsStart),
+    Status(ArgsStatus),
+    Stop(ArgsStop),
 }
 #[tracing::instrument(level = "trace")]
 pub fn run(args: &Args) -> Result<()> {
     match &args.nested {
+        SubCommand::Console(args) => run_console(args),
         SubCommand::Control(args) => run_control(args),
         SubCommand::Get(args) => run_get(args),
         SubCommand::List(args) => run_list(args),
         SubCommand::Kill(args) => run_kill(args),
+        SubCommand::Power(args) => run_power(args),
         SubCommand::Reset(args) => run_reset(args),
         SubCommand::Shell(args) => run_shell(args),
         SubCommand::Show(args) => run_show(args),
+        SubCommand::Start(args) => run_start(args),
+        SubCommand::Status(args) => run_status(args),
+        SubCommand::Stop(args) => run_stop(args),
     }
 }
 
@@ -130,7 +202,8 @@ pub fn run_reset(args: &ArgsReset) -> Result<()> {
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
-/// list servo-compatible devices (Servo V4, Servo V4p1, SuzyQable)
+/// list servo-compatible devices (Servo V4, Servo V4p1, SuzyQable, C2D2)
+/// with their firmware versions and the DUTs they are attached to
 #[argh(subcommand, name = "list")]
 pub struct ArgsList {
     /// retrieve additional info as well (takes more time)
@@ -164,13 +237,17 @@ pub fn run_list(args: &ArgsList) -> Result<()> {
         println!("{}", list);
         return Ok(());
     }
-    println!("product         serial                          usb_sysfs_path");
-    let devices = list.devices().clone();
-    for s in devices {
+    println!(
+        "{:<16} {:<24} {:<40} {:<16} usb_sysfs_path",
+        "product", "serial", "firmware", "dut"
+    );
+    for s in list.devices() {
         println!(
-            "{:16}{:24}\t{}",
-            s.product(),
+            "{:<16} {:<24} {:<40} {:<16} {}",
+            s.kind(),
             s.serial(),
+            s.firmware_version().unwrap_or("-"),
+            s.dut().unwrap_or("-"),
             s.usb_sysfs_path()
         );
     }
@@ -275,3 +352,333 @@ fn run_show(args: &ArgsShow) -> Result<()> {
     }
     Ok(())
 }
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// start servod in the background for servos, or restart the ones which
+/// crashed
+#[argh(subcommand, name = "start")]
+pub struct ArgsStart {
+    /// cros checkout to run servod in (default: the one used last for the
+    /// servo, or the current directory)
+    #[argh(option)]
+    cros: Option<String>,
+    /// servo serial (default: all the connected servos)
+    #[argh(option)]
+    serial: Option<String>,
+}
+fn run_start(args: &ArgsStart) -> Result<()> {
+    let list = ServoList::discover()?;
+    let targets: Vec<&LocalServo> = match &args.serial {
+        Some(serial) => vec![list.find_by_serial(serial)?],
+        None => list.devices().iter().filter(|s| s.runs_servod()).collect(),
+    };
+    if targets.is_empty() {
+        bail!("No servo is connected");
+    }
+    let processes = list_servod_processes()?;
+    for s in targets {
+        if let Some(p) = processes.iter().find(|p| p.serial == s.serial()) {
+            println!(
+                "servod for {} is already running on port {}",
+                s.serial(),
+                p.port.map(|p| p.to_string()).unwrap_or("?".to_string())
+            );
+            continue;
+        }
+        if SERVOD_INSTANCES.get(s.serial())?.is_some() {
+            warn!("servod for {} is not running. Restarting...", s.serial());
+        }
+        let instance = s.start_servod_daemon(&servod_repo(s.serial(), &args.cros)?)?;
+        println!(
+            "servod for {} is running on port {} (log: {})",
+            s.serial(),
+            instance.port,
+            instance.log.display()
+        );
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// stop servod instances
+#[argh(subcommand, name = "stop")]
+pub struct ArgsStop {
+    /// servo serial (default: all the servod instances)
+    #[argh(option)]
+    serial: Option<String>,
+}
+fn run_stop(args: &ArgsStop) -> Result<()> {
+    let serials: BTreeSet<String> = match &args.serial {
+        Some(serial) => BTreeSet::from([serial.clone()]),
+        None => SERVOD_INSTANCES
+            .entries()?
+            .into_keys()
+            .chain(list_servod_processes()?.into_iter().map(|p| p.serial))
+            .collect(),
+    };
+    for serial in serials {
+        stop_servod(&serial)?;
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show the servod instances and the ports they listen on
+#[argh(subcommand, name = "status")]
+pub struct ArgsStatus {
+    /// servo serial (default: all the servod instances)
+    #[argh(option)]
+    serial: Option<String>,
+    /// stop the orphaned instances (not started by cro3, or for servos which
+    /// are not connected)
+    #[argh(switch)]
+    cleanup: bool,
+}
+fn run_status(args: &ArgsStatus) -> Result<()> {
+    let list = ServoList::discover()?;
+    let connected: Vec<String> = list
+        .devices()
+        .iter()
+        .map(|s| s.serial().to_string())
+        .collect();
+    let states = servod_states(
+        &SERVOD_INSTANCES.entries()?.into_iter().collect(),
+        &list_servod_processes()?,
+        &connected,
+    );
+    println!(
+        "{:<24} {:>5} {:>8} {:<8} dut",
+        "serial", "port", "pid", "state"
+    );
+    for (serial, port, pid, state) in &states {
+        if args.serial.as_ref().is_some_and(|s| s != serial) {
+            continue;
+        }
+        let dut = list
+            .find_by_serial(serial)
+            .ok()
+            .and_then(|s| s.dut())
+            .unwrap_or("-");
+        println!(
+            "{serial:<24} {:>5} {:>8} {state:<8} {dut}",
+            port.map(|p| p.to_string()).unwrap_or("-".to_string()),
+            pid.map(|p| p.to_string()).unwrap_or("-".to_string()),
+        );
+        if args.cleanup && *state == ServodState::Orphaned {
+            stop_servod(serial)?;
+        }
+    }
+    if states
+        .iter()
+        .any(|(_, _, _, state)| *state == ServodState::Crashed)
+    {
+        println!("Run `cro3 servo start` to restart the crashed instances");
+    }
+    Ok(())
+}
+
+/// Returns the cros checkout to run servod for `serial` in: `cros`, or the
+/// one used for the servo last
+fn servod_repo(serial: &str, cros: &Option<String>) -> Result<String> {
+    match (cros, SERVOD_INSTANCES.get(serial)?) {
+        (None, Some(last)) => Ok(last.repo),
+        (cros, _) => get_cros_dir(cros),
+    }
+}
+
+/// Connects to the servod of `servo`, starting it if it is not running
+fn connect_servod(servo: &LocalServo, cros: &Option<String>) -> Result<(ServodConnection, Chroot)> {
+    let repo = servod_repo(servo.serial(), cros)?;
+    let servod = match ServodConnection::from_serial(servo.serial()) {
+        Ok(servod) => servod,
+        Err(_) => {
+            servo.start_servod_daemon(&repo)?;
+            ServodConnection::from_serial(servo.serial())?
+        }
+    };
+    Ok((servod, Chroot::new(&repo)?))
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// attach to the EC, AP (cpu) or Cr50 console of a DUT through servod, or
+/// send commands to it, logging the traffic
+#[argh(subcommand, name = "console")]
+pub struct ArgsConsole {
+    /// cros checkout to run servod in if it is not running
+    #[argh(option)]
+    cros: Option<String>,
+    /// serial of the servo connected to the DUT. It is remembered as a
+    /// "servo" tag of the DUT.
+    #[argh(option)]
+    servo: Option<String>,
+    /// console to attach: ec, cpu or cr50 (default: cpu)
+    #[argh(option, long = "type", default = "String::from(\"cpu\")")]
+    console_type: String,
+    /// command to send to the console instead of attaching to it. Can be
+    /// repeated.
+    #[argh(option)]
+    send: Vec<String>,
+    /// seconds to wait for the output of each --send command (default: 2)
+    #[argh(option, default = "2.0")]
+    wait: f64,
+    /// DUT to attach (choose interactively if omitted)
+    #[argh(positional)]
+    dut: Option<String>,
+}
+fn run_console(args: &ArgsConsole) -> Result<()> {
+    let controls = uart_pty_controls(&args.console_type)?;
+    let dut = resolve_dut(&args.dut, None)?;
+    let id = registered_dut_id(&dut).unwrap_or(dut);
+    let servo = find_servo_for_dut(&id, &args.servo)?;
+    let (servod, chroot) = connect_servod(&servo, &args.cros)?;
+    let pty = controls
+        .iter()
+        .find_map(|c| servod.get_control(&chroot, c).ok())
+        .context(anyhow!(
+            "servod for {} does not have the {} console",
+            servo.serial(),
+            args.console_type
+        ))?;
+    let mut tty = open_raw_tty(pty.trim())?;
+    let log_path = console_log_path(&args.console_type, &id)?;
+    let log = Arc::new(Mutex::new(ConsoleLog::create(&log_path)?));
+    info!(
+        "Attached to the {} console of {id} ({})",
+        args.console_type,
+        pty.trim()
+    );
+    info!("Logging to {log_path:?}");
+
+    spawn_console_reader(&tty, log.clone())?;
+
+    if !args.send.is_empty() {
+        for cmd in &args.send {
+            log.lock().unwrap().write(format!("> {cmd}\n").as_bytes())?;
+            tty.write_all(format!("{cmd}\n").as_bytes())?;
+            thread::sleep(Duration::from_secs_f64(args.wait));
+        }
+        println!();
+        return Ok(());
+    }
+    info!("{CONSOLE_HELP}");
+    forward_keys_to_console(&mut tty, &|| {
+        servod
+            .run_dut_control(&chroot, &["power_state:reset"])
+            .map(|_| ())
+    })
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// sample the power rails of a DUT through servod (optionally while running a
+/// workload on it) and show the statistics of each rail
+#[argh(subcommand, name = "power")]
+pub struct ArgsPower {
+    /// cros checkout to run servod and dut-control in
+    #[argh(option)]
+    cros: Option<String>,
+    /// serial of the servo connected to the DUT. It is remembered as a
+    /// "servo" tag of the DUT.
+    #[argh(option)]
+    servo: Option<String>,
+    /// seconds to sample for (default: 300)
+    #[argh(option, default = "300")]
+    duration: u64,
+    /// interval between the samples, e.g. 1s, 500ms (default: 1s)
+    #[argh(option, default = "String::from(\"1s\")")]
+    interval: String,
+    /// power rail (a *_mw control of servod) to sample. Can be repeated.
+    /// (default: all the *_mw controls)
+    #[argh(option)]
+    rail: Vec<String>,
+    /// command to run on the DUT over SSH while sampling
+    #[argh(option)]
+    workload: Option<String>,
+    /// directory to write power.csv and power.json in (default:
+    /// ~/.cro3/power/DUT_TIMESTAMP)
+    #[argh(option)]
+    output: Option<String>,
+    /// DUT to measure (choose interactively if omitted)
+    #[argh(positional)]
+    dut: Option<String>,
+}
+fn run_power(args: &ArgsPower) -> Result<()> {
+    let interval = parse_interval(&args.interval)?;
+    let dut = resolve_dut(&args.dut, None)?;
+    let id = registered_dut_id(&dut).unwrap_or(dut.clone());
+    let servo = find_servo_for_dut(&id, &args.servo)?;
+    let (servod, chroot) = connect_servod(&servo, &args.cros)?;
+    let rails = if args.rail.is_empty() {
+        power_rails(&servod.run_dut_control::<&str>(&chroot, &[])?)
+    } else {
+        args.rail.clone()
+    };
+    if rails.is_empty() {
+        bail!(
+            "servod for {} does not have power rails (*_mw controls). Is a Sweetberry or a servo \
+             with the INAs of the board attached?",
+            servo.serial()
+        );
+    }
+    let dir = match &args.output {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            PathBuf::from(dir)
+        }
+        None => gen_path_in_cro3_dir(&format!(
+            "power/{}_{}/.keep",
+            id.replace(['/', ':', '[', ']'], "_"),
+            Local::now().format("%Y%m%d_%H%M%S")
+        ))?
+        .parent()
+        .context("Failed to get the output dir")?
+        .to_path_buf(),
+    };
+
+    let workload = args.workload.clone().map(|workload| {
+        let ssh = SshInfo::new(&dut);
+        info!("Running the workload on {id}: {workload}");
+        thread::spawn(move || ssh?.run_cmd_piped(&[workload]))
+    });
+    info!(
+        "Sampling {} rails every {:?} for {}s...",
+        rails.len(),
+        interval,
+        args.duration
+    );
+    let mut raw = String::new();
+    chroot.run_bash_script_in_chroot_with_lines(
+        "servo_power",
+        &sampling_script(servod.port(), &rails, args.duration, interval),
+        |line| {
+            raw += line;
+            raw += "\n";
+        },
+    )?;
+    if let Some(workload) = workload {
+        match workload.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("The workload failed: {e:#}"),
+            Err(_) => warn!("The workload thread panicked"),
+        }
+    }
+
+    let samples = parse_power_log(&raw);
+    if samples.is_empty() {
+        bail!("No samples were read from servod. Output:\n{raw}");
+    }
+    let report = PowerReport {
+        dut: id,
+        servo: servo.serial().to_string(),
+        interval: interval.as_secs_f64(),
+        stats: power_stats(&samples, &rails),
+        samples,
+    };
+    fs::write(dir.join("power.csv"), to_csv(&report.samples, &rails))?;
+    fs::write(
+        dir.join("power.json"),
+        serde_json::to_string_pretty(&report)?,
+    )?;
+    print!("{}", format_power_table(&report.stats));
+    info!("{} samples written to {dir:?}", report.samples.len());
+    Ok(())
+}
@@ -4,16 +4,42 @@
 // license that can be found in the LICENSE file or at
 // https://developers.google.com/open-source/licenses/bsd
 
+use std::collections::BTreeMap;
+use std::env::current_dir;
+use std::env::current_exe;
 use std::fs;
+use std::io::stderr;
+use std::io::stdin;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
 
 use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Error;
 use anyhow::Result;
 use argh::FromArgs;
+use cro3::config::Config;
+use cro3::proxy::boto_path;
+use cro3::proxy::check_proxy_consistency;
+use cro3::proxy::proxy_from_env;
+use cro3::proxy::proxy_host_and_port;
+use cro3::proxy::update_boto_proxy;
+use cro3::repo::get_cros_dir_unchecked;
 use cro3::util::cro3_paths::gen_path_in_cro3_dir;
+use cro3::util::picker::is_interactive;
+use cro3::util::shell_helpers::get_stderr;
 use cro3::util::shell_helpers::get_stdout;
 use cro3::util::shell_helpers::run_bash_command;
+use cro3::util::shell_helpers::shell_quote;
+use cro3::util::super_user_helpers::has_root_privilege;
+use cro3::util::super_user_helpers::run_cro3_with_sudo;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use tempdir::TempDir;
 use tracing::error;
 use tracing::info;
 use tracing::warn;
@@ -31,6 +57,12 @@ enum SubCommand {
     Env(ArgsEnv),
     BashCompletion(ArgsBashCompletion),
     ZshCompletion(ArgsZshCompletion),
+    Proxy(ArgsProxy),
+    Udev(ArgsUdev),
+    Auth(ArgsAuth),
+    SelfUpdate(ArgsSelfUpdate),
+    Snapshot(ArgsSnapshot),
+    Diff(ArgsDiff),
 }
 #[tracing::instrument(level = "trace")]
 pub fn run(args: &Args) -> Result<()> {
@@ -38,14 +70,25 @@ pub fn run(args: &Args) -> Result<()> {
         SubCommand::Env(args) => run_env(args),
         SubCommand::BashCompletion(args) => run_bash_completion(args),
         SubCommand::ZshCompletion(args) => run_zsh_completion(args),
+        SubCommand::Proxy(args) => run_proxy(args),
+        SubCommand::Udev(args) => run_udev(args),
+        SubCommand::Auth(args) => run_auth(args),
+        SubCommand::SelfUpdate(args) => run_self_update(args),
+        SubCommand::Snapshot(args) => run_snapshot(args),
+        SubCommand::Diff(args) => run_diff(args),
     }
 }
 
 #[derive(FromArgs, PartialEq, Debug)]
 /// Check if this machine is ready to develop CrOS and do fix as needed
 #[argh(subcommand, name = "env")]
-pub struct ArgsEnv {}
-fn run_env(_args: &ArgsEnv) -> Result<()> {
+pub struct ArgsEnv {
+    /// path to the CrOS checkout, or the directory you are going to check out
+    /// into (default: the CrOS checkout in use, or the current directory)
+    #[argh(option)]
+    cros: Option<String>,
+}
+fn run_env(args: &ArgsEnv) -> Result<()> {
     info!("Checking the environment...");
     let print_err_and_ignore = |e: Error| -> Result<()> {
         error!("FAIL: {}", e);
@@ -55,6 +98,8 @@ fn run_env(_args: &ArgsEnv) -> Result<()> {
     check_gsutil().or_else(print_err_and_ignore)?;
     check_gcloud().or_else(print_err_and_ignore)?;
     check_gcloud_auth_list().or_else(print_err_and_ignore)?;
+    check_proxy().or_else(print_err_and_ignore)?;
+    check_checkout_filesystem(&args.cros).or_else(print_err_and_ignore)?;
     Ok(())
 }
 
@@ -102,6 +147,131 @@ fn check_gcloud_auth_list() -> Result<()> {
     Ok(())
 }
 
+fn check_proxy() -> Result<()> {
+    match check_proxy_consistency()? {
+        Some(proxy) => info!("proxy {proxy} is configured consistently"),
+        None => info!("No proxy is configured"),
+    }
+    Ok(())
+}
+
+// A full CrOS checkout with a chroot and a few board builds needs this much.
+const MIN_FREE_SPACE_FOR_CHECKOUT_GB: u64 = 200;
+const MIN_FREE_INODES_FOR_CHECKOUT: u64 = 10_000_000;
+// Folders used by cloud storage clients. Syncing a checkout is very slow and
+// breaks symlinks and file permissions.
+const CLOUD_SYNC_DIR_PATTERNS: [&str; 7] = [
+    "Google Drive",
+    "GoogleDrive",
+    "My Drive",
+    "Dropbox",
+    "OneDrive",
+    "iCloud",
+    "CloudStorage",
+];
+
+fn check_checkout_filesystem(cros: &Option<String>) -> Result<()> {
+    let dir = match get_cros_dir_unchecked(cros) {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => current_dir()?,
+    };
+    // The checkout may not exist yet. Check the nearest existing ancestor.
+    let existing = dir
+        .ancestors()
+        .find(|p| p.exists())
+        .context(anyhow!("No existing directory found in {dir:?}"))?;
+    let existing = existing.canonicalize()?;
+    info!("Checking the filesystem for the checkout at {dir:?}...");
+    let mut errors = Vec::new();
+
+    if let Some(pattern) = CLOUD_SYNC_DIR_PATTERNS
+        .iter()
+        .find(|p| existing.to_string_lossy().contains(*p))
+    {
+        errors.push(format!(
+            "{existing:?} looks like a folder synced by a cloud storage client ({pattern}). \
+             Please check out somewhere outside of it (e.g. ~/chromiumos)."
+        ));
+    }
+
+    let fs_type = get_stdout(&run_bash_command(
+        &format!("stat -f -c %T '{}'", existing.display()),
+        None,
+    )?);
+    match fs_type.as_str() {
+        "nfs" | "nfs4" | "smb2" | "cifs" | "sshfs" | "fuse.sshfs" => errors.push(format!(
+            "{existing:?} is on a network filesystem ({fs_type}). The chroot and builds do not \
+             work well on it. Please use a local disk."
+        )),
+        "exfat" | "msdos" | "vfat" | "fuseblk" | "ntfs" => errors.push(format!(
+            "{existing:?} is on {fs_type}, which does not support symlinks or unix permissions \
+             properly. Please use a disk formatted with ext4 or btrfs."
+        )),
+        _ => {}
+    }
+
+    if !is_case_sensitive(&existing)? {
+        errors.push(format!(
+            "{existing:?} is on a case-insensitive filesystem. The CrOS source contains files \
+             whose names differ only in case. Please use a case-sensitive filesystem."
+        ));
+    }
+
+    let df = get_stdout(&run_bash_command(
+        &format!(
+            "df --output=avail,iavail,itotal -B1 '{}' | tail -n 1",
+            existing.display()
+        ),
+        None,
+    )?);
+    let df: Vec<u64> = df
+        .split_whitespace()
+        .map(|v| v.parse().unwrap_or(0))
+        .collect();
+    if let [avail, iavail, itotal] = df[..] {
+        let avail_gb = avail / 1024 / 1024 / 1024;
+        info!("Free space: {avail_gb} GiB, free inodes: {iavail}");
+        if avail_gb < MIN_FREE_SPACE_FOR_CHECKOUT_GB {
+            errors.push(format!(
+                "Only {avail_gb} GiB is available on the filesystem. At least \
+                 {MIN_FREE_SPACE_FOR_CHECKOUT_GB} GiB is recommended for a checkout with builds. \
+                 Please free up some space or use another disk."
+            ));
+        }
+        // Some filesystems (e.g. btrfs) allocate inodes dynamically and report 0
+        if itotal != 0 && iavail < MIN_FREE_INODES_FOR_CHECKOUT {
+            errors.push(format!(
+                "Only {iavail} inodes are available on the filesystem. At least \
+                 {MIN_FREE_INODES_FOR_CHECKOUT} are recommended. Please clean up small files or \
+                 use a filesystem created with more inodes (e.g. mkfs.ext4 -i 8192)."
+            ));
+        }
+    } else {
+        warn!("Failed to get the free space of {existing:?}");
+    }
+
+    if !errors.is_empty() {
+        bail!(
+            "{dir:?} is not suitable for a CrOS checkout:\n{}",
+            errors.join("\n")
+        );
+    }
+    info!("The filesystem for {dir:?} looks good");
+    Ok(())
+}
+
+fn is_case_sensitive(dir: &Path) -> Result<bool> {
+    let lower = dir.join(format!(".cro3_case_check_{}", std::process::id()));
+    let upper = dir.join(format!(".CRO3_CASE_CHECK_{}", std::process::id()));
+    if fs::write(&lower, "").is_err() {
+        warn!("Skipped the case sensitivity check since {dir:?} is not writable");
+        return Ok(true);
+    }
+    let result = !upper.exists();
+    fs::remove_file(&lower)?;
+    Ok(result)
+}
+
 fn shell_shared_setup() -> Result<(), Error> {
     fs::write(
         gen_path_in_cro3_dir("cro3.bash")?,
@@ -149,3 +319,505 @@ fn run_zsh_completion(_args: &ArgsZshCompletion) -> Result<()> {
 
     Ok(())
 }
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Configure a proxy for git, repo, gsutil and cro3 consistently
+#[argh(subcommand, name = "proxy")]
+pub struct ArgsProxy {
+    /// proxy URL (e.g. http://proxy.example.com:3128). If omitted, the one in
+    /// HTTP(S)_PROXY is used, or asked interactively.
+    #[argh(option)]
+    url: Option<String>,
+
+    /// comma-separated list of hosts that should not go through the proxy
+    #[argh(option)]
+    no_proxy: Option<String>,
+
+    /// remove the proxy settings instead
+    #[argh(switch)]
+    clear: bool,
+}
+fn run_proxy(args: &ArgsProxy) -> Result<()> {
+    let url = if args.clear {
+        None
+    } else if let Some(url) = args.url.clone().or_else(proxy_from_env) {
+        Some(url)
+    } else if is_interactive() {
+        eprint!("Proxy URL (e.g. http://proxy.example.com:3128): ");
+        stderr().flush()?;
+        let mut line = String::new();
+        stdin().read_line(&mut line)?;
+        Some(line.trim().to_string())
+    } else {
+        bail!("Please specify the proxy with --url");
+    };
+    let host_and_port = url.as_deref().map(proxy_host_and_port).transpose()?;
+    if let Some((host, port)) = &host_and_port {
+        info!("Configuring proxy {host}:{port}...");
+    } else {
+        info!("Removing the proxy settings...");
+    }
+
+    // git (and repo, which uses git to fetch the source)
+    let git_cmd = match &url {
+        Some(url) => {
+            let url = shell_quote(url);
+            format!("git config --global http.proxy {url} && git config --global https.proxy {url}")
+        }
+        None => "git config --global --unset http.proxy; git config --global --unset https.proxy; \
+                 true"
+            .to_string(),
+    };
+    run_bash_command(&git_cmd, None)?
+        .status
+        .exit_ok()
+        .context("Failed to update the git config")?;
+    info!("Updated the global git config");
+
+    // gsutil
+    let boto_path = boto_path()?;
+    let boto = fs::read_to_string(&boto_path).unwrap_or_default();
+    let updated = update_boto_proxy(&boto, host_and_port.as_ref().map(|(h, p)| (h.as_str(), *p)));
+    if boto != updated && !(boto.is_empty() && url.is_none()) {
+        fs::write(&boto_path, updated).context(anyhow!("Failed to update {boto_path:?}"))?;
+        info!("Updated {boto_path:?}");
+    }
+
+    // cro3 itself (and repo, gsutil and curl invoked by cro3)
+    let mut config = Config::read()?;
+    match &url {
+        Some(url) => config.set("http_proxy", &[url])?,
+        None => config.clear("http_proxy")?,
+    }
+    match (&args.no_proxy, args.clear) {
+        (Some(no_proxy), false) => config.set("no_proxy", &[no_proxy])?,
+        (_, true) => config.clear("no_proxy")?,
+        _ => {}
+    }
+    info!("Updated the cro3 config");
+
+    // Interactive shells outside of cro3 (e.g. running repo directly)
+    let env_path = gen_path_in_cro3_dir("proxy.env")?;
+    if let Some(url) = &url {
+        let mut env = String::new();
+        for k in ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"] {
+            env += &format!("export {k}={}\n", shell_quote(url));
+        }
+        if let Some(no_proxy) = &args.no_proxy {
+            for k in ["no_proxy", "NO_PROXY"] {
+                env += &format!("export {k}={}\n", shell_quote(no_proxy));
+            }
+        }
+        fs::write(&env_path, env)?;
+        warn!(
+            "Wrote {env_path:?}. Please add `. {}` to your shell rc to use the proxy outside of \
+             cro3 (e.g. running repo directly).",
+            env_path.to_string_lossy()
+        );
+    } else if env_path.exists() {
+        fs::remove_file(&env_path)?;
+        warn!("Removed {env_path:?}. Please remove it from your shell rc as well.");
+    }
+    Ok(())
+}
+
+const UDEV_RULES_PATH: &str = "/etc/udev/rules.d/99-cro3-servo.rules";
+const UDEV_GROUP: &str = "plugdev";
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Install udev rules for servo, Cr50/Ti50 (SuzyQ) and other debug devices
+#[argh(subcommand, name = "udev")]
+pub struct ArgsUdev {}
+fn run_udev(_args: &ArgsUdev) -> Result<()> {
+    if !has_root_privilege()? {
+        warn!("Installing udev rules requires root. Running `sudo cro3 setup udev`...");
+        run_cro3_with_sudo(&["setup", "udev"])?;
+        return check_udev_group();
+    }
+    run_bash_command(&format!("groupadd -f {UDEV_GROUP}"), None)?
+        .status
+        .exit_ok()
+        .context(anyhow!("Failed to create group {UDEV_GROUP}"))?;
+    fs::write(UDEV_RULES_PATH, include_str!("99-cro3-servo.rules"))
+        .context(anyhow!("Failed to write {UDEV_RULES_PATH}"))?;
+    info!("Installed {UDEV_RULES_PATH}");
+    run_bash_command("udevadm control --reload-rules && udevadm trigger", None)?
+        .status
+        .exit_ok()
+        .context("Failed to reload udev rules")?;
+    info!("Reloaded udev rules. Please re-plug servo and SuzyQ cables if connected.");
+    Ok(())
+}
+
+fn check_udev_group() -> Result<()> {
+    let groups = get_stdout(&run_bash_command("id -nG", None)?);
+    if groups.split_whitespace().any(|g| g == UDEV_GROUP) {
+        info!("You are in the {UDEV_GROUP} group. Servo devices should be accessible.");
+        return Ok(());
+    }
+    let user = get_stdout(&run_bash_command("id -un", None)?);
+    bail!(
+        "You are not in the {UDEV_GROUP} group yet, so servo devices are not accessible. Please \
+         run `sudo usermod -aG {UDEV_GROUP} {user}` and then log in again (or run `newgrp \
+         {UDEV_GROUP}` for the current shell)."
+    )
+}
+
+// Buckets that should be readable to flash images. The internal one is checked
+// only for internal users.
+const PUBLIC_IMAGE_BUCKET: &str = "gs://chromeos-image-archive/";
+const INTERNAL_IMAGE_BUCKET: &str = "gs://chromeos-releases/";
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Log in to gcloud and gsutil, then verify access to the image buckets
+#[argh(subcommand, name = "auth")]
+pub struct ArgsAuth {
+    /// account to log in (e.g. you@example.com). Chosen in the browser if
+    /// omitted.
+    #[argh(option)]
+    account: Option<String>,
+
+    /// only verify the current credentials without logging in
+    #[argh(switch)]
+    verify_only: bool,
+}
+fn run_auth(args: &ArgsAuth) -> Result<()> {
+    if !args.verify_only {
+        let account = args.account.clone().unwrap_or_default();
+        for (what, cmd) in [
+            ("gcloud", format!("gcloud auth login {account}")),
+            (
+                "application default credentials",
+                "gcloud auth application-default login".to_string(),
+            ),
+        ] {
+            warn!("Logging in for {what}. Please follow the instructions...");
+            run_bash_interactive(&cmd).context(anyhow!("Failed to log in for {what}"))?;
+        }
+        if !can_read_bucket(PUBLIC_IMAGE_BUCKET)? {
+            // gsutil.py in depot_tools has its own credentials in ~/.boto
+            warn!(
+                "Setting up credentials for gsutil. Please enter 'chromeos-swarming' as a project \
+                 ID if asked."
+            );
+            run_bash_interactive("gsutil.py config")
+                .context("Failed to set up the credentials for gsutil")?;
+        }
+    }
+
+    let account = get_stdout(&run_bash_command("gcloud config get-value account", None)?);
+    if account.is_empty() {
+        bail!("No account is active in gcloud. Please run `cro3 setup auth` without --verify-only");
+    }
+    info!("Active account: {account}");
+    let mut buckets = vec![PUBLIC_IMAGE_BUCKET];
+    if Config::read()?.is_internal() {
+        buckets.push(INTERNAL_IMAGE_BUCKET);
+    }
+    let mut errors = Vec::new();
+    for bucket in buckets {
+        if can_read_bucket(bucket)? {
+            info!("OK: {bucket} is accessible");
+        } else {
+            errors.push(bucket);
+        }
+    }
+    if !errors.is_empty() {
+        bail!(
+            "{account} cannot access {errors:?}. Please check if the account is the right one, or \
+             run `gsutil.py config` again."
+        );
+    }
+    Config::read()?.set("gcloud_account", &[&account])?;
+    info!("Recorded {account} in the cro3 config");
+    Ok(())
+}
+
+fn run_bash_interactive(cmd: &str) -> Result<()> {
+    std::process::Command::new("bash")
+        .arg("-c")
+        .arg(cmd)
+        .status()?
+        .exit_ok()
+        .context(anyhow!("`{cmd}` failed"))
+}
+
+fn can_read_bucket(bucket: &str) -> Result<bool> {
+    Ok(
+        run_bash_command(&format!("gsutil.py ls {bucket} >/dev/null 2>&1"), None)?
+            .status
+            .success(),
+    )
+}
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const RELEASE_REPO: &str = "google/cro3";
+const RELEASE_ASSET_NAME: &str = "cro3-x86_64-unknown-linux-gnu";
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Update cro3 to the latest release
+#[argh(subcommand, name = "self-update")]
+pub struct ArgsSelfUpdate {
+    /// only check if a newer version is available
+    #[argh(switch)]
+    check: bool,
+
+    /// release tag to install (e.g. v0.2.0) instead of the latest one
+    #[argh(option)]
+    version: Option<String>,
+
+    /// build the release from source with `cargo install` instead of
+    /// downloading the prebuilt binary
+    #[argh(switch)]
+    from_source: bool,
+
+    /// install even if the version is not newer than the current one
+    #[argh(switch)]
+    force: bool,
+}
+fn run_self_update(args: &ArgsSelfUpdate) -> Result<()> {
+    let api = match &args.version {
+        Some(tag) => format!("https://api.github.com/repos/{RELEASE_REPO}/releases/tags/{tag}"),
+        None => format!("https://api.github.com/repos/{RELEASE_REPO}/releases/latest"),
+    };
+    let release = run_bash_command(&format!("curl -sSfL {api}"), None)?;
+    release.status.exit_ok().context(anyhow!(
+        "Failed to get the release info from {api}: {}",
+        get_stderr(&release)
+    ))?;
+    let release: Value = serde_json::from_str(&get_stdout(&release))?;
+    let tag = release["tag_name"]
+        .as_str()
+        .context("No tag_name in the release info")?;
+    info!("cro3 v{VERSION} is installed. The latest release is {tag}.");
+    if !args.force && args.version.is_none() && !is_newer_version(tag, VERSION) {
+        info!("cro3 is up to date");
+        return Ok(());
+    }
+    if args.check {
+        warn!("{tag} is available. Run `cro3 setup self-update` to install it.");
+        return Ok(());
+    }
+
+    let exe = current_exe()?.canonicalize()?;
+    let dir = exe.parent().context("Failed to get the dir of cro3")?;
+    let tmpdir = TempDir::new("cro3_self_update")?;
+    let new_exe = if args.from_source {
+        build_release_from_source(tag, tmpdir.path())?
+    } else {
+        download_release_binary(&release, tmpdir.path())?
+    };
+    // Copy the binary next to the current one first, then rename it to
+    // replace the current one atomically (rename works on a running binary).
+    let staged = dir.join(".cro3.new");
+    fs::copy(new_exe, &staged).context(anyhow!(
+        "Failed to write {staged:?}. Is cro3 installed in a writable place?"
+    ))?;
+    fs::set_permissions(&staged, fs::Permissions::from_mode(0o755))?;
+    let output = std::process::Command::new(&staged)
+        .arg("version")
+        .output()?;
+    if !output.status.success() {
+        fs::remove_file(&staged)?;
+        bail!("The new binary does not work: {}", get_stdout(&output));
+    }
+    fs::rename(&staged, &exe).context(anyhow!("Failed to replace {exe:?}"))?;
+    info!("Updated {exe:?} to {}", get_stdout(&output));
+    Ok(())
+}
+
+fn download_release_binary(release: &Value, dir: &Path) -> Result<PathBuf> {
+    let assets = release["assets"]
+        .as_array()
+        .context("No assets in the release info")?;
+    let find_asset = |name: &str| -> Option<String> {
+        assets
+            .iter()
+            .find(|a| a["name"].as_str() == Some(name))
+            .and_then(|a| a["browser_download_url"].as_str())
+            .map(str::to_string)
+    };
+    let binary_url = find_asset(RELEASE_ASSET_NAME).context(anyhow!(
+        "No prebuilt binary {RELEASE_ASSET_NAME} in the release. Try --from-source."
+    ))?;
+    let checksum_url = find_asset(&format!("{RELEASE_ASSET_NAME}.sha256")).context(anyhow!(
+        "No checksum for {RELEASE_ASSET_NAME} in the release. Try --from-source."
+    ))?;
+    let binary = dir.join(RELEASE_ASSET_NAME);
+    info!("Downloading {binary_url}...");
+    run_bash_command(
+        &format!("curl -sSfL -o {} {binary_url}", binary.display()),
+        None,
+    )?
+    .status
+    .exit_ok()
+    .context(anyhow!("Failed to download {binary_url}"))?;
+    let expected = run_bash_command(&format!("curl -sSfL {checksum_url}"), None)?;
+    expected
+        .status
+        .exit_ok()
+        .context(anyhow!("Failed to download {checksum_url}"))?;
+    let expected = get_stdout(&expected);
+    let expected = expected
+        .split_whitespace()
+        .next()
+        .context("Empty checksum file")?;
+    let actual = get_stdout(&run_bash_command(
+        &format!("sha256sum {} | cut -d ' ' -f 1", binary.display()),
+        None,
+    )?);
+    if actual != expected {
+        bail!("Checksum mismatch for {binary_url}: expected {expected}, got {actual}");
+    }
+    info!("Checksum verified: {actual}");
+    Ok(binary)
+}
+
+fn build_release_from_source(tag: &str, dir: &Path) -> Result<PathBuf> {
+    info!("Building cro3 {tag} from source. It will take a few minutes...");
+    std::process::Command::new("cargo")
+        .args([
+            "install",
+            "--locked",
+            "--git",
+            &format!("https://github.com/{RELEASE_REPO}"),
+            "--tag",
+            tag,
+            "--root",
+        ])
+        .arg(dir)
+        .status()?
+        .exit_ok()
+        .context("cargo install failed")?;
+    Ok(dir.join("bin/cro3"))
+}
+
+/// Returns true if the version tag `a` (e.g. "v0.2.0") is newer than `b`
+fn is_newer_version(a: &str, b: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.trim_start_matches('v')
+            .split('.')
+            .map(|e| e.parse().unwrap_or(0))
+            .collect()
+    };
+    parse(a) > parse(b)
+}
+
+const ENV_BASELINE_FILE_NAME: &str = "env-baseline.json";
+// Commands to get the versions of the tools recorded in the baseline
+const ENV_BASELINE_CMDS: [(&str, &str); 8] = [
+    ("os", ". /etc/os-release && echo $PRETTY_NAME"),
+    ("kernel", "uname -r"),
+    ("git", "git --version"),
+    (
+        "repo",
+        "repo --version 2>&1 | grep -E '^repo (launcher )?version' | head -n 1",
+    ),
+    ("gsutil", "gsutil version 2>&1 | head -n 1"),
+    ("gcloud", "gcloud version 2>/dev/null | head -n 1"),
+    ("rustc", "rustc --version"),
+    ("python3", "python3 --version"),
+];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EnvBaseline {
+    recorded_at: String,
+    cro3: String,
+    tools: BTreeMap<String, String>,
+}
+impl EnvBaseline {
+    fn collect() -> Result<Self> {
+        let mut tools = BTreeMap::new();
+        for (name, cmd) in ENV_BASELINE_CMDS {
+            let output = run_bash_command(cmd, None)?;
+            let version = get_stdout(&output);
+            let version = if !output.status.success() || version.is_empty() {
+                "(not found)".to_string()
+            } else {
+                version
+            };
+            tools.insert(name.to_string(), version);
+        }
+        Ok(Self {
+            recorded_at: chrono::Local::now().to_rfc3339(),
+            cro3: VERSION.to_string(),
+            tools,
+        })
+    }
+}
+
+fn env_baseline_path(path: &Option<String>) -> Result<PathBuf> {
+    match path {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => gen_path_in_cro3_dir(ENV_BASELINE_FILE_NAME),
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Record the versions of the tools used for development as a baseline
+#[argh(subcommand, name = "snapshot")]
+pub struct ArgsSnapshot {
+    /// path to write the baseline (default: ~/.cro3/env-baseline.json). Commit
+    /// it to share the baseline within a team.
+    #[argh(option)]
+    output: Option<String>,
+}
+fn run_snapshot(args: &ArgsSnapshot) -> Result<()> {
+    let path = env_baseline_path(&args.output)?;
+    let baseline = EnvBaseline::collect()?;
+    for (name, version) in &baseline.tools {
+        println!("{name:<10} {version}");
+    }
+    fs::write(&path, serde_json::to_string_pretty(&baseline)?)
+        .context(anyhow!("Failed to write {path:?}"))?;
+    info!("Recorded the environment baseline to {path:?}");
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// Show differences between the current environment and the baseline
+#[argh(subcommand, name = "diff")]
+pub struct ArgsDiff {
+    /// path to the baseline (default: ~/.cro3/env-baseline.json)
+    #[argh(option)]
+    baseline: Option<String>,
+}
+fn run_diff(args: &ArgsDiff) -> Result<()> {
+    let path = env_baseline_path(&args.baseline)?;
+    let baseline = fs::read_to_string(&path).context(anyhow!(
+        "Failed to read {path:?}. Please run `cro3 setup snapshot` first."
+    ))?;
+    let baseline: EnvBaseline = serde_json::from_str(&baseline)?;
+    let current = EnvBaseline::collect()?;
+    info!(
+        "Comparing with the baseline recorded at {} (cro3 v{})",
+        baseline.recorded_at, baseline.cro3
+    );
+    let mut names: Vec<&String> = baseline.tools.keys().chain(current.tools.keys()).collect();
+    names.sort();
+    names.dedup();
+    let mut drifted = 0;
+    for name in names {
+        let before = baseline
+            .tools
+            .get(name)
+            .map(String::as_str)
+            .unwrap_or("(not recorded)");
+        let after = current
+            .tools
+            .get(name)
+            .map(String::as_str)
+            .unwrap_or("(not recorded)");
+        if before == after {
+            println!("  {name:<10} {after}");
+        } else {
+            drifted += 1;
+            println!("! {name:<10} {before} -> {after}");
+        }
+    }
+    if drifted > 0 {
+        bail!("{drifted} tools differ from the baseline {path:?}");
+    }
+    info!("No drift from the baseline");
+    Ok(())
+}
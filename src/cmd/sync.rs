@@ -21,6 +21,7 @@ use anyhow::Result;
 use argh::FromArgs;
 use cro3::arc::lookup_arc_version;
 use cro3::arc::setup_arc_repo;
+use cro3::build::boards_with_workon_packages;
 use cro3::cros::lookup_full_version;
 use cro3::cros::setup_cros_repo;
 use cro3::repo::get_cros_dir_unchecked;
@@ -111,7 +112,18 @@ pub fn run(args: &Args) -> Result<()> {
         setup_arc_repo(&repo, &version)?;
     }
 
-    repo_sync(&repo, args.force, args.verbose)
+    repo_sync(&repo, args.force, args.verbose)?;
+    if is_cros {
+        for (board, packages) in boards_with_workon_packages()? {
+            warn!(
+                "{} packages are worked on for {board}, so they are built from the synced source \
+                 instead of the prebuilts: {}",
+                packages.len(),
+                packages.join(" ")
+            );
+        }
+    }
+    Ok(())
 }
 
 /// Extract a appropriate version name from a argument.
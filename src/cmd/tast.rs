@@ -4,6 +4,54 @@
 // license that can be found in the LICENSE file or at
 // https://developers.google.com/open-source/licenses/bsd
 
+//! ## Run Tast tests
+//! ```
+//! cro3 tast list --cros ${CROS} --dut ${DUT} 'example.*'
+//! cro3 tast list --cros ${CROS} ${DUT} --attr 'group:mainline && !informational'
+//! cro3 tast list --cached --json 'example.*'
+//! cro3 tast run --cros ${CROS} --dut ${DUT} example.Pass
+//! cro3 tast run --cros ${CROS} ${DUT} 'example.*'
+//! ```
+//!
+//! The results are shown as a table of the tests with the logs of the failed
+//! ones, and recorded under ~/.cro3/results with the logs of all tests.
+//!
+//! `tast run` builds the test bundles from the checkout for each run. To run
+//! modified tests without building them each time, or with `tast` outside of
+//! cro3, build the local bundle and push it to the DUT in place of the one in
+//! the image, and run the tests with `--prebuilt`. Without a checkout,
+//! `--prebuilt` runs the `tast` in PATH.
+//! ```
+//! cro3 tast build --cros ${CROS} --dut ${DUT}
+//! cro3 tast run --cros ${CROS} --dut ${DUT} --prebuilt 'example.*'
+//! # Put back the bundle of the image
+//! cro3 tast build --dut ${DUT} --restore
+//! ```
+//!
+//! With several DUTs of the same board, the matched tests can be split across
+//! them to run in parallel. The DUTs can be a group in the config, or given
+//! with commas. The bundles are built once and pushed to each DUT (see `cro3
+//! tast build`), and the results are merged into one report. The bundles of
+//! the images are put back on the DUTs afterwards.
+//! ```
+//! cro3 tast run --cros ${CROS} --shard-across dut1,dut2,dut3 'example.*'
+//! ```
+//!
+//! With `--retries`, the failed tests are rerun, and the ones that pass on a
+//! retry are reported as flaky apart from the failures, so they do not fail
+//! the run.
+//! ```
+//! cro3 tast run --cros ${CROS} --dut ${DUT} --retries 2 'example.*'
+//! ```
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+use std::time::Instant;
+
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
@@ -12,11 +60,34 @@ use cro3::cache::KvCache;
 use cro3::chroot::Chroot;
 use cro3::config::Config;
 use cro3::cros::ensure_testing_rsa_is_there;
+use cro3::dut::expand_dut_groups;
+use cro3::dut::infer_board;
+use cro3::dut::resolve_dut;
 use cro3::dut::SshInfo;
+use cro3::dut::SSH_CACHE;
 use cro3::repo::get_cros_dir;
+use cro3::tast::attr_expr_matches;
+use cro3::tast::build_local_bundle;
+use cro3::tast::dut_goarch;
+use cro3::tast::host_tast;
+use cro3::tast::parse_tast_list;
+use cro3::tast::push_local_bundle;
+use cro3::tast::read_tast_results;
+use cro3::tast::restore_local_bundles;
+use cro3::tast::shard_tests;
+use cro3::tast::TastTestInfo;
+use cro3::test_results::TestResult;
+use cro3::test_results::TestRun;
+use cro3::test_results::TestStatus;
+use cro3::util::cro3_paths::gen_path_in_cro3_dir;
 use glob::Pattern;
+use rayon::prelude::*;
+use tracing::error;
+use tracing::info;
 use tracing::warn;
 
+use crate::cmd::test::print_run;
+
 #[derive(FromArgs, PartialEq, Debug)]
 /// run Tast test
 #[argh(subcommand, name = "tast")]
@@ -25,23 +96,88 @@ pub struct Args {
     nested: SubCommand,
 }
 
-pub static TEST_CACHE: KvCache<Vec<String>> = KvCache::new("tast_cache");
+static TEST_CACHE_NAME: &str = "tast_cache";
+pub static TEST_CACHE: KvCache<Vec<String>> = KvCache::new(TEST_CACHE_NAME);
+/// Tests in each bundle with their attributes
+static TEST_INFO_CACHE: KvCache<Vec<TastTestInfo>> = KvCache::new("tast_test_info_cache");
 static DEFAULT_BUNDLE: &str = "cros";
+/// Subdirectory of the results of a run with the bundles on the DUT
+static PREBUILT_RESULTS: &str = "prebuilt";
 
 #[derive(FromArgs, PartialEq, Debug)]
 #[argh(subcommand)]
 enum SubCommand {
     List(ArgsList),
     Run(ArgsRun),
+    Build(ArgsBuild),
 }
 #[tracing::instrument(level = "trace")]
 pub fn run(args: &Args) -> Result<()> {
     match &args.nested {
         SubCommand::List(args) => run_tast_list(args),
         SubCommand::Run(args) => run_tast_run(args),
+        SubCommand::Build(args) => run_tast_build(args),
     }
 }
 
+#[derive(FromArgs, PartialEq, Debug)]
+/// build local test bundles from the checkout and push them to a DUT
+#[argh(subcommand, name = "build")]
+pub struct ArgsBuild {
+    /// target cros repo directory
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// DUT to push the bundles to. They are built for its architecture (or
+    /// for this machine without it).
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// bundles to build (default: the configured tast_bundles, or cros). Can
+    /// be repeated.
+    #[argh(option)]
+    bundle: Vec<String>,
+
+    /// put back the bundles of the image on the DUT instead
+    #[argh(switch)]
+    restore: bool,
+}
+
+fn run_tast_build(args: &ArgsBuild) -> Result<()> {
+    if args.restore {
+        let dut = resolve_dut(&args.dut, None)?;
+        return restore_local_bundles(&SshInfo::new(&dut)?, &dut);
+    }
+    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    let config = Config::read()?;
+    let mut bundles: Vec<&str> = args.bundle.iter().map(String::as_str).collect();
+    if bundles.is_empty() {
+        bundles = config.tast_bundles();
+    }
+    if bundles.is_empty() {
+        bundles.push(DEFAULT_BUNDLE);
+    }
+    let dut = args
+        .dut
+        .as_ref()
+        .map(|dut| -> Result<(String, SshInfo)> {
+            ensure_testing_rsa_is_there()?;
+            let dut = resolve_dut(&Some(dut.clone()), None)?;
+            let ssh = SshInfo::new(&dut)?;
+            Ok((dut, ssh))
+        })
+        .transpose()?;
+    let goarch = dut.as_ref().map(|(_, ssh)| dut_goarch(ssh)).transpose()?;
+    for bundle in bundles {
+        let path = build_local_bundle(&chroot, bundle, goarch)?;
+        info!("Built the {bundle} bundle: {path:?}");
+        if let Some((dut, ssh)) = &dut {
+            push_local_bundle(ssh, dut, bundle, &path)?;
+        }
+    }
+    Ok(())
+}
+
 #[derive(FromArgs, PartialEq, Debug)]
 /// Get tast test for the target DUT
 #[argh(subcommand, name = "list")]
@@ -54,9 +190,19 @@ pub struct ArgsList {
     #[argh(option)]
     dut: Option<String>,
 
-    /// glob pattern of the listint test
-    #[argh(positional)]
-    tests: Option<String>,
+    /// glob pattern of the listint test, optionally preceded by the DUT
+    /// (a known DUT alone is also taken as the DUT)
+    #[argh(positional, arg_name = "[DUT] PATTERN")]
+    tests: Vec<String>,
+
+    /// attribute expression to filter the tests (e.g. "group:mainline &&
+    /// !informational")
+    #[argh(option)]
+    attr: Option<String>,
+
+    /// print the tests with their attributes as a JSON array
+    #[argh(switch)]
+    json: bool,
 
     /// only show cached list
     #[argh(switch)]
@@ -66,41 +212,29 @@ pub struct ArgsList {
     repo: Option<String>,
 }
 
-fn print_cached_tests_in_bundle(filter: &Pattern, bundle: &str) -> Result<()> {
-    if let Ok(Some(tests)) = TEST_CACHE.get(bundle) {
-        for t in &tests {
-            if filter.matches(t) {
-                println!("{t}");
-            }
-        }
-        return Ok(());
-    }
-    bail!("No cache found")
-}
-
-fn print_cached_tests(filter: &Pattern, bundles: &Vec<&str>) -> Result<()> {
-    // Ensure all bundles are cached.
+fn cached_tests(bundles: &Vec<&str>) -> Result<Vec<TastTestInfo>> {
+    let mut tests = Vec::new();
     for b in bundles {
-        if TEST_CACHE.get(b)?.is_none() {
-            bail!("No cache found for {b}.");
+        match TEST_INFO_CACHE.get(b)? {
+            Some(t) => tests.extend(t),
+            None => bail!("No cache found for {b}. Please rerun with --dut <DUT>"),
         }
     }
-    for b in bundles {
-        print_cached_tests_in_bundle(filter, b)?
-    }
-    Ok(())
+    Ok(tests)
 }
 
 fn update_cached_tests_in_bundle(bundle: &str, chroot: &Chroot, port: u16) -> Result<()> {
     let list = chroot.exec_in_chroot(&[
         "tast",
         "list",
+        "-json",
         "-installbuilddeps",
         &format!("--buildbundle={}", bundle),
         &format!("127.0.0.1:{}", port),
     ])?;
-    let tests: Vec<String> = list.lines().map(|s| s.to_string()).collect::<Vec<_>>();
-    TEST_CACHE.set(bundle, tests)?;
+    let tests = parse_tast_list(&list, bundle)?;
+    TEST_CACHE.set(bundle, tests.iter().map(|t| t.name.clone()).collect())?;
+    TEST_INFO_CACHE.set(bundle, tests)?;
     Ok(())
 }
 
@@ -116,10 +250,25 @@ fn update_cached_tests(bundles: &Vec<&str>, dut: &str, repodir: &str) -> Result<
     Ok(())
 }
 
+/// Prints a test per line with the tab separated name, description and
+/// attributes, so that the names can be cut out for the completion
+fn print_tests(tests: &[TastTestInfo]) {
+    for t in tests {
+        println!("{}\t{}\t{}", t.name, t.desc, t.attr.join(" "));
+    }
+}
+
 fn run_tast_list(args: &ArgsList) -> Result<()> {
-    let filter = args
-        .tests
-        .as_ref()
+    // `cro3 tast list DUT [PATTERN]` is the same as `cro3 tast list --dut DUT
+    // [PATTERN]`
+    let (dut, pattern) = match args.tests.as_slice() {
+        [dut, pattern] => (Some(dut.clone()), Some(pattern)),
+        [dut] if args.dut.is_none() && SSH_CACHE.get(dut)?.is_some() => (Some(dut.clone()), None),
+        [pattern] => (args.dut.clone(), Some(pattern)),
+        [] => (args.dut.clone(), None),
+        _ => bail!("Too many arguments: {}", args.tests.join(" ")),
+    };
+    let filter = pattern
         .map(|s| Pattern::new(s))
         .unwrap_or_else(|| Pattern::new("*"))?;
     let config = Config::read()?;
@@ -129,15 +278,25 @@ fn run_tast_list(args: &ArgsList) -> Result<()> {
     }
 
     if !args.cached {
-        let dut = args
-            .dut
-            .as_ref()
-            .expect("Test name is not cached. Please rerun with --dut <DUT>");
-
-        update_cached_tests(&bundles, dut, &get_cros_dir(&args.cros)?)?;
+        let dut = dut.context("Test name is not cached. Please rerun with --dut <DUT>")?;
+        update_cached_tests(&bundles, &dut, &get_cros_dir(&args.cros)?)?;
     }
 
-    print_cached_tests(&filter, &bundles)?;
+    let mut tests = Vec::new();
+    for t in cached_tests(&bundles)? {
+        let attr_matched = match &args.attr {
+            Some(expr) => attr_expr_matches(expr, &t.attr)?,
+            None => true,
+        };
+        if filter.matches(&t.name) && attr_matched {
+            tests.push(t);
+        }
+    }
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&tests)?);
+    } else {
+        print_tests(&tests);
+    }
 
     Ok(())
 }
@@ -150,18 +309,37 @@ pub struct ArgsRun {
     #[argh(option)]
     cros: Option<String>,
 
-    /// target DUT
+    /// target DUT (choose interactively if omitted)
     #[argh(option)]
-    dut: String,
+    dut: Option<String>,
 
     /// test options (e.g. "-var ...")
     #[argh(option)]
     option: Option<String>,
 
-    /// test name or pattern
+    /// use the test bundles on the DUT (e.g. pushed with `cro3 tast build`)
+    /// instead of building them from the checkout
+    #[argh(switch)]
+    prebuilt: bool,
+
+    /// DUT group (or DUTs separated by commas) of the same board to split the
+    /// matched tests across and run them in parallel
+    #[argh(option)]
+    shard_across: Option<String>,
+
+    /// rerun the failed tests up to this number of times. The tests which pass
+    /// on a retry are reported as flaky, not as failures.
+    #[argh(option, default = "0")]
+    retries: u32,
+
+    /// test name or pattern, or the DUT if a pattern follows
     #[argh(positional)]
     tests: String,
 
+    /// test name or pattern, if the DUT is given before it
+    #[argh(positional)]
+    pattern: Option<String>,
+
     #[argh(option, hidden_help)]
     repo: Option<String>,
 }
@@ -179,15 +357,17 @@ fn bundle_has_test(bundle: &str, filter: &Pattern) -> bool {
 
 fn run_test_with_bundle(
     bundle: &str,
-    filter: &Pattern,
+    tests: &str,
     chroot: &Chroot,
     port: u16,
     opt: Option<&str>,
+    results_dir: &str,
 ) -> Result<()> {
     chroot.run_bash_script_in_chroot(
         "tast_run_cmd",
         &format!(
-            "tast run -installbuilddeps -buildbundle={bundle} {} 127.0.0.1:{port} {filter}",
+            "tast run -installbuilddeps -buildbundle={bundle} -resultsdir={results_dir} {} \
+             127.0.0.1:{port} {tests}",
             opt.unwrap_or("")
         ),
         None,
@@ -195,37 +375,400 @@ fn run_test_with_bundle(
     Ok(())
 }
 
+/// Runs `tests` with the bundles on the DUT, and returns the results in
+/// `results_dir` (relative to ~/.cro3/results)
+fn run_prebuilt_tests(
+    tests: &str,
+    chroot: Option<&Chroot>,
+    port: u16,
+    opt: Option<&str>,
+    results_dir: &str,
+) -> Result<Vec<TestResult>> {
+    let Some(chroot) = chroot else {
+        // Without a checkout, run the tast installed on this machine
+        let tast = host_tast().context("tast is not found in the chroot nor in PATH")?;
+        let status = Command::new(tast)
+            .arg("run")
+            .arg("-build=false")
+            .arg(format!(
+                "-resultsdir={}",
+                TestRun::dir(results_dir)?.display()
+            ))
+            .args(opt.unwrap_or("").split_whitespace())
+            .arg(format!("127.0.0.1:{port}"))
+            .args(tests.split_whitespace())
+            .status()?;
+        if !status.success() {
+            bail!("tast run failed: {status}");
+        }
+        return read_tast_results(&TestRun::dir(results_dir)?);
+    };
+    chroot.run_bash_script_in_chroot(
+        "tast_run_cmd",
+        &format!(
+            "tast run -build=false -resultsdir=/cro3/results/{results_dir} {} 127.0.0.1:{port} \
+             {tests}",
+            opt.unwrap_or("")
+        ),
+        None,
+    )?;
+    read_tast_results(&TestRun::dir(results_dir)?)
+}
+
 fn run_tast_run(args: &ArgsRun) -> Result<()> {
     ensure_testing_rsa_is_there()?;
-    let filter = Pattern::new(&args.tests)?;
-    let repodir = get_cros_dir(&args.cros)?;
-    let chroot = Chroot::new(&repodir)?;
-    let ssh = SshInfo::new(&args.dut).context("failed to create SshInfo")?;
+    // `cro3 tast run DUT TESTS` is the same as `cro3 tast run --dut DUT TESTS`
+    let (dut, tests) = match &args.pattern {
+        Some(pattern) => (Some(args.tests.clone()), pattern),
+        None => (args.dut.clone(), &args.tests),
+    };
+    if let Some(group) = &args.shard_across {
+        if dut.is_some() {
+            bail!("--shard-across runs the tests on the DUTs in {group}. Please remove the DUT.");
+        }
+        let run = run_sharded(args, group, tests)?;
+        return print_run(&run);
+    }
+    let dut = resolve_dut(&dut, None)?;
+    let chroot = match get_cros_dir(&args.cros) {
+        Ok(repodir) => Some(Chroot::new(&repodir)?),
+        Err(_) if args.prebuilt && host_tast().is_some() => None,
+        Err(e) => return Err(e),
+    };
+    let ssh = SshInfo::new(&dut).context("failed to create SshInfo")?;
     // setup port forwarding for chroot.
     let ssh = ssh.into_forwarded()?;
     let opt = args.option.as_deref();
+    let run = if args.prebuilt {
+        run_prebuilt_tast_tests(chroot.as_ref(), &dut, &ssh, tests, opt, args.retries)?
+    } else {
+        let chroot = chroot.context("A cros checkout is needed to build the tests")?;
+        run_tast_tests(&chroot, &dut, &ssh, tests, opt, args.retries)?
+    };
+    print_run(&run)
+}
 
-    let mut matched = false;
+/// Runs the tests matching `tests` split across the DUTs in `group`, and
+/// records the results of all shards as one run
+fn run_sharded(args: &ArgsRun, group: &str, tests: &str) -> Result<TestRun> {
+    let duts = expand_dut_groups(&group.split(',').map(str::to_string).collect::<Vec<_>>())?;
+    if duts.is_empty() {
+        bail!("No DUTs to shard the tests across");
+    }
+    let mut boards = BTreeSet::new();
+    for dut in &duts {
+        boards.insert(infer_board(None, Some(dut))?);
+    }
+    if boards.len() > 1 {
+        bail!(
+            "The DUTs to shard the tests across must be of the same board, but {group} has {}",
+            boards.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+    let repodir = get_cros_dir(&args.cros)?;
+    let chroot = Chroot::new(&repodir)?;
     let config = Config::read()?;
     let mut bundles = config.tast_bundles();
     if bundles.is_empty() {
         bundles.push(DEFAULT_BUNDLE);
     }
+    if bundles
+        .iter()
+        .any(|b| !matches!(TEST_CACHE.get(b), Ok(Some(_))))
+    {
+        update_cached_tests(&bundles, &duts[0], &repodir)?;
+    }
+    if args.prebuilt {
+        return run_shards(args, group, tests, &duts, &chroot, &bundles);
+    }
+    warn_if_test_cache_is_stale(&repodir);
+    let result = run_shards(args, group, tests, &duts, &chroot, &bundles);
+    // Put back the bundles of the images even if the tests failed, since the
+    // bundles built from the checkout were pushed to all DUTs
+    for dut in &duts {
+        if let Err(e) = SshInfo::new(dut).and_then(|ssh| restore_local_bundles(&ssh, dut)) {
+            warn!("Failed to restore the test bundles on {dut}: {e:#}");
+        }
+    }
+    result
+}
 
-    for b in bundles {
-        if bundle_has_test(b, &filter) {
-            matched = true;
-            run_test_with_bundle(b, &filter, &chroot, ssh.port(), opt)?
+/// Warns if the tests were cached before the tast tests in `repodir` were
+/// updated, since the tests added after that are not matched
+fn warn_if_test_cache_is_stale(repodir: &str) {
+    let modified = |path: PathBuf| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let cached = gen_path_in_cro3_dir(TEST_CACHE_NAME)
+        .ok()
+        .and_then(modified);
+    let checkout = modified(Path::new(repodir).join("src/platform/tast-tests/.git/HEAD"));
+    if let (Some(cached), Some(checkout)) = (cached, checkout) {
+        if cached < checkout {
+            warn!(
+                "The cached tests are older than the checkout, so new tests may not be run. Run \
+                 `cro3 tast list --dut <DUT>` to update them."
+            );
         }
     }
+}
 
-    if !matched {
-        warn!(
-            "{0} did not match any cached tests. Run it with default bundle.",
-            args.tests
+/// Runs the tests matching `tests` split across `duts`, building the bundles
+/// and pushing them to the DUTs first unless --prebuilt is given
+fn run_shards(
+    args: &ArgsRun,
+    group: &str,
+    tests: &str,
+    duts: &[String],
+    chroot: &Chroot,
+    bundles: &[&str],
+) -> Result<TestRun> {
+    let filter = Pattern::new(tests)?;
+    let mut matched = Vec::new();
+    for b in bundles {
+        let names: Vec<String> = TEST_CACHE
+            .get(b)?
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|t| filter.matches(t))
+            .collect();
+        if names.is_empty() {
+            continue;
+        }
+        if !args.prebuilt {
+            // Build the bundle once for all DUTs, instead of building it for
+            // each shard in parallel
+            let goarch = dut_goarch(&SshInfo::new(&duts[0])?)?;
+            let path = build_local_bundle(chroot, b, Some(goarch))?;
+            for dut in duts {
+                push_local_bundle(&SshInfo::new(dut)?, dut, b, &path)?;
+            }
+        }
+        matched.extend(names);
+    }
+    if matched.is_empty() {
+        bail!("{tests} did not match any tests");
+    }
+    let shards = shard_tests(&matched, duts.len());
+    let mut run = TestRun::start("tast", group, tests)?.with_build_of(&SshInfo::new(&duts[0])?);
+    info!(
+        "Running {} tests across {} DUTs",
+        matched.len(),
+        shards.len()
+    );
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(shards.len())
+        .build()
+        .context("Failed to create a thread pool")?;
+    let results: Vec<(&String, usize, Result<Vec<TestResult>>, Duration)> = pool.install(|| {
+        duts.par_iter()
+            .zip(shards.par_iter())
+            .map(|(dut, shard)| {
+                let start = Instant::now();
+                let result = run_shard(chroot, dut, shard, args.option.as_deref(), &run.id);
+                if let Err(e) = &result {
+                    error!("{dut}: {e:#}");
+                }
+                (dut, shard.len(), result, start.elapsed())
+            })
+            .collect()
+    });
+    let width = duts.iter().map(|id| id.len()).max().unwrap_or_default();
+    println!("{:width$} {:>6} {:>6} RESULT", "DUT", "TESTS", "MIN");
+    let mut errors = Vec::new();
+    for (dut, n, result, elapsed) in results {
+        let status = match result {
+            Ok(results) => {
+                let failed = results
+                    .iter()
+                    .filter(|r| r.status == TestStatus::Failed)
+                    .count();
+                run.results.extend(results);
+                format!("ok: {failed} failed")
+            }
+            Err(e) => {
+                let status = format!("failed: {e:#}");
+                errors.push(dut.clone());
+                status
+            }
+        };
+        println!(
+            "{dut:width$} {n:>6} {:>6.1} {status}",
+            elapsed.as_secs_f64() / 60.0
         );
-        run_test_with_bundle(DEFAULT_BUNDLE, &filter, &chroot, ssh.port(), opt)?
     }
+    run.results.sort_by(|a, b| a.name.cmp(&b.name));
+    if !errors.is_empty() {
+        run.save()?;
+        bail!("Failed to run the tests on {}", errors.join(", "));
+    }
+    // Retry on one of the DUTs, since only a few tests fail usually
+    let id = run.id.clone();
+    run.retry_failures(args.retries, |tests, retry| {
+        run_shard(
+            chroot,
+            &duts[0],
+            tests,
+            args.option.as_deref(),
+            &format!("{id}/{retry}"),
+        )
+    })?;
+    run.save()?;
+    Ok(run)
+}
 
-    Ok(())
+/// Runs `tests` on `dut` with the bundles on it, with the results in a
+/// subdirectory of `results_dir` (relative to ~/.cro3/results) for the DUT
+fn run_shard(
+    chroot: &Chroot,
+    dut: &str,
+    tests: &[String],
+    opt: Option<&str>,
+    results_dir: &str,
+) -> Result<Vec<TestResult>> {
+    let ssh = SshInfo::new(dut)?.into_forwarded()?;
+    let name = dut.replace(|c: char| !c.is_ascii_alphanumeric(), "_");
+    chroot.run_bash_script_in_chroot(
+        &format!("tast_run_{name}"),
+        &format!(
+            "tast run -build=false -resultsdir=/cro3/results/{results_dir}/{name} {} 127.0.0.1:{} \
+             {}",
+            opt.unwrap_or(""),
+            ssh.port(),
+            tests.join(" ")
+        ),
+        None,
+    )?;
+    read_tast_results(&TestRun::dir(results_dir)?.join(name))
+}
+
+/// Returns the bundles which have the tests matching `tests`
+fn bundles_for(tests: &str) -> Result<Vec<String>> {
+    let filter = Pattern::new(tests)?;
+    let config = Config::read()?;
+    let mut bundles = config.tast_bundles();
+    if bundles.is_empty() {
+        bundles.push(DEFAULT_BUNDLE);
+    }
+    let mut bundles: Vec<String> = bundles
+        .into_iter()
+        .filter(|b| bundle_has_test(b, &filter))
+        .map(str::to_string)
+        .collect();
+    if bundles.is_empty() {
+        warn!("{tests} did not match any cached tests. Run it with default bundle.");
+        bundles.push(DEFAULT_BUNDLE.to_string());
+    }
+    Ok(bundles)
+}
+
+/// Runs `tests` with each of `bundles` that has them, with the results in
+/// RESULTS_DIR/BUNDLE (relative to ~/.cro3/results)
+fn run_with_bundles(
+    chroot: &Chroot,
+    ssh: &SshInfo,
+    bundles: &[String],
+    tests: &[String],
+    opt: Option<&str>,
+    results_dir: &str,
+) -> Result<Vec<TestResult>> {
+    let mut results = Vec::new();
+    for b in bundles {
+        let tests: Vec<&str> = tests
+            .iter()
+            .filter(|t| bundles.len() == 1 || Pattern::new(t).is_ok_and(|p| bundle_has_test(b, &p)))
+            .map(String::as_str)
+            .collect();
+        if tests.is_empty() {
+            continue;
+        }
+        run_test_with_bundle(
+            b,
+            &tests.join(" "),
+            chroot,
+            ssh.port(),
+            opt,
+            &format!("/cro3/results/{results_dir}/{b}"),
+        )?;
+        results.extend(read_tast_results(&TestRun::dir(results_dir)?.join(b))?);
+    }
+    Ok(results)
+}
+
+/// Runs the tests matching `tests` once on the DUT forwarded with `ssh`, with
+/// the results in `results_dir` (relative to ~/.cro3/results). Without
+/// `chroot`, `prebuilt` runs the tast in PATH.
+pub fn run_tast_once(
+    chroot: Option<&Chroot>,
+    ssh: &SshInfo,
+    tests: &str,
+    opt: Option<&str>,
+    prebuilt: bool,
+    results_dir: &str,
+) -> Result<Vec<TestResult>> {
+    if prebuilt {
+        return run_prebuilt_tests(tests, chroot, ssh.port(), opt, results_dir);
+    }
+    let chroot = chroot.context("A cros checkout is needed to build the tests")?;
+    run_with_bundles(
+        chroot,
+        ssh,
+        &bundles_for(tests)?,
+        &[tests.to_string()],
+        opt,
+        results_dir,
+    )
+}
+
+/// Runs the tests matching `tests` on the DUT forwarded with `ssh`, with the
+/// bundles that have them, and records the results. The failed tests are
+/// retried up to `retries` times.
+pub fn run_tast_tests(
+    chroot: &Chroot,
+    dut: &str,
+    ssh: &SshInfo,
+    tests: &str,
+    opt: Option<&str>,
+    retries: u32,
+) -> Result<TestRun> {
+    let bundles = bundles_for(tests)?;
+    let mut run = TestRun::start("tast", dut, tests)?.with_build_of(ssh);
+    let id = run.id.clone();
+    run.results = run_with_bundles(chroot, ssh, &bundles, &[tests.to_string()], opt, &id)?;
+    run.retry_failures(retries, |tests, retry| {
+        run_with_bundles(chroot, ssh, &bundles, tests, opt, &format!("{id}/{retry}"))
+    })?;
+    run.save()?;
+    Ok(run)
+}
+
+/// Runs the tests matching `tests` on the DUT forwarded with `ssh`, with the
+/// bundles on the DUT (see --prebuilt), and records the results. The failed
+/// tests are retried up to `retries` times.
+pub fn run_prebuilt_tast_tests(
+    chroot: Option<&Chroot>,
+    dut: &str,
+    ssh: &SshInfo,
+    tests: &str,
+    opt: Option<&str>,
+    retries: u32,
+) -> Result<TestRun> {
+    let mut run = TestRun::start("tast", dut, tests)?.with_build_of(ssh);
+    let id = run.id.clone();
+    run.results = run_prebuilt_tests(
+        tests,
+        chroot,
+        ssh.port(),
+        opt,
+        &format!("{id}/{PREBUILT_RESULTS}"),
+    )?;
+    run.retry_failures(retries, |tests, retry| {
+        run_prebuilt_tests(
+            &tests.join(" "),
+            chroot,
+            ssh.port(),
+            opt,
+            &format!("{id}/{retry}"),
+        )
+    })?;
+    run.save()?;
+    Ok(run)
 }
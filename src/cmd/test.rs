@@ -0,0 +1,1003 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! ## Run autotest tests
+//! `cro3 test autotest` runs autotest tests or suites on a DUT with test_that
+//! in the chroot. The results are shown and recorded under ~/.cro3/results in
+//! the same format as `cro3 tast run`.
+//! ```
+//! cro3 test autotest --cros ${CROS} ${DUT} suite:smoke
+//! cro3 test autotest --cros ${CROS} --dut ${DUT} dummy_Pass
+//! ```
+//!
+//! ## Export test results for CI
+//! Each run of `cro3 tast run` and `cro3 test autotest` is recorded as
+//! run.json and junit.xml in ~/.cro3/results/RUN_ID. `cro3 test export` writes
+//! them out for CI pipelines, for the latest run or the given one.
+//! ```
+//! cro3 test export --format junit --output junit.xml
+//! cro3 test export --format json tast-20231015-100000
+//! ```
+//!
+//! ## Share test results
+//! `cro3 test report` shows a recorded run, or writes it with `--html` as a
+//! self-contained HTML page with the summary, the status of each test, and
+//! the end of the logs and the screenshots of the tests which did not pass.
+//! ```
+//! cro3 test report tast-20231015-100000 --html out/
+//! ```
+//!
+//! ## Compare test results
+//! `cro3 test diff` compares two recorded runs (the latest two if omitted),
+//! e.g. before and after applying a CL, and shows new failures, fixed tests
+//! and the tests which became slower. It fails if there are new failures.
+//! ```
+//! cro3 test diff tast-20231015-100000 tast-20231015-110000
+//! cro3 test diff --threshold 50
+//! ```
+//!
+//! ## Compare performance metrics
+//! The metrics reported by the tests (results-chart.json and the perf keyvals
+//! of autotest) are recorded with each run. `cro3 test perf` shows them for a
+//! run, or compares them between two runs, e.g. on two builds flashed on the
+//! same DUT, with the percentage of the changes.
+//! ```
+//! cro3 test perf tast-20231015-100000
+//! cro3 test perf tast-20231015-100000 tast-20231015-110000 --threshold 5
+//! ```
+//!
+//! ## Measure the flakiness of a test
+//! `cro3 test flake` runs a tast test repeatedly, and reports the flake rate
+//! and the distribution of the durations. The logs of the failed iterations
+//! are bundled into failures.tar.gz to attach to a bug.
+//! ```
+//! cro3 test flake --cros ${CROS} ${DUT} example.Flaky --runs 50
+//! ```
+//!
+//! ## Stress test
+//! `cro3 test stress` runs tast tests (or autotest tests and suites with
+//! `--autotest`) in a loop until the deadline, or until they fail with
+//! `--until-failure`. On each failure, the system logs, the new crash reports
+//! and a screenshot of the DUT are saved with the results.
+//! ```
+//! cro3 test stress --cros ${CROS} ${DUT} --until-failure --max-hours 12 'example.*'
+//! cro3 test stress --cros ${CROS} ${DUT} --autotest --max-hours 1 suite:smoke
+//! ```
+//!
+//! ## Measure code coverage
+//! `cro3 test coverage` builds the packages with USE=coverage, deploys them
+//! and runs tast tests, and then collects the coverage data written on the
+//! DUT into an lcov file and an HTML report in the results directory. Daemons
+//! have to be restarted with `--restart` to write the data.
+//! ```
+//! cro3 test coverage --cros ${CROS} --dut ${DUT} --package shill --restart shill 'shill.*'
+//! ```
+//!
+//! ## Run gtest binaries
+//! `cro3 test gtest` runs a gtest binary on the DUT (or pushes it from this
+//! machine with `--push`) with the given arguments, and records the results
+//! like the other tests.
+//! ```
+//! cro3 test gtest ${DUT} /usr/local/libexec/tests/foo_unittest --gtest_filter='FooTest.*'
+//! cro3 test gtest --dut ${DUT} --push ./out/foo_unittest
+//! ```
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use argh::FromArgs;
+use cro3::autotest::read_autotest_results;
+use cro3::build::build_packages;
+use cro3::build::BuildOptions;
+use cro3::build::BuildOverrides;
+use cro3::chroot::Chroot;
+use cro3::coverage::coverage_report_script;
+use cro3::coverage::format_coverage_table;
+use cro3::coverage::llvm_profile_file;
+use cro3::coverage::parse_lcov;
+use cro3::coverage::DUT_PROFILE_DIR;
+use cro3::cros::ensure_testing_rsa_is_there;
+use cro3::deploy::deploy_packages;
+use cro3::deploy::ensure_rootfs_writable;
+use cro3::dut::infer_board;
+use cro3::dut::resolve_dut;
+use cro3::dut::SshInfo;
+use cro3::dut_crash::list_crashes;
+use cro3::gtest::parse_gtest_json;
+use cro3::gtest::parse_gtest_output;
+use cro3::perf::compare_perf;
+use cro3::progress::format_duration;
+use cro3::repo::get_cros_dir;
+use cro3::test_report::to_html_report;
+use cro3::test_results::diff_runs;
+use cro3::test_results::flake_stats;
+use cro3::test_results::format_flake_report;
+use cro3::test_results::format_results_table;
+use cro3::test_results::reserve_run_id;
+use cro3::test_results::to_junit_xml;
+use cro3::test_results::TestResult;
+use cro3::test_results::TestRun;
+use cro3::test_results::TestStatus;
+use cro3::util::shell_helpers::shell_quote;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+use crate::cmd::tast::run_tast_once;
+use crate::cmd::tast::run_tast_tests;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// run tests and manage their results
+#[argh(subcommand, name = "test")]
+pub struct Args {
+    #[argh(subcommand)]
+    nested: SubCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum SubCommand {
+    Autotest(ArgsAutotest),
+    Export(ArgsExport),
+    Diff(ArgsDiff),
+    Perf(ArgsPerf),
+    Flake(ArgsFlake),
+    Stress(ArgsStress),
+    Coverage(ArgsCoverage),
+    Gtest(ArgsGtest),
+    Report(ArgsReport),
+}
+
+#[tracing::instrument(level = "trace")]
+pub fn run(args: &Args) -> Result<()> {
+    match &args.nested {
+        SubCommand::Autotest(args) => run_autotest(args),
+        SubCommand::Export(args) => run_export(args),
+        SubCommand::Diff(args) => run_diff(args),
+        SubCommand::Perf(args) => run_perf(args),
+        SubCommand::Flake(args) => run_flake(args),
+        SubCommand::Stress(args) => run_stress(args),
+        SubCommand::Coverage(args) => run_coverage(args),
+        SubCommand::Gtest(args) => run_gtest(args),
+        SubCommand::Report(args) => run_report(args),
+    }
+}
+
+/// Prints the results of a run, and fails if any test failed
+pub fn print_run(run: &TestRun) -> Result<()> {
+    print!("{}", format_results_table(&run.results));
+    println!("{}", run.summary());
+    println!("Results: {}", TestRun::dir(&run.id)?.display());
+    if let Some(report) = format_flake_report(run)? {
+        println!("Flaky tests (not counted as failures):");
+        print!("{report}");
+    }
+    let failed = run.failed();
+    if !failed.is_empty() {
+        bail!("Failed tests: {}", failed.join(" "));
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// run autotest tests or suites with test_that
+#[argh(subcommand, name = "autotest")]
+pub struct ArgsAutotest {
+    /// target cros repo directory
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target DUT
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// board of the DUT (default: inferred from the DUT)
+    #[argh(option)]
+    board: Option<String>,
+
+    /// arguments for the tests (passed to test_that --args)
+    #[argh(option)]
+    args: Option<String>,
+
+    /// tests or suites (e.g. suite:smoke) to run, preceded by the DUT if
+    /// --dut is not given
+    #[argh(positional, arg_name = "[DUT] TEST")]
+    tests: Vec<String>,
+}
+
+fn run_autotest(args: &ArgsAutotest) -> Result<()> {
+    ensure_testing_rsa_is_there()?;
+    let (dut, tests) = match (&args.dut, args.tests.as_slice()) {
+        (Some(_), tests) => (args.dut.clone(), tests),
+        (None, [dut, tests @ ..]) if !tests.is_empty() => (Some(dut.clone()), tests),
+        (None, tests) => (None, tests),
+    };
+    if tests.is_empty() {
+        bail!("Please specify the tests or suites to run");
+    }
+    let dut = resolve_dut(&dut, args.board.as_deref())?;
+    let board = infer_board(args.board.as_deref(), Some(&dut))?;
+    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    let ssh = SshInfo::new(&dut)?.into_forwarded()?;
+
+    let tests = tests.join(" ");
+    let mut run = TestRun::start("autotest", &dut, &tests)?.with_build_of(&ssh);
+    run.results = run_autotest_once(
+        &chroot,
+        &ssh,
+        &board,
+        &tests,
+        args.args.as_deref(),
+        &format!("{}/autotest", run.id),
+    )?;
+    run.save()?;
+    print_run(&run)
+}
+
+/// Runs autotest `tests` with test_that on the DUT forwarded with `ssh`, with
+/// the results in `results_dir` (relative to ~/.cro3/results)
+fn run_autotest_once(
+    chroot: &Chroot,
+    ssh: &SshInfo,
+    board: &str,
+    tests: &str,
+    test_args: Option<&str>,
+    results_dir: &str,
+) -> Result<Vec<TestResult>> {
+    let test_args = test_args
+        .map(|a| format!("--args='{a}'"))
+        .unwrap_or_default();
+    // test_that exits with an error if any test failed, which is reported
+    // with the results
+    chroot.run_bash_script_in_chroot(
+        "autotest_run_cmd",
+        &format!(
+            "test_that --board={board} --results_dir=/cro3/results/{results_dir} {test_args} \
+             127.0.0.1:{} {tests} || true",
+            ssh.port()
+        ),
+        None,
+    )?;
+    let results = read_autotest_results(&TestRun::dir(results_dir)?)?;
+    if results.is_empty() {
+        bail!("No results of {tests} were found. Please check the output of test_that.");
+    }
+    Ok(results)
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// write the results of a test run as JUnit XML or JSON
+#[argh(subcommand, name = "export")]
+pub struct ArgsExport {
+    /// format of the results: junit or json (default: junit)
+    #[argh(option, default = "String::from(\"junit\")")]
+    format: String,
+
+    /// file to write the results to (default: stdout)
+    #[argh(option)]
+    output: Option<String>,
+
+    /// ID of the run (default: the latest run)
+    #[argh(positional)]
+    run: Option<String>,
+}
+
+fn run_export(args: &ArgsExport) -> Result<()> {
+    let run = TestRun::load_or_latest(args.run.as_deref())?;
+    let exported = match args.format.as_str() {
+        "junit" => to_junit_xml(&run),
+        "json" => serde_json::to_string_pretty(&run)? + "\n",
+        format => bail!("Unknown format: {format}. Please specify junit or json."),
+    };
+    match &args.output {
+        Some(path) => fs::write(path, exported).context(format!("Failed to write {path}")),
+        None => {
+            print!("{exported}");
+            Ok(())
+        }
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// compare the results of two test runs
+#[argh(subcommand, name = "diff")]
+pub struct ArgsDiff {
+    /// percentage of the increase of the duration to report a test as slower
+    /// (default: 20)
+    #[argh(option, default = "20.0")]
+    threshold: f64,
+
+    /// seconds of the increase of the duration to report a test as slower, to
+    /// ignore the noise of short tests (default: 5)
+    #[argh(option, default = "5.0")]
+    min_secs: f64,
+
+    /// IDs of the runs to compare (default: the latest two runs)
+    #[argh(positional, arg_name = "RUN")]
+    runs: Vec<String>,
+}
+
+fn print_section(title: &str, lines: &[String]) {
+    if lines.is_empty() {
+        return;
+    }
+    println!("{title} ({}):", lines.len());
+    for l in lines {
+        println!("  {l}");
+    }
+}
+
+fn run_diff(args: &ArgsDiff) -> Result<()> {
+    let ids = match args.runs.as_slice() {
+        [a, b] => vec![a.clone(), b.clone()],
+        [] => {
+            let ids = TestRun::list()?;
+            if ids.len() < 2 {
+                bail!("At least two test runs are needed to compare");
+            }
+            ids[ids.len() - 2..].to_vec()
+        }
+        _ => bail!("Please specify two runs to compare"),
+    };
+    let a = TestRun::load(&ids[0])?;
+    let b = TestRun::load(&ids[1])?;
+    println!("A: {} ({}): {}", a.id, a.dut, a.summary());
+    println!("B: {} ({}): {}", b.id, b.dut, b.summary());
+    let diff = diff_runs(&a, &b, args.threshold / 100.0, args.min_secs);
+    let new_failures: Vec<String> = diff
+        .new_failures
+        .iter()
+        .map(|name| {
+            let error = b
+                .results
+                .iter()
+                .find(|r| &r.name == name)
+                .and_then(|r| r.error.as_deref())
+                .and_then(|e| e.lines().next())
+                .unwrap_or_default();
+            format!("{name}  {error}")
+        })
+        .collect();
+    print_section("New failures", &new_failures);
+    print_section("Fixed", &diff.fixed);
+    print_section(
+        "Slower",
+        &diff
+            .slower
+            .iter()
+            .map(|(name, before, after)| {
+                format!(
+                    "{name}  {} -> {} (+{:.0}%)",
+                    format_duration(*before),
+                    format_duration(*after),
+                    (after / before.max(0.001) - 1.0) * 100.0
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    print_section("Only in A", &diff.removed);
+    print_section("Only in B", &diff.added);
+    if !diff.new_failures.is_empty() {
+        bail!("{} new failures in {}", diff.new_failures.len(), b.id);
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show or compare the performance metrics of test runs
+#[argh(subcommand, name = "perf")]
+pub struct ArgsPerf {
+    /// percentage of a change to report it as a regression (default: 0)
+    #[argh(option, default = "0.0")]
+    threshold: f64,
+
+    /// IDs of the runs: one to show its metrics, or two to compare them
+    /// (default: the latest run)
+    #[argh(positional, arg_name = "RUN")]
+    runs: Vec<String>,
+}
+
+fn run_perf(args: &ArgsPerf) -> Result<()> {
+    let describe = |run: &TestRun| {
+        format!(
+            "{} ({}{})",
+            run.id,
+            run.dut,
+            run.build
+                .as_ref()
+                .map(|b| format!(", {b}"))
+                .unwrap_or_default()
+        )
+    };
+    let (a, b) = match args.runs.as_slice() {
+        [] => (TestRun::load_or_latest(None)?, None),
+        [a] => (TestRun::load(a)?, None),
+        [a, b] => (TestRun::load(a)?, Some(TestRun::load(b)?)),
+        _ => bail!("Please specify one or two runs"),
+    };
+    let Some(b) = b else {
+        println!("{}", describe(&a));
+        if a.perf.is_empty() {
+            println!("No performance metrics are recorded");
+        }
+        for m in &a.perf {
+            println!("{}\t{}\t{} {}", m.test, m.name, m.value, m.units);
+        }
+        return Ok(());
+    };
+    println!("A: {}", describe(&a));
+    println!("B: {}", describe(&b));
+    let deltas = compare_perf(&a.perf, &b.perf);
+    if deltas.is_empty() {
+        bail!("No performance metrics to compare");
+    }
+    let mut regressions = 0;
+    for d in &deltas {
+        let percent = d
+            .percent
+            .map(|p| format!("{p:+.1}%"))
+            .unwrap_or("-".to_string());
+        let significant = d.percent.map_or(true, |p| p.abs() >= args.threshold);
+        let verdict = match d.regressed {
+            Some(true) if significant => {
+                regressions += 1;
+                "  REGRESSED"
+            }
+            Some(false) if significant && d.before != d.after => "  improved",
+            _ => "",
+        };
+        println!(
+            "{}\t{}\t{} -> {} {}\t{percent}{verdict}",
+            d.test, d.name, d.before, d.after, d.units
+        );
+    }
+    println!("{} metrics compared, {regressions} regressed", deltas.len());
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// run a tast test repeatedly to measure its flakiness
+#[argh(subcommand, name = "flake")]
+pub struct ArgsFlake {
+    /// target cros repo directory
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target DUT
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// number of the runs (default: 10)
+    #[argh(option, default = "10")]
+    runs: u32,
+
+    /// test options (e.g. "-var ...")
+    #[argh(option)]
+    option: Option<String>,
+
+    /// use the test bundles on the DUT instead of building them from the
+    /// checkout (see `cro3 tast run --prebuilt`)
+    #[argh(switch)]
+    prebuilt: bool,
+
+    /// test name or pattern, preceded by the DUT if --dut is not given
+    #[argh(positional, arg_name = "[DUT] TEST")]
+    tests: Vec<String>,
+}
+
+fn run_flake(args: &ArgsFlake) -> Result<()> {
+    ensure_testing_rsa_is_there()?;
+    let (dut, tests) = match (&args.dut, args.tests.as_slice()) {
+        (Some(_), [tests]) => (args.dut.clone(), tests),
+        (None, [dut, tests]) => (Some(dut.clone()), tests),
+        (None, [tests]) => (None, tests),
+        _ => bail!("Please specify a test to run"),
+    };
+    let dut = resolve_dut(&dut, None)?;
+    let chroot = match get_cros_dir(&args.cros) {
+        Ok(repodir) => Some(Chroot::new(&repodir)?),
+        Err(_) if args.prebuilt => None,
+        Err(e) => return Err(e),
+    };
+    let ssh = SshInfo::new(&dut)?.into_forwarded()?;
+    let id = reserve_run_id("flake")?;
+    let dir = TestRun::dir(&id)?;
+    let mut iterations: Vec<Vec<TestResult>> = Vec::new();
+    let mut errors = 0;
+    for i in 1..=args.runs {
+        info!("Run {i}/{}", args.runs);
+        match run_tast_once(
+            chroot.as_ref(),
+            &ssh,
+            tests,
+            args.option.as_deref(),
+            args.prebuilt,
+            &format!("{id}/run{i}"),
+        ) {
+            Ok(results) => iterations.push(results),
+            Err(e) => {
+                error!("Run {i} failed: {e:#}");
+                errors += 1;
+                iterations.push(Vec::new());
+            }
+        }
+    }
+    let stats = flake_stats(&iterations);
+    fs::write(
+        dir.join("flake.json"),
+        serde_json::to_string_pretty(&stats)?,
+    )?;
+
+    println!(
+        "{:40} {:>5} {:>5} {:>6} {:>8} {:>8} {:>8} {:>8}",
+        "TEST", "RUNS", "FAIL", "RATE", "MIN", "MEDIAN", "P90", "MAX"
+    );
+    for s in &stats {
+        println!(
+            "{:40} {:>5} {:>5} {:>5.1}% {:>8} {:>8} {:>8} {:>8}",
+            s.name,
+            s.runs,
+            s.failed_iterations.len(),
+            s.flake_rate(),
+            format_duration(s.percentile(0.0)),
+            format_duration(s.percentile(50.0)),
+            format_duration(s.percentile(90.0)),
+            format_duration(s.percentile(100.0))
+        );
+    }
+    if errors > 0 {
+        println!("{errors} runs failed to run the tests");
+    }
+    let mut failed: Vec<u32> = stats
+        .iter()
+        .flat_map(|s| s.failed_iterations.clone())
+        .collect();
+    failed.sort();
+    failed.dedup();
+    if !failed.is_empty() {
+        let status = Command::new("tar")
+            .arg("czf")
+            .arg(dir.join("failures.tar.gz"))
+            .arg("-C")
+            .arg(&dir)
+            .args(failed.iter().map(|i| format!("run{i}")))
+            .status()?;
+        if !status.success() {
+            bail!("Failed to bundle the logs of the failed runs");
+        }
+        println!(
+            "Logs of the failed runs: {}",
+            dir.join("failures.tar.gz").display()
+        );
+    }
+    println!("Results: {}", dir.display());
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// run tests in a loop and capture the state of the DUT on failures
+#[argh(subcommand, name = "stress")]
+pub struct ArgsStress {
+    /// target cros repo directory
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target DUT
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// stop at the first failure
+    #[argh(switch)]
+    until_failure: bool,
+
+    /// hours to run the tests for (default: 12)
+    #[argh(option, default = "12.0")]
+    max_hours: f64,
+
+    /// run autotest tests or suites with test_that instead of tast tests
+    #[argh(switch)]
+    autotest: bool,
+
+    /// test options (e.g. "-var ..." for tast, or the --args of test_that)
+    #[argh(option)]
+    option: Option<String>,
+
+    /// use the tast test bundles on the DUT instead of building them from the
+    /// checkout (see `cro3 tast run --prebuilt`)
+    #[argh(switch)]
+    prebuilt: bool,
+
+    /// test name, pattern or suite, preceded by the DUT if --dut is not given
+    #[argh(positional, arg_name = "[DUT] TEST")]
+    tests: Vec<String>,
+}
+
+/// Saves the system logs, the crash reports which are not in `known_crashes`
+/// and a screenshot of the DUT into `dir`. The captured crash reports are
+/// added to `known_crashes`.
+fn capture_dut_state(ssh: &SshInfo, dir: &Path, known_crashes: &mut BTreeSet<String>) {
+    let dest = dir.to_string_lossy().to_string();
+    if let Err(e) = fs::create_dir_all(dir) {
+        warn!("Failed to create {dest}: {e}");
+        return;
+    }
+    let remote = "/tmp/cro3_stress";
+    if let Err(e) = ssh.run_cmd_stdio(&format!(
+        "rm -rf {remote} && mkdir -p {remote} && (screenshot {remote}/screenshot.png || true) && \
+         tar czf {remote}/var_log.tar.gz -C /var log 2>/dev/null; true"
+    )) {
+        warn!("Failed to capture the state of the DUT: {e:#}");
+        return;
+    }
+    // The screenshot is not available if the display is off
+    for file in ["var_log.tar.gz", "screenshot.png"] {
+        if let Err(e) = ssh.get_files(&[format!("{remote}/{file}")], Some(&dest)) {
+            warn!("Failed to pull {file}: {e:#}");
+        }
+    }
+    let new_crashes: Vec<_> = list_crashes(ssh)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|c| !known_crashes.contains(&c.name))
+        .collect();
+    let crashes: Vec<String> = new_crashes.iter().flat_map(|c| c.paths()).collect();
+    known_crashes.extend(new_crashes.into_iter().map(|c| c.name));
+    if !crashes.is_empty() {
+        let crash_dir = dir.join("crash").to_string_lossy().to_string();
+        if let Err(e) = fs::create_dir_all(&crash_dir)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| ssh.get_files(&crashes, Some(&crash_dir)))
+        {
+            warn!("Failed to pull the crash reports: {e:#}");
+        }
+    }
+    ssh.run_cmd_stdio(&format!("rm -rf {remote}")).ok();
+}
+
+fn run_stress(args: &ArgsStress) -> Result<()> {
+    ensure_testing_rsa_is_there()?;
+    let (dut, tests) = match (&args.dut, args.tests.as_slice()) {
+        (Some(_), tests) => (args.dut.clone(), tests),
+        (None, [dut, tests @ ..]) if !tests.is_empty() => (Some(dut.clone()), tests),
+        (None, tests) => (None, tests),
+    };
+    if tests.is_empty() {
+        bail!("Please specify the tests to run");
+    }
+    let tests = tests.join(" ");
+    let dut = resolve_dut(&dut, None)?;
+    let chroot = match get_cros_dir(&args.cros) {
+        Ok(repodir) => Some(Chroot::new(&repodir)?),
+        Err(_) if args.prebuilt && !args.autotest => None,
+        Err(e) => return Err(e),
+    };
+    let ssh = SshInfo::new(&dut)?.into_forwarded()?;
+    let board = if args.autotest {
+        infer_board(None, Some(&dut))?
+    } else {
+        String::new()
+    };
+    let mut known_crashes: BTreeSet<String> = list_crashes(&ssh)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|c| c.name)
+        .collect();
+    let id = reserve_run_id("stress")?;
+    let dir = TestRun::dir(&id)?;
+    let deadline = Duration::from_secs_f64(args.max_hours * 3600.0);
+    let start = Instant::now();
+    let mut iterations = 0;
+    let mut failures: Vec<(u32, String)> = Vec::new();
+    while start.elapsed() < deadline {
+        iterations += 1;
+        info!(
+            "Iteration {iterations} ({} elapsed, {} failures)",
+            format_duration(start.elapsed().as_secs_f64()),
+            failures.len()
+        );
+        let results_dir = format!("{id}/iter{iterations}");
+        let results = if args.autotest {
+            let chroot = chroot.as_ref().context("A cros checkout is needed")?;
+            run_autotest_once(
+                chroot,
+                &ssh,
+                &board,
+                &tests,
+                args.option.as_deref(),
+                &results_dir,
+            )
+        } else {
+            run_tast_once(
+                chroot.as_ref(),
+                &ssh,
+                &tests,
+                args.option.as_deref(),
+                args.prebuilt,
+                &results_dir,
+            )
+        };
+        let failure = match results {
+            Ok(results) => {
+                let failed: Vec<&str> = results
+                    .iter()
+                    .filter(|r| r.status == TestStatus::Failed)
+                    .map(|r| r.name.as_str())
+                    .collect();
+                (!failed.is_empty()).then(|| failed.join(" "))
+            }
+            Err(e) => Some(format!("{e:#}")),
+        };
+        let Some(failure) = failure else {
+            continue;
+        };
+        error!("Iteration {iterations} failed: {failure}");
+        let capture = dir.join(format!("iter{iterations}")).join("capture");
+        capture_dut_state(&ssh, &capture, &mut known_crashes);
+        info!("Captured the state of the DUT to {}", capture.display());
+        failures.push((iterations, failure));
+        if args.until_failure {
+            break;
+        }
+    }
+    println!(
+        "{iterations} iterations in {}, {} failed",
+        format_duration(start.elapsed().as_secs_f64()),
+        failures.len()
+    );
+    for (i, failure) in &failures {
+        println!(
+            "  iteration {i}: {failure}\n    {}",
+            dir.join(format!("iter{i}")).join("capture").display()
+        );
+    }
+    println!("Results: {}", dir.display());
+    if !failures.is_empty() {
+        bail!("{} of {iterations} iterations failed", failures.len());
+    }
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// measure the code coverage of packages by tast tests
+#[argh(subcommand, name = "coverage")]
+pub struct ArgsCoverage {
+    /// target cros repo directory
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target DUT
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// target board (default: the board of the DUT)
+    #[argh(option)]
+    board: Option<String>,
+
+    /// packages to measure the coverage of. Can be repeated.
+    #[argh(option)]
+    package: Vec<String>,
+
+    /// upstart jobs to restart before and after the tests, so that the
+    /// daemons of the packages write the coverage data (e.g. shill). Can be
+    /// repeated.
+    #[argh(option)]
+    restart: Vec<String>,
+
+    /// use the packages deployed to the DUT before, instead of building and
+    /// deploying them with USE=coverage
+    #[argh(switch)]
+    no_build: bool,
+
+    /// test options (e.g. "-var ...")
+    #[argh(option)]
+    option: Option<String>,
+
+    /// test name or pattern, preceded by the DUT if --dut is not given
+    #[argh(positional, arg_name = "[DUT] TEST")]
+    tests: Vec<String>,
+}
+
+fn restart_jobs(ssh: &SshInfo, jobs: &[String]) {
+    for job in jobs {
+        ssh.run_cmd_stdio(&format!("restart {job} || start {job}"))
+            .map_err(|e| warn!("Failed to restart {job}: {e:#}"))
+            .ok();
+    }
+}
+
+fn run_coverage(args: &ArgsCoverage) -> Result<()> {
+    if args.package.is_empty() {
+        bail!("Please specify the packages to measure with --package");
+    }
+    ensure_testing_rsa_is_there()?;
+    let (dut, tests) = match (&args.dut, args.tests.as_slice()) {
+        (Some(_), tests) => (args.dut.clone(), tests),
+        (None, [dut, tests @ ..]) if !tests.is_empty() => (Some(dut.clone()), tests),
+        (None, tests) => (None, tests),
+    };
+    if tests.is_empty() {
+        bail!("Please specify the tests to run");
+    }
+    let tests = tests.join(" ");
+    let dut = resolve_dut(&dut, args.board.as_deref())?;
+    let board = infer_board(args.board.as_deref(), Some(&dut))?;
+    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    let ssh = SshInfo::new(&dut)?.into_forwarded()?;
+    if !args.no_build {
+        build_packages(
+            &chroot,
+            &board,
+            &args.package,
+            &BuildOptions {
+                workon: true,
+                remoteexec: None,
+                profile: false,
+                overrides: BuildOverrides::new(Some("coverage"), None)?,
+            },
+        )?;
+        ensure_rootfs_writable(&ssh)?;
+        for r in deploy_packages(&chroot, &board, &ssh, &args.package, false) {
+            if !r.status.is_deployed() {
+                bail!("Failed to deploy {}: {}", r.package, r.status);
+            }
+        }
+    }
+
+    ssh.run_cmd_stdio(&format!(
+        "rm -rf {DUT_PROFILE_DIR} && mkdir -p -m 1777 {DUT_PROFILE_DIR} && initctl set-env \
+         --global LLVM_PROFILE_FILE={}",
+        llvm_profile_file()
+    ))?;
+    restart_jobs(&ssh, &args.restart);
+    let run = run_tast_tests(&chroot, &dut, &ssh, &tests, args.option.as_deref(), 0);
+    // The profiles are written when the processes exit
+    ssh.run_cmd_stdio("initctl unset-env --global LLVM_PROFILE_FILE")?;
+    restart_jobs(&ssh, &args.restart);
+    let run = run?;
+
+    let dir = TestRun::dir(&run.id)?.join("coverage");
+    let profraw = dir.join("profraw");
+    fs::create_dir_all(&profraw)?;
+    ssh.get_files(
+        &[format!("{DUT_PROFILE_DIR}/*.profraw")],
+        Some(&profraw.to_string_lossy().to_string()),
+    )
+    .context(
+        "No coverage data was written. Are the packages built with USE=coverage, and are their \
+         daemons restarted with --restart?",
+    )?;
+    chroot.run_bash_script_in_chroot(
+        "coverage_report",
+        &coverage_report_script(
+            &board,
+            &args.package,
+            &format!("/cro3/results/{}/coverage", run.id),
+        ),
+        None,
+    )?;
+    let files = parse_lcov(&fs::read_to_string(dir.join("coverage.lcov"))?);
+    print!("{}", format_coverage_table(&files));
+    println!("{}", run.summary());
+    println!("lcov: {}", dir.join("coverage.lcov").display());
+    println!("HTML: {}", dir.join("html").join("index.html").display());
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// run a gtest binary on a DUT and record its results
+#[argh(subcommand, name = "gtest")]
+pub struct ArgsGtest {
+    /// target DUT
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// push BINARY from this machine to the DUT instead of running the one on
+    /// the DUT
+    #[argh(switch)]
+    push: bool,
+
+    /// path of the gtest binary and its arguments (e.g. --gtest_filter=...),
+    /// preceded by the DUT if --dut is not given
+    #[argh(positional, greedy, arg_name = "[DUT] BINARY [ARGS...]")]
+    args: Vec<String>,
+}
+
+/// Directory on the DUT to run gtest binaries in. /tmp can not be used since
+/// it is mounted noexec.
+const DUT_GTEST_DIR: &str = "/usr/local/tmp/cro3_gtest";
+
+fn run_gtest(args: &ArgsGtest) -> Result<()> {
+    ensure_testing_rsa_is_there()?;
+    let (dut, binary, test_args) = match (&args.dut, args.args.as_slice()) {
+        (None, [dut, binary, rest @ ..]) if !dut.contains('/') => (Some(dut.clone()), binary, rest),
+        (_, [binary, rest @ ..]) => (args.dut.clone(), binary, rest),
+        _ => bail!("Please specify the gtest binary to run"),
+    };
+    let dut = resolve_dut(&dut, None)?;
+    let ssh = SshInfo::new(&dut)?;
+    let binary = if args.push {
+        let name = Path::new(binary)
+            .file_name()
+            .context("Invalid path of the binary")?
+            .to_string_lossy()
+            .to_string();
+        let dest = format!("{DUT_GTEST_DIR}/bin");
+        ssh.send_files(&[binary.clone()], Some(&dest))?;
+        format!("{dest}/{name}")
+    } else {
+        binary.clone()
+    };
+    let name = binary.rsplit('/').next().unwrap_or(&binary).to_string();
+
+    let mut run = TestRun::start("gtest", &dut, &name)?.with_build_of(&ssh);
+    let out = format!("{DUT_GTEST_DIR}/out");
+    let test_args: Vec<String> = test_args.iter().map(|a| shell_quote(a)).collect();
+    info!("Running {binary} on {dut}...");
+    // The binary exits with an error if any test failed, which is reported
+    // with the results
+    ssh.run_cmd_stdio(&format!(
+        "rm -rf {out} && mkdir -p {out} && cd {out} && ({} {} \
+         --gtest_output=json:{out}/report.json > {out}/output.log 2>&1 || true)",
+        shell_quote(&binary),
+        test_args.join(" ")
+    ))?;
+    let dir = TestRun::dir(&run.id)?;
+    ssh.get_files(
+        &[format!("{out}/*")],
+        Some(&dir.to_string_lossy().to_string()),
+    )?;
+    let log = dir.join("output.log");
+    run.results = match fs::read_to_string(dir.join("report.json")) {
+        Ok(json) => parse_gtest_json(&json, &log)?,
+        Err(_) => parse_gtest_output(&fs::read_to_string(&log).unwrap_or_default(), &log),
+    };
+    if run.results.is_empty() {
+        run.results.push(TestResult {
+            name: name.clone(),
+            status: TestStatus::Failed,
+            duration: 0.0,
+            error: Some("No tests were run".to_string()),
+            log: Some(log),
+            attempts: 1,
+        });
+    }
+    run.save()?;
+    print_run(&run)
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// show a recorded test run, or write it as an HTML report
+#[argh(subcommand, name = "report")]
+pub struct ArgsReport {
+    /// directory to write the HTML report (index.html) to
+    #[argh(option)]
+    html: Option<String>,
+
+    /// ID of the run (default: the latest run)
+    #[argh(positional)]
+    run: Option<String>,
+}
+
+fn run_report(args: &ArgsReport) -> Result<()> {
+    let run = TestRun::load_or_latest(args.run.as_deref())?;
+    let Some(dir) = &args.html else {
+        print!("{}", format_results_table(&run.results));
+        println!("{}", run.summary());
+        return Ok(());
+    };
+    fs::create_dir_all(dir).context(format!("Failed to create {dir}"))?;
+    let path = Path::new(dir).join("index.html");
+    fs::write(&path, to_html_report(&run)).context(format!("Failed to write {path:?}"))?;
+    println!("Wrote the report of {} to {}", run.id, path.display());
+    Ok(())
+}
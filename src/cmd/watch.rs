@@ -0,0 +1,182 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! ## Rebuild and redeploy on source changes
+//! `cro3 watch` watches the source directories of the packages, and builds
+//! and deploys them to the DUT each time a file is saved. The directories are
+//! found with cros_workon (narrowed down to CROS_WORKON_SUBTREE), or can be
+//! given with `--path`. The DUT is not rebooted, so use `--restart` to restart
+//! the service of the package after each deploy.
+//! ```
+//! cro3 watch --cros ${CROS} --dut ${DUT} --package shill --restart shill
+//! cro3 watch --cros ${CROS} --dut ${DUT} --package ${PACKAGE_NAME} --path ${CROS}/src/platform2/foo
+//! ```
+
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::bail;
+use anyhow::Result;
+use argh::FromArgs;
+use cro3::build::build_packages;
+use cro3::build::BuildOptions;
+use cro3::chroot::Chroot;
+use cro3::cros::ensure_testing_rsa_is_there;
+use cro3::deploy::deploy_packages;
+use cro3::deploy::ensure_rootfs_writable;
+use cro3::dut::infer_board;
+use cro3::dut::resolve_dut;
+use cro3::dut::SshInfo;
+use cro3::progress::format_duration;
+use cro3::repo::get_cros_dir;
+use cro3::watch::changed_files;
+use cro3::watch::package_source_dirs;
+use cro3::watch::take_snapshot;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// rebuild and redeploy packages to a DUT when their sources change
+#[argh(subcommand, name = "watch")]
+pub struct Args {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target DUT (choose interactively if omitted)
+    #[argh(option)]
+    dut: Option<String>,
+
+    /// board of the packages (default: the board of the DUT)
+    #[argh(option)]
+    board: Option<String>,
+
+    /// packages to build and deploy. Can be repeated.
+    #[argh(option)]
+    package: Vec<String>,
+
+    /// directories to watch instead of the source directories of the
+    /// packages. Can be repeated.
+    #[argh(option)]
+    path: Vec<String>,
+
+    /// upstart job to restart on the DUT after each deploy (e.g. shill)
+    #[argh(option)]
+    restart: Option<String>,
+
+    /// send only the differences of the files to the DUT (see `cro3 deploy
+    /// --delta`)
+    #[argh(switch)]
+    delta: bool,
+
+    /// seconds between the checks for changes (default: 2)
+    #[argh(option, default = "2")]
+    interval: u64,
+}
+
+#[tracing::instrument(level = "trace")]
+pub fn run(args: &Args) -> Result<()> {
+    if args.package.is_empty() {
+        bail!("Please specify the packages to watch with --package");
+    }
+    ensure_testing_rsa_is_there()?;
+    let dut = resolve_dut(&args.dut, args.board.as_deref())?;
+    let board = infer_board(args.board.as_deref(), Some(&dut))?;
+    let repo = get_cros_dir(&args.cros)?;
+    let chroot = Chroot::new(&repo)?;
+    let dirs: Vec<PathBuf> = if args.path.is_empty() {
+        let mut dirs = Vec::new();
+        for package in &args.package {
+            dirs.extend(package_source_dirs(&chroot, &repo, &board, package)?);
+        }
+        dirs
+    } else {
+        args.path.iter().map(PathBuf::from).collect()
+    };
+    if dirs.is_empty() {
+        bail!("No source directories of the packages are found. Please specify --path.");
+    }
+    let target = SshInfo::new(&dut)?.into_forwarded()?;
+    ensure_rootfs_writable(&target)?;
+
+    let interval = Duration::from_secs(args.interval.max(1));
+    let mut snapshot = take_snapshot(&dirs);
+    info!("Watching {} files in:", snapshot.len());
+    for dir in &dirs {
+        info!("  {}", dir.display());
+    }
+    loop {
+        thread::sleep(interval);
+        let mut current = take_snapshot(&dirs);
+        if changed_files(&snapshot, &current).is_empty() {
+            continue;
+        }
+        // Wait for the other files saved at the same time
+        loop {
+            thread::sleep(interval);
+            let next = take_snapshot(&dirs);
+            if changed_files(&current, &next).is_empty() {
+                break;
+            }
+            current = next;
+        }
+        let changed = changed_files(&snapshot, &current);
+        snapshot = current;
+        if changed.is_empty() {
+            continue;
+        }
+        info!(
+            "{} changed{}",
+            changed[0].display(),
+            if changed.len() > 1 {
+                format!(" (and {} more)", changed.len() - 1)
+            } else {
+                String::new()
+            }
+        );
+        let start = Instant::now();
+        if let Err(e) = build_and_deploy(args, &chroot, &board, &target) {
+            error!("{e:#}");
+            info!("Waiting for changes...");
+            continue;
+        }
+        info!(
+            "Deployed in {}. Waiting for changes...",
+            format_duration(start.elapsed().as_secs_f64())
+        );
+    }
+}
+
+fn build_and_deploy(args: &Args, chroot: &Chroot, board: &str, target: &SshInfo) -> Result<()> {
+    build_packages(
+        chroot,
+        board,
+        &args.package,
+        &BuildOptions {
+            workon: true,
+            remoteexec: None,
+            profile: false,
+            overrides: Default::default(),
+        },
+    )?;
+    let results = deploy_packages(chroot, board, target, &args.package, args.delta);
+    for r in &results {
+        if !r.status.is_deployed() {
+            bail!("Failed to deploy {}: {}", r.package, r.status);
+        }
+        info!("{}: {}", r.package, r.status);
+    }
+    if let Some(service) = &args.restart {
+        target
+            .run_cmd_stdio(&format!("restart {service} || start {service}"))
+            .map_err(|e| warn!("Failed to restart {service}: {e:#}"))
+            .ok();
+    }
+    Ok(())
+}
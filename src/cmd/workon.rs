@@ -0,0 +1,141 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! ## Work on packages
+//! Packages worked on are built from the source in the checkout instead of
+//! the prebuilts. cro3 remembers them per board, and warns on builds and
+//! syncs if other packages are worked on.
+//! ```
+//! cro3 workon start --cros ${CROS} --board ${BOARD} ${PACKAGE_NAME}
+//! cro3 workon list --cros ${CROS} --board ${BOARD}
+//! # Stop working on all the packages of the board
+//! cro3 workon stop --cros ${CROS} --board ${BOARD}
+//! # Show the packages recorded for all the boards without the chroot
+//! cro3 workon list --cached
+//! ```
+
+use anyhow::bail;
+use anyhow::Result;
+use argh::FromArgs;
+use cro3::build::boards_with_workon_packages;
+use cro3::build::list_workon_packages;
+use cro3::build::recorded_workon_packages;
+use cro3::build::set_workon;
+use cro3::chroot::Chroot;
+use cro3::repo::get_cros_dir;
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// build packages from the source in the checkout (cros workon)
+#[argh(subcommand, name = "workon")]
+pub struct Args {
+    #[argh(subcommand)]
+    nested: SubCommand,
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+enum SubCommand {
+    Start(ArgsStart),
+    Stop(ArgsStop),
+    List(ArgsList),
+}
+#[tracing::instrument(level = "trace")]
+pub fn run(args: &Args) -> Result<()> {
+    match &args.nested {
+        SubCommand::Start(args) => run_start(args),
+        SubCommand::Stop(args) => run_stop(args),
+        SubCommand::List(args) => run_list(args),
+    }
+}
+
+fn print_packages(packages: &[String]) {
+    for p in packages {
+        println!("{p}");
+    }
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// start working on packages
+#[argh(subcommand, name = "start")]
+struct ArgsStart {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target board (or host)
+    #[argh(option)]
+    board: String,
+
+    /// packages to work on
+    #[argh(positional)]
+    packages: Vec<String>,
+}
+fn run_start(args: &ArgsStart) -> Result<()> {
+    if args.packages.is_empty() {
+        bail!("Please specify the packages to work on");
+    }
+    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    print_packages(&set_workon(&chroot, &args.board, &args.packages, true)?);
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// stop working on packages (all of them if none is given)
+#[argh(subcommand, name = "stop")]
+struct ArgsStop {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target board (or host)
+    #[argh(option)]
+    board: String,
+
+    /// packages to stop working on
+    #[argh(positional)]
+    packages: Vec<String>,
+}
+fn run_stop(args: &ArgsStop) -> Result<()> {
+    let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+    print_packages(&set_workon(&chroot, &args.board, &args.packages, false)?);
+    Ok(())
+}
+
+#[derive(FromArgs, PartialEq, Debug)]
+/// list the packages worked on
+#[argh(subcommand, name = "list")]
+struct ArgsList {
+    /// target cros repo dir
+    #[argh(option)]
+    cros: Option<String>,
+
+    /// target board (or host). All the recorded boards are shown with
+    /// --cached if omitted.
+    #[argh(option)]
+    board: Option<String>,
+
+    /// only show the packages recorded by cro3, without entering the chroot
+    #[argh(switch)]
+    cached: bool,
+}
+fn run_list(args: &ArgsList) -> Result<()> {
+    match (&args.board, args.cached) {
+        (Some(board), true) => print_packages(&recorded_workon_packages(board)?),
+        (None, true) => {
+            for (board, packages) in boards_with_workon_packages()? {
+                for p in packages {
+                    println!("{board}\t{p}");
+                }
+            }
+        }
+        (Some(board), false) => {
+            let chroot = Chroot::new(&get_cros_dir(&args.cros)?)?;
+            print_packages(&list_workon_packages(&chroot, board)?);
+        }
+        (None, false) => bail!("Please specify --board, or --cached"),
+    }
+    Ok(())
+}
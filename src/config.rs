@@ -8,6 +8,7 @@ use std::collections::HashMap;
 use std::fs::read_to_string;
 use std::fs::write;
 use std::str::FromStr;
+use std::time::Duration;
 
 use anyhow::bail;
 use anyhow::Context;
@@ -45,6 +46,47 @@ impl SshOverride {
         &self.ssh_options
     }
 }
+/// Retry policy for connecting to DUTs over SSH before running commands or
+/// transferring files
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SshRetry {
+    /// Max number of attempts, including the first one
+    pub attempts: u32,
+    /// Delay before the first retry, doubled on each retry (up to 30s)
+    pub backoff_sec: f64,
+    /// No retries are made after this many seconds since the first attempt
+    pub deadline_sec: u64,
+}
+impl Default for SshRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            backoff_sec: 1.0,
+            deadline_sec: 120,
+        }
+    }
+}
+impl SshRetry {
+    /// Returns the delay before the `retry`-th retry (1-origin), or None if
+    /// no more retries should be made
+    pub fn delay_before_retry(&self, retry: u32, elapsed: Duration) -> Option<Duration> {
+        if retry >= self.attempts {
+            return None;
+        }
+        let delay = (self.backoff_sec * 2f64.powi(retry as i32 - 1)).min(30.0);
+        let delay = Duration::from_secs_f64(delay.max(0.0));
+        (elapsed + delay <= Duration::from_secs(self.deadline_sec)).then_some(delay)
+    }
+}
+/// Container to run the SDK (cros_sdk) in, for machines where it can not run
+/// directly (e.g. no sudo or an unsupported distro)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SdkContainer {
+    /// podman or docker
+    pub engine: String,
+    /// Image with depot_tools in PATH
+    pub image: String,
+}
 // When adding a new config parameter, add an item in this enum and
 // struct Config.
 #[derive(Debug, PartialEq, EnumIter, EnumString, strum_macros::Display)]
@@ -66,6 +108,14 @@ pub enum ConfigKey {
     ArcVmCheepsImage,
     ArcVmBettyImageForBranch,
     ArcContainerCheepsImageForBranch,
+    HttpProxy,
+    NoProxy,
+    GcloudAccount,
+    PowerHook,
+    DutGroups,
+    ProxyJump,
+    SshRetry,
+    SdkContainer,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
@@ -134,6 +184,42 @@ pub struct Config {
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     #[serde(default)]
     arc_container_cheeps_image_for_branch: HashMap<String, String>,
+    /// Proxy URL used by cro3 and the commands invoked by cro3. It is set by
+    /// `cro3 setup proxy`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    http_proxy: Option<String>,
+    /// Comma-separated list of hosts that should not go through the proxy.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    no_proxy: Option<String>,
+    /// Account used for gcloud and gsutil. It is set by `cro3 setup auth`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    gcloud_account: Option<String>,
+    /// Shell command to turn DUTs on / off without servo (e.g. via a PoE
+    /// switch or a smart plug). $CRO3_DUT and $CRO3_POWER (on or off) are set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    power_hook: Option<String>,
+    /// Key: group name, value: DUT IDs in the group. A group name can be
+    /// given wherever a DUT is accepted.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    #[serde(default)]
+    dut_groups: HashMap<String, Vec<String>>,
+    /// Key: regex for DUT host names, value: jump host ([user@]host[:port])
+    /// to reach the matching DUTs, unless one is set by `cro3 dut jump`.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    #[serde(default)]
+    proxy_jump: HashMap<String, String>,
+    /// Retry policy for SSH operations on DUTs. The default is used if unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    ssh_retry: Option<SshRetry>,
+    /// Container to run cros_sdk in, if set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    sdk_container: Option<SdkContainer>,
 }
 static CONFIG_FILE_NAME: &str = "config.json";
 impl Config {
@@ -276,6 +362,80 @@ impl Config {
                 self.arc_container_cheeps_image_for_branch
                     .insert(branch, target);
             }
+            ConfigKey::HttpProxy => {
+                if values.len() != 1 {
+                    bail!("{key} only takes 1 params");
+                }
+                self.http_proxy = Some(values[0].as_ref().to_string());
+            }
+            ConfigKey::NoProxy => {
+                if values.len() != 1 {
+                    bail!("{key} only takes 1 params");
+                }
+                self.no_proxy = Some(values[0].as_ref().to_string());
+            }
+            ConfigKey::GcloudAccount => {
+                if values.len() != 1 {
+                    bail!("{key} only takes 1 params");
+                }
+                self.gcloud_account = Some(values[0].as_ref().to_string());
+            }
+            ConfigKey::PowerHook => {
+                if values.len() != 1 {
+                    bail!("{key} only takes 1 params");
+                }
+                self.power_hook = Some(values[0].as_ref().to_string());
+            }
+            ConfigKey::DutGroups => {
+                if values.len() < 2 {
+                    bail!("{key} takes 2+ parameters");
+                }
+                let group = values[0].as_ref().to_string();
+                let duts: Vec<String> =
+                    values[1..].iter().map(|s| s.as_ref().to_string()).collect();
+                self.dut_groups.insert(group, duts);
+            }
+            ConfigKey::ProxyJump => {
+                if values.len() != 2 {
+                    bail!("{key} takes 2 parameters");
+                }
+                let host_regex = values[0].as_ref().to_string();
+                Regex::new(&host_regex).context("Invalid regex is provided as a host_pattern")?;
+                self.proxy_jump
+                    .insert(host_regex, values[1].as_ref().to_string());
+            }
+            ConfigKey::SshRetry => {
+                if values.len() != 3 {
+                    bail!("{key} takes 3 parameters: ATTEMPTS BACKOFF_SEC DEADLINE_SEC");
+                }
+                self.ssh_retry = Some(SshRetry {
+                    attempts: values[0]
+                        .as_ref()
+                        .parse()
+                        .context("ATTEMPTS should be an integer")?,
+                    backoff_sec: values[1]
+                        .as_ref()
+                        .parse()
+                        .context("BACKOFF_SEC should be a number")?,
+                    deadline_sec: values[2]
+                        .as_ref()
+                        .parse()
+                        .context("DEADLINE_SEC should be an integer")?,
+                });
+            }
+            ConfigKey::SdkContainer => {
+                if values.len() != 2 {
+                    bail!("{key} takes 2 parameters: ENGINE IMAGE");
+                }
+                let engine = values[0].as_ref().to_string();
+                if engine != "podman" && engine != "docker" {
+                    bail!("ENGINE should be podman or docker");
+                }
+                self.sdk_container = Some(SdkContainer {
+                    engine,
+                    image: values[1].as_ref().to_string(),
+                });
+            }
         }
         self.write()
     }
@@ -327,6 +487,26 @@ impl Config {
             ConfigKey::ArcContainerCheepsImageForBranch => {
                 self.arc_container_cheeps_image_for_branch.clear()
             }
+            ConfigKey::HttpProxy => {
+                self.http_proxy = None;
+            }
+            ConfigKey::NoProxy => {
+                self.no_proxy = None;
+            }
+            ConfigKey::GcloudAccount => {
+                self.gcloud_account = None;
+            }
+            ConfigKey::PowerHook => {
+                self.power_hook = None;
+            }
+            ConfigKey::DutGroups => self.dut_groups.clear(),
+            ConfigKey::ProxyJump => self.proxy_jump.clear(),
+            ConfigKey::SshRetry => {
+                self.ssh_retry = None;
+            }
+            ConfigKey::SdkContainer => {
+                self.sdk_container = None;
+            }
         }
         self.write()?;
         Ok(())
@@ -387,4 +567,72 @@ impl Config {
     pub fn arc_container_cheeps_image_for_branch(&self) -> &HashMap<String, String> {
         &self.arc_container_cheeps_image_for_branch
     }
+    pub fn http_proxy(&self) -> Option<String> {
+        self.http_proxy.clone()
+    }
+    pub fn no_proxy(&self) -> Option<String> {
+        self.no_proxy.clone()
+    }
+    pub fn gcloud_account(&self) -> Option<String> {
+        self.gcloud_account.clone()
+    }
+    pub fn power_hook(&self) -> Option<String> {
+        self.power_hook.clone()
+    }
+    pub fn dut_group(&self, group: &str) -> Option<&Vec<String>> {
+        self.dut_groups.get(group)
+    }
+    /// Returns the jump host for a DUT host name. If multiple patterns match,
+    /// the one that comes first in lexicographic order is used.
+    pub fn proxy_jump_for(&self, host: &str) -> Result<Option<String>> {
+        let mut patterns: Vec<&String> = self.proxy_jump.keys().collect();
+        patterns.sort();
+        for pattern in patterns {
+            if Regex::new(pattern)
+                .context("Failed to compile regex for proxy_jump")?
+                .is_match(host)
+            {
+                return Ok(self.proxy_jump.get(pattern).cloned());
+            }
+        }
+        Ok(None)
+    }
+    pub fn ssh_retry(&self) -> SshRetry {
+        self.ssh_retry.clone().unwrap_or_default()
+    }
+    pub fn sdk_container(&self) -> Option<SdkContainer> {
+        self.sdk_container.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn ssh_retry_delays() {
+        let policy = SshRetry::default();
+        let delays: Vec<Option<Duration>> = (1..=4)
+            .map(|retry| policy.delay_before_retry(retry, Duration::ZERO))
+            .collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(2)),
+                Some(Duration::from_secs(4)),
+                None
+            ]
+        );
+        // The deadline is over after the delay
+        assert_eq!(policy.delay_before_retry(2, Duration::from_secs(119)), None);
+        let policy = SshRetry {
+            attempts: 100,
+            backoff_sec: 1.0,
+            deadline_sec: 10000,
+        };
+        assert_eq!(
+            policy.delay_before_retry(10, Duration::ZERO),
+            Some(Duration::from_secs(30))
+        );
+    }
 }
@@ -0,0 +1,139 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Code coverage of packages built with USE=coverage (clang source-based
+//! coverage), collected from the .profraw files written on the DUT while the
+//! tests run.
+
+/// Directory on the DUT where the instrumented programs write the profiles
+pub const DUT_PROFILE_DIR: &str = "/usr/local/cro3_coverage";
+
+/// Value of LLVM_PROFILE_FILE on the DUT, with a file for each process (%p)
+/// and binary (%m)
+pub fn llvm_profile_file() -> String {
+    format!("{DUT_PROFILE_DIR}/%p-%m.profraw")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileCoverage {
+    pub path: String,
+    pub lines_found: u64,
+    pub lines_hit: u64,
+}
+impl FileCoverage {
+    pub fn percent(&self) -> f64 {
+        if self.lines_found == 0 {
+            0.0
+        } else {
+            self.lines_hit as f64 / self.lines_found as f64 * 100.0
+        }
+    }
+}
+
+/// Parses the line coverage of each source file (SF:, LF: and LH: records)
+/// in an lcov tracefile
+pub fn parse_lcov(lcov: &str) -> Vec<FileCoverage> {
+    let mut files = Vec::new();
+    let mut current: Option<FileCoverage> = None;
+    for line in lcov.lines() {
+        let line = line.trim();
+        if let Some(path) = line.strip_prefix("SF:") {
+            current = Some(FileCoverage {
+                path: path.to_string(),
+                lines_found: 0,
+                lines_hit: 0,
+            });
+        } else if let (Some(f), Some(n)) = (current.as_mut(), line.strip_prefix("LF:")) {
+            f.lines_found = n.parse().unwrap_or_default();
+        } else if let (Some(f), Some(n)) = (current.as_mut(), line.strip_prefix("LH:")) {
+            f.lines_hit = n.parse().unwrap_or_default();
+        } else if line == "end_of_record" {
+            files.extend(current.take());
+        }
+    }
+    files
+}
+
+/// Formats the coverage of the files (the least covered first) and the total
+pub fn format_coverage_table(files: &[FileCoverage]) -> String {
+    let mut files: Vec<&FileCoverage> = files.iter().collect();
+    files.sort_by(|a, b| a.percent().total_cmp(&b.percent()));
+    let mut table = format!("{:>7} {:>13}  FILE\n", "COVER", "LINES");
+    let mut total = FileCoverage {
+        path: "TOTAL".to_string(),
+        lines_found: 0,
+        lines_hit: 0,
+    };
+    for f in files {
+        total.lines_found += f.lines_found;
+        total.lines_hit += f.lines_hit;
+        table += &format_row(f);
+    }
+    table += &format_row(&total);
+    table
+}
+
+fn format_row(f: &FileCoverage) -> String {
+    format!(
+        "{:>6.1}% {:>13}  {}\n",
+        f.percent(),
+        format!("{}/{}", f.lines_hit, f.lines_found),
+        f.path
+    )
+}
+
+/// Script to run in the chroot, which merges the profiles in `dir`/profraw
+/// and writes coverage.lcov and an HTML report in `dir`/html for the
+/// binaries installed by `packages` for `board`
+pub fn coverage_report_script(board: &str, packages: &[String], dir: &str) -> String {
+    format!(
+        r#"cd {dir}
+llvm-profdata merge -sparse profraw/*.profraw -o merged.profdata
+objects=()
+for f in $(qlist-{board} {packages}); do
+  if [[ -f /build/{board}$f ]] && file -b /build/{board}$f | grep -q '^ELF'; then
+    objects+=(-object /build/{board}$f)
+  fi
+done
+if [[ ${{#objects[@]}} -eq 0 ]]; then
+  echo "No binaries are installed by the packages" >&2
+  exit 1
+fi
+llvm-cov export -format=lcov -instr-profile=merged.profdata "${{objects[@]:1}}" > coverage.lcov
+rm -rf html
+genhtml -q --ignore-errors source coverage.lcov -o html
+"#,
+        packages = packages.join(" ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn lcov() {
+        let lcov = "TN:
+SF:/mnt/host/source/src/platform2/shill/manager.cc
+DA:10,1
+DA:11,0
+LF:200
+LH:150
+end_of_record
+SF:/mnt/host/source/src/platform2/shill/device.cc
+LF:100
+LH:10
+end_of_record
+";
+        let files = parse_lcov(lcov);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].lines_hit, 150);
+        assert_eq!(files[0].percent(), 75.0);
+        let table = format_coverage_table(&files);
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[1].ends_with("device.cc"));
+        assert_eq!(lines[3], "  53.3%       160/300  TOTAL");
+    }
+}
@@ -51,6 +51,17 @@ pub fn lookup_full_version(input: &str, board: &str) -> Result<String> {
     }
 }
 
+/// Versions that have been looked up so far (e.g. "R120-15662.0.0")
+pub fn cached_versions() -> Result<Vec<String>> {
+    let mut versions: Vec<String> = VERSION_TO_MILESTONE_CACHE
+        .entries()?
+        .into_values()
+        .collect();
+    versions.sort();
+    versions.dedup();
+    Ok(versions)
+}
+
 pub fn ensure_testing_rsa_is_there() -> Result<()> {
     let cmd = "
 if ! [ -f ~/.ssh/testing_rsa ]; then
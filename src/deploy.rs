@@ -0,0 +1,778 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Deploying packages built locally to a DUT with `cros deploy`, one package
+//! at a time in the order of their dependencies, and the kernel with
+//! update_kernel.sh. A deploy followed by a reboot can be rolled back to the
+//! other slot if the DUT does not come back healthy. Chrome built in a Chrome
+//! checkout is deployed with deploy_chrome, outside the chroot.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::build::package_name;
+use crate::build::update_kernel;
+use crate::cache::KvCache;
+use crate::chroot::Chroot;
+use crate::dut::SshInfo;
+use crate::image_cache::format_size;
+use crate::provision::InactiveSlot;
+
+lazy_static! {
+    // e.g. "[binary   R    ] chromeos-base/foo-0.0.1-r1::chromiumos to /build/..."
+    static ref RE_MERGE_ENTRY: Regex = Regex::new(r"^\[[^\]]+\]\s+(\S+?)(::\S+)?(\s|$)").unwrap();
+    // e.g. "ui start/running, process 1234"
+    static ref RE_UPSTART_STATUS: Regex =
+        Regex::new(r"^(\S+) (\w+)/(\w+)(, process (\d+))?").unwrap();
+}
+
+/// Chrome deployed to each DUT by `cro3 deploy chrome` (key: DUT ID)
+pub static DUT_CHROME_BUILDS: KvCache<ChromeBuild> = KvCache::new("dut_chrome_builds");
+
+/// Upstart jobs which make the DUT unusable if they are not running
+const CRITICAL_SERVICES: &[&str] = &["ui", "shill", "dbus"];
+
+/// Returns `packages` in the order they would be merged, given the output of
+/// `emerge --pretend`. Packages not in the output are kept at the end.
+pub fn parse_merge_order(output: &str, packages: &[String]) -> Vec<String> {
+    let mut ordered = Vec::new();
+    for line in output.lines() {
+        let Some(c) = RE_MERGE_ENTRY.captures(line.trim()) else {
+            continue;
+        };
+        let merged = package_name(&c[1]);
+        for p in packages {
+            let matched = p == merged || merged.ends_with(&format!("/{p}"));
+            if matched && !ordered.contains(p) {
+                ordered.push(p.clone());
+            }
+        }
+    }
+    for p in packages {
+        if !ordered.contains(p) {
+            ordered.push(p.clone());
+        }
+    }
+    ordered
+}
+
+/// Sorts `packages` so that each one is deployed after the ones it depends on
+pub fn deploy_order(chroot: &Chroot, board: &str, packages: &[String]) -> Result<Vec<String>> {
+    if packages.len() < 2 {
+        return Ok(packages.to_vec());
+    }
+    let mut args = vec![
+        format!("emerge-{board}"),
+        "--pretend".to_string(),
+        "--quiet".to_string(),
+        "--emptytree".to_string(),
+        "--usepkgonly".to_string(),
+        "--with-bdeps=n".to_string(),
+    ];
+    args.extend(packages.iter().cloned());
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match chroot.exec_in_chroot(&args) {
+        Ok(output) => Ok(parse_merge_order(&output, packages)),
+        Err(e) => {
+            warn!("Failed to resolve the dependencies, deploying in the given order: {e:#}");
+            Ok(packages.to_vec())
+        }
+    }
+}
+
+/// Returns the partition number of a device, e.g. 3 for /dev/nvme0n1p3
+pub fn partition_number(dev: &str) -> Option<u32> {
+    let dev = dev.trim();
+    let digits = dev.len() - dev.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    dev[dev.len() - digits..].parse().ok()
+}
+
+/// Makes the rootfs of the DUT writable. If rootfs verification is enabled,
+/// it is removed from the running kernel partition and the DUT is rebooted.
+pub fn ensure_rootfs_writable(ssh: &SshInfo) -> Result<()> {
+    let rootdev = ssh.run_cmd_stdio("rootdev")?;
+    if rootdev.trim().starts_with("/dev/dm-") {
+        // e.g. /dev/nvme0n1p3 => the kernel is on partition 2
+        let root_num = partition_number(&ssh.run_cmd_stdio("rootdev -s")?)
+            .context("Failed to get the root partition of the DUT")?;
+        info!("Removing rootfs verification of the DUT...");
+        ssh.run_cmd_stdio(&format!(
+            "/usr/share/vboot/bin/make_dev_ssd.sh --partitions {} --remove_rootfs_verification \
+             --force",
+            root_num - 1
+        ))
+        .context("Failed to disable rootfs verification")?;
+        info!("Rebooting the DUT...");
+        ssh.run_cmd_stdio("(sleep 1; reboot) >/dev/null 2>&1 &")?;
+        ssh.close_connection()?;
+        std::thread::sleep(Duration::from_secs(10));
+        ssh.wait_for_boot(Duration::from_secs(300), false)?;
+    }
+    ssh.run_cmd_stdio("mount -o remount,rw /")
+        .context("Failed to remount the rootfs as writable")?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeployStatus {
+    Deployed,
+    /// Deployed by sending only the differences from the files on the DUT
+    DeployedDelta(RsyncStats),
+    Failed(String),
+    /// Not deployed because a package before it failed
+    Skipped,
+}
+impl DeployStatus {
+    pub fn is_deployed(&self) -> bool {
+        matches!(
+            self,
+            DeployStatus::Deployed | DeployStatus::DeployedDelta(_)
+        )
+    }
+}
+impl std::fmt::Display for DeployStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeployStatus::Deployed => write!(f, "deployed"),
+            DeployStatus::DeployedDelta(stats) => write!(
+                f,
+                "deployed (sent {} of {})",
+                format_size(stats.literal),
+                format_size(stats.literal + stats.matched)
+            ),
+            DeployStatus::Failed(e) => write!(f, "failed: {e}"),
+            DeployStatus::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeployResult {
+    pub package: String,
+    pub status: DeployStatus,
+    pub duration: Option<Duration>,
+}
+
+/// Bytes sent as is (literal) and reconstructed from the files on the
+/// destination (matched) by rsync
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RsyncStats {
+    pub literal: u64,
+    pub matched: u64,
+}
+
+/// Parses the output of `rsync --stats`
+pub fn parse_rsync_stats(output: &str) -> Option<RsyncStats> {
+    let value = |key: &str| -> Option<u64> {
+        output
+            .lines()
+            .find_map(|l| l.trim().strip_prefix(key))
+            .and_then(|v| v.split_whitespace().next())
+            .and_then(|v| v.replace(',', "").parse().ok())
+    };
+    Some(RsyncStats {
+        literal: value("Literal data:")?,
+        matched: value("Matched data:")?,
+    })
+}
+
+/// Returns the glob of the binary packages of `package`, relative to the
+/// packages directory of the board
+fn binpkg_glob(package: &str) -> String {
+    if package.contains('/') {
+        format!("{package}-[0-9]*.tbz2")
+    } else {
+        format!("*/{package}-[0-9]*.tbz2")
+    }
+}
+
+/// Returns true if `package` is installed on the DUT
+fn is_installed_on_dut(target: &SshInfo, package: &str) -> bool {
+    let pattern = if package.contains('/') {
+        format!("/var/db/pkg/{package}-[0-9]*")
+    } else {
+        format!("/var/db/pkg/*/{package}-[0-9]*")
+    };
+    target.run_cmd_stdio(&format!("ls -d {pattern}")).is_ok()
+}
+
+/// Deploys the files of the latest binary package of `package` with rsync,
+/// which sends only the parts that differ from the files on the DUT. Unlike
+/// `cros deploy`, the package database on the DUT is not updated, so this is
+/// only for a package deployed before.
+fn deploy_package_delta(
+    chroot: &Chroot,
+    board: &str,
+    target: &SshInfo,
+    package: &str,
+    name: &str,
+) -> Result<RsyncStats> {
+    let script = format!(
+        r#"set -e
+cd /build/{board}/packages
+f=$(ls -t {glob} 2>/dev/null | head -n 1)
+if [ -z "$f" ]; then echo "No binary package of {package}. Please build it first." >&2; exit 1; fi
+dst=/cro3/tmp/{name}
+sudo rm -rf $dst && mkdir -p $dst
+bzip2 -dc $f 2>/dev/null | sudo tar -xp -C $dst
+ssh="ssh -p {port} -i $HOME/.ssh/testing_rsa -o StrictHostKeyChecking=no -o UserKnownHostsFile=/dev/null"
+sudo rsync -aK --stats -e "$ssh" $dst/ root@{host}:/
+sudo rm -rf $dst"#,
+        glob = binpkg_glob(package),
+        port = target.port(),
+        host = target.host(),
+    );
+    let output = chroot.exec_in_chroot(&["bash", "-c", &script])?;
+    parse_rsync_stats(&output).context("Failed to parse the output of rsync")
+}
+
+/// Deploys `packages` to `target` in order, and stops at the first failure
+/// since the packages after it may depend on it. With `delta`, the packages
+/// installed on the DUT are updated by sending only the differences of their
+/// files. `target` must be reachable from the chroot (see
+/// SshInfo::into_forwarded).
+pub fn deploy_packages(
+    chroot: &Chroot,
+    board: &str,
+    target: &SshInfo,
+    packages: &[String],
+    delta: bool,
+) -> Vec<DeployResult> {
+    let host = target.host_and_port();
+    // Scripts of the deploys to other DUTs can run at the same time
+    let script = format!(
+        "deploy_{}",
+        host.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+    );
+    let mut results: Vec<DeployResult> = Vec::new();
+    for (i, package) in packages.iter().enumerate() {
+        if results
+            .iter()
+            .any(|r| matches!(r.status, DeployStatus::Failed(_)))
+        {
+            results.push(DeployResult {
+                package: package.clone(),
+                status: DeployStatus::Skipped,
+                duration: None,
+            });
+            continue;
+        }
+        info!(
+            "{host}: Deploying {package} ({}/{})...",
+            i + 1,
+            packages.len()
+        );
+        let start = Instant::now();
+        if delta && is_installed_on_dut(target, package) {
+            match deploy_package_delta(chroot, board, target, package, &script) {
+                Ok(stats) => {
+                    results.push(DeployResult {
+                        package: package.clone(),
+                        status: DeployStatus::DeployedDelta(stats),
+                        duration: Some(start.elapsed()),
+                    });
+                    continue;
+                }
+                Err(e) => warn!("{host}: Failed to send the delta of {package}: {e:#}"),
+            }
+        }
+        let status = match chroot.run_bash_script_in_chroot(
+            &script,
+            &format!("cros deploy --force --board={board} {host} {package}"),
+            None,
+        ) {
+            Ok(_) => DeployStatus::Deployed,
+            Err(e) => DeployStatus::Failed(e.root_cause().to_string()),
+        };
+        results.push(DeployResult {
+            package: package.clone(),
+            status,
+            duration: Some(start.elapsed()),
+        });
+    }
+    results
+}
+
+/// The kernel running on a DUT
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunningKernel {
+    /// e.g. 5.15.120-19745-g0123456789ab
+    pub release: String,
+    /// e.g. #1 SMP PREEMPT Wed Oct 15 01:23:45 UTC 2025, which differs
+    /// between builds of the same release
+    pub version: String,
+    /// The kernel partition booted
+    pub partition: Option<u32>,
+}
+impl RunningKernel {
+    /// Parses the output of `uname -r; uname -v; rootdev -s`
+    pub fn parse(output: &str) -> Result<Self> {
+        let mut lines = output.lines().map(str::trim);
+        let release = lines.next().filter(|l| !l.is_empty());
+        let version = lines.next().filter(|l| !l.is_empty());
+        let (Some(release), Some(version)) = (release, version) else {
+            bail!("Unexpected output of uname: {output:?}");
+        };
+        Ok(Self {
+            release: release.to_string(),
+            version: version.to_string(),
+            // The kernel is on the partition before the rootfs
+            partition: lines.next().and_then(partition_number).map(|n| n - 1),
+        })
+    }
+    pub fn of(ssh: &SshInfo) -> Result<Self> {
+        Self::parse(&ssh.run_cmd_stdio("uname -r; uname -v; rootdev -s")?)
+    }
+}
+
+/// Returns an error describing why `after` is not the kernel deployed over
+/// `before`. `expected_release` is the release of the kernel built, if known.
+pub fn check_new_kernel(
+    before: &RunningKernel,
+    after: &RunningKernel,
+    expected_release: Option<&str>,
+    ab_update: bool,
+) -> Result<()> {
+    if ab_update && after.partition.is_some() && after.partition == before.partition {
+        bail!(
+            "The DUT booted the old slot (kernel partition {}) and is running {}. The new kernel \
+             may have failed to boot.",
+            after.partition.unwrap_or_default(),
+            after.release
+        );
+    }
+    if after == before {
+        bail!(
+            "The DUT is still running the old kernel {} ({}). It may have fallen back to the \
+             previous kernel.",
+            after.release,
+            after.version
+        );
+    }
+    if let Some(expected) = expected_release {
+        if after.release != expected {
+            bail!(
+                "The DUT is running {} but {expected} was deployed",
+                after.release
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Returns the release of the kernel built last for `board`, from the
+/// modules installed in its sysroot
+pub fn built_kernel_release(chroot: &Chroot, board: &str) -> Option<String> {
+    chroot
+        .exec_in_chroot(&["bash", "-c", &format!("ls -t /build/{board}/lib/modules")])
+        .ok()
+        .and_then(|output| output.lines().next().map(|l| l.trim().to_string()))
+        .filter(|release| !release.is_empty())
+}
+
+/// Writes the kernel built for `board` and its modules to the DUT, reboots
+/// it, and checks that the new kernel is running. If `rollback` is true, the
+/// DUT is rolled back to the previous slot when it does not come back healthy.
+pub fn deploy_kernel(
+    chroot: &Chroot,
+    board: &str,
+    ssh: &SshInfo,
+    ab_update: bool,
+    rollback: bool,
+) -> Result<RunningKernel> {
+    let before = RunningKernel::of(ssh)?;
+    let expected = built_kernel_release(chroot, board);
+    let rollback = match (rollback, ab_update) {
+        (false, _) => None,
+        // update_kernel.sh lets the firmware boot the new slot only once
+        (true, true) => Some(Rollback::to_running_slot(ssh)?),
+        (true, false) => Rollback::to_other_slot(ssh)?,
+    };
+    if let (Some(rollback), false) = (&rollback, ab_update) {
+        rollback.arm(ssh)?;
+    }
+    update_kernel(chroot, board, &ssh.into_forwarded()?, ab_update)?;
+    ssh.close_connection()?;
+    std::thread::sleep(Duration::from_secs(10));
+    match &rollback {
+        Some(rollback) => rollback.verify(ssh)?,
+        None => ssh
+            .wait_for_boot(Duration::from_secs(180), false)
+            .context("The DUT did not come back after updating the kernel")?,
+    }
+    let after = RunningKernel::of(ssh)?;
+    check_new_kernel(&before, &after, expected.as_deref(), ab_update)?;
+    info!(
+        "{} is running the new kernel: {} (was {})",
+        ssh.host(),
+        after.release,
+        before.release
+    );
+    Ok(after)
+}
+
+/// Parses the output of `status JOB...` of upstart into the PIDs of the jobs
+/// (None if not running)
+pub fn parse_upstart_status(output: &str) -> Vec<(String, Option<u32>)> {
+    output
+        .lines()
+        .filter_map(|l| RE_UPSTART_STATUS.captures(l.trim()))
+        .map(|c| {
+            let running = &c[2] == "start" && &c[3] == "running";
+            let pid = c.get(5).and_then(|p| p.as_str().parse().ok());
+            (c[1].to_string(), pid.filter(|_| running))
+        })
+        .collect()
+}
+
+/// Returns the services which are not running in `after`, or were restarted
+/// since `before`, with the reason
+pub fn unhealthy_services(
+    before: &[(String, Option<u32>)],
+    after: &[(String, Option<u32>)],
+) -> Vec<String> {
+    let mut unhealthy = Vec::new();
+    for (name, pid) in after {
+        let pid_before = before.iter().find(|(n, _)| n == name).and_then(|(_, p)| *p);
+        match pid {
+            None => unhealthy.push(format!("{name} is not running")),
+            Some(pid) if pid_before.is_some_and(|p| p != *pid) => {
+                unhealthy.push(format!("{name} is restarting (crash loop?)"))
+            }
+            _ => {}
+        }
+    }
+    unhealthy
+}
+
+fn critical_service_status(ssh: &SshInfo) -> Result<Vec<(String, Option<u32>)>> {
+    let cmd = CRITICAL_SERVICES
+        .iter()
+        .map(|s| format!("status {s}"))
+        .collect::<Vec<_>>()
+        .join("; ");
+    Ok(parse_upstart_status(&ssh.run_cmd_stdio(&cmd)?))
+}
+
+/// Rolls back a deploy to the slot which was working before it, if the DUT
+/// does not come back healthy after the reboot
+pub struct Rollback {
+    disk: String,
+    /// Kernel partition which was working before the deploy
+    good_kern: String,
+    /// Kernel partition which boots the deployed change
+    deployed_kern: String,
+}
+impl Rollback {
+    /// Prepares to roll back a deploy to the running slot to the other slot.
+    /// Returns None if the other slot is not bootable.
+    pub fn to_other_slot(ssh: &SshInfo) -> Result<Option<Self>> {
+        let slot = InactiveSlot::of(ssh)?;
+        let rollback = Self {
+            disk: slot.disk,
+            good_kern: slot.kern_num,
+            deployed_kern: slot.active_kern_num,
+        };
+        let bootable = ssh.run_cmd_stdio(&format!(
+            "[ $(cgpt show -i {kern} -P {disk}) -gt 0 ] && [ $(cgpt show -i {kern} -S {disk}) -eq \
+             1 ] && echo yes || true",
+            kern = rollback.good_kern,
+            disk = rollback.disk
+        ))?;
+        if bootable.trim() != "yes" {
+            warn!(
+                "The other slot (kernel partition {}) is not bootable, so the deploy can not be \
+                 rolled back",
+                rollback.good_kern
+            );
+            return Ok(None);
+        }
+        Ok(Some(rollback))
+    }
+    /// Prepares to roll back a deploy to the inactive slot (e.g.
+    /// update_kernel.sh --ab_update) to the running slot
+    pub fn to_running_slot(ssh: &SshInfo) -> Result<Self> {
+        let slot = InactiveSlot::of(ssh)?;
+        Ok(Self {
+            disk: slot.disk,
+            good_kern: slot.active_kern_num,
+            deployed_kern: slot.kern_num,
+        })
+    }
+    /// Lets the firmware boot the deployed slot only once, so that it falls
+    /// back to the good slot if the DUT fails to boot. Call this right before
+    /// the reboot.
+    pub fn arm(&self, ssh: &SshInfo) -> Result<()> {
+        ssh.run_cmd_stdio(&format!(
+            "cgpt add -i {} -S 0 -T 1 {}",
+            self.deployed_kern, self.disk
+        ))
+        .context("Failed to update the partition table")?;
+        Ok(())
+    }
+    /// Checks that the DUT booted the deployed slot and its critical
+    /// services are running after the reboot. Otherwise, boots the good slot
+    /// and returns an error describing what went wrong.
+    pub fn verify(&self, ssh: &SshInfo) -> Result<()> {
+        if let Err(e) = ssh.wait_for_boot(Duration::from_secs(300), true) {
+            if !ssh.is_reachable(5) {
+                bail!(
+                    "The DUT did not come back after the deploy, and could not be rolled back \
+                     remotely: {e:#}"
+                );
+            }
+            warn!("{e:#}");
+        }
+        let slot = InactiveSlot::of(ssh)?;
+        if slot.active_kern_num == self.good_kern {
+            bail!(
+                "The DUT failed to boot the deployed change, and fell back to the previous slot \
+                 (kernel partition {})",
+                self.good_kern
+            );
+        }
+        let before = critical_service_status(ssh)?;
+        std::thread::sleep(Duration::from_secs(20));
+        let after = critical_service_status(ssh)?;
+        let unhealthy = unhealthy_services(&before, &after);
+        if unhealthy.is_empty() {
+            ssh.run_cmd_stdio(&format!(
+                "cgpt add -i {} -S 1 -T 0 {}",
+                self.deployed_kern, self.disk
+            ))?;
+            return Ok(());
+        }
+        warn!(
+            "The DUT is unhealthy after the deploy: {}. Rolling back to the previous slot (kernel \
+             partition {})...",
+            unhealthy.join(", "),
+            self.good_kern
+        );
+        ssh.run_cmd_stdio(&format!(
+            "cgpt prioritize -i {kern} {disk} && cgpt add -i {deployed} -P 0 {disk}",
+            kern = self.good_kern,
+            deployed = self.deployed_kern,
+            disk = self.disk
+        ))?;
+        ssh.run_cmd_stdio("(sleep 1; reboot) >/dev/null 2>&1 &")?;
+        ssh.close_connection()?;
+        std::thread::sleep(Duration::from_secs(10));
+        ssh.wait_for_boot(Duration::from_secs(300), false)
+            .context("The DUT did not come back after the rollback")?;
+        bail!(
+            "Rolled back to the previous slot (kernel partition {}) since {}",
+            self.good_kern,
+            unhealthy.join(", ")
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChromeBuild {
+    /// e.g. 120.0.6099.0
+    pub version: String,
+    pub build_dir: String,
+    /// HEAD of the Chrome checkout, if known
+    pub git_hash: Option<String>,
+    /// Unix time of the deploy
+    pub deployed_at: i64,
+}
+
+/// Returns the version in the output of `chrome --version`, e.g.
+/// "Google Chrome 120.0.6099.0 " or "Chromium 120.0.6099.0 unknown"
+pub fn parse_chrome_version(output: &str) -> Option<String> {
+    output
+        .split_whitespace()
+        .find(|w| w.split('.').count() == 4 && w.split('.').all(|n| n.parse::<u32>().is_ok()))
+        .map(str::to_string)
+}
+
+/// Returns the deploy_chrome script to use: the one in the Chrome checkout
+/// that has `build_dir` (e.g. ~/chromium/src/out_brya/Release), or the one in
+/// the cros checkout
+pub fn find_deploy_chrome(build_dir: &Path, cros: Option<&str>) -> Result<PathBuf> {
+    let candidates = build_dir
+        .ancestors()
+        .map(|dir| dir.join("third_party/chromite/bin/deploy_chrome"))
+        .chain(cros.map(|cros| Path::new(cros).join("chromite/bin/deploy_chrome")));
+    for candidate in candidates {
+        if candidate.exists() {
+            return Ok(candidate);
+        }
+    }
+    bail!("deploy_chrome is not found. Please specify a build dir in a Chrome checkout, or --cros.")
+}
+
+/// Deploys Chrome in `build_dir` to the DUT with deploy_chrome, which
+/// remounts the rootfs as writable, stops ui, copies the files and restarts
+/// ui. This runs outside the chroot.
+pub fn deploy_chrome(
+    dut: &str,
+    board: &str,
+    build_dir: &Path,
+    cros: Option<&str>,
+    extra_args: &[String],
+) -> Result<ChromeBuild> {
+    let build_dir = build_dir
+        .canonicalize()
+        .context(anyhow!("{build_dir:?} is not found"))?;
+    if !build_dir.join("chrome").exists() {
+        bail!("No chrome binary in {build_dir:?}. Please build it first.");
+    }
+    let script = find_deploy_chrome(&build_dir, cros)?;
+    let ssh = SshInfo::new(dut)?;
+    let target = ssh.into_forwarded()?;
+    info!("Deploying Chrome in {build_dir:?} with {script:?}...");
+    Command::new(&script)
+        .arg("--build-dir")
+        .arg(&build_dir)
+        .arg(format!("--device={}", target.host_and_port()))
+        .arg(format!("--board={board}"))
+        .arg("--force")
+        .args(extra_args)
+        .status()
+        .context(anyhow!("Failed to run {script:?}"))?
+        .exit_ok()
+        .context("deploy_chrome failed")?;
+    let version = ssh.run_cmd_stdio("/opt/google/chrome/chrome --version")?;
+    let git_hash = Command::new("git")
+        .arg("-C")
+        .arg(&build_dir)
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string());
+    let build = ChromeBuild {
+        version: parse_chrome_version(&version).unwrap_or(version.trim().to_string()),
+        build_dir: build_dir.to_string_lossy().to_string(),
+        git_hash,
+        deployed_at: chrono::Local::now().timestamp(),
+    };
+    DUT_CHROME_BUILDS.set(dut, build.clone())?;
+    Ok(build)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn merge_order() {
+        let output = "[binary   R    ] chromeos-base/libbrillo-0.0.1-r2345::chromiumos to \
+                      /build/b/
+[binary   R    ] chromeos-base/shill-0.0.1-r100 to /build/b/
+[binary   R    ] dev-libs/libfoo-1.0
+[binary   R    ] chromeos-base/debugd-0.0.1-r7
+";
+        let packages = vec![
+            "debugd".to_string(),
+            "chromeos-base/shill".to_string(),
+            "unknown".to_string(),
+            "libbrillo".to_string(),
+        ];
+        assert_eq!(
+            parse_merge_order(output, &packages),
+            vec!["libbrillo", "chromeos-base/shill", "debugd", "unknown"]
+        );
+        assert_eq!(partition_number("/dev/nvme0n1p3\n"), Some(3));
+        assert_eq!(partition_number("/dev/sda5"), Some(5));
+        assert_eq!(partition_number("/dev/sda"), None);
+    }
+    #[test]
+    fn new_kernel() {
+        let before = RunningKernel::parse(
+            "5.15.120-19745-g0123456789ab\n#1 SMP PREEMPT Wed Oct 1 01:23:45 UTC 2025\n/dev/sda3\n",
+        )
+        .unwrap();
+        assert_eq!(before.partition, Some(2));
+        let rebuilt = RunningKernel {
+            version: "#1 SMP PREEMPT Wed Oct 15 01:23:45 UTC 2025".to_string(),
+            ..before.clone()
+        };
+        let expected = Some("5.15.120-19745-g0123456789ab");
+        assert!(check_new_kernel(&before, &rebuilt, expected, false).is_ok());
+        assert!(check_new_kernel(&before, &before, expected, false).is_err());
+        assert!(check_new_kernel(&before, &rebuilt, Some("6.1.0"), false).is_err());
+        // --ab_update boots the other slot
+        assert!(check_new_kernel(&before, &rebuilt, expected, true).is_err());
+        let other_slot = RunningKernel {
+            partition: Some(4),
+            ..rebuilt
+        };
+        assert!(check_new_kernel(&before, &other_slot, expected, true).is_ok());
+        assert!(RunningKernel::parse("").is_err());
+    }
+    #[test]
+    fn rsync_stats() {
+        let output = "Number of files: 1,234 (reg: 1,000, dir: 234)
+Total file size: 157,286,400 bytes
+Literal data: 1,048,576 bytes
+Matched data: 156,237,824 bytes
+Total bytes sent: 1,100,000
+";
+        let stats = parse_rsync_stats(output).unwrap();
+        assert_eq!(stats.literal, 1_048_576);
+        assert_eq!(stats.matched, 156_237_824);
+        assert_eq!(
+            DeployStatus::DeployedDelta(stats).to_string(),
+            "deployed (sent 1.0 MiB of 150.0 MiB)"
+        );
+        assert_eq!(parse_rsync_stats("sent 10 bytes"), None);
+    }
+    #[test]
+    fn chrome_version() {
+        assert_eq!(
+            parse_chrome_version("Google Chrome 120.0.6099.0 \n").as_deref(),
+            Some("120.0.6099.0")
+        );
+        assert_eq!(
+            parse_chrome_version("Chromium 121.0.6130.1 unknown").as_deref(),
+            Some("121.0.6130.1")
+        );
+        assert_eq!(parse_chrome_version("chrome: not found"), None);
+    }
+    #[test]
+    fn service_health() {
+        let before = parse_upstart_status(
+            "ui start/running, process 1234
+shill start/running, process 567
+dbus start/running, process 89
+",
+        );
+        assert_eq!(before[0], ("ui".to_string(), Some(1234)));
+        let after = parse_upstart_status(
+            "ui start/post-start, (post-start) process 2345
+shill start/running, process 567
+dbus stop/waiting
+",
+        );
+        assert_eq!(after[0], ("ui".to_string(), None));
+        assert_eq!(
+            unhealthy_services(&before, &after),
+            vec!["ui is not running", "dbus is not running"]
+        );
+        let restarted = parse_upstart_status("shill start/running, process 600");
+        assert_eq!(
+            unhealthy_services(&before, &restarted),
+            vec!["shill is restarting (crash loop?)"]
+        );
+        assert!(unhealthy_services(&before, &before).is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Installing DLC (downloadable content) images to DUTs without an update
+//! server: the image built for the OS version of the DUT is pushed into the
+//! DLC cache on the DUT and installed by dlcservice. See `cro3 dlc`.
+
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use tracing::info;
+
+use crate::dut::SshInfo;
+use crate::google_storage::download_gs_file;
+use crate::image_cache::cached_image_dir;
+use crate::image_cache::ImageKind;
+use crate::parser::LsbRelease;
+
+/// Where the image is copied to before it is moved into the DLC cache
+const DUT_DLC_TMP: &str = "/usr/local/tmp/cro3_dlc.img";
+
+/// A build in the image archive (e.g. brya-release/R120-15662.0.0)
+#[derive(Debug, Clone, PartialEq)]
+pub struct DlcBuild {
+    pub board: String,
+    pub builder_path: String,
+}
+impl DlcBuild {
+    /// Returns the build that the DUT runs, according to /etc/lsb-release
+    pub fn of_dut(ssh: &SshInfo) -> Result<Self> {
+        let lsb = LsbRelease::from_str(&ssh.run_cmd_stdio("cat /etc/lsb-release")?)?;
+        let board = lsb
+            .get("CHROMEOS_RELEASE_BOARD")
+            .context("No board in /etc/lsb-release")?;
+        let builder_path = lsb.get("CHROMEOS_RELEASE_BUILDER_PATH").context(
+            "The DUT runs a local build. Please specify --version, or --image with a DLC image \
+             built locally.",
+        )?;
+        Ok(Self {
+            board: board.to_string(),
+            builder_path: builder_path.to_string(),
+        })
+    }
+    fn version(&self) -> &str {
+        self.builder_path
+            .rsplit('/')
+            .next()
+            .unwrap_or(&self.builder_path)
+    }
+}
+
+/// Downloads the image of DLC `id` in `build`, or reuses the one downloaded
+/// before
+pub fn download_dlc(build: &DlcBuild, id: &str) -> Result<PathBuf> {
+    let dir = cached_image_dir(&build.board, build.version(), ImageKind::Dlc)?.join(id);
+    std::fs::create_dir_all(&dir)?;
+    let image = dir.join("dlc.img");
+    if image.exists() {
+        info!("Using the cached {image:?}");
+        return Ok(image);
+    }
+    let url = format!(
+        "gs://chromeos-image-archive/{}/dlc/{id}/package/dlc.img",
+        build.builder_path
+    );
+    info!("Downloading {url}...");
+    download_gs_file(&url, &image).context(anyhow!(
+        "Failed to download DLC {id} of {}",
+        build.builder_path
+    ))?;
+    Ok(image)
+}
+
+/// Returns the SHA-256 of the image expected by the rootfs, in the content of
+/// imageloader.json of a DLC
+pub fn parse_dlc_manifest(json: &str) -> Result<String> {
+    let manifest: serde_json::Value = serde_json::from_str(json)?;
+    manifest["image-sha256-hash"]
+        .as_str()
+        .map(|s| s.to_lowercase())
+        .context("No image-sha256-hash in the DLC manifest")
+}
+
+/// Returns the root path of the mounted DLC if the output of
+/// `dlcservice_util --dlc_state` says that it is installed
+pub fn parse_dlc_state(output: &str) -> Result<Option<String>> {
+    let state: serde_json::Value = serde_json::from_str(output)
+        .context(anyhow!("Failed to parse the state of the DLC: {output}"))?;
+    // The state is an enum of DlcState, where 2 is INSTALLED
+    let installed = match &state["state"] {
+        serde_json::Value::Number(n) => n.as_u64() == Some(2),
+        serde_json::Value::String(s) => s == "INSTALLED",
+        _ => false,
+    };
+    Ok(installed.then(|| state["root_path"].as_str().unwrap_or_default().to_string()))
+}
+
+fn sha256_of_file(path: &Path) -> Result<String> {
+    let output = Command::new("sha256sum")
+        .arg(path)
+        .output()
+        .context("Failed to run sha256sum")?;
+    output.status.exit_ok().context("sha256sum failed")?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Pushes the image of DLC `id` to the DUT and installs it with dlcservice.
+/// Returns the path where the DLC is mounted.
+pub fn install_dlc(ssh: &SshInfo, id: &str, image: &Path) -> Result<String> {
+    let manifest = format!("/opt/google/dlc/{id}/package/imageloader.json");
+    let Ok(manifest) = ssh.run_cmd_stdio(&format!("cat {manifest}")) else {
+        let available = ssh.run_cmd_stdio("ls /opt/google/dlc").unwrap_or_default();
+        bail!(
+            "DLC {id} is not supported by the image on the DUT. Available ones: {}",
+            available
+                .split_whitespace()
+                .collect::<Vec<&str>>()
+                .join(" ")
+        );
+    };
+    let expected = parse_dlc_manifest(&manifest)?;
+    let actual = sha256_of_file(image)?;
+    if actual != expected {
+        bail!(
+            "{image:?} does not match the OS on the DUT (SHA-256 is {actual} but {expected} is \
+             expected). The DLC must be built together with the OS."
+        );
+    }
+
+    info!("Sending {image:?} to the DUT...");
+    let status = ssh
+        .scp_send_cmd(
+            &[image.to_string_lossy().to_string()],
+            Some(&DUT_DLC_TMP.to_string()),
+        )?
+        .status()?;
+    if !status.success() {
+        bail!("Failed to copy {image:?} to the DUT");
+    }
+    // dlcservice verifies the images in the cache and mounts them on install
+    let dir = format!("/var/cache/dlc/{id}/package");
+    ssh.run_cmd_stdio(&format!(
+        "stop dlcservice; for s in dlc_a dlc_b; do mkdir -p {dir}/$s && cp {DUT_DLC_TMP} \
+         {dir}/$s/dlc.img || exit 1; done && rm -f {DUT_DLC_TMP} && chown -R \
+         dlcservice:dlcservice /var/cache/dlc/{id} && chmod -R u=rwX,go=rX /var/cache/dlc/{id} && \
+         start dlcservice && for i in $(seq 10); do dlcservice_util --list >/dev/null 2>&1 && \
+         break; sleep 1; done"
+    ))
+    .context("Failed to put the image into the DLC cache")?;
+    info!("Installing DLC {id}...");
+    ssh.run_cmd_stdio(&format!("dlcservice_util --install --id={id}"))
+        .context("dlcservice failed to install the DLC")?;
+    let state = ssh.run_cmd_stdio(&format!("dlcservice_util --dlc_state --id={id}"))?;
+    parse_dlc_state(&state)?.context(anyhow!("DLC {id} is not installed: {state}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn dlc_state() {
+        assert_eq!(
+            parse_dlc_manifest(
+                r#"{"fs-type":"squashfs","id":"sample-dlc","image-sha256-hash":"AB12CD","size":"53248"}"#
+            )
+            .unwrap(),
+            "ab12cd"
+        );
+        assert!(parse_dlc_manifest("{}").is_err());
+        let installed = r#"{
+   "id": "sample-dlc",
+   "last_error_code": "",
+   "progress": 1.0,
+   "root_path": "/run/imageloader/sample-dlc/package/root",
+   "state": 2
+}"#;
+        assert_eq!(
+            parse_dlc_state(installed).unwrap(),
+            Some("/run/imageloader/sample-dlc/package/root".to_string())
+        );
+        assert_eq!(
+            parse_dlc_state(&installed.replace("\"state\": 2", "\"state\": \"NOT_INSTALLED\""))
+                .unwrap(),
+            None
+        );
+        assert!(parse_dlc_state("Failed to get state").is_err());
+    }
+}
@@ -4,9 +4,16 @@
 // license that can be found in the LICENSE file or at
 // https://developers.google.com/open-source/licenses/bsd
 
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::env::current_dir;
 use std::ffi::OsStr;
+use std::fs::create_dir_all;
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::net::TcpStream;
 use std::ops::Range;
 use std::process::Command;
 use std::process::Output;
@@ -35,15 +42,24 @@ use serde::{Deserialize, Serialize};
 //use strum::additional_attributes;
 use tracing::error;
 use tracing::info;
+use tracing::warn;
 use url::Url;
 
 use crate::cache::KvCache;
 use crate::config::Config;
+use crate::config::SshRetry;
 use crate::cros::ensure_testing_rsa_is_there;
+use crate::dut_filter::DutFilter;
+use crate::dut_key::user_key_path;
+use crate::repo::find_cros_dir_from_cwd;
+use crate::util::cro3_paths::gen_path_in_cro3_dir;
+use crate::util::picker::is_interactive;
+use crate::util::picker::pick;
 use crate::util::shell_helpers::get_async_lines;
 use crate::util::shell_helpers::get_stderr;
 use crate::util::shell_helpers::get_stdout;
 use crate::util::shell_helpers::run_bash_command;
+use crate::util::shell_helpers::shell_quote;
 
 const COMMON_SSH_OPTIONS: [&str; 14] = [
     // Do not read ~/.ssh/config to avoid effects comes from ssh_config
@@ -78,7 +94,31 @@ lazy_static! {
         Regex::new(r"^0x[0-9a-fA-F]+$").unwrap();
 }
 
+/// Directory to push files to if not specified. /usr/local is on the stateful
+/// partition so it is writable and survives reboots (but not flashing).
+pub const DEFAULT_PUSH_DEST: &str = "/usr/local/tmp";
+
+/// Exit code of ssh when the connection failed (or the remote command exited
+/// with 255, which is rare)
+const SSH_CONNECTION_ERROR: i32 = 255;
+
+/// bootstat records this file (under /tmp, cleared on reboot) when the login
+/// screen is shown
+const LOGIN_PROMPT_VISIBLE_CHECK: &str = "test -e /tmp/uptime-login-prompt-visible";
+
 pub static SSH_CACHE: KvCache<SshInfo> = KvCache::new("ssh_cache");
+/// Board of each DUT (key: DUT ID), recorded when the DUT info is fetched
+pub static DUT_BOARD_CACHE: KvCache<String> = KvCache::new("dut_board_cache");
+/// Model of each DUT (key: DUT ID), recorded along with the board
+pub static DUT_MODEL_CACHE: KvCache<String> = KvCache::new("dut_model_cache");
+/// Release (CHROMEOS_RELEASE_DESCRIPTION) of each DUT (key: DUT ID)
+pub static DUT_RELEASE_CACHE: KvCache<String> = KvCache::new("dut_release_cache");
+/// User-defined tags of each DUT (key: DUT ID), e.g. location, owner
+pub static DUT_TAGS: KvCache<BTreeMap<String, String>> = KvCache::new("dut_tags");
+/// Leases taken by this user (key: DUT ID), mirrored from the DUTs
+pub static DUT_LEASE_CACHE: KvCache<DutLease> = KvCache::new("dut_lease_cache");
+/// DUT ID chosen last time in each workspace (key: path of the workspace)
+static LAST_DUT_CACHE: KvCache<String> = KvCache::new("last_dut_cache");
 
 /// PortForwarding represents Local/Remote forwarding on ssh
 #[derive(Debug, Clone)]
@@ -215,6 +255,11 @@ lazy_static! {
         m.insert("ipv6_addrs", r"ip -6 address show dev `cro3_get_default_iface` mngtmpaddr | grep inet6 | sed -E 's/\s+/ /g' | tr '/' ' ' | cut -d ' ' -f 3");
         m.insert("mac", r"ip addr show dev `cro3_get_default_iface` | grep ether | grep -E -o '([0-9a-z]{2}:){5}([0-9a-z]{2})' | head -n 1");
         m.insert("release", r"cat /etc/lsb-release | grep CHROMEOS_RELEASE_DESCRIPTION | sed -e 's/CHROMEOS_RELEASE_DESCRIPTION=//'");
+        m.insert("os_version", r"cat /etc/lsb-release | grep CHROMEOS_RELEASE_VERSION | cut -d '=' -f 2");
+        m.insert("arc_version", r"cat /etc/lsb-release | grep CHROMEOS_ARC_VERSION | cut -d '=' -f 2");
+        m.insert("chrome_version", r"/opt/google/chrome/chrome --version");
+        m.insert("kernel", r"uname -r");
+        m.insert("ec_version", r"ectool version | grep 'RW version' | sed -E 's/^RW version:\s*//'");
         m.insert("dev_boot_usb", r"crossystem dev_boot_usb");
         m.insert("dev_default_boot", r"crossystem dev_default_boot");
         m.insert("fwid", r"crossystem fwid");
@@ -264,7 +309,22 @@ impl DutInfo {
             ssh: ssh.clone(),
             info,
         };
-        SSH_CACHE.set(dut.id(), ssh.clone())?;
+        // proxy_jump in the config is applied on each use instead of being
+        // recorded, so that changes in the config take effect
+        if ssh.jump.is_some() && ssh.jump == JumpHost::from_config(&ssh.host)? {
+            SSH_CACHE.set(dut.id(), ssh.with_jump(None))?;
+        } else {
+            SSH_CACHE.set(dut.id(), ssh.clone())?;
+        }
+        if let Some(board) = dut.info.get("board") {
+            DUT_BOARD_CACHE.set(dut.id(), board.clone())?;
+        }
+        if let Some(model) = dut.info.get("model") {
+            DUT_MODEL_CACHE.set(dut.id(), model.clone())?;
+        }
+        if let Some(release) = dut.info.get("release") {
+            DUT_RELEASE_CACHE.set(dut.id(), release.clone())?;
+        }
         Ok(dut)
     }
     /// new should be fast enough (less than a sec per a DUT)
@@ -323,60 +383,60 @@ impl DutInfo {
             bail!("key {key} did not found. stderr")
         }
     }
-    fn parse_values(
-        keys: &[&str],
-        mut values: HashMap<String, Result<String>>,
-    ) -> Result<HashMap<String, String>> {
-        // Construct values based on values
+    /// Fill values for the keys that are derived from other values
+    fn derive_values(keys: &[&str], values: &mut HashMap<String, Result<String>>) {
         if keys.contains(&"timestamp") {
             values.insert("timestamp".to_string(), Ok(Local::now().to_string()));
         }
         if keys.contains(&"model") {
-            if let Some(Ok(model)) = values.get("model_from_cros_config") {
-                values.insert("model".to_string(), Ok(model.clone()));
+            let model = if let Some(Ok(model)) = values.get("model_from_cros_config") {
+                Ok(model.clone())
             } else if let Some(Ok(model)) = values.get("model_from_mosys") {
-                values.insert("model".to_string(), Ok(model.clone()));
+                Ok(model.clone())
             } else {
-                bail!("Failed to get model");
-            }
+                Err(anyhow!("Failed to get model"))
+            };
+            values.insert("model".to_string(), model);
         }
         if keys.contains(&"gbb_flags") {
-            let gbb_flags = if let Some(Ok(v)) = values.get("gbb_flags") {
-                v
-            } else {
-                bail!("Failed to get model");
+            let gbb_flags = match values.get("gbb_flags") {
+                Some(Ok(v)) => RE_GBB_FLAGS
+                    .find(v)
+                    .map(|m| m.as_str().to_string())
+                    .context(anyhow!(
+                        "gbb_flags should match regex RE_GBB_FLAGS but got {v:?}"
+                    )),
+                _ => Err(anyhow!("Failed to get gbb_flags")),
             };
-            if let Some(gbb_flags) = RE_GBB_FLAGS.find(gbb_flags) {
-                values.insert("gbb_flags".to_string(), Ok(gbb_flags.as_str().to_string()));
-            } else {
-                return Err(anyhow!(
-                    "gbb_flags should match regex RE_GBB_FLAGS but got {gbb_flags:?}"
-                ));
-            }
+            values.insert("gbb_flags".to_string(), gbb_flags);
         }
         if keys.contains(&"dut_id") {
             let serial = if let Some(Ok(serial)) = values.get("serial") {
-                serial.to_string()
-            } else {
+                Ok(serial.to_string())
+            } else if let Some(Ok(mac)) = values.get("mac") {
                 // Some DUTs don't have serial number. So use MAC address,
-                let serial = if let Some(Ok(mac)) = values.get("mac") {
-                    format!("NoSerial{}", mac.replace(':', "").to_lowercase())
-                } else {
-                    return Err(anyhow!(
-                        "Failed to get MAC address. {:?}",
-                        values.get("mac")
-                    ));
-                };
+                let serial = format!("NoSerial{}", mac.replace(':', "").to_lowercase());
                 values.insert("serial".to_string(), Ok(serial.to_string()));
-                serial
-            };
-            if let Some(Ok(model)) = values.get("model") {
-                let dut_id = format!("{model}_{serial}");
-                values.insert("dut_id".to_string(), Ok(dut_id));
+                Ok(serial)
             } else {
-                bail!("Failed to get model. {:?}", values.get("model"));
-            }
+                Err(anyhow!(
+                    "Failed to get MAC address. {:?}",
+                    values.get("mac")
+                ))
+            };
+            let dut_id = match (serial, values.get("model")) {
+                (Ok(serial), Some(Ok(model))) => Ok(format!("{model}_{serial}")),
+                (Err(e), _) => Err(e),
+                (_, model) => Err(anyhow!("Failed to get model. {model:?}")),
+            };
+            values.insert("dut_id".to_string(), dut_id);
         }
+    }
+    fn parse_values(
+        keys: &[&str],
+        mut values: HashMap<String, Result<String>>,
+    ) -> Result<HashMap<String, String>> {
+        Self::derive_values(keys, &mut values);
         // Collect all values for given keys
         keys.iter()
             .map(|&k| {
@@ -389,7 +449,7 @@ impl DutInfo {
             })
             .collect()
     }
-    pub fn fetch_keys(ssh: &SshInfo, keys: &Vec<&str>) -> Result<HashMap<String, String>> {
+    fn fetch_raw_values(ssh: &SshInfo, keys: &[&str]) -> Result<HashMap<String, Result<String>>> {
         ensure_testing_rsa_is_there()?;
         // First, list up all the keys to retrieve from a DUT
         let mut keys_from_dut = HashSet::new();
@@ -431,8 +491,244 @@ impl DutInfo {
                 (key.to_string(), value)
             })
             .collect();
+        Ok(values)
+    }
+    pub fn fetch_keys(ssh: &SshInfo, keys: &[&str]) -> Result<HashMap<String, String>> {
+        let values = Self::fetch_raw_values(ssh, keys)?;
         Self::parse_values(keys, values)
     }
+    /// Same as fetch_keys but the values that are not available on the DUT
+    /// are None instead of an error
+    pub fn fetch_keys_optional(
+        ssh: &SshInfo,
+        keys: &[&str],
+    ) -> Result<BTreeMap<String, Option<String>>> {
+        let mut values = Self::fetch_raw_values(ssh, keys)?;
+        Self::derive_values(keys, &mut values);
+        Ok(keys
+            .iter()
+            .map(|&k| {
+                (
+                    k.to_string(),
+                    values.get(k).and_then(|v| v.as_ref().ok().cloned()),
+                )
+            })
+            .collect())
+    }
+}
+
+/// JumpHost is a host (e.g. a labstation or a bastion) used to reach a DUT
+/// that is not directly accessible from this machine.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JumpHost {
+    /// [user@]host[:port] of the jump host. The user's ssh config is used to
+    /// connect to it, unlike DUTs.
+    host: String,
+    /// Identity file to log in to the jump host (e.g. ~/.ssh/testing_rsa for
+    /// labstations). If None, the user's ssh config and agent are used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    identity: Option<String>,
+    /// Forward the ssh agent to the DUT. Note that the keys can be used by
+    /// anyone who has root on the DUT while connected.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    forward_agent: bool,
+    /// Open a new connection to the jump host for every ssh session instead
+    /// of sharing one.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    #[serde(default)]
+    no_connection_sharing: bool,
+}
+impl JumpHost {
+    pub fn new(
+        host: &str,
+        identity: Option<String>,
+        forward_agent: bool,
+        no_connection_sharing: bool,
+    ) -> Result<Self> {
+        let url = Url::parse(&format!("ssh://{host}")).context(anyhow!(
+            "Invalid jump host: {host}. It should be [user@]host[:port]"
+        ))?;
+        if url.host_str().is_none() {
+            bail!("Invalid jump host: {host}. It should be [user@]host[:port]");
+        }
+        Ok(Self {
+            host: host.to_string(),
+            identity,
+            forward_agent,
+            no_connection_sharing,
+        })
+    }
+    /// Returns the jump host for `dut_host` given by proxy_jump in the config
+    pub fn from_config(dut_host: &str) -> Result<Option<Self>> {
+        Self::from_proxy_jump_of(&Config::read()?, dut_host)
+    }
+    fn from_proxy_jump_of(config: &Config, dut_host: &str) -> Result<Option<Self>> {
+        config
+            .proxy_jump_for(dut_host)?
+            .map(|via| Self::new(&via, None, false, false).context("Invalid proxy_jump in config"))
+            .transpose()
+    }
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+    /// Returns args for ssh to connect to the jump host (without commands)
+    pub fn gen_ssh_args(&self) -> Result<Vec<String>> {
+        let url = Url::parse(&format!("ssh://{}", self.host))?;
+        let mut args = Vec::new();
+        if !self.no_connection_sharing {
+            let dir = gen_path_in_cro3_dir("ssh")?;
+            create_dir_all(&dir)?;
+            args.extend_from_slice(&[
+                "-o".to_string(),
+                "ControlMaster=auto".to_string(),
+                "-o".to_string(),
+                format!("ControlPath={}/jump-%C", dir.to_string_lossy()),
+                "-o".to_string(),
+                "ControlPersist=10m".to_string(),
+            ]);
+        }
+        if let Some(identity) = &self.identity {
+            args.extend_from_slice(&["-i".to_string(), identity.clone()]);
+        }
+        if let Some(port) = url.port() {
+            args.extend_from_slice(&["-p".to_string(), port.to_string()]);
+        }
+        let host = url.host_str().context("No host in the jump host")?;
+        if url.username().is_empty() {
+            args.push(host.to_string());
+        } else {
+            args.push(format!("{}@{host}", url.username()));
+        }
+        Ok(args)
+    }
+    fn gen_proxy_command(&self) -> Result<String> {
+        // The outer ssh expands the tokens in ProxyCommand, and does not know
+        // %C in ControlPath, which is for the inner ssh
+        let args: Vec<String> = self
+            .gen_ssh_args()?
+            .iter()
+            .map(|s| shell_quote(&s.replace('%', "%%")))
+            .collect();
+        Ok(format!("ssh -W '[%h]:%p' {}", args.join(" ")))
+    }
+}
+
+// The lease marker is on the stateful partition so that it is visible to
+// anyone who uses the DUT. Note that it is wiped by flashing with
+// --clobber-stateful.
+const DUT_LEASE_PATH: &str = "/usr/local/cro3_lease.json";
+
+/// DutLease represents that a DUT is being used by someone
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DutLease {
+    /// user@hostname of the lease holder
+    holder: String,
+    /// Unix time when the lease expires
+    expires: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    reason: Option<String>,
+}
+impl DutLease {
+    pub fn new(minutes: u64, reason: Option<String>) -> Self {
+        Self {
+            holder: Self::me(),
+            expires: Local::now().timestamp() + minutes as i64 * 60,
+            reason,
+        }
+    }
+    fn me() -> String {
+        let host = whoami::fallible::hostname().unwrap_or("unknown".to_string());
+        format!("{}@{host}", whoami::username())
+    }
+    pub fn holder(&self) -> &str {
+        &self.holder
+    }
+    pub fn is_mine(&self) -> bool {
+        self.holder == Self::me()
+    }
+    pub fn is_expired(&self) -> bool {
+        Local::now().timestamp() >= self.expires
+    }
+    pub fn remaining_minutes(&self) -> i64 {
+        (self.expires - Local::now().timestamp()).max(0) / 60
+    }
+    pub fn description(&self) -> String {
+        let reason = self
+            .reason
+            .as_ref()
+            .map(|r| format!(" ({r})"))
+            .unwrap_or_default();
+        format!(
+            "leased by {} for {} more minutes{reason}",
+            self.holder,
+            self.remaining_minutes()
+        )
+    }
+}
+
+/// Fails if the DUT is leased by someone else, unless `steal` is true.
+pub fn ensure_dut_is_not_leased(ssh: &SshInfo, steal: bool) -> Result<()> {
+    match ssh.get_lease()? {
+        Some(lease) if !lease.is_mine() => {
+            if steal {
+                warn!("Stealing the DUT {}", lease.description());
+                Ok(())
+            } else {
+                bail!(
+                    "The DUT is {}. Use --steal if you really need to use it.",
+                    lease.description()
+                )
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Quote an argument for the command line given via `rsync -e`. rsync splits
+/// it by spaces, honoring single and double quotes (without escapes).
+fn quote_for_rsync(s: &str) -> String {
+    if !s.contains(['\'', '"', ' ']) {
+        s.to_string()
+    } else if s.contains('\'') {
+        format!("\"{s}\"")
+    } else {
+        format!("'{s}'")
+    }
+}
+
+/// Waits until an ssh process for port forwarding prints `token`, which means
+/// the forwarding is established. Returns false if the local port could not be
+/// used.
+async fn wait_for_forwarding(child: &mut async_process::Child, token: &str) -> Result<bool> {
+    let (ssh_stdout, ssh_stderr) = get_async_lines(child);
+    let ssh_stdout = ssh_stdout.context(anyhow!("ssh_stdout was None"))?;
+    let ssh_stderr = ssh_stderr.context(anyhow!("ssh_stderr was None"))?;
+    let mut merged_stream = stream::select(ssh_stdout.fuse(), ssh_stderr.fuse());
+    loop {
+        let mut merged_stream = merged_stream.next();
+        select! {
+            line = merged_stream => {
+                if let Some(Ok(line)) = line {
+                    if line.contains(token) {
+                        return Ok(true);
+                    }
+                    info!("{line}");
+                    if line.contains("cannot listen to port") {
+                        return Ok(false);
+                    }
+                }
+            }
+            complete => {
+                    // stdout is closed unexpectedly since ssh process is terminated.
+                    // stderr may contain some info and will be closed as well,
+                    // so do nothing here and wait for activities on stderr stream.
+                bail!("SSH process streams are closed");
+            }
+        }
+    }
 }
 
 /// SshInfo holds information needed to establish an ssh connection
@@ -442,16 +738,84 @@ pub struct SshInfo {
     /// IPv6 address MUST NOT not have brackets.
     host: String,
     port: u16,
+    /// The host to go through to reach this DUT, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    jump: Option<JumpHost>,
 }
 impl SshInfo {
     pub fn ping(&self) -> Result<()> {
         let host = &self.host;
-        let output = run_bash_command(&format!("ping -c 1 -W 0.5 {host} 1>/dev/null 2>&1"), None)?;
+        let cmd = format!("ping -c 1 -W 0.5 {host} 1>/dev/null 2>&1");
+        let output = if let Some(jump) = &self.jump {
+            // The DUT may not be reachable from here, so ping from the jump host
+            Command::new("ssh")
+                .args(jump.gen_ssh_args()?)
+                .arg("--")
+                .arg(cmd)
+                .output()?
+        } else {
+            run_bash_command(&cmd, None)?
+        };
         output.status.exit_ok().context("Failed to ping")
     }
+    /// Returns true if a command can be run on the DUT via SSH within
+    /// `timeout_sec`
+    pub fn is_reachable(&self, timeout_sec: u64) -> bool {
+        let timeout = format!("ConnectTimeout={timeout_sec}");
+        self.ssh_cmd_without_retry(Some(&["-o", &timeout]))
+            .and_then(|mut cmd| Ok(cmd.arg("true").output()?))
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    }
+    /// Returns the clock skew of the DUT from this machine in seconds (positive
+    /// if the DUT is ahead), compared at the midpoint of an SSH round trip.
+    pub fn clock_skew(&self) -> Result<f64> {
+        // Establish the connection first so that it does not count as latency
+        self.run_cmd_stdio("true")?;
+        let before = Local::now().timestamp_micros();
+        let output = self.run_cmd_stdio("date +%s.%N")?;
+        let after = Local::now().timestamp_micros();
+        let dut_time: f64 = output
+            .trim()
+            .parse()
+            .context(anyhow!("Unexpected output of date: {output}"))?;
+        Ok(dut_time - (before + after) as f64 / 2.0 / 1e6)
+    }
+    /// Sets the clock (and the RTC, if possible) of the DUT to the time of this
+    /// machine
+    pub fn set_clock_from_host(&self) -> Result<()> {
+        self.run_cmd_stdio("true")?;
+        let now = Local::now().timestamp_micros() as f64 / 1e6;
+        self.run_cmd_stdio(&format!(
+            "date -u -s @{now:.3} >/dev/null && (hwclock -w 2>/dev/null || true)"
+        ))
+        .context("Failed to set the clock of the DUT")?;
+        Ok(())
+    }
+    /// Waits until the DUT answers SSH and, if `login_screen` is true, until
+    /// the UI has shown the login screen in the current boot.
+    pub fn wait_for_boot(&self, timeout: Duration, login_screen: bool) -> Result<()> {
+        let deadline = std::time::Instant::now() + timeout;
+        loop {
+            if self.is_reachable(5)
+                && (!login_screen || self.run_cmd_stdio(LOGIN_PROMPT_VISIBLE_CHECK).is_ok())
+            {
+                return Ok(());
+            }
+            if std::time::Instant::now() > deadline {
+                bail!(
+                    "{} did not reach the state within {}s",
+                    self.host_and_port(),
+                    timeout.as_secs()
+                );
+            }
+            thread::sleep(Duration::from_secs(5));
+        }
+    }
     pub fn new(dut: &str) -> Result<Self> {
         if let Ok(Some(resolved)) = SSH_CACHE.get(dut) {
-            return Ok(resolved);
+            return resolved.with_configured_jump();
         }
         if dut.contains('_') {
             // '_' is a character that is not allowed for hostname.
@@ -469,7 +833,23 @@ impl SshInfo {
         let url = Url::parse(&url).context(anyhow!("Failed to parse url: {url}"))?;
         let host = url.host_str().unwrap_or("127.0.0.1").to_string();
         let port = url.port().unwrap_or(22);
-        Self::new_host_and_port(&host, port)
+        let ssh = Self::new_host_and_port(&host, port)?;
+        // Use the jump host registered for the same address, if any
+        let jump = SSH_CACHE.entries().ok().and_then(|duts| {
+            duts.values()
+                .find(|e| e.host == ssh.host && e.port == ssh.port && e.jump.is_some())
+                .and_then(|e| e.jump.clone())
+        });
+        ssh.with_jump(jump).with_configured_jump()
+    }
+    /// Applies the proxy_jump in the config if no jump host is set for this
+    /// DUT explicitly (with `cro3 dut jump`)
+    fn with_configured_jump(self) -> Result<Self> {
+        if self.jump.is_some() {
+            return Ok(self);
+        }
+        let jump = JumpHost::from_config(&self.host)?;
+        Ok(self.with_jump(jump))
     }
     pub fn new_host_and_port(host: &str, port: u16) -> Result<Self> {
         let host = if let Some(c) = RE_IPV6_WITH_BRACKETS.captures(host) {
@@ -487,12 +867,22 @@ impl SshInfo {
             Ok(Self {
                 host: host.to_string(),
                 port,
+                jump: None,
             })
         }
     }
+    pub fn with_jump(&self, jump: Option<JumpHost>) -> Self {
+        Self {
+            jump,
+            ..self.clone()
+        }
+    }
     pub fn host(&self) -> &str {
         &self.host
     }
+    pub fn jump(&self) -> Option<&JumpHost> {
+        self.jump.as_ref()
+    }
     pub fn needs_port_forwarding_in_chroot(&self) -> bool {
         self.host != "localhost" && self.host != "127.0.0.1"
     }
@@ -519,10 +909,13 @@ impl SshInfo {
     }
 
     fn gen_ssh_options(&self) -> Result<Vec<String>> {
-        let mut args: Vec<String> = Vec::from(COMMON_SSH_OPTIONS)
-            .iter()
-            .map(|s| s.to_string())
-            .collect();
+        let mut args: Vec<String> = Vec::new();
+        // Offer the per-user key (`cro3 dut key`) before testing_rsa
+        let user_key = user_key_path()?;
+        if user_key.exists() {
+            args.extend_from_slice(&["-i".to_string(), user_key.to_string_lossy().to_string()]);
+        }
+        args.extend(COMMON_SSH_OPTIONS.iter().map(|s| s.to_string()));
         let host = &self.host;
         let config = Config::read()?;
         for (k, v) in config.ssh_overrides() {
@@ -535,11 +928,50 @@ impl SshInfo {
             }
             args.extend(v.ssh_options().iter().map(|e| e.to_owned()));
         }
+        if let Some(jump) = &self.jump {
+            args.extend_from_slice(&[
+                "-o".to_string(),
+                format!("ProxyCommand={}", jump.gen_proxy_command()?),
+            ]);
+            if jump.forward_agent {
+                args.extend_from_slice(&["-o".to_string(), "ForwardAgent=yes".to_string()]);
+            }
+        }
+        // Since ssh uses the first value given for each option, this can be
+        // disabled with ssh_overrides (e.g. `-o ControlPath=none`).
+        args.extend(Self::gen_multiplexing_options()?);
         Ok(args)
     }
 
+    /// Options to reuse a connection to the same DUT across ssh / scp
+    /// invocations, with a control socket per DUT under ~/.cro3/ssh.
+    fn gen_multiplexing_options() -> Result<Vec<String>> {
+        let dir = gen_path_in_cro3_dir("ssh")?;
+        create_dir_all(&dir)?;
+        Ok(vec![
+            "-o".to_string(),
+            "ControlMaster=auto".to_string(),
+            "-o".to_string(),
+            format!("ControlPath={}/dut-%C", dir.to_string_lossy()),
+            "-o".to_string(),
+            "ControlPersist=10m".to_string(),
+            // Detect DUTs which went away (e.g. rebooted) without closing the
+            // shared connection, so that later commands do not hang on it.
+            "-o".to_string(),
+            "ServerAliveInterval=5".to_string(),
+            "-o".to_string(),
+            "ServerAliveCountMax=3".to_string(),
+        ])
+    }
+
     fn gen_ssh_args(&self, optional_args: Option<&[&str]>) -> Result<Vec<String>> {
-        let mut args = self.gen_ssh_options()?;
+        // optional_args go first to take precedence over the default options
+        let mut args: Vec<String> = optional_args
+            .unwrap_or_default()
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        args.extend(self.gen_ssh_options()?);
 
         let host = &self.host.replace(['[', ']'], "");
         let port = self.port;
@@ -547,13 +979,84 @@ impl SshInfo {
         let user_at_host = format!("{user}@{host}");
         let port = port.to_string();
         args.extend_from_slice(&["-p".to_string(), port]);
-        if let Some(optional_args) = optional_args {
-            args.extend(optional_args.iter().map(|s| s.to_string()));
-        }
         args.extend_from_slice(&[user_at_host, "--".to_string()]);
         Ok(args)
     }
 
+    /// Closes the shared connection to the DUT, if any. This should be called
+    /// when the DUT is going to reboot, since the connection can not be used
+    /// anymore.
+    pub fn close_connection(&self) -> Result<()> {
+        let host = &self.host.replace(['[', ']'], "");
+        Command::new("ssh")
+            .args(self.gen_ssh_options()?)
+            .args(["-p", &self.port.to_string(), "-O", "exit"])
+            .arg(format!("root@{host}"))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        Ok(())
+    }
+
+    /// ssh command line to be given to `rsync -e`
+    fn gen_rsync_ssh_command(&self) -> Result<String> {
+        let mut args = vec!["ssh".to_string()];
+        args.extend(self.gen_ssh_options()?);
+        args.extend_from_slice(&["-p".to_string(), self.port.to_string()]);
+        Ok(args
+            .iter()
+            .map(|s| quote_for_rsync(s))
+            .collect::<Vec<String>>()
+            .join(" "))
+    }
+    fn rsync_remote_prefix(&self) -> String {
+        let host = &self.host.replace(['[', ']'], "");
+        if host.contains(':') {
+            format!("root@[{host}]")
+        } else {
+            format!("root@{host}")
+        }
+    }
+    fn gen_rsync_args(&self) -> Result<Vec<String>> {
+        Ok(vec![
+            // Recursive, preserving attributes, compressed
+            "-az".to_string(),
+            "--partial".to_string(),
+            "--info=progress2".to_string(),
+            "-e".to_string(),
+            self.gen_rsync_ssh_command()?,
+        ])
+    }
+    pub fn rsync_get_cmd(&self, files: &[String], dest: Option<&String>) -> Result<Command> {
+        self.ensure_connection()?;
+        let mut cmd = Command::new("rsync");
+        cmd.args(self.gen_rsync_args()?);
+        let prefix = self.rsync_remote_prefix();
+        cmd.args(files.iter().map(|p| format!("{prefix}:{p}")));
+        cmd.arg(dest.map(String::as_str).unwrap_or("."));
+        Ok(cmd)
+    }
+    pub fn rsync_send_cmd(&self, files: &[String], dest: &str) -> Result<Command> {
+        self.ensure_connection()?;
+        let mut cmd = Command::new("rsync");
+        cmd.args(self.gen_rsync_args()?);
+        cmd.args(files);
+        cmd.arg(format!("{}:{dest}", self.rsync_remote_prefix()));
+        Ok(cmd)
+    }
+    /// Returns true if rsync is available on both this machine and the DUT.
+    /// scp is used to transfer files otherwise.
+    fn can_use_rsync(&self) -> bool {
+        let local = Command::new("rsync")
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .map(|s| s.success())
+            .unwrap_or(false);
+        local && self.run_cmd_stdio("command -v rsync").is_ok()
+    }
+
     fn gen_scp_get_args(&self, files: &[String], dest: Option<&String>) -> Result<Vec<String>> {
         let mut args = self.gen_ssh_options()?;
 
@@ -606,17 +1109,27 @@ impl SshInfo {
         Ok(args)
     }
     pub fn scp_get_cmd(&self, files: &[String], dest: Option<&String>) -> Result<Command> {
+        self.ensure_connection()?;
         let mut cmd = Command::new("scp");
         cmd.args(self.gen_scp_get_args(files, dest)?);
         Ok(cmd)
     }
     pub fn scp_send_cmd(&self, files: &[String], dest: Option<&String>) -> Result<Command> {
+        self.ensure_connection()?;
         let mut cmd = Command::new("scp");
         cmd.args(self.gen_scp_send_args(files, dest)?);
         Ok(cmd)
     }
 
+    /// Returns an ssh command to run a command on the DUT, after making sure
+    /// that the DUT can be connected (see ensure_connection())
     pub fn ssh_cmd(&self, additional_ssh_args: Option<&[&str]>) -> Result<Command> {
+        self.ensure_connection()?;
+        self.ssh_cmd_without_retry(additional_ssh_args)
+    }
+    /// Returns an ssh command without connecting to the DUT beforehand, for
+    /// probes which handle unreachable DUTs by themselves
+    pub fn ssh_cmd_without_retry(&self, additional_ssh_args: Option<&[&str]>) -> Result<Command> {
         let mut cmd = Command::new("ssh");
         cmd.args(self.gen_ssh_args(additional_ssh_args)?);
         Ok(cmd)
@@ -629,6 +1142,60 @@ impl SshInfo {
         cmd.args(self.gen_ssh_args(additional_ssh_args)?);
         Ok(cmd)
     }
+    /// Makes sure that a connection to the DUT can be established, retrying
+    /// with the policy in the config (`cro3 config set ssh_retry`) while it
+    /// can not. Only the connection is retried, so that the operations, which
+    /// may not be idempotent, run once on the shared connection established
+    /// here.
+    fn ensure_connection(&self) -> Result<()> {
+        let host = &self.host.replace(['[', ']'], "");
+        let port = self.port.to_string();
+        let alive = Command::new("ssh")
+            .args(self.gen_ssh_options()?)
+            .args(["-p", &port, "-O", "check"])
+            .arg(format!("root@{host}"))
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?
+            .success();
+        if alive {
+            return Ok(());
+        }
+        let start = std::time::Instant::now();
+        let mut policy = None;
+        let mut retry = 0;
+        loop {
+            let output = self
+                .ssh_cmd_without_retry(None)?
+                .arg("true")
+                .stdin(Stdio::null())
+                .output()?;
+            if output.status.code() != Some(SSH_CONNECTION_ERROR) {
+                return Ok(());
+            }
+            // Read the config only when needed since this is a hot path
+            let policy: &SshRetry = policy
+                .get_or_insert_with(|| Config::read().map(|c| c.ssh_retry()).unwrap_or_default());
+            retry += 1;
+            let Some(delay) = policy.delay_before_retry(retry, start.elapsed()) else {
+                bail!(
+                    "Failed to connect to {} after {retry} attempts ({:.0}s): {}",
+                    self.host_and_port(),
+                    start.elapsed().as_secs_f64(),
+                    get_stderr(&output)
+                );
+            };
+            warn!(
+                "Connection to {} failed (attempt {retry}/{}). Retrying in {:.1}s...",
+                self.host_and_port(),
+                policy.attempts,
+                delay.as_secs_f64()
+            );
+            // The shared connection may be stale after the failure
+            let _ = self.close_connection();
+            thread::sleep(delay);
+        }
+    }
     /// run_cmd_piped will execute the given cmd on a remote machine.
     /// stdio will be pass-throughed to cro3's stdio.
     pub fn run_cmd_piped<T: AsRef<str> + AsRef<OsStr> + std::fmt::Debug>(
@@ -637,8 +1204,7 @@ impl SshInfo {
     ) -> Result<()> {
         let mut ssh = self.ssh_cmd(None)?;
         ssh.args(arg);
-        let cmd = ssh.spawn()?;
-        let result = cmd.wait_with_output()?;
+        let result = ssh.spawn()?.wait_with_output()?;
         result.status.exit_ok().context(anyhow!(
             "run_cmd_piped failed with {:?}. cmd = {:?}",
             result.status.code(),
@@ -648,8 +1214,8 @@ impl SshInfo {
     fn run_cmd_captured(&self, cmd: &str) -> Result<Output> {
         let mut ssh = self.ssh_cmd(None)?;
         ssh.arg(cmd).stdout(Stdio::piped()).stderr(Stdio::piped());
-        let cmd = ssh.spawn()?;
-        let output = cmd
+        let output = ssh
+            .spawn()?
             .wait_with_output()
             .context("wait_with_output failed in run_cmd_captured")?;
         if output.status.success() {
@@ -678,16 +1244,27 @@ impl SshInfo {
         additional_ssh_args: &[&str],
     ) -> Result<async_process::Child> {
         let fwport: String = format!("{}:127.0.0.1:{}", port, dut_port);
-        let mut args = vec![
-            "-L",
-            &fwport,
+        self.spawn_forwarding_process(&["-L", &fwport], command, additional_ssh_args)
+    }
+    fn spawn_forwarding_process(
+        &self,
+        forwarding_args: &[&str],
+        command: &str,
+        additional_ssh_args: &[&str],
+    ) -> Result<async_process::Child> {
+        let mut args = forwarding_args.to_vec();
+        args.extend_from_slice(&[
             "-o",
             "ExitOnForwardFailure yes",
             "-o",
             "ServerAliveInterval=5",
             "-o",
             "ServerAliveCountMax=1",
-        ];
+            // Use a dedicated connection since the reconnection logic relies
+            // on the exit of this process.
+            "-o",
+            "ControlPath=none",
+        ]);
         args.extend(additional_ssh_args.iter());
 
         let child = self
@@ -731,38 +1308,42 @@ impl SshInfo {
         for port in ports {
             // Try to establish port forwarding
             let mut child = self.start_port_forwarding(port, 22, sshcmd, additional_ssh_args)?;
-            let (ssh_stdout, ssh_stderr) = get_async_lines(&mut child);
-            let ssh_stdout = ssh_stdout.context(anyhow!("ssh_stdout was None"))?;
-            let ssh_stderr = ssh_stderr.context(anyhow!("ssh_stderr was None"))?;
-            let mut merged_stream = stream::select(ssh_stdout.fuse(), ssh_stderr.fuse());
-            loop {
-                let mut merged_stream = merged_stream.next();
-                select! {
-                    line = merged_stream => {
-                        if let Some(Ok(line)) = line {
-                            if line.contains(COMMON_PORT_FORWARD_TOKEN) {
-                                info!("cro3: Established SSH port forwarding for {self:?} on {port}");
-                                return Ok((child, port));
-                            }
-                            info!("{line}");
-                            if line.contains("cannot listen to port") {
-                                // Try next port
-                                break;
-                            }
-                        }
-                    }
-                    complete => {
-                            // stdout is closed unexpectedly since ssh process is terminated.
-                            // stderr may contain some info and will be closed as well,
-                            // so do nothing here and wait for activities on stderr stream.
-                        bail!("SSH process streams are closed");
-                    }
-                }
+            if wait_for_forwarding(&mut child, COMMON_PORT_FORWARD_TOKEN).await? {
+                info!("cro3: Established SSH port forwarding for {self:?} on {port}");
+                return Ok((child, port));
             }
+            // Try next port
         }
 
         bail!("Could not find a port available for forwarding")
     }
+    /// Forward `local_port` on this machine to `remote_port` on the DUT, or
+    /// `remote_port` on the DUT to `local_port` on this machine if `reverse`
+    /// is true. The future will be resolved once the forwarding is ready, and
+    /// the forwarding is kept until the returned process exits.
+    pub async fn start_tunnel(
+        &self,
+        local_port: u16,
+        remote_port: u16,
+        reverse: bool,
+    ) -> Result<async_process::Child> {
+        const TUNNEL_TOKEN: &str = "cro3-ssh-tunnel";
+        let forwarding = if reverse {
+            ["-R", &format!("{remote_port}:127.0.0.1:{local_port}")].map(str::to_string)
+        } else {
+            ["-L", &format!("{local_port}:127.0.0.1:{remote_port}")].map(str::to_string)
+        };
+        let forwarding: Vec<&str> = forwarding.iter().map(String::as_str).collect();
+        let mut child = self.spawn_forwarding_process(
+            &forwarding,
+            &format!("echo {TUNNEL_TOKEN}; sleep 8h"),
+            &[],
+        )?;
+        if !wait_for_forwarding(&mut child, TUNNEL_TOKEN).await? {
+            bail!("Port {local_port} is already in use");
+        }
+        Ok(child)
+    }
     /// Keep forwarding in background.
     /// The execution will be blocked until the first attemp succeeds, and the
     /// return value represents which port is used for this forwarding, or an
@@ -869,27 +1450,68 @@ impl SshInfo {
             arc_dir
         ))
     }
+    /// Returns the active lease on the DUT, if any
+    pub fn get_lease(&self) -> Result<Option<DutLease>> {
+        let lease = self.run_cmd_stdio(&format!("cat {DUT_LEASE_PATH} 2>/dev/null || true"))?;
+        if lease.is_empty() {
+            return Ok(None);
+        }
+        let lease: DutLease =
+            serde_json::from_str(&lease).context("Failed to parse the lease on the DUT")?;
+        Ok((!lease.is_expired()).then_some(lease))
+    }
+    pub fn set_lease(&self, lease: &DutLease) -> Result<()> {
+        let lease = STANDARD.encode(serde_json::to_string(lease)?);
+        self.run_cmd_stdio(&format!("echo {lease} | base64 -d > {DUT_LEASE_PATH}"))?;
+        Ok(())
+    }
+    pub fn clear_lease(&self) -> Result<()> {
+        self.run_cmd_stdio(&format!("rm -f {DUT_LEASE_PATH}"))?;
+        Ok(())
+    }
+    /// Copy files from the DUT to `dest` (the current directory by default),
+    /// with rsync if available (only the differences are transferred), or scp.
     pub fn get_files(&self, files: &[String], dest: Option<&String>) -> Result<()> {
-        let mut cmd = self.scp_get_cmd(files, dest)?;
+        let mut cmd = if self.can_use_rsync() {
+            self.rsync_get_cmd(files, dest)?
+        } else {
+            warn!("rsync is not available. Falling back to scp.");
+            self.scp_get_cmd(files, dest)?
+        };
         let chd = cmd.stderr(Stdio::piped()).spawn()?;
         let result = chd.wait_with_output()?;
         let stderr = get_stderr(&result);
         result.status.exit_ok().context(anyhow!(
-            r#"Failed to run scp {cmd:?}:
+            r#"Failed to run {cmd:?}:
 stderr:
     {}"#,
             stderr
         ))
     }
+    /// Copy files to `dest` on the DUT (DEFAULT_PUSH_DEST by default), with
+    /// rsync if available (only the differences are transferred), or scp.
+    /// `dest` is created if it does not exist.
     pub fn send_files(&self, files: &[String], dest: Option<&String>) -> Result<()> {
-        let mut cmd = self.scp_send_cmd(files, dest)?;
+        let dest = dest.map(String::as_str).unwrap_or(DEFAULT_PUSH_DEST);
+        let quoted_dest = if let Some(rest) = dest.strip_prefix("~/") {
+            format!("\"$HOME\"/{}", shell_quote(rest))
+        } else {
+            shell_quote(dest)
+        };
+        self.run_cmd_stdio(&format!("mkdir -p {quoted_dest}"))?;
+        let mut cmd = if self.can_use_rsync() {
+            self.rsync_send_cmd(files, dest)?
+        } else {
+            warn!("rsync is not available. Falling back to scp.");
+            self.scp_send_cmd(files, Some(&dest.to_string()))?
+        };
         let chd = cmd.stderr(Stdio::piped()).spawn()?;
         let result = chd.wait_with_output()?;
         let stderr = get_stderr(&result);
-        result.status.exit_ok().context(anyhow!(
-            "Failed to run scp {cmd:?}:\nstderr:\n    {}",
-            stderr
-        ))
+        result
+            .status
+            .exit_ok()
+            .context(anyhow!("Failed to run {cmd:?}:\nstderr:\n    {}", stderr))
     }
 }
 
@@ -941,7 +1563,11 @@ pub fn fetch_dut_info_in_parallel(
     Ok(addrs
         .par_iter()
         .flat_map(|addr| -> Result<DutInfo> {
-            let addr = &format!("[{}]", addr);
+            let addr = &if addr.contains(':') {
+                format!("[{}]", addr)
+            } else {
+                addr.to_string()
+            };
             // Since we are listing the DUTs on the same network
             // so assume that port 22 is open for ssh
             let ssh = SshInfo::new_host_and_port(addr, 22).context("failed to create SshInfo")?;
@@ -987,9 +1613,112 @@ pub fn discover_local_nodes(iface: Option<String>) -> Result<Vec<String>> {
     Ok(addrs)
 }
 
+/// Returns the host addresses in an IPv4 CIDR range (e.g. 192.168.0.0/24)
+pub fn hosts_in_ipv4_cidr(cidr: &str) -> Result<Vec<Ipv4Addr>> {
+    let (addr, prefix) = cidr.split_once('/').context(anyhow!(
+        "Invalid CIDR {cidr:?}. It should be like 192.168.0.0/24"
+    ))?;
+    let addr = Ipv4Addr::from_str(addr)?;
+    let prefix: u32 = prefix.parse()?;
+    if !(16..=32).contains(&prefix) {
+        bail!("The prefix length should be in 16..=32 to avoid scanning too many hosts");
+    }
+    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    let network = u32::from(addr) & mask;
+    let size = 1u32 << (32 - prefix);
+    let hosts = if size <= 2 {
+        // /31 and /32 do not have network and broadcast addresses
+        (0..size).collect::<Vec<u32>>()
+    } else {
+        (1..size - 1).collect()
+    };
+    Ok(hosts
+        .into_iter()
+        .map(|i| Ipv4Addr::from(network + i))
+        .collect())
+}
+
+/// Returns the SSH banner (e.g. "SSH-2.0-OpenSSH_8.9") if the host talks SSH
+/// on the port
+pub fn probe_ssh_banner(addr: &SocketAddr, timeout: Duration) -> Option<String> {
+    let mut stream = TcpStream::connect_timeout(addr, timeout).ok()?;
+    stream.set_read_timeout(Some(timeout)).ok()?;
+    let mut buf = [0u8; 256];
+    let len = stream.read(&mut buf).ok()?;
+    let banner = String::from_utf8_lossy(&buf[..len]);
+    let banner = banner.lines().next()?.trim();
+    banner.starts_with("SSH-").then(|| banner.to_string())
+}
+
+/// Returns addresses in the CIDR range that accept SSH connections
+pub fn scan_ssh_hosts(cidr: &str) -> Result<Vec<String>> {
+    let hosts = hosts_in_ipv4_cidr(cidr)?;
+    info!("Probing SSH on {} hosts in {cidr}...", hosts.len());
+    // Use a dedicated pool since most of the time is spent on waiting
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(64)
+        .build()
+        .context("Failed to create a thread pool")?;
+    Ok(pool.install(|| {
+        hosts
+            .par_iter()
+            .flat_map(|host| {
+                let addr = SocketAddr::new((*host).into(), 22);
+                let banner = probe_ssh_banner(&addr, Duration::from_millis(500))?;
+                info!("{host}: {banner}");
+                Some(host.to_string())
+            })
+            .collect()
+    }))
+}
+
+/// Returns addresses of the hosts advertising SSH via mDNS (needs avahi)
+pub fn discover_mdns_nodes() -> Result<Vec<String>> {
+    info!("Looking for SSH servers advertised via mDNS...");
+    let output = run_bash_command(
+        "avahi-browse --resolve --parsable --terminate _ssh._tcp",
+        None,
+    )?;
+    output.status.exit_ok().context(anyhow!(
+        "avahi-browse failed. Please install avahi-utils: {}",
+        get_stderr(&output)
+    ))?;
+    // Resolved entries look like:
+    // =;eth0;IPv4;name;_ssh._tcp;local;hostname.local;192.168.0.2;22;
+    let mut addrs: Vec<String> = get_stdout(&output)
+        .lines()
+        .filter(|l| l.starts_with('='))
+        .flat_map(|l| {
+            let fields: Vec<&str> = l.split(';').collect();
+            let iface = fields.get(1)?;
+            let addr = fields.get(7)?;
+            if addr.starts_with("fe80:") {
+                Some(format!("{addr}%{iface}"))
+            } else {
+                Some(addr.to_string())
+            }
+        })
+        .collect();
+    addrs.sort();
+    addrs.dedup();
+    Ok(addrs)
+}
+
 pub fn register_dut(dut: &str) -> Result<DutInfo> {
     info!("Checking DutInfo of {dut:?}...");
     let info = DutInfo::new(dut)?;
+    register_dut_info(info)
+}
+
+/// Register a DUT that is reachable via `jump`
+pub fn register_dut_with_jump(dut: &str, jump: JumpHost) -> Result<DutInfo> {
+    info!("Checking DutInfo of {dut:?} via {}...", jump.host());
+    let ssh = SshInfo::new(dut)?.with_jump(Some(jump));
+    let info = block_on(DutInfo::from_ssh(&ssh, &Vec::new()))?;
+    register_dut_info(info)
+}
+
+fn register_dut_info(info: DutInfo) -> Result<DutInfo> {
     let id = info.id();
     let ssh = info.ssh();
     SSH_CACHE.set(id, ssh.clone())?;
@@ -997,6 +1726,231 @@ pub fn register_dut(dut: &str) -> Result<DutInfo> {
     Ok(info)
 }
 
+/// Returns tags of a DUT, including the ones derived from the DUT list
+/// (id, host, port, board, model) unless they are overridden by the user.
+pub fn dut_tags(id: &str) -> Result<BTreeMap<String, String>> {
+    let mut tags = BTreeMap::new();
+    tags.insert("id".to_string(), id.to_string());
+    if let Some(ssh) = SSH_CACHE.get(id)? {
+        tags.insert("host".to_string(), ssh.host().to_string());
+        tags.insert("port".to_string(), ssh.port().to_string());
+        if let Some(jump) = ssh.jump() {
+            tags.insert("jump".to_string(), jump.host().to_string());
+        }
+    }
+    if let Some(board) = DUT_BOARD_CACHE.get(id)? {
+        tags.insert("board".to_string(), board);
+    }
+    if let Some(model) = DUT_MODEL_CACHE.get(id)? {
+        tags.insert("model".to_string(), model);
+    }
+    tags.extend(DUT_TAGS.get(id)?.unwrap_or_default());
+    Ok(tags)
+}
+
+/// Returns the board of a DUT. /etc/lsb-release on the DUT is read only on the
+/// first contact, and the result is cached until the DUT is flashed again.
+pub fn board_of_dut(dut: &str) -> Result<String> {
+    cached_dut_info(dut, "board", &DUT_BOARD_CACHE)
+}
+
+/// Returns `board` if it is given, or the board of `dut` with a notice
+/// otherwise. If both are given, a warning is shown when they do not match
+/// according to the cached board of the DUT, since building or flashing for a
+/// mistyped board wastes hours.
+pub fn infer_board(board: Option<&str>, dut: Option<&str>) -> Result<String> {
+    match (board, dut) {
+        (Some(board), Some(dut)) => {
+            let id = registered_dut_id(dut).unwrap_or(dut.to_string());
+            if let Some(board_of_dut) = DUT_BOARD_CACHE.get(&id)? {
+                if !is_compatible_board(board, &board_of_dut) {
+                    warn!("--board {board} does not match the board of {dut} ({board_of_dut})");
+                }
+            }
+            Ok(board.to_string())
+        }
+        (Some(board), None) => Ok(board.to_string()),
+        (None, Some(dut)) => {
+            let board = board_of_dut(dut).context(anyhow!(
+                "Failed to get the board of {dut}. Please specify --board."
+            ))?;
+            info!("--board is not given. Using the board of {dut}: {board}");
+            Ok(board)
+        }
+        (None, None) => bail!("Please specify --board or --dut"),
+    }
+}
+
+/// Returns the model of a DUT, cached in the same way as board_of_dut().
+pub fn model_of_dut(dut: &str) -> Result<String> {
+    cached_dut_info(dut, "model", &DUT_MODEL_CACHE)
+}
+
+fn cached_dut_info(dut: &str, key: &str, cache: &KvCache<String>) -> Result<String> {
+    if let Ok(id) = registered_dut_id(dut) {
+        if let Some(value) = cache.get(&id)? {
+            return Ok(value);
+        }
+    }
+    // DutInfo::new() fills the caches
+    DutInfo::new(dut)?
+        .info()
+        .get(key)
+        .cloned()
+        .context(anyhow!("Failed to get the {key} of {dut}"))
+}
+
+/// Forgets the board, model and release of a DUT since they can be changed by
+/// flashing another image.
+pub fn invalidate_dut_image_info(dut: &str) -> Result<()> {
+    if let Ok(id) = registered_dut_id(dut) {
+        DUT_BOARD_CACHE.remove(&id)?;
+        DUT_MODEL_CACHE.remove(&id)?;
+        DUT_RELEASE_CACHE.remove(&id)?;
+    }
+    Ok(())
+}
+
+/// Returns the ID of a registered DUT specified by its ID or address
+pub fn registered_dut_id(dut: &str) -> Result<String> {
+    let duts = SSH_CACHE.entries()?;
+    if duts.contains_key(dut) {
+        return Ok(dut.to_string());
+    }
+    duts.iter()
+        .find(|(_, ssh)| ssh.host() == dut || ssh.host_and_port() == dut)
+        .map(|(id, _)| id.clone())
+        .context(anyhow!(
+            "DUT {dut} is not in the list. Please run `cro3 dut list --add {dut}` first."
+        ))
+}
+
+/// Returns IDs of the registered DUTs that match with the filter expression
+/// (see dut_filter.rs for the syntax), in alphabetical order.
+pub fn filter_duts(filter: &str) -> Result<Vec<String>> {
+    let filter = DutFilter::parse(filter)?;
+    let mut ids: Vec<String> = SSH_CACHE
+        .entries()?
+        .into_keys()
+        .filter(|id| dut_tags(id).map(|t| filter.matches(&t)).unwrap_or(false))
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
+
+/// Expands DUT group names (see `cro3 config set dut_groups`) into the DUTs
+/// in the groups. The others are kept as they are. Duplicates are removed.
+pub fn expand_dut_groups(duts: &[String]) -> Result<Vec<String>> {
+    let config = Config::read()?;
+    let mut expanded: Vec<String> = Vec::new();
+    for dut in duts {
+        let members = config
+            .dut_group(dut)
+            .cloned()
+            .unwrap_or_else(|| vec![dut.clone()]);
+        for member in members {
+            if !expanded.contains(&member) {
+                expanded.push(member);
+            }
+        }
+    }
+    Ok(expanded)
+}
+
+/// Returns true if the base board names (without suffix '64' or '-*') are
+/// matched, e.g. "brya" and "brya-kernelnext".
+pub fn is_compatible_board(a: &str, b: &str) -> bool {
+    let base = |s: &str| -> String { s.chars().take_while(|c| c.is_ascii_alphabetic()).collect() };
+    base(a) == base(b)
+}
+
+fn current_workspace() -> String {
+    find_cros_dir_from_cwd()
+        .or_else(|_| -> Result<String> { Ok(current_dir()?.to_string_lossy().to_string()) })
+        .unwrap_or_default()
+}
+
+/// Determine a DUT to use from a value given via --dut.
+/// If it is omitted, or it matches with multiple DUT IDs, registered DUTs
+/// (filtered to ones compatible with `board`, if known) are shown in an
+/// interactive picker. The choice is remembered per workspace (a cros
+/// checkout, or the current directory) and preselected next time.
+/// If it is a DUT group, the DUTs in the group are the candidates.
+pub fn resolve_dut(dut: &Option<String>, board: Option<&str>) -> Result<String> {
+    let duts = SSH_CACHE
+        .entries()
+        .context(anyhow!("SSH_CACHE is not initialized yet"))?;
+    let mut ids: Vec<String> = duts.keys().cloned().collect();
+    ids.sort();
+    let group = match dut {
+        Some(dut) => Config::read()?.dut_group(dut).cloned(),
+        None => None,
+    };
+    let query = match (dut, group) {
+        (_, Some(group)) if group.len() == 1 => return Ok(group[0].clone()),
+        (Some(dut), Some(group)) if !is_interactive() => bail!(
+            "{dut} is a group of DUTs ({}). Please specify one of them.",
+            group.join(", ")
+        ),
+        (_, Some(group)) => {
+            ids = group;
+            ""
+        }
+        // '_' is not allowed for hostnames, so the others are treated as an
+        // address of a DUT.
+        (Some(dut), None) if !dut.contains('_') || duts.contains_key(dut) => return Ok(dut.clone()),
+        (Some(dut), None) => {
+            let matched: Vec<&String> = ids.iter().filter(|id| id.contains(dut)).collect();
+            match matched.len() {
+                // SshInfo::new() will report the error with a hint
+                0 => return Ok(dut.clone()),
+                1 => return Ok(matched[0].clone()),
+                _ => dut.as_str(),
+            }
+        }
+        (None, None) => "",
+    };
+    if !is_interactive() {
+        bail!("Please specify --dut. `cro3 dut list --ids` shows the registered DUTs.");
+    }
+    let boards = DUT_BOARD_CACHE.entries()?;
+    let mut candidates: Vec<String> = ids
+        .into_iter()
+        .filter(|id| match (board, boards.get(id)) {
+            (Some(board), Some(board_of_dut)) => is_compatible_board(board, board_of_dut),
+            _ => true,
+        })
+        .collect();
+    if candidates.is_empty() {
+        bail!(
+            "No DUTs are registered{}. Please specify --dut or run `cro3 dut list --add` first.",
+            board.map(|b| format!(" for {b}")).unwrap_or_default()
+        );
+    }
+    let workspace = current_workspace();
+    if let Some(last) = LAST_DUT_CACHE.get(&workspace)? {
+        if let Some(pos) = candidates.iter().position(|id| id == &last) {
+            let last = candidates.remove(pos);
+            candidates.insert(0, last);
+        }
+    }
+    let labels: Vec<String> = candidates
+        .iter()
+        .map(|id| {
+            format!(
+                "{:32} {:16} {}",
+                id,
+                boards.get(id).map(String::as_str).unwrap_or("-"),
+                duts.get(id).map(|s| s.host_and_port()).unwrap_or_default()
+            )
+        })
+        .collect();
+    let id = candidates[pick("Choose a DUT", &labels, query)?].clone();
+    LAST_DUT_CACHE.set(&workspace, id.clone())?;
+    info!("Using DUT {id}");
+    Ok(id)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1077,6 +2031,76 @@ mod tests {
         assert_eq!(result_actual.expect("result should be Ok"), result_expected);
     }
     #[test]
+    fn compatible_board() {
+        assert!(is_compatible_board("brya", "brya"));
+        assert!(is_compatible_board("brya", "brya-kernelnext"));
+        assert!(is_compatible_board("octopus64", "octopus"));
+        assert!(!is_compatible_board("brya", "brask"));
+    }
+    #[test]
+    fn ipv4_cidr() {
+        let hosts = hosts_in_ipv4_cidr("192.168.1.77/30").unwrap();
+        assert_eq!(
+            hosts,
+            vec![
+                Ipv4Addr::new(192, 168, 1, 77),
+                Ipv4Addr::new(192, 168, 1, 78)
+            ]
+        );
+        assert_eq!(hosts_in_ipv4_cidr("10.0.0.0/24").unwrap().len(), 254);
+        assert_eq!(hosts_in_ipv4_cidr("10.0.0.1/32").unwrap().len(), 1);
+        assert!(hosts_in_ipv4_cidr("10.0.0.0/8").is_err());
+        assert!(hosts_in_ipv4_cidr("10.0.0.0").is_err());
+    }
+    #[test]
+    fn rsync_quote() {
+        assert_eq!(quote_for_rsync("-p"), "-p");
+        assert_eq!(quote_for_rsync("a b"), "'a b'");
+        assert_eq!(
+            quote_for_rsync("ProxyCommand=ssh -W '[%h]:%p' jump"),
+            "\"ProxyCommand=ssh -W '[%h]:%p' jump\""
+        );
+    }
+    #[test]
+    fn jump_host_proxy_command() {
+        assert!(JumpHost::new("user@", None, false, false).is_err());
+        let jump = JumpHost::new(
+            "me@bastion.example:2222",
+            Some("~/my key".to_string()),
+            false,
+            true,
+        )
+        .unwrap();
+        assert_eq!(
+            jump.gen_proxy_command().unwrap(),
+            "ssh -W '[%h]:%p' -i '~/my key' -p 2222 me@bastion.example"
+        );
+        let jump = JumpHost::new("labstation.example", None, false, false).unwrap();
+        let command = jump.gen_proxy_command().unwrap();
+        assert!(command.starts_with("ssh -W '[%h]:%p' -o ControlMaster=auto -o "));
+        assert!(command.contains("/jump-%%C"));
+        assert!(command.ends_with("-o ControlPersist=10m labstation.example"));
+    }
+    #[test]
+    fn configured_proxy_jump() {
+        let config: Config =
+            serde_json::from_str(r#"{"proxy_jump": {"^dut[0-9]+\\.lab$": "me@bastion.example"}}"#)
+                .unwrap();
+        assert_eq!(
+            JumpHost::from_proxy_jump_of(&config, "192.0.2.1").unwrap(),
+            None
+        );
+        let jump = JumpHost::from_proxy_jump_of(&config, "dut1.lab").unwrap();
+        let ssh = SshInfo::new_host_and_port("dut1.lab", 22)
+            .unwrap()
+            .with_jump(jump);
+        let jump = ssh.jump().unwrap();
+        assert_eq!(jump.host(), "me@bastion.example");
+        let command = jump.gen_proxy_command().unwrap();
+        assert!(command.contains("/jump-%%C"));
+        assert!(command.ends_with(" me@bastion.example"));
+    }
+    #[test]
     fn default_dut_info_has_no_env_specific_keys() {
         assert!(!DEFAULT_DUT_INFO_KEYS
             .iter()
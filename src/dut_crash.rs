@@ -0,0 +1,127 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Crash reports on DUTs. crash_reporter writes each crash as a set of files
+//! sharing the same name (e.g. chrome.20231010.123456.12345.{meta,dmp,log})
+//! into the crash directories below.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+
+use crate::dut::SshInfo;
+
+const CRASH_DIRS: [&str; 3] = [
+    "/var/spool/crash",
+    "/home/chronos/crash",
+    "/home/chronos/u-*/crash",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrashReport {
+    /// Directory on the DUT which has the files of the report
+    pub dir: String,
+    /// Common part of the file names (e.g. chrome.20231010.123456.12345)
+    pub name: String,
+    /// Unix time of the newest file
+    pub time: i64,
+    /// Total size of the files in bytes
+    pub size: u64,
+    /// File names in the dir
+    pub files: Vec<String>,
+}
+impl CrashReport {
+    pub fn paths(&self) -> Vec<String> {
+        self.files
+            .iter()
+            .map(|f| format!("{}/{f}", self.dir))
+            .collect()
+    }
+}
+
+/// Parses lines of `stat -c '%Y %s %n'` into crash reports, sorted by time
+pub fn parse_crash_listing(output: &str) -> Vec<CrashReport> {
+    let mut reports: BTreeMap<(String, String), CrashReport> = BTreeMap::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.splitn(3, ' ').collect();
+        let (time, size, path) = if let [time, size, path] = fields.as_slice() {
+            (time.parse::<i64>(), size.parse::<u64>(), *path)
+        } else {
+            continue;
+        };
+        let (time, size) = if let (Ok(time), Ok(size)) = (time, size) {
+            (time, size)
+        } else {
+            continue;
+        };
+        // Files without an extension are not a part of reports
+        let (dir, name) = if let Some((dir, file)) = path.rsplit_once('/') {
+            match file.rsplit_once('.') {
+                Some((name, _ext)) => (dir, name),
+                None => continue,
+            }
+        } else {
+            continue;
+        };
+        let file = &path[dir.len() + 1..];
+        let report = reports
+            .entry((dir.to_string(), name.to_string()))
+            .or_insert(CrashReport {
+                dir: dir.to_string(),
+                name: name.to_string(),
+                time,
+                size: 0,
+                files: Vec::new(),
+            });
+        report.time = report.time.max(time);
+        report.size += size;
+        report.files.push(file.to_string());
+    }
+    let mut reports: Vec<CrashReport> = reports.into_values().collect();
+    reports.sort_by_key(|r| r.time);
+    reports
+}
+
+pub fn list_crashes(ssh: &SshInfo) -> Result<Vec<CrashReport>> {
+    let output = ssh.run_cmd_stdio(&format!(
+        "find {} -maxdepth 1 -type f -exec stat -c '%Y %s %n' {{}} + 2>/dev/null || true",
+        CRASH_DIRS.join(" ")
+    ))?;
+    Ok(parse_crash_listing(&output))
+}
+
+pub fn remove_crash(ssh: &SshInfo, report: &CrashReport) -> Result<()> {
+    ssh.run_cmd_stdio(&format!("rm -f {}", report.paths().join(" ")))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn crash_listing() {
+        let output = "\
+1700000010 100 /var/spool/crash/chrome.20231114.222650.12345.meta
+1700000005 2000 /var/spool/crash/chrome.20231114.222650.12345.dmp
+1700000000 300 /home/chronos/u-abc/crash/powerd.20231114.222640.99.log
+1700000001 10 /home/chronos/u-abc/crash/powerd.20231114.222640.99.meta
+broken line
+";
+        let reports = parse_crash_listing(output);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].name, "powerd.20231114.222640.99");
+        assert_eq!(reports[0].dir, "/home/chronos/u-abc/crash");
+        assert_eq!(reports[0].time, 1700000001);
+        assert_eq!(reports[1].size, 2100);
+        assert_eq!(
+            reports[1].paths(),
+            vec![
+                "/var/spool/crash/chrome.20231114.222650.12345.meta",
+                "/var/spool/crash/chrome.20231114.222650.12345.dmp"
+            ]
+        );
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Export of the DUT list (with the cached board, model, release and tags)
+//! for lab schedulers and spreadsheets. See `cro3 dut export`.
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::dut::DUT_BOARD_CACHE;
+use crate::dut::DUT_MODEL_CACHE;
+use crate::dut::DUT_RELEASE_CACHE;
+use crate::dut::DUT_TAGS;
+use crate::dut::SSH_CACHE;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DutRecord {
+    pub id: String,
+    pub host: String,
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jump: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub board: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub release: Option<String>,
+    /// User-defined tags (`cro3 dut tag`)
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+}
+
+/// Returns the records of all the registered DUTs, sorted by ID. Only the
+/// cached values are used, so no DUTs are contacted.
+pub fn collect_dut_records() -> Result<Vec<DutRecord>> {
+    let boards = DUT_BOARD_CACHE.entries()?;
+    let models = DUT_MODEL_CACHE.entries()?;
+    let releases = DUT_RELEASE_CACHE.entries()?;
+    let tags = DUT_TAGS.entries()?;
+    let mut records: Vec<DutRecord> = SSH_CACHE
+        .entries()?
+        .into_iter()
+        .map(|(id, ssh)| DutRecord {
+            host: ssh.host().to_string(),
+            port: ssh.port(),
+            jump: ssh.jump().map(|j| j.host().to_string()),
+            board: boards.get(&id).cloned(),
+            model: models.get(&id).cloned(),
+            release: releases.get(&id).cloned(),
+            tags: tags.get(&id).cloned().unwrap_or_default(),
+            id,
+        })
+        .collect();
+    records.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(records)
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Returns a CSV with a header row. Each tag gets its own "tag:<key>" column.
+pub fn to_csv(records: &[DutRecord]) -> String {
+    let tag_keys: BTreeSet<&String> = records.iter().flat_map(|r| r.tags.keys()).collect();
+    let mut header: Vec<String> = ["id", "host", "port", "jump", "board", "model", "release"]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+    header.extend(tag_keys.iter().map(|k| format!("tag:{k}")));
+    let mut lines = vec![header
+        .iter()
+        .map(|s| csv_field(s))
+        .collect::<Vec<_>>()
+        .join(",")];
+    for r in records {
+        let mut row = vec![
+            r.id.clone(),
+            r.host.clone(),
+            r.port.to_string(),
+            r.jump.clone().unwrap_or_default(),
+            r.board.clone().unwrap_or_default(),
+            r.model.clone().unwrap_or_default(),
+            r.release.clone().unwrap_or_default(),
+        ];
+        row.extend(
+            tag_keys
+                .iter()
+                .map(|k| r.tags.get(*k).cloned().unwrap_or_default()),
+        );
+        lines.push(
+            row.iter()
+                .map(|s| csv_field(s))
+                .collect::<Vec<_>>()
+                .join(","),
+        );
+    }
+    lines.join("\n") + "\n"
+}
+
+/// Returns Swarming bot dimensions of each DUT (key: DUT ID), in the same
+/// naming as the lab (dut_name, label-board, label-model, ...). Tags are
+/// exported as label-<key>, and a tag without a value as "True".
+pub fn to_swarming_dimensions(
+    records: &[DutRecord],
+) -> BTreeMap<String, BTreeMap<String, Vec<String>>> {
+    records
+        .iter()
+        .map(|r| {
+            let mut dims: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            dims.insert("dut_name".to_string(), vec![r.id.clone()]);
+            if let Some(board) = &r.board {
+                dims.insert("label-board".to_string(), vec![board.clone()]);
+            }
+            if let Some(model) = &r.model {
+                dims.insert("label-model".to_string(), vec![model.clone()]);
+            }
+            if let Some(release) = &r.release {
+                dims.insert("label-os_version".to_string(), vec![release.clone()]);
+            }
+            for (k, v) in &r.tags {
+                let v = if v.is_empty() { "True" } else { v };
+                dims.insert(format!("label-{k}"), vec![v.to_string()]);
+            }
+            (r.id.clone(), dims)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn export_formats() {
+        let records = vec![
+            DutRecord {
+                id: "brya_abc".to_string(),
+                host: "192.168.0.2".to_string(),
+                port: 22,
+                jump: None,
+                board: Some("brya".to_string()),
+                model: Some("taniks".to_string()),
+                release: Some("15604.0.0 (Test Build), dev".to_string()),
+                tags: BTreeMap::from([
+                    ("location".to_string(), "desk".to_string()),
+                    ("has_servo".to_string(), "".to_string()),
+                ]),
+            },
+            DutRecord {
+                id: "octopus_def".to_string(),
+                host: "192.168.0.3".to_string(),
+                port: 2222,
+                jump: Some("root@lab".to_string()),
+                board: None,
+                model: None,
+                release: None,
+                tags: BTreeMap::new(),
+            },
+        ];
+        assert_eq!(
+            to_csv(&records),
+            "id,host,port,jump,board,model,release,tag:has_servo,tag:location
+brya_abc,192.168.0.2,22,,brya,taniks,\"15604.0.0 (Test Build), dev\",,desk
+octopus_def,192.168.0.3,2222,root@lab,,,,,
+"
+        );
+        let dims = to_swarming_dimensions(&records);
+        assert_eq!(dims["brya_abc"]["label-board"], vec!["brya"]);
+        assert_eq!(dims["brya_abc"]["label-has_servo"], vec!["True"]);
+        assert_eq!(dims["brya_abc"]["label-location"], vec!["desk"]);
+        assert_eq!(dims["octopus_def"].len(), 1);
+    }
+}
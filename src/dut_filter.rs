@@ -0,0 +1,223 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Filter expressions to choose DUTs by their tags, e.g.
+//! `board==brya && has_servo`, `owner!=me || !(location=~"lab*")`.
+//!
+//! - `key` is true if the tag exists and is not one of "", "0", "false", "no"
+//! - `key==value`, `key!=value` compare the tag value as a string
+//! - `key=~pattern` matches the tag value with a glob pattern
+//! - `!`, `&&`, `||` and parentheses can be used to combine them
+
+use std::collections::BTreeMap;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use glob::Pattern;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Eq,
+    Ne,
+    Match,
+    Not,
+    And,
+    Or,
+    LParen,
+    RParen,
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            ' ' | '\t' => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '&' if chars.next_if_eq(&'&').is_some() => Token::And,
+            '|' if chars.next_if_eq(&'|').is_some() => Token::Or,
+            '=' if chars.next_if_eq(&'=').is_some() => Token::Eq,
+            '=' if chars.next_if_eq(&'~').is_some() => Token::Match,
+            '!' if chars.next_if_eq(&'=').is_some() => Token::Ne,
+            '!' => Token::Not,
+            '"' | '\'' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(ch) => word.push(ch),
+                        None => bail!("Unterminated quote in filter: {expr}"),
+                    }
+                }
+                Token::Word(word)
+            }
+            c if is_word_char(c) => {
+                let mut word = c.to_string();
+                while let Some(ch) = chars.next_if(|ch| is_word_char(*ch)) {
+                    word.push(ch);
+                }
+                Token::Word(word)
+            }
+            c => bail!("Unexpected character {c:?} in filter: {expr}"),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || "_-.:/*?[]@".contains(c)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Has(String),
+    Eq(String, String),
+    Ne(String, String),
+    Match(String, Pattern),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+impl Expr {
+    fn eval(&self, tags: &BTreeMap<String, String>) -> bool {
+        let get = |k: &str| tags.get(k).map(String::as_str);
+        match self {
+            Expr::Has(k) => !matches!(get(k), None | Some("" | "0" | "false" | "no")),
+            Expr::Eq(k, v) => get(k) == Some(v),
+            Expr::Ne(k, v) => get(k) != Some(v),
+            Expr::Match(k, p) => get(k).map(|v| p.matches(v)).unwrap_or(false),
+            Expr::Not(e) => !e.eval(tags),
+            Expr::And(a, b) => a.eval(tags) && b.eval(tags),
+            Expr::Or(a, b) => a.eval(tags) || b.eval(tags),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut lhs = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            lhs = Expr::And(Box::new(lhs), Box::new(self.parse_unary()?));
+        }
+        Ok(lhs)
+    }
+    fn parse_unary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::LParen) => {
+                let e = self.parse_or()?;
+                if self.next() != Some(Token::RParen) {
+                    bail!("Missing ')'");
+                }
+                Ok(e)
+            }
+            Some(Token::Word(key)) => {
+                let op = match self.peek() {
+                    Some(Token::Eq | Token::Ne | Token::Match) => self.next(),
+                    _ => return Ok(Expr::Has(key)),
+                };
+                let value = match self.next() {
+                    Some(Token::Word(v)) => v,
+                    t => bail!("Expected a value after {key}{op:?} but got {t:?}"),
+                };
+                Ok(match op {
+                    Some(Token::Eq) => Expr::Eq(key, value),
+                    Some(Token::Ne) => Expr::Ne(key, value),
+                    _ => Expr::Match(
+                        key,
+                        Pattern::new(&value).context("Invalid glob pattern in filter")?,
+                    ),
+                })
+            }
+            t => bail!("Expected a tag name, '!' or '(' but got {t:?}"),
+        }
+    }
+}
+
+/// DutFilter is a parsed filter expression
+#[derive(Debug, Clone, PartialEq)]
+pub struct DutFilter {
+    expr: Expr,
+}
+impl DutFilter {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(expr)?,
+            pos: 0,
+        };
+        let e = parser
+            .parse_or()
+            .context(format!("Failed to parse filter: {expr}"))?;
+        if let Some(t) = parser.peek() {
+            bail!("Unexpected {t:?} in filter: {expr}");
+        }
+        Ok(Self { expr: e })
+    }
+    pub fn matches(&self, tags: &BTreeMap<String, String>) -> bool {
+        self.expr.eval(tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn tags(kv: &[(&str, &str)]) -> BTreeMap<String, String> {
+        kv.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+    #[test]
+    fn filter() {
+        let brya = tags(&[("board", "brya"), ("has_servo", "true"), ("owner", "alice")]);
+        let volteer = tags(&[("board", "volteer"), ("has_servo", "false")]);
+        let f = DutFilter::parse("board==brya && has_servo").unwrap();
+        assert!(f.matches(&brya));
+        assert!(!f.matches(&volteer));
+        let f = DutFilter::parse("!has_servo || owner != alice").unwrap();
+        assert!(!f.matches(&brya));
+        assert!(f.matches(&volteer));
+        let f = DutFilter::parse("board=~'br*' && (owner==alice || owner==bob)").unwrap();
+        assert!(f.matches(&brya));
+        assert!(!f.matches(&volteer));
+        // && binds tighter than ||
+        let f = DutFilter::parse("board==volteer || board==brya && owner==bob").unwrap();
+        assert!(!f.matches(&brya));
+        assert!(f.matches(&volteer));
+    }
+    #[test]
+    fn filter_errors() {
+        assert!(DutFilter::parse("board==").is_err());
+        assert!(DutFilter::parse("(board==brya").is_err());
+        assert!(DutFilter::parse("board==brya owner").is_err());
+        assert!(DutFilter::parse("board & has_servo").is_err());
+        assert!(DutFilter::parse("board=='brya").is_err());
+    }
+}
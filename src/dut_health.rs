@@ -0,0 +1,161 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Health records of DUTs, collected periodically by `cro3 dut monitor
+//! --health` and appended to ~/.cro3/dut_health.jsonl as a time series.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use anyhow::Result;
+use chrono::Local;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::dut::SshInfo;
+use crate::util::cro3_paths::gen_path_in_cro3_dir;
+use crate::util::shell_helpers::get_stdout;
+
+/// A shell command to collect the health info in one ssh round trip
+const HEALTH_CMD: &str = r"
+echo uptime=$(cut -d ' ' -f 1 /proc/uptime)
+echo boot_id=$(cat /proc/sys/kernel/random/boot_id)
+echo temp=$(cat /sys/class/thermal/thermal_zone*/temp 2>/dev/null | sort -n | tail -n 1)
+echo disk=$(df --output=pcent /mnt/stateful_partition | tail -n 1 | tr -d ' %')
+";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DutHealth {
+    /// Unix time when the record is taken
+    pub time: i64,
+    pub dut: String,
+    pub reachable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub uptime_sec: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub boot_id: Option<String>,
+    /// The highest temperature among the thermal zones
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub temp_celsius: Option<f64>,
+    /// Usage of the stateful partition
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub disk_usage_percent: Option<u32>,
+}
+impl DutHealth {
+    fn unreachable(dut: &str) -> Self {
+        Self {
+            time: Local::now().timestamp(),
+            dut: dut.to_string(),
+            reachable: false,
+            uptime_sec: None,
+            boot_id: None,
+            temp_celsius: None,
+            disk_usage_percent: None,
+        }
+    }
+    /// Parses the output of HEALTH_CMD
+    pub fn parse(dut: &str, output: &str) -> Self {
+        let mut health = Self::unreachable(dut);
+        health.reachable = true;
+        for (k, v) in output.lines().filter_map(|l| l.trim().split_once('=')) {
+            match k {
+                "uptime" => health.uptime_sec = v.parse().ok(),
+                "boot_id" => health.boot_id = (!v.is_empty()).then(|| v.to_string()),
+                "temp" => health.temp_celsius = v.parse::<f64>().ok().map(|t| t / 1000.0),
+                "disk" => health.disk_usage_percent = v.parse().ok(),
+                _ => {}
+            }
+        }
+        health
+    }
+    /// Collects the health info of a DUT. An unreachable DUT is reported as
+    /// such instead of an error.
+    pub fn fetch(dut: &str) -> Self {
+        let output = SshInfo::new(dut).and_then(|ssh| {
+            Ok(ssh
+                .ssh_cmd_without_retry(Some(&["-o", "ConnectTimeout=10"]))?
+                .arg(HEALTH_CMD)
+                .output()?)
+        });
+        match output {
+            Ok(output) if output.status.success() => Self::parse(dut, &get_stdout(&output)),
+            _ => Self::unreachable(dut),
+        }
+    }
+    /// Returns alerts by comparing with the previous record of the same DUT
+    pub fn alerts(&self, prev: Option<&DutHealth>) -> Vec<String> {
+        let mut alerts = Vec::new();
+        let prev = if let Some(prev) = prev {
+            prev
+        } else {
+            return alerts;
+        };
+        match (prev.reachable, self.reachable) {
+            (true, false) => alerts.push(format!("{} became unreachable", self.dut)),
+            (false, true) => alerts.push(format!("{} is reachable again", self.dut)),
+            _ => {}
+        }
+        if let (Some(prev_boot_id), Some(boot_id)) = (&prev.boot_id, &self.boot_id) {
+            if prev_boot_id != boot_id {
+                alerts.push(format!(
+                    "{} rebooted (uptime: {:.0}s)",
+                    self.dut,
+                    self.uptime_sec.unwrap_or_default()
+                ));
+            }
+        }
+        alerts
+    }
+    /// Returns the sample to compare the next one with. The boot ID of `prev`
+    /// is kept while the DUT is unreachable, so that a reboot meanwhile is
+    /// still detected.
+    pub fn to_remember(&self, prev: Option<&DutHealth>) -> DutHealth {
+        let mut h = self.clone();
+        if !h.reachable {
+            h.boot_id = prev.and_then(|p| p.boot_id.clone());
+        }
+        h
+    }
+    pub fn append_to_log(&self) -> Result<()> {
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(gen_path_in_cro3_dir("dut_health.jsonl")?)?;
+        writeln!(f, "{}", serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn parse_and_alerts() {
+        let output = "uptime=123.45\nboot_id=aaaa\ntemp=45500\ndisk=37\n";
+        let h1 = DutHealth::parse("dut1", output);
+        assert!(h1.reachable);
+        assert_eq!(h1.uptime_sec, Some(123.45));
+        assert_eq!(h1.boot_id.as_deref(), Some("aaaa"));
+        assert_eq!(h1.temp_celsius, Some(45.5));
+        assert_eq!(h1.disk_usage_percent, Some(37));
+        let h2 = DutHealth::parse("dut1", "uptime=10.0\nboot_id=bbbb\ntemp=\ndisk=37\n");
+        assert_eq!(h2.temp_celsius, None);
+        assert_eq!(h2.alerts(Some(&h1)), vec!["dut1 rebooted (uptime: 10s)"]);
+        assert!(h1.alerts(None).is_empty());
+        let h3 = DutHealth::unreachable("dut1");
+        assert_eq!(h3.alerts(Some(&h2)), vec!["dut1 became unreachable"]);
+        assert_eq!(h2.alerts(Some(&h3)), vec!["dut1 is reachable again"]);
+        let h4 = DutHealth::parse("dut1", "uptime=5.0\nboot_id=cccc\ntemp=\ndisk=37\n");
+        assert_eq!(
+            h4.alerts(Some(&h3.to_remember(Some(&h2)))),
+            vec!["dut1 is reachable again", "dut1 rebooted (uptime: 5s)"]
+        );
+    }
+}
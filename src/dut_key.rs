@@ -0,0 +1,135 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Per-user SSH keys for DUTs. The key is kept in ~/.cro3/ssh and is offered
+//! before the well-known testing_rsa, so that shared DUTs can be accessed
+//! even after the testing key is removed from them.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::cache::KvCache;
+use crate::dut::SshInfo;
+use crate::util::cro3_paths::gen_path_in_cro3_dir;
+use crate::util::shell_helpers::shell_quote;
+
+/// Public key installed on each DUT (key: DUT ID)
+pub static DUT_USER_KEY_CACHE: KvCache<String> = KvCache::new("dut_user_key_cache");
+
+const AUTHORIZED_KEYS: &str = "/root/.ssh/authorized_keys";
+/// The rootfs is read-only unless rootfs verification is disabled
+const REMOUNT_ROOTFS: &str = "mount -o remount,rw / 2>/dev/null";
+
+pub fn user_key_path() -> Result<PathBuf> {
+    gen_path_in_cro3_dir("ssh/id_ed25519")
+}
+
+fn public_key_path(key: &Path) -> PathBuf {
+    let mut path = key.as_os_str().to_owned();
+    path.push(".pub");
+    path.into()
+}
+
+/// Returns the public key of the user, if generated
+pub fn user_public_key() -> Result<Option<String>> {
+    let path = public_key_path(&user_key_path()?);
+    if !path.exists() {
+        return Ok(None);
+    }
+    Ok(Some(fs::read_to_string(&path)?.trim().to_string()))
+}
+
+/// Generates a new key pair at `path` and returns the public key
+pub fn generate_key(path: &Path) -> Result<String> {
+    let user = whoami::username();
+    let host = whoami::fallible::hostname().unwrap_or("unknown".to_string());
+    for p in [path.to_path_buf(), public_key_path(path)] {
+        if p.exists() {
+            fs::remove_file(&p)?;
+        }
+    }
+    Command::new("ssh-keygen")
+        .args(["-q", "-t", "ed25519", "-N", ""])
+        .arg("-C")
+        .arg(format!("cro3 {user}@{host}"))
+        .arg("-f")
+        .arg(path)
+        .status()
+        .context("Failed to run ssh-keygen")?
+        .exit_ok()
+        .context("ssh-keygen failed")?;
+    Ok(fs::read_to_string(public_key_path(path))?
+        .trim()
+        .to_string())
+}
+
+/// Replaces the user key with the one at `path` (e.g. generated for rotation)
+pub fn replace_user_key(path: &Path) -> Result<()> {
+    let user_key = user_key_path()?;
+    fs::rename(public_key_path(path), public_key_path(&user_key))?;
+    fs::rename(path, &user_key)?;
+    Ok(())
+}
+
+/// Returns the base64 part of a public key, which identifies the key
+/// regardless of its comment
+pub fn key_body(pubkey: &str) -> Result<&str> {
+    pubkey
+        .split_whitespace()
+        .nth(1)
+        .context(anyhow!("Invalid public key: {pubkey}"))
+}
+
+pub fn install_key(ssh: &SshInfo, pubkey: &str) -> Result<()> {
+    let body = shell_quote(key_body(pubkey)?);
+    let pubkey = shell_quote(pubkey);
+    let result = ssh.run_cmd_stdio(&format!(
+        "{REMOUNT_ROOTFS}; mkdir -p /root/.ssh && touch {AUTHORIZED_KEYS} && (grep -qF {body} \
+         {AUTHORIZED_KEYS} || echo {pubkey} >> {AUTHORIZED_KEYS}) && chmod 600 {AUTHORIZED_KEYS}"
+    ));
+    if result.is_err() {
+        bail!(
+            "Failed to update {AUTHORIZED_KEYS}. Is rootfs verification disabled on {}?",
+            ssh.host_and_port()
+        );
+    }
+    Ok(())
+}
+
+pub fn remove_key(ssh: &SshInfo, pubkey: &str) -> Result<()> {
+    let body = shell_quote(key_body(pubkey)?);
+    ssh.run_cmd_stdio(&format!(
+        "{REMOUNT_ROOTFS}; [ ! -f {AUTHORIZED_KEYS} ] || {{ grep -vF {body} {AUTHORIZED_KEYS} > \
+         {AUTHORIZED_KEYS}.cro3; mv {AUTHORIZED_KEYS}.cro3 {AUTHORIZED_KEYS} && chmod 600 \
+         {AUTHORIZED_KEYS}; }}"
+    ))
+    .context(anyhow!("Failed to update {AUTHORIZED_KEYS}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn public_key() {
+        assert_eq!(
+            key_body("ssh-ed25519 AAAAC3Nza cro3 user@host").unwrap(),
+            "AAAAC3Nza"
+        );
+        assert!(key_body("garbage").is_err());
+        assert_eq!(
+            public_key_path(Path::new("/a/id_ed25519")),
+            PathBuf::from("/a/id_ed25519.pub")
+        );
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Network configuration of DUTs via shill (the connection manager of
+//! ChromiumOS) over SSH. Services configured here are saved in the shill
+//! profile, so the settings persist across reboots (but not flashing with
+//! --clobber-stateful).
+
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::dut::SshInfo;
+use crate::util::shell_helpers::shell_quote;
+
+lazy_static! {
+    static ref RE_SERVICE_PATH: Regex = Regex::new(r"/service/\d+").unwrap();
+    static ref RE_SERVICE_STATE: Regex = Regex::new(r"'State': <'([a-z-]+)'>").unwrap();
+}
+
+/// States of a shill service that have an IP connectivity
+const CONNECTED_STATES: [&str; 4] = ["online", "ready", "portal", "no-connectivity"];
+
+/// Returns a GVariant string literal of `s`
+pub fn gvariant_str(s: &str) -> String {
+    format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+/// Returns the properties (a{sv} in GVariant text format) to configure a WiFi
+/// service with. Open networks are used if `psk` is None.
+pub fn wifi_properties(ssid: &str, psk: Option<&str>, hidden: bool) -> String {
+    let mut props = vec![
+        "'Type': <'wifi'>".to_string(),
+        format!("'SSID': <{}>", gvariant_str(ssid)),
+        format!("'WiFi.HiddenSSID': <{hidden}>"),
+        "'AutoConnect': <true>".to_string(),
+        "'SaveCredentials': <true>".to_string(),
+    ];
+    if let Some(psk) = psk {
+        props.push("'SecurityClass': <'psk'>".to_string());
+        props.push(format!("'Passphrase': <{}>", gvariant_str(psk)));
+    } else {
+        props.push("'SecurityClass': <'none'>".to_string());
+    }
+    format!("{{{}}}", props.join(", "))
+}
+
+/// Returns the StaticIPConfig property (a{sv} in GVariant text format) for
+/// `address` in CIDR notation (e.g. 192.168.0.10/24)
+pub fn static_ip_properties(address: &str, gateway: &str, dns: &[String]) -> Result<String> {
+    let (addr, prefix) = address.split_once('/').context(anyhow!(
+        "{address} should be in CIDR notation (e.g. 192.168.0.10/24)"
+    ))?;
+    let addr: Ipv4Addr = addr.parse().context(anyhow!("Invalid address: {addr}"))?;
+    let prefix: u8 = prefix
+        .parse()
+        .context(anyhow!("Invalid prefix: {prefix}"))?;
+    if prefix > 32 {
+        bail!("Invalid prefix: {prefix}");
+    }
+    let gateway: Ipv4Addr = gateway
+        .parse()
+        .context(anyhow!("Invalid gateway: {gateway}"))?;
+    let mut props = vec![
+        format!("'Address': <'{addr}'>"),
+        format!("'Prefixlen': <int32 {prefix}>"),
+        format!("'Gateway': <'{gateway}'>"),
+    ];
+    if !dns.is_empty() {
+        let servers: Result<Vec<String>> = dns
+            .iter()
+            .map(|s| {
+                let s: Ipv4Addr = s.parse().context(anyhow!("Invalid DNS server: {s}"))?;
+                Ok(format!("'{s}'"))
+            })
+            .collect();
+        props.push(format!("'NameServers': <[{}]>", servers?.join(", ")));
+    }
+    Ok(format!("{{{}}}", props.join(", ")))
+}
+
+fn shill_call(ssh: &SshInfo, path: &str, method: &str, args: &[&str]) -> Result<String> {
+    let args: Vec<String> = args.iter().map(|a| shell_quote(a)).collect();
+    ssh.run_cmd_stdio(&format!(
+        "gdbus call --system --dest org.chromium.flimflam --object-path {path} --method \
+         org.chromium.flimflam.{method} {}",
+        args.join(" ")
+    ))
+    .context(anyhow!("Failed to call {method} of shill"))
+}
+
+/// Configures a WiFi service and connects to it. Returns the service path.
+pub fn configure_wifi(
+    ssh: &SshInfo,
+    ssid: &str,
+    psk: Option<&str>,
+    hidden: bool,
+) -> Result<String> {
+    let output = shill_call(
+        ssh,
+        "/",
+        "Manager.ConfigureService",
+        &[&wifi_properties(ssid, psk, hidden)],
+    )?;
+    let service = RE_SERVICE_PATH
+        .find(&output)
+        .context(anyhow!("Unexpected output of ConfigureService: {output}"))?
+        .as_str()
+        .to_string();
+    shill_call(ssh, &service, "Service.Connect", &[])?;
+    Ok(service)
+}
+
+pub fn service_state(ssh: &SshInfo, service: &str) -> Result<String> {
+    let output = shill_call(ssh, service, "Service.GetProperties", &[])?;
+    Ok(RE_SERVICE_STATE
+        .captures(&output)
+        .and_then(|c| c.get(1))
+        .context(anyhow!("No State in the properties of {service}"))?
+        .as_str()
+        .to_string())
+}
+
+/// Waits until a service is connected. "failure" is reported immediately.
+pub fn wait_for_connection(ssh: &SshInfo, service: &str, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        let state = service_state(ssh, service)?;
+        if CONNECTED_STATES.contains(&state.as_str()) {
+            return Ok(());
+        }
+        if state == "failure" || start.elapsed() > timeout {
+            bail!("Failed to connect (state: {state})");
+        }
+        thread::sleep(Duration::from_secs(2));
+    }
+}
+
+/// Returns the connected service of the type (e.g. ethernet, wifi)
+pub fn connected_service(ssh: &SshInfo, service_type: &str) -> Result<String> {
+    let output = shill_call(ssh, "/", "Manager.GetProperties", &[])?;
+    for service in RE_SERVICE_PATH.find_iter(&output) {
+        let props = shill_call(ssh, service.as_str(), "Service.GetProperties", &[])?;
+        let state = RE_SERVICE_STATE
+            .captures(&props)
+            .and_then(|c| c.get(1))
+            .map(|m| m.as_str())
+            .unwrap_or_default();
+        if props.contains(&format!("'Type': <'{service_type}'>"))
+            && CONNECTED_STATES.contains(&state)
+        {
+            return Ok(service.as_str().to_string());
+        }
+    }
+    bail!("No connected {service_type} service is found")
+}
+
+/// Sets (or clears, if `props` is None) the static IP config of a service.
+/// Note that the connection to the DUT can be lost if the address changes.
+pub fn set_static_ip(ssh: &SshInfo, service: &str, props: Option<&str>) -> Result<()> {
+    if let Some(props) = props {
+        shill_call(
+            ssh,
+            service,
+            "Service.SetProperty",
+            &["StaticIPConfig", &format!("<{props}>")],
+        )?;
+    } else {
+        shill_call(ssh, service, "Service.ClearProperty", &["StaticIPConfig"])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn shill_properties() {
+        assert_eq!(gvariant_str(r"it's a\b"), r"'it\'s a\\b'");
+        assert_eq!(
+            wifi_properties("lab", Some("pass"), false),
+            "{'Type': <'wifi'>, 'SSID': <'lab'>, 'WiFi.HiddenSSID': <false>, 'AutoConnect': \
+             <true>, 'SaveCredentials': <true>, 'SecurityClass': <'psk'>, 'Passphrase': <'pass'>}"
+        );
+        assert_eq!(
+            static_ip_properties("192.168.0.10/24", "192.168.0.1", &["8.8.8.8".to_string()])
+                .unwrap(),
+            "{'Address': <'192.168.0.10'>, 'Prefixlen': <int32 24>, 'Gateway': <'192.168.0.1'>, \
+             'NameServers': <['8.8.8.8']>}"
+        );
+        assert!(static_ip_properties("192.168.0.10", "192.168.0.1", &[]).is_err());
+        assert!(static_ip_properties("192.168.0.10/33", "192.168.0.1", &[]).is_err());
+        assert!(static_ip_properties("192.168.0.10/24", "gw", &[]).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Battery, thermal and fan readings of DUTs for power / thermal debugging,
+//! sampled by `cro3 dut sensors`.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use chrono::Local;
+
+use crate::dut::SshInfo;
+
+/// A shell command to collect the readings in one ssh round trip
+const SENSORS_CMD: &str = r"
+for b in /sys/class/power_supply/BAT*; do
+  [ -d $b ] || continue
+  echo battery_percent=$(cat $b/capacity)
+  echo battery_current_ua=$(cat $b/current_now)
+  echo battery_voltage_uv=$(cat $b/voltage_now)
+  echo battery_status=$(cat $b/status)
+  break
+done
+for z in /sys/class/thermal/thermal_zone*; do
+  echo thermal.$(basename $z).$(cat $z/type)=$(cat $z/temp)
+done 2>/dev/null
+ectool pwmgetfanrpm all 2>/dev/null | sed 's/^/ec: /'
+";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SensorSample {
+    /// Unix time when the sample is taken
+    pub time: i64,
+    pub battery_percent: Option<f64>,
+    /// Positive while charging
+    pub battery_current_ma: Option<f64>,
+    pub battery_voltage_v: Option<f64>,
+    pub battery_status: Option<String>,
+    /// Temperature in Celsius of each thermal zone (key: type of the zone)
+    pub thermal: BTreeMap<String, f64>,
+    /// RPM of each fan (key: fan0, fan1, ...)
+    pub fans: BTreeMap<String, u32>,
+}
+impl SensorSample {
+    /// Parses the output of SENSORS_CMD
+    pub fn parse(output: &str) -> Self {
+        let mut sample = SensorSample {
+            time: Local::now().timestamp(),
+            battery_percent: None,
+            battery_current_ma: None,
+            battery_voltage_v: None,
+            battery_status: None,
+            thermal: BTreeMap::new(),
+            fans: BTreeMap::new(),
+        };
+        for line in output.lines().map(|l| l.trim()) {
+            if let Some(fan) = line.strip_prefix("ec: Fan ") {
+                // "Fan 0 RPM: 2345", "Fan 1 stalled!" or "Fan 2 not present"
+                let (index, state) = fan.split_once(' ').unwrap_or((fan, ""));
+                let rpm = if let Some(rpm) = state.strip_prefix("RPM: ") {
+                    rpm.trim().parse().ok()
+                } else if state.starts_with("stalled") {
+                    Some(0)
+                } else {
+                    None
+                };
+                if let Some(rpm) = rpm {
+                    sample.fans.insert(format!("fan{index}"), rpm);
+                }
+                continue;
+            }
+            let (k, v) = if let Some(kv) = line.split_once('=') {
+                kv
+            } else {
+                continue;
+            };
+            if let Some(zone) = k.strip_prefix("thermal.") {
+                let (zone, zone_type) = zone.split_once('.').unwrap_or((zone, zone));
+                if let Ok(temp) = v.parse::<f64>() {
+                    // Some boards have multiple zones of the same type
+                    let name = if sample.thermal.contains_key(zone_type) {
+                        format!("{zone_type}({zone})")
+                    } else {
+                        zone_type.to_string()
+                    };
+                    sample.thermal.insert(name, temp / 1000.0);
+                }
+                continue;
+            }
+            match k {
+                "battery_percent" => sample.battery_percent = v.parse().ok(),
+                "battery_current_ua" => {
+                    sample.battery_current_ma = v.parse::<f64>().ok().map(|c| c / 1000.0)
+                }
+                "battery_voltage_uv" => {
+                    sample.battery_voltage_v = v.parse::<f64>().ok().map(|v| v / 1000000.0)
+                }
+                "battery_status" => sample.battery_status = (!v.is_empty()).then(|| v.to_string()),
+                _ => {}
+            }
+        }
+        sample
+    }
+    pub fn fetch(ssh: &SshInfo) -> Result<Self> {
+        Ok(Self::parse(&ssh.run_cmd_stdio(SENSORS_CMD)?))
+    }
+    /// Returns (name, value) of each column to show, in a stable order
+    pub fn columns(&self) -> Vec<(String, String)> {
+        let value = |v: Option<String>| v.unwrap_or("-".to_string());
+        let mut columns = vec![
+            (
+                "time".to_string(),
+                chrono::DateTime::from_timestamp(self.time, 0)
+                    .map(|t| t.with_timezone(&Local).format("%H:%M:%S").to_string())
+                    .unwrap_or_default(),
+            ),
+            (
+                "battery_%".to_string(),
+                value(self.battery_percent.map(|v| format!("{v:.0}"))),
+            ),
+            (
+                "current_mA".to_string(),
+                value(self.battery_current_ma.map(|v| format!("{v:.0}"))),
+            ),
+            (
+                "voltage_V".to_string(),
+                value(self.battery_voltage_v.map(|v| format!("{v:.3}"))),
+            ),
+            ("status".to_string(), value(self.battery_status.clone())),
+        ];
+        columns.extend(
+            self.thermal
+                .iter()
+                .map(|(k, v)| (format!("{k}_C"), format!("{v:.1}"))),
+        );
+        columns.extend(
+            self.fans
+                .iter()
+                .map(|(k, v)| (format!("{k}_rpm"), v.to_string())),
+        );
+        columns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn parse_sensors() {
+        let output = "\
+battery_percent=87
+battery_current_ua=-1234000
+battery_voltage_uv=12345000
+battery_status=Discharging
+thermal.thermal_zone0.x86_pkg_temp=45500
+thermal.thermal_zone1.TSR0=40000
+thermal.thermal_zone2.TSR0=41000
+ec: Fan 0 RPM: 2345
+ec: Fan 1 stalled!
+ec: Fan 2 not present
+";
+        let s = SensorSample::parse(output);
+        assert_eq!(s.battery_percent, Some(87.0));
+        assert_eq!(s.battery_current_ma, Some(-1234.0));
+        assert_eq!(s.battery_voltage_v, Some(12.345));
+        assert_eq!(s.battery_status.as_deref(), Some("Discharging"));
+        assert_eq!(s.thermal.get("x86_pkg_temp"), Some(&45.5));
+        assert_eq!(s.thermal.get("TSR0"), Some(&40.0));
+        assert_eq!(s.thermal.get("TSR0(thermal_zone2)"), Some(&41.0));
+        assert_eq!(s.fans.get("fan0"), Some(&2345));
+        assert_eq!(s.fans.get("fan1"), Some(&0));
+        assert_eq!(s.fans.get("fan2"), None);
+        let names: Vec<String> = s.columns().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(
+            names[1..],
+            [
+                "battery_%",
+                "current_mA",
+                "voltage_V",
+                "status",
+                "TSR0_C",
+                "TSR0(thermal_zone2)_C",
+                "x86_pkg_temp_C",
+                "fan0_rpm",
+                "fan1_rpm"
+            ]
+        );
+        let empty = SensorSample::parse("");
+        assert_eq!(empty.columns()[1].1, "-");
+    }
+}
@@ -0,0 +1,118 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Interactive shell on a DUT with local line editing, per-DUT history and
+//! completion of remote paths. See `cro3 dut shell`.
+//!
+//! Each line runs in a new remote shell over the shared SSH connection, so
+//! editing stays responsive on slow links. Only the working directory is
+//! carried over between lines (not shell variables or functions).
+
+use std::path::PathBuf;
+use std::process;
+
+use anyhow::Result;
+
+use crate::dut::SshInfo;
+use crate::util::cro3_paths::gen_path_in_cro3_dir;
+use crate::util::shell_helpers::shell_quote;
+
+/// Maximum number of completion candidates to fetch
+const MAX_CANDIDATES: usize = 200;
+
+pub fn history_path(dut_id: &str) -> Result<PathBuf> {
+    gen_path_in_cro3_dir(&format!("shell_history/{dut_id}"))
+}
+
+/// Returns true if `line` may change the working directory
+pub fn changes_dir(line: &str) -> bool {
+    line.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .any(|w| matches!(w, "cd" | "pushd" | "popd"))
+}
+
+/// Returns a script that prints the completion candidates for the last word
+/// of `line` on the DUT. The first word is completed as a command, and the
+/// others as paths (with a trailing '/' for directories).
+pub fn completion_script(cwd: &str, line: &str) -> String {
+    let (head, word) = line.rsplit_once(char::is_whitespace).unwrap_or(("", line));
+    let cd = format!("cd {} 2>/dev/null", shell_quote(cwd));
+    let word = shell_quote(word);
+    if head.trim().is_empty() && !word.contains('/') {
+        format!("{cd}; compgen -c -- {word} | sort -u | head -n {MAX_CANDIDATES}")
+    } else {
+        format!(
+            "{cd}; compgen -f -- {word} | sort | head -n {MAX_CANDIDATES} | while read -r f; do \
+             if [ -d \"$f\" ]; then echo \"$f/\"; else echo \"$f\"; fi; done"
+        )
+    }
+}
+
+pub struct RemoteShell<'a> {
+    ssh: &'a SshInfo,
+    cwd: String,
+    /// File on the DUT to pass the working directory back after each line
+    cwd_file: String,
+}
+impl<'a> RemoteShell<'a> {
+    pub fn new(ssh: &'a SshInfo) -> Result<Self> {
+        let cwd = ssh.run_cmd_stdio("pwd")?.trim().to_string();
+        Ok(Self {
+            ssh,
+            cwd,
+            cwd_file: format!("/tmp/.cro3_shell_cwd_{}", process::id()),
+        })
+    }
+    pub fn cwd(&self) -> &str {
+        &self.cwd
+    }
+    /// Runs a line on the DUT with a terminal, and returns its exit code
+    pub fn run(&mut self, line: &str) -> Result<Option<i32>> {
+        let script = format!(
+            "cd {} 2>/dev/null; {line}\n__rc=$?; pwd > {}; exit $__rc",
+            shell_quote(&self.cwd),
+            self.cwd_file
+        );
+        let status = self
+            .ssh
+            .ssh_cmd(Some(&["-t", "-q"]))?
+            .arg(script)
+            .status()?;
+        if changes_dir(line) {
+            if let Ok(cwd) = self.ssh.run_cmd_stdio(&format!("cat {}", self.cwd_file)) {
+                self.cwd = cwd.trim().to_string();
+            }
+        }
+        Ok(status.code())
+    }
+    /// Returns the completion candidates for the last word of `line`
+    pub fn complete(&self, line: &str) -> Vec<String> {
+        self.ssh
+            .run_cmd_stdio(&completion_script(&self.cwd, line))
+            .map(|output| output.lines().map(|l| l.to_string()).collect())
+            .unwrap_or_default()
+    }
+    /// Removes the temporary file on the DUT
+    pub fn close(&self) -> Result<()> {
+        self.ssh
+            .run_cmd_stdio(&format!("rm -f {}", self.cwd_file))
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn shell_helpers() {
+        assert!(changes_dir("cd /tmp"));
+        assert!(changes_dir("make && pushd out"));
+        assert!(!changes_dir("ls /cdrom"));
+        assert!(completion_script("/root", "tast").contains("compgen -c -- tast"));
+        assert!(completion_script("/root", "ls /usr/lo").contains("compgen -f -- /usr/lo"));
+        assert!(completion_script("/", "./scr").contains("compgen -f -- ./scr"));
+        assert!(completion_script("/", "cat ").contains("compgen -f -- ''"));
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! CPU / memory usage of DUTs and their processes, calculated from /proc so
+//! that nothing needs to be installed on the DUTs. See `cro3 dut top`.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::dut::SshInfo;
+
+/// A shell command to read /proc/stat, /proc/meminfo and the stat of all the
+/// processes in one ssh round trip
+const TOP_CMD: &str = "grep '^cpu' /proc/stat; grep -E '^(MemTotal|MemAvailable):' /proc/meminfo; \
+                       cat /proc/[0-9]*/stat 2>/dev/null";
+
+/// Page size of the DUTs, which is 4KiB on all ChromeOS devices
+const PAGE_SIZE_KB: u64 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+struct ProcStat {
+    command: String,
+    /// utime + stime
+    jiffies: u64,
+    rss_kb: u64,
+}
+
+/// Counters read at a point of time
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ProcSnapshot {
+    num_cpus: usize,
+    total_jiffies: u64,
+    idle_jiffies: u64,
+    mem_total_kb: u64,
+    mem_available_kb: u64,
+    procs: HashMap<u32, ProcStat>,
+}
+impl ProcSnapshot {
+    /// Parses the output of TOP_CMD
+    pub fn parse(output: &str) -> Self {
+        let mut snapshot = Self::default();
+        for line in output.lines() {
+            if let Some(cpu) = line.strip_prefix("cpu ") {
+                let values: Vec<u64> = cpu
+                    .split_whitespace()
+                    .filter_map(|v| v.parse().ok())
+                    .collect();
+                snapshot.total_jiffies = values.iter().sum();
+                // idle + iowait
+                snapshot.idle_jiffies = values.get(3).unwrap_or(&0) + values.get(4).unwrap_or(&0);
+            } else if line.starts_with("cpu") {
+                snapshot.num_cpus += 1;
+            } else if let Some((pid, stat)) = parse_proc_stat(line) {
+                snapshot.procs.insert(pid, stat);
+            } else if let Some((key, value)) = line.split_once(':') {
+                let kb = value
+                    .trim()
+                    .trim_end_matches(" kB")
+                    .parse()
+                    .unwrap_or_default();
+                match key {
+                    "MemTotal" => snapshot.mem_total_kb = kb,
+                    "MemAvailable" => snapshot.mem_available_kb = kb,
+                    _ => {}
+                }
+            }
+        }
+        snapshot
+    }
+    pub fn fetch(ssh: &SshInfo) -> Result<Self> {
+        Ok(Self::parse(&ssh.run_cmd_stdio(TOP_CMD)?))
+    }
+}
+
+/// Parses a line of /proc/<pid>/stat. The command is in parentheses and can
+/// contain spaces and parentheses, so the fields are split after the last ')'.
+fn parse_proc_stat(line: &str) -> Option<(u32, ProcStat)> {
+    let (pid, rest) = line.split_once(" (")?;
+    let (command, fields) = rest.rsplit_once(") ")?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    // fields[0] is the state (the 3rd field of stat)
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let rss_pages: u64 = fields.get(21)?.parse().ok()?;
+    Some((
+        pid.parse().ok()?,
+        ProcStat {
+            command: command.to_string(),
+            jiffies: utime + stime,
+            rss_kb: rss_pages * PAGE_SIZE_KB,
+        },
+    ))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcUsage {
+    pub pid: u32,
+    pub command: String,
+    /// 100% = one CPU core, as in top(1)
+    pub cpu_percent: f64,
+    pub rss_mb: f64,
+}
+
+/// Usage between two snapshots
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopSample {
+    /// 100% = all the CPU cores
+    pub cpu_percent: f64,
+    pub mem_used_mb: f64,
+    pub mem_total_mb: f64,
+    /// Sorted by CPU usage, then RSS
+    pub procs: Vec<ProcUsage>,
+}
+impl TopSample {
+    pub fn between(prev: &ProcSnapshot, cur: &ProcSnapshot) -> Self {
+        let total = cur.total_jiffies.saturating_sub(prev.total_jiffies).max(1) as f64;
+        let idle = cur.idle_jiffies.saturating_sub(prev.idle_jiffies) as f64;
+        let per_core = total / cur.num_cpus.max(1) as f64;
+        let mut procs: Vec<ProcUsage> = cur
+            .procs
+            .iter()
+            .map(|(pid, stat)| {
+                // Processes started after the previous snapshot count from 0
+                let prev_jiffies = prev.procs.get(pid).map(|p| p.jiffies).unwrap_or(0);
+                ProcUsage {
+                    pid: *pid,
+                    command: stat.command.clone(),
+                    cpu_percent: stat.jiffies.saturating_sub(prev_jiffies) as f64 / per_core
+                        * 100.0,
+                    rss_mb: stat.rss_kb as f64 / 1024.0,
+                }
+            })
+            .collect();
+        procs.sort_by(|a, b| {
+            b.cpu_percent
+                .total_cmp(&a.cpu_percent)
+                .then(b.rss_mb.total_cmp(&a.rss_mb))
+        });
+        Self {
+            cpu_percent: (total - idle) / total * 100.0,
+            mem_used_mb: cur.mem_total_kb.saturating_sub(cur.mem_available_kb) as f64 / 1024.0,
+            mem_total_mb: cur.mem_total_kb as f64 / 1024.0,
+            procs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn top_sample() {
+        let stat = |pid: u32, command: &str, utime: u64, rss: u64| {
+            format!(
+                "{pid} ({command}) S 1 1 1 0 -1 4194560 100 0 0 0 {utime} 0 0 0 20 0 1 0 10 \
+                 1000000 {rss} 18446744073709551615"
+            )
+        };
+        let prev = ProcSnapshot::parse(&format!(
+            "cpu  100 0 100 700 100 0 0 0 0 0\ncpu0 50 0 50 350 50 0 0 0 0 0\ncpu1 50 0 50 350 50 \
+             0 0 0 0 0\nMemTotal:        8000000 kB\nMemAvailable:    6000000 kB\n{}\n{}\n",
+            stat(1, "init", 10, 100),
+            stat(100, "chrome", 50, 25600)
+        ));
+        assert_eq!(prev.num_cpus, 2);
+        assert_eq!(prev.procs[&100].rss_kb, 102400);
+        let cur = ProcSnapshot::parse(&format!(
+            "cpu  200 0 200 750 150 0 0 0 0 0\ncpu0 0\ncpu1 0\nMemTotal:        8000000 \
+             kB\nMemAvailable:    5000000 kB\n{}\n{}\n{}\n",
+            stat(1, "init", 10, 100),
+            stat(100, "chrome", 100, 25600),
+            stat(200, "kworker/0:1 (b)", 25, 256)
+        ));
+        let sample = TopSample::between(&prev, &cur);
+        // 300 jiffies in total, 100 of them are idle
+        assert_eq!(format!("{:.1}", sample.cpu_percent), "66.7");
+        assert_eq!(format!("{:.0}", sample.mem_used_mb), "2930");
+        assert_eq!(sample.procs[0].pid, 100);
+        assert_eq!(format!("{:.1}", sample.procs[0].cpu_percent), "33.3");
+        // A new process: 25 jiffies out of 150 per core
+        assert_eq!(sample.procs[1].command, "kworker/0:1 (b)");
+        assert_eq!(format!("{:.1}", sample.procs[1].cpu_percent), "16.7");
+        assert_eq!(sample.procs[2].cpu_percent, 0.0);
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Updating the AP and EC firmware of DUTs over SSH, and verifying what was
+//! written by comparing the firmware IDs in the image and in the flash.
+//! See `cro3 flash firmware`.
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::dut::DutInfo;
+use crate::dut::SshInfo;
+use crate::google_storage::download_gs_file;
+use crate::image_cache::cached_image_dir;
+use crate::image_cache::ImageKind;
+use crate::util::cro3_paths::gen_path_in_cro3_dir;
+
+/// DutInfo keys of the running firmware versions
+pub const FIRMWARE_VERSION_KEYS: [&str; 3] = ["ro_fwid", "fwid", "ec_version"];
+
+const FIRMWARE_ARCHIVE: &str = "firmware_from_source.tar.bz2";
+
+/// Working directory on the DUT
+const DUT_FIRMWARE_DIR: &str = "/usr/local/tmp/cro3_firmware";
+
+/// FMAP sections that hold the firmware IDs of an AP image
+const FWID_SECTIONS: [&str; 3] = ["RO_FRID", "RW_FWID_A", "RW_FWID_B"];
+
+pub fn firmware_versions(ssh: &SshInfo) -> Result<BTreeMap<String, Option<String>>> {
+    DutInfo::fetch_keys_optional(ssh, &FIRMWARE_VERSION_KEYS)
+}
+
+/// Downloads the firmware built for a release (e.g. R120-15662.0.0), or
+/// reuses the one downloaded before. Returns the extracted directory.
+pub fn download_firmware(board: &str, full_version: &str) -> Result<PathBuf> {
+    let dir = cached_image_dir(board, full_version, ImageKind::Firmware)?;
+    let archive = dir.join(FIRMWARE_ARCHIVE);
+    if !archive.exists() {
+        let url = format!(
+            "gs://chromeos-image-archive/{board}-release/{full_version}/{FIRMWARE_ARCHIVE}"
+        );
+        info!("Downloading {url}...");
+        download_gs_file(&url, &archive)?;
+        Command::new("tar")
+            .arg("-xjf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&dir)
+            .status()?
+            .exit_ok()
+            .context("Failed to extract the firmware")?;
+    }
+    Ok(dir)
+}
+
+/// Returns the AP and EC images for `model` in an extracted firmware archive.
+/// Unified builds have an image per model, and older ones have only one.
+pub fn find_firmware_images(dir: &Path, model: &str) -> (Option<PathBuf>, Option<PathBuf>) {
+    let first = |candidates: &[String]| {
+        candidates
+            .iter()
+            .map(|c| dir.join(c))
+            .find(|path| path.exists())
+    };
+    (
+        first(&[format!("image-{model}.bin"), "image.bin".to_string()]),
+        first(&[format!("{model}/ec.bin"), "ec.bin".to_string()]),
+    )
+}
+
+/// A shell script that prints the firmware IDs in an AP image (e.g.
+/// "RW_FWID_A=Google_Brya.14505.0.0")
+fn fwids_script(image: &str) -> String {
+    format!(
+        "d=$(mktemp -d) && (cd $d && futility dump_fmap -x {image} {sections} >/dev/null && for s \
+         in {sections}; do [ -f $s ] && echo \"$s=$(tr -d '\\000' < $s)\"; done); rm -rf $d",
+        sections = FWID_SECTIONS.join(" ")
+    )
+}
+
+/// Parses the output of fwids_script()
+pub fn parse_fwids(output: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(|l| l.trim().split_once('='))
+        .map(|(k, v)| (k.to_string(), v.trim().to_string()))
+        .collect()
+}
+
+/// Returns an error if the firmware IDs in the flash do not match the ones in
+/// the image written. RO_FRID is not checked if the RO firmware is write
+/// protected.
+pub fn check_fwids(
+    expected: &BTreeMap<String, String>,
+    actual: &BTreeMap<String, String>,
+    ro_writable: bool,
+) -> Result<()> {
+    if expected.is_empty() {
+        bail!("No firmware IDs are found in the image. Is it an AP firmware image?");
+    }
+    for (section, id) in expected {
+        if section == "RO_FRID" && !ro_writable {
+            continue;
+        }
+        match actual.get(section) {
+            Some(actual_id) if actual_id == id => {}
+            actual_id => bail!("{section} in the flash is {actual_id:?} but {id} is expected"),
+        }
+    }
+    Ok(())
+}
+
+fn send_image(ssh: &SshInfo, image: &Path, name: &str) -> Result<String> {
+    ssh.run_cmd_stdio(&format!("mkdir -p {DUT_FIRMWARE_DIR}"))?;
+    let status = ssh
+        .scp_send_cmd(
+            &[image.to_string_lossy().to_string()],
+            Some(&format!("{DUT_FIRMWARE_DIR}/{name}")),
+        )?
+        .status()?;
+    if !status.success() {
+        bail!("Failed to copy {image:?} to the DUT");
+    }
+    Ok(format!("{DUT_FIRMWARE_DIR}/{name}"))
+}
+
+/// Writes an AP firmware image with futility over SSH, then reads the flash
+/// back to verify the firmware IDs. Returns the firmware IDs in the flash.
+pub fn flash_ap_over_ssh(ssh: &SshInfo, image: &Path) -> Result<BTreeMap<String, String>> {
+    let remote = send_image(ssh, image, "ap.bin")?;
+    let ro_writable = ssh.run_cmd_stdio("crossystem wpsw_cur")?.trim() == "0";
+    if !ro_writable {
+        warn!("Write protection is enabled. Only the RW firmware will be updated.");
+    }
+    let expected = parse_fwids(&ssh.run_cmd_stdio(&fwids_script(&remote))?);
+    info!("Writing {image:?} to the AP firmware...");
+    ssh.run_cmd_piped(&[format!("futility update -i {remote} --force")])
+        .context("futility update failed")?;
+    info!("Reading back the AP firmware to verify it...");
+    let readback = format!("{DUT_FIRMWARE_DIR}/readback.bin");
+    ssh.run_cmd_stdio(&format!("flashrom -p host -r {readback} >/dev/null"))?;
+    let actual = parse_fwids(&ssh.run_cmd_stdio(&fwids_script(&readback))?);
+    check_fwids(&expected, &actual, ro_writable)?;
+    info!("Verified: {actual:?}");
+    Ok(actual)
+}
+
+/// Writes an EC firmware image with flashrom over SSH. flashrom verifies the
+/// written data by default.
+pub fn flash_ec_over_ssh(ssh: &SshInfo, image: &Path) -> Result<()> {
+    let remote = send_image(ssh, image, "ec.bin")?;
+    info!("Writing {image:?} to the EC...");
+    ssh.run_cmd_piped(&[format!("flashrom -p ec -w {remote}")])
+        .context("flashrom failed")?;
+    Ok(())
+}
+
+pub fn cleanup_on_dut(ssh: &SshInfo) -> Result<()> {
+    ssh.run_cmd_stdio(&format!("rm -rf {DUT_FIRMWARE_DIR}"))
+        .map(|_| ())
+}
+
+#[derive(Serialize)]
+struct FirmwareUpdateRecord<'a> {
+    timestamp: String,
+    dut: &'a str,
+    ap: Option<&'a Path>,
+    ec: Option<&'a Path>,
+    /// Running versions before the update
+    before: &'a Option<BTreeMap<String, Option<String>>>,
+    /// Firmware IDs in the AP flash after the update
+    written: &'a Option<BTreeMap<String, String>>,
+    /// Running versions after the update (only if the DUT was rebooted)
+    after: &'a Option<BTreeMap<String, Option<String>>>,
+}
+
+/// Appends the versions before and after an update to
+/// ~/.cro3/firmware_history.jsonl
+pub fn record_firmware_update(
+    dut: &str,
+    ap: Option<&Path>,
+    ec: Option<&Path>,
+    before: &Option<BTreeMap<String, Option<String>>>,
+    written: &Option<BTreeMap<String, String>>,
+    after: &Option<BTreeMap<String, Option<String>>>,
+) -> Result<()> {
+    let record = FirmwareUpdateRecord {
+        timestamp: chrono::Local::now().to_rfc3339(),
+        dut,
+        ap,
+        ec,
+        before,
+        written,
+        after,
+    };
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(gen_path_in_cro3_dir("firmware_history.jsonl")?)?;
+    writeln!(f, "{}", serde_json::to_string(&record)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    #[test]
+    fn firmware_ids() {
+        let image = parse_fwids(
+            "RO_FRID=Google_Brya.14505.0.0
+RW_FWID_A=Google_Brya.14505.10.0
+RW_FWID_B=Google_Brya.14505.10.0
+",
+        );
+        assert_eq!(image["RW_FWID_A"], "Google_Brya.14505.10.0");
+        let mut flash = image.clone();
+        assert!(check_fwids(&image, &flash, true).is_ok());
+        flash.insert("RO_FRID".to_string(), "Google_Brya.14000.0.0".to_string());
+        assert!(check_fwids(&image, &flash, true).is_err());
+        assert!(check_fwids(&image, &flash, false).is_ok());
+        flash.remove("RW_FWID_B");
+        assert!(check_fwids(&image, &flash, false).is_err());
+        assert!(check_fwids(&BTreeMap::new(), &flash, false).is_err());
+
+        let dir = TempDir::new("firmware").unwrap();
+        assert_eq!(find_firmware_images(dir.path(), "brya"), (None, None));
+        std::fs::write(dir.path().join("image-brya.bin"), "").unwrap();
+        std::fs::write(dir.path().join("ec.bin"), "").unwrap();
+        assert_eq!(
+            find_firmware_images(dir.path(), "brya"),
+            (
+                Some(dir.path().join("image-brya.bin")),
+                Some(dir.path().join("ec.bin"))
+            )
+        );
+    }
+}
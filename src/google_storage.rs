@@ -1,7 +1,33 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
 use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
 
+use anyhow::anyhow;
+use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::progress::add_progress_bar;
+
+/// Size of the ranges fetched by a gsutil invocation while downloading
+const DOWNLOAD_CHUNK_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Number of attempts to fetch a chunk before giving up
+const DOWNLOAD_ATTEMPTS: u32 = 5;
 
 pub fn list_gs_files(pattern: &str) -> Result<String> {
     let cmd = format!("gsutil.py ls {}", pattern.trim());
@@ -15,3 +41,247 @@ pub fn list_gs_files(pattern: &str) -> Result<String> {
         .trim()
         .to_string())
 }
+
+/// Metadata of an object on Google Storage, which is also kept next to a
+/// partial download to check that it can be resumed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GsObject {
+    pub url: String,
+    pub size: u64,
+    /// Changes when the object is overwritten
+    pub generation: String,
+    /// Hex string. Composite objects do not have one.
+    pub md5: Option<String>,
+}
+
+/// Parses the output of `gsutil stat`
+pub fn parse_gs_stat(url: &str, output: &str) -> Result<GsObject> {
+    let mut size = None;
+    let mut generation = String::new();
+    let mut md5 = None;
+    for line in output.lines() {
+        if let Some((key, value)) = line.trim().split_once(':') {
+            let value = value.trim();
+            match key {
+                "Content-Length" => size = value.parse().ok(),
+                "Generation" => generation = value.to_string(),
+                "Hash (md5)" => {
+                    md5 = Some(
+                        STANDARD
+                            .decode(value)
+                            .context(anyhow!("Invalid md5 of {url}: {value}"))?
+                            .iter()
+                            .map(|b| format!("{b:02x}"))
+                            .collect::<Vec<String>>()
+                            .concat(),
+                    )
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(GsObject {
+        url: url.to_string(),
+        size: size.context(anyhow!("No Content-Length in the stat of {url}"))?,
+        generation,
+        md5,
+    })
+}
+
+pub fn stat_gs_file(url: &str) -> Result<GsObject> {
+    let output = Command::new("gsutil.py")
+        .args(["stat", url])
+        .stderr(Stdio::inherit())
+        .output()
+        .context("Failed to execute gsutil stat (maybe you need depot_tools)")?;
+    if !output.status.success() {
+        bail!("Failed to get the metadata of {url}");
+    }
+    parse_gs_stat(url, &String::from_utf8_lossy(&output.stdout))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+/// Appends bytes [offset, end) of `url` to `file`, returning the number of
+/// bytes appended (which can be short if gsutil fails in the middle)
+fn append_range(url: &str, offset: u64, end: u64, file: &Path, bar: &ProgressBar) -> Result<u64> {
+    let mut child = Command::new("gsutil.py")
+        .args(["cat", "-r", &format!("{offset}-{}", end - 1), url])
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to execute gsutil cat (maybe you need depot_tools)")?;
+    let mut stdout = child.stdout.take().context("No stdout of gsutil")?;
+    let mut out = OpenOptions::new().append(true).open(file)?;
+    let mut buf = vec![0u8; 1024 * 1024];
+    let mut written = 0;
+    // Everything received is kept, even if gsutil fails later
+    let result = loop {
+        match stdout.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => {
+                out.write_all(&buf[..n])?;
+                written += n as u64;
+                bar.inc(n as u64);
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    out.sync_data()?;
+    let status = child.wait()?;
+    result?;
+    if !status.success() {
+        bail!("gsutil cat failed with {status}");
+    }
+    if written == 0 {
+        bail!("gsutil cat returned no data");
+    }
+    Ok(written)
+}
+
+fn md5_of_file(path: &Path) -> Result<String> {
+    let output = Command::new("md5sum")
+        .arg(path)
+        .output()
+        .context("Failed to run md5sum")?;
+    output.status.exit_ok().context("md5sum failed")?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Uploads `src` to `url` on Google Storage
+pub fn upload_gs_file(src: &Path, url: &str) -> Result<()> {
+    let status = Command::new("gsutil.py")
+        .args(["cp", &src.to_string_lossy(), url])
+        .status()
+        .context("Failed to execute gsutil cp (maybe you need depot_tools)")?;
+    if !status.success() {
+        bail!("Failed to upload {src:?} to {url}");
+    }
+    Ok(())
+}
+
+/// Downloads a file on Google Storage to `dest`. The file is fetched in
+/// chunks into `<dest>.part`, so that an interrupted download continues from
+/// where it left off on the next call (as long as the object is not updated
+/// in between), and is moved to `dest` after its md5 is verified.
+pub fn download_gs_file(url: &str, dest: &Path) -> Result<()> {
+    let object = stat_gs_file(url)?;
+    let part = with_suffix(dest, ".part");
+    let bookkeeping = with_suffix(dest, ".part.json");
+    let resumable = fs::read_to_string(&bookkeeping)
+        .ok()
+        .and_then(|s| serde_json::from_str::<GsObject>(&s).ok())
+        .map(|o| o == object)
+        .unwrap_or(false);
+    let mut offset = if resumable {
+        fs::metadata(&part).map(|m| m.len()).unwrap_or(0)
+    } else {
+        0
+    };
+    if offset > object.size {
+        offset = 0;
+    }
+    if offset == 0 {
+        fs::write(&part, [])?;
+        fs::write(&bookkeeping, serde_json::to_string(&object)?)?;
+    } else {
+        info!(
+            "Resuming the download of {url} from {:.1} MB",
+            offset as f64 / 1e6
+        );
+    }
+
+    let bar = add_progress_bar(ProgressBar::new(object.size));
+    bar.set_style(ProgressStyle::with_template(
+        "{wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+    )?);
+    bar.set_position(offset);
+    let mut failures = 0;
+    while offset < object.size {
+        let end = (offset + DOWNLOAD_CHUNK_SIZE).min(object.size);
+        match append_range(url, offset, end, &part, &bar) {
+            Ok(n) => {
+                offset += n;
+                failures = 0;
+            }
+            Err(e) => {
+                // Resume from what has been written so far
+                offset = fs::metadata(&part)?.len();
+                failures += 1;
+                if failures >= DOWNLOAD_ATTEMPTS {
+                    bar.abandon();
+                    return Err(e.context(anyhow!(
+                        "Failed to download {url}. Run the command again to resume."
+                    )));
+                }
+                warn!("{e:#}. Retrying...");
+                std::thread::sleep(Duration::from_secs(1 << failures));
+            }
+        }
+    }
+    bar.finish();
+
+    let size = fs::metadata(&part)?.len();
+    let corrupted = if size != object.size {
+        Some(format!(
+            "{size} bytes downloaded but {} expected",
+            object.size
+        ))
+    } else if let Some(expected) = &object.md5 {
+        let actual = md5_of_file(&part)?;
+        (&actual != expected).then(|| format!("md5 is {actual} but {expected} expected"))
+    } else {
+        warn!("{url} has no md5 to verify the download with");
+        None
+    };
+    if let Some(reason) = corrupted {
+        // Start over on the next call
+        fs::remove_file(&part)?;
+        fs::remove_file(&bookkeeping)?;
+        bail!("The download of {url} is corrupted: {reason}");
+    }
+    fs::rename(&part, dest)?;
+    fs::remove_file(&bookkeeping)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn gs_stat() {
+        let url = "gs://chromeos-image-archive/brya-release/R120-15662.0.0/stateful.tgz";
+        let output = "gs://chromeos-image-archive/brya-release/R120-15662.0.0/stateful.tgz:
+    Creation time:          Thu, 19 Oct 2023 10:00:00 GMT
+    Update time:            Thu, 19 Oct 2023 10:00:00 GMT
+    Storage class:          STANDARD
+    Content-Length:         1073741824
+    Content-Type:           application/octet-stream
+    Hash (crc32c):          AAAAAA==
+    Hash (md5):             1B2M2Y8AsgTpgAmY7PhCfg==
+    ETag:                   CJqD7Ojj7oEDEAE=
+    Generation:             1697709600000000
+    Metageneration:         1
+";
+        assert_eq!(
+            parse_gs_stat(url, output).unwrap(),
+            GsObject {
+                url: url.to_string(),
+                size: 1073741824,
+                generation: "1697709600000000".to_string(),
+                md5: Some("d41d8cd98f00b204e9800998ecf8427e".to_string()),
+            }
+        );
+        let composite =
+            output.replace("    Hash (md5):             1B2M2Y8AsgTpgAmY7PhCfg==\n", "");
+        assert_eq!(parse_gs_stat(url, &composite).unwrap().md5, None);
+        assert!(parse_gs_stat(url, "").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Results of gtest binaries run on a DUT, read from the report of
+//! --gtest_output=json, or from the output if the binary did not write it
+//! (e.g. because it crashed).
+
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+use regex_macro::regex;
+
+use crate::test_results::TestResult;
+use crate::test_results::TestStatus;
+
+/// Keeps the first lines of a failure message, to show it in a table
+fn first_lines(message: &str) -> String {
+    message
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .take(3)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parses the report written with --gtest_output=json, which has the tests
+/// of each suite in testsuites[].testsuite[]. `log` is set as the log of the
+/// tests.
+pub fn parse_gtest_json(json: &str, log: &Path) -> Result<Vec<TestResult>> {
+    let report: serde_json::Value =
+        serde_json::from_str(json).context("Failed to parse the gtest report")?;
+    let mut results = Vec::new();
+    for suite in report["testsuites"].as_array().into_iter().flatten() {
+        for test in suite["testsuite"].as_array().into_iter().flatten() {
+            let failures: Vec<&str> = test["failures"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|f| f["failure"].as_str())
+                .collect();
+            let status = if !failures.is_empty() {
+                TestStatus::Failed
+            } else if test["status"] == "NOTRUN"
+                || matches!(test["result"].as_str(), Some("SKIPPED" | "SUPPRESSED"))
+            {
+                TestStatus::Skipped
+            } else {
+                TestStatus::Passed
+            };
+            results.push(TestResult {
+                name: format!(
+                    "{}.{}",
+                    test["classname"].as_str().unwrap_or_default(),
+                    test["name"].as_str().unwrap_or_default()
+                ),
+                status,
+                duration: test["time"]
+                    .as_str()
+                    .and_then(|t| t.trim_end_matches('s').parse().ok())
+                    .unwrap_or_default(),
+                error: failures.first().map(|f| first_lines(f)),
+                log: Some(log.to_path_buf()),
+                attempts: 1,
+            });
+        }
+    }
+    Ok(results)
+}
+
+/// Parses the output of a gtest binary, which has "[ RUN      ] Suite.Test"
+/// and "[       OK ] Suite.Test (12 ms)" (or FAILED / SKIPPED) lines for each
+/// test. A test which has not finished is reported as a failure.
+pub fn parse_gtest_output(output: &str, log: &Path) -> Vec<TestResult> {
+    let re_end = regex!(r"^\[\s*(OK|FAILED|SKIPPED)\s*\] (\S+) \((\d+) ms\)");
+    let mut results = Vec::new();
+    let mut running: Option<(String, Vec<&str>)> = None;
+    for line in output.lines() {
+        if let Some(name) = line.strip_prefix("[ RUN      ] ") {
+            running = Some((name.trim().to_string(), Vec::new()));
+        } else if let Some(c) = re_end.captures(line) {
+            let messages = running
+                .take()
+                .filter(|(name, _)| *name == c[2])
+                .map(|(_, messages)| messages)
+                .unwrap_or_default();
+            let status = match &c[1] {
+                "OK" => TestStatus::Passed,
+                "SKIPPED" => TestStatus::Skipped,
+                _ => TestStatus::Failed,
+            };
+            results.push(TestResult {
+                name: c[2].to_string(),
+                status,
+                duration: c[3].parse::<f64>().unwrap_or_default() / 1000.0,
+                error: (status != TestStatus::Passed && !messages.is_empty())
+                    .then(|| first_lines(&messages.join("\n"))),
+                log: Some(log.to_path_buf()),
+                attempts: 1,
+            });
+        } else if let Some((_, messages)) = running.as_mut() {
+            messages.push(line);
+        }
+    }
+    if let Some((name, _)) = running {
+        results.push(TestResult {
+            name,
+            status: TestStatus::Failed,
+            duration: 0.0,
+            error: Some("The test did not finish (crashed?)".to_string()),
+            log: Some(log.to_path_buf()),
+            attempts: 1,
+        });
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn gtest_results() {
+        let log = Path::new("/r/output.log");
+        let json = r#"{"tests": 2, "testsuites": [{"name": "FooTest", "testsuite": [
+  {"name": "Works", "status": "RUN", "result": "COMPLETED", "time": "0.012s",
+   "classname": "FooTest"},
+  {"name": "Breaks", "status": "RUN", "result": "COMPLETED", "time": "0.003s",
+   "classname": "FooTest",
+   "failures": [{"failure": "foo_test.cc:10\nExpected equality\n  a\n  b", "type": ""}]}
+]}]}"#;
+        let results = parse_gtest_json(json, log).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].name, "FooTest.Works");
+        assert_eq!(results[0].duration, 0.012);
+        assert_eq!(results[1].status, TestStatus::Failed);
+        assert_eq!(
+            results[1].error.as_deref(),
+            Some("foo_test.cc:10 Expected equality a")
+        );
+
+        let output = "[==========] Running 3 tests from 1 test suite.
+[ RUN      ] FooTest.Works
+[       OK ] FooTest.Works (12 ms)
+[ RUN      ] FooTest.Breaks
+foo_test.cc:10: Failure
+Expected equality
+[  FAILED  ] FooTest.Breaks (3 ms)
+[ RUN      ] FooTest.Crashes
+";
+        let results = parse_gtest_output(output, log);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].status, TestStatus::Passed);
+        assert_eq!(
+            results[1].error.as_deref(),
+            Some("foo_test.cc:10: Failure Expected equality")
+        );
+        assert_eq!(results[2].name, "FooTest.Crashes");
+        assert_eq!(results[2].status, TestStatus::Failed);
+    }
+}
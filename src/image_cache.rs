@@ -0,0 +1,269 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Local cache of the images downloaded for flashing, under ~/.cro3/cache.
+//! Each entry is a directory keyed by board, version and kind
+//! (e.g. brya/R120-15662.0.0/test_disk), and the least recently used ones are
+//! evicted first by `cro3 cache gc`.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::cache::KvCache;
+use crate::util::cro3_paths::gen_path_in_cro3_dir;
+
+/// Unix time of the last use of each entry, keyed by board/version/kind
+static IMAGE_CACHE_LAST_USED: KvCache<i64> = KvCache::new("image_cache_last_used");
+
+/// What is stored in a cache entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageKind {
+    /// Kernel, rootfs and stateful artifacts of a test image
+    TestArtifacts,
+    /// A test disk image (chromiumos_test_image.bin)
+    TestDisk,
+    /// AP and EC firmware images built from source
+    Firmware,
+    /// A recovery image (signed with the release keys if available)
+    Recovery,
+    /// DLC images, in a directory per DLC ID
+    Dlc,
+    /// A release image signed with the release keys
+    Signed,
+    /// Dev and base disk images (chromiumos_image.bin and
+    /// chromiumos_base_image.bin)
+    OtherDisk,
+    /// Android images for fastboot, keyed by build target and build ID
+    Android,
+    /// Builds shared by others with `cro3 build upload`
+    Shared,
+}
+impl ImageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageKind::TestArtifacts => "test_artifacts",
+            ImageKind::TestDisk => "test_disk",
+            ImageKind::Firmware => "firmware",
+            ImageKind::Recovery => "recovery",
+            ImageKind::Dlc => "dlc",
+            ImageKind::Signed => "signed",
+            ImageKind::OtherDisk => "disk",
+            ImageKind::Android => "android",
+            ImageKind::Shared => "shared",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedImage {
+    pub board: String,
+    pub version: String,
+    pub kind: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub last_used: i64,
+}
+impl CachedImage {
+    pub fn key(&self) -> String {
+        format!("{}/{}/{}", self.board, self.version, self.kind)
+    }
+}
+
+pub fn image_cache_dir() -> Result<PathBuf> {
+    let mut dir = gen_path_in_cro3_dir("cache/.keep")?;
+    dir.pop();
+    Ok(dir)
+}
+
+/// Returns the directory of a cache entry, which may not exist
+pub fn cached_image_path(board: &str, version: &str, kind: ImageKind) -> Result<PathBuf> {
+    Ok(image_cache_dir()?.join(format!("{board}/{version}/{}", kind.as_str())))
+}
+
+/// Returns the directory of a cache entry, creating it if needed, and marks
+/// the entry as used now
+pub fn cached_image_dir(board: &str, version: &str, kind: ImageKind) -> Result<PathBuf> {
+    let key = format!("{board}/{version}/{}", kind.as_str());
+    let dir = cached_image_path(board, version, kind)?;
+    fs::create_dir_all(&dir)?;
+    IMAGE_CACHE_LAST_USED.set(&key, chrono::Local::now().timestamp())?;
+    Ok(dir)
+}
+
+fn dir_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        size += if meta.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            meta.len()
+        };
+    }
+    Ok(size)
+}
+
+fn subdirs(path: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push((
+                entry.file_name().to_string_lossy().to_string(),
+                entry.path(),
+            ));
+        }
+    }
+    Ok(dirs)
+}
+
+/// Returns the cache entries, the most recently used first
+pub fn list_cached_images() -> Result<Vec<CachedImage>> {
+    let last_used = IMAGE_CACHE_LAST_USED.entries()?;
+    let mut images = Vec::new();
+    for (board, board_dir) in subdirs(&image_cache_dir()?)? {
+        for (version, version_dir) in subdirs(&board_dir)? {
+            for (kind, path) in subdirs(&version_dir)? {
+                let mut image = CachedImage {
+                    board: board.clone(),
+                    version: version.clone(),
+                    kind,
+                    size: dir_size(&path)?,
+                    last_used: 0,
+                    path,
+                };
+                // Entries created by older versions of cro3 have no record
+                image.last_used = match last_used.get(&image.key()) {
+                    Some(t) => *t,
+                    None => chrono::DateTime::<chrono::Local>::from(
+                        fs::metadata(&image.path)?.modified()?,
+                    )
+                    .timestamp(),
+                };
+                images.push(image);
+            }
+        }
+    }
+    images.sort_by(|a, b| b.last_used.cmp(&a.last_used).then(a.key().cmp(&b.key())));
+    Ok(images)
+}
+
+pub fn remove_cached_image(image: &CachedImage) -> Result<()> {
+    fs::remove_dir_all(&image.path).context(format!("Failed to remove {:?}", image.path))?;
+    IMAGE_CACHE_LAST_USED.remove(&image.key())?;
+    // Remove the parent dirs if they are empty now
+    let version_dir = image.path.parent().context("No parent dir")?;
+    if fs::remove_dir(version_dir).is_ok() {
+        let _ = fs::remove_dir(version_dir.parent().context("No parent dir")?);
+    }
+    Ok(())
+}
+
+/// Returns the entries to evict to make the total size at most `max_size`,
+/// the least recently used first
+pub fn select_images_to_evict(images: &[CachedImage], max_size: u64) -> Vec<&CachedImage> {
+    let mut total: u64 = images.iter().map(|i| i.size).sum();
+    let mut lru: Vec<&CachedImage> = images.iter().collect();
+    lru.sort_by_key(|i| i.last_used);
+    lru.into_iter()
+        .take_while(|i| {
+            let evict = total > max_size;
+            total = total.saturating_sub(i.size);
+            evict
+        })
+        .collect()
+}
+
+/// Parses a size like "100G", "512M" or "1T" (in powers of 1024), or a plain
+/// number of bytes
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let shift = match unit
+        .trim()
+        .to_ascii_uppercase()
+        .trim_end_matches(['B', 'I'])
+    {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => bail!("Unknown unit of size: {s}. Use one of K, M, G and T"),
+    };
+    let num: f64 = num.parse().context(format!("Invalid size: {s}"))?;
+    Ok((num * (1u64 << shift) as f64) as u64)
+}
+
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn image_cache_eviction() {
+        assert_eq!(parse_size("100G").unwrap(), 100 << 30);
+        assert_eq!(parse_size("1.5GiB").unwrap(), 3 << 29);
+        assert_eq!(parse_size("512m").unwrap(), 512 << 20);
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("G").is_err());
+        assert_eq!(format_size(3 << 29), "1.5 GiB");
+        assert_eq!(format_size(100), "100 B");
+
+        let image = |version: &str, size: u64, last_used: i64| CachedImage {
+            board: "brya".to_string(),
+            version: version.to_string(),
+            kind: ImageKind::TestDisk.as_str().to_string(),
+            path: PathBuf::from(version),
+            size,
+            last_used,
+        };
+        let images = vec![
+            image("R120-15662.0.0", 8 << 30, 300),
+            image("R119-15633.0.0", 8 << 30, 100),
+            image("R121-15700.0.0", 4 << 30, 200),
+        ];
+        let versions = |evicted: Vec<&CachedImage>| -> Vec<String> {
+            evicted.iter().map(|i| i.version.clone()).collect()
+        };
+        assert_eq!(
+            versions(select_images_to_evict(&images, 20 << 30)),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            versions(select_images_to_evict(&images, 12 << 30)),
+            vec!["R119-15633.0.0"]
+        );
+        assert_eq!(
+            versions(select_images_to_evict(&images, 10 << 30)),
+            vec!["R119-15633.0.0", "R121-15700.0.0"]
+        );
+        assert_eq!(select_images_to_evict(&images, 0).len(), 3);
+    }
+}
@@ -13,13 +13,42 @@
 #![feature(assert_matches)]
 
 pub mod arc;
+pub mod autotest;
+pub mod bisect;
+pub mod build;
 pub mod cache;
 pub mod chroot;
 pub mod config;
+pub mod coverage;
 pub mod cros;
+pub mod deploy;
+pub mod dlc;
 pub mod dut;
+pub mod dut_crash;
+pub mod dut_export;
+pub mod dut_filter;
+pub mod dut_health;
+pub mod dut_key;
+pub mod dut_net;
+pub mod dut_sensors;
+pub mod dut_shell;
+pub mod dut_top;
+pub mod firmware;
 pub mod google_storage;
+pub mod gtest;
+pub mod image_cache;
 pub mod parser;
+pub mod perf;
+pub mod progress;
+pub mod provision;
+pub mod proxy;
 pub mod repo;
 pub mod servo;
+pub mod servo_power;
+pub mod shared_build;
+pub mod tast;
+pub mod test_report;
+pub mod test_results;
+pub mod usb_image;
 pub mod util;
+pub mod watch;
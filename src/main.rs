@@ -11,6 +11,7 @@ use std::str::FromStr;
 
 use anyhow::bail;
 use anyhow::Result;
+use cro3::progress::LogWriter;
 use tracing::trace;
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::layer::SubscriberExt;
@@ -40,7 +41,7 @@ fn main() -> Result<()> {
         .with_line_number(true)
         .with_thread_ids(true)
         .with_level(true)
-        .with_writer(std::io::stderr);
+        .with_writer(|| LogWriter);
     tracing_subscriber::registry()
         .with(tracing_subscriber)
         .with(cro3_logging_env_filter)
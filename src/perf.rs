@@ -0,0 +1,193 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Performance metrics reported by tests, collected from results-chart.json
+//! (written by tast and autotest tests for crosbolt) and keyval files of
+//! autotest, to compare them between runs.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::test_results::TestResult;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PerfMetric {
+    pub test: String,
+    /// e.g. "Boot.Time" or "Boot.Time.story" for a story of a chart
+    pub name: String,
+    pub value: f64,
+    #[serde(default)]
+    pub units: String,
+    /// "up" or "down" if a larger or smaller value is better
+    #[serde(default)]
+    pub improvement_direction: Option<String>,
+}
+
+/// Parses results-chart.json, which has charts of the metrics with the
+/// stories (or "summary") of each, e.g.
+/// {"Boot.Time": {"summary": {"units": "ms", "improvement_direction": "down",
+/// "type": "scalar", "value": 1234}}}. A list of values is averaged.
+pub fn parse_results_chart(json: &str, test: &str) -> Result<Vec<PerfMetric>> {
+    let charts: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(json).context("Failed to parse results-chart.json")?;
+    let mut metrics = Vec::new();
+    for (chart, stories) in &charts {
+        let Some(stories) = stories.as_object() else {
+            continue;
+        };
+        for (story, v) in stories {
+            let value = match v.get("values").and_then(|v| v.as_array()) {
+                Some(values) if !values.is_empty() => {
+                    values.iter().filter_map(|v| v.as_f64()).sum::<f64>() / values.len() as f64
+                }
+                _ => match v.get("value").and_then(|v| v.as_f64()) {
+                    Some(value) => value,
+                    None => continue,
+                },
+            };
+            metrics.push(PerfMetric {
+                test: test.to_string(),
+                name: if story == "summary" {
+                    chart.clone()
+                } else {
+                    format!("{chart}.{story}")
+                },
+                value,
+                units: v["units"].as_str().unwrap_or_default().to_string(),
+                improvement_direction: v["improvement_direction"].as_str().map(str::to_string),
+            });
+        }
+    }
+    Ok(metrics)
+}
+
+/// Parses a keyval file of autotest, which has the perf values as lines of
+/// "KEY{perf}=VALUE"
+pub fn parse_perf_keyval(keyval: &str, test: &str) -> Vec<PerfMetric> {
+    keyval
+        .lines()
+        .filter_map(|l| l.split_once("{perf}="))
+        .filter_map(|(name, value)| {
+            Some(PerfMetric {
+                test: test.to_string(),
+                name: name.trim().to_string(),
+                value: value.trim().parse().ok()?,
+                units: String::new(),
+                improvement_direction: None,
+            })
+        })
+        .collect()
+}
+
+/// Collects the metrics of a test from the directory of its log (for tast)
+/// or the results directory next to it (for autotest)
+pub fn collect_perf_metrics(result: &TestResult) -> Vec<PerfMetric> {
+    let Some(dir) = result.log.as_deref().and_then(Path::parent) else {
+        return Vec::new();
+    };
+    let results_dir = dir.parent().map(|d| d.join("results"));
+    for dir in [Some(dir.to_path_buf()), results_dir.clone()]
+        .iter()
+        .flatten()
+    {
+        if let Ok(json) = fs::read_to_string(dir.join("results-chart.json")) {
+            if let Ok(metrics) = parse_results_chart(&json, &result.name) {
+                return metrics;
+            }
+        }
+    }
+    results_dir
+        .and_then(|d| fs::read_to_string(d.join("keyval")).ok())
+        .map(|keyval| parse_perf_keyval(&keyval, &result.name))
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerfDelta {
+    pub test: String,
+    pub name: String,
+    pub units: String,
+    pub before: f64,
+    pub after: f64,
+    /// Percentage of the change, or None if `before` is 0
+    pub percent: Option<f64>,
+    /// True if it got worse, or None if the better direction is unknown
+    pub regressed: Option<bool>,
+}
+
+/// Compares the metrics which are in both `before` and `after`
+pub fn compare_perf(before: &[PerfMetric], after: &[PerfMetric]) -> Vec<PerfDelta> {
+    after
+        .iter()
+        .filter_map(|a| {
+            let b = before
+                .iter()
+                .find(|b| b.test == a.test && b.name == a.name)?;
+            let percent = (b.value != 0.0).then(|| (a.value - b.value) / b.value.abs() * 100.0);
+            let regressed = match a.improvement_direction.as_deref() {
+                _ if a.value == b.value => Some(false),
+                Some("up") => Some(a.value < b.value),
+                Some("down") => Some(a.value > b.value),
+                _ => None,
+            };
+            Some(PerfDelta {
+                test: a.test.clone(),
+                name: a.name.clone(),
+                units: a.units.clone(),
+                before: b.value,
+                after: a.value,
+                percent,
+                regressed,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn perf_metrics() {
+        let chart = r#"{
+  "Boot.Time": {"summary": {"units": "ms", "improvement_direction": "down",
+                            "type": "scalar", "value": 1000}},
+  "FPS": {"video": {"units": "fps", "improvement_direction": "up",
+                    "type": "list_of_scalar_values", "values": [29, 31]}}
+}"#;
+        let before = parse_results_chart(chart, "perf.Boot").unwrap();
+        assert_eq!(before.len(), 2);
+        assert_eq!(before[1].name, "FPS.video");
+        assert_eq!(before[1].value, 30.0);
+        let keyval = "foo=bar\nmem_free{perf}=512.5\n";
+        assert_eq!(
+            parse_perf_keyval(keyval, "platform_Mem"),
+            vec![PerfMetric {
+                test: "platform_Mem".to_string(),
+                name: "mem_free".to_string(),
+                value: 512.5,
+                units: String::new(),
+                improvement_direction: None,
+            }]
+        );
+
+        let after: Vec<PerfMetric> = before
+            .iter()
+            .map(|m| PerfMetric {
+                value: m.value * 1.1,
+                ..m.clone()
+            })
+            .collect();
+        let deltas = compare_perf(&before, &after);
+        assert_eq!(deltas[0].regressed, Some(true));
+        assert_eq!(deltas[1].regressed, Some(false));
+        assert!((deltas[0].percent.unwrap() - 10.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Progress reporting of flashing, broken down into phases. Each phase is
+//! timed, and the remaining time is estimated from the durations of the same
+//! phases in the previous runs.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use indicatif::MultiProgress;
+use indicatif::ProgressBar;
+use indicatif::ProgressState;
+use indicatif::ProgressStyle;
+use lazy_static::lazy_static;
+use tracing::info;
+
+use crate::cache::KvCache;
+
+/// Average duration of each phase in seconds, to estimate the remaining time
+static PHASE_SECONDS: KvCache<f64> = KvCache::new("flash_phase_seconds");
+
+lazy_static! {
+    /// All the bars being drawn, so that the ones of DUTs flashed in parallel
+    /// and the logs do not overwrite each other
+    static ref BARS: MultiProgress = MultiProgress::new();
+}
+
+/// Adds a bar to the ones drawn together
+pub fn add_progress_bar(bar: ProgressBar) -> ProgressBar {
+    BARS.add(bar)
+}
+
+/// Stderr that hides the progress bars while writing, for logging
+pub struct LogWriter;
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        BARS.suspend(|| std::io::stderr().write(buf))
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Download,
+    WriteKernel,
+    WriteRootfs,
+    Stateful,
+    Reboot,
+    Verify,
+}
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Download => "download",
+            Phase::WriteKernel => "write kernel",
+            Phase::WriteRootfs => "write rootfs",
+            Phase::Stateful => "stateful",
+            Phase::Reboot => "reboot",
+            Phase::Verify => "verify",
+        }
+    }
+}
+
+/// Returns the estimated seconds to run `phases`, or None if some of them
+/// have never been timed
+pub fn estimate_remaining(history: &HashMap<String, f64>, phases: &[Phase]) -> Option<f64> {
+    phases
+        .iter()
+        .map(|p| history.get(p.as_str()).copied())
+        .sum()
+}
+
+/// e.g. "42s", "3m05s"
+pub fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    if secs < 60 {
+        format!("{secs}s")
+    } else {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// Progress of flashing a DUT. The bar is abandoned with the failed phase if
+/// this is dropped before finish().
+pub struct FlashProgress {
+    host: String,
+    bar: ProgressBar,
+    phases: Vec<Phase>,
+    /// Index of the running phase in `phases`
+    current: Option<usize>,
+    phase_start: Instant,
+    start: Instant,
+    timings: Vec<(Phase, Duration)>,
+    history: HashMap<String, f64>,
+    /// When all the phases are expected to be done, shown as the ETA
+    deadline: Arc<Mutex<Option<Instant>>>,
+}
+impl FlashProgress {
+    /// Shows a bar of `host` that goes through `phases` in order
+    pub fn new(host: &str, phases: &[Phase]) -> Result<Self> {
+        let deadline: Arc<Mutex<Option<Instant>>> = Arc::new(Mutex::new(None));
+        let eta = {
+            let deadline = deadline.clone();
+            move |_: &ProgressState, w: &mut dyn std::fmt::Write| {
+                if let Some(deadline) = *deadline.lock().unwrap() {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    let _ = write!(w, ", ETA {}", format_duration(left.as_secs_f64()));
+                }
+            }
+        };
+        let bar = add_progress_bar(ProgressBar::new_spinner());
+        bar.set_style(
+            ProgressStyle::with_template("{spinner} {prefix}: {msg} [{elapsed}{eta}]")?
+                .with_key("eta", eta),
+        );
+        bar.set_prefix(host.to_string());
+        bar.enable_steady_tick(Duration::from_millis(200));
+        Ok(Self {
+            host: host.to_string(),
+            bar,
+            phases: phases.to_vec(),
+            current: None,
+            phase_start: Instant::now(),
+            start: Instant::now(),
+            timings: Vec::new(),
+            history: PHASE_SECONDS.entries()?,
+            deadline,
+        })
+    }
+    fn end_phase(&mut self) {
+        if let Some(i) = self.current.take() {
+            self.timings
+                .push((self.phases[i], self.phase_start.elapsed()));
+        }
+    }
+    /// Ends the running phase and starts `phase`, showing `detail` with it
+    pub fn start(&mut self, phase: Phase, detail: &str) {
+        self.end_phase();
+        let i = match self.phases.iter().position(|p| *p == phase) {
+            Some(i) => i,
+            None => {
+                self.phases.push(phase);
+                self.phases.len() - 1
+            }
+        };
+        self.current = Some(i);
+        self.phase_start = Instant::now();
+        *self.deadline.lock().unwrap() = estimate_remaining(&self.history, &self.phases[i..])
+            .map(|secs| Instant::now() + Duration::from_secs_f64(secs));
+        self.bar.set_message(format!(
+            "[{}/{}] {}: {detail}",
+            i + 1,
+            self.phases.len(),
+            phase.as_str()
+        ));
+    }
+    /// Ends the last phase, shows the time taken by each phase, and records
+    /// them for the estimates of the later runs
+    pub fn finish(mut self) -> Result<()> {
+        self.end_phase();
+        self.bar.finish_and_clear();
+        for (phase, duration) in &self.timings {
+            let secs = duration.as_secs_f64();
+            // Weigh the recent runs more
+            let average = match self.history.get(phase.as_str()) {
+                Some(average) => (average + secs) / 2.0,
+                None => secs,
+            };
+            PHASE_SECONDS.set(phase.as_str(), average)?;
+        }
+        info!(
+            "{}: done in {} ({})",
+            self.host,
+            format_duration(self.start.elapsed().as_secs_f64()),
+            self.timings
+                .iter()
+                .map(|(phase, d)| format!(
+                    "{} {}",
+                    phase.as_str(),
+                    format_duration(d.as_secs_f64())
+                ))
+                .collect::<Vec<String>>()
+                .join(", ")
+        );
+        Ok(())
+    }
+}
+impl Drop for FlashProgress {
+    fn drop(&mut self) {
+        if !self.bar.is_finished() {
+            let phase = self
+                .current
+                .map(|i| self.phases[i].as_str())
+                .unwrap_or("start");
+            self.bar.abandon_with_message(format!(
+                "failed at {phase} after {}",
+                format_duration(self.start.elapsed().as_secs_f64())
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn flash_eta() {
+        let history: HashMap<String, f64> = [("write rootfs", 120.0), ("reboot", 60.0)]
+            .iter()
+            .map(|(k, v)| (k.to_string(), *v))
+            .collect();
+        assert_eq!(
+            estimate_remaining(&history, &[Phase::WriteRootfs, Phase::Reboot]),
+            Some(180.0)
+        );
+        assert_eq!(
+            estimate_remaining(&history, &[Phase::Stateful, Phase::Reboot]),
+            None
+        );
+        assert_eq!(estimate_remaining(&history, &[]), Some(0.0));
+        assert_eq!(format_duration(42.4), "42s");
+        assert_eq!(format_duration(185.0), "3m05s");
+    }
+}
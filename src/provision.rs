@@ -0,0 +1,1181 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Provisioning of prebuilt test images to DUTs without `cros flash` (and
+//! thus without a ChromiumOS checkout). The kernel and rootfs are written to
+//! the inactive slot over SSH, and the stateful partition is updated on the
+//! next boot, in the same way as the lab does. Both the artifacts of release
+//! builds on Google Storage and locally built disk images are supported.
+//!
+//! DUTs that can not be reached over SSH can be reimaged via servo instead,
+//! by booting them into recovery mode from the USB stick on the servo.
+
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use lazy_static::lazy_static;
+use regex::Regex;
+use tracing::info;
+use tracing::warn;
+
+use crate::build::recorded_local_image;
+use crate::build::BuildImageType;
+use crate::chroot::Chroot;
+use crate::cros::lookup_full_version;
+use crate::dut::SshInfo;
+use crate::google_storage::download_gs_file;
+use crate::google_storage::list_gs_files;
+use crate::image_cache::cached_image_dir;
+use crate::image_cache::cached_image_path;
+use crate::image_cache::ImageKind;
+use crate::progress::FlashProgress;
+use crate::progress::Phase;
+use crate::servo::ServodConnection;
+use crate::usb_image::write_image_with_sudo;
+use crate::util::shell_helpers::shell_quote;
+
+lazy_static! {
+    static ref RE_CHANNEL_VERSION: Regex = Regex::new(r"/(\d+)\.(\d+)\.(\d+)/?$").unwrap();
+}
+
+pub const CHANNELS: [&str; 4] = ["canary", "dev", "beta", "stable"];
+
+const KERNEL_ARTIFACT: &str = "full_dev_part_KERN.bin.gz";
+const ROOTFS_ARTIFACT: &str = "full_dev_part_ROOT.bin.gz";
+const STATEFUL_ARTIFACT: &str = "stateful.tgz";
+
+const STATEFUL_DIR: &str = "/mnt/stateful_partition";
+
+/// Returns the latest version (e.g. 15753.0.0) in the output of `gsutil ls`
+/// for a channel directory
+pub fn latest_version_in_listing(listing: &str) -> Option<String> {
+    listing
+        .lines()
+        .filter_map(|l| {
+            let c = RE_CHANNEL_VERSION.captures(l.trim())?;
+            let v: Vec<u32> = (1..=3).filter_map(|i| c[i].parse().ok()).collect();
+            Some((v, format!("{}.{}.{}", &c[1], &c[2], &c[3])))
+        })
+        .max()
+        .map(|(_, v)| v)
+}
+
+/// Returns the latest version released to `channel` for `board`, with the
+/// milestone (e.g. R120-15662.0.0)
+pub fn latest_version_on_channel(board: &str, channel: &str) -> Result<String> {
+    if !CHANNELS.contains(&channel) {
+        bail!(
+            "Unknown channel: {channel}. Use one of {}",
+            CHANNELS.join(", ")
+        );
+    }
+    let listing = list_gs_files(&format!(
+        "gs://chromeos-releases/{channel}-channel/{board}/"
+    ))?;
+    let version = latest_version_in_listing(&listing).context(anyhow!(
+        "No release is found for {board} on {channel} channel"
+    ))?;
+    lookup_full_version(&version, board)
+}
+
+/// Downloads the artifacts to provision a test image of `full_version` (e.g.
+/// R120-15662.0.0), or reuses the ones downloaded before. Returns the
+/// directory that contains them.
+pub fn download_test_image(board: &str, full_version: &str) -> Result<PathBuf> {
+    let dir = cached_image_dir(board, full_version, ImageKind::TestArtifacts)?;
+    for artifact in [KERNEL_ARTIFACT, ROOTFS_ARTIFACT, STATEFUL_ARTIFACT] {
+        let dest = dir.join(artifact);
+        if dest.exists() {
+            info!("Using the cached {dest:?}");
+            continue;
+        }
+        let url = format!("gs://chromeos-image-archive/{board}-release/{full_version}/{artifact}");
+        info!("Downloading {url}...");
+        download_gs_file(&url, &dest)?;
+    }
+    Ok(dir)
+}
+
+/// Downloads `name`.tar.xz in the image archive, and extracts `name`.bin from
+/// it into `dir`, unless it has been extracted before
+fn download_archived_disk_image(
+    board: &str,
+    full_version: &str,
+    name: &str,
+    dir: &Path,
+) -> Result<PathBuf> {
+    let image = dir.join(format!("{name}.bin"));
+    if image.exists() {
+        info!("Using the cached {image:?}");
+        return Ok(image);
+    }
+    let archive = dir.join(format!("{name}.tar.xz"));
+    if !archive.exists() {
+        let url =
+            format!("gs://chromeos-image-archive/{board}-release/{full_version}/{name}.tar.xz");
+        info!("Downloading {url}...");
+        download_gs_file(&url, &archive)?;
+    }
+    info!("Extracting {archive:?}...");
+    Command::new("tar")
+        .arg("-xJf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(dir)
+        .arg(format!("{name}.bin"))
+        .status()?
+        .exit_ok()
+        .context(anyhow!("Failed to extract {archive:?}"))?;
+    std::fs::remove_file(&archive)?;
+    Ok(image)
+}
+
+/// Downloads the test disk image (chromiumos_test_image.bin) of
+/// `full_version`, or reuses the one downloaded before
+pub fn download_test_disk_image(board: &str, full_version: &str) -> Result<PathBuf> {
+    let dir = cached_image_dir(board, full_version, ImageKind::TestDisk)?;
+    download_archived_disk_image(board, full_version, "chromiumos_test_image", &dir)
+}
+
+/// Downloads the disk image of `image_type`, or reuses the one downloaded
+/// before
+pub fn download_disk_image(
+    board: &str,
+    full_version: &str,
+    image_type: ImageType,
+) -> Result<PathBuf> {
+    let name = match image_type {
+        ImageType::Test => return download_test_disk_image(board, full_version),
+        ImageType::Recovery => return download_recovery_image(board, full_version),
+        ImageType::Signed => return download_signed_image(board, full_version),
+        ImageType::Dev => "chromiumos_image",
+        ImageType::Base => "chromiumos_base_image",
+    };
+    let dir = cached_image_dir(board, full_version, ImageKind::OtherDisk)?;
+    download_archived_disk_image(board, full_version, name, &dir)
+}
+
+/// Type of the images to flash
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageType {
+    /// Images with the test tools and SSH access with the testing key, which
+    /// most features of cro3 (and tast) need
+    Test,
+    /// Images with the developer tools, but without the test ones
+    Dev,
+    /// Images as built for release, without the developer or test tools
+    Base,
+    /// Images that consumers use to recover their devices. They boot in
+    /// recovery mode, install themselves and have no SSH access.
+    Recovery,
+    /// Release (base) images signed with the release keys, as shipped to
+    /// consumers. They have no SSH access nor test tools.
+    Signed,
+}
+impl FromStr for ImageType {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "test" => Ok(ImageType::Test),
+            "dev" => Ok(ImageType::Dev),
+            "base" => Ok(ImageType::Base),
+            "recovery" => Ok(ImageType::Recovery),
+            "signed" => Ok(ImageType::Signed),
+            _ => bail!("Unknown image type: {s}. Use test, dev, base, recovery or signed"),
+        }
+    }
+}
+impl ImageType {
+    /// Name of the image in xBuddy paths of cros flash
+    pub fn xbuddy_variant(&self) -> &'static str {
+        match self {
+            ImageType::Test => "test",
+            ImageType::Dev => "dev",
+            ImageType::Base => "base",
+            ImageType::Recovery | ImageType::Signed => "signed",
+        }
+    }
+}
+
+/// What a DUT is flashed for, to choose the type of the image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Purpose {
+    /// Running tast tests
+    Tast,
+    /// Using cro3 features over SSH (deploy, dut shell, etc.)
+    Ssh,
+    /// Developing on the DUT with the developer tools
+    Develop,
+    /// Checking the behavior as consumers see it
+    Consumer,
+}
+impl FromStr for Purpose {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tast" => Ok(Purpose::Tast),
+            "ssh" => Ok(Purpose::Ssh),
+            "develop" => Ok(Purpose::Develop),
+            "consumer" => Ok(Purpose::Consumer),
+            _ => bail!("Unknown purpose: {s}. Use tast, ssh, develop or consumer"),
+        }
+    }
+}
+
+/// Returns the image type to flash for `purpose`, or `image_type` if it is
+/// given, with a warning if the image lacks what cro3 or the purpose needs
+pub fn select_image_type(
+    purpose: Option<Purpose>,
+    image_type: Option<ImageType>,
+) -> (ImageType, Option<String>) {
+    let suggested = match purpose {
+        None | Some(Purpose::Tast) | Some(Purpose::Ssh) => ImageType::Test,
+        Some(Purpose::Develop) => ImageType::Dev,
+        Some(Purpose::Consumer) => ImageType::Base,
+    };
+    let image_type = image_type.unwrap_or(suggested);
+    let warning = match (purpose, image_type) {
+        (_, ImageType::Test) => None,
+        (Some(purpose @ (Purpose::Tast | Purpose::Ssh)), _) => Some(format!(
+            "{image_type:?} images can not be used for {purpose:?} since they have no SSH access \
+             with the testing key. Please use a test image."
+        )),
+        _ => Some(format!(
+            "{image_type:?} images have no SSH access with the testing key, so cro3 features like \
+             deploy, tast and dut shell do not work with the DUT until a test image is flashed \
+             again"
+        )),
+    };
+    (image_type, warning)
+}
+
+/// Returns the image of `kind` (e.g. recovery, base) to use in the output of
+/// `gsutil ls` on the release bucket, preferring the ones signed with the MP
+/// keys as shipped to consumers over the pre-MP ones
+pub fn select_signed_image(listing: &str, kind: &str) -> Option<String> {
+    let images: Vec<&str> = listing
+        .lines()
+        .map(str::trim)
+        .filter(|l| l.contains(&format!("_{kind}_")) && l.ends_with(".bin"))
+        .collect();
+    images
+        .iter()
+        .find(|l| l.contains("_mp"))
+        .or(images.first())
+        .map(|l| l.to_string())
+}
+
+/// Returns the URL of the image of `kind` signed for `full_version` in the
+/// release bucket, if it is accessible
+fn find_signed_image(board: &str, full_version: &str, kind: &str) -> Result<Option<String>> {
+    let platform_version = full_version.rsplit('-').next().unwrap_or(full_version);
+    let listing = list_gs_files(&format!(
+        "gs://chromeos-releases/*-channel/{board}/{platform_version}/\
+         chromeos_{platform_version}_{board}_{kind}_*.bin"
+    ))?;
+    Ok(select_signed_image(&listing, kind))
+}
+
+/// Downloads the release image of `full_version` signed with the release
+/// keys, or reuses the one downloaded before
+pub fn download_signed_image(board: &str, full_version: &str) -> Result<PathBuf> {
+    let dir = cached_image_dir(board, full_version, ImageKind::Signed)?;
+    let image = dir.join("signed_image.bin");
+    if image.exists() {
+        info!("Using the cached {image:?}");
+        return Ok(image);
+    }
+    let url = find_signed_image(board, full_version, "base")?.context(anyhow!(
+        "No signed image of {board} {full_version} is found. Signed images are in \
+         gs://chromeos-releases, which needs a partner or release access."
+    ))?;
+    info!("Downloading {url}...");
+    download_gs_file(&url, &image)?;
+    Ok(image)
+}
+
+/// Downloads the recovery image of `full_version` (e.g. R120-15662.0.0), or
+/// reuses the one downloaded before. The signed one in the release bucket is
+/// used if it is accessible, and the one in the image archive (signed with
+/// the developer keys) otherwise.
+pub fn download_recovery_image(board: &str, full_version: &str) -> Result<PathBuf> {
+    let dir = cached_image_dir(board, full_version, ImageKind::Recovery)?;
+    let image = dir.join("recovery_image.bin");
+    if image.exists() {
+        info!("Using the cached {image:?}");
+        return Ok(image);
+    }
+    if let Some(url) = find_signed_image(board, full_version, "recovery")? {
+        info!("Downloading {url}...");
+        download_gs_file(&url, &image)?;
+        return Ok(image);
+    }
+    warn!(
+        "No signed recovery image of {board} {full_version} is accessible. Using the one signed \
+         with the developer keys, which boots only in developer mode."
+    );
+    download_archived_disk_image(board, full_version, "recovery_image", &dir)
+}
+
+/// A file used to flash an image
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageSource {
+    pub url: String,
+    /// Where it is cached, if it has been downloaded already
+    pub cached: Option<PathBuf>,
+}
+
+/// Returns the files to flash an image of `image_type` with, without
+/// downloading them. `disk` selects the disk image instead of the partition
+/// artifacts for test images.
+pub fn image_sources(
+    board: &str,
+    full_version: &str,
+    image_type: ImageType,
+    disk: bool,
+) -> Result<Vec<ImageSource>> {
+    let archive = format!("gs://chromeos-image-archive/{board}-release/{full_version}");
+    let source = |url: String, kind: ImageKind, file: &str| -> Result<ImageSource> {
+        let path = cached_image_path(board, full_version, kind)?.join(file);
+        Ok(ImageSource {
+            url,
+            cached: path.exists().then_some(path),
+        })
+    };
+    Ok(match (image_type, disk) {
+        (ImageType::Test, false) => [KERNEL_ARTIFACT, ROOTFS_ARTIFACT, STATEFUL_ARTIFACT]
+            .iter()
+            .map(|a| source(format!("{archive}/{a}"), ImageKind::TestArtifacts, a))
+            .collect::<Result<Vec<ImageSource>>>()?,
+        (ImageType::Test, true) => vec![source(
+            format!("{archive}/chromiumos_test_image.tar.xz"),
+            ImageKind::TestDisk,
+            "chromiumos_test_image.bin",
+        )?],
+        (ImageType::Dev, _) => vec![source(
+            format!("{archive}/chromiumos_image.tar.xz"),
+            ImageKind::OtherDisk,
+            "chromiumos_image.bin",
+        )?],
+        (ImageType::Base, _) => vec![source(
+            format!("{archive}/chromiumos_base_image.tar.xz"),
+            ImageKind::OtherDisk,
+            "chromiumos_base_image.bin",
+        )?],
+        (ImageType::Recovery, _) => vec![source(
+            find_signed_image(board, full_version, "recovery")?
+                .unwrap_or(format!("{archive}/recovery_image.tar.xz")),
+            ImageKind::Recovery,
+            "recovery_image.bin",
+        )?],
+        (ImageType::Signed, _) => vec![source(
+            find_signed_image(board, full_version, "base")?.context(anyhow!(
+                "No signed image of {board} {full_version} is found"
+            ))?,
+            ImageKind::Signed,
+            "signed_image.bin",
+        )?],
+    })
+}
+
+/// Returns the device file of partition `num` on `disk` (e.g. /dev/sda3,
+/// /dev/nvme0n1p3)
+pub fn partition_device(disk: &str, num: &str) -> String {
+    if disk.ends_with(|c: char| c.is_ascii_digit()) {
+        format!("{disk}p{num}")
+    } else {
+        format!("{disk}{num}")
+    }
+}
+
+/// Partitions of the slot that is not running
+pub struct InactiveSlot {
+    pub disk: String,
+    pub kern_num: String,
+    pub root_num: String,
+    /// Device of the running rootfs
+    pub active_root: String,
+    /// Kernel partition of the running slot
+    pub active_kern_num: String,
+}
+impl InactiveSlot {
+    pub fn of(ssh: &SshInfo) -> Result<Self> {
+        let rootdev = ssh.get_rootdev()?;
+        let disk = ssh.get_rootdisk()?;
+        let part = ssh.get_partnum_info()?;
+        let get = |key: &str| {
+            part.get(key)
+                .cloned()
+                .context(anyhow!("{key} not found in the partition layout"))
+        };
+        let (kern_num, root_num, active_kern_num) = if rootdev.ends_with(&get("root_a")?) {
+            (get("kern_b")?, get("root_b")?, get("kern_a")?)
+        } else if rootdev.ends_with(&get("root_b")?) {
+            (get("kern_a")?, get("root_a")?, get("kern_b")?)
+        } else {
+            bail!("Unsupported partition layout (rootdev: {rootdev})");
+        };
+        Ok(Self {
+            disk,
+            kern_num,
+            root_num,
+            active_root: rootdev,
+            active_kern_num,
+        })
+    }
+}
+
+/// A test image to provision
+pub enum TestImage {
+    /// Artifacts of a release build in a directory (see download_test_image)
+    Artifacts(PathBuf),
+    /// A disk image such as chromiumos_test_image.bin built locally
+    DiskImage(PathBuf),
+}
+impl TestImage {
+    /// Returns the gzipped contents to be sent to the DUT, which is either an
+    /// artifact file or a partition of the disk image (compressed by a child
+    /// process that should be waited for after sending).
+    fn gzipped(&self, artifact: &str, label: &str) -> Result<(Stdio, Option<Child>)> {
+        match self {
+            TestImage::Artifacts(dir) => {
+                let path = dir.join(artifact);
+                let file = File::open(&path).context(anyhow!("Failed to open {path:?}"))?;
+                Ok((file.into(), None))
+            }
+            TestImage::DiskImage(image) => {
+                let part = read_gpt(image)?
+                    .into_iter()
+                    .find(|p| p.label == label)
+                    .context(anyhow!("{label} is not found in {image:?}"))?;
+                let mut child = Command::new("bash")
+                    .arg("-c")
+                    .arg(format!(
+                        "dd if={} bs=4M iflag=skip_bytes,count_bytes skip={} count={} status=none \
+                         | gzip -1",
+                        shell_quote(&image.to_string_lossy()),
+                        part.offset,
+                        part.size
+                    ))
+                    .stdout(Stdio::piped())
+                    .spawn()?;
+                let stdout = child.stdout.take().context("No stdout of gzip")?;
+                Ok((stdout.into(), Some(child)))
+            }
+        }
+    }
+    /// Returns the uncompressed rootfs as (file, offset, size), extracting it
+    /// from the artifact if needed
+    fn raw_rootfs(&self) -> Result<(PathBuf, u64, u64)> {
+        match self {
+            TestImage::Artifacts(dir) => {
+                let path = dir.join(ROOTFS_ARTIFACT.trim_end_matches(".gz"));
+                if !path.exists() {
+                    info!("Extracting {ROOTFS_ARTIFACT}...");
+                    let tmp = dir.join("rootfs.part");
+                    Command::new("bash")
+                        .arg("-c")
+                        .arg(format!(
+                            "gzip -dc {} > {}",
+                            shell_quote(&dir.join(ROOTFS_ARTIFACT).to_string_lossy()),
+                            shell_quote(&tmp.to_string_lossy())
+                        ))
+                        .status()?
+                        .exit_ok()
+                        .context("Failed to extract the rootfs")?;
+                    std::fs::rename(&tmp, &path)?;
+                }
+                let size = std::fs::metadata(&path)?.len();
+                Ok((path, 0, size))
+            }
+            TestImage::DiskImage(image) => {
+                let part = read_gpt(image)?
+                    .into_iter()
+                    .find(|p| p.label == "ROOT-A")
+                    .context(anyhow!("ROOT-A is not found in {image:?}"))?;
+                Ok((image.clone(), part.offset, part.size))
+            }
+        }
+    }
+}
+
+/// Block size to compare rootfs images in delta provisioning
+const DELTA_BLOCK_SIZE: u64 = 1024 * 1024;
+
+/// Returns a command that prints the block $i in `size` bytes from `offset`
+/// of `path`. The last block can be short.
+fn read_block_cmd(path: &str, offset: u64, size: u64) -> String {
+    let b = DELTA_BLOCK_SIZE;
+    format!(
+        "n=$(({size} - i * {b})); [ $n -gt {b} ] && n={b}; dd if={path} bs={b} \
+         iflag=skip_bytes,count_bytes skip=$(({offset} + i * {b})) count=$n status=none"
+    )
+}
+
+/// Returns a script that prints the md5 of each block in `size` bytes from
+/// `offset` of `path`, one per line. The same script runs on the host and the
+/// DUT so that the results can be compared.
+fn block_hashes_script(path: &str, offset: u64, size: u64) -> String {
+    format!(
+        "i=0; while [ $((i * {DELTA_BLOCK_SIZE})) -lt {size} ]; do {} | md5sum | cut -c 1-32; \
+         i=$((i + 1)); done",
+        read_block_cmd(path, offset, size)
+    )
+}
+
+/// Returns a script that prints the md5 of `size` bytes from `offset` of
+/// `path`
+fn range_hash_script(path: &str, offset: u64, size: u64) -> String {
+    format!(
+        "dd if={path} bs=4M iflag=skip_bytes,count_bytes skip={offset} count={size} status=none | \
+         md5sum | cut -c 1-32"
+    )
+}
+
+/// Returns the indices of the blocks whose hashes differ (or are missing in
+/// `remote`)
+pub fn changed_blocks(local: &str, remote: &str) -> Vec<usize> {
+    let remote: Vec<&str> = remote.lines().collect();
+    local
+        .lines()
+        .enumerate()
+        .filter(|(i, hash)| remote.get(*i) != Some(hash))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+fn run_local_script(script: &str) -> Result<String> {
+    let output = Command::new("bash").arg("-c").arg(script).output()?;
+    output
+        .status
+        .exit_ok()
+        .context("Failed to read the image")?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Writes the rootfs of the image to `device` by copying the running rootfs
+/// on the DUT first and sending only the blocks that differ from it. This is
+/// much faster than sending the whole rootfs if the DUT runs a nearby
+/// version.
+fn write_rootfs_delta(
+    ssh: &SshInfo,
+    image: &TestImage,
+    active_root: &str,
+    device: &str,
+) -> Result<()> {
+    let host = ssh.host_and_port();
+    let (src, offset, size) = image.raw_rootfs()?;
+    let src = shell_quote(&src.to_string_lossy());
+    info!("{host}: Comparing the rootfs with the running one...");
+    let remote = ssh.run_cmd_stdio(&block_hashes_script(active_root, 0, size))?;
+    let local = run_local_script(&block_hashes_script(&src, offset, size))?;
+    let changed = changed_blocks(&local, &remote);
+    info!(
+        "{host}: {} of {} blocks ({} MiB each) differ",
+        changed.len(),
+        local.lines().count(),
+        DELTA_BLOCK_SIZE >> 20
+    );
+    ssh.run_cmd_stdio(&format!(
+        "dd if={active_root} of={device} bs=4M iflag=count_bytes count={size} conv=fsync \
+         status=none"
+    ))
+    .context("Failed to copy the running rootfs")?;
+    if !changed.is_empty() {
+        let list = changed
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<String>>()
+            .join(" ");
+        let mut child = Command::new("bash")
+            .arg("-c")
+            .arg(format!(
+                "for i in {list}; do {}; done | gzip -1",
+                read_block_cmd(&src, offset, size)
+            ))
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdout = child.stdout.take().context("No stdout of gzip")?;
+        send_to_dut(
+            ssh,
+            (stdout.into(), Some(child)),
+            &format!(
+                "gzip -dc | (for i in {list}; do dd of={device} bs={DELTA_BLOCK_SIZE} seek=$i \
+                 count=1 iflag=fullblock conv=notrunc status=none; done) && sync"
+            ),
+            &format!("Writing {} changed blocks to {device}", changed.len()),
+        )?;
+    }
+    let expected = run_local_script(&range_hash_script(&src, offset, size))?;
+    let actual = ssh.run_cmd_stdio(&range_hash_script(device, 0, size))?;
+    if actual.trim() != expected {
+        bail!("The rootfs written to {device} is corrupted (md5: {actual}, expected: {expected})");
+    }
+    Ok(())
+}
+
+/// Returns the latest test image built in a cros checkout for `board`. The
+/// one recorded by `cro3 build image` is preferred, since "latest" may point
+/// to an image of another type.
+pub fn latest_local_image(repo: &str, board: &str) -> Result<PathBuf> {
+    let repo_dir = Path::new(repo)
+        .canonicalize()
+        .unwrap_or(PathBuf::from(repo));
+    if let Some(image) = recorded_local_image(board, BuildImageType::Test)? {
+        if image.path.starts_with(repo_dir) {
+            return Ok(image.path);
+        }
+    }
+    let path = Path::new(repo).join(format!(
+        "src/build/images/{board}/latest/chromiumos_test_image.bin"
+    ));
+    if !path.exists() {
+        bail!("{path:?} is not found. Please build a test image for {board} first.");
+    }
+    Ok(path)
+}
+
+/// A partition in a disk image (offset and size in bytes)
+#[derive(Debug, Clone, PartialEq)]
+pub struct GptPartition {
+    pub num: u32,
+    pub label: String,
+    pub offset: u64,
+    pub size: u64,
+}
+
+const SECTOR_SIZE: u64 = 512;
+
+fn le_u32(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+fn le_u64(b: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+/// Parses the partition entries of a GPT with the header (LBA 1)
+pub fn parse_gpt(header: &[u8], entries: &[u8]) -> Result<Vec<GptPartition>> {
+    if header.len() < 92 || &header[0..8] != b"EFI PART" {
+        bail!("No GPT header is found");
+    }
+    let entry_size = le_u32(header, 84) as usize;
+    if entry_size < 128 {
+        bail!("Invalid GPT entry size: {entry_size}");
+    }
+    let mut partitions = Vec::new();
+    for (i, e) in entries.chunks_exact(entry_size).enumerate() {
+        // Unused entries have the type GUID of all zeros
+        if e[0..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+        let first = le_u64(e, 32);
+        let last = le_u64(e, 40);
+        let name: Vec<u16> = e[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+        partitions.push(GptPartition {
+            num: i as u32 + 1,
+            label: String::from_utf16_lossy(&name),
+            offset: first * SECTOR_SIZE,
+            size: (last + 1 - first) * SECTOR_SIZE,
+        });
+    }
+    Ok(partitions)
+}
+
+pub fn read_gpt(image: &Path) -> Result<Vec<GptPartition>> {
+    let mut f = File::open(image).context(anyhow!("Failed to open {image:?}"))?;
+    let mut header = vec![0u8; SECTOR_SIZE as usize];
+    f.seek(SeekFrom::Start(SECTOR_SIZE))?;
+    f.read_exact(&mut header)?;
+    if &header[0..8] != b"EFI PART" {
+        bail!("{image:?} is not a disk image with GPT");
+    }
+    let entries_lba = le_u64(&header, 72);
+    let num_entries = le_u32(&header, 80) as usize;
+    let entry_size = le_u32(&header, 84) as usize;
+    let mut entries = vec![0u8; num_entries * entry_size];
+    f.seek(SeekFrom::Start(entries_lba * SECTOR_SIZE))?;
+    f.read_exact(&mut entries)?;
+    parse_gpt(&header, &entries)
+}
+
+/// Sends `input` to a command on the DUT
+fn send_to_dut(ssh: &SshInfo, input: (Stdio, Option<Child>), cmd: &str, what: &str) -> Result<()> {
+    let status = ssh.ssh_cmd(None)?.arg(cmd).stdin(input.0).status()?;
+    if let Some(mut child) = input.1 {
+        child
+            .wait()?
+            .exit_ok()
+            .context(anyhow!("Failed to read the image ({what})"))?;
+    }
+    if !status.success() {
+        bail!("Failed on the DUT ({what})");
+    }
+    Ok(())
+}
+
+pub struct ProvisionOptions {
+    pub clobber_stateful: bool,
+    /// Send only the blocks of the rootfs that differ from the running one
+    pub delta: bool,
+    pub disable_rootfs_verification: bool,
+    pub clear_tpm_owner: bool,
+}
+
+/// Provisions a test image to the DUT, reboots it into the image and waits
+/// until it is up again
+pub fn provision_test_image(
+    ssh: &SshInfo,
+    image: &TestImage,
+    options: &ProvisionOptions,
+    progress: &mut FlashProgress,
+) -> Result<()> {
+    let slot = InactiveSlot::of(ssh)?;
+    for (artifact, label, num, phase) in [
+        (
+            KERNEL_ARTIFACT,
+            "KERN-A",
+            &slot.kern_num,
+            Phase::WriteKernel,
+        ),
+        (
+            ROOTFS_ARTIFACT,
+            "ROOT-A",
+            &slot.root_num,
+            Phase::WriteRootfs,
+        ),
+    ] {
+        let device = partition_device(&slot.disk, num);
+        progress.start(phase, &device);
+        if label == "ROOT-A" && options.delta {
+            write_rootfs_delta(ssh, image, &slot.active_root, &device)?;
+            continue;
+        }
+        send_to_dut(
+            ssh,
+            image.gzipped(artifact, label)?,
+            &format!("gzip -dc | dd of={device} bs=4M conv=fsync 2>/dev/null"),
+            &format!("Writing {label} of the image to {device}"),
+        )?;
+    }
+
+    // chromeos_startup swaps dev_image and var_overlay with the new ones on
+    // the next boot if .update_available exists
+    let update_available = if options.clobber_stateful {
+        format!("echo -n clobber > {STATEFUL_DIR}/.update_available")
+    } else {
+        format!("touch {STATEFUL_DIR}/.update_available")
+    };
+    let extract = match image {
+        TestImage::Artifacts(_) => format!(
+            "tar -xzf - -C {STATEFUL_DIR} --transform=s,^dev_image,dev_image_new, \
+             --transform=s,^var_overlay,var_new,"
+        ),
+        // The stateful partition can not be mounted locally without root, so
+        // it is mounted on the DUT to copy the contents
+        TestImage::DiskImage(_) => format!(
+            "gzip -dc > {STATEFUL_DIR}/cro3_stateful.img && mkdir -p /tmp/cro3_stateful && mount \
+             -o loop,ro {STATEFUL_DIR}/cro3_stateful.img /tmp/cro3_stateful && cp -a \
+             /tmp/cro3_stateful/dev_image {STATEFUL_DIR}/dev_image_new && cp -a \
+             /tmp/cro3_stateful/var_overlay {STATEFUL_DIR}/var_new; rc=$?; umount \
+             /tmp/cro3_stateful; rm -f {STATEFUL_DIR}/cro3_stateful.img; [ $rc -eq 0 ]"
+        ),
+    };
+    progress.start(Phase::Stateful, STATEFUL_DIR);
+    send_to_dut(
+        ssh,
+        image.gzipped(STATEFUL_ARTIFACT, "STATE")?,
+        &format!(
+            "rm -rf {STATEFUL_DIR}/dev_image_new {STATEFUL_DIR}/var_new && {extract} && \
+             {update_available}"
+        ),
+        "Updating the stateful partition",
+    )?;
+
+    if options.disable_rootfs_verification {
+        ssh.run_cmd_stdio(&format!(
+            "/usr/share/vboot/bin/make_dev_ssd.sh --partitions {} --remove_rootfs_verification \
+             --force",
+            slot.kern_num
+        ))
+        .context("Failed to disable rootfs verification")?;
+    }
+    if options.clear_tpm_owner {
+        ssh.run_cmd_stdio("crossystem clear_tpm_owner_request=1")?;
+    }
+    // Boot the new slot next. It falls back to the current slot if the new
+    // one fails to boot 6 times, and is marked successful after a good boot.
+    ssh.run_cmd_stdio(&format!(
+        "cgpt add -i {kern} -S 0 -T 6 {disk} && cgpt prioritize -i {kern} {disk}",
+        kern = slot.kern_num,
+        disk = slot.disk
+    ))
+    .context("Failed to update the partition table")?;
+
+    progress.start(Phase::Reboot, "waiting for the DUT to boot");
+    ssh.run_cmd_stdio("(sleep 1; reboot) >/dev/null 2>&1 &")?;
+    ssh.close_connection()?;
+    std::thread::sleep(Duration::from_secs(10));
+    ssh.wait_for_boot(Duration::from_secs(600), false)?;
+    let slot_now = InactiveSlot::of(ssh)?;
+    if slot_now.kern_num == slot.kern_num {
+        bail!("The DUT did not boot from the new image (it may have fallen back)");
+    }
+    Ok(())
+}
+
+/// Writes a signed image to the inactive slot of the DUT and reboots it into
+/// the image, wiping the stateful partition (powerwash) since the one of a
+/// test image is not usable. The DUT is not reachable over SSH afterwards, so
+/// this returns without waiting for it.
+pub fn provision_signed_image(ssh: &SshInfo, image: &Path, normal_mode: bool) -> Result<()> {
+    let image = TestImage::DiskImage(image.to_path_buf());
+    let slot = InactiveSlot::of(ssh)?;
+    for (label, num) in [("KERN-A", &slot.kern_num), ("ROOT-A", &slot.root_num)] {
+        let device = partition_device(&slot.disk, num);
+        info!("Writing {label} of the image to {device}...");
+        send_to_dut(
+            ssh,
+            image.gzipped("", label)?,
+            &format!("gzip -dc | dd of={device} bs=4M conv=fsync 2>/dev/null"),
+            &format!("Writing {label} of the image to {device}"),
+        )?;
+    }
+    // clobber-state runs on the next boot and wipes the stateful partition
+    ssh.run_cmd_stdio(&format!(
+        "echo 'fast safe' > {STATEFUL_DIR}/factory_install_reset && crossystem \
+         clear_tpm_owner_request=1"
+    ))?;
+    if normal_mode {
+        ssh.run_cmd_stdio("crossystem disable_dev_request=1")
+            .context("Failed to request the normal mode")?;
+    }
+    ssh.run_cmd_stdio(&format!(
+        "cgpt add -i {kern} -S 0 -T 6 {disk} && cgpt prioritize -i {kern} {disk}",
+        kern = slot.kern_num,
+        disk = slot.disk
+    ))
+    .context("Failed to update the partition table")?;
+    info!(
+        "{}: Rebooting into the signed image...",
+        ssh.host_and_port()
+    );
+    ssh.run_cmd_stdio("(sleep 1; reboot) >/dev/null 2>&1 &")?;
+    ssh.close_connection()
+}
+
+/// What a DUT is expected to run after provisioning
+pub struct ExpectedImage {
+    /// e.g. R120-15662.0.0 or 15662.0.0. The version is not checked if None.
+    pub version: Option<String>,
+    pub rootfs_verification: bool,
+}
+
+/// Returns the release description in the content of /etc/lsb-release, or an
+/// error if its version is not `expected`
+pub fn check_lsb_release(lsb_release: &str, expected: Option<&str>) -> Result<String> {
+    let value = |key: &str| {
+        lsb_release
+            .lines()
+            .find_map(|l| l.strip_prefix(&format!("{key}=")))
+            .map(|v| v.trim().to_string())
+    };
+    let version = value("CHROMEOS_RELEASE_VERSION").context("No version in /etc/lsb-release")?;
+    if let Some(expected) = expected {
+        // R120-15662.0.0 => 15662.0.0
+        let expected = expected.rsplit('-').next().unwrap_or(expected);
+        if version != expected {
+            bail!("The DUT runs {version} but {expected} is expected");
+        }
+    }
+    Ok(value("CHROMEOS_RELEASE_DESCRIPTION").unwrap_or(version))
+}
+
+/// Returns an error if the state of rootfs verification is not the expected
+/// one. `rootdev` is the output of rootdev, and `verity_status` is the output
+/// of `dmsetup status vroot` (e.g. "0 4710400 verity V"), which can be empty.
+pub fn check_rootfs_verity(rootdev: &str, verity_status: &str, enabled: bool) -> Result<()> {
+    let on_dm = rootdev.trim().starts_with("/dev/dm-");
+    match (enabled, on_dm) {
+        (true, false) => bail!(
+            "Rootfs verification is disabled unexpectedly (root: {})",
+            rootdev.trim()
+        ),
+        (false, true) => bail!("Rootfs verification is still enabled"),
+        _ => {}
+    }
+    if enabled && verity_status.split_whitespace().last() == Some("C") {
+        bail!("The rootfs is corrupted: {}", verity_status.trim());
+    }
+    Ok(())
+}
+
+/// Checks that a provisioned DUT reaches the login screen and runs the
+/// expected image
+pub fn verify_provisioned_dut(ssh: &SshInfo, expected: &ExpectedImage) -> Result<()> {
+    let host = ssh.host_and_port();
+    info!("{host}: Waiting for the login screen...");
+    ssh.wait_for_boot(Duration::from_secs(300), true)
+        .context("The DUT did not reach the login screen")?;
+    let release = check_lsb_release(
+        &ssh.run_cmd_stdio("cat /etc/lsb-release")?,
+        expected.version.as_deref(),
+    )?;
+    check_rootfs_verity(
+        &ssh.run_cmd_stdio("rootdev")?,
+        &ssh.run_cmd_stdio("dmsetup status vroot 2>/dev/null || true")?,
+        expected.rootfs_verification,
+    )?;
+    info!("{host}: Verified {release}");
+    Ok(())
+}
+
+/// Sets servo controls, e.g. "power_state:rec"
+fn servo_control(chroot: &Chroot, servod: &ServodConnection, controls: &[&str]) -> Result<()> {
+    info!("Setting {}...", controls.join(" "));
+    servod.run_dut_control(chroot, controls)?;
+    Ok(())
+}
+
+/// Writes `image` to the USB stick on the servo, and leaves it connected to
+/// the DUT
+fn write_image_to_servo_usbkey(
+    chroot: &Chroot,
+    servod: &ServodConnection,
+    image: &Path,
+) -> Result<()> {
+    servo_control(
+        chroot,
+        servod,
+        &["image_usbkey_direction:servo_sees_usbkey"],
+    )?;
+    // The device node appears a bit after the mux is switched
+    std::thread::sleep(Duration::from_secs(5));
+    let device = servod.get_control(chroot, "image_usbkey_dev")?;
+    if !device.starts_with("/dev/") {
+        bail!("The USB stick on the servo is not found (image_usbkey_dev: {device})");
+    }
+    info!("Writing {image:?} to {device} on the servo...");
+    write_image_with_sudo(image, &device, true)?;
+    servo_control(chroot, servod, &["image_usbkey_direction:dut_sees_usbkey"])
+}
+
+/// Reimages a DUT that can not boot (or be reached over SSH) via its servo.
+/// The disk image is written to the USB stick on the servo, the DUT is booted
+/// into recovery mode from it, and the image is installed to the internal
+/// disk with chromeos-install.
+pub fn provision_via_servo(
+    chroot: &Chroot,
+    servod: &ServodConnection,
+    image: &Path,
+    ssh: &SshInfo,
+) -> Result<()> {
+    write_image_to_servo_usbkey(chroot, servod, image)?;
+    servo_control(chroot, servod, &["power_state:rec"])?;
+    ssh.close_connection()?;
+    info!("Waiting for the DUT to boot from the USB stick...");
+    ssh.wait_for_boot(Duration::from_secs(600), false)?;
+    let fw_type = ssh.run_cmd_stdio("crossystem mainfw_type")?;
+    if fw_type.trim() != "recovery" {
+        bail!("The DUT did not boot in recovery mode (mainfw_type: {fw_type})");
+    }
+
+    info!("Installing the image to the internal disk...");
+    ssh.run_cmd_piped(&["chromeos-install", "--yes"])
+        .context("chromeos-install failed")?;
+    // Take the USB stick away from the DUT so that it boots from the disk
+    servo_control(chroot, servod, &["power_state:off"])?;
+    servo_control(
+        chroot,
+        servod,
+        &["image_usbkey_direction:servo_sees_usbkey"],
+    )?;
+    servo_control(chroot, servod, &["power_state:on"])?;
+    ssh.close_connection()?;
+    info!("Waiting for the DUT to boot from the internal disk...");
+    ssh.wait_for_boot(Duration::from_secs(600), false)?;
+    let fw_type = ssh.run_cmd_stdio("crossystem mainfw_type")?;
+    if fw_type.trim() == "recovery" {
+        bail!("The DUT is still in recovery mode after the installation");
+    }
+    Ok(())
+}
+
+/// Time for a recovery image to install itself. The DUT can not be watched
+/// over SSH while it runs.
+pub const RECOVERY_INSTALL_TIME: Duration = Duration::from_secs(15 * 60);
+
+/// Recovers the DUT with a recovery image via servo, as consumers do with a
+/// USB stick. The DUT runs the image without SSH access afterwards.
+pub fn recover_via_servo(chroot: &Chroot, servod: &ServodConnection, image: &Path) -> Result<()> {
+    write_image_to_servo_usbkey(chroot, servod, image)?;
+    servo_control(chroot, servod, &["power_state:rec"])?;
+    info!(
+        "Waiting {} minutes for the recovery to complete...",
+        RECOVERY_INSTALL_TIME.as_secs() / 60
+    );
+    std::thread::sleep(RECOVERY_INSTALL_TIME);
+    // The recovery screen reboots the DUT once the USB stick is removed
+    servo_control(
+        chroot,
+        servod,
+        &["image_usbkey_direction:servo_sees_usbkey"],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn latest_version_in_listing_picks_the_highest() {
+        let listing = "gs://chromeos-releases/canary-channel/brya/15662.0.0/
+gs://chromeos-releases/canary-channel/brya/15753.0.0/
+gs://chromeos-releases/canary-channel/brya/9999.0.0/
+gs://chromeos-releases/canary-channel/brya/15753.10.0/
+gs://chromeos-releases/canary-channel/brya/LATEST";
+        assert_eq!(
+            latest_version_in_listing(listing).as_deref(),
+            Some("15753.10.0")
+        );
+        assert_eq!(latest_version_in_listing(""), None);
+    }
+
+    #[test]
+    fn partition_device_of_disks() {
+        assert_eq!(partition_device("/dev/sda", "3"), "/dev/sda3");
+        assert_eq!(partition_device("/dev/nvme0n1", "4"), "/dev/nvme0n1p4");
+        assert_eq!(partition_device("/dev/mmcblk0", "2"), "/dev/mmcblk0p2");
+    }
+
+    #[test]
+    fn parse_gpt_skips_unused_entries() {
+        let mut header = vec![0u8; 512];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        let mut entries = vec![0u8; 128 * 3];
+        // Entry 2 is unused
+        for (i, first, last, label) in [(0, 64u64, 127u64, "STATE"), (2, 128, 191, "ROOT-A")] {
+            let e = &mut entries[i * 128..(i + 1) * 128];
+            e[0] = 1;
+            e[32..40].copy_from_slice(&first.to_le_bytes());
+            e[40..48].copy_from_slice(&last.to_le_bytes());
+            for (j, c) in label.encode_utf16().enumerate() {
+                e[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+        let parts = parse_gpt(&header, &entries).unwrap();
+        assert_eq!(
+            parts,
+            vec![
+                GptPartition {
+                    num: 1,
+                    label: "STATE".to_string(),
+                    offset: 32768,
+                    size: 32768
+                },
+                GptPartition {
+                    num: 3,
+                    label: "ROOT-A".to_string(),
+                    offset: 65536,
+                    size: 32768
+                }
+            ]
+        );
+        assert!(parse_gpt(&[0u8; 512], &entries).is_err());
+    }
+
+    #[test]
+    fn check_lsb_release_matches_the_version() {
+        let lsb_release = "CHROMEOS_RELEASE_BOARD=brya
+CHROMEOS_RELEASE_DESCRIPTION=15662.0.0 (Official Build) dev-channel brya test
+CHROMEOS_RELEASE_VERSION=15662.0.0
+";
+        assert_eq!(
+            check_lsb_release(lsb_release, Some("R120-15662.0.0")).unwrap(),
+            "15662.0.0 (Official Build) dev-channel brya test"
+        );
+        assert!(check_lsb_release(lsb_release, Some("15663.0.0")).is_err());
+        assert!(check_lsb_release(lsb_release, None).is_ok());
+        assert!(check_lsb_release("", None).is_err());
+    }
+
+    #[test]
+    fn check_rootfs_verity_of_the_root_device() {
+        assert!(check_rootfs_verity("/dev/dm-0\n", "0 4710400 verity V\n", true).is_ok());
+        assert!(check_rootfs_verity("/dev/dm-0", "0 4710400 verity C", true).is_err());
+        assert!(check_rootfs_verity("/dev/nvme0n1p3", "", true).is_err());
+        assert!(check_rootfs_verity("/dev/nvme0n1p3", "", false).is_ok());
+        assert!(check_rootfs_verity("/dev/dm-0", "", false).is_err());
+    }
+
+    #[test]
+    fn changed_blocks_of_hashes() {
+        assert_eq!(changed_blocks("a\nb\nc\nd", "a\nx\nc"), vec![1, 3]);
+        assert_eq!(changed_blocks("a\nb", "a\nb\nc"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn select_signed_image_prefers_mp_keys() {
+        let dir = "gs://chromeos-releases/stable-channel/brya/15662.58.0";
+        let listing = format!(
+            "{dir}/chromeos_15662.58.0_brya_recovery_stable-channel_premp.bin
+{dir}/chromeos_15662.58.0_brya_recovery_stable-channel_mp-v3.bin
+"
+        );
+        assert_eq!(
+            select_signed_image(&listing, "recovery"),
+            Some(format!(
+                "{dir}/chromeos_15662.58.0_brya_recovery_stable-channel_mp-v3.bin"
+            ))
+        );
+        assert_eq!(
+            select_signed_image(listing.lines().next().unwrap(), "recovery"),
+            Some(format!(
+                "{dir}/chromeos_15662.58.0_brya_recovery_stable-channel_premp.bin"
+            ))
+        );
+        assert_eq!(select_signed_image(&listing, "base"), None);
+    }
+
+    #[test]
+    fn image_type_for_purpose() {
+        assert_eq!(
+            "recovery".parse::<ImageType>().unwrap(),
+            ImageType::Recovery
+        );
+        assert_eq!("signed".parse::<ImageType>().unwrap(), ImageType::Signed);
+        assert_eq!(select_image_type(None, None), (ImageType::Test, None));
+        assert_eq!(
+            select_image_type(Some(Purpose::Develop), None).0,
+            ImageType::Dev
+        );
+        assert!(select_image_type(Some(Purpose::Develop), None).1.is_some());
+        assert_eq!(
+            select_image_type(Some(Purpose::Consumer), Some(ImageType::Test)),
+            (ImageType::Test, None)
+        );
+        let (image_type, warning) = select_image_type(Some(Purpose::Tast), Some(ImageType::Base));
+        assert_eq!(image_type, ImageType::Base);
+        assert!(warning.unwrap().contains("Tast"));
+        assert!("factory".parse::<ImageType>().is_err());
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Proxy settings for the tools used by cro3 (git, repo, gsutil and cro3
+//! itself). On corp networks, these tools fail in various ways if only some of
+//! them know about the proxy, so cro3 keeps them consistent.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use dirs::home_dir;
+use lazy_static::lazy_static;
+use url::Url;
+
+use crate::config::Config;
+use crate::util::shell_helpers::get_stdout;
+use crate::util::shell_helpers::run_bash_command;
+
+const PROXY_ENV_VARS: [&str; 4] = ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy"];
+const NO_PROXY_ENV_VARS: [&str; 2] = ["NO_PROXY", "no_proxy"];
+
+lazy_static! {
+    /// Proxy in the environment before apply_proxy_config() sets the one in
+    /// the config, to check the environment itself
+    static ref ORIGINAL_ENV_PROXY: Option<String> = proxy_from_env();
+}
+
+/// Returns "host:port" of a given proxy URL (e.g. http://proxy.example:3128).
+/// The scheme can be omitted.
+pub fn proxy_host_and_port(proxy: &str) -> Result<(String, u16)> {
+    let proxy = proxy.trim();
+    let url = if proxy.contains("://") {
+        Url::parse(proxy)
+    } else {
+        Url::parse(&format!("http://{proxy}"))
+    }
+    .context(format!("Failed to parse proxy URL: {proxy}"))?;
+    let host = url.host_str().context("Proxy URL does not have a host")?;
+    let port = url
+        .port_or_known_default()
+        .context("Proxy URL does not have a port")?;
+    Ok((host.to_string(), port))
+}
+
+fn normalize(proxy: &str) -> String {
+    proxy_host_and_port(proxy)
+        .map(|(host, port)| format!("{host}:{port}"))
+        .unwrap_or(proxy.to_string())
+}
+
+/// Proxy URL taken from the environment variables, if any
+pub fn proxy_from_env() -> Option<String> {
+    PROXY_ENV_VARS
+        .iter()
+        .find_map(|k| env::var(k).ok().filter(|v| !v.is_empty()))
+}
+
+/// Path to the boto config used by gsutil
+pub fn boto_path() -> Result<PathBuf> {
+    if let Ok(path) = env::var("BOTO_CONFIG") {
+        return Ok(PathBuf::from(path));
+    }
+    Ok(home_dir()
+        .context("Failed to determine home dir")?
+        .join(".boto"))
+}
+
+/// Returns "host:port" configured in the [Boto] section of a boto config
+pub fn read_boto_proxy(boto: &str) -> Option<String> {
+    let mut in_boto_section = false;
+    let mut host = None;
+    let mut port = None;
+    for line in boto.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_boto_section = line == "[Boto]";
+            continue;
+        }
+        if !in_boto_section {
+            continue;
+        }
+        if let Some((k, v)) = line.split_once('=') {
+            match k.trim() {
+                "proxy" => host = Some(v.trim().to_string()),
+                "proxy_port" => port = Some(v.trim().to_string()),
+                _ => {}
+            }
+        }
+    }
+    match (host, port) {
+        (Some(host), Some(port)) => Some(format!("{host}:{port}")),
+        (Some(host), None) => Some(host),
+        _ => None,
+    }
+}
+
+/// Returns a boto config with the proxy in the [Boto] section replaced with
+/// `proxy` (or removed if None), keeping the other lines as is.
+pub fn update_boto_proxy(boto: &str, proxy: Option<(&str, u16)>) -> String {
+    let mut lines: Vec<String> = Vec::new();
+    let mut in_boto_section = false;
+    let mut found_section = false;
+    let proxy_lines = |lines: &mut Vec<String>| {
+        if let Some((host, port)) = proxy {
+            lines.push(format!("proxy = {host}"));
+            lines.push(format!("proxy_port = {port}"));
+        }
+    };
+    for line in boto.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_boto_section = trimmed == "[Boto]";
+            lines.push(line.to_string());
+            if in_boto_section {
+                found_section = true;
+                proxy_lines(&mut lines);
+            }
+            continue;
+        }
+        if in_boto_section {
+            if let Some((k, _)) = trimmed.split_once('=') {
+                if k.trim() == "proxy" || k.trim() == "proxy_port" {
+                    continue;
+                }
+            }
+        }
+        lines.push(line.to_string());
+    }
+    if !found_section && proxy.is_some() {
+        if lines.last().map(|l| !l.trim().is_empty()).unwrap_or(false) {
+            lines.push(String::new());
+        }
+        lines.push("[Boto]".to_string());
+        proxy_lines(&mut lines);
+    }
+    lines.join("\n") + "\n"
+}
+
+/// http.proxy in the global git config
+pub fn git_proxy() -> Result<Option<String>> {
+    let output = run_bash_command("git config --global --get http.proxy", None)?;
+    let proxy = get_stdout(&output);
+    Ok((!proxy.is_empty()).then_some(proxy))
+}
+
+/// Check if all the proxy settings point to the same proxy.
+/// Returns the proxy in use (if any).
+pub fn check_proxy_consistency() -> Result<Option<String>> {
+    let boto = fs::read_to_string(boto_path()?).unwrap_or_default();
+    let settings = [
+        ("environment variables", ORIGINAL_ENV_PROXY.clone()),
+        ("boto config (gsutil)", read_boto_proxy(&boto)),
+        ("git config", git_proxy()?),
+        ("cro3 config", Config::read()?.http_proxy()),
+    ];
+    let configured: Vec<(&str, String)> = settings
+        .iter()
+        .flat_map(|(name, proxy)| proxy.as_ref().map(|p| (*name, normalize(p))))
+        .collect();
+    let first = if let Some(first) = configured.first() {
+        first
+    } else {
+        return Ok(None);
+    };
+    let missing: Vec<&str> = settings
+        .iter()
+        .filter(|(_, p)| p.is_none())
+        .map(|(name, _)| *name)
+        .collect();
+    let mismatched: Vec<String> = configured
+        .iter()
+        .filter(|(_, p)| p != &first.1)
+        .map(|(name, p)| format!("{name}: {p}"))
+        .collect();
+    if !mismatched.is_empty() || !missing.is_empty() {
+        bail!(
+            "Proxy settings are inconsistent ({}: {}, not configured in: {:?}, mismatched: {:?}). \
+             Run `cro3 setup proxy --url <PROXY_URL>` to configure them at once.",
+            first.0,
+            first.1,
+            missing,
+            mismatched
+        );
+    }
+    Ok(Some(first.1.clone()))
+}
+
+/// Export the proxy configured via `cro3 setup proxy` as environment variables
+/// so that the commands invoked by cro3 (e.g. repo, gsutil, curl) use it as
+/// well. Variables already set by the user are respected.
+pub fn apply_proxy_config() -> Result<()> {
+    lazy_static::initialize(&ORIGINAL_ENV_PROXY);
+    let config = Config::read()?;
+    if let Some(proxy) = config.http_proxy() {
+        if proxy_from_env().is_none() {
+            for k in PROXY_ENV_VARS {
+                env::set_var(k, &proxy);
+            }
+        }
+    }
+    if let Some(no_proxy) = config.no_proxy() {
+        if NO_PROXY_ENV_VARS.iter().all(|k| env::var(k).is_err()) {
+            for k in NO_PROXY_ENV_VARS {
+                env::set_var(k, &no_proxy);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn host_and_port() {
+        assert_eq!(
+            proxy_host_and_port("http://proxy.example:3128").unwrap(),
+            ("proxy.example".to_string(), 3128)
+        );
+        assert_eq!(
+            proxy_host_and_port("proxy.example:8080").unwrap(),
+            ("proxy.example".to_string(), 8080)
+        );
+        assert_eq!(
+            proxy_host_and_port("http://proxy.example").unwrap(),
+            ("proxy.example".to_string(), 80)
+        );
+    }
+    #[test]
+    fn boto_proxy() {
+        let boto = "[Credentials]\ngs_oauth2_refresh_token = x\n\n[Boto]\nproxy = old\nproxy_port \
+                    = 1\nhttps_validate_certificates = True\n";
+        assert_eq!(read_boto_proxy(boto), Some("old:1".to_string()));
+        let updated = update_boto_proxy(boto, Some(("proxy.example", 3128)));
+        assert_eq!(
+            updated,
+            "[Credentials]\ngs_oauth2_refresh_token = x\n\n[Boto]\nproxy = \
+             proxy.example\nproxy_port = 3128\nhttps_validate_certificates = True\n"
+        );
+        assert_eq!(
+            read_boto_proxy(&updated),
+            Some("proxy.example:3128".to_string())
+        );
+        let removed = update_boto_proxy(&updated, None);
+        assert_eq!(read_boto_proxy(&removed), None);
+        assert!(removed.contains("https_validate_certificates"));
+        let created = update_boto_proxy("[Credentials]\n", Some(("p", 80)));
+        assert_eq!(
+            created,
+            "[Credentials]\n\n[Boto]\nproxy = p\nproxy_port = 80\n"
+        );
+    }
+}
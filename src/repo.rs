@@ -240,7 +240,7 @@ fn ensure_is_cros_dir(path: &str) -> Result<()> {
     ))
 }
 
-fn find_cros_dir_from_cwd() -> Result<String> {
+pub fn find_cros_dir_from_cwd() -> Result<String> {
     let mut path = env::current_dir()?;
     let mut dir = path.to_string_lossy().to_string();
 
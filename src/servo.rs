@@ -30,9 +30,19 @@ use std::fmt;
 use std::fmt::Display;
 use std::fmt::Formatter;
 use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::stdin;
+use std::io::stdout;
+use std::io::Read;
+use std::io::Write;
 use std::iter::FromIterator;
 use std::os::unix::fs::FileTypeExt;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
 use std::time::Duration;
 
 use anyhow::anyhow;
@@ -40,6 +50,7 @@ use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
 use async_process::Child;
+use chrono::Local;
 use futures::executor::block_on;
 use futures::select;
 use futures::FutureExt;
@@ -47,6 +58,7 @@ use futures::StreamExt;
 use lazy_static::lazy_static;
 use macaddr::MacAddr6;
 use macaddr::MacAddr8;
+use nix::sys::termios;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 use regex::Regex;
@@ -54,13 +66,17 @@ use retry::delay;
 use retry::retry;
 use serde::Deserialize;
 use serde::Serialize;
+use termion::raw::IntoRawMode;
 use tracing::error;
 use tracing::info;
 use tracing::trace;
 use tracing::warn;
 
+use crate::cache::KvCache;
 use crate::chroot::Chroot;
 use crate::config::Config;
+use crate::dut::DUT_TAGS;
+use crate::util::cro3_paths::gen_path_in_cro3_dir;
 use crate::util::shell_helpers::get_async_lines;
 use crate::util::shell_helpers::get_stdout;
 use crate::util::shell_helpers::run_bash_command;
@@ -75,6 +91,12 @@ lazy_static! {
     static ref RE_GBB_FLAGS: Regex = Regex::new(r"^flags: 0x(?P<flags>[0-9a-fA-F]+)$").unwrap();
     static ref RE_USB_SYSFS_PATH_FUNC: Regex = Regex::new(r"\.[0-9]+$").unwrap();
 }
+
+/// servod instances started by `cro3 servo start` (key: servo serial)
+pub static SERVOD_INSTANCES: KvCache<ServodInstance> = KvCache::new("servod_instances");
+
+/// Ports to allocate for servod instances
+const SERVOD_PORTS: std::ops::RangeInclusive<u16> = 9901..=9999;
 #[cfg(test)]
 mod tests {
     use pretty_assertions::assert_eq;
@@ -113,9 +135,97 @@ mod tests {
             usb_sysfs_path: sysfs_path.to_string(),
             tty_list,
             slow_info: Some(slow_info),
+            ..Default::default()
         }
     }
     #[test]
+    fn duts_of_servos() {
+        let servo = create_mock_servo("SERVOV4P1-S-0000000000", "/sys/bus/usb/devices/1-2.3");
+        let cr50 = LocalServo {
+            product: "Cr50".to_string(),
+            serial: "0681D03A-92DCCD32".to_string(),
+            usb_sysfs_path: "/sys/bus/usb/devices/1-2.4".to_string(),
+            ..Default::default()
+        };
+        let other = create_mock_servo("SERVOV4P1-S-0000000001", "/sys/bus/usb/devices/1-4");
+        let mut devices = vec![servo, cr50, other];
+        let servo_to_dut =
+            BTreeMap::from([("SERVOV4P1-S-0000000000".to_string(), "dut1".to_string())]);
+        attach_duts(&mut devices, &servo_to_dut);
+        assert_eq!(devices[0].dut(), Some("dut1"));
+        assert_eq!(devices[1].dut(), Some("dut1"));
+        assert_eq!(devices[1].kind(), "SuzyQ (Cr50)");
+        assert_eq!(devices[2].dut(), None);
+    }
+    #[test]
+    fn console_log() {
+        let mut at_line_start = true;
+        assert_eq!(
+            timestamp_lines(b"> version\nChip: ", &mut at_line_start, "[t] "),
+            b"[t] > version\n[t] Chip: "
+        );
+        assert!(!at_line_start);
+        assert_eq!(
+            timestamp_lines(b"g3\n", &mut at_line_start, "[u] "),
+            b"g3\n"
+        );
+        assert!(at_line_start);
+        assert_eq!(uart_pty_controls("cr50").unwrap()[1], "gsc_uart_pty");
+        assert!(uart_pty_controls("usb").is_err());
+    }
+    #[test]
+    fn servod_processes() {
+        let ps = "  101 sudo servod -s SERVOV4P1-S-0000000000 -p 9901
+  102 /usr/bin/python3 /usr/bin/servod -s SERVOV4P1-S-0000000000 -p 9901
+  103 python3 -u /usr/lib/python3.6/site-packages/servo/servod.py --serialname C2D2-123
+  104 vim servod.rs
+  105 /usr/bin/cros_sdk --no-ns-pid -- sudo servod -s X -p 9902
+";
+        let processes = parse_servod_processes(ps);
+        assert_eq!(
+            processes,
+            vec![
+                ServodProcess {
+                    pid: 102,
+                    serial: "SERVOV4P1-S-0000000000".to_string(),
+                    port: Some(9901),
+                },
+                ServodProcess {
+                    pid: 103,
+                    serial: "C2D2-123".to_string(),
+                    port: None,
+                },
+            ]
+        );
+        let instance = |port| ServodInstance {
+            port,
+            repo: "/cros".to_string(),
+            log: PathBuf::from("/log"),
+            started_at: 0,
+        };
+        let instances = BTreeMap::from([
+            ("SERVOV4P1-S-0000000000".to_string(), instance(9901)),
+            ("SERVOV4P1-S-0000000001".to_string(), instance(9902)),
+        ]);
+        let states = servod_states(
+            &instances,
+            &processes,
+            &["SERVOV4P1-S-0000000000".to_string()],
+        );
+        let states: Vec<(&str, ServodState)> = states
+            .iter()
+            .map(|(s, _, _, state)| (s.as_str(), *state))
+            .collect();
+        assert_eq!(
+            states,
+            vec![
+                ("SERVOV4P1-S-0000000000", ServodState::Running),
+                ("SERVOV4P1-S-0000000001", ServodState::Crashed),
+                ("C2D2-123", ServodState::Orphaned),
+            ]
+        );
+    }
+    #[test]
     fn local_servo_info_in_json() {
         let servo = create_mock_servo("SERVOV4P1-S-0000000000", "/sys/bus/usb/devices/1-2.3");
         let serialized = format!("\n{servo}");
@@ -232,6 +342,38 @@ fn get_usb_sysfs_path_stem(path: &str) -> String {
     RE_USB_SYSFS_PATH_FUNC.replace(path, "").to_string()
 }
 
+/// Locates the servo connected to a DUT. It is taken from `servo` or the
+/// "servo" tag of the DUT, or the only servo with an AP console.
+pub fn find_servo_for_dut(id: &str, servo: &Option<String>) -> Result<LocalServo> {
+    let servos = ServoList::discover()?;
+    let mut tags = DUT_TAGS.get(id)?.unwrap_or_default();
+    if let Some(serial) = servo.as_ref().or(tags.get("servo")) {
+        return Ok(servos.find_by_serial(serial)?.clone());
+    }
+    let candidates: Vec<&LocalServo> = servos
+        .devices()
+        .iter()
+        .filter(|s| s.ap_console_tty_type().is_some())
+        .collect();
+    match candidates.as_slice() {
+        [] => bail!("No servo with an AP console found"),
+        [s] => {
+            tags.insert("servo".to_string(), s.serial().to_string());
+            DUT_TAGS.set(id, tags)?;
+            Ok((*s).clone())
+        }
+        _ => bail!(
+            "Multiple servos found ({}). Please specify --servo, or tag the DUT with `cro3 dut \
+             tag --dut {id} servo=<SERIAL>`",
+            candidates
+                .iter()
+                .map(|s| s.serial())
+                .collect::<Vec<&str>>()
+                .join(", ")
+        ),
+    }
+}
+
 pub fn get_servo_attached_to_cr50(cr50: &LocalServo) -> Result<LocalServo> {
     let usb_path = cr50.usb_sysfs_path();
     let common_path = get_usb_sysfs_path_stem(usb_path);
@@ -270,6 +412,7 @@ fn discover() -> Result<Vec<LocalServo>> {
             if product.starts_with("Servo")
                 || product.starts_with("Cr50")
                 || product.starts_with("Ti50")
+                || product.starts_with("C2D2")
             {
                 let paths = fs::read_dir(&usb_sysfs_path).context("failed to read dir")?;
                 let tty_list: BTreeMap<String, String> = paths
@@ -291,6 +434,9 @@ fn discover() -> Result<Vec<LocalServo>> {
                 Ok(LocalServo {
                     product,
                     serial,
+                    // The firmware of the devices reports its version as the
+                    // configuration string
+                    firmware_version: read_usb_attribute(&usb_sysfs_path, "configuration").ok(),
                     usb_sysfs_path: usb_sysfs_path.to_string_lossy().to_string(),
                     tty_list,
                     ..Default::default()
@@ -316,6 +462,170 @@ fn discover_slow() -> Result<Vec<LocalServo>> {
     Ok(servos)
 }
 
+/// Sets the DUT of each device from `servo_to_dut` (servo serial to DUT). A
+/// Cr50 behind a servo is attached to the DUT of the servo.
+fn attach_duts(devices: &mut [LocalServo], servo_to_dut: &BTreeMap<String, String>) {
+    let stems: BTreeMap<String, String> = devices
+        .iter()
+        .filter_map(|s| {
+            let dut = servo_to_dut.get(&s.serial)?;
+            Some((get_usb_sysfs_path_stem(&s.usb_sysfs_path), dut.clone()))
+        })
+        .collect();
+    for s in devices {
+        s.dut = servo_to_dut
+            .get(&s.serial)
+            .or_else(|| stems.get(&get_usb_sysfs_path_stem(&s.usb_sysfs_path)))
+            .cloned();
+    }
+}
+
+/// Returns the DUTs tagged with the serials of their servos (see
+/// find_servo_for_dut), keyed by the serials
+fn servo_to_dut_map() -> BTreeMap<String, String> {
+    DUT_TAGS
+        .entries()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(dut, tags)| Some((tags.get("servo")?.clone(), dut)))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServodInstance {
+    pub port: u16,
+    /// cros checkout whose chroot runs servod, to restart it
+    pub repo: String,
+    pub log: PathBuf,
+    /// Unix time of the start
+    pub started_at: i64,
+}
+
+/// A servod process running on this machine
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServodProcess {
+    pub pid: u32,
+    pub serial: String,
+    pub port: Option<u16>,
+}
+
+/// Parses the output of `ps -eo pid=,args=` into the servod processes, leaving
+/// out the sudo and cros_sdk processes that launched them
+pub fn parse_servod_processes(ps: &str) -> Vec<ServodProcess> {
+    ps.lines()
+        .filter_map(|line| {
+            let (pid, args) = line.trim().split_once(' ')?;
+            let args: Vec<&str> = args.split_whitespace().collect();
+            let servod = args
+                .iter()
+                .position(|a| a.ends_with("servod") || a.ends_with("servod.py"))?;
+            if args[..servod]
+                .iter()
+                .any(|a| !a.contains("python") && !a.starts_with('-'))
+            {
+                return None;
+            }
+            let value_of = |names: &[&str]| {
+                args.windows(2)
+                    .find(|w| names.contains(&w[0]))
+                    .map(|w| w[1].to_string())
+            };
+            Some(ServodProcess {
+                pid: pid.parse().ok()?,
+                serial: value_of(&["-s", "--serialname"])?,
+                port: value_of(&["-p", "--port"]).and_then(|p| p.parse().ok()),
+            })
+        })
+        .collect()
+}
+
+pub fn list_servod_processes() -> Result<Vec<ServodProcess>> {
+    let output = run_bash_command("ps -eo pid=,args=", None)?;
+    Ok(parse_servod_processes(&get_stdout(&output)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServodState {
+    Running,
+    /// Started by cro3, but not running any more
+    Crashed,
+    /// Not started by cro3, or running for a servo which is not connected
+    Orphaned,
+}
+impl Display for ServodState {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.pad(match self {
+            ServodState::Running => "running",
+            ServodState::Crashed => "crashed",
+            ServodState::Orphaned => "orphaned",
+        })
+    }
+}
+
+/// Returns the serial, the port, the PID and the state of each servod
+/// instance known from `instances` (started by cro3) or `processes`
+pub fn servod_states(
+    instances: &BTreeMap<String, ServodInstance>,
+    processes: &[ServodProcess],
+    connected: &[String],
+) -> Vec<(String, Option<u16>, Option<u32>, ServodState)> {
+    let mut states = Vec::new();
+    for (serial, instance) in instances {
+        match processes.iter().find(|p| &p.serial == serial) {
+            Some(p) => states.push((
+                serial.clone(),
+                p.port,
+                Some(p.pid),
+                if connected.contains(serial) {
+                    ServodState::Running
+                } else {
+                    ServodState::Orphaned
+                },
+            )),
+            None => states.push((
+                serial.clone(),
+                Some(instance.port),
+                None,
+                ServodState::Crashed,
+            )),
+        }
+    }
+    for p in processes {
+        if !instances.contains_key(&p.serial) {
+            states.push((p.serial.clone(), p.port, Some(p.pid), ServodState::Orphaned));
+        }
+    }
+    states
+}
+
+/// Returns a port for servod which is not used by `used` nor other processes,
+/// preferring `preferred`
+fn allocate_servod_port(preferred: Option<u16>, used: &[u16]) -> Result<u16> {
+    preferred
+        .into_iter()
+        .chain(SERVOD_PORTS)
+        .find(|p| !used.contains(p) && std::net::TcpListener::bind(("127.0.0.1", *p)).is_ok())
+        .context("No port is available for servod")
+}
+
+/// Stops the servod processes of `serial`, and forgets its instance
+pub fn stop_servod(serial: &str) -> Result<()> {
+    let pids: Vec<String> = list_servod_processes()?
+        .iter()
+        .filter(|p| p.serial == serial)
+        .map(|p| p.pid.to_string())
+        .collect();
+    if !pids.is_empty() {
+        info!("Stopping servod for {serial} (PID {})...", pids.join(" "));
+        run_bash_command(&format!("sudo kill {}", pids.join(" ")), None)?
+            .status
+            .exit_ok()
+            .context(anyhow!("Failed to stop servod for {serial}"))?;
+    }
+    SERVOD_INSTANCES.remove(serial)?;
+    Ok(())
+}
+
 pub fn reset_devices(serials: &Vec<String>) -> Result<()> {
     let servo_info = discover()?;
     let servo_info: Vec<LocalServo> = if !serials.is_empty() {
@@ -346,10 +656,14 @@ impl ServoList {
         Self { devices }
     }
     pub fn discover() -> Result<Self> {
-        Ok(Self::new(discover()?))
+        let mut devices = discover()?;
+        attach_duts(&mut devices, &servo_to_dut_map());
+        Ok(Self::new(devices))
     }
     pub fn discover_slow() -> Result<Self> {
-        Ok(Self::new(discover_slow()?))
+        let mut devices = discover_slow()?;
+        attach_duts(&mut devices, &servo_to_dut_map());
+        Ok(Self::new(devices))
     }
     pub fn find_by_serial(&self, serial: &str) -> Result<&LocalServo> {
         self.devices
@@ -382,6 +696,151 @@ pub struct SlowServoInfo {
 }
 impl SlowServoInfo {}
 
+/// Names of the tty interfaces that are connected to the AP (CPU) console of
+/// the DUT, in the order of preference
+const AP_CONSOLE_TTY_TYPES: [&str; 3] = ["AP", "CPU", "DUT UART"];
+
+/// Sends a BREAK to a tty opened with LocalServo::open_tty (e.g. followed by a
+/// key to trigger a magic SysRq on the DUT)
+pub fn send_break(tty: &File) -> Result<()> {
+    termios::tcsendbreak(tty, 0).context("Failed to send a break")
+}
+
+/// Opens a tty (or a pty of servod) in raw mode
+pub fn open_raw_tty(tty_path: &str) -> Result<File> {
+    let tty = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(tty_path)
+        .context(anyhow!("Failed to open {tty_path}"))?;
+    let mut attrs = termios::tcgetattr(&tty)?;
+    termios::cfmakeraw(&mut attrs);
+    termios::cfsetspeed(&mut attrs, termios::BaudRate::B115200)?;
+    termios::tcsetattr(&tty, termios::SetArg::TCSANOW, &attrs)?;
+    Ok(tty)
+}
+
+/// Returns the servod controls which have the path of the pty of a console
+/// ("ec", "cpu" or "cr50"), in the order to try. Newer servod calls the Cr50
+/// console gsc.
+pub fn uart_pty_controls(console: &str) -> Result<&'static [&'static str]> {
+    Ok(match console {
+        "ec" => &["ec_uart_pty"],
+        "cpu" | "ap" => &["cpu_uart_pty"],
+        "cr50" | "gsc" | "ti50" => &["cr50_uart_pty", "gsc_uart_pty"],
+        _ => bail!("Unknown console type: {console}. Please specify ec, cpu or cr50."),
+    })
+}
+
+/// Prefixes each line in `data` with `stamp`. `at_line_start` tells whether
+/// the previous data ended with a newline, and is updated for the next data.
+pub fn timestamp_lines(data: &[u8], at_line_start: &mut bool, stamp: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        if *at_line_start {
+            out.extend_from_slice(stamp.as_bytes());
+            *at_line_start = false;
+        }
+        out.push(b);
+        if b == b'\n' {
+            *at_line_start = true;
+        }
+    }
+    out
+}
+
+/// Ctrl-]
+pub const CONSOLE_ESCAPE: u8 = 0x1d;
+pub const CONSOLE_HELP: &str = "Ctrl-] then: q: quit, b: send break, s: reboot via SysRq (break + \
+                                b), r: reboot the DUT, Ctrl-]: send Ctrl-]";
+
+/// Returns the path of the log of the `console` (e.g. "cpu") of `dut`, e.g.
+/// ~/.cro3/logs/console_cpu_dut1_20231015_123456.log
+pub fn console_log_path(console: &str, dut: &str) -> Result<PathBuf> {
+    gen_path_in_cro3_dir(&format!(
+        "logs/console_{}_{}_{}.log",
+        console.to_lowercase().replace(' ', "_"),
+        dut.replace(['/', ':', '[', ']'], "_"),
+        Local::now().format("%Y%m%d_%H%M%S")
+    ))
+}
+
+/// Log of a console with a timestamp on each line
+pub struct ConsoleLog {
+    file: File,
+    at_line_start: bool,
+}
+impl ConsoleLog {
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self {
+            file: File::create(path).context(anyhow!("Failed to create {path:?}"))?,
+            at_line_start: true,
+        })
+    }
+    pub fn write(&mut self, data: &[u8]) -> Result<()> {
+        let stamp = Local::now().format("[%Y-%m-%d %H:%M:%S%.3f] ").to_string();
+        self.file
+            .write_all(&timestamp_lines(data, &mut self.at_line_start, &stamp))?;
+        Ok(())
+    }
+}
+
+/// Prints the output of the console `tty` and writes it to `log` in a thread
+pub fn spawn_console_reader(tty: &File, log: Arc<Mutex<ConsoleLog>>) -> Result<()> {
+    let mut reader = tty.try_clone()?;
+    thread::spawn(move || -> Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                return Ok(());
+            }
+            let mut out = stdout().lock();
+            out.write_all(&buf[..n])?;
+            out.flush()?;
+            log.lock().unwrap().write(&buf[..n])?;
+        }
+    });
+    Ok(())
+}
+
+/// Sends the keys typed on the terminal to the console `tty` until Ctrl-] q is
+/// typed. The other keys after Ctrl-] are listed in CONSOLE_HELP, and `reboot`
+/// is called for r.
+pub fn forward_keys_to_console(tty: &mut File, reboot: &dyn Fn() -> Result<()>) -> Result<()> {
+    let _raw = stdout().into_raw_mode()?;
+    let mut escaped = false;
+    for b in stdin().bytes() {
+        let b = b?;
+        if !escaped {
+            if b == CONSOLE_ESCAPE {
+                escaped = true;
+            } else {
+                tty.write_all(&[b])?;
+            }
+            continue;
+        }
+        escaped = false;
+        match b {
+            b'q' | b'.' => break,
+            b'b' => send_break(tty)?,
+            b's' => {
+                send_break(tty)?;
+                tty.write_all(b"b")?;
+            }
+            b'r' => {
+                if let Err(e) = reboot() {
+                    eprint!("\r\ncro3: Failed to reboot the DUT: {e}\r\n");
+                }
+            }
+            CONSOLE_ESCAPE => tty.write_all(&[CONSOLE_ESCAPE])?,
+            _ => eprint!("\r\ncro3: {CONSOLE_HELP}\r\n"),
+        }
+    }
+    eprint!("\r\n");
+    Ok(())
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize, Default)]
 pub struct LocalServo {
     product: String,
@@ -390,6 +849,13 @@ pub struct LocalServo {
     // Using BTreeMap here to keep the ordering when printing this structure
     tty_list: BTreeMap<String, String>,
     slow_info: Option<SlowServoInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    firmware_version: Option<String>,
+    /// DUT which the device is attached to, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    dut: Option<String>,
 }
 impl LocalServo {
     pub fn product(&self) -> &str {
@@ -398,6 +864,20 @@ impl LocalServo {
     pub fn serial(&self) -> &str {
         &self.serial
     }
+    pub fn firmware_version(&self) -> Option<&str> {
+        self.firmware_version.as_deref()
+    }
+    pub fn dut(&self) -> Option<&str> {
+        self.dut.as_deref()
+    }
+    /// Kind of the device, e.g. "SuzyQ (Cr50)" for the CCD of a DUT
+    pub fn kind(&self) -> String {
+        if self.is_cr50() {
+            format!("SuzyQ ({})", self.product)
+        } else {
+            self.product.clone()
+        }
+    }
     pub fn tty_list(&self) -> &BTreeMap<String, String> {
         &self.tty_list
     }
@@ -408,6 +888,16 @@ impl LocalServo {
             .context(anyhow!("tty[{}] not found", tty_type))?;
         Ok(path.clone())
     }
+    /// tty type of the AP console, if this device has one
+    pub fn ap_console_tty_type(&self) -> Option<&'static str> {
+        AP_CONSOLE_TTY_TYPES
+            .into_iter()
+            .find(|t| self.tty_list.contains_key(*t))
+    }
+    /// Opens a tty in raw mode, to be used as an interactive console
+    pub fn open_tty(&self, tty_type: &str) -> Result<File> {
+        open_raw_tty(&self.tty_path(tty_type)?)
+    }
     pub fn run_cmd(&self, tty_type: &str, cmd: &str) -> Result<String> {
         let tty_path = &self.tty_path(tty_type)?;
         // Check if socat is installed
@@ -512,6 +1002,66 @@ impl LocalServo {
         })?;
         ServodConnection::from_serial(&self.serial)
     }
+    /// Starts servod for this device in the background on a free port (the
+    /// port used before if possible), and records the instance
+    pub fn start_servod_daemon(&self, repo: &str) -> Result<ServodInstance> {
+        let chroot = Chroot::new(repo)?;
+        let used: Vec<u16> = SERVOD_INSTANCES
+            .entries()?
+            .into_iter()
+            .filter(|(serial, _)| serial != &self.serial)
+            .map(|(_, i)| i.port)
+            .chain(list_servod_processes()?.iter().filter_map(|p| p.port))
+            .collect();
+        let preferred = SERVOD_INSTANCES.get(&self.serial)?.map(|i| i.port);
+        let port = allocate_servod_port(preferred, &used)?;
+        let log = gen_path_in_cro3_dir(&format!("servod/{}.log", self.serial))?;
+        info!("Starting servod for {} on port {port}...", self.serial);
+        chroot.spawn_detached_in_chroot(
+            &[
+                "sudo",
+                "servod",
+                "-s",
+                &self.serial,
+                "-p",
+                &port.to_string(),
+            ],
+            &log,
+        )?;
+        let deadline = std::time::Instant::now() + Duration::from_secs(60);
+        loop {
+            let output = fs::read_to_string(&log).unwrap_or_default();
+            if output.contains("Listening on localhost port") {
+                break;
+            }
+            let running = list_servod_processes()?
+                .iter()
+                .any(|p| p.serial == self.serial);
+            if std::time::Instant::now() > deadline || (!running && output.contains("Traceback")) {
+                let lines: Vec<&str> = output.lines().collect();
+                bail!(
+                    "servod for {} did not start. See {}:\n{}",
+                    self.serial,
+                    log.display(),
+                    lines[lines.len().saturating_sub(10)..].join("\n")
+                );
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+        let instance = ServodInstance {
+            port,
+            repo: repo.to_string(),
+            log,
+            started_at: chrono::Local::now().timestamp(),
+        };
+        SERVOD_INSTANCES.set(&self.serial, instance.clone())?;
+        Ok(instance)
+    }
+    /// Whether servod can be started for this device (Servo v4, Servo Micro,
+    /// C2D2...). The CCD of a DUT is used through the servo in front of it.
+    pub fn runs_servod(&self) -> bool {
+        self.is_servo() || self.product().starts_with("C2D2")
+    }
     pub fn is_cr50(&self) -> bool {
         self.product() == "Cr50" || self.product() == "Ti50"
     }
@@ -690,4 +1240,39 @@ impl ServodConnection {
         )?;
         Ok(output)
     }
+    /// Returns the value of a servo control
+    pub fn get_control(&self, chroot: &Chroot, name: &str) -> Result<String> {
+        let output = self.run_dut_control(chroot, &[name])?;
+        Ok(output
+            .trim()
+            .strip_prefix(&format!("{name}:"))
+            .context(anyhow!("Unexpected output of dut-control: {output}"))?
+            .to_string())
+    }
+    /// Writes an AP firmware image (a path in the chroot) via the servo
+    pub fn flash_ap_firmware(&self, chroot: &Chroot, image: &str) -> Result<()> {
+        info!("Writing {image} to the AP firmware via servo...");
+        chroot.exec_in_chroot(&[
+            "sudo",
+            "futility",
+            "update",
+            "--servo",
+            &format!("--servo_port={}", self.port),
+            "--force",
+            "-i",
+            image,
+        ])?;
+        Ok(())
+    }
+    /// Writes an EC firmware image (a path in the chroot) via the servo
+    pub fn flash_ec_firmware(&self, chroot: &Chroot, board: &str, image: &str) -> Result<()> {
+        info!("Writing {image} to the EC via servo...");
+        chroot.exec_in_chroot(&[
+            "flash_ec",
+            &format!("--board={board}"),
+            &format!("--port={}", self.port),
+            &format!("--image={image}"),
+        ])?;
+        Ok(())
+    }
 }
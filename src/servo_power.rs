@@ -0,0 +1,224 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Power measurement of a DUT with the power rails (INAs on the board or a
+//! Sweetberry) read through servod, and the statistics of the samples.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::test_results::percentile;
+
+/// Parses an interval like "1s", "500ms", "2m" or "1.5" (seconds)
+pub fn parse_interval(s: &str) -> Result<Duration> {
+    let s = s.trim();
+    let (value, scale) = if let Some(v) = s.strip_suffix("ms") {
+        (v, 0.001)
+    } else if let Some(v) = s.strip_suffix('s') {
+        (v, 1.0)
+    } else if let Some(v) = s.strip_suffix('m') {
+        (v, 60.0)
+    } else {
+        (s, 1.0)
+    };
+    let value: f64 = value
+        .parse()
+        .context(format!("Invalid interval: {s} (e.g. 1s, 500ms)"))?;
+    if value <= 0.0 {
+        bail!("Invalid interval: {s}");
+    }
+    Ok(Duration::from_secs_f64(value * scale))
+}
+
+/// Picks the controls of the power rails (in mW) from the output of
+/// dut-control listing all the controls
+pub fn power_rails(controls: &str) -> Vec<String> {
+    controls
+        .lines()
+        .filter_map(|l| l.split_once(':'))
+        .map(|(name, _)| name.trim())
+        .filter(|name| name.ends_with("_mw"))
+        .map(str::to_string)
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PowerSample {
+    /// Seconds since the first sample
+    pub time: f64,
+    /// mW of each rail
+    pub values: BTreeMap<String, f64>,
+}
+
+/// Parses the output of the sampling loop, which has a "t:UNIX_TIME" line
+/// followed by "RAIL:VALUE" lines of dut-control for each sample
+pub fn parse_power_log(log: &str) -> Vec<PowerSample> {
+    let mut samples: Vec<PowerSample> = Vec::new();
+    let mut start = None;
+    for line in log.lines() {
+        let Some((name, value)) = line.trim().split_once(':') else {
+            continue;
+        };
+        let Ok(value) = value.trim().parse::<f64>() else {
+            continue;
+        };
+        if name == "t" {
+            let start = *start.get_or_insert(value);
+            samples.push(PowerSample {
+                time: value - start,
+                values: BTreeMap::new(),
+            });
+        } else if let Some(sample) = samples.last_mut() {
+            sample.values.insert(name.to_string(), value);
+        }
+    }
+    samples.retain(|s| !s.values.is_empty());
+    samples
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RailStats {
+    pub rail: String,
+    pub samples: usize,
+    /// mW
+    pub mean: f64,
+    pub p95: f64,
+    pub min: f64,
+    pub max: f64,
+    /// Joules over the samples, integrated with the trapezoidal rule
+    pub energy: f64,
+}
+
+/// Computes the statistics of each rail in `rails` over the samples
+pub fn power_stats(samples: &[PowerSample], rails: &[String]) -> Vec<RailStats> {
+    rails
+        .iter()
+        .map(|rail| {
+            let points: Vec<(f64, f64)> = samples
+                .iter()
+                .filter_map(|s| Some((s.time, *s.values.get(rail)?)))
+                .collect();
+            let values: Vec<f64> = points.iter().map(|(_, v)| *v).collect();
+            let energy_mj: f64 = points
+                .windows(2)
+                .map(|w| (w[1].0 - w[0].0) * (w[0].1 + w[1].1) / 2.0)
+                .sum();
+            RailStats {
+                rail: rail.clone(),
+                samples: values.len(),
+                mean: values.iter().sum::<f64>() / values.len().max(1) as f64,
+                p95: percentile(&values, 95.0),
+                min: values.iter().copied().reduce(f64::min).unwrap_or_default(),
+                max: values.iter().copied().reduce(f64::max).unwrap_or_default(),
+                energy: energy_mj / 1000.0,
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PowerReport {
+    pub dut: String,
+    pub servo: String,
+    /// Seconds between the samples
+    pub interval: f64,
+    pub stats: Vec<RailStats>,
+    pub samples: Vec<PowerSample>,
+}
+
+/// Script to run in the chroot, which prints the values of `rails` read with
+/// dut-control from servod on `port` every `interval` for `duration`
+pub fn sampling_script(port: u16, rails: &[String], duration: u64, interval: Duration) -> String {
+    format!(
+        r#"end=$((SECONDS + {duration}))
+while [[ $SECONDS -lt $end ]]; do
+  echo "t:$(date +%s.%N)"
+  dut-control -p {port} {rails} || true
+  sleep {interval}
+done
+"#,
+        rails = rails.join(" "),
+        interval = interval.as_secs_f64()
+    )
+}
+
+/// Formats the statistics as a table
+pub fn format_power_table(stats: &[RailStats]) -> String {
+    let mut table = format!(
+        "{:<24} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+        "RAIL", "MEAN_MW", "P95_MW", "MIN_MW", "MAX_MW", "ENERGY_J"
+    );
+    for s in stats {
+        table += &format!(
+            "{:<24} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>10.2}\n",
+            s.rail, s.mean, s.p95, s.min, s.max, s.energy
+        );
+    }
+    table
+}
+
+/// Formats the samples as CSV with a column for each rail
+pub fn to_csv(samples: &[PowerSample], rails: &[String]) -> String {
+    let mut csv = format!("time_s,{}\n", rails.join(","));
+    for s in samples {
+        let values: Vec<String> = rails
+            .iter()
+            .map(|r| s.values.get(r).map(|v| v.to_string()).unwrap_or_default())
+            .collect();
+        csv += &format!("{:.3},{}\n", s.time, values.join(","));
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn power() {
+        assert_eq!(parse_interval("1s").unwrap(), Duration::from_secs(1));
+        assert_eq!(parse_interval("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_interval("2").unwrap(), Duration::from_secs(2));
+        assert!(parse_interval("fast").is_err());
+
+        let controls = "ppvar_vbat_mw:1200.5\nppdut5_mw:2500.0\nec_uart_pty:/dev/pts/3\n";
+        let rails = power_rails(controls);
+        assert_eq!(rails, vec!["ppvar_vbat_mw", "ppdut5_mw"]);
+
+        let log = "t:100.0
+ppvar_vbat_mw:1000.0
+ppdut5_mw:2000.0
+t:101.0
+ppvar_vbat_mw:3000.0
+ppdut5_mw:2000.0
+t:102.0
+Failed to connect to servod
+";
+        let samples = parse_power_log(log);
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[1].time, 1.0);
+        let stats = power_stats(&samples, &rails);
+        assert_eq!(stats[0].mean, 2000.0);
+        assert_eq!(stats[0].p95, 3000.0);
+        assert_eq!(stats[0].max, 3000.0);
+        assert_eq!(stats[0].energy, 2.0);
+        assert_eq!(stats[1].min, 2000.0);
+        assert!(format_power_table(&stats)
+            .lines()
+            .nth(1)
+            .unwrap()
+            .starts_with("ppvar_vbat_mw                2000.0     3000.0"));
+        assert_eq!(
+            to_csv(&samples, &rails),
+            "time_s,ppvar_vbat_mw,ppdut5_mw\n0.000,1000,2000\n1.000,3000,2000\n"
+        );
+    }
+}
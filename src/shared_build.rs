@@ -0,0 +1,226 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Sharing local builds through Google Storage. `cro3 build upload` puts an
+//! image and binary packages built locally under a GS path with
+//! metadata.json, and `cro3 flash --from` downloads the image on another
+//! machine.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+
+use crate::build::recorded_local_image;
+use crate::build::recorded_package_overrides;
+use crate::build::BuildImageType;
+use crate::build::BuildOverrides;
+use crate::chroot::Chroot;
+use crate::google_storage::download_gs_file;
+use crate::google_storage::upload_gs_file;
+use crate::image_cache::cached_image_dir;
+use crate::image_cache::ImageKind;
+use crate::repo::get_current_synced_cros_version;
+use crate::util::cro3_paths::gen_path_in_cro3_dir;
+use crate::util::shell_helpers::get_stdout;
+use crate::util::shell_helpers::run_bash_command;
+
+/// Contents of metadata.json of a shared build. It is uploaded last, so a
+/// build without it is incomplete.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SharedBuild {
+    pub board: String,
+    /// Version of the checkout (e.g. R120-15662.0.0)
+    pub version: String,
+    /// user@hostname of the uploader
+    pub builder: String,
+    /// Unix time of the upload
+    pub uploaded_at: i64,
+    pub image_type: Option<String>,
+    /// File name of the image, if any
+    pub image: Option<String>,
+    /// Paths of the binary packages (e.g. chromeos-base/foo-0.0.1-r1.tbz2)
+    pub packages: Vec<String>,
+    /// HEAD of each project in the checkout, keyed by its path
+    pub git_hashes: BTreeMap<String, String>,
+    /// Packages built with overrides of USE flags or FEATURES
+    #[serde(default)]
+    pub package_overrides: BTreeMap<String, BuildOverrides>,
+}
+
+/// Parses the lines of "PATH HASH" into a map
+pub fn parse_git_hashes(output: &str) -> BTreeMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().split_once(' '))
+        .filter(|(_, hash)| hash.len() == 40 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(|(path, hash)| (path.to_string(), hash.to_string()))
+        .collect()
+}
+
+fn git_hashes(repo: &str) -> Result<BTreeMap<String, String>> {
+    let output = run_bash_command(
+        "repo forall -c 'echo \"$REPO_PATH $(git rev-parse HEAD)\"'",
+        Some(repo),
+    )?;
+    output
+        .status
+        .exit_ok()
+        .context("Failed to get the git hashes of the checkout")?;
+    Ok(parse_git_hashes(&get_stdout(&output)))
+}
+
+/// Copies the latest binary packages of `packages` for `board` into
+/// ~/.cro3/tmp/share_BOARD, and returns their paths relative to it
+fn stage_binary_packages(chroot: &Chroot, board: &str, packages: &[String]) -> Result<Vec<String>> {
+    let script = format!(
+        "set -e
+dst=/cro3/tmp/share_{board}/packages
+rm -rf $dst && mkdir -p $dst
+cd /build/{board}/packages
+for p in {}; do
+  f=$(ls -t $p-[0-9]*.tbz2 2>/dev/null | head -n 1)
+  if [ -z \"$f\" ]; then echo \"No binary package of $p. Please build it first.\" >&2; exit 1; fi
+  mkdir -p $dst/$(dirname $f) && cp $f $dst/$f
+  echo $f
+done",
+        packages.join(" ")
+    );
+    let output = chroot.exec_in_chroot(&["bash", "-c", &script])?;
+    Ok(output.lines().map(|l| l.trim().to_string()).collect())
+}
+
+/// Uploads the image of `image_type` built last for `board` and the binary
+/// packages of `packages` to `url` (e.g. gs://bucket/path)
+pub fn upload_build(
+    chroot: &Chroot,
+    repo: &str,
+    board: &str,
+    url: &str,
+    image_type: Option<BuildImageType>,
+    packages: &[String],
+) -> Result<SharedBuild> {
+    let url = url.trim_end_matches('/');
+    if !url.starts_with("gs://") {
+        bail!("Please specify a gs:// URL to upload the build to");
+    }
+    let image = match image_type {
+        Some(image_type) => Some(recorded_local_image(board, image_type)?.context(anyhow!(
+            "No {} image of {board} is found. Please run `cro3 build image` first.",
+            image_type.as_str()
+        ))?),
+        None => None,
+    };
+    // The overrides of the packages are the ones as of the image if any
+    let package_overrides = match &image {
+        Some(image) => image.package_overrides.clone(),
+        None => recorded_package_overrides(board)?
+            .into_iter()
+            .filter(|(package, _)| packages.contains(package))
+            .collect(),
+    };
+    let image = image.map(|image| image.path);
+    let staged = if packages.is_empty() {
+        Vec::new()
+    } else {
+        stage_binary_packages(chroot, board, packages)?
+    };
+    let mut staging = gen_path_in_cro3_dir(&format!("tmp/share_{board}/.keep"))?;
+    staging.pop();
+    fs::create_dir_all(&staging)?;
+
+    if let Some(image) = &image {
+        let name = image.file_name().context("No file name")?.to_string_lossy();
+        info!("Uploading {image:?}...");
+        upload_gs_file(image, &format!("{url}/{name}"))?;
+    }
+    for package in &staged {
+        info!("Uploading {package}...");
+        upload_gs_file(
+            &staging.join("packages").join(package),
+            &format!("{url}/packages/{package}"),
+        )?;
+    }
+    let host = whoami::fallible::hostname().unwrap_or("unknown".to_string());
+    let build = SharedBuild {
+        board: board.to_string(),
+        version: get_current_synced_cros_version(repo)?,
+        builder: format!("{}@{host}", whoami::username()),
+        uploaded_at: chrono::Local::now().timestamp(),
+        image_type: image_type.map(|t| t.as_str().to_string()),
+        image: image
+            .as_ref()
+            .and_then(|i| i.file_name())
+            .map(|n| n.to_string_lossy().to_string()),
+        packages: staged,
+        git_hashes: git_hashes(repo)?,
+        package_overrides,
+    };
+    let metadata = staging.join("metadata.json");
+    fs::write(&metadata, serde_json::to_string_pretty(&build)?)?;
+    upload_gs_file(&metadata, &format!("{url}/metadata.json"))?;
+    fs::remove_dir_all(&staging)?;
+    Ok(build)
+}
+
+/// Returns the metadata of the build shared at `url`
+pub fn shared_build_metadata(url: &str) -> Result<SharedBuild> {
+    let url = url.trim_end_matches('/');
+    let path = gen_path_in_cro3_dir("tmp/shared_build_metadata.json")?;
+    download_gs_file(&format!("{url}/metadata.json"), &path).context(anyhow!(
+        "No build is shared at {url}. Was `cro3 build upload` completed?"
+    ))?;
+    serde_json::from_str(&fs::read_to_string(&path)?).context("Invalid metadata.json")
+}
+
+/// Downloads the image of the build shared at `url`, or reuses the one
+/// downloaded before
+pub fn download_shared_image(url: &str, build: &SharedBuild) -> Result<PathBuf> {
+    let url = url.trim_end_matches('/');
+    let name = build
+        .image
+        .as_ref()
+        .context(anyhow!("The build shared at {url} has no image"))?;
+    let dir = cached_image_dir(
+        &build.board,
+        &format!("{}-{}", build.version, build.uploaded_at),
+        ImageKind::Shared,
+    )?;
+    let image = dir.join(name);
+    if image.exists() {
+        info!("Using the cached {image:?}");
+        return Ok(image);
+    }
+    info!("Downloading {url}/{name}...");
+    download_gs_file(&format!("{url}/{name}"), &image)?;
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn git_hashes_of_checkout() {
+        let output = "chromite 0123456789abcdef0123456789abcdef01234567
+src/platform2 fedcba9876543210fedcba9876543210fedcba98
+error: something failed
+src/third_party/kernel/v5.15 not-a-hash
+";
+        let hashes = parse_git_hashes(output);
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(
+            hashes.get("src/platform2").map(String::as_str),
+            Some("fedcba9876543210fedcba9876543210fedcba98")
+        );
+    }
+}
@@ -0,0 +1,442 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Results of Tast runs, read from the results directory that `tast run`
+//! writes, and local test bundles built from the checkout and pushed to DUTs.
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use glob::Pattern;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+
+use crate::cache::KvCache;
+use crate::chroot::Chroot;
+use crate::dut::SshInfo;
+use crate::test_results::TestResult;
+use crate::test_results::TestStatus;
+use crate::util::cro3_paths::gen_path_in_cro3_dir;
+
+/// Local test bundles installed in test images
+const LOCAL_BUNDLE_DIR: &str = "/usr/local/libexec/tast/bundles/local";
+
+/// Local bundles built from the checkout and pushed to each DUT (key: DUT ID)
+pub static TAST_PUSHED_BUNDLES: KvCache<Vec<String>> = KvCache::new("tast_pushed_bundles");
+
+/// Parses results.json of a Tast run, which has an entry per test with its
+/// errors and the reason if it was skipped. The logs of the tests are in
+/// `results_dir`.
+pub fn parse_tast_results(json: &str, results_dir: &Path) -> Result<Vec<TestResult>> {
+    let results: Vec<serde_json::Value> =
+        serde_json::from_str(json).context("Failed to parse the results of Tast")?;
+    Ok(results
+        .iter()
+        .map(|result| {
+            let name = result["name"].as_str().unwrap_or_default().to_string();
+            let error = result["errors"]
+                .as_array()
+                .and_then(|errors| errors.first())
+                .map(|e| e["reason"].as_str().unwrap_or_default().to_string());
+            let skip_reason = result["skipReason"]
+                .as_str()
+                .filter(|reason| !reason.is_empty());
+            let status = if error.is_some() {
+                TestStatus::Failed
+            } else if skip_reason.is_some() {
+                TestStatus::Skipped
+            } else {
+                TestStatus::Passed
+            };
+            let time = |key: &str| {
+                result[key]
+                    .as_str()
+                    .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+            };
+            let duration = match (time("start"), time("end")) {
+                (Some(start), Some(end)) => {
+                    ((end - start).num_milliseconds() as f64 / 1000.0).max(0.0)
+                }
+                _ => 0.0,
+            };
+            TestResult {
+                log: Some(results_dir.join("tests").join(&name).join("log.txt")),
+                name,
+                status,
+                duration,
+                error: error.or(skip_reason.map(str::to_string)),
+                attempts: 1,
+            }
+        })
+        .collect())
+}
+
+/// Reads the results of a Tast run from `results_dir` on this machine
+pub fn read_tast_results(results_dir: &Path) -> Result<Vec<TestResult>> {
+    let path = results_dir.join("results.json");
+    parse_tast_results(
+        &fs::read_to_string(&path).context(anyhow!("Failed to read {path:?}"))?,
+        results_dir,
+    )
+}
+
+/// Returns the path of `tast` if it is installed on this machine, to run
+/// prebuilt tests without a checkout
+pub fn host_tast() -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join("tast"))
+        .find(|path| path.is_file())
+}
+
+/// A test listed by `tast list -json`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TastTestInfo {
+    pub name: String,
+    #[serde(default)]
+    pub desc: String,
+    #[serde(default)]
+    pub attr: Vec<String>,
+    /// Bundle that has the test
+    #[serde(default)]
+    pub bundle: String,
+}
+
+/// Parses the output of `tast list -json` for the tests in `bundle`
+pub fn parse_tast_list(json: &str, bundle: &str) -> Result<Vec<TastTestInfo>> {
+    let mut tests: Vec<TastTestInfo> =
+        serde_json::from_str(json).context("Failed to parse the list of Tast tests")?;
+    for t in &mut tests {
+        t.bundle = bundle.to_string();
+    }
+    Ok(tests)
+}
+
+fn tokenize_attr_expr(expr: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' | ')' | '!' => tokens.push(c.to_string()),
+            '&' | '|' => {
+                if chars.next() != Some(c) {
+                    bail!("Unexpected '{c}' in {expr:?}");
+                }
+                tokens.push(format!("{c}{c}"));
+            }
+            '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => s.push(c),
+                        None => bail!("Unterminated quote in {expr:?}"),
+                    }
+                }
+                tokens.push(format!("\"{s}"));
+            }
+            c => {
+                let mut s = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "()!&|\"".contains(c) {
+                        break;
+                    }
+                    s.push(c);
+                    chars.next();
+                }
+                tokens.push(s);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct AttrExprParser<'a> {
+    tokens: &'a [String],
+    pos: usize,
+    attrs: &'a [String],
+}
+impl AttrExprParser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(String::as_str)
+    }
+    fn or(&mut self) -> Result<bool> {
+        let mut v = self.and()?;
+        while self.peek() == Some("||") {
+            self.pos += 1;
+            v |= self.and()?;
+        }
+        Ok(v)
+    }
+    fn and(&mut self) -> Result<bool> {
+        let mut v = self.unary()?;
+        while self.peek() == Some("&&") {
+            self.pos += 1;
+            v &= self.unary()?;
+        }
+        Ok(v)
+    }
+    fn unary(&mut self) -> Result<bool> {
+        let tokens = self.tokens;
+        let token = tokens
+            .get(self.pos)
+            .context("Unexpected end of the expression")?
+            .as_str();
+        self.pos += 1;
+        match token {
+            "!" => Ok(!self.unary()?),
+            "(" => {
+                let v = self.or()?;
+                if self.peek() != Some(")") {
+                    bail!("Missing ')'");
+                }
+                self.pos += 1;
+                Ok(v)
+            }
+            ")" | "&&" | "||" => bail!("Unexpected '{token}'"),
+            atom => {
+                let atom = atom.strip_prefix('"').unwrap_or(atom);
+                let pattern = Pattern::new(atom)?;
+                Ok(self.attrs.iter().any(|a| pattern.matches(a)))
+            }
+        }
+    }
+}
+
+/// Evaluates an attribute expression of Tast (e.g. "group:mainline &&
+/// !informational") for a test with `attrs`. Attribute names can have globs.
+pub fn attr_expr_matches(expr: &str, attrs: &[String]) -> Result<bool> {
+    let tokens = tokenize_attr_expr(expr)?;
+    let mut parser = AttrExprParser {
+        tokens: &tokens,
+        pos: 0,
+        attrs,
+    };
+    let v = parser
+        .or()
+        .context(anyhow!("Invalid attribute expression: {expr}"))?;
+    if parser.pos != tokens.len() {
+        bail!("Invalid attribute expression: {expr}");
+    }
+    Ok(v)
+}
+
+/// Splits `tests` into at most `n` shards of similar sizes
+pub fn shard_tests(tests: &[String], n: usize) -> Vec<Vec<String>> {
+    let mut shards = vec![Vec::new(); n.clamp(1, tests.len().max(1))];
+    let count = shards.len();
+    for (i, t) in tests.iter().enumerate() {
+        shards[i % count].push(t.clone());
+    }
+    shards
+}
+
+/// Returns the Go package of the local test bundle, e.g.
+/// go.chromium.org/tast-tests/cros/local/bundles/cros
+pub fn local_bundle_package(bundle: &str) -> String {
+    if bundle == "cros" {
+        "go.chromium.org/tast-tests/cros/local/bundles/cros".to_string()
+    } else {
+        format!("go.chromium.org/tast-tests-private/{bundle}/local/bundles/{bundle}")
+    }
+}
+
+/// Returns the Go architecture (GOARCH) of an ELF executable from the first
+/// 20 bytes of it, e.g. "arm64"
+pub fn elf_goarch(header: &[u8]) -> Option<&'static str> {
+    if header.len() < 20 || !header.starts_with(b"\x7fELF") {
+        return None;
+    }
+    match u16::from_le_bytes([header[18], header[19]]) {
+        0x03 => Some("386"),
+        0x28 => Some("arm"),
+        0x3e => Some("amd64"),
+        0xb7 => Some("arm64"),
+        _ => None,
+    }
+}
+
+/// Returns the Go architecture of the userland of the DUT, which can be 32-bit
+/// on ARM even with a 64-bit kernel
+pub fn dut_goarch(ssh: &SshInfo) -> Result<&'static str> {
+    let header: Vec<u8> = ssh
+        .run_cmd_stdio("head -c 20 /bin/sh | od -An -tx1 -v")?
+        .split_whitespace()
+        .filter_map(|b| u8::from_str_radix(b, 16).ok())
+        .collect();
+    elf_goarch(&header).context("Failed to get the architecture of the DUT")
+}
+
+/// Builds the local test bundle from the checkout with fast_build.sh, which is
+/// much faster than emerging the bundle package, and returns the path of the
+/// executable. It is built for `goarch` (e.g. of dut_goarch()), or for this
+/// machine if None.
+pub fn build_local_bundle(chroot: &Chroot, bundle: &str, goarch: Option<&str>) -> Result<PathBuf> {
+    let goarch = goarch.unwrap_or("host");
+    let env = match goarch {
+        "host" => String::new(),
+        "arm" => "GOARCH=arm GOARM=7 ".to_string(),
+        _ => format!("GOARCH={goarch} "),
+    };
+    chroot.run_bash_script_in_chroot(
+        "tast_build_bundle",
+        &format!(
+            r###"
+TOPDIR=~/trunk
+[ -d $TOPDIR ] || TOPDIR=~/chromiumos
+mkdir -p /cro3/tmp/tast_bundles/{goarch}
+{env}$TOPDIR/src/platform/tast/fast_build.sh -b {} -o /cro3/tmp/tast_bundles/{goarch}/{bundle}
+"###,
+            local_bundle_package(bundle)
+        ),
+        None,
+    )?;
+    gen_path_in_cro3_dir(&format!("tmp/tast_bundles/{goarch}/{bundle}"))
+}
+
+/// Replaces the local test bundle on the DUT with `path`. The bundle of the
+/// image is kept to restore it later.
+pub fn push_local_bundle(ssh: &SshInfo, dut: &str, bundle: &str, path: &Path) -> Result<()> {
+    let mut header = Vec::new();
+    fs::File::open(path)
+        .context(anyhow!("Failed to open {path:?}"))?
+        .take(20)
+        .read_to_end(&mut header)?;
+    let arch = elf_goarch(&header).context(anyhow!("{path:?} is not a known executable"))?;
+    let dut_arch = dut_goarch(ssh)?;
+    if arch != dut_arch {
+        bail!("The {bundle} bundle is built for {arch}, but {dut} is {dut_arch}");
+    }
+    let installed = format!("{LOCAL_BUNDLE_DIR}/{bundle}");
+    ssh.run_cmd_stdio(&format!(
+        "[ -e {installed}.orig ] || [ ! -e {installed} ] || cp -p {installed} {installed}.orig"
+    ))?;
+    ssh.send_files(
+        &[path.to_string_lossy().to_string()],
+        Some(&LOCAL_BUNDLE_DIR.to_string()),
+    )?;
+    ssh.run_cmd_stdio(&format!("chmod 755 {installed}"))?;
+    let mut pushed = TAST_PUSHED_BUNDLES.get(dut)?.unwrap_or_default();
+    if !pushed.iter().any(|b| b == bundle) {
+        pushed.push(bundle.to_string());
+    }
+    TAST_PUSHED_BUNDLES.set(dut, pushed)?;
+    info!("Pushed the {bundle} bundle to {dut}");
+    Ok(())
+}
+
+/// Puts back the local test bundles of the image on the DUT
+pub fn restore_local_bundles(ssh: &SshInfo, dut: &str) -> Result<()> {
+    for bundle in TAST_PUSHED_BUNDLES.get(dut)?.unwrap_or_default() {
+        let installed = format!("{LOCAL_BUNDLE_DIR}/{bundle}");
+        ssh.run_cmd_stdio(&format!(
+            "[ ! -e {installed}.orig ] || mv {installed}.orig {installed}"
+        ))?;
+        info!("Restored the {bundle} bundle of the image on {dut}");
+    }
+    TAST_PUSHED_BUNDLES.remove(dut)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn elf_architectures() {
+        let mut header = b"\x7fELF\x02\x01\x01".to_vec();
+        header.resize(20, 0);
+        header[18] = 0xb7;
+        assert_eq!(elf_goarch(&header), Some("arm64"));
+        header[18] = 0x28;
+        assert_eq!(elf_goarch(&header), Some("arm"));
+        header[18] = 0x3e;
+        assert_eq!(elf_goarch(&header), Some("amd64"));
+        assert_eq!(elf_goarch(&header[..19]), None);
+        assert_eq!(elf_goarch(b"#!/bin/sh\necho not an ELF"), None);
+    }
+    #[test]
+    fn tast_results() {
+        let json = r#"[
+  {"name": "example.Pass", "errors": null, "skipReason": "",
+   "start": "2023-10-15T10:00:00.5Z", "end": "2023-10-15T10:00:03Z"},
+  {"name": "example.Fail", "errors": [{"reason": "boom"}], "skipReason": ""},
+  {"name": "example.Skip", "errors": null, "skipReason": "missing SoftwareDeps: arc"}
+]"#;
+        let results = parse_tast_results(json, Path::new("/r")).unwrap();
+        let statuses: Vec<(&str, TestStatus)> = results
+            .iter()
+            .map(|r| (r.name.as_str(), r.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("example.Pass", TestStatus::Passed),
+                ("example.Fail", TestStatus::Failed),
+                ("example.Skip", TestStatus::Skipped)
+            ]
+        );
+        assert_eq!(results[0].duration, 2.5);
+        assert_eq!(
+            results[0].log,
+            Some(PathBuf::from("/r/tests/example.Pass/log.txt"))
+        );
+        assert_eq!(results[1].error.as_deref(), Some("boom"));
+        assert_eq!(
+            results[2].error.as_deref(),
+            Some("missing SoftwareDeps: arc")
+        );
+        assert!(parse_tast_results("not json", Path::new("/r")).is_err());
+    }
+    #[test]
+    fn test_list() {
+        let json = r#"[
+  {"name": "example.Pass", "pkg": "x", "desc": "Always passes",
+   "attr": ["group:mainline", "informational"], "softwareDeps": []},
+  {"name": "example.Fail", "attr": ["group:mainline"]}
+]"#;
+        let tests = parse_tast_list(json, "cros").unwrap();
+        assert_eq!(tests[0].desc, "Always passes");
+        assert_eq!(tests[1].bundle, "cros");
+        let expr = "group:mainline && !informational";
+        assert!(!attr_expr_matches(expr, &tests[0].attr).unwrap());
+        assert!(attr_expr_matches(expr, &tests[1].attr).unwrap());
+        assert!(
+            attr_expr_matches("group:* && (informational || \"name:x\")", &tests[0].attr).unwrap()
+        );
+        assert!(!attr_expr_matches("!group:mainline || foo", &tests[1].attr).unwrap());
+        assert!(attr_expr_matches("group:mainline &&", &tests[1].attr).is_err());
+        assert!(attr_expr_matches("(group:mainline", &tests[1].attr).is_err());
+        assert!(attr_expr_matches("a b", &tests[1].attr).is_err());
+    }
+    #[test]
+    fn shards() {
+        let tests: Vec<String> = (0..5).map(|i| format!("t.{i}")).collect();
+        assert_eq!(
+            shard_tests(&tests, 2),
+            vec![vec!["t.0", "t.2", "t.4"], vec!["t.1", "t.3"]]
+        );
+        assert_eq!(shard_tests(&tests[..1], 3), vec![vec!["t.0"]]);
+        assert_eq!(shard_tests(&tests, 0).len(), 1);
+    }
+    #[test]
+    fn bundle_packages() {
+        assert_eq!(
+            local_bundle_package("cros"),
+            "go.chromium.org/tast-tests/cros/local/bundles/cros"
+        );
+        assert_eq!(
+            local_bundle_package("crosint"),
+            "go.chromium.org/tast-tests-private/crosint/local/bundles/crosint"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Self-contained HTML reports of recorded test runs, with the excerpts of
+//! the logs and the screenshots embedded, to share them as a single file.
+
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::progress::format_duration;
+use crate::test_results::escape_xml;
+use crate::test_results::TestResult;
+use crate::test_results::TestRun;
+use crate::test_results::TestStatus;
+
+/// Number of the last lines of a log to embed for a test which did not pass
+const LOG_EXCERPT_LINES: usize = 50;
+
+/// Screenshots larger than this are linked instead of embedded
+const MAX_EMBEDDED_SCREENSHOT: u64 = 4 * 1024 * 1024;
+
+const STYLE: &str = "body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: left; vertical-align: top; }
+.PASS { color: #188038; } .FAIL { color: #d93025; } .SKIP { color: #80868b; }
+.FLAKY { color: #e37400; }
+pre { background: #f8f9fa; padding: 8px; max-height: 30em; overflow: auto; }
+img { max-width: 640px; border: 1px solid #ccc; }";
+
+/// Returns the last `lines` lines of the file at `path`
+pub fn log_excerpt(path: &Path, lines: usize) -> Option<String> {
+    let log = fs::read_to_string(path).ok()?;
+    let all: Vec<&str> = log.lines().collect();
+    Some(all[all.len().saturating_sub(lines)..].join("\n"))
+}
+
+/// Returns the screenshots (*.png) next to the log of `result`, including
+/// the ones in the subdirectories (e.g. faillog/ of tast)
+pub fn screenshots(result: &TestResult) -> Vec<PathBuf> {
+    let Some(dir) = result.log.as_deref().and_then(Path::parent) else {
+        return Vec::new();
+    };
+    let mut pngs = Vec::new();
+    let mut dirs = vec![(dir.to_path_buf(), 0)];
+    while let Some((dir, depth)) = dirs.pop() {
+        for path in fs::read_dir(&dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|e| e.path())
+        {
+            if path.is_dir() && depth < 2 {
+                dirs.push((path, depth + 1));
+            } else if path.extension().is_some_and(|e| e == "png") {
+                pngs.push(path);
+            }
+        }
+    }
+    pngs.sort();
+    pngs
+}
+
+fn screenshot_html(path: &Path) -> String {
+    let name = escape_xml(&path.to_string_lossy());
+    match fs::metadata(path) {
+        Ok(m) if m.len() <= MAX_EMBEDDED_SCREENSHOT => match fs::read(path) {
+            Ok(png) => format!(
+                "<p><img src=\"data:image/png;base64,{}\" alt=\"{name}\"></p>\n",
+                STANDARD.encode(png)
+            ),
+            Err(_) => String::new(),
+        },
+        _ => format!("<p><a href=\"file://{name}\">{name}</a></p>\n"),
+    }
+}
+
+/// Formats the run as an HTML page with the summary, the status of each test
+/// and the details of the tests which did not pass
+pub fn to_html_report(run: &TestRun) -> String {
+    let title = format!("Test results: {}", escape_xml(&run.id));
+    let started_at = chrono::DateTime::from_timestamp(run.started_at, 0)
+        .map(|t| {
+            t.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default();
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n"
+    );
+    html += &format!("<style>\n{STYLE}\n</style>\n</head>\n<body>\n<h1>{title}</h1>\n<table>\n");
+    for (key, value) in [
+        ("Tests", run.tests.as_str()),
+        ("Framework", run.framework.as_str()),
+        ("DUT", run.dut.as_str()),
+        ("Build", run.build.as_deref().unwrap_or("-")),
+        ("Started at", started_at.as_str()),
+        ("Summary", run.summary().as_str()),
+    ] {
+        html += &format!("<tr><th>{key}</th><td>{}</td></tr>\n", escape_xml(value));
+    }
+    html += "</table>\n<h2>Tests</h2>\n<table>\n";
+    html += "<tr><th>STATUS</th><th>TIME</th><th>TEST</th><th>ERROR</th></tr>\n";
+    for (i, r) in run.results.iter().enumerate() {
+        let name = if r.status == TestStatus::Passed {
+            escape_xml(&r.name)
+        } else {
+            format!("<a href=\"#test{i}\">{}</a>", escape_xml(&r.name))
+        };
+        html += &format!(
+            "<tr><td class=\"{0}\">{0}</td><td>{1}</td><td>{name}</td><td>{2}</td></tr>\n",
+            r.status,
+            format_duration(r.duration),
+            escape_xml(
+                r.error
+                    .as_deref()
+                    .unwrap_or_default()
+                    .lines()
+                    .next()
+                    .unwrap_or_default()
+            )
+        );
+    }
+    html += "</table>\n";
+    for (i, r) in run.results.iter().enumerate() {
+        if r.status == TestStatus::Passed {
+            continue;
+        }
+        html += &format!(
+            "<h2 id=\"test{i}\" class=\"{}\">{}</h2>\n",
+            r.status,
+            escape_xml(&r.name)
+        );
+        if let Some(error) = &r.error {
+            html += &format!("<pre>{}</pre>\n", escape_xml(error));
+        }
+        if let Some(log) = &r.log {
+            html += &format!("<p>Log: {}</p>\n", escape_xml(&log.to_string_lossy()));
+            if let Some(excerpt) = log_excerpt(log, LOG_EXCERPT_LINES) {
+                html += &format!("<pre>{}</pre>\n", escape_xml(&excerpt));
+            }
+        }
+        for png in screenshots(r) {
+            html += &screenshot_html(&png);
+        }
+    }
+    if !run.perf.is_empty() {
+        html += "<h2>Performance metrics</h2>\n<table>\n";
+        html += "<tr><th>TEST</th><th>METRIC</th><th>VALUE</th></tr>\n";
+        for m in &run.perf {
+            html += &format!(
+                "<tr><td>{}</td><td>{}</td><td>{} {}</td></tr>\n",
+                escape_xml(&m.test),
+                escape_xml(&m.name),
+                m.value,
+                escape_xml(&m.units)
+            );
+        }
+        html += "</table>\n";
+    }
+    html += "</body>\n</html>\n";
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn html_report() {
+        let run = TestRun {
+            results: vec![
+                TestResult {
+                    name: "example.Pass".to_string(),
+                    status: TestStatus::Passed,
+                    duration: 3.0,
+                    error: None,
+                    log: None,
+                    attempts: 1,
+                },
+                TestResult {
+                    name: "example.Fail".to_string(),
+                    status: TestStatus::Failed,
+                    duration: 5.0,
+                    error: Some("a < b".to_string()),
+                    log: Some(PathBuf::from("/nonexistent/log.txt")),
+                    attempts: 1,
+                },
+            ],
+            ..TestRun::new("tast", "dut1", "example.*")
+        };
+        let html = to_html_report(&run);
+        assert!(html.contains("<tr><th>Summary</th><td>1 passed, 1 failed, 0 skipped</td></tr>"));
+        assert!(html.contains("<body>\n<h1>Test results: tast-"));
+        assert!(html.contains("<td>example.Pass</td>"));
+        assert!(html.contains("<a href=\"#test1\">example.Fail</a></td><td>a &lt; b</td>"));
+        assert!(html.contains("<h2 id=\"test1\" class=\"FAIL\">example.Fail</h2>"));
+        assert!(!html.contains("Performance metrics"));
+    }
+}
@@ -0,0 +1,655 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Results of test runs in a common schema, recorded under
+//! ~/.cro3/results/RUN_ID with the logs of the tests. Failed tests can be
+//! retried, and the ones that pass on a retry are reported as flaky apart
+//! from the failures.
+//!
+//! Each run is written as run.json, whose fields are kept compatible for the
+//! scripts reading it, and as junit.xml for CI systems.
+
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+
+use crate::cache::KvCache;
+use crate::dut::SshInfo;
+use crate::parser::LsbRelease;
+use crate::perf::collect_perf_metrics;
+use crate::perf::PerfMetric;
+use crate::progress::format_duration;
+use crate::util::cro3_paths::gen_path_in_cro3_dir;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    Skipped,
+    /// Failed, and then passed on a retry
+    Flaky,
+}
+impl std::fmt::Display for TestStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            TestStatus::Passed => "PASS",
+            TestStatus::Failed => "FAIL",
+            TestStatus::Skipped => "SKIP",
+            TestStatus::Flaky => "FLAKY",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TestResult {
+    pub name: String,
+    pub status: TestStatus,
+    /// Seconds
+    pub duration: f64,
+    /// The first error, or the reason to skip
+    pub error: Option<String>,
+    /// Log of the test on this machine
+    pub log: Option<PathBuf>,
+    /// Number of runs of the test including retries
+    #[serde(default = "one")]
+    pub attempts: u32,
+}
+fn one() -> u32 {
+    1
+}
+
+/// Tests found flaky so far (key: test name)
+pub static FLAKY_TESTS: KvCache<FlakeRecord> = KvCache::new("flaky_tests");
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlakeRecord {
+    /// Number of runs the test was flaky in
+    pub count: u32,
+    /// ID of the last run the test was flaky in
+    pub last_run: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TestRun {
+    /// e.g. tast-20231015-123456-789
+    pub id: String,
+    /// e.g. tast
+    pub framework: String,
+    pub dut: String,
+    /// The tests given to run, e.g. a pattern
+    pub tests: String,
+    /// Unix time of the start
+    pub started_at: i64,
+    /// Build on the DUT, e.g. brya-release/R120-15662.0.0
+    #[serde(default)]
+    pub build: Option<String>,
+    pub results: Vec<TestResult>,
+    /// Performance metrics reported by the tests
+    #[serde(default)]
+    pub perf: Vec<PerfMetric>,
+}
+/// Returns an ID for a run, e.g. tast-20231015-123456-789
+pub fn new_run_id(prefix: &str) -> String {
+    format!(
+        "{prefix}-{}",
+        chrono::Local::now().format("%Y%m%d-%H%M%S-%3f")
+    )
+}
+
+/// Returns an ID for a run like new_run_id(), and creates the directory of the
+/// results to reserve it, so that the runs started at the same time (e.g. on
+/// multiple DUTs) get different IDs, like tast-20231015-123456-789-2
+pub fn reserve_run_id(prefix: &str) -> Result<String> {
+    let base = new_run_id(prefix);
+    let parent = gen_path_in_cro3_dir("results")?;
+    fs::create_dir_all(&parent)?;
+    let mut id = base.clone();
+    for n in 2.. {
+        match fs::create_dir(parent.join(&id)) {
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => id = format!("{base}-{n}"),
+            result => {
+                result.context(anyhow!("Failed to create the directory of {id}"))?;
+                break;
+            }
+        }
+    }
+    Ok(id)
+}
+
+impl TestRun {
+    /// Starts a run with a reserved ID (see reserve_run_id())
+    pub fn start(framework: &str, dut: &str, tests: &str) -> Result<Self> {
+        Ok(Self {
+            id: reserve_run_id(framework)?,
+            ..Self::new(framework, dut, tests)
+        })
+    }
+    /// Returns a run with an ID which is not reserved. Use start() for the
+    /// runs to save.
+    pub fn new(framework: &str, dut: &str, tests: &str) -> Self {
+        let now = chrono::Local::now();
+        Self {
+            id: new_run_id(framework),
+            framework: framework.to_string(),
+            dut: dut.to_string(),
+            tests: tests.to_string(),
+            started_at: now.timestamp(),
+            build: None,
+            results: Vec::new(),
+            perf: Vec::new(),
+        }
+    }
+    /// Records the build on the DUT, to tell the runs on different builds
+    pub fn with_build_of(mut self, ssh: &SshInfo) -> Self {
+        self.build = ssh
+            .run_cmd_stdio("cat /etc/lsb-release")
+            .ok()
+            .and_then(|lsb| LsbRelease::from_str(&lsb).ok())
+            .and_then(|lsb| {
+                lsb.get("CHROMEOS_RELEASE_BUILDER_PATH")
+                    .or(lsb.get("CHROMEOS_RELEASE_VERSION"))
+                    .map(str::to_string)
+            });
+        self
+    }
+    /// Directory to keep the results and the logs of the run in
+    pub fn dir(id: &str) -> Result<PathBuf> {
+        let dir = gen_path_in_cro3_dir(&format!("results/{id}"))?;
+        fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+    /// Writes the run with the performance metrics found in the results
+    pub fn save(&mut self) -> Result<()> {
+        self.perf = self.results.iter().flat_map(collect_perf_metrics).collect();
+        let dir = Self::dir(&self.id)?;
+        let path = dir.join("run.json");
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .context(anyhow!("Failed to write {path:?}"))?;
+        let path = dir.join("junit.xml");
+        fs::write(&path, to_junit_xml(self)).context(anyhow!("Failed to write {path:?}"))
+    }
+    pub fn load(id: &str) -> Result<Self> {
+        let path = gen_path_in_cro3_dir(&format!("results/{id}/run.json"))?;
+        serde_json::from_str(
+            &fs::read_to_string(&path).context(anyhow!("No test run {id} is recorded"))?,
+        )
+        .context(anyhow!("Failed to parse {path:?}"))
+    }
+    /// Returns the IDs of the recorded runs from the oldest one
+    pub fn list() -> Result<Vec<String>> {
+        let dir = gen_path_in_cro3_dir("results")?;
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Ok(Vec::new());
+        };
+        let mut runs: Vec<(i64, String)> = entries
+            .flatten()
+            .filter_map(|e| Self::load(&e.file_name().to_string_lossy()).ok())
+            .map(|run| (run.started_at, run.id))
+            .collect();
+        runs.sort();
+        Ok(runs.into_iter().map(|(_, id)| id).collect())
+    }
+    /// Loads the run `id`, or the latest run if it is None
+    pub fn load_or_latest(id: Option<&str>) -> Result<Self> {
+        match id {
+            Some(id) => Self::load(id),
+            None => Self::load(
+                Self::list()?
+                    .last()
+                    .context("No test runs are recorded yet")?,
+            ),
+        }
+    }
+    pub fn count(&self, status: TestStatus) -> usize {
+        self.results.iter().filter(|r| r.status == status).count()
+    }
+    pub fn failed(&self) -> Vec<&str> {
+        self.results
+            .iter()
+            .filter(|r| r.status == TestStatus::Failed)
+            .map(|r| r.name.as_str())
+            .collect()
+    }
+    /// e.g. "3 passed, 1 failed, 0 skipped"
+    pub fn summary(&self) -> String {
+        let summary = format!(
+            "{} passed, {} failed, {} skipped",
+            self.count(TestStatus::Passed),
+            self.count(TestStatus::Failed),
+            self.count(TestStatus::Skipped)
+        );
+        match self.count(TestStatus::Flaky) {
+            0 => summary,
+            n => format!("{summary}, {n} flaky"),
+        }
+    }
+    /// Updates the results of the failed tests with the results of a retry
+    pub fn merge_retry(&mut self, retry: Vec<TestResult>) {
+        for r in retry {
+            let Some(result) = self
+                .results
+                .iter_mut()
+                .find(|t| t.name == r.name && t.status == TestStatus::Failed)
+            else {
+                continue;
+            };
+            result.attempts += 1;
+            if r.status == TestStatus::Passed {
+                // Keep the error and the log of the failure to look into it
+                result.status = TestStatus::Flaky;
+            } else {
+                result.error = r.error;
+                result.log = r.log;
+            }
+        }
+    }
+    /// Reruns the failed tests up to `retries` times with `rerun`, which
+    /// runs the given tests with the results in the given subdirectory of the
+    /// run
+    pub fn retry_failures(
+        &mut self,
+        retries: u32,
+        rerun: impl Fn(&[String], &str) -> Result<Vec<TestResult>>,
+    ) -> Result<()> {
+        for i in 1..=retries {
+            let failed: Vec<String> = self.failed().iter().map(|t| t.to_string()).collect();
+            if failed.is_empty() {
+                break;
+            }
+            info!("Retrying {} failed tests ({i}/{retries})", failed.len());
+            self.merge_retry(rerun(&failed, &format!("retry{i}"))?);
+        }
+        for r in &self.results {
+            if r.status == TestStatus::Flaky {
+                let count = FLAKY_TESTS.get(&r.name)?.map(|f| f.count).unwrap_or(0);
+                FLAKY_TESTS.set(
+                    &r.name,
+                    FlakeRecord {
+                        count: count + 1,
+                        last_run: self.id.clone(),
+                    },
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Results of a test run repeatedly to measure its flakiness
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FlakeStats {
+    pub name: String,
+    /// Number of the runs which passed or failed (not skipped)
+    pub runs: u32,
+    /// Iterations (from 1) the test failed in
+    pub failed_iterations: Vec<u32>,
+    /// Seconds of each run
+    pub durations: Vec<f64>,
+}
+impl FlakeStats {
+    /// Percentage of the failed runs
+    pub fn flake_rate(&self) -> f64 {
+        if self.runs == 0 {
+            return 0.0;
+        }
+        self.failed_iterations.len() as f64 / self.runs as f64 * 100.0
+    }
+    /// Returns the duration at the percentile `p` (0-100) of the runs
+    pub fn percentile(&self, p: f64) -> f64 {
+        percentile(&self.durations, p)
+    }
+}
+
+/// Returns the value at the percentile `p` (0-100) of `values`, or 0 if empty
+pub fn percentile(values: &[f64], p: f64) -> f64 {
+    let mut values = values.to_vec();
+    values.sort_by(f64::total_cmp);
+    match values.len() {
+        0 => 0.0,
+        n => values[((n - 1) as f64 * p / 100.0).round() as usize],
+    }
+}
+
+/// Aggregates the results of the iterations of repeated runs per test
+pub fn flake_stats(iterations: &[Vec<TestResult>]) -> Vec<FlakeStats> {
+    let mut stats: Vec<FlakeStats> = Vec::new();
+    for (i, results) in iterations.iter().enumerate() {
+        for r in results {
+            if r.status == TestStatus::Skipped {
+                continue;
+            }
+            let s = match stats.iter().position(|s| s.name == r.name) {
+                Some(pos) => &mut stats[pos],
+                None => {
+                    stats.push(FlakeStats {
+                        name: r.name.clone(),
+                        runs: 0,
+                        failed_iterations: Vec::new(),
+                        durations: Vec::new(),
+                    });
+                    stats.last_mut().unwrap()
+                }
+            };
+            s.runs += 1;
+            s.durations.push(r.duration);
+            if r.status == TestStatus::Failed {
+                s.failed_iterations.push(i as u32 + 1);
+            }
+        }
+    }
+    stats
+}
+
+/// Formats the flaky tests of a run with the number of runs each of them has
+/// been flaky in, or returns None if there are none
+pub fn format_flake_report(run: &TestRun) -> Result<Option<String>> {
+    let mut report = String::new();
+    for r in run.results.iter().filter(|r| r.status == TestStatus::Flaky) {
+        let count = FLAKY_TESTS.get(&r.name)?.map(|f| f.count).unwrap_or(1);
+        report.push_str(&format!(
+            "{} (passed on attempt {}, flaky in {count} runs)\n",
+            r.name, r.attempts
+        ));
+    }
+    Ok((!report.is_empty()).then_some(report))
+}
+
+/// Differences of the results between two runs
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RunDiff {
+    /// Tests which failed only in the second run
+    pub new_failures: Vec<String>,
+    /// Tests which failed in the first run and passed in the second one
+    pub fixed: Vec<String>,
+    /// Tests which took longer in the second run: (name, before, after)
+    pub slower: Vec<(String, f64, f64)>,
+    /// Tests which are only in the first run
+    pub removed: Vec<String>,
+    /// Tests which are only in the second run
+    pub added: Vec<String>,
+}
+
+/// Compares the results of run `a` with run `b`. A test is slower if it took
+/// `threshold` (e.g. 0.2 for 20%) and `min_secs` longer, so that the noise of
+/// short tests is ignored.
+pub fn diff_runs(a: &TestRun, b: &TestRun, threshold: f64, min_secs: f64) -> RunDiff {
+    let mut diff = RunDiff::default();
+    for rb in &b.results {
+        let ra = a.results.iter().find(|r| r.name == rb.name);
+        let failed_before = ra.is_some_and(|r| r.status == TestStatus::Failed);
+        match rb.status {
+            TestStatus::Failed if !failed_before => diff.new_failures.push(rb.name.clone()),
+            TestStatus::Passed | TestStatus::Flaky if failed_before => {
+                diff.fixed.push(rb.name.clone())
+            }
+            _ => {}
+        }
+        match ra {
+            None => diff.added.push(rb.name.clone()),
+            Some(ra)
+                if ra.status != TestStatus::Skipped
+                    && rb.status != TestStatus::Skipped
+                    && rb.duration - ra.duration >= min_secs
+                    && rb.duration > ra.duration * (1.0 + threshold) =>
+            {
+                diff.slower
+                    .push((rb.name.clone(), ra.duration, rb.duration))
+            }
+            _ => {}
+        }
+    }
+    diff.removed = a
+        .results
+        .iter()
+        .filter(|ra| !b.results.iter().any(|rb| rb.name == ra.name))
+        .map(|ra| ra.name.clone())
+        .collect();
+    diff
+}
+
+pub(crate) fn escape_xml(s: &str) -> String {
+    s.chars()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\t'))
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&apos;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+/// Formats the run as JUnit XML. Flaky tests are reported as passed with
+/// flakyFailure, and the logs as attachments in system-out.
+pub fn to_junit_xml(run: &TestRun) -> String {
+    let time: f64 = run.results.iter().map(|r| r.duration).sum();
+    let timestamp = chrono::DateTime::from_timestamp(run.started_at, 0)
+        .map(|t| t.format("%Y-%m-%dT%H:%M:%S").to_string())
+        .unwrap_or_default();
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str(&format!(
+        "<testsuites name=\"cro3\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" \
+         time=\"{time:.3}\">\n",
+        run.results.len(),
+        run.count(TestStatus::Failed),
+        run.count(TestStatus::Skipped)
+    ));
+    xml.push_str(&format!(
+        "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{time:.3}\" \
+         timestamp=\"{timestamp}\" hostname=\"{}\">\n",
+        escape_xml(&run.id),
+        run.results.len(),
+        run.count(TestStatus::Failed),
+        run.count(TestStatus::Skipped),
+        escape_xml(&run.dut)
+    ));
+    for r in &run.results {
+        xml.push_str(&format!(
+            "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\">\n",
+            escape_xml(&r.name),
+            escape_xml(&run.framework),
+            r.duration
+        ));
+        let error = r.error.as_deref().unwrap_or_default();
+        let message = escape_xml(error.lines().next().unwrap_or_default());
+        match r.status {
+            TestStatus::Passed => {}
+            TestStatus::Failed => xml.push_str(&format!(
+                "      <failure message=\"{message}\">{}</failure>\n",
+                escape_xml(error)
+            )),
+            TestStatus::Skipped => {
+                xml.push_str(&format!("      <skipped message=\"{message}\"/>\n"))
+            }
+            TestStatus::Flaky => xml.push_str(&format!(
+                "      <flakyFailure message=\"{message}\">{}</flakyFailure>\n",
+                escape_xml(error)
+            )),
+        }
+        if let Some(log) = &r.log {
+            xml.push_str(&format!(
+                "      <system-out>[[ATTACHMENT|{}]]</system-out>\n",
+                escape_xml(&log.to_string_lossy())
+            ));
+        }
+        xml.push_str("    </testcase>\n");
+    }
+    xml.push_str("  </testsuite>\n</testsuites>\n");
+    xml
+}
+
+/// Formats the results as a table of the status, duration and name of each
+/// test, followed by the error and the log if it did not pass
+pub fn format_results_table(results: &[TestResult]) -> String {
+    let mut table = String::new();
+    for r in results {
+        table.push_str(&format!(
+            "{:<5} {:>8}  {}\n",
+            r.status,
+            format_duration(r.duration),
+            r.name
+        ));
+        if r.status != TestStatus::Passed {
+            if let Some(error) = &r.error {
+                table.push_str(&format!(
+                    "                {}\n",
+                    error.lines().next().unwrap_or("")
+                ));
+            }
+            if let Some(log) = &r.log {
+                table.push_str(&format!("                {}\n", log.display()));
+            }
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn results_table() {
+        let results = vec![
+            TestResult {
+                name: "example.Pass".to_string(),
+                status: TestStatus::Passed,
+                duration: 3.0,
+                error: None,
+                log: Some(PathBuf::from("/r/tests/example.Pass/log.txt")),
+                attempts: 1,
+            },
+            TestResult {
+                name: "example.Fail".to_string(),
+                status: TestStatus::Failed,
+                duration: 65.0,
+                error: Some("boom\nstack".to_string()),
+                log: Some(PathBuf::from("/r/tests/example.Fail/log.txt")),
+                attempts: 1,
+            },
+        ];
+        assert_eq!(
+            format_results_table(&results),
+            format!(
+                "PASS  {:>8}  example.Pass\nFAIL  {:>8}  example.Fail\n                boom\n                /r/tests/example.Fail/log.txt\n",
+                format_duration(3.0),
+                format_duration(65.0)
+            )
+        );
+        let mut run = TestRun {
+            results: results.clone(),
+            ..TestRun::new("tast", "dut", "example.*")
+        };
+        assert_eq!(run.summary(), "1 passed, 1 failed, 0 skipped");
+        assert_eq!(run.failed(), vec!["example.Fail"]);
+        assert!(run.id.starts_with("tast-"));
+        let xml = to_junit_xml(&TestRun {
+            dut: "dut<1>".to_string(),
+            ..run.clone()
+        });
+        assert!(xml.contains("tests=\"2\" failures=\"1\" skipped=\"0\" time=\"68.000\""));
+        assert!(xml.contains("hostname=\"dut&lt;1&gt;\""));
+        assert!(xml.contains(
+            "    <testcase name=\"example.Fail\" classname=\"tast\" time=\"65.000\">
+      <failure message=\"boom\">boom
+stack</failure>
+      <system-out>[[ATTACHMENT|/r/tests/example.Fail/log.txt]]</system-out>
+    </testcase>"
+        ));
+
+        let retry = TestResult {
+            status: TestStatus::Passed,
+            log: None,
+            ..results[1].clone()
+        };
+        // A retry of a test which did not fail is ignored
+        run.merge_retry(vec![results[0].clone()]);
+        assert_eq!(run.results, results);
+        run.merge_retry(vec![retry]);
+        assert_eq!(run.results[1].status, TestStatus::Flaky);
+        assert_eq!(run.results[1].attempts, 2);
+        assert_eq!(run.results[1].log, results[1].log);
+        assert!(run.failed().is_empty());
+        assert_eq!(run.summary(), "1 passed, 0 failed, 0 skipped, 1 flaky");
+    }
+    #[test]
+    fn flakiness() {
+        let result = |status, duration| TestResult {
+            name: "t.Flaky".to_string(),
+            status,
+            duration,
+            error: None,
+            log: None,
+            attempts: 1,
+        };
+        let iterations = vec![
+            vec![result(TestStatus::Passed, 10.0)],
+            vec![result(TestStatus::Failed, 30.0)],
+            // An iteration which failed to run
+            vec![],
+            vec![result(TestStatus::Passed, 20.0)],
+            vec![result(TestStatus::Skipped, 0.0)],
+        ];
+        let stats = flake_stats(&iterations);
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].runs, 3);
+        assert_eq!(stats[0].failed_iterations, vec![2]);
+        assert!((stats[0].flake_rate() - 100.0 / 3.0).abs() < 1e-9);
+        assert_eq!(stats[0].percentile(0.0), 10.0);
+        assert_eq!(stats[0].percentile(50.0), 20.0);
+        assert_eq!(stats[0].percentile(100.0), 30.0);
+    }
+    #[test]
+    fn run_diff() {
+        let result = |name: &str, status, duration| TestResult {
+            name: name.to_string(),
+            status,
+            duration,
+            error: None,
+            log: None,
+            attempts: 1,
+        };
+        let a = TestRun {
+            results: vec![
+                result("t.Fixed", TestStatus::Failed, 1.0),
+                result("t.Broken", TestStatus::Passed, 1.0),
+                result("t.Slow", TestStatus::Passed, 10.0),
+                result("t.Noisy", TestStatus::Passed, 1.0),
+                result("t.Removed", TestStatus::Passed, 1.0),
+            ],
+            ..TestRun::new("tast", "dut", "t.*")
+        };
+        let b = TestRun {
+            results: vec![
+                result("t.Fixed", TestStatus::Passed, 1.0),
+                result("t.Broken", TestStatus::Failed, 1.0),
+                result("t.Slow", TestStatus::Passed, 20.0),
+                result("t.Noisy", TestStatus::Passed, 3.0),
+                result("t.Added", TestStatus::Failed, 1.0),
+            ],
+            ..TestRun::new("tast", "dut", "t.*")
+        };
+        assert_eq!(
+            diff_runs(&a, &b, 0.2, 5.0),
+            RunDiff {
+                new_failures: vec!["t.Broken".to_string(), "t.Added".to_string()],
+                fixed: vec!["t.Fixed".to_string()],
+                slower: vec![("t.Slow".to_string(), 10.0, 20.0)],
+                removed: vec!["t.Removed".to_string()],
+                added: vec!["t.Added".to_string()],
+            }
+        );
+    }
+}
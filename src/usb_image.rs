@@ -0,0 +1,272 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Writing disk images to USB sticks with progress and verification, without
+//! `cros flash`. See `cro3 flash usb`.
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::anyhow;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
+use tracing::info;
+
+use crate::util::super_user_helpers::has_root_privilege;
+use crate::util::super_user_helpers::run_cro3_with_sudo;
+
+/// Devices larger than this are unlikely to be USB sticks
+const MAX_USB_STICK_SIZE: u64 = 256 * 1024 * 1024 * 1024;
+
+const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// A whole-disk block device, as seen in /sys/block
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockDevice {
+    /// e.g. sdb
+    pub name: String,
+    pub size: u64,
+    pub removable: bool,
+    /// True if the device is connected via USB
+    pub usb: bool,
+    pub model: String,
+    /// Mount points of the device and its partitions
+    pub mounts: Vec<String>,
+}
+impl BlockDevice {
+    /// Reads the info of `device` (e.g. /dev/sdb) from sysfs
+    pub fn of(device: &str) -> Result<Self> {
+        let name = device.trim_start_matches("/dev/").to_string();
+        let sys = Path::new("/sys/block").join(&name);
+        if !sys.exists() {
+            bail!(
+                "{device} is not a whole-disk block device (partitions like /dev/sdb1 are not \
+                 accepted)"
+            );
+        }
+        let read = |file: &str| {
+            fs::read_to_string(sys.join(file))
+                .map(|s| s.trim().to_string())
+                .unwrap_or_default()
+        };
+        let sectors: u64 = read("size").parse().unwrap_or_default();
+        let mounts = fs::read_to_string("/proc/mounts")?
+            .lines()
+            .filter_map(|l| {
+                let mut fields = l.split_whitespace();
+                let dev = fields.next()?;
+                let mount_point = fields.next()?;
+                let dev_name = dev.strip_prefix("/dev/")?;
+                (dev_name.starts_with(&name)).then(|| format!("{dev} on {mount_point}"))
+            })
+            .collect();
+        Ok(Self {
+            usb: fs::canonicalize(&sys)?.to_string_lossy().contains("/usb"),
+            removable: read("removable") == "1",
+            size: sectors * 512,
+            model: format!("{} {}", read("device/vendor"), read("device/model"))
+                .trim()
+                .to_string(),
+            mounts,
+            name,
+        })
+    }
+    pub fn description(&self) -> String {
+        format!(
+            "/dev/{} ({}, {:.1} GB{})",
+            self.name,
+            if self.model.is_empty() {
+                "unknown model"
+            } else {
+                &self.model
+            },
+            self.size as f64 / 1e9,
+            if self.usb { ", USB" } else { "" }
+        )
+    }
+    /// Returns an error if the device does not look like a USB stick that the
+    /// image can be written to. `force` skips the checks on the device type
+    /// and size, but not the ones on mounts.
+    pub fn check_writable(&self, image_size: u64, force: bool) -> Result<()> {
+        if let Some(root) = self.mounts.iter().find(|m| m.ends_with(" on /")) {
+            bail!("{} holds the root filesystem ({root})", self.description());
+        }
+        if !self.mounts.is_empty() {
+            bail!(
+                "{} is mounted. Please unmount it first: {}",
+                self.description(),
+                self.mounts.join(", ")
+            );
+        }
+        if self.size < image_size {
+            bail!(
+                "{} is smaller than the image ({:.1} GB)",
+                self.description(),
+                image_size as f64 / 1e9
+            );
+        }
+        if force {
+            return Ok(());
+        }
+        if !self.usb && !self.removable {
+            bail!(
+                "{} is neither removable nor on USB. Use --force if it is really the target.",
+                self.description()
+            );
+        }
+        if self.size > MAX_USB_STICK_SIZE {
+            bail!(
+                "{} is too large for a USB stick. Use --force if it is really the target.",
+                self.description()
+            );
+        }
+        Ok(())
+    }
+}
+
+fn progress_bar(len: u64, msg: &str) -> Result<ProgressBar> {
+    let bar = ProgressBar::new(len);
+    bar.set_style(ProgressStyle::with_template(
+        "{msg:>10} {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+    )?);
+    bar.set_message(msg.to_string());
+    Ok(bar)
+}
+
+/// Copies `len` bytes from `reader` to `writer`, showing the progress
+fn copy_with_progress(
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+    len: u64,
+    msg: &str,
+) -> Result<()> {
+    let bar = progress_bar(len, msg)?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut remaining = len;
+    while remaining > 0 {
+        let n = reader.read(&mut buf[..(remaining.min(CHUNK_SIZE as u64) as usize)])?;
+        if n == 0 {
+            bail!("Unexpected end of data ({remaining} bytes left)");
+        }
+        writer.write_all(&buf[..n])?;
+        remaining -= n as u64;
+        bar.inc(n as u64);
+    }
+    bar.finish();
+    Ok(())
+}
+
+/// Returns the SHA-256 of the first `len` bytes of `reader`, via sha256sum
+fn sha256(reader: &mut dyn Read, len: u64, msg: &str) -> Result<String> {
+    let mut child = Command::new("sha256sum")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to run sha256sum")?;
+    copy_with_progress(
+        reader,
+        child.stdin.as_mut().context("No stdin of sha256sum")?,
+        len,
+        msg,
+    )?;
+    drop(child.stdin.take());
+    let output = child.wait_with_output()?;
+    output.status.exit_ok().context("sha256sum failed")?;
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+/// Writes `image` to `device` and verifies the written data
+pub fn write_image(image: &Path, device: &str) -> Result<()> {
+    let len = fs::metadata(image)?.len();
+    let expected = sha256(&mut File::open(image)?, len, "checksum")?;
+    {
+        let mut dev = OpenOptions::new()
+            .write(true)
+            .open(device)
+            .context(anyhow!("Failed to open {device} for writing"))?;
+        copy_with_progress(&mut File::open(image)?, &mut dev, len, "writing")?;
+        dev.sync_all()?;
+    }
+    // Drop the page cache of the device so that the data is read back from
+    // the stick itself
+    let _ = Command::new("blockdev")
+        .args(["--flushbufs", device])
+        .status();
+    let actual = sha256(&mut File::open(device)?, len, "verifying")?;
+    if actual != expected {
+        bail!("Verification failed: the SHA-256 of {device} is {actual} but {expected} expected");
+    }
+    Ok(())
+}
+
+/// Writes an image like write_image(). If the device is not writable by the
+/// current user, `cro3 flash usb` is run with sudo to write it.
+pub fn write_image_with_sudo(image: &Path, device: &str, force: bool) -> Result<()> {
+    if has_root_privilege()? || OpenOptions::new().write(true).open(device).is_ok() {
+        return write_image(image, device);
+    }
+    info!("Writing to {device} requires root. Running with sudo...");
+    let image = image.to_string_lossy();
+    let mut args = vec![
+        "flash", "usb", "--device", device, "--image", &image, "--yes",
+    ];
+    if force {
+        args.push("--force");
+    }
+    run_cro3_with_sudo(&args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn usb_target_check() {
+        let stick = BlockDevice {
+            name: "sdb".to_string(),
+            size: 32_000_000_000,
+            removable: true,
+            usb: true,
+            model: "SanDisk Ultra".to_string(),
+            mounts: vec![],
+        };
+        let gb = 1_000_000_000;
+        assert!(stick.check_writable(8 * gb, false).is_ok());
+        assert!(stick.check_writable(64 * gb, true).is_err());
+        let mounted = BlockDevice {
+            mounts: vec!["/dev/sdb1 on /media/usb".to_string()],
+            ..stick.clone()
+        };
+        assert!(mounted.check_writable(8 * gb, true).is_err());
+        let system = BlockDevice {
+            name: "nvme0n1".to_string(),
+            size: 1_000_000_000_000,
+            removable: false,
+            usb: false,
+            model: "".to_string(),
+            mounts: vec!["/dev/nvme0n1p2 on /".to_string()],
+        };
+        assert!(system.check_writable(8 * gb, true).is_err());
+        let internal = BlockDevice {
+            mounts: vec![],
+            ..system.clone()
+        };
+        assert!(internal.check_writable(8 * gb, false).is_err());
+        assert!(internal.check_writable(8 * gb, true).is_ok());
+    }
+}
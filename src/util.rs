@@ -5,5 +5,8 @@
 // https://developers.google.com/open-source/licenses/bsd
 
 pub mod cro3_paths;
+pub mod line_editor;
+pub mod picker;
+pub mod rotating_file;
 pub mod shell_helpers;
 pub mod super_user_helpers;
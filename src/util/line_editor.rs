@@ -0,0 +1,323 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! A minimal line editor on the terminal with history and completion,
+//! supporting the common emacs-style keys (Ctrl-A/E/K/U/W, arrows, Tab).
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::stdin;
+use std::io::stdout;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+
+/// Number of history entries to keep
+const MAX_HISTORY: usize = 1000;
+
+/// The line being edited
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LineBuffer {
+    chars: Vec<char>,
+    /// Index in `chars`
+    cursor: usize,
+}
+impl LineBuffer {
+    pub fn text(&self) -> String {
+        self.chars.iter().collect()
+    }
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+    /// Replaces the whole line and moves the cursor to the end
+    pub fn set(&mut self, text: &str) {
+        self.chars = text.chars().collect();
+        self.cursor = self.chars.len();
+    }
+    pub fn insert(&mut self, s: &str) {
+        for c in s.chars() {
+            self.chars.insert(self.cursor, c);
+            self.cursor += 1;
+        }
+    }
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.chars.remove(self.cursor);
+        }
+    }
+    pub fn delete(&mut self) {
+        if self.cursor < self.chars.len() {
+            self.chars.remove(self.cursor);
+        }
+    }
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+    pub fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.chars.len());
+    }
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+    pub fn end(&mut self) {
+        self.cursor = self.chars.len();
+    }
+    pub fn kill_to_end(&mut self) {
+        self.chars.truncate(self.cursor);
+    }
+    pub fn kill_to_start(&mut self) {
+        self.chars.drain(..self.cursor);
+        self.cursor = 0;
+    }
+    /// Returns the index where the word before the cursor starts
+    fn word_start(&self) -> usize {
+        self.chars[..self.cursor]
+            .iter()
+            .rposition(|c| c.is_whitespace())
+            .map(|i| i + 1)
+            .unwrap_or(0)
+    }
+    /// Deletes the word before the cursor (Ctrl-W)
+    pub fn delete_word(&mut self) {
+        // Skip the spaces right before the cursor first, as shells do
+        while self.cursor > 0 && self.chars[self.cursor - 1].is_whitespace() {
+            self.backspace();
+        }
+        let start = self.word_start();
+        self.chars.drain(start..self.cursor);
+        self.cursor = start;
+    }
+    /// Returns the text before the cursor, which is used for completion
+    pub fn before_cursor(&self) -> String {
+        self.chars[..self.cursor].iter().collect()
+    }
+    /// Replaces the word before the cursor with `word`
+    pub fn replace_word(&mut self, word: &str) {
+        let start = self.word_start();
+        self.chars.drain(start..self.cursor);
+        self.cursor = start;
+        self.insert(word);
+    }
+}
+
+/// Returns the longest common prefix of `candidates`
+pub fn common_prefix(candidates: &[String]) -> String {
+    let mut prefix: Vec<char> = match candidates.first() {
+        Some(c) => c.chars().collect(),
+        None => return String::new(),
+    };
+    for c in &candidates[1..] {
+        let len = prefix
+            .iter()
+            .zip(c.chars())
+            .take_while(|(a, b)| *a == b)
+            .count();
+        prefix.truncate(len);
+    }
+    prefix.iter().collect()
+}
+
+/// Line history, optionally persisted to a file (one entry per line)
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<String>,
+    path: Option<PathBuf>,
+}
+impl History {
+    /// Loads the history from `path`. The file is created on the first add().
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut entries: Vec<String> = if path.exists() {
+            fs::read_to_string(&path)
+                .context(format!("Failed to read {path:?}"))?
+                .lines()
+                .map(|l| l.to_string())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if entries.len() > MAX_HISTORY {
+            entries.drain(..entries.len() - MAX_HISTORY);
+            fs::write(&path, entries.join("\n") + "\n")?;
+        }
+        Ok(Self {
+            entries,
+            path: Some(path),
+        })
+    }
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+    /// Adds a line to the history, unless it is empty or the same as the last
+    pub fn add(&mut self, line: &str) -> Result<()> {
+        let line = line.trim();
+        if line.is_empty() || self.entries.last().map(|l| l.as_str()) == Some(line) {
+            return Ok(());
+        }
+        self.entries.push(line.to_string());
+        if let Some(path) = &self.path {
+            let mut f = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(f, "{line}")?;
+        }
+        Ok(())
+    }
+}
+
+pub struct LineEditor {
+    pub history: History,
+}
+impl LineEditor {
+    pub fn new(history: History) -> Self {
+        Self { history }
+    }
+    /// Reads a line from the terminal. `complete` is called with the text
+    /// before the cursor on Tab, and returns the candidates for the last word.
+    /// Returns None on Ctrl-D with an empty line. Ctrl-C discards the line.
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        complete: &mut dyn FnMut(&str) -> Vec<String>,
+    ) -> Result<Option<String>> {
+        let mut tty = stdout()
+            .into_raw_mode()
+            .context("Failed to switch the terminal into raw mode")?;
+        let mut line = LineBuffer::default();
+        // Index in the history while browsing it, and the line being edited
+        let mut history_index = self.history.entries().len();
+        let mut editing = String::new();
+        let mut keys = stdin().keys();
+        loop {
+            let tail = line.text().chars().count() - line.cursor();
+            write!(
+                tty,
+                "\r{}{prompt}{}",
+                termion::clear::CurrentLine,
+                line.text()
+            )?;
+            if tail > 0 {
+                write!(tty, "{}", termion::cursor::Left(tail as u16))?;
+            }
+            tty.flush()?;
+            let key = match keys.next() {
+                Some(key) => key?,
+                None => return Ok(None),
+            };
+            match key {
+                Key::Char('\n') | Key::Char('\r') => {
+                    write!(tty, "\r\n")?;
+                    break;
+                }
+                Key::Ctrl('c') => {
+                    write!(tty, "^C\r\n")?;
+                    return Ok(Some(String::new()));
+                }
+                Key::Ctrl('d') => {
+                    if line.text().is_empty() {
+                        write!(tty, "\r\n")?;
+                        return Ok(None);
+                    }
+                    line.delete();
+                }
+                Key::Char('\t') => {
+                    let candidates = complete(&line.before_cursor());
+                    if candidates.len() == 1 {
+                        let c = &candidates[0];
+                        line.replace_word(c);
+                        if !c.ends_with('/') {
+                            line.insert(" ");
+                        }
+                    } else if !candidates.is_empty() {
+                        let prefix = common_prefix(&candidates);
+                        let word = line.before_cursor();
+                        let word = word.rsplit(char::is_whitespace).next().unwrap_or("");
+                        if prefix.chars().count() > word.chars().count() {
+                            line.replace_word(&prefix);
+                        } else {
+                            write!(tty, "\r\n{}\r\n", candidates.join("  "))?;
+                        }
+                    }
+                }
+                Key::Up | Key::Ctrl('p') => {
+                    if history_index > 0 {
+                        if history_index == self.history.entries().len() {
+                            editing = line.text();
+                        }
+                        history_index -= 1;
+                        line.set(&self.history.entries()[history_index]);
+                    }
+                }
+                Key::Down | Key::Ctrl('n') => {
+                    if history_index < self.history.entries().len() {
+                        history_index += 1;
+                        if history_index == self.history.entries().len() {
+                            line.set(&editing);
+                        } else {
+                            line.set(&self.history.entries()[history_index]);
+                        }
+                    }
+                }
+                Key::Left | Key::Ctrl('b') => line.left(),
+                Key::Right | Key::Ctrl('f') => line.right(),
+                Key::Home | Key::Ctrl('a') => line.home(),
+                Key::End | Key::Ctrl('e') => line.end(),
+                Key::Backspace | Key::Ctrl('h') => line.backspace(),
+                Key::Delete => line.delete(),
+                Key::Ctrl('k') => line.kill_to_end(),
+                Key::Ctrl('u') => line.kill_to_start(),
+                Key::Ctrl('w') => line.delete_word(),
+                Key::Ctrl('l') => write!(
+                    tty,
+                    "{}{}",
+                    termion::clear::All,
+                    termion::cursor::Goto(1, 1)
+                )?,
+                Key::Char(c) => line.insert(&c.to_string()),
+                _ => {}
+            }
+        }
+        tty.flush()?;
+        let text = line.text();
+        self.history.add(&text)?;
+        Ok(Some(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn line_buffer() {
+        let mut line = LineBuffer::default();
+        line.insert("ls /usr/lo");
+        assert_eq!(line.before_cursor(), "ls /usr/lo");
+        line.replace_word("/usr/local/");
+        assert_eq!(line.text(), "ls /usr/local/");
+        line.home();
+        line.right();
+        line.right();
+        line.insert(" -l");
+        assert_eq!(line.text(), "ls -l /usr/local/");
+        line.delete_word();
+        assert_eq!(line.text(), "ls  /usr/local/");
+        line.end();
+        line.delete_word();
+        assert_eq!(line.text(), "ls  ");
+        line.set("cat a b");
+        line.left();
+        line.kill_to_start();
+        assert_eq!((line.text().as_str(), line.cursor()), ("b", 0));
+        assert_eq!(
+            common_prefix(&["chrome".to_string(), "chronos/".to_string()]),
+            "chro"
+        );
+        assert_eq!(common_prefix(&[]), "");
+    }
+}
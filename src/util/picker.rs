@@ -0,0 +1,186 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+use std::io::stderr;
+use std::io::stdin;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+
+// Number of candidates shown at once in the picker
+const MAX_VISIBLE_ITEMS: usize = 10;
+
+static INTERACTIVE: AtomicBool = AtomicBool::new(true);
+
+/// Disable interactive prompts (e.g. for scripts). This is controlled by
+/// `cro3 --no-interactive`.
+pub fn set_interactive(enabled: bool) {
+    INTERACTIVE.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns true if cro3 is allowed to ask something to the user, i.e.
+/// --no-interactive is not given and both stdin and stderr are terminals.
+pub fn is_interactive() -> bool {
+    INTERACTIVE.load(Ordering::Relaxed) && termion::is_tty(&stdin()) && termion::is_tty(&stderr())
+}
+
+/// Ask a yes/no question on the terminal. Returns `default` without asking if
+/// cro3 is not interactive.
+pub fn confirm(prompt: &str, default: bool) -> Result<bool> {
+    if !is_interactive() {
+        return Ok(default);
+    }
+    eprint!("{prompt} {} ", if default { "[Y/n]" } else { "[y/N]" });
+    stderr().flush()?;
+    let mut line = String::new();
+    stdin().read_line(&mut line)?;
+    Ok(match line.trim().to_lowercase().as_str() {
+        "y" | "yes" => true,
+        "n" | "no" => false,
+        _ => default,
+    })
+}
+
+/// Returns a score if all the chars in `query` appear in `item` in the same
+/// order (case insensitive). Smaller is better.
+fn fuzzy_score(item: &str, query: &str) -> Option<usize> {
+    let item = item.to_lowercase();
+    let query = query.to_lowercase();
+    if query.is_empty() {
+        return Some(0);
+    }
+    if let Some(pos) = item.find(&query) {
+        // Substring matches are always preferred over scattered matches
+        return Some(pos);
+    }
+    let mut chars = item.char_indices();
+    let mut first = None;
+    let mut last = 0;
+    for q in query.chars() {
+        let (i, _) = chars.find(|(_, c)| *c == q)?;
+        first.get_or_insert(i);
+        last = i;
+    }
+    Some(item.len() + last - first.unwrap_or(0))
+}
+
+/// Returns indices of `items` matched with `query`, in the order of relevance.
+pub fn fuzzy_filter(items: &[String], query: &str) -> Vec<usize> {
+    let mut matched: Vec<(usize, usize)> = items
+        .iter()
+        .enumerate()
+        .flat_map(|(i, item)| fuzzy_score(item, query).map(|score| (score, i)))
+        .collect();
+    // sort_by_key is stable so the original order is kept for the same scores
+    matched.sort_by_key(|e| e.0);
+    matched.iter().map(|e| e.1).collect()
+}
+
+/// Show a fuzzy-searchable list of `items` on the terminal and let the user
+/// pick one of them. Returns the index of the chosen item.
+pub fn pick(prompt: &str, items: &[String], initial_query: &str) -> Result<usize> {
+    if items.is_empty() {
+        bail!("Nothing to choose from");
+    }
+    if !is_interactive() {
+        bail!("{prompt}: cannot ask interactively (not a tty or --no-interactive is given)");
+    }
+    let mut query = initial_query.to_string();
+    let mut selected = 0;
+    let mut tty = stderr()
+        .into_raw_mode()
+        .context("Failed to switch the terminal into raw mode")?;
+    let mut keys = stdin().keys();
+    let mut lines_drawn = 0;
+    let result = loop {
+        let matched = fuzzy_filter(items, &query);
+        selected = selected.min(matched.len().saturating_sub(1));
+        // Redraw the picker from the line where it started
+        if lines_drawn > 0 {
+            write!(tty, "{}", termion::cursor::Up(lines_drawn))?;
+        }
+        write!(
+            tty,
+            "\r{}{prompt} (type to filter, Enter to select, Esc to abort)\r\n> {query}\r\n",
+            termion::clear::AfterCursor
+        )?;
+        lines_drawn = 2;
+        for (row, i) in matched.iter().take(MAX_VISIBLE_ITEMS).enumerate() {
+            if row == selected {
+                write!(
+                    tty,
+                    "{}  {}{}\r\n",
+                    termion::style::Invert,
+                    items[*i],
+                    termion::style::Reset
+                )?;
+            } else {
+                write!(tty, "  {}\r\n", items[*i])?;
+            }
+            lines_drawn += 1;
+        }
+        if matched.len() > MAX_VISIBLE_ITEMS {
+            write!(tty, "  ... {} more\r\n", matched.len() - MAX_VISIBLE_ITEMS)?;
+            lines_drawn += 1;
+        }
+        tty.flush()?;
+        match keys.next().context("stdin is closed")?? {
+            Key::Char('\n') => {
+                if let Some(i) = matched.get(selected) {
+                    break Ok(*i);
+                }
+            }
+            Key::Esc | Key::Ctrl('c') | Key::Ctrl('d') => break Err(anyhow::anyhow!("Aborted")),
+            Key::Up | Key::Ctrl('p') => selected = selected.saturating_sub(1),
+            Key::Down | Key::Ctrl('n') | Key::Char('\t') => {
+                if selected + 1 < matched.len().min(MAX_VISIBLE_ITEMS) {
+                    selected += 1
+                }
+            }
+            Key::Backspace => {
+                query.pop();
+            }
+            Key::Char(c) => {
+                query.push(c);
+                selected = 0;
+            }
+            _ => {}
+        }
+    };
+    write!(
+        tty,
+        "{}\r{}",
+        termion::cursor::Up(lines_drawn),
+        termion::clear::AfterCursor
+    )?;
+    tty.flush()?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn fuzzy_filter_order() {
+        let items: Vec<String> = ["brya_SERIAL1", "volteer_SERIAL2", "brask_SERIAL3"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(fuzzy_filter(&items, ""), vec![0, 1, 2]);
+        assert_eq!(fuzzy_filter(&items, "br"), vec![0, 2]);
+        assert_eq!(fuzzy_filter(&items, "serial2"), vec![1]);
+        // Scattered match is allowed but ranked lower than substring matches
+        assert_eq!(fuzzy_filter(&items, "bs"), vec![2, 0]);
+        assert!(fuzzy_filter(&items, "xyz").is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+
+/// RotatingFile appends lines to a file, renaming it to `<path>.1` (and
+/// `<path>.1` to `<path>.2`, ...) once it grows larger than `max_bytes`.
+/// Only `max_backups` old files are kept.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_backups: usize,
+    file: File,
+    size: u64,
+}
+impl RotatingFile {
+    pub fn new(path: &str, max_bytes: u64, max_backups: usize) -> Result<Self> {
+        let path = PathBuf::from(path);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .context(format!("Failed to open {path:?}"))?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_bytes,
+            max_backups,
+            file,
+            size,
+        })
+    }
+    fn backup_path(&self, i: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{i}"));
+        path.into()
+    }
+    fn rotate(&mut self) -> Result<()> {
+        for i in (1..self.max_backups).rev() {
+            let from = self.backup_path(i);
+            if from.exists() {
+                fs::rename(&from, self.backup_path(i + 1))?;
+            }
+        }
+        if self.max_backups > 0 {
+            fs::rename(&self.path, self.backup_path(1))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+    #[test]
+    fn rotation() {
+        let dir = TempDir::new("cro3_rotating_file").unwrap();
+        let path = dir.path().join("log.txt");
+        let mut f = RotatingFile::new(path.to_str().unwrap(), 10, 2).unwrap();
+        for line in ["aaaa", "bbbb", "cccc", "dddd", "eeee"] {
+            f.write_line(line).unwrap();
+        }
+        let read = |name: &str| fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("log.txt"), "eeee\n");
+        assert_eq!(read("log.txt.1"), "cccc\ndddd\n");
+        assert_eq!(read("log.txt.2"), "aaaa\nbbbb\n");
+        assert!(!dir.path().join("log.txt.3").exists());
+    }
+}
@@ -19,6 +19,7 @@ pub fn has_root_privilege() -> Result<bool> {
 /// Usage of this should be minimized, to avoid environment variable related
 /// issues. Current use cases are:
 /// - Resetting servo by writing to sysfs
+/// - Installing udev rules for servo
 pub fn run_cro3_with_sudo(args: &[&str]) -> Result<()> {
     let mut c = Command::new("sudo");
     let status = c
@@ -0,0 +1,185 @@
+// Copyright 2023 The ChromiumOS Authors
+//
+// Use of this source code is governed by a BSD-style
+// license that can be found in the LICENSE file or at
+// https://developers.google.com/open-source/licenses/bsd
+
+//! Watching the source directories of packages for `cro3 watch`. The
+//! directories are polled for modification times, since they are not large
+//! once narrowed down to the subtree of each package.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use anyhow::Result;
+
+use crate::chroot::Chroot;
+
+/// Modification times of the files under the watched directories
+pub type SourceSnapshot = BTreeMap<PathBuf, SystemTime>;
+
+fn walk(dir: &Path, snapshot: &mut SourceSnapshot) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        // Skip .git and the other hidden files (e.g. swap files of editors)
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        if metadata.is_dir() {
+            walk(&entry.path(), snapshot);
+        } else if let Ok(modified) = metadata.modified() {
+            snapshot.insert(entry.path(), modified);
+        }
+    }
+}
+
+pub fn take_snapshot(dirs: &[PathBuf]) -> SourceSnapshot {
+    let mut snapshot = SourceSnapshot::new();
+    for dir in dirs {
+        walk(dir, &mut snapshot);
+    }
+    snapshot
+}
+
+/// Returns the files modified, added or removed between the snapshots
+pub fn changed_files(before: &SourceSnapshot, after: &SourceSnapshot) -> Vec<PathBuf> {
+    let mut changed: Vec<PathBuf> = after
+        .iter()
+        .filter(|(path, modified)| before.get(*path) != Some(modified))
+        .map(|(path, _)| path.clone())
+        .collect();
+    changed.extend(
+        before
+            .keys()
+            .filter(|path| !after.contains_key(*path))
+            .cloned(),
+    );
+    changed
+}
+
+/// Parses the output of `cros_workon info PACKAGE`, e.g.
+/// "chromeos-base/shill chromiumos/platform2 /mnt/host/source/src/platform2",
+/// into the source directories in the checkout at `repo`
+pub fn parse_workon_info(output: &str, repo: &str) -> Vec<PathBuf> {
+    output
+        .lines()
+        .filter_map(|l| l.split_whitespace().nth(2))
+        .flat_map(|paths| paths.split(','))
+        .filter_map(|path| {
+            path.find("/src/")
+                .map(|i| Path::new(repo).join(&path[i + 1..]))
+        })
+        .collect()
+}
+
+/// Parses CROS_WORKON_SUBTREE of an ebuild (e.g. "common-mk shill .gn"). The
+/// subtrees are only used for packages from a single project.
+pub fn parse_workon_subtree(ebuild: &str) -> Vec<String> {
+    ebuild
+        .lines()
+        .find_map(|l| l.trim().strip_prefix("CROS_WORKON_SUBTREE="))
+        .filter(|v| v.starts_with('"'))
+        .map(|v| {
+            v.trim_matches('"')
+                .split_whitespace()
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the source directories of `package` in the checkout at `repo`
+pub fn package_source_dirs(
+    chroot: &Chroot,
+    repo: &str,
+    board: &str,
+    package: &str,
+) -> Result<Vec<PathBuf>> {
+    let info =
+        chroot.exec_in_chroot(&["cros_workon", &format!("--board={board}"), "info", package])?;
+    let dirs = parse_workon_info(&info, repo);
+    let ebuild = chroot
+        .exec_in_chroot(&[
+            "bash",
+            "-c",
+            &format!("cat $(equery-{board} which {package})"),
+        ])
+        .unwrap_or_default();
+    let subtree: Vec<String> = parse_workon_subtree(&ebuild)
+        .into_iter()
+        // Skip the common files such as .gn, which rarely change
+        .filter(|s| !s.starts_with('.') && s != "common-mk")
+        .collect();
+    match dirs.as_slice() {
+        [dir] if !subtree.is_empty() => Ok(subtree.iter().map(|s| dir.join(s)).collect()),
+        _ => Ok(dirs),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    #[test]
+    fn source_changes() {
+        let t0 = SystemTime::UNIX_EPOCH;
+        let t1 = t0 + Duration::from_secs(1);
+        let before = SourceSnapshot::from([
+            (PathBuf::from("a.cc"), t0),
+            (PathBuf::from("b.cc"), t0),
+            (PathBuf::from("c.cc"), t0),
+        ]);
+        let after = SourceSnapshot::from([
+            (PathBuf::from("a.cc"), t0),
+            (PathBuf::from("b.cc"), t1),
+            (PathBuf::from("d.cc"), t0),
+        ]);
+        assert_eq!(
+            changed_files(&before, &after),
+            vec![
+                PathBuf::from("b.cc"),
+                PathBuf::from("d.cc"),
+                PathBuf::from("c.cc")
+            ]
+        );
+        assert!(changed_files(&after, &after).is_empty());
+    }
+    #[test]
+    fn workon_sources() {
+        assert_eq!(
+            parse_workon_info(
+                "chromeos-base/shill chromiumos/platform2 /mnt/host/source/src/platform2\n",
+                "/work/cros"
+            ),
+            vec![PathBuf::from("/work/cros/src/platform2")]
+        );
+        assert_eq!(
+            parse_workon_info(
+                "chromeos-base/foo a,b \
+                 /home/u/chromiumos/src/a,/home/u/chromiumos/src/third_party/b",
+                "/c"
+            ),
+            vec![
+                PathBuf::from("/c/src/a"),
+                PathBuf::from("/c/src/third_party/b")
+            ]
+        );
+        let ebuild = "CROS_WORKON_PROJECT=\"chromiumos/platform2\"
+CROS_WORKON_SUBTREE=\"common-mk shill .gn\"
+";
+        assert_eq!(
+            parse_workon_subtree(ebuild),
+            vec!["common-mk", "shill", ".gn"]
+        );
+        assert!(parse_workon_subtree("CROS_WORKON_SUBTREE=(\"a\" \"b\")").is_empty());
+    }
+}